@@ -0,0 +1,589 @@
+// Alerting on the stored readings. Rules are evaluated every `interval`
+// against each station's newest reading: a threshold rule such as
+// `temp > 40` with `for = "10m"` fires once the condition has held at every
+// evaluation for ten minutes, and an absence rule fires when a station has
+// not reported for its `absent` duration. Firing and resolving are sent to
+// the rule's channels, webhooks receiving a JSON POST and mail going through
+// an SMTP relay. Alert state, including which notifications went out, is
+// kept in `alerts.json` in the data directory, so a restart neither forgets
+// a pending alert nor notifies twice; failed notifications are retried at
+// the next evaluation. Only a node accepting writes evaluates, so replicas,
+// followers and standby regions stay quiet.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use crate::units;
+use crate::AppState;
+
+pub const ALERTS_FILE: &str = "alerts.json";
+
+/// Longest a notification may take before it counts as failed.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Alerts notified at once.
+const NOTIFY_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsConfig {
+    #[serde(alias = "interval", deserialize_with = "units::secs")]
+    pub interval_secs: u64,
+    pub rules: Vec<Rule>,
+    pub channels: Vec<Channel>,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self { interval_secs: 30, rules: Vec::new(), channels: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    /// Stations the rule watches; every station with data when empty.
+    #[serde(default)]
+    pub stations: Vec<String>,
+    /// `<field> <op> <number>` with `>`, `>=`, `<`, `<=`, `==` or `!=`.
+    #[serde(default)]
+    pub condition: Option<String>,
+    /// How long `condition` has to hold before the alert fires.
+    #[serde(default, alias = "for", deserialize_with = "units::secs")]
+    pub for_secs: u64,
+    /// Fire when the station's newest reading is older than this, instead
+    /// of a condition.
+    #[serde(default, alias = "absent", deserialize_with = "units::opt_secs")]
+    pub absent_secs: Option<u64>,
+    /// Channels notified; every channel when empty.
+    #[serde(default)]
+    pub notify: Vec<String>,
+}
+
+/// Webhook URLs and headers carry credentials, so they are shown as `***`
+/// in config dumps and debug output.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Channel {
+    pub name: String,
+    /// Webhook receiving a JSON POST per firing or resolved alert.
+    #[serde(default, serialize_with = "crate::config::redacted_opt")]
+    pub url: Option<String>,
+    #[serde(default, serialize_with = "redacted_headers")]
+    pub headers: BTreeMap<String, String>,
+    /// Mail recipients, sent through `smtp`.
+    #[serde(default)]
+    pub email: Vec<String>,
+    /// `host:port` of a relay accepting mail without authentication or TLS,
+    /// such as the local MTA.
+    #[serde(default)]
+    pub smtp: Option<String>,
+    #[serde(default)]
+    pub from: Option<String>,
+}
+
+impl std::fmt::Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Channel")
+            .field("name", &self.name)
+            .field("url", &self.url.as_ref().map(|_| "***"))
+            .field("headers", &self.headers.keys().map(|k| (k, "***")).collect::<BTreeMap<_, _>>())
+            .field("email", &self.email)
+            .field("smtp", &self.smtp)
+            .field("from", &self.from)
+            .finish()
+    }
+}
+
+/// Header names with their values shown as `***`.
+fn redacted_headers<S: serde::Serializer>(headers: &BTreeMap<String, String>, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_map(headers.keys().map(|k| (k, "***")))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    field: String,
+    op: Op,
+    threshold: f64,
+}
+
+impl Condition {
+    fn parse(s: &str) -> Result<Self> {
+        const OPS: [(&str, Op); 6] = [(">=", Op::Ge), ("<=", Op::Le), ("==", Op::Eq), ("!=", Op::Ne), (">", Op::Gt), ("<", Op::Lt)];
+        let (at, symbol, op) = OPS
+            .iter()
+            .filter_map(|(symbol, op)| s.find(symbol).map(|at| (at, *symbol, *op)))
+            .min_by_key(|(at, ..)| *at)
+            .ok_or_else(|| anyhow!("condition '{}' needs one of >, >=, <, <=, ==, !=", s))?;
+        let field = s[..at].trim();
+        let threshold = s[at + symbol.len()..].trim();
+        if field.is_empty() {
+            bail!("condition '{}' names no field", s);
+        }
+        let threshold = threshold.parse::<f64>().map_err(|_| anyhow!("condition '{}' must compare with a number", s))?;
+        Ok(Self { field: field.to_string(), op, threshold })
+    }
+
+    fn holds(&self, v: f64) -> bool {
+        match self.op {
+            Op::Gt => v > self.threshold,
+            Op::Ge => v >= self.threshold,
+            Op::Lt => v < self.threshold,
+            Op::Le => v <= self.threshold,
+            Op::Eq => v == self.threshold,
+            Op::Ne => v != self.threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    /// The condition holds but not yet for the rule's `for`.
+    Pending,
+    Firing,
+    /// Stopped firing; kept until the resolution is notified.
+    Resolved,
+}
+
+/// One rule's alert for one station.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    pub station_id: String,
+    pub state: AlertState,
+    /// RFC3339 time the condition was first seen to hold.
+    pub active_since: String,
+    /// The value that last matched, or the age in seconds of the newest
+    /// reading for an absence rule; `None` for a station without data.
+    pub value: Option<f64>,
+    /// The last state sent to every channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notified: Option<AlertState>,
+}
+
+/// The alerting engine of one node.
+pub struct Alerts {
+    cfg: AlertsConfig,
+    conditions: Vec<Option<Condition>>,
+    /// `None` when the server is read-only and state cannot be kept.
+    path: Option<PathBuf>,
+    alerts: Mutex<BTreeMap<(String, String), Alert>>,
+    client: reqwest::Client,
+}
+
+fn rfc3339(ms: i64) -> String {
+    DateTime::from_timestamp_millis(ms).unwrap_or_default().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+impl Alerts {
+    /// Check `cfg` and load the alert state of `data_dir`.
+    pub fn open(cfg: AlertsConfig, data_dir: &Path, read_only: bool) -> Result<Self> {
+        let mut conditions = Vec::with_capacity(cfg.rules.len());
+        for rule in &cfg.rules {
+            let condition = rule.condition.as_deref().map(Condition::parse).transpose().with_context(|| format!("alert rule {}", rule.name))?;
+            if condition.is_some() == rule.absent_secs.is_some() {
+                bail!("alert rule {} needs either a condition or absent", rule.name);
+            }
+            if let Some(unknown) = rule.notify.iter().find(|n| !cfg.channels.iter().any(|c| &c.name == *n)) {
+                bail!("alert rule {} notifies unknown channel {}", rule.name, unknown);
+            }
+            conditions.push(condition);
+        }
+        for c in &cfg.channels {
+            match (&c.url, c.email.is_empty()) {
+                (Some(_), true) => {}
+                (None, false) if c.smtp.is_some() && c.from.is_some() => {}
+                (None, false) => bail!("alert channel {} sends mail and needs smtp and from", c.name),
+                _ => bail!("alert channel {} needs either a url or email recipients", c.name),
+            }
+        }
+        let path = data_dir.join(ALERTS_FILE);
+        let alerts = match std::fs::read(&path) {
+            Ok(data) => {
+                let list: Vec<Alert> = serde_json::from_slice(&data).with_context(|| format!("reading {}", path.display()))?;
+                list.into_iter().map(|a| ((a.rule.clone(), a.station_id.clone()), a)).collect()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let client = reqwest::Client::builder().timeout(NOTIFY_TIMEOUT).build().unwrap_or_default();
+        Ok(Self { cfg, conditions, path: (!read_only).then_some(path), alerts: Mutex::new(alerts), client })
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.cfg.interval_secs.max(1))
+    }
+
+    /// Pending, firing and not yet notified resolved alerts, by rule and station.
+    pub async fn list(&self) -> Vec<Alert> {
+        self.alerts.lock().await.values().cloned().collect()
+    }
+
+    /// Evaluate every rule at `now` (epoch milliseconds), then send the
+    /// notifications that are due. A station whose newest reading cannot be
+    /// read is skipped until the next evaluation. Notifications go out
+    /// concurrently, without holding the alert state.
+    pub async fn evaluate(&self, state: &AppState, now: i64) -> Result<()> {
+        let known: Vec<String> = crate::query::stations(state).await.into_iter().map(|s| s.station_id).collect();
+        let mut latest = BTreeMap::new();
+        for rule in &self.cfg.rules {
+            let stations = if rule.stations.is_empty() { &known } else { &rule.stations };
+            for station in stations {
+                if latest.contains_key(station) {
+                    continue;
+                }
+                match crate::query::latest(state, station).await {
+                    Ok(o) => {
+                        latest.insert(station.clone(), o);
+                    }
+                    Err(e) => tracing::warn!(rule = rule.name, station_id = station, "alert evaluation skipped the station: {:#}", e),
+                }
+            }
+        }
+        let mut alerts = self.alerts.lock().await;
+        let before = alerts.clone();
+        // alerts of rules no longer configured are forgotten
+        alerts.retain(|(rule, _), _| self.cfg.rules.iter().any(|r| &r.name == rule));
+        for (rule, condition) in self.cfg.rules.iter().zip(&self.conditions) {
+            let stations = if rule.stations.is_empty() { &known } else { &rule.stations };
+            for station in stations {
+                let Some(latest) = latest.get(station) else { continue };
+                let (active, value) = match (condition, rule.absent_secs) {
+                    (Some(c), _) => match latest.as_ref().and_then(|o| o.number(&c.field)) {
+                        Some(v) => (c.holds(v), Some(v)),
+                        // without a value the alert stays as it is
+                        None => continue,
+                    },
+                    (None, absent) => {
+                        let age = latest.as_ref().map(|o| (now - o.time.millis()) as f64 / 1000.0);
+                        (age.is_none_or(|a| a > absent.unwrap_or(0) as f64), age)
+                    }
+                };
+                let key = (rule.name.clone(), station.clone());
+                match (alerts.get_mut(&key), active) {
+                    (None, true) => {
+                        let state = if rule.for_secs == 0 { AlertState::Firing } else { AlertState::Pending };
+                        alerts.insert(key, Alert { rule: rule.name.clone(), station_id: station.clone(), state, active_since: rfc3339(now), value, notified: None });
+                    }
+                    (Some(alert), true) => {
+                        if alert.state == AlertState::Resolved {
+                            (alert.state, alert.active_since) = (AlertState::Pending, rfc3339(now));
+                        }
+                        let since = DateTime::parse_from_rfc3339(&alert.active_since).map_or(now, |t| t.timestamp_millis());
+                        if alert.state == AlertState::Pending && now - since >= rule.for_secs as i64 * 1000 {
+                            alert.state = AlertState::Firing;
+                        }
+                        alert.value = value;
+                    }
+                    // a pending alert is dropped unless its firing went out unresolved
+                    (Some(alert), false) if alert.state == AlertState::Pending && alert.notified != Some(AlertState::Firing) => {
+                        alerts.remove(&key);
+                    }
+                    (Some(alert), false) => alert.state = AlertState::Resolved,
+                    (None, false) => {}
+                }
+            }
+        }
+        let due: Vec<Alert> = alerts
+            .values()
+            .filter(|a| matches!(a.state, AlertState::Firing | AlertState::Resolved) && a.notified != Some(a.state))
+            .cloned()
+            .collect();
+        drop(alerts);
+
+        let mut sent = Vec::new();
+        let mut due = due.into_iter();
+        let mut sending = tokio::task::JoinSet::new();
+        loop {
+            while sending.len() < NOTIFY_CONCURRENCY {
+                let Some(alert) = due.next() else { break };
+                let (client, rule, channels) = (self.client.clone(), self.cfg.rules.iter().find(|r| r.name == alert.rule).cloned(), self.cfg.channels.clone());
+                sending.spawn(async move {
+                    let result = notify(&client, rule.as_ref(), &channels, &alert).await;
+                    (alert, result)
+                });
+            }
+            let Some(done) = sending.join_next().await else { break };
+            match done? {
+                (alert, Ok(())) => sent.push(alert),
+                (alert, Err(e)) => tracing::warn!(rule = alert.rule, station_id = alert.station_id, "alert notification failed: {:#}", e),
+            }
+        }
+
+        let mut alerts = self.alerts.lock().await;
+        for a in sent {
+            // the alert may have been dropped or changed state meanwhile
+            if let Some(alert) = alerts.get_mut(&(a.rule, a.station_id)).filter(|alert| alert.state == a.state) {
+                alert.notified = Some(a.state);
+            }
+        }
+        alerts.retain(|_, a| !(a.state == AlertState::Resolved && a.notified == Some(AlertState::Resolved)));
+        if *alerts != before {
+            if let Some(path) = &self.path {
+                save(path, &alerts).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send `alert`'s state to the channels of its rule, every channel when it
+/// names none.
+async fn notify(client: &reqwest::Client, rule: Option<&Rule>, channels: &[Channel], alert: &Alert) -> Result<()> {
+    let summary = match rule {
+        Some(Rule { condition: Some(c), .. }) => format!("{} at {}: {}", alert.rule, alert.station_id, c),
+        Some(Rule { absent_secs: Some(secs), .. }) => format!("{} at {}: no data for {}s", alert.rule, alert.station_id, secs),
+        _ => format!("{} at {}", alert.rule, alert.station_id),
+    };
+    let status = if alert.state == AlertState::Firing { "firing" } else { "resolved" };
+    let notify = rule.map(|r| r.notify.as_slice()).unwrap_or_default();
+    for channel in channels.iter().filter(|c| notify.is_empty() || notify.contains(&c.name)) {
+        let sent = match &channel.url {
+            Some(url) => {
+                let body = serde_json::json!({ "status": status, "summary": summary, "alert": alert });
+                let mut req = client.post(url).json(&body);
+                for (k, v) in &channel.headers {
+                    req = req.header(k, v);
+                }
+                req.send().await.and_then(|r| r.error_for_status()).map(drop).map_err(anyhow::Error::from)
+            }
+            None => {
+                // a line break in a station id must not start a header of its own
+                let subject = format!("[{}] {}", status.to_uppercase(), summary).replace(['\r', '\n'], " ");
+                let body = format!("{}\n\nsince {}, value {}\n", summary, alert.active_since, alert.value.map_or("none".to_string(), |v| v.to_string()));
+                let relay = channel.smtp.as_deref().unwrap_or_default();
+                tokio::time::timeout(NOTIFY_TIMEOUT, send_mail(relay, channel.from.as_deref().unwrap_or_default(), &channel.email, &subject, &body))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("timed out")))
+            }
+        };
+        sent.with_context(|| format!("channel {}", channel.name))?;
+    }
+    Ok(())
+}
+
+async fn save(path: &Path, alerts: &BTreeMap<(String, String), Alert>) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let data = serde_json::to_vec_pretty(&alerts.values().collect::<Vec<_>>())?;
+    tokio::fs::write(&tmp, data).await.with_context(|| format!("writing {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path).await.with_context(|| format!("replacing {}", path.display()))?;
+    Ok(())
+}
+
+/// Deliver one plain-text message through an SMTP relay.
+async fn send_mail(relay: &str, from: &str, to: &[String], subject: &str, body: &str) -> Result<()> {
+    let stream = tokio::net::TcpStream::connect(relay).await.with_context(|| format!("connecting to {}", relay))?;
+    let (read, mut write) = stream.into_split();
+    let mut replies = BufReader::new(read).lines();
+    let mut expect = async |code: char| -> Result<()> {
+        loop {
+            let line = replies.next_line().await?.ok_or_else(|| anyhow!("relay closed the connection"))?;
+            // `250-` continues a multi-line reply, `250 ` ends it
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            if !line.starts_with(code) {
+                bail!("relay answered '{}'", line);
+            }
+            return Ok(());
+        }
+    };
+    expect('2').await?;
+    let mut commands = vec!["EHLO skypulsedb".to_string(), format!("MAIL FROM:<{}>", from)];
+    commands.extend(to.iter().map(|t| format!("RCPT TO:<{}>", t)));
+    for command in commands {
+        write.write_all(format!("{}\r\n", command).as_bytes()).await?;
+        expect('2').await?;
+    }
+    write.write_all(b"DATA\r\n").await?;
+    expect('3').await?;
+    let date = Utc::now().to_rfc2822();
+    let mut message = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n", from, to.join(", "), subject, date);
+    for line in body.lines() {
+        // a leading dot is doubled so that no line ends the message early
+        message.push_str(if line.starts_with('.') { "." } else { "" });
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    write.write_all(message.as_bytes()).await?;
+    expect('2').await?;
+    write.write_all(b"QUIT\r\n").await?;
+    Ok(())
+}
+
+/// Evaluate the rules every `interval` until shutdown.
+pub async fn run(state: Arc<AppState>, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let Some(alerts) = &state.alerts else { return };
+    let mut ticker = tokio::time::interval(alerts.interval());
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {
+                if state.write_refusal().is_some() {
+                    continue;
+                }
+                if let Err(e) = alerts.evaluate(&state, Utc::now().timestamp_millis()).await {
+                    tracing::error!("alert evaluation failed: {:#}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::{Observation, Timestamp};
+
+    /// A relay answering every command, sending each message's text down `tx`.
+    async fn smtp_relay(listener: tokio::net::TcpListener, tx: tokio::sync::mpsc::UnboundedSender<String>) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"220 relay\r\n").await.unwrap();
+            let mut message: Option<String> = None;
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply: &[u8] = match message.as_mut() {
+                    Some(m) if line == "." => {
+                        let _ = tx.send(std::mem::take(m));
+                        message = None;
+                        b"250 queued\r\n"
+                    }
+                    Some(m) => {
+                        m.push_str(&line);
+                        m.push('\n');
+                        continue;
+                    }
+                    None if line == "DATA" => {
+                        message = Some(String::new());
+                        b"354 go ahead\r\n"
+                    }
+                    None if line.starts_with("EHLO") => b"250-relay\r\n250 OK\r\n",
+                    None => b"250 OK\r\n",
+                };
+                write.write_all(reply).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fires_notifies_and_resolves() {
+        let dir = crate::test_util::TempDir::new("alerts");
+        let state = AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap();
+
+        let hooks = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let received = hooks.clone();
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |axum::Json(v): axum::Json<serde_json::Value>| async move { received.lock().unwrap().push(v) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = listener.local_addr().unwrap().to_string();
+        let (tx, mut mail) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(smtp_relay(listener, tx));
+
+        let cfg: AlertsConfig = toml::from_str(&format!(
+            r#"
+            [[rules]]
+            name = "heat"
+            stations = ["A"]
+            condition = "temp > 40"
+            for = "10m"
+            notify = ["ops"]
+
+            [[rules]]
+            name = "silent"
+            stations = ["B"]
+            absent = "30m"
+            notify = ["mail"]
+
+            [[channels]]
+            name = "ops"
+            url = "{}"
+
+            [[channels]]
+            name = "mail"
+            email = ["ops@example.com"]
+            smtp = "{}"
+            from = "skypulse@example.com"
+            "#,
+            hook_url, relay
+        ))
+        .unwrap();
+        let reading = |ms: i64, temp: f64| {
+            let mut o = Observation::empty("A", Timestamp(ms));
+            o.set_field("temp", temp);
+            o
+        };
+        let t0 = 1_735_689_600_000;
+        crate::ingest::append(&state, vec![reading(t0, 41.5)]).await.unwrap();
+        let alerts = Alerts::open(cfg.clone(), &dir, false).unwrap();
+        alerts.evaluate(&state, t0 + 1000).await.unwrap();
+        let states: Vec<(String, AlertState)> = alerts.list().await.into_iter().map(|a| (a.rule, a.state)).collect();
+        assert_eq!(states, [("heat".to_string(), AlertState::Pending), ("silent".to_string(), AlertState::Firing)]);
+        assert!(hooks.lock().unwrap().is_empty());
+        let message = mail.recv().await.unwrap();
+        assert!(message.contains("Subject: [FIRING] silent at B: no data for 1800s"), "{}", message);
+
+        // the pending alert survives a restart and fires ten minutes on
+        let alerts = Alerts::open(cfg.clone(), &dir, false).unwrap();
+        alerts.evaluate(&state, t0 + 601_000).await.unwrap();
+        let fired = hooks.lock().unwrap().clone();
+        assert_eq!(fired.len(), 1);
+        assert_eq!((fired[0]["status"].as_str(), fired[0]["alert"]["value"].as_f64()), (Some("firing"), Some(41.5)));
+        alerts.evaluate(&state, t0 + 631_000).await.unwrap();
+        assert_eq!(hooks.lock().unwrap().len(), 1);
+
+        crate::ingest::append(&state, vec![reading(t0 + 640_000, 30.0)]).await.unwrap();
+        alerts.evaluate(&state, t0 + 661_000).await.unwrap();
+        assert_eq!(hooks.lock().unwrap()[1]["status"], "resolved");
+        assert_eq!(alerts.list().await.len(), 1);
+        let saved = std::fs::read_to_string(dir.join(ALERTS_FILE)).unwrap();
+        assert!(saved.contains("\"silent\"") && !saved.contains("\"heat\""));
+
+        // a line break in a station id stays inside the subject
+        let silent = cfg.rules.iter().find(|r| r.name == "silent");
+        let forged = Alert { station_id: "B\r\nBcc: x@example.com".into(), ..alerts.list().await[0].clone() };
+        notify(&reqwest::Client::new(), silent, &cfg.channels, &forged).await.unwrap();
+        let message = mail.recv().await.unwrap();
+        let headers = message.split("\n\n").next().unwrap();
+        assert!(!headers.lines().any(|l| l.starts_with("Bcc:")), "{}", message);
+
+        // the alerts of a removed rule are forgotten
+        let without_silent = AlertsConfig { rules: cfg.rules.iter().filter(|r| r.name != "silent").cloned().collect(), ..cfg.clone() };
+        let alerts = Alerts::open(without_silent, &dir, false).unwrap();
+        alerts.evaluate(&state, t0 + 691_000).await.unwrap();
+        assert!(alerts.list().await.is_empty());
+        assert!(!std::fs::read_to_string(dir.join(ALERTS_FILE)).unwrap().contains("\"silent\""));
+
+        // webhook URLs and headers stay out of dumps
+        assert!(!serde_json::to_string(&cfg).unwrap().contains(&hook_url));
+        assert!(!format!("{:?}", cfg).contains(&hook_url));
+
+        assert!(Condition::parse("temp >= -5.5").unwrap().holds(-5.5));
+        for bad in ["temp", "> 4", "temp > hot"] {
+            assert!(Condition::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use axum::{extract::{Extension, Query}, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::debug::DebugState;
+use crate::mirror::{self, ApplyReport, MirrorStatus};
+use crate::rebuild::{self, Progress, Target};
+use crate::replication::{self, Position, ReplicationStatus};
+use crate::storage::chunk_store::Relocation;
+use crate::storage::fragmentation::{self, StationFragmentation};
+use crate::storage::{diff, retention::RetentionPolicy, Manifest};
+
+#[derive(Deserialize)]
+pub struct DiffParams {
+    /// Recompute local checksums from the chunk files instead of trusting the manifest.
+    #[serde(default)]
+    pub verify: bool,
+}
+
+/// Return this node's chunk manifest so a peer can diff against it.
+pub async fn manifest_handler(Extension(state): Extension<Arc<crate::AppState>>) -> Json<Manifest> {
+    Json(state.chunk_store.manifest().await)
+}
+
+/// Diff a peer's manifest (request body) against the local one.
+/// In the response `a` is this node and `b` is the peer.
+pub async fn diff_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Query(params): Query<DiffParams>,
+    Json(peer): Json<Manifest>,
+) -> Result<Json<diff::ChunkDiff>, (StatusCode, String)> {
+    let local = if params.verify {
+        state.chunk_store.scan().await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    } else {
+        state.chunk_store.manifest().await
+    };
+    Ok(Json(diff::diff_manifests(&local, &peer)))
+}
+
+/// Return the retention policy in effect.
+pub async fn retention_handler(Extension(state): Extension<Arc<crate::AppState>>) -> Json<RetentionPolicy> {
+    Json(state.retention.clone())
+}
+
+/// Current station -> tenant assignments made by routing rules.
+pub async fn tenants_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+) -> Json<std::collections::BTreeMap<String, String>> {
+    match &state.router {
+        Some(r) => Json(r.assignments().await),
+        None => Json(Default::default()),
+    }
+}
+
+/// Pending and firing alerts, and resolved ones not yet notified.
+pub async fn alerts_handler(Extension(state): Extension<Arc<crate::AppState>>) -> Json<Vec<crate::alerts::Alert>> {
+    match &state.alerts {
+        Some(a) => Json(a.list().await),
+        None => Json(Vec::new()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RebuildParams {
+    pub what: Target,
+    pub station_id: Option<String>,
+}
+
+/// Start rebuilding `what` from the raw chunks, for one station or all of
+/// them. Answers 202 with the initial progress; poll `GET` on the same path.
+pub async fn rebuild_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Query(params): Query<RebuildParams>,
+) -> Result<(StatusCode, Json<Progress>), (StatusCode, String)> {
+    if state.read_only() && params.what != Target::LastValues {
+        return Err((StatusCode::FORBIDDEN, "server is read-only".to_string()));
+    }
+    if params.what == Target::Manifest && params.station_id.is_some() {
+        return Err((StatusCode::BAD_REQUEST, "the manifest can only be rebuilt as a whole".to_string()));
+    }
+    match rebuild::start(state, params.what, params.station_id).await {
+        Ok(progress) => Ok((StatusCode::ACCEPTED, Json(progress))),
+        Err(running) => Err((StatusCode::CONFLICT, format!("a {} rebuild is already running", running.what.as_str()))),
+    }
+}
+
+/// Progress of the running or most recent rebuild.
+pub async fn rebuild_status_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+) -> Result<Json<Progress>, (StatusCode, String)> {
+    match state.rebuild.lock().await.clone() {
+        Some(progress) => Ok(Json(progress)),
+        None => Err((StatusCode::NOT_FOUND, "no rebuild has run".to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RelocateRequest {
+    /// New home for the chunk files; must be empty or not exist yet.
+    pub chunk_dir: std::path::PathBuf,
+}
+
+/// Move the chunk directory to another path (for example another disk)
+/// while the server keeps running. Every copied file is checked against its
+/// manifest checksum before the old files are removed.
+pub async fn relocate_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Json(req): Json<RelocateRequest>,
+) -> Result<Json<Relocation>, (StatusCode, String)> {
+    if state.read_only() {
+        return Err((StatusCode::FORBIDDEN, "server is read-only".to_string()));
+    }
+    let job = state.clone();
+    let report = state
+        .runtimes
+        .compaction
+        .spawn(async move { job.chunk_store.relocate(&job.data_dir, req.chunk_dir).await })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+    tracing::info!(
+        "relocated {} chunks ({} bytes) from {} to {}",
+        report.chunks,
+        report.bytes,
+        report.from.display(),
+        report.to.display()
+    );
+    Ok(Json(report))
+}
+
+#[derive(Deserialize, Default)]
+pub struct SnapshotRequest {
+    /// Directory to create the timestamped snapshot in; defaults to
+    /// `data_dir/snapshots`.
+    pub dir: Option<std::path::PathBuf>,
+}
+
+/// Flush the MemTable and copy the chunk files into a new snapshot directory
+/// without stopping ingestion.
+pub async fn snapshot_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    body: Option<Json<SnapshotRequest>>,
+) -> Result<Json<crate::snapshot::Snapshot>, (StatusCode, String)> {
+    let req = body.map(|Json(r)| r).unwrap_or_default();
+    let job = state.clone();
+    let snapshot = state
+        .runtimes
+        .compaction
+        .spawn(async move { crate::snapshot::create(&job, req.dir.as_deref()).await })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    Ok(Json(snapshot))
+}
+
+#[derive(Deserialize)]
+pub struct StatsParams {
+    pub station_id: Option<String>,
+    /// Only list stations scoring at least this.
+    pub min_score: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct StorageStats {
+    pub chunks: usize,
+    pub bytes: u64,
+    pub rows: usize,
+    pub target_chunk_bytes: u64,
+    /// Score above which compaction merges a station's chunks; `None` when compaction is off.
+    pub max_fragmentation: Option<f64>,
+    /// Most fragmented first.
+    pub stations: Vec<StationFragmentation>,
+}
+
+/// Chunk totals and per-station fragmentation scores.
+pub async fn stats_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Query(params): Query<StatsParams>,
+) -> Json<StorageStats> {
+    let manifest = state.chunk_store.manifest().await;
+    let cfg = state.compaction.clone().unwrap_or_default();
+    let mut stations: Vec<StationFragmentation> = fragmentation::analyze(&manifest, cfg.target_chunk_bytes)
+        .into_values()
+        .filter(|f| params.station_id.as_ref().is_none_or(|s| *s == f.station_id))
+        .filter(|f| params.min_score.is_none_or(|min| f.score >= min))
+        .collect();
+    stations.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.station_id.cmp(&b.station_id)));
+    Json(StorageStats {
+        chunks: manifest.chunks.len(),
+        bytes: manifest.chunks.values().map(|c| c.bytes).sum(),
+        rows: manifest.chunks.values().map(|c| c.rows).sum(),
+        target_chunk_bytes: cfg.target_chunk_bytes,
+        max_fragmentation: state.compaction.as_ref().map(|c| c.max_fragmentation),
+        stations,
+    })
+}
+
+/// Redacted snapshot of the in-memory state, for debugging a stuck instance.
+pub async fn debug_state_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+) -> Result<Json<DebugState>, (StatusCode, String)> {
+    crate::debug::snapshot(&state).await.map(Json).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// This node's followers and, on a follower, its progress through the leader's WAL.
+pub async fn replication_status_handler(Extension(state): Extension<Arc<crate::AppState>>) -> Json<ReplicationStatus> {
+    Json(state.replication.status(state.wal.as_deref()))
+}
+
+#[derive(Deserialize)]
+pub struct WalParams {
+    pub follower: String,
+    /// 0 for the oldest segment still on disk.
+    #[serde(default)]
+    pub segment: u64,
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default = "default_wal_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_wal_bytes() -> u64 {
+    1024 * 1024
+}
+
+/// WAL records from `segment` at `offset`, for a follower. Headers say where
+/// to continue: `x-wal-next-offset` in `x-wal-segment`, or the start of
+/// `x-wal-next-segment` once that one is read to its end. A segment already
+/// removed answers 410 Gone.
+pub async fn replication_wal_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Query(params): Query<WalParams>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    use axum::http::header::{HeaderName, CONTENT_TYPE};
+    use axum::response::IntoResponse;
+
+    let Some(wal) = state.wal.as_deref() else { return Err((StatusCode::NOT_FOUND, "server is read-only and has no WAL".to_string())) };
+    if params.follower.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "follower must name the follower".to_string()));
+    }
+    let tail = wal
+        .read_records(params.segment, params.offset, params.max_bytes.min(replication::MAX_READ_BYTES))
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?
+        .ok_or_else(|| (StatusCode::GONE, format!("WAL segment {} has been removed", params.segment)))?;
+    let position = Position { segment: tail.segment, offset: params.offset };
+    state.replication.seen(wal, &params.follower, position).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut headers = vec![
+        (CONTENT_TYPE, "application/octet-stream".to_string()),
+        (HeaderName::from_static("x-wal-segment"), tail.segment.to_string()),
+        (HeaderName::from_static("x-wal-next-offset"), tail.next_offset.to_string()),
+        (HeaderName::from_static("x-wal-active"), tail.active.to_string()),
+    ];
+    if let Some(next) = tail.next_segment {
+        headers.push((HeaderName::from_static("x-wal-next-segment"), next.to_string()));
+    }
+    let headers: axum::http::HeaderMap = headers.into_iter().map(|(k, v)| (k, v.parse().unwrap())).collect();
+    Ok((headers, tail.records).into_response())
+}
+
+fn mirror(state: &crate::AppState) -> Result<&mirror::Mirror, (StatusCode, String)> {
+    state.mirror.as_deref().ok_or((StatusCode::NOT_FOUND, "mirroring is not configured".to_string()))
+}
+
+/// Role, outbox backlog and lag per peer, and what arrived from each origin.
+pub async fn mirror_status_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+) -> Result<Json<MirrorStatus>, (StatusCode, String)> {
+    Ok(Json(mirror(&state)?.status().await))
+}
+
+/// Apply a batch shipped by a peer region.
+pub async fn mirror_apply_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Json(entries): Json<Vec<mirror::Entry>>,
+) -> Result<Json<ApplyReport>, (StatusCode, String)> {
+    let report = mirror(&state)?
+        .apply(&state, entries)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    Ok(Json(report))
+}
+
+/// Make this region active (accepting client writes) or a standby.
+async fn set_role(state: &crate::AppState, standby: bool) -> Result<Json<MirrorStatus>, (StatusCode, String)> {
+    let m = mirror(state)?;
+    m.set_standby(standby).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    tracing::warn!(region = m.region(), "mirror role changed to {}", if standby { "standby" } else { "active" });
+    Ok(Json(m.status().await))
+}
+
+pub async fn mirror_promote_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+) -> Result<Json<MirrorStatus>, (StatusCode, String)> {
+    set_role(&state, false).await
+}
+
+pub async fn mirror_demote_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+) -> Result<Json<MirrorStatus>, (StatusCode, String)> {
+    set_role(&state, true).await
+}
@@ -0,0 +1,177 @@
+// API-key authentication. Keys come from the `[auth]` config section (or
+// `SKYPULSE_API_KEYS`) and each carries a scope; route groups require a scope
+// via `require`. With no keys configured every request is allowed, as before.
+// A key may also name a response filter (see `filter`) for public access.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use axum::{extract::Request, http::{header, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use super::filter::ResponseFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Queries and station lookups.
+    Read,
+    /// Ingest endpoints only.
+    Write,
+    /// Everything, including `/api/v1/admin/*`.
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            other => bail!("unknown scope '{}', expected read, write or admin", other),
+        }
+    }
+
+    fn allows(self, needed: Scope) -> bool {
+        self == Scope::Admin || self == needed
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    #[serde(serialize_with = "crate::config::redacted")]
+    pub key: String,
+    pub scope: Scope,
+    /// Shown in logs instead of the key itself.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Response filter from `filters` applied to everything this key reads.
+    #[serde(default)]
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub keys: Vec<ApiKey>,
+    /// Named response filters keys can refer to.
+    pub filters: BTreeMap<String, ResponseFilter>,
+}
+
+impl AuthConfig {
+    /// `SKYPULSE_API_KEYS=key:scope,key:scope` replaces the configured keys.
+    pub fn apply_env(&mut self) -> Result<()> {
+        let Ok(v) = std::env::var("SKYPULSE_API_KEYS") else { return Ok(()) };
+        let mut keys = Vec::new();
+        for entry in v.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((key, scope)) = entry.rsplit_once(':') else {
+                bail!("SKYPULSE_API_KEYS entries must look like key:scope");
+            };
+            keys.push(ApiKey { key: key.to_string(), scope: Scope::parse(scope)?, name: None, filter: None });
+        }
+        self.keys = keys;
+        Ok(())
+    }
+
+    /// Every filter a key names must be defined.
+    pub fn validate(&self) -> Result<()> {
+        for key in &self.keys {
+            if let Some(f) = key.filter.as_deref().filter(|f| !self.filters.contains_key(*f)) {
+                bail!("API key {} names unknown filter '{}'", key.name.as_deref().unwrap_or("unnamed"), f);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// The configured key matching `token`, compared in constant time.
+    fn lookup(&self, token: &str) -> Option<&ApiKey> {
+        self.keys.iter().find(|k| constant_time_eq(k.key.as_bytes(), token.as_bytes()))
+    }
+
+    /// The response filter of the key matching `token`, if it has one.
+    pub fn filter(&self, token: Option<&str>) -> Option<&ResponseFilter> {
+        self.filters.get(self.filter_name(token)?)
+    }
+
+    /// The name of that filter.
+    pub fn filter_name(&self, token: Option<&str>) -> Option<&str> {
+        token.and_then(|t| self.lookup(t))?.filter.as_deref()
+    }
+
+    /// `Err` carries the status to answer with: 401 for a missing or unknown
+    /// key, 403 for a key without the needed scope.
+    pub fn authorize(&self, token: Option<&str>, needed: Scope) -> Result<(), StatusCode> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let key = token.and_then(|t| self.lookup(t)).ok_or(StatusCode::UNAUTHORIZED)?;
+        if key.scope.allows(needed) {
+            Ok(())
+        } else {
+            tracing::debug!(key = key.name.as_deref().unwrap_or("unnamed"), "key lacks {:?} scope", needed);
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware for a route group that needs `scope`.
+pub async fn require(scope: Scope, req: Request, next: Next) -> Response {
+    let Some(state) = req.extensions().get::<Arc<crate::AppState>>() else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "missing state").into_response();
+    };
+    let token = super::http::request_token(req.headers());
+    match state.auth.authorize(token.as_deref(), scope) {
+        Ok(()) => next.run(req).await,
+        Err(StatusCode::UNAUTHORIZED) => {
+            (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], "missing or invalid API key").into_response()
+        }
+        Err(status) => (status, format!("API key does not grant {:?} access", scope).to_lowercase()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> AuthConfig {
+        AuthConfig {
+            keys: vec![
+                ApiKey { key: "r".into(), scope: Scope::Read, name: None, filter: None },
+                ApiKey { key: "w".into(), scope: Scope::Write, name: None, filter: None },
+                ApiKey { key: "a".into(), scope: Scope::Admin, name: None, filter: None },
+                ApiKey { key: "p".into(), scope: Scope::Read, name: None, filter: Some("public".into()) },
+            ],
+            filters: [("public".to_string(), ResponseFilter::default())].into(),
+        }
+    }
+
+    #[test]
+    fn scopes_are_separate() {
+        let auth = keys();
+        assert_eq!(auth.authorize(None, Scope::Read), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(auth.authorize(Some("nope"), Scope::Read), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(auth.authorize(Some("r"), Scope::Read), Ok(()));
+        assert_eq!(auth.authorize(Some("r"), Scope::Write), Err(StatusCode::FORBIDDEN));
+        assert_eq!(auth.authorize(Some("w"), Scope::Read), Err(StatusCode::FORBIDDEN));
+        assert_eq!(auth.authorize(Some("a"), Scope::Write), Ok(()));
+        assert_eq!(AuthConfig::default().authorize(None, Scope::Admin), Ok(()));
+    }
+
+    #[test]
+    fn filters_follow_the_key() {
+        let mut auth = keys();
+        assert!(auth.filter(Some("p")).is_some());
+        assert!(auth.filter(Some("r")).is_none());
+        assert!(auth.filter(None).is_none());
+        assert!(auth.validate().is_ok());
+        auth.filters.clear();
+        assert!(auth.validate().is_err());
+    }
+}
@@ -0,0 +1,156 @@
+// Server-Sent Events for browser dashboards that cannot hold a WebSocket.
+// `GET /api/v1/stations/:id/events` streams each new observation of the
+// station as an `observation` event whose id is the row's time in epoch
+// milliseconds, plus a `heartbeat` event every 15 seconds so proxies keep
+// the connection open. A reconnecting `EventSource` sends the last id it saw
+// as `Last-Event-ID`, and the rows stored after that time are replayed before
+// the live ones; `?since=<RFC3339>` does the same on a first connect.
+
+use axum::{
+    extract::{Extension, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, Sse},
+};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use super::filter::ResponseFilter;
+use crate::storage::memtable::{Observation, Timestamp};
+
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// Rows per batch when replaying after a resume.
+const REPLAY_BATCH: usize = 1024;
+/// Events queued ahead of a slow client.
+const EVENT_QUEUE: usize = 64;
+
+#[derive(Deserialize)]
+pub struct EventParams {
+    /// Replay rows after this RFC3339 time when no `Last-Event-ID` is sent.
+    pub since: Option<String>,
+}
+
+fn observation_event(o: &Observation, filter: Option<&ResponseFilter>) -> Event {
+    let mut v = serde_json::to_value(o).unwrap_or_default();
+    if let Some(f) = filter {
+        f.apply(&mut v);
+    }
+    Event::default().event("observation").id(o.time.millis().to_string()).data(v.to_string())
+}
+
+/// Where a stream resumes: after the time in `Last-Event-ID`, else after `since`.
+fn resume_after(headers: &HeaderMap, since: Option<&str>) -> Result<Option<Timestamp>, String> {
+    if let Some(id) = headers.get("last-event-id") {
+        let id = id.to_str().unwrap_or_default().trim();
+        return id.parse().map(|ms| Some(Timestamp(ms))).map_err(|_| format!("Last-Event-ID must be epoch milliseconds, got '{}'", id));
+    }
+    since.map(|s| Timestamp::parse(s).map_err(|e| e.to_string())).transpose()
+}
+
+/// GET /api/v1/stations/:id/events
+pub async fn events_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Path(station_id): Path<String>,
+    Query(params): Query<EventParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let after = resume_after(&headers, params.since.as_deref()).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let filter = state.auth.filter(super::http::request_token(&headers).as_deref()).cloned();
+    let (tx, rx) = mpsc::channel(EVENT_QUEUE);
+    // subscribe before replaying so nothing written in between is missed
+    let mut live = state.live.subscribe();
+    let rt = state.runtimes.query.clone();
+    rt.spawn(async move {
+        let send = |event: Event| tx.send(event);
+        // the newest time replayed; live batches queued during the replay may repeat those rows
+        let mut replayed = None;
+        if let Some(after) = after {
+            let start = Timestamp(after.millis().saturating_add(1)).to_datetime();
+            let mut batches = crate::query::stream::observations(state.clone(), &station_id, Some(start), None, REPLAY_BATCH);
+            while let Some(batch) = batches.next().await {
+                let rows = match batch {
+                    Ok(b) => b.observations,
+                    Err(e) => {
+                        let _ = send(Event::default().event("error").data(e.to_string())).await;
+                        return;
+                    }
+                };
+                for o in rows {
+                    replayed = replayed.max(Some(o.time));
+                    if send(observation_event(&o, filter.as_ref())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let mut overlap = if replayed.is_some() { live.len() } else { 0 };
+        let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT, HEARTBEAT);
+        loop {
+            let events = tokio::select! {
+                _ = tx.closed() => return,
+                _ = heartbeat.tick() => vec![Event::default().event("heartbeat").data(chrono::Utc::now().to_rfc3339())],
+                batch = live.recv() => match batch {
+                    Ok(obs) => {
+                        let seen = if overlap > 0 { overlap -= 1; replayed } else { None };
+                        obs.iter()
+                            .filter(|o| o.station_id == station_id && seen.is_none_or(|t| o.time > t))
+                            .map(|o| observation_event(o, filter.as_ref()))
+                            .collect()
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        overlap = overlap.saturating_sub(n as usize);
+                        vec![Event::default().event("lagged").data(n.to_string())]
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+            };
+            for event in events {
+                if send(event).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(Sse::new(ReceiverStream::new(rx).map(Ok)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn resumes_after_the_last_event_id_then_streams_live_rows() {
+        let dir = crate::test_util::TempDir::new("events");
+        let state = Arc::new(crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let at = |id: &str, t: &str| Observation::empty(id, t.parse().unwrap());
+        crate::ingest::append(&state, vec![at("A", "2025-01-01T00:00:00Z"), at("A", "2025-01-01T00:01:00Z")]).await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/stations/A/events", listener.local_addr().unwrap());
+        let app = super::super::http::router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let bad = reqwest::Client::new().get(&url).header("Last-Event-ID", "noon").send().await.unwrap();
+        assert_eq!(bad.status(), 400);
+
+        let first = Timestamp::parse("2025-01-01T00:00:00Z").unwrap().millis();
+        let mut resp = reqwest::Client::new().get(&url).header("Last-Event-ID", first.to_string()).send().await.unwrap();
+        assert_eq!(resp.headers()["content-type"], "text/event-stream");
+        let mut text = String::new();
+        // the second stored row is replayed, then a live row of A follows and B's is left out
+        while !text.contains("id: ") {
+            text.push_str(&String::from_utf8_lossy(&resp.chunk().await.unwrap().unwrap()));
+        }
+        crate::ingest::append(&state, vec![at("B", "2025-01-01T00:02:00Z"), at("A", "2025-01-01T00:03:00Z")]).await.unwrap();
+        while text.matches("id: ").count() < 2 {
+            text.push_str(&String::from_utf8_lossy(&resp.chunk().await.unwrap().unwrap()));
+        }
+        let ids: Vec<&str> = text.lines().filter_map(|l| l.strip_prefix("id: ")).collect();
+        assert_eq!(ids, [(first + 60_000).to_string(), (first + 180_000).to_string()]);
+        assert!(text.contains("event: observation") && !text.contains("\"B\""));
+    }
+}
@@ -0,0 +1,86 @@
+use axum::{
+    body::Body,
+    extract::{Extension, Path},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::sync::Arc;
+use crate::export::{ExportError, ExportRequest, Job};
+
+fn status(e: ExportError) -> (StatusCode, String) {
+    match e {
+        ExportError::Invalid(msg) => (StatusCode::BAD_REQUEST, msg),
+        ExportError::NotFound => (StatusCode::NOT_FOUND, "no such export job or part".to_string()),
+        ExportError::Busy(n) => (StatusCode::TOO_MANY_REQUESTS, format!("{} exports are already running; retry later", n)),
+        ExportError::Failed(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+    }
+}
+
+/// Response filter name of the requesting key; jobs are scoped to it.
+fn filter_name<'a>(state: &'a crate::AppState, headers: &HeaderMap) -> Option<&'a str> {
+    state.auth.filter_name(super::http::request_token(headers).as_deref())
+}
+
+/// POST /api/v1/export/jobs
+///
+/// Start exporting a station's raw rows; answers 202 with the job to poll.
+pub async fn create_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<ExportRequest>,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, String)> {
+    let filter = filter_name(&state, &headers).and_then(|name| Some((name.to_string(), state.auth.filters.get(name)?.clone())));
+    let job = state.exports.create(state.clone(), req, filter).await.map_err(status)?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// GET /api/v1/export/jobs
+pub async fn list_handler(Extension(state): Extension<Arc<crate::AppState>>, headers: HeaderMap) -> Json<Vec<Job>> {
+    Json(state.exports.list(filter_name(&state, &headers)).await)
+}
+
+/// GET /api/v1/export/jobs/:id
+pub async fn get_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, (StatusCode, String)> {
+    state.exports.get(&id, filter_name(&state, &headers)).await.map(Json).map_err(status)
+}
+
+/// DELETE /api/v1/export/jobs/:id
+pub async fn delete_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state.exports.remove(&id, filter_name(&state, &headers)).await.map_err(status)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/v1/export/jobs/:id/parts/:index
+///
+/// The part file as written, streamed from disk, with its checksum as the
+/// ETag. It is served as an opaque download so the response filter does not
+/// rewrite it again.
+pub async fn part_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Path((id, index)): Path<(String, usize)>,
+) -> Result<Response, (StatusCode, String)> {
+    let (path, part) = state.exports.part(&id, index, filter_name(&state, &headers)).await.map_err(status)?;
+    let file = tokio::fs::File::open(&path).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let disposition = format!("attachment; filename=\"{}-part-{:05}.ndjson\"", id, index);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, part.bytes.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::ETAG, format!("\"{}\"", part.sha256)),
+            (header::HeaderName::from_static("x-content-sha256"), part.sha256),
+        ],
+        Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    )
+        .into_response())
+}
@@ -0,0 +1,180 @@
+// Response filters for public endpoints. An API key may name a filter from
+// `[auth.filters.<name>]`; every JSON or NDJSON response to that key is then
+// rewritten before it leaves the server: redacted names are dropped, named
+// values are rounded and coordinates are snapped to a coarse grid. The
+// transform works on plain JSON values, so export paths can share it.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use axum::{body::Body, extract::Request, http::{header, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use crate::query::derived::Derived;
+
+fn default_coordinates() -> Vec<String> {
+    ["lat", "lon", "latitude", "longitude"].map(String::from).to_vec()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseFilter {
+    /// Names removed wherever they appear: fields, tags or aggregate columns.
+    pub redact: Vec<String>,
+    /// Decimal places each named value is rounded to; under an aggregate
+    /// column every number is rounded.
+    pub round: BTreeMap<String, u32>,
+    /// Grid in degrees that coordinates snap to (0.01 is about 1 km); 0 leaves them exact.
+    pub coordinate_grid: f64,
+    /// Names holding coordinates.
+    pub coordinates: Vec<String>,
+}
+
+impl Default for ResponseFilter {
+    fn default() -> Self {
+        Self { redact: Vec::new(), round: BTreeMap::new(), coordinate_grid: 0.0, coordinates: default_coordinates() }
+    }
+}
+
+/// Apply `f` to every non-integer number in `v`; counts stay integers.
+fn map_numbers(v: &mut Value, f: &dyn Fn(f64) -> f64) {
+    match v {
+        Value::Number(n) if n.is_f64() => {
+            if let Some(x) = n.as_f64().map(f).and_then(Number::from_f64) {
+                *n = x;
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|i| map_numbers(i, f)),
+        Value::Object(map) => map.values_mut().for_each(|i| map_numbers(i, f)),
+        _ => {}
+    }
+}
+
+fn round_to(x: f64, places: u32) -> f64 {
+    let scale = 10f64.powi(places.min(15) as i32);
+    (x * scale).round() / scale
+}
+
+impl ResponseFilter {
+    /// Rewrite `v` in place.
+    pub fn apply(&self, v: &mut Value) {
+        match v {
+            Value::Object(map) => {
+                map.retain(|k, _| !self.redact.contains(k));
+                for (k, child) in map.iter_mut() {
+                    if let Some(&places) = self.round.get(k) {
+                        map_numbers(child, &|x| round_to(x, places));
+                    } else if self.coordinate_grid > 0.0 && self.coordinates.contains(k) {
+                        let grid = self.coordinate_grid;
+                        // rounding again drops the float noise of the grid multiple
+                        map_numbers(child, &|x| round_to((x / grid).round() * grid, 9));
+                    } else {
+                        self.apply(child);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|i| self.apply(i)),
+            _ => {}
+        }
+    }
+
+    /// The value `x` named `name` as `apply` would leave it; `None` when
+    /// the name is redacted.
+    pub fn number(&self, name: &str, x: f64) -> Option<f64> {
+        if self.redact.iter().any(|r| r == name) {
+            return None;
+        }
+        let mut v = serde_json::json!({ name: x });
+        self.apply(&mut v);
+        v[name].as_f64()
+    }
+
+    /// A redacted input of the derived field `d`, which computing it for
+    /// this key would leak.
+    pub fn redacted_input(&self, d: Derived) -> Option<&'static str> {
+        d.inputs().iter().copied().find(|i| self.redact.iter().any(|r| r == i))
+    }
+
+    /// Rewrite a JSON document, or NDJSON when `ndjson` is set. Lines that
+    /// are not JSON pass through unchanged.
+    pub fn apply_bytes(&self, body: &[u8], ndjson: bool) -> Vec<u8> {
+        let rewrite = |part: &[u8]| match serde_json::from_slice::<Value>(part) {
+            Ok(mut v) => {
+                self.apply(&mut v);
+                serde_json::to_vec(&v).unwrap_or_else(|_| part.to_vec())
+            }
+            Err(_) => part.to_vec(),
+        };
+        if !ndjson {
+            return rewrite(body);
+        }
+        let mut out = Vec::with_capacity(body.len());
+        for line in body.split_inclusive(|b| *b == b'\n') {
+            let content = line.strip_suffix(b"\n").unwrap_or(line);
+            if content.trim_ascii().is_empty() {
+                out.extend_from_slice(line);
+                continue;
+            }
+            out.extend(rewrite(content));
+            if content.len() < line.len() {
+                out.push(b'\n');
+            }
+        }
+        out
+    }
+}
+
+/// Middleware rewriting the responses to keys that have a filter. Other
+/// responses, and bodies that are neither JSON nor NDJSON, pass untouched.
+/// A filtered body is read into memory, at most `limit` bytes of it
+/// (`server.max_body_bytes`); a larger answer is an error.
+pub async fn filter(limit: usize, req: Request, next: Next) -> Response {
+    let state = req.extensions().get::<Arc<crate::AppState>>().cloned();
+    let token = super::http::request_token(req.headers());
+    let resp = next.run(req).await;
+    let Some(filter) = state.as_ref().and_then(|s| s.auth.filter(token.as_deref())) else { return resp };
+    let content_type = resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let ndjson = content_type.starts_with("application/x-ndjson");
+    if !ndjson && !content_type.starts_with("application/json") {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let bytes = match axum::body::to_bytes(body, limit).await {
+        Ok(b) => b,
+        Err(e) => {
+            let message = format!("reading response: {}; a filtered answer holds at most {} bytes, narrow the query", e, limit);
+            return (StatusCode::INTERNAL_SERVER_ERROR, message).into_response();
+        }
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(filter.apply_bytes(&bytes, ndjson)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_rounds_and_snaps() {
+        let f = ResponseFilter {
+            redact: vec!["battery".into(), "serial".into()],
+            round: [("temp".to_string(), 1)].into(),
+            coordinate_grid: 0.05,
+            ..Default::default()
+        };
+        let mut v = serde_json::json!({
+            "rows": [{"station_id": "A", "tags": {"serial": "x1"}, "fields": {"temp": 21.437, "battery": 3.7, "lat": 22.3193, "lon": 114.1694}}],
+            "aggregates": {"temp": {"mean": 20.06, "count": 4}},
+        });
+        f.apply(&mut v);
+        assert_eq!(
+            v,
+            serde_json::json!({
+                "rows": [{"station_id": "A", "tags": {}, "fields": {"temp": 21.4, "lat": 22.3, "lon": 114.15}}],
+                "aggregates": {"temp": {"mean": 20.1, "count": 4}},
+            })
+        );
+
+        let nd = f.apply_bytes(b"{\"battery\":1,\"temp\":1.26}\n\nnot json\n", true);
+        assert_eq!(String::from_utf8(nd).unwrap(), "{\"temp\":1.3}\n\nnot json\n");
+    }
+}
@@ -0,0 +1,60 @@
+use axum::{extract::Extension, http::StatusCode, Json};
+use std::sync::Arc;
+use crate::forecast::{self, Forecast, ForecastRequest};
+use crate::query::{Order, RangeQuery, ResolutionChoice};
+
+/// GET /api/v1/forecasters
+pub async fn list_handler(Extension(state): Extension<Arc<crate::AppState>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "forecasters": state.forecasters.names() }))
+}
+
+/// POST /api/v1/forecast
+///
+/// Resample the station's recent history of one field, run the named
+/// forecaster over it and (unless `store` is false) write the predictions to
+/// the station's forecast namespace.
+pub async fn run_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Json(req): Json<ForecastRequest>,
+) -> Result<Json<Forecast>, (StatusCode, String)> {
+    req.validate().map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if req.store {
+        if let Some(reason) = state.write_refusal() {
+            return Err((StatusCode::FORBIDDEN, reason.to_string()));
+        }
+    }
+    let Some(forecaster) = state.forecasters.get(&req.forecaster) else {
+        let known = state.forecasters.names().join(", ");
+        return Err((StatusCode::BAD_REQUEST, format!("unknown forecaster '{}', expected one of {}", req.forecaster, known)));
+    };
+    let internal = |e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e));
+    let Some(latest) = crate::query::latest(&state, &req.station_id).await.map_err(internal)? else {
+        return Err((StatusCode::NOT_FOUND, format!("no data for station {}", req.station_id)));
+    };
+    let end = latest.timestamp() + chrono::Duration::milliseconds(1);
+    let q = RangeQuery {
+        station_id: req.station_id.clone(),
+        start: Some(end - chrono::Duration::milliseconds(req.history_ms as i64)),
+        end: Some(end),
+        resolution: ResolutionChoice::Raw,
+        fields: vec![req.field.clone()],
+        aggregations: Vec::new(),
+        interval_secs: None,
+        tz: None,
+        fill: Default::default(),
+        units: Default::default(),
+        max_latency_ms: None,
+        order: Order::Asc,
+        limit: None,
+        forecast: false,
+    };
+    let history = crate::query::scan_raw(&state, &q).await.map_err(internal)?;
+
+    let mut result = forecast::predict(forecaster.as_ref(), &req, &history)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)))?;
+    if req.store && !result.points.is_empty() {
+        crate::ingest::append(&state, result.points.clone()).await.map_err(internal)?;
+        result.stored = true;
+    }
+    Ok(Json(result))
+}
@@ -1,60 +1,735 @@
-use axum::{routing::post, Router, Json, extract::Extension};
+use axum::{routing::{delete, get, post, put}, Router, Json, extract::{Extension, Query, Request}, http::{HeaderMap, StatusCode}};
+use axum::extract::DefaultBodyLimit;
+use axum::middleware::{self, Next};
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use super::auth::Scope;
+use crate::config::{ServerConfig, Surface};
+use crate::slo;
+use crate::storage::memtable::{FieldValue, Observation, Timestamp};
 use tokio::sync::broadcast::Sender as BroadcastSender;
+use tower_http::compression::CompressionLayer;
+use tower_http::decompression::RequestDecompressionLayer;
 
+/// A write: `tags` and `fields` hold any names, and `null` in `fields`
+/// records that the sensor reported no data. The original weather fields are
+/// still accepted at the top level, where an explicit `null` (`Some(None)`)
+/// is likewise kept apart from an omitted key (`None`).
 #[derive(Deserialize)]
 pub struct WriteRequest {
     pub station_id: String,
-    pub time: String,
-    pub temp: Option<f64>,
-    pub humidity: Option<f64>,
-    pub pressure: Option<f64>,
-    pub wind_speed: Option<f64>,
-    pub wind_dir: Option<u16>,
+    /// RFC3339; stored as epoch milliseconds.
+    #[serde(deserialize_with = "rfc3339")]
+    pub time: Timestamp,
+    #[serde(default)]
+    pub tags: std::collections::BTreeMap<String, String>,
+    #[serde(default)]
+    pub fields: std::collections::BTreeMap<String, FieldValue>,
+    #[serde(default, deserialize_with = "explicit")]
+    pub temp: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "explicit")]
+    pub humidity: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "explicit")]
+    pub pressure: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "explicit")]
+    pub wind_speed: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "explicit")]
+    pub wind_dir: Option<Option<u16>>,
+    /// Tags a [`SharedBatch`] gives all its readings, shared rather than
+    /// copied into each; `tags` wins when both name one.
+    #[serde(skip)]
+    pub shared_tags: Option<Arc<std::collections::BTreeMap<String, String>>>,
 }
 
-pub async fn run(state: Arc<crate::AppState>, shutdown: BroadcastSender<()>) {
-    let app = Router::new()
-        .route("/api/v1/write", post(write_handler))
-        .layer(Extension(state));
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-    println!("Listening on http://{}", addr);
-    let server = axum::Server::bind(&addr).serve(app.into_make_service());
-    let mut shutdown_sub = shutdown.subscribe();
-    let graceful = server.with_graceful_shutdown(async move {
-        let _ = shutdown_sub.recv().await;
+fn rfc3339<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Timestamp, D::Error> {
+    Timestamp::parse(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+}
+
+fn explicit<'de, D, T>(d: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(d).map(Some)
+}
+
+impl WriteRequest {
+    /// Top-level weather fields first, so `fields` wins when both name one.
+    pub fn to_observation(&self) -> Observation {
+        let mut obs = Observation::empty(&self.station_id, self.time);
+        obs.tags = self.shared_tags.as_deref().cloned().unwrap_or_default();
+        obs.tags.extend(self.tags.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let values = [
+            ("temp", self.temp),
+            ("humidity", self.humidity),
+            ("pressure", self.pressure),
+            ("wind_speed", self.wind_speed),
+            ("wind_dir", self.wind_dir.map(|v| v.map(f64::from))),
+        ];
+        for (name, v) in values {
+            match v {
+                Some(Some(v)) => obs.set_field(name, v),
+                Some(None) => obs.set_null(name),
+                None => {}
+            }
+        }
+        for (name, value) in &self.fields {
+            obs.set(name.as_str(), value.clone());
+        }
+        obs
+    }
+}
+
+/// API key sent as `Authorization: Bearer <key>` or `X-Api-Key: <key>`.
+pub(crate) fn request_token(headers: &HeaderMap) -> Option<String> {
+    if let Some(v) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(v.to_string());
+    }
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string())
+}
+
+/// A bound socket and whether it serves the public API, the admin endpoints, or both.
+pub struct Listener {
+    pub addr: SocketAddr,
+    socket: std::net::TcpListener,
+    api: bool,
+    admin: bool,
+}
+
+/// Bind every configured address up front so a taken port fails startup.
+/// Sockets are plain std listeners so they can be served from any runtime.
+pub fn bind(cfg: &crate::config::ServerConfig) -> anyhow::Result<Vec<Listener>> {
+    let separate_admin = !cfg.admin_bind.is_empty();
+    let mut listeners = Vec::new();
+    let addrs = cfg.bind.iter().map(|a| (*a, true, !separate_admin));
+    for (addr, api, admin) in addrs.chain(cfg.admin_bind.iter().map(|a| (*a, false, true))) {
+        let socket = std::net::TcpListener::bind(addr).with_context(|| format!("failed to bind {}", addr))?;
+        socket.set_nonblocking(true)?;
+        listeners.push(Listener { addr: socket.local_addr()?, socket, api, admin });
+    }
+    Ok(listeners)
+}
+
+/// Apply route layers with `f`, unless every route of `router` is disabled
+/// (axum refuses route layers on a router without routes).
+fn layered(router: Router, f: impl FnOnce(Router) -> Router) -> Router {
+    if router.has_routes() {
+        f(router)
+    } else {
+        router
+    }
+}
+
+/// Gate every route of `router` on an API key with `scope`.
+fn scoped(router: Router, scope: Scope) -> Router {
+    layered(router, |r| r.route_layer(middleware::from_fn(move |req: Request, next: Next| super::auth::require(scope, req, next))))
+}
+
+/// `router` with `add`'s routes when `surface` is enabled.
+fn surface(router: Router, server: &ServerConfig, surface: Surface, add: impl FnOnce(Router) -> Router) -> Router {
+    if server.enabled(surface) {
+        add(router)
+    } else {
+        router
+    }
+}
+
+/// The public API under `base`: `/api/v1` for the server's own data,
+/// `/api/v1/db/<name>` for a namespace.
+fn api_routes(server: &ServerConfig, base: &str) -> Router {
+    let at = |path: &str| format!("{}{}", base, path);
+    let write = surface(Router::new(), server, Surface::Write, |r| {
+        r.route(&at("/write"), post(write_handler)).route(&at("/write/batch"), post(batch_write_handler))
+    });
+    let write = surface(write, server, Surface::Influx, |r| r.route(&at("/write/influx"), post(influx_write_handler)));
+    let write = surface(write, server, Surface::PromWrite, |r| r.route(&at("/prom/write"), post(prom_write_handler)));
+    let write = surface(write, server, Surface::Forecast, |r| r.route(&at("/forecast"), post(super::forecast::run_handler)));
+    let write = surface(write, server, Surface::Delete, |r| r.route(&at("/series"), delete(delete_series_handler)));
+    let write = surface(write, server, Surface::Stations, |r| {
+        r.route(&at("/stations/:id"), put(super::stations::put_handler).delete(super::stations::delete_handler))
+    });
+    let read = surface(Router::new(), server, Surface::Query, |r| {
+        r.route(&at("/query"), get(super::query::query_handler)).route(&at("/sql"), get(super::sql::get_handler).post(super::sql::post_handler))
+    });
+    let read = surface(read, server, Surface::Promql, |r| {
+        r.route(&at("/prom/api/v1/query"), get(super::prom::instant_handler).post(super::prom::instant_handler))
+            .route(&at("/prom/api/v1/query_range"), get(super::prom::range_handler).post(super::prom::range_handler))
+            .route(&at("/prom/api/v1/labels"), get(super::prom::labels_handler).post(super::prom::labels_handler))
+            .route(&at("/prom/api/v1/label/:name/values"), get(super::prom::label_values_handler))
+    });
+    let read = surface(read, server, Surface::Stations, |r| {
+        r.route(&at("/stations"), get(super::stations::list_handler))
+            .route(&at("/stations/:id"), get(super::stations::get_handler))
+            .route(&at("/stations/:id/latest"), get(super::stations::latest_handler))
+            .route(&at("/stations/:id/tail"), get(super::stations::tail_handler))
+    });
+    let read = surface(read, server, Surface::Forecast, |r| r.route(&at("/forecasters"), get(super::forecast::list_handler)));
+    let read = surface(read, server, Surface::Export, |r| {
+        r.route(&at("/export/jobs"), get(super::export::list_handler).post(super::export::create_handler))
+            .route(&at("/export/jobs/:id"), get(super::export::get_handler).delete(super::export::delete_handler))
+            .route(&at("/export/jobs/:id/parts/:index"), get(super::export::part_handler))
     });
-    if let Err(e) = graceful.await {
-        eprintln!("server error: {}", e);
+    // long-lived, so kept out of the query latency SLO
+    let live = surface(Router::new(), server, Surface::Subscribe, |r| {
+        r.route(&at("/subscribe"), get(super::subscribe::subscribe_handler))
+            .route(&at("/stations/:id/events"), get(super::events::events_handler))
+    });
+    let write = layered(write, |w| {
+        w.route_layer(middleware::from_fn(|req: Request, next: Next| slo::track(slo::Kind::Write, req, next)))
+            .route_layer(middleware::from_fn(super::rate_limit::limit))
+    });
+    // bulk imports run for minutes, so they stay out of the write latency SLO
+    let import = surface(Router::new(), server, Surface::Import, |r| r.route(&at("/import/csv"), post(super::import::csv_handler)));
+    let import = layered(import, |i| i.route_layer(middleware::from_fn(super::rate_limit::limit)));
+    let write = write.merge(import);
+    let read = layered(read, |r| r.route_layer(middleware::from_fn(|req: Request, next: Next| slo::track(slo::Kind::Query, req, next))));
+    let read = scoped(read, Scope::Read).merge(scoped(live, Scope::Read));
+    let limit = usize::try_from(server.max_body_bytes).unwrap_or(usize::MAX);
+    layered(scoped(write, Scope::Write).merge(read), |r| {
+        r.route_layer(middleware::from_fn(move |req: Request, next: Next| super::filter::filter(limit, req, next)))
+    })
+}
+
+fn admin_routes(server: &ServerConfig) -> Router {
+    let metrics = surface(Router::new(), server, Surface::Metrics, |r| r.route("/metrics", get(metrics_handler)));
+    let admin = surface(Router::new(), server, Surface::Admin, |r| {
+        r.route("/api/v1/admin/manifest", get(super::admin::manifest_handler))
+            .route("/api/v1/admin/diff", post(super::admin::diff_handler))
+            .route("/api/v1/admin/retention", get(super::admin::retention_handler))
+            .route("/api/v1/admin/tenants", get(super::admin::tenants_handler))
+            .route("/api/v1/admin/alerts", get(super::admin::alerts_handler))
+            .route("/api/v1/admin/rebuild", get(super::admin::rebuild_status_handler).post(super::admin::rebuild_handler))
+            .route("/api/v1/admin/relocate", post(super::admin::relocate_handler))
+            .route("/api/v1/admin/snapshot", post(super::admin::snapshot_handler))
+            .route("/api/v1/admin/stats", get(super::admin::stats_handler))
+            .route("/api/v1/admin/debug/state", get(super::admin::debug_state_handler))
+            .route("/api/v1/admin/mirror", get(super::admin::mirror_status_handler))
+            .route("/api/v1/admin/mirror/apply", post(super::admin::mirror_apply_handler))
+            .route("/api/v1/admin/mirror/promote", post(super::admin::mirror_promote_handler))
+            .route("/api/v1/admin/mirror/demote", post(super::admin::mirror_demote_handler))
+            .route("/api/v1/admin/replication", get(super::admin::replication_status_handler))
+            .route("/api/v1/admin/replication/wal", get(super::admin::replication_wal_handler))
+    });
+    scoped(metrics, Scope::Read).merge(scoped(admin, Scope::Admin))
+}
+
+fn app(state: Arc<crate::AppState>, api: bool, admin: bool) -> Router {
+    let server = &state.config.server;
+    let mut app = Router::new();
+    if api {
+        app = app.merge(api_routes(server, "/api/v1"));
+        // a namespace's routes see its own state; the inner extension wins
+        for (name, ns) in &state.namespaces {
+            app = app.merge(api_routes(server, &format!("/api/v1/db/{}", name)).layer(Extension(ns.clone())));
+        }
+    }
+    if admin {
+        app = app.merge(admin_routes(server));
+    }
+    // gzip/zstd request bodies are inflated before the handlers see them;
+    // other encodings (snappy for Prometheus remote write) pass through. The
+    // body limit counts inflated bytes, so a small bomb stops at the limit.
+    let limit = usize::try_from(server.max_body_bytes).unwrap_or(usize::MAX);
+    app.layer(DefaultBodyLimit::max(limit))
+        .layer(Extension(state))
+        .layer(CompressionLayer::new())
+        .layer(RequestDecompressionLayer::new().pass_through_unaccepted(true))
+}
+
+/// The full HTTP API (public and admin routes) over `state`, for embedding in
+/// an existing axum application with `Router::merge` or `nest`.
+pub fn router(state: Arc<crate::AppState>) -> Router {
+    app(state, true, true)
+}
+
+/// Serve every listener until shutdown is signalled, over HTTPS when `tls` is set.
+pub async fn run(
+    state: Arc<crate::AppState>,
+    listeners: Vec<Listener>,
+    tls: Option<RustlsConfig>,
+    shutdown: BroadcastSender<()>,
+) {
+    let mut servers = tokio::task::JoinSet::new();
+    let disabled = &state.config.server.disable;
+    if !disabled.is_empty() {
+        tracing::info!("API surfaces disabled: {:?}", disabled);
+    }
+    for l in listeners {
+        let app = app(state.clone(), l.api, l.admin);
+        let kind = if !l.api { "admin" } else if l.admin { "api+admin" } else { "api" };
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        tracing::info!("listening on {}://{} ({})", scheme, l.addr, kind);
+        let mut shutdown_sub = shutdown.subscribe();
+        let addr = l.addr;
+        match tls.clone() {
+            Some(rustls) => {
+                let handle = axum_server::Handle::new();
+                let server = axum_server::from_tcp_rustls(l.socket, rustls).handle(handle.clone());
+                servers.spawn(async move {
+                    tokio::spawn(async move {
+                        let _ = shutdown_sub.recv().await;
+                        handle.graceful_shutdown(None);
+                    });
+                    if let Err(e) = server.serve(app.into_make_service_with_connect_info::<SocketAddr>()).await {
+                        tracing::error!("server error on {}: {}", addr, e);
+                    }
+                });
+            }
+            None => {
+                let listener = match tokio::net::TcpListener::from_std(l.socket) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        tracing::error!("failed to listen on {}: {}", l.addr, e);
+                        continue;
+                    }
+                };
+                servers.spawn(async move {
+                    let graceful = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(async move {
+                        let _ = shutdown_sub.recv().await;
+                    });
+                    if let Err(e) = graceful.await {
+                        tracing::error!("server error on {}: {}", addr, e);
+                    }
+                });
+            }
+        }
     }
+    while servers.join_next().await.is_some() {}
 }
 
+/// GET /metrics (Prometheus text format)
+async fn metrics_handler(Extension(state): Extension<Arc<crate::AppState>>) -> impl axum::response::IntoResponse {
+    let mut body = state.metrics.render(&state.metrics_snapshot().await);
+    state.slo.render(&mut body);
+    crate::health::render(&mut body, &state.health);
+    if let Some(qc) = &state.qc {
+        qc.render(&mut body);
+    }
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Current WAL durability policy, reported with JSON write responses so
+/// clients know what an acknowledgement guarantees: `always` means the write
+/// was synced to disk, `interval(<ms>)` that it will be within that time, and
+/// `never` that it survives a process crash but maybe not a power failure.
+fn durability(state: &crate::AppState) -> String {
+    state.wal.as_ref().map(|w| w.durability().to_string()).unwrap_or_default()
+}
+
+/// POST /api/v1/write
 async fn write_handler(
     Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
     Json(payload): Json<WriteRequest>,
-) -> Json<serde_json::Value> {
-    // serialize payload to JSON line for WAL
-    let obs = crate::storage::memtable::Observation {
-        station_id: payload.station_id.clone(),
-        time: payload.time.clone(),
-        temp: payload.temp,
-        humidity: payload.humidity,
-        pressure: payload.pressure,
-        wind_speed: payload.wind_speed,
-        wind_dir: payload.wind_dir,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let Some(reason) = state.write_refusal() {
+        return Err((StatusCode::FORBIDDEN, reason.to_string()));
+    }
+
+    let obs = payload.to_observation();
+
+    let meta = crate::ingest::routing::RouteMeta { token: request_token(&headers), tags: obs.tags.clone() };
+    let (admitted, rejected) = crate::ingest::admit(&state, vec![obs], &meta).await;
+    if let Some((_, reason)) = rejected.into_iter().next() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, reason));
+    }
+    crate::ingest::append(&state, admitted)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(serde_json::json!({"status": "ok", "durability": durability(&state)})))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteParams {
+    pub station_id: String,
+    /// RFC3339 or relative such as `now-1d`; open when omitted.
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+/// DELETE /api/v1/series?station_id=...&start=...&end=...
+///
+/// Buffered rows go at once; rows already flushed are hidden from reads and
+/// removed from the chunk files by the background purge.
+async fn delete_series_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Query(params): Query<DeleteParams>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let Some(reason) = state.write_refusal() {
+        return Err((StatusCode::FORBIDDEN, reason.to_string()));
+    }
+    let now = chrono::Utc::now();
+    let bound = |t: &Option<String>, name: &str| match t {
+        Some(t) => crate::query::parse_time_at(t, now)
+            .map(|t| Some(Timestamp::from_datetime(t)))
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid {}: {}", name, e))),
+        None => Ok(None),
     };
+    let (start, end) = (bound(&params.start, "start")?, bound(&params.end, "end")?);
+    crate::delete::validate(&params.station_id, start, end).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let deleted = crate::delete::delete(&state, &params.station_id, start, end)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(serde_json::json!({
+        "status": "ok",
+        "removed_buffered": deleted.removed_buffered,
+        "tombstone": deleted.tombstone,
+    })))
+}
+
+/// A batch item's line (or array position) and what it parsed to.
+pub type BatchItem = (usize, Result<WriteRequest, String>);
+
+/// One station's readings with the metadata given once: `tags` apply to
+/// every reading and `units` (unit per field) are kept as `unit.<field>`
+/// tags. Readings are `{"time", "fields"}` objects or `[time, fields]` pairs,
+/// each parsed on its own so a bad one is rejected alone.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SharedBatch {
+    station_id: String,
+    #[serde(default)]
+    tags: std::collections::BTreeMap<String, String>,
+    #[serde(default)]
+    units: std::collections::BTreeMap<String, String>,
+    readings: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Reading {
+    Pair(String, std::collections::BTreeMap<String, FieldValue>),
+    Entry {
+        time: String,
+        #[serde(default)]
+        fields: std::collections::BTreeMap<String, FieldValue>,
+    },
+}
+
+impl SharedBatch {
+    /// Items numbered by position in `readings`.
+    fn into_items(self) -> Vec<BatchItem> {
+        let mut tags = self.tags;
+        tags.extend(self.units.into_iter().map(|(field, unit)| (format!("unit.{}", field), unit)));
+        let tags = Arc::new(tags);
+        self.readings
+            .into_iter()
+            .enumerate()
+            .map(|(i, reading)| {
+                let req = match serde_json::from_value(reading) {
+                    Ok(Reading::Pair(time, fields) | Reading::Entry { time, fields }) => {
+                        Timestamp::parse(&time).map_err(|e| e.to_string()).map(|time| WriteRequest {
+                            station_id: self.station_id.clone(),
+                            time,
+                            tags: Default::default(),
+                            fields,
+                            temp: None,
+                            humidity: None,
+                            pressure: None,
+                            wind_speed: None,
+                            wind_dir: None,
+                            shared_tags: Some(tags.clone()),
+                        })
+                    }
+                    Err(_) => Err("a reading is a [time, fields] pair or a {\"time\", \"fields\"} object".to_string()),
+                };
+                (i + 1, req)
+            })
+            .collect()
+    }
+}
 
-    if let Ok(line) = serde_json::to_vec(&obs) {
-        let _ = state.wal.append(&line).await;
+/// Split a batch body into numbered items: a JSON array (numbered by
+/// position), a [`SharedBatch`] object (numbered by reading), or NDJSON with
+/// one request per line (numbered by line, blank lines skipped). A leading
+/// byte order mark and CR line endings are tolerated. Each item parses
+/// independently so one bad entry doesn't sink the batch.
+pub fn parse_batch(body: &[u8]) -> Result<Vec<BatchItem>, String> {
+    let body = body.strip_prefix(b"\xEF\xBB\xBF".as_slice()).unwrap_or(body);
+    let trimmed = body.trim_ascii_start();
+    // a single-line NDJSON body is an object too, but has no `readings`
+    if trimmed.first() == Some(&b'{') {
+        if let Ok(shared) = serde_json::from_slice::<SharedBatch>(trimmed) {
+            return Ok(shared.into_items());
+        }
+    }
+    if trimmed.first() == Some(&b'[') {
+        let items: Vec<serde_json::Value> = serde_json::from_slice(trimmed).map_err(|e| e.to_string())?;
+        return Ok(items
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i + 1, serde_json::from_value(v).map_err(|e| e.to_string())))
+            .collect());
     }
+    Ok(body
+        .split(|b| *b == b'\n')
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_ascii()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| (n, serde_json::from_slice(line).map_err(|e| e.to_string())))
+        .collect())
+}
 
-    // insert into MemTable
-    {
-        let mut mt = state.memtable.lock().await;
-        mt.insert(obs);
+/// POST /api/v1/write/batch
+///
+/// Accepted items are written to the WAL and MemTable together. The response
+/// has a result per item (`accepted`, `rejected` with the reason, or
+/// `duplicate` when the same station and time was accepted earlier in the batch or
+/// is already buffered) so a gateway can resubmit just the rejected lines.
+async fn batch_write_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let Some(reason) = state.write_refusal() {
+        return Err((StatusCode::FORBIDDEN, reason.to_string()));
     }
+    let items = parse_batch(&body).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let token = request_token(&headers);
+
+    // station -> times already taken, from the MemTable and earlier admitted items
+    let mut seen: HashMap<String, HashSet<Timestamp>> = HashMap::new();
+    let mut admitted = Vec::with_capacity(items.len());
+    let mut results = Vec::with_capacity(items.len());
+    let mut errors = Vec::new();
+    let mut duplicates = 0;
+    for (i, (line, item)) in items.into_iter().enumerate() {
+        let req = match item {
+            Ok(r) => r,
+            Err(e) => {
+                results.push(serde_json::json!({"line": line, "status": "rejected", "error": e}));
+                errors.push(serde_json::json!({"index": i, "line": line, "error": e}));
+                continue;
+            }
+        };
+        let obs = req.to_observation();
+        let meta = crate::ingest::routing::RouteMeta { token: token.clone(), tags: obs.tags.clone() };
+        let (ok, rejected) = crate::ingest::admit(&state, vec![obs], &meta).await;
+        if let Some((_, reason)) = rejected.into_iter().next() {
+            results.push(serde_json::json!({"line": line, "status": "rejected", "error": reason}));
+            errors.push(serde_json::json!({"index": i, "line": line, "error": reason}));
+            continue;
+        }
+        for o in ok {
+            if !seen.contains_key(&o.station_id) {
+                let mt = state.memtable.lock().await;
+                let times = mt.buffer.get(&o.station_id).map(|b| b.iter().map(|o| o.time).collect());
+                seen.insert(o.station_id.clone(), times.unwrap_or_default());
+            }
+            if !seen.entry(o.station_id.clone()).or_default().insert(o.time) {
+                duplicates += 1;
+                results.push(serde_json::json!({"line": line, "status": "duplicate"}));
+                continue;
+            }
+            admitted.push(o);
+            results.push(serde_json::json!({"line": line, "status": "accepted"}));
+        }
+    }
+
+    let accepted = crate::ingest::append(&state, admitted)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(serde_json::json!({
+        "status": if errors.is_empty() { "ok" } else { "partial" },
+        "accepted": accepted,
+        "rejected": errors.len(),
+        "duplicates": duplicates,
+        "errors": errors,
+        "results": results,
+        "durability": durability(&state),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct InfluxParams {
+    /// Timestamp precision: ns (default), us, ms or s.
+    pub precision: Option<String>,
+}
 
-    Json(serde_json::json!({"status": "ok"}))
+/// POST /api/v1/write/influx
+///
+/// Like InfluxDB, valid lines are written even when others are rejected;
+/// the response is 204 when everything was accepted and 400 otherwise.
+async fn influx_write_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<InfluxParams>,
+    body: String,
+) -> Result<StatusCode, (StatusCode, Json<serde_json::Value>)> {
+    use crate::ingest::line_protocol::{self, Precision};
+    let fail = |status: StatusCode, msg: String| (status, Json(serde_json::json!({"code": "invalid", "message": msg})));
+    if let Some(reason) = state.write_refusal() {
+        return Err(fail(StatusCode::FORBIDDEN, reason.to_string()));
+    }
+    let precision = match params.precision.as_deref() {
+        Some(p) => Precision::parse(p).map_err(|e| fail(StatusCode::BAD_REQUEST, e.to_string()))?,
+        None => Precision::default(),
+    };
+    let now = chrono::Utc::now();
+    let mut errors = Vec::new();
+    let mut points = Vec::new();
+    for (line, parsed) in line_protocol::parse(&body) {
+        match parsed.and_then(|p| line_protocol::to_observation(&p, precision, now)) {
+            Ok(p) => points.push(p),
+            Err(e) => errors.push(format!("line {}: {}", line, e)),
+        }
+    }
+
+    let (accepted, rejected) = crate::ingest::write_tagged(&state, request_token(&headers), points)
+        .await
+        .map_err(|e| fail(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    errors.extend(rejected);
+
+    if errors.is_empty() {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(fail(
+            StatusCode::BAD_REQUEST,
+            format!("partial write: {} accepted, {} rejected; {}", accepted, errors.len(), errors.join("; ")),
+        ))
+    }
+}
+
+/// POST /api/v1/prom/write (Prometheus remote_write)
+///
+/// Returns 204 on success. Tenant rejections answer 400 so Prometheus drops
+/// the batch instead of retrying it forever; storage errors answer 500.
+async fn prom_write_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    use crate::ingest::prom_remote;
+    if let Some(reason) = state.write_refusal() {
+        return Err((StatusCode::FORBIDDEN, reason.to_string()));
+    }
+    let req = prom_remote::decode(&body).map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+    let (points, _skipped) = prom_remote::to_observations(&req);
+    let (_, errors) = crate::ingest::write_tagged(&state, request_token(&headers), points)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if errors.is_empty() {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::BAD_REQUEST, errors.join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_array_and_ndjson_batches() {
+        let arr = br#" [{"station_id":"A","time":"2025-01-01T08:00:00+08:00","temp":1.0}, {"time":"2025-01-01T00:00:00Z"}, {"station_id":"A","time":"noon"}]"#;
+        let items = parse_batch(arr).unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].1.as_ref().unwrap().time.to_string(), "2025-01-01T00:00:00Z");
+        assert_eq!(items[1].0, 2);
+        assert!(items[1].1.is_err());
+        assert!(matches!(&items[2].1, Err(e) if e.contains("invalid time 'noon'")));
+
+        let nd = b"\xEF\xBB\xBF{\"station_id\":\"A\",\"time\":\"2025-01-01T00:00:00Z\"}\r\n\r\n{\"station_id\":\"B\",\"time\":\"2025-01-01T00:00:00Z\",\"temp\":null}\n";
+        let items = parse_batch(nd).unwrap();
+        // numbered by line, so the blank line 2 leaves a gap
+        assert_eq!(items.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![1, 3]);
+        assert!(items.iter().all(|(_, r)| r.is_ok()));
+
+        assert!(parse_batch(b"[1, 2").is_err());
+    }
+
+    #[tokio::test]
+    async fn disabled_surfaces_are_left_out_of_the_router() {
+        let dir = crate::test_util::TempDir::new("surfaces");
+        let mut cfg = crate::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        cfg.server.disable = "admin, export,metrics".split(',').map(|s| s.parse().unwrap()).collect();
+        let state = Arc::new(crate::AppState::open(&cfg).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let status = |path: &str| {
+            let url = format!("{}{}", base, path);
+            async move { reqwest::get(url).await.unwrap().status().as_u16() }
+        };
+        assert_eq!(status("/api/v1/stations").await, 200);
+        assert_eq!(status("/api/v1/export/jobs").await, 404);
+        assert_eq!(status("/api/v1/admin/manifest").await, 404);
+        assert_eq!(status("/metrics").await, 404);
+        assert!("websocket".parse::<Surface>().is_err());
+    }
+
+    #[tokio::test]
+    async fn limits_inflated_request_bodies() {
+        let dir = crate::test_util::TempDir::new("body-limit");
+        let mut cfg = crate::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        cfg.server.max_body_bytes = 64 * 1024;
+        let state = Arc::new(crate::AppState::open(&cfg).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/write/batch", listener.local_addr().unwrap());
+        let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let post = |body: Vec<u8>| {
+            let request = reqwest::Client::new().post(&url).header("Content-Encoding", "zstd").body(zstd::encode_all(&body[..], 3).unwrap()).send();
+            async move { request.await.unwrap().status().as_u16() }
+        };
+        let line = br#"{"station_id": "A", "time": "2025-01-01T00:00:00Z", "fields": {"temp": 1.5}}"#.to_vec();
+        assert_eq!(post(line).await, 200);
+        // a few hundred compressed bytes that inflate to a megabyte
+        assert_eq!(post(vec![b' '; 1024 * 1024]).await, 413);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_reading_does_not_shadow_a_later_one() {
+        let dir = crate::test_util::TempDir::new("batch-dup-routing");
+        let mut cfg = crate::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        cfg.routing = Some(
+            serde_json::from_str(r#"{"tenants": [{"name": "t", "validation": {"ranges": {"temp": {"min": -20, "max": 50}}}}], "default_tenant": "t"}"#)
+                .unwrap(),
+        );
+        let state = Arc::new(crate::AppState::open(&cfg).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/write/batch", listener.local_addr().unwrap());
+        let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let body = br#"{"station_id": "A", "time": "2025-01-01T00:00:00Z", "fields": {"temp": 80}}
+{"station_id": "A", "time": "2025-01-01T00:00:00Z", "fields": {"temp": 21}}
+{"station_id": "A", "time": "2025-01-01T00:00:00Z", "fields": {"temp": 22}}"#;
+        let reply: serde_json::Value = reqwest::Client::new().post(&url).body(&body[..]).send().await.unwrap().json().await.unwrap();
+        let statuses: Vec<_> = reply["results"].as_array().unwrap().iter().map(|r| r["status"].as_str().unwrap().to_string()).collect();
+        assert_eq!(statuses, ["rejected", "accepted", "duplicate"]);
+        assert_eq!((reply["accepted"].as_u64(), reply["duplicates"].as_u64()), (Some(1), Some(1)));
+    }
+
+    #[test]
+    fn parses_shared_metadata_batches() {
+        let body = br#"{"station_id": "A", "tags": {"site": "roof"}, "units": {"temp": "C"},
+            "readings": [["2025-01-01T00:00:00Z", {"temp": 1.5}], {"time": "2025-01-01T00:01:00Z", "fields": {"temp": null}},
+                         ["noon", {}], ["2025-01-01T00:02:00Z", {"temp": [1]}], 7]}"#;
+        let items = parse_batch(body).unwrap();
+        // bad readings are rejected one by one rather than sinking the body
+        assert_eq!(items.iter().map(|(n, r)| (*n, r.is_ok())).collect::<Vec<_>>(), vec![(1, true), (2, true), (3, false), (4, false), (5, false)]);
+        let obs = items[0].1.as_ref().unwrap().to_observation();
+        assert_eq!((obs.station_id.as_str(), obs.number("temp")), ("A", Some(1.5)));
+        assert_eq!(obs.tags.get("unit.temp").map(String::as_str), Some("C"));
+        assert_eq!(obs.tags.get("site").map(String::as_str), Some("roof"));
+        assert_eq!(items[1].1.as_ref().unwrap().fields.get("temp"), Some(&FieldValue::Null));
+        assert!(items[2].1.is_err());
+
+        // a lone NDJSON line is still a single write
+        let items = parse_batch(br#"{"station_id":"A","time":"2025-01-01T00:00:00Z"}"#).unwrap();
+        assert_eq!(items.len(), 1);
+    }
 }
@@ -0,0 +1,108 @@
+// CSV import over HTTP: the query string carries the column mapping and the
+// request body is fed to `ingest::import` chunk by chunk as it arrives, so
+// the body limit of the other endpoints does not apply.
+
+use axum::{
+    body::Body,
+    extract::{Extension, Query},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+use crate::ingest::import::{self, CsvImport, CsvMapping, ImportError, ImportReport};
+
+#[derive(Deserialize)]
+pub struct CsvParams {
+    /// Station of rows without a station column.
+    pub station_id: Option<String>,
+    /// Column holding the station id; `station_id` by default.
+    pub station_column: Option<String>,
+    /// Column holding the time; `time` by default.
+    pub time_column: Option<String>,
+    /// chrono format such as `%Y-%m-%d %H:%M` (UTC), `unix` or `unix_ms`; RFC3339 by default.
+    pub time_format: Option<String>,
+    /// Comma-separated `column:field` renames.
+    pub fields: Option<String>,
+    /// Comma-separated columns stored as tags.
+    pub tags: Option<String>,
+    /// Comma-separated columns left out.
+    pub skip: Option<String>,
+    /// One character, or `tab`; `,` by default.
+    pub delimiter: Option<String>,
+}
+
+fn list(s: Option<&str>) -> Vec<String> {
+    s.into_iter().flat_map(|s| s.split(',')).map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+impl CsvParams {
+    fn mapping(self) -> anyhow::Result<CsvMapping> {
+        Ok(CsvMapping {
+            fields: self.fields.as_deref().map(CsvMapping::parse_fields).transpose()?.unwrap_or_default(),
+            tags: list(self.tags.as_deref()),
+            skip: list(self.skip.as_deref()),
+            delimiter: self.delimiter.as_deref().map(import::parse_delimiter).transpose()?,
+            station_id: self.station_id,
+            station_column: self.station_column,
+            time_column: self.time_column,
+            time_format: self.time_format,
+        })
+    }
+}
+
+fn status(e: ImportError) -> (StatusCode, String) {
+    match e {
+        ImportError::Invalid(msg) => (StatusCode::BAD_REQUEST, msg),
+        ImportError::Failed(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+    }
+}
+
+/// POST /api/v1/import/csv
+///
+/// Streams the CSV body into the WAL in batches; answers with the rows
+/// accepted and the line and reason of each rejected one.
+pub async fn csv_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<CsvParams>,
+    body: Body,
+) -> Result<Json<ImportReport>, (StatusCode, String)> {
+    if let Some(reason) = state.write_refusal() {
+        return Err((StatusCode::FORBIDDEN, reason.to_string()));
+    }
+    let mapping = params.mapping().map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let mut import = CsvImport::new(&state, mapping, super::http::request_token(&headers));
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, format!("reading body: {}", e)))?;
+        import.feed(&chunk).await.map_err(status)?;
+    }
+    import.finish().await.map(Json).map_err(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn imports_a_posted_csv() {
+        let dir = crate::test_util::TempDir::new("import-api");
+        let state = Arc::new(crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/import/csv", listener.local_addr().unwrap());
+        let app = super::super::http::router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let resp = client.post(format!("{}?time_format=unix&fields=t:temp", url)).body("station_id\ttime\tt\nA\t1735689600\t1.5\n").send().await.unwrap();
+        assert_eq!(resp.status(), 400);
+        let resp = client.post(format!("{}?time_format=unix&fields=t:temp&delimiter=tab", url)).body("station_id\ttime\tt\nA\t1735689600\t1.5\nB\t1735689600\tx\n").send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let report: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!((report["accepted"].as_u64(), report["rejected"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(state.memtable.lock().await.buffer["A"][0].number("temp"), Some(1.5));
+    }
+}
@@ -1 +1,15 @@
 pub mod http;
+pub mod admin;
+pub mod query;
+pub mod stations;
+pub mod tls;
+pub mod auth;
+pub mod rate_limit;
+pub mod forecast;
+pub mod filter;
+pub mod export;
+pub mod subscribe;
+pub mod events;
+pub mod import;
+pub mod sql;
+pub mod prom;
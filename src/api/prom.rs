@@ -0,0 +1,180 @@
+// The Prometheus HTTP query API over `query::promql`, mounted under
+// `/api/v1/prom` so that Grafana's Prometheus data source can use that URL
+// as its server. Parameters come from the query string or a form body, and
+// answers and errors take the shape Prometheus gives them.
+
+use axum::{
+    extract::{Extension, Form, Path},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+use crate::query::{self, promql::{self, Expr, Series, Value}};
+
+type PromResult = Result<Json<JsonValue>, (StatusCode, Json<JsonValue>)>;
+
+fn error(status: StatusCode, kind: &str, e: anyhow::Error) -> (StatusCode, Json<JsonValue>) {
+    (status, Json(json!({ "status": "error", "errorType": kind, "error": format!("{:#}", e) })))
+}
+
+fn bad_data(e: anyhow::Error) -> (StatusCode, Json<JsonValue>) {
+    error(StatusCode::BAD_REQUEST, "bad_data", e)
+}
+
+fn success(data: JsonValue) -> PromResult {
+    Ok(Json(json!({ "status": "success", "data": data })))
+}
+
+/// Unix seconds, fractional or not, RFC3339 or relative to `now`; in milliseconds.
+fn parse_time(s: &str, now: DateTime<Utc>) -> anyhow::Result<i64> {
+    match s.parse::<f64>() {
+        Ok(secs) => Ok((secs * 1000.0).round() as i64),
+        Err(_) => Ok(query::parse_time_at(s, now).map_err(|e| anyhow::anyhow!("invalid time '{}': {}", s, e))?.timestamp_millis()),
+    }
+}
+
+fn sample(t: i64, v: f64) -> JsonValue {
+    json!([t as f64 / 1000.0, v.to_string()])
+}
+
+fn series_json(s: Series) -> JsonValue {
+    json!({ "metric": s.labels, "values": s.points.into_iter().map(|(t, v)| sample(t, v)).collect::<Vec<_>>() })
+}
+
+#[derive(Deserialize)]
+pub struct InstantParams {
+    pub query: String,
+    /// Evaluation time; now by default.
+    pub time: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RangeParams {
+    pub query: String,
+    pub start: String,
+    pub end: String,
+    /// Seconds, or a duration such as `1m`.
+    pub step: String,
+}
+
+async fn evaluate(state: Arc<crate::AppState>, headers: &HeaderMap, q: &str, start: i64, end: i64, step: i64) -> Result<Value, (StatusCode, Json<JsonValue>)> {
+    let expr = Expr::parse(q).map_err(bad_data)?;
+    let filter = state.auth.filter(super::http::request_token(headers).as_deref()).cloned();
+    let rt = state.runtimes.query.clone();
+    let run = async move { promql::evaluate(&state, &expr, start, end, step, filter.as_ref()).await };
+    query::isolate(&rt, "promql query", run).await.map_err(|e| {
+        if e.is::<promql::TooManyRows>() {
+            bad_data(e)
+        } else {
+            error(StatusCode::UNPROCESSABLE_ENTITY, "execution", e)
+        }
+    })
+}
+
+/// GET or POST /api/v1/prom/api/v1/query
+pub async fn instant_handler(Extension(state): Extension<Arc<crate::AppState>>, headers: HeaderMap, Form(params): Form<InstantParams>) -> PromResult {
+    let now = Utc::now();
+    let t = match params.time.as_deref() {
+        Some(t) => parse_time(t, now).map_err(bad_data)?,
+        None => now.timestamp_millis(),
+    };
+    let data = match evaluate(state, &headers, &params.query, t, t, 1).await? {
+        Value::Scalar(v) => json!({ "resultType": "scalar", "result": sample(t, v) }),
+        Value::Vector(series) => {
+            let result: Vec<JsonValue> = series
+                .into_iter()
+                .filter_map(|s| s.points.last().map(|&(t, v)| json!({ "metric": s.labels, "value": sample(t, v) })))
+                .collect();
+            json!({ "resultType": "vector", "result": result })
+        }
+        Value::Matrix(series) => json!({ "resultType": "matrix", "result": series.into_iter().map(series_json).collect::<Vec<_>>() }),
+    };
+    success(data)
+}
+
+/// GET or POST /api/v1/prom/api/v1/query_range
+pub async fn range_handler(Extension(state): Extension<Arc<crate::AppState>>, headers: HeaderMap, Form(params): Form<RangeParams>) -> PromResult {
+    let now = Utc::now();
+    let start = parse_time(&params.start, now).map_err(bad_data)?;
+    let end = parse_time(&params.end, now).map_err(bad_data)?;
+    let step = crate::units::parse_duration(&params.step).map_err(bad_data)?.as_millis() as i64;
+    let result: Vec<JsonValue> = match evaluate(state, &headers, &params.query, start, end, step).await? {
+        Value::Scalar(v) => {
+            let points = (0..).map(|i| start + i * step).take_while(|t| *t <= end).map(|t| (t, v)).collect();
+            vec![series_json(Series { labels: Default::default(), points })]
+        }
+        Value::Vector(series) | Value::Matrix(series) => series.into_iter().map(series_json).collect(),
+    };
+    success(json!({ "resultType": "matrix", "result": result }))
+}
+
+/// GET or POST /api/v1/prom/api/v1/labels
+pub async fn labels_handler(Extension(state): Extension<Arc<crate::AppState>>, headers: HeaderMap) -> PromResult {
+    let filter = state.auth.filter(super::http::request_token(&headers).as_deref());
+    let names = promql::label_names(&state, filter).await.map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, "internal", e))?;
+    success(json!(names))
+}
+
+/// GET /api/v1/prom/api/v1/label/:name/values
+pub async fn label_values_handler(Extension(state): Extension<Arc<crate::AppState>>, headers: HeaderMap, Path(name): Path<String>) -> PromResult {
+    let filter = state.auth.filter(super::http::request_token(&headers).as_deref());
+    let values = promql::label_values(&state, &name, filter).await.map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, "internal", e))?;
+    success(json!(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::{Observation, Timestamp};
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn answers_grafana_queries() {
+        let dir = crate::test_util::TempDir::new("promql");
+        let state = Arc::new(crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let rows: Vec<Observation> = ["HK001", "HK002"]
+            .iter()
+            .enumerate()
+            .flat_map(|(n, station)| {
+                (0..3).map(move |i| {
+                    let mut o = Observation::empty(*station, Timestamp(1_735_689_600_000 + i * 60_000));
+                    o.tags.insert("region".into(), "kowloon".into());
+                    o.set_field("temp", 20.0 + (n as i64 * 10 + i) as f64);
+                    o
+                })
+            })
+            .collect();
+        crate::ingest::append(&state, rows).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/api/v1/prom/api/v1", listener.local_addr().unwrap());
+        let app = super::super::http::router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let get = |path: &str, params: &[(&str, &str)]| {
+            let request = client.get(format!("{}/{}", base, path)).query(params).send();
+            async move { request.await.unwrap().json::<JsonValue>().await.unwrap() }
+        };
+        let v = get("query", &[("query", "1+1")]).await;
+        assert_eq!(v["data"]["resultType"], "scalar");
+        assert_eq!(v["data"]["result"][1], "2");
+        let v = get("query", &[("query", "skypulse_temp{station_id=\"HK002\"}"), ("time", "1735689720")]).await;
+        assert_eq!(v["data"]["result"][0]["metric"], json!({ "__name__": "skypulse_temp", "station_id": "HK002", "region": "kowloon" }));
+        assert_eq!(v["data"]["result"][0]["value"], json!([1735689720.0, "32"]));
+
+        // POSTed as a form, the way Grafana sends it
+        let form = [("query", "avg by (region) (temp)"), ("start", "2025-01-01T00:00:00Z"), ("end", "1735689720"), ("step", "1m")];
+        let v: JsonValue = client.post(format!("{}/query_range", base)).form(&form).send().await.unwrap().json().await.unwrap();
+        assert_eq!(v["data"]["result"][0]["metric"], json!({ "region": "kowloon" }));
+        assert_eq!(v["data"]["result"][0]["values"], json!([[1735689600.0, "25"], [1735689660.0, "26"], [1735689720.0, "27"]]));
+
+        let bad = client.get(format!("{}/query", base)).query(&[("query", "temp > 1")]).send().await.unwrap();
+        assert_eq!(bad.status(), 400);
+        assert_eq!(bad.json::<JsonValue>().await.unwrap()["errorType"], "bad_data");
+        assert_eq!(get("label/__name__/values", &[]).await["data"], json!(["temp"]));
+        assert_eq!(get("labels", &[]).await["data"], json!(["__name__", "region", "station_id"]));
+    }
+}
@@ -0,0 +1,749 @@
+use axum::{
+    body::Body,
+    extract::{Extension, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+use super::filter::ResponseFilter;
+use crate::columnar::{self, ParquetWriter};
+use crate::offline::{csv_cell, CsvRows};
+use crate::query::{self, aggregate, convert::Units, cursor::Cursor, derived, geo::Area, Order, QueryResult, RangeQuery, ResolutionChoice, Rows};
+use crate::stations::StationMeta;
+use crate::storage::memtable::{FieldValue, Observation};
+
+/// Rows per batch when streaming raw rows as a file.
+const STREAM_BATCH: usize = 4096;
+const ARROW_STREAM: &str = "application/vnd.apache.arrow.stream";
+/// Most stations one area query runs for.
+const MAX_AREA_STATIONS: usize = 500;
+/// Most rows one area query answers with, over all its stations.
+const MAX_AREA_ROWS: usize = 200_000;
+/// Stations of an area query queried at once.
+const AREA_CONCURRENCY: usize = 8;
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    /// Required unless an area is given.
+    #[serde(default)]
+    pub station_id: String,
+    /// `lat,lon` of the centre of an area of `radius` (`25km`, `800m`):
+    /// the query then runs for every registered station inside it.
+    pub near: Option<String>,
+    pub radius: Option<String>,
+    /// Area as `min_lon,min_lat,max_lon,max_lat`, instead of `near`.
+    pub bbox: Option<String>,
+    /// Inclusive start of the range: RFC3339 or relative such as `now-6h`.
+    pub start: Option<String>,
+    /// Exclusive end of the range, like `start`.
+    pub end: Option<String>,
+    /// `raw`, `1m`, `1h` or `auto` (default).
+    pub resolution: Option<String>,
+    /// Comma-separated aggregations: min, max, mean, sum, count, first, last, nulls, missing.
+    pub agg: Option<String>,
+    /// Comma-separated fields to aggregate; defaults to every numeric field.
+    /// Derived fields such as `dew_point` are computed into the rows.
+    pub fields: Option<String>,
+    /// GROUP BY time window such as `5m` or `1h`; `step` is accepted too.
+    #[serde(alias = "step")]
+    pub interval: Option<String>,
+    /// IANA time zone the `interval` windows align to, such as
+    /// `Asia/Hong_Kong`; UTC by default.
+    pub tz: Option<String>,
+    /// `null`, `previous` or `linear` emits the `interval` windows without
+    /// observations too, from `start` to `end` or now, filling their values.
+    pub fill: Option<String>,
+    /// `metric`, `imperial` and `field:unit` overrides such as
+    /// `wind_speed:kn`, comma-separated; values as stored by default.
+    pub units: Option<String>,
+    /// Latency budget in milliseconds or with a unit (`2s`); slower plans
+    /// fall back to coarser rollups.
+    #[serde(default, alias = "max_latency", deserialize_with = "crate::units::opt_millis")]
+    pub max_latency_ms: Option<u64>,
+    /// `asc` (default) or `desc` for most recent first.
+    pub order: Option<String>,
+    /// Maximum number of rows or buckets to return.
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page of raw rows.
+    pub cursor: Option<String>,
+    /// Also return the stored forecast for the range.
+    #[serde(default)]
+    pub forecast: bool,
+    /// Also return the station's registered metadata (JSON only).
+    #[serde(default)]
+    pub metadata: bool,
+    /// `json` (default), `arrow` for an Arrow IPC stream (as with
+    /// `Accept: application/vnd.apache.arrow.stream`) or `parquet` for the
+    /// raw rows as a Parquet file.
+    pub format: Option<String>,
+}
+
+/// Refuse derived fields computed from a field the key's filter redacts.
+pub(crate) fn check_derived(fields: &[String], filter: Option<&ResponseFilter>) -> Result<(), (StatusCode, String)> {
+    let Some(f) = filter else { return Ok(()) };
+    for d in derived::requested(fields) {
+        if let Some(input) = f.redacted_input(d) {
+            return Err((StatusCode::FORBIDDEN, format!("{} is derived from {}, which is redacted for this key", d.name(), input)));
+        }
+    }
+    Ok(())
+}
+
+fn parse_time(name: &str, value: Option<&str>, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, (StatusCode, String)> {
+    value
+        .map(|v| query::parse_time_at(v, now).map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid {}: {}", name, e))))
+        .transpose()
+}
+
+/// The status for a query that failed to execute: 400 for a filled query
+/// over more windows than it may emit, which an open `start` only shows once
+/// the rows are read, else 500.
+pub(crate) fn execution_error(e: anyhow::Error) -> (StatusCode, String) {
+    match e.is::<aggregate::TooManyWindows>() {
+        true => (StatusCode::BAD_REQUEST, e.to_string()),
+        false => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// The range query `params` ask for.
+fn plan(params: &QueryParams, now: DateTime<Utc>) -> Result<RangeQuery, (StatusCode, String)> {
+    let bad_request = |e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let q = RangeQuery {
+        station_id: params.station_id.clone(),
+        start: parse_time("start", params.start.as_deref(), now)?,
+        end: parse_time("end", params.end.as_deref(), now)?,
+        resolution: ResolutionChoice::parse(params.resolution.as_deref().unwrap_or("auto")).map_err(bad_request)?,
+        fields: aggregate::parse_fields(params.fields.as_deref()),
+        aggregations: aggregate::AggFn::parse_list(params.agg.as_deref().unwrap_or("")).map_err(bad_request)?,
+        interval_secs: params.interval.as_deref().map(query::parse_interval).transpose().map_err(bad_request)?,
+        tz: params.tz.as_deref().map(query::parse_tz).transpose().map_err(bad_request)?,
+        fill: aggregate::Fill::parse(params.fill.as_deref().unwrap_or("none")).map_err(bad_request)?,
+        units: Units::parse(params.units.as_deref().unwrap_or("")).map_err(bad_request)?,
+        max_latency_ms: params.max_latency_ms,
+        order: Order::parse(params.order.as_deref().unwrap_or("asc")).map_err(bad_request)?,
+        limit: params.limit,
+        forecast: params.forecast,
+    };
+    if q.tz.is_some() && q.interval_secs.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "tz aligns interval windows; add interval".into()));
+    }
+    match (q.fill, q.interval_secs, q.start) {
+        (aggregate::Fill::None, ..) => {}
+        (_, None, _) => return Err((StatusCode::BAD_REQUEST, "fill emits interval windows; add interval".into())),
+        (_, Some(interval), Some(start)) if (q.end.unwrap_or(now) - start).num_seconds() / interval >= aggregate::MAX_FILLED_WINDOWS => {
+            return Err((StatusCode::BAD_REQUEST, format!("fill emits at most {} windows; narrow the range or widen the interval", aggregate::MAX_FILLED_WINDOWS)));
+        }
+        _ => {}
+    }
+    Ok(q)
+}
+
+#[derive(Serialize)]
+struct AreaStation {
+    /// From the centre of a `near` and `radius` area.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance_km: Option<f64>,
+    #[serde(flatten)]
+    result: QueryResult,
+}
+
+/// `q` for every registered station inside `area`, nearest first for a
+/// radius and by id for a box. Stations are placed by their coordinates as
+/// the key's filter shows them, so a coarse grid is not undone by probing
+/// with small areas. Raw rows need a `limit`, and the answer stops at
+/// [`MAX_AREA_ROWS`] over all stations.
+async fn area_query(state: Arc<crate::AppState>, area: Area, q: RangeQuery, filter: Option<&ResponseFilter>) -> Result<Response, (StatusCode, String)> {
+    let raw = q.interval_secs.is_none() && q.aggregations.is_empty();
+    if raw && q.limit.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "area queries need a limit, or agg or interval".into()));
+    }
+    let shown = |name: &str, x: f64| filter.map_or(Some(x), |f| f.number(name, x));
+    let mut inside = Vec::new();
+    for meta in state.stations.list().await {
+        let Some((lat, lon)) = meta.latitude.zip(meta.longitude) else { continue };
+        let Some((lat, lon)) = shown("latitude", lat).zip(shown("longitude", lon)) else { continue };
+        if let Some(distance_km) = area.locate(lat, lon) {
+            inside.push((distance_km, meta));
+        }
+    }
+    if inside.len() > MAX_AREA_STATIONS {
+        return Err((StatusCode::BAD_REQUEST, format!("{} stations are in the area, more than {}; narrow it", inside.len(), MAX_AREA_STATIONS)));
+    }
+    let too_many = || (StatusCode::BAD_REQUEST, format!("the area query returns more than {} rows; narrow the area, the range or the limit", MAX_AREA_ROWS));
+    if q.limit.is_some_and(|n| raw && n.saturating_mul(inside.len()) > MAX_AREA_ROWS) {
+        return Err(too_many());
+    }
+    inside.sort_by(|a, b| a.0.unwrap_or(0.0).total_cmp(&b.0.unwrap_or(0.0)));
+    let mut stations: Vec<Option<AreaStation>> = (0..inside.len()).map(|_| None).collect();
+    let mut pending = inside.into_iter().enumerate();
+    // dropping the set on an early return aborts the stations still running
+    let mut running = tokio::task::JoinSet::new();
+    let mut rows = 0;
+    loop {
+        while running.len() < AREA_CONCURRENCY {
+            let Some((i, (distance_km, meta))) = pending.next() else { break };
+            let q = RangeQuery { station_id: meta.station_id.clone(), ..q.clone() };
+            let state = state.clone();
+            running.spawn(async move { (i, distance_km, meta, query::execute_isolated(state, q).await) });
+        }
+        let Some(done) = running.join_next().await else { break };
+        let (i, distance_km, meta, result) = done.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let mut result = result.map_err(execution_error)?;
+        rows += match &result.rows {
+            Some(Rows::Raw(r)) => r.len(),
+            Some(Rows::Rollup(r)) => r.len(),
+            Some(Rows::Buckets(r)) => r.len(),
+            None => 1,
+        };
+        if rows > MAX_AREA_ROWS {
+            return Err(too_many());
+        }
+        // each station's rows are cut to `limit`; there is nothing to page
+        result.next_cursor = None;
+        result.metadata = Some(meta);
+        stations[i] = Some(AreaStation { distance_km, result });
+    }
+    Ok(Json(serde_json::json!({ "stations": stations.into_iter().flatten().collect::<Vec<_>>() })).into_response())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Json,
+    Arrow,
+    Csv,
+}
+
+pub async fn query_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<QueryParams>,
+) -> Result<Response, (StatusCode, String)> {
+    let format = match params.format.as_deref() {
+        None if headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).is_some_and(|v| v.contains(ARROW_STREAM)) => Format::Arrow,
+        None | Some("json") => Format::Json,
+        Some("arrow") => Format::Arrow,
+        Some("csv") => Format::Csv,
+        Some("parquet") => return parquet(state, &headers, params).await,
+        Some(other) => return Err((StatusCode::BAD_REQUEST, format!("unknown format '{}'; expected json, arrow, csv or parquet", other))),
+    };
+    if format != Format::Json && (params.forecast || params.metadata) {
+        return Err((StatusCode::BAD_REQUEST, "forecast and metadata are only returned as JSON".into()));
+    }
+    let metadata = match params.metadata {
+        true => state.stations.get(&params.station_id).await,
+        false => None,
+    };
+    let filter = state.auth.filter(super::http::request_token(&headers).as_deref()).cloned();
+    let bad_request = |e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let mut q = plan(&params, Utc::now())?;
+    check_derived(&q.fields, filter.as_ref())?;
+    if params.near.is_some() || params.radius.is_some() || params.bbox.is_some() {
+        if format != Format::Json || params.cursor.is_some() {
+            return Err((StatusCode::BAD_REQUEST, "area queries answer as JSON and are not paged; drop format and cursor".into()));
+        }
+        let area = Area::parse(params.near.as_deref(), params.radius.as_deref(), params.bbox.as_deref()).map_err(bad_request)?;
+        return area_query(state, area, q, filter.as_ref()).await;
+    }
+    if q.station_id.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "station_id is required, or an area: near and radius, or bbox".into()));
+    }
+    let raw = q.interval_secs.is_none() && q.aggregations.is_empty() && q.effective_resolution() == ResolutionChoice::Raw;
+    if let Some(cursor) = params.cursor.as_deref() {
+        if format != Format::Json || !raw {
+            return Err((StatusCode::BAD_REQUEST, "cursor pages raw rows as JSON; use resolution=raw without agg or interval".into()));
+        }
+        Cursor::decode(cursor).and_then(|c| c.apply(&mut q)).map_err(bad_request)?;
+        // a narrower range must not switch later pages to rollups
+        q.resolution = ResolutionChoice::Raw;
+    }
+    if format == Format::Csv {
+        return csv(state, q, filter).await;
+    }
+    if format == Format::Json && raw && q.order == Order::Asc && q.max_latency_ms.is_none() && !q.forecast {
+        if q.start.zip(q.end).is_some_and(|(s, e)| s >= e) {
+            // a cursor past the end of the range
+            let empty = QueryResult { metadata, rows: Some(Rows::Raw(Vec::new())), ..QueryResult::empty(&q.station_id) };
+            return Ok(Json(empty).into_response());
+        }
+        // the filter middleware rewrites the JSON
+        let encoder = JsonRows::new(&q.station_id, metadata.as_ref());
+        let rows = RowSource::store(state.clone(), &q);
+        return Ok(([(header::CONTENT_TYPE, "application/json")], stream_rows(state, q, rows, encoder).await?).into_response());
+    }
+    let mut result = query::execute_isolated(state, q).await.map_err(execution_error)?;
+    if format == Format::Json {
+        result.metadata = metadata;
+        return Ok(Json(result).into_response());
+    }
+    let body = arrow_stream(result, filter.as_ref()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    Ok(([(header::CONTENT_TYPE, ARROW_STREAM)], body).into_response())
+}
+
+/// The rollup, bucket or whole-range aggregate rows of `result` as JSON
+/// objects, rewritten by the key's filter.
+fn aggregate_rows(rows: Option<Rows>, aggregates: Option<aggregate::Aggregates>, filter: Option<&ResponseFilter>) -> anyhow::Result<Vec<serde_json::Value>> {
+    let mut v = match rows {
+        Some(rows) => serde_json::to_value(rows)?,
+        None => serde_json::json!([{ "aggregates": aggregates }]),
+    };
+    if let Some(f) = filter {
+        f.apply(&mut v);
+    }
+    Ok(match v {
+        serde_json::Value::Array(rows) => rows,
+        _ => Vec::new(),
+    })
+}
+
+/// `result` as an Arrow IPC stream: raw rows with the columns of
+/// `columnar`, rollups, buckets or whole-range aggregates as
+/// `<field>_<aggregation>` columns. The station and the resolution used are
+/// in the schema metadata. The response filter middleware only rewrites
+/// JSON, so the key's filter is applied here.
+fn arrow_stream(result: QueryResult, filter: Option<&ResponseFilter>) -> anyhow::Result<Vec<u8>> {
+    let (schema, batches) = match result.rows {
+        Some(Rows::Raw(rows)) => columnar::batches(&rows.into_iter().map(|o| filtered(o, filter)).collect::<Vec<_>>())?,
+        rows => {
+            let rows = aggregate_rows(rows, result.aggregates, filter)?;
+            let batch = columnar::AggregateTable::new(&rows)?.batch()?;
+            (batch.schema(), vec![batch])
+        }
+    };
+    let mut metadata = std::collections::HashMap::from([
+        ("station_id".to_string(), result.station_id),
+        ("resolution".to_string(), result.resolution.to_string()),
+    ]);
+    if let Some(planned) = result.downgraded_from {
+        metadata.insert("downgraded_from".to_string(), planned.to_string());
+    }
+    let schema = Arc::new(schema.as_ref().clone().with_metadata(metadata));
+    let batches = batches.into_iter().map(|b| b.with_schema(schema.clone())).collect::<Result<Vec<_>, _>>()?;
+    let mut out = Vec::new();
+    columnar::write_arrow_stream(&schema, &batches, &mut out)?;
+    Ok(out)
+}
+
+fn shaped(mut o: Observation, derived: &[derived::Derived], units: &Units) -> Observation {
+    query::shape(&mut o, derived, units);
+    o
+}
+
+/// `o` as the key's response filter would show it.
+fn filtered(o: Observation, filter: Option<&ResponseFilter>) -> Observation {
+    let Some(f) = filter else { return o };
+    let mut v = serde_json::to_value(&o).unwrap_or_default();
+    f.apply(&mut v);
+    serde_json::from_value(v).unwrap_or(o)
+}
+
+/// A format that raw rows are streamed out in.
+trait RowEncoder: Send + 'static {
+    /// Encode `rows`; returns the bytes ready to send.
+    fn encode(&mut self, rows: &[Observation]) -> anyhow::Result<Vec<u8>>;
+    /// The rest of the body; `next_cursor` is set when `limit` cut the rows short.
+    fn finish(self, next_cursor: Option<String>) -> anyhow::Result<Vec<u8>>;
+}
+
+impl RowEncoder for ParquetWriter<Vec<u8>> {
+    fn encode(&mut self, rows: &[Observation]) -> anyhow::Result<Vec<u8>> {
+        self.write(rows)?;
+        Ok(self.take())
+    }
+
+    fn finish(self, _: Option<String>) -> anyhow::Result<Vec<u8>> {
+        ParquetWriter::finish(self)
+    }
+}
+
+impl RowEncoder for CsvRows<Vec<u8>> {
+    fn encode(&mut self, rows: &[Observation]) -> anyhow::Result<Vec<u8>> {
+        self.write(rows)?;
+        self.take()
+    }
+
+    fn finish(self, _: Option<String>) -> anyhow::Result<Vec<u8>> {
+        CsvRows::finish(self)
+    }
+}
+
+/// A raw `QueryResult` as JSON, written as the rows arrive.
+struct JsonRows {
+    out: Vec<u8>,
+    rows: usize,
+}
+
+impl JsonRows {
+    fn new(station_id: &str, metadata: Option<&StationMeta>) -> Self {
+        let mut head = format!("{{\"station_id\":{},", serde_json::Value::from(station_id));
+        if let Some(m) = metadata.and_then(|m| serde_json::to_string(m).ok()) {
+            head.push_str(&format!("\"metadata\":{},", m));
+        }
+        head.push_str("\"resolution\":\"raw\",\"rows\":[");
+        Self { out: head.into_bytes(), rows: 0 }
+    }
+}
+
+impl RowEncoder for JsonRows {
+    fn encode(&mut self, rows: &[Observation]) -> anyhow::Result<Vec<u8>> {
+        for o in rows {
+            if self.rows > 0 {
+                self.out.push(b',');
+            }
+            serde_json::to_writer(&mut self.out, o)?;
+            self.rows += 1;
+        }
+        Ok(std::mem::take(&mut self.out))
+    }
+
+    fn finish(mut self, next_cursor: Option<String>) -> anyhow::Result<Vec<u8>> {
+        self.out.push(b']');
+        if let Some(c) = next_cursor {
+            self.out.extend_from_slice(format!(",\"next_cursor\":\"{}\"", c).as_bytes());
+        }
+        self.out.push(b'}');
+        Ok(self.out)
+    }
+}
+
+/// The first pass of a streamed file: the columns of the range's first
+/// `q.limit` raw rows as the key's filter leaves them, and those rows for the
+/// second. A failure here is still an error status.
+async fn scan_columns(
+    state: &Arc<crate::AppState>,
+    q: &RangeQuery,
+    filter: Option<&ResponseFilter>,
+) -> Result<columnar::Scan, (StatusCode, String)> {
+    if q.start.zip(q.end).is_some_and(|(s, e)| s >= e) {
+        return Err((StatusCode::BAD_REQUEST, "start must be before end".into()));
+    }
+    let derived = derived::requested(&q.fields);
+    let map = |o| filtered(shaped(o, &derived, &q.units), filter);
+    columnar::scan_columns(state.clone(), &q.station_id, q.start, q.end, &q.fields, q.limit, map)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Where `stream_rows` reads from.
+enum RowSource {
+    /// The range from storage, shaped as the query asks.
+    Store {
+        batches: std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<crate::ObservationBatch, crate::QueryError>> + Send>>,
+        derived: Vec<derived::Derived>,
+        units: Units,
+    },
+    /// The rows a first pass spooled, shaped and filtered already.
+    Spooled(crate::query::spill::SpoolReader),
+}
+
+impl RowSource {
+    fn store(state: Arc<crate::AppState>, q: &RangeQuery) -> Self {
+        let batches = query::stream::observations(state, &q.station_id, q.start, q.end, STREAM_BATCH);
+        RowSource::Store { batches: Box::pin(batches), derived: derived::requested(&q.fields), units: q.units.clone() }
+    }
+
+    async fn next(&mut self) -> Option<Result<Vec<Observation>, String>> {
+        match self {
+            RowSource::Store { batches, derived, units } => {
+                let batch = batches.next().await?;
+                Some(batch.map(|b| b.observations.into_iter().map(|o| shaped(o, derived, units)).collect()).map_err(|e| format!("{:#}", e)))
+            }
+            RowSource::Spooled(rows) => match rows.next_batch().await {
+                Ok(batch) if batch.is_empty() => None,
+                batch => Some(batch.map_err(|e| format!("{:#}", e))),
+            },
+        }
+    }
+}
+
+/// Stream `rows`, at most `q.limit` of them, through `encoder`. The first
+/// batch is read before the answer is sent, so a failure there is still an
+/// error status; a later one cuts the body short.
+async fn stream_rows(
+    state: Arc<crate::AppState>,
+    q: RangeQuery,
+    mut rows: RowSource,
+    mut encoder: impl RowEncoder,
+) -> Result<Body, (StatusCode, String)> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(4);
+    let (started_tx, started) = tokio::sync::oneshot::channel::<Result<(), String>>();
+    let mut started_tx = Some(started_tx);
+    let mut left = q.limit.unwrap_or(usize::MAX);
+    state.runtimes.query.clone().spawn(async move {
+        let mut last = None;
+        let mut more = false;
+        while !more {
+            let batch = rows.next().await;
+            if let Some(started) = started_tx.take() {
+                if let Some(Err(e)) = &batch {
+                    let _ = started.send(Err(e.clone()));
+                    return;
+                }
+                let _ = started.send(Ok(()));
+            }
+            let Some(batch) = batch else { break };
+            let mut batch = match batch {
+                Ok(rows) => rows,
+                Err(e) => {
+                    let _ = tx.send(Err(std::io::Error::other(e))).await;
+                    return;
+                }
+            };
+            // rows past the limit only tell that there is another page
+            if batch.len() > left {
+                batch.truncate(left);
+                more = true;
+            }
+            left -= batch.len();
+            last = batch.last().map(|o| o.time).or(last);
+            let bytes = match encoder.encode(&batch) {
+                Ok(b) => b,
+                Err(e) => {
+                    let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+                    return;
+                }
+            };
+            if !bytes.is_empty() && tx.send(Ok(bytes)).await.is_err() {
+                return;
+            }
+        }
+        let next_cursor = last.filter(|_| more).map(|time| Cursor { station_id: q.station_id.clone(), order: Order::Asc, time }.encode());
+        let _ = tx.send(encoder.finish(next_cursor).map_err(|e| std::io::Error::other(e.to_string()))).await;
+    });
+    match started.await {
+        Ok(Err(e)) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+        // the task ended before the first batch: it panicked
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "query failed".into())),
+        Ok(Ok(())) => {}
+    }
+    Ok(Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx)))
+}
+
+/// The raw rows of the range as a Parquet file, streamed a row group at a
+/// time.
+async fn parquet(state: Arc<crate::AppState>, headers: &HeaderMap, params: QueryParams) -> Result<Response, (StatusCode, String)> {
+    let raw = params.resolution.as_deref().is_none_or(|r| r == "raw" || r == "auto");
+    if !raw || params.agg.is_some() || params.interval.is_some() || params.forecast || params.order.as_deref().is_some_and(|o| o != "asc") {
+        return Err((StatusCode::BAD_REQUEST, "format=parquet returns raw rows in time order; drop resolution, agg, interval, order and forecast".into()));
+    }
+    let now = Utc::now();
+    let q = RangeQuery {
+        start: parse_time("start", params.start.as_deref(), now)?,
+        end: parse_time("end", params.end.as_deref(), now)?,
+        fields: aggregate::parse_fields(params.fields.as_deref()),
+        units: Units::parse(params.units.as_deref().unwrap_or("")).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?,
+        limit: params.limit,
+        ..RangeQuery::new(&params.station_id, None, None)
+    };
+    let filter = state.auth.filter(super::http::request_token(headers).as_deref()).cloned();
+    check_derived(&q.fields, filter.as_ref())?;
+    let scan = scan_columns(&state, &q, filter.as_ref()).await?;
+    let writer = ParquetWriter::new(scan.columns, Vec::new()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let disposition = format!("attachment; filename=\"{}.parquet\"", q.station_id);
+    let body = stream_rows(state, q, RowSource::Spooled(scan.rows), writer).await?;
+    Ok(([(header::CONTENT_TYPE, "application/vnd.apache.parquet".to_string()), (header::CONTENT_DISPOSITION, disposition)], body).into_response())
+}
+
+/// The result as CSV with a fixed column order: raw rows as
+/// `station_id,time,<field>...` with fields in name order (the layout file
+/// drops import), aggregated ones as `[station_id,]time,<field>_<aggregation>...`.
+/// A plain raw scan in time order is streamed like Parquet; anything else
+/// is computed first.
+async fn csv(state: Arc<crate::AppState>, q: RangeQuery, filter: Option<ResponseFilter>) -> Result<Response, (StatusCode, String)> {
+    let headers = [(header::CONTENT_TYPE, "text/csv; charset=utf-8")];
+    let streamed = q.interval_secs.is_none()
+        && q.aggregations.is_empty()
+        && q.order == Order::Asc
+        && q.max_latency_ms.is_none()
+        && q.effective_resolution() == ResolutionChoice::Raw;
+    if streamed {
+        let scan = scan_columns(&state, &q, filter.as_ref()).await?;
+        let writer = CsvRows::new(scan.columns.fields().cloned().collect(), Vec::new()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        return Ok((headers, stream_rows(state, q, RowSource::Spooled(scan.rows), writer).await?).into_response());
+    }
+    let fields = q.fields.clone();
+    let result = query::execute_isolated(state, q).await.map_err(execution_error)?;
+    let body = match result.rows {
+        Some(Rows::Raw(rows)) => {
+            let rows: Vec<Observation> = rows.into_iter().map(|o| filtered(o, filter.as_ref())).collect();
+            let mut columns = columnar::Columns::new(&fields);
+            rows.iter().for_each(|o| columns.observe(o));
+            CsvRows::new(columns.fields().cloned().collect(), Vec::new()).and_then(|mut w| {
+                w.write(&rows)?;
+                w.finish()
+            })
+        }
+        rows => aggregate_rows(rows, result.aggregates, filter.as_ref()).and_then(|rows| aggregate_csv(&rows)),
+    };
+    let body = body.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    Ok((headers, body).into_response())
+}
+
+fn aggregate_csv(rows: &[serde_json::Value]) -> anyhow::Result<Vec<u8>> {
+    let table = columnar::AggregateTable::new(rows)?;
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let fixed = [("station_id", table.station.as_ref()), ("time", table.time.as_ref())];
+    let names = fixed.iter().filter(|(_, c)| c.is_some()).map(|(name, _)| name.to_string());
+    writer.write_record(names.chain(table.columns.keys().cloned()))?;
+    for i in 0..rows.len() {
+        let fixed = fixed.iter().filter_map(|(_, c)| c.map(|c| c[i].unwrap_or_default().to_string()));
+        let cells = table.columns.values().map(|c| csv_cell(c[i].and_then(serde_json::Number::as_f64).map(FieldValue::Number).as_ref()));
+        writer.write_record(fixed.chain(cells))?;
+    }
+    writer.into_inner().map_err(|e| anyhow::anyhow!("{}", e.error()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::net::SocketAddr;
+
+    /// Serve three minutes of station A's temperatures from a fresh data
+    /// directory; returns the state, the query URL for station A and the
+    /// directory.
+    async fn serve(name: &str) -> (Arc<crate::AppState>, String, crate::test_util::TempDir) {
+        let dir = crate::test_util::TempDir::new(&format!("query-{}", name));
+        let state = Arc::new(crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let rows: Vec<Observation> = (0..3)
+            .map(|i| {
+                let mut o = Observation::empty("A", crate::storage::memtable::Timestamp(1_735_689_600_000 + i * 60_000));
+                o.set_field("temp", 20.0 + i as f64);
+                o
+            })
+            .collect();
+        crate::ingest::append(&state, rows).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/query?station_id=A", listener.local_addr().unwrap());
+        let app = super::super::http::router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (state, url, dir)
+    }
+
+    #[tokio::test]
+    async fn streams_raw_rows_as_parquet() {
+        let (_state, url, _dir) = serve("parquet").await;
+        let url = format!("{}&format=parquet", url);
+        let client = reqwest::Client::new();
+        assert_eq!(client.get(format!("{}&agg=mean", url)).send().await.unwrap().status(), 400);
+        let resp = client.get(format!("{}&limit=2", url)).send().await.unwrap();
+        assert_eq!(resp.headers()["content-type"], "application/vnd.apache.parquet");
+        let reader = ParquetRecordBatchReaderBuilder::try_new(resp.bytes().await.unwrap()).unwrap().build().unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        assert_eq!(batches[0].schema().field(2).name(), "temp");
+    }
+
+    #[tokio::test]
+    async fn answers_as_arrow_streams() {
+        let (_state, url, _dir) = serve("arrow").await;
+        let client = reqwest::Client::new();
+        let read = |bytes: Vec<u8>| arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(bytes), None).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+        let resp = client.get(format!("{}&resolution=raw", url)).header("Accept", ARROW_STREAM).send().await.unwrap();
+        assert_eq!(resp.headers()["content-type"], ARROW_STREAM);
+        let batches = read(resp.bytes().await.unwrap().to_vec());
+        assert_eq!(batches[0].num_rows(), 3);
+        assert_eq!(batches[0].schema().metadata()["resolution"], "raw");
+        let resp = client.get(format!("{}&format=arrow&interval=1h&agg=max,count", url)).send().await.unwrap();
+        let batch = read(resp.bytes().await.unwrap().to_vec()).remove(0);
+        let names: Vec<String> = batch.schema().fields().iter().map(|f| f.name().clone()).collect();
+        assert_eq!(names, ["time", "temp_count", "temp_max"]);
+        assert_eq!(batch.schema().field(1).data_type(), &arrow_schema::DataType::UInt64);
+        let max = batch.column(2).as_any().downcast_ref::<arrow_array::Float64Array>().unwrap();
+        assert_eq!(max.value(0), 22.0);
+    }
+
+    async fn csv(client: &reqwest::Client, url: &str, query: &str) -> String {
+        client.get(format!("{}&format=csv{}", url, query)).send().await.unwrap().text().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_as_csv() {
+        let (_state, url, _dir) = serve("csv").await;
+        let client = reqwest::Client::new();
+        assert_eq!(csv(&client, &url, "&resolution=raw&limit=2").await, "station_id,time,temp\nA,2025-01-01T00:00:00Z,20\nA,2025-01-01T00:01:00Z,21\n");
+        assert_eq!(csv(&client, &url, "&resolution=raw&order=desc&limit=1").await, "station_id,time,temp\nA,2025-01-01T00:02:00Z,22\n");
+        assert_eq!(csv(&client, &url, "&interval=1h&agg=max,count").await, "time,temp_count,temp_max\n2025-01-01T00:00:00Z,3,22\n");
+        // an open start fills from the first row up to now, far too many minutes
+        let resp = client.get(format!("{}&interval=1m&agg=max&fill=null", url)).send().await.unwrap();
+        assert_eq!(resp.status(), 400);
+        assert!(resp.text().await.unwrap().contains("narrow the range"));
+    }
+
+    #[tokio::test]
+    async fn converts_units() {
+        let (_state, url, _dir) = serve("units").await;
+        let client = reqwest::Client::new();
+        assert_eq!(csv(&client, &url, "&resolution=raw&limit=1&units=imperial").await, "station_id,time,temp\nA,2025-01-01T00:00:00Z,68\n");
+        assert_eq!(client.get(format!("{}&units=temp:kn", url)).send().await.unwrap().status(), 400);
+    }
+
+    #[tokio::test]
+    async fn pages_raw_rows_with_cursors() {
+        let (_state, url, _dir) = serve("pages").await;
+        let client = reqwest::Client::new();
+        // page through the rows, streamed oldest first and computed newest first
+        for order in ["asc", "desc"] {
+            let page = |cursor: Option<String>| {
+                let cursor = cursor.map(|c| format!("&cursor={}", c)).unwrap_or_default();
+                let request = client.get(format!("{}&resolution=raw&order={}&limit=2{}", url, order, cursor)).send();
+                async move { request.await.unwrap().json::<serde_json::Value>().await.unwrap() }
+            };
+            let first = page(None).await;
+            let second = page(first["next_cursor"].as_str().map(String::from)).await;
+            let temps = |v: &serde_json::Value| v["rows"].as_array().unwrap().iter().map(|r| r["fields"]["temp"].as_f64().unwrap()).collect::<Vec<_>>();
+            let (mut all, last) = (temps(&first), temps(&second));
+            all.extend(last);
+            assert_eq!(all, if order == "asc" { [20.0, 21.0, 22.0] } else { [22.0, 21.0, 20.0] });
+            assert!(second.get("next_cursor").is_none());
+        }
+        let bad = client.get(format!("{}&interval=1h&cursor=00", url)).send().await.unwrap();
+        assert_eq!(bad.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn unreadable_first_batch_is_an_error_status() {
+        let (state, url, dir) = serve("broken").await;
+        let mut o = Observation::empty("A", crate::storage::memtable::Timestamp(1_735_600_000_000));
+        o.set_field("temp", 1.0);
+        state.chunk_store.write_chunk("A", "flush-1", &[o]).await.unwrap();
+        std::fs::write(dir.join("chunks").join("A-flush-1.ndjson"), b"not a chunk").unwrap();
+        let resp = reqwest::get(format!("{}&resolution=raw", url)).await.unwrap();
+        assert_eq!(resp.status(), 500);
+    }
+
+    #[tokio::test]
+    async fn finds_stations_near_a_point_and_in_a_box() {
+        let (state, url, _dir) = serve("area").await;
+        let client = reqwest::Client::new();
+        for (id, lat, lon) in [("A", 22.302, 114.174), ("B", 22.38, 114.19), ("C", 22.199, 113.544)] {
+            let meta = serde_json::from_value(serde_json::json!({ "station_id": id, "latitude": lat, "longitude": lon })).unwrap();
+            state.stations.put(meta).await.unwrap();
+        }
+        let area = |query: &str| {
+            let request = client.get(format!("{}&resolution=raw&order=desc&limit=1&{}", url.replace("station_id=A", ""), query)).send();
+            async move { request.await.unwrap().json::<serde_json::Value>().await.unwrap() }
+        };
+        let near = area("near=22.38,114.19&radius=20km").await;
+        let stations = near["stations"].as_array().unwrap();
+        assert_eq!(stations.iter().map(|s| s["station_id"].as_str().unwrap()).collect::<Vec<_>>(), ["B", "A"]);
+        assert_eq!(stations[0]["distance_km"], 0.0);
+        assert!(stations[0]["rows"].as_array().unwrap().is_empty());
+        assert_eq!(stations[1]["rows"][0]["fields"]["temp"], 22.0);
+        assert_eq!(stations[1]["metadata"]["latitude"], 22.302);
+        let boxed = area("bbox=113,22,114,23").await;
+        assert_eq!(boxed["stations"][0]["station_id"], "C");
+        assert!(boxed["stations"][0].get("distance_km").is_none());
+        let unlimited = client.get(format!("{}&bbox=113,22,114,23", url.replace("station_id=A", ""))).send().await.unwrap();
+        assert_eq!(unlimited.status(), 400);
+        let hourly = client.get(format!("{}&bbox=113,22,115,23&interval=1h&agg=mean", url.replace("station_id=A", ""))).send().await.unwrap();
+        assert_eq!(hourly.json::<serde_json::Value>().await.unwrap()["stations"].as_array().unwrap().len(), 3);
+        for query in ["near=22.38,114.19", "bbox=113,22,114,23&format=csv", ""] {
+            let resp = client.get(format!("{}&{}", url.replace("station_id=A", ""), query)).send().await.unwrap();
+            assert_eq!(resp.status(), 400, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// Per-client rate limiting on the write endpoints. Each client gets a token
+// bucket keyed on its API key (when authentication is on) or its remote IP;
+// a request that finds the bucket empty is answered 429 with Retry-After.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Sustained requests per second allowed per client.
+    pub requests_per_sec: f64,
+    /// Requests a client may make in a burst after being idle.
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+}
+
+fn default_burst() -> u32 {
+    20
+}
+
+impl RateLimitConfig {
+    /// `SKYPULSE_RATE_LIMIT` (requests per second) enables limiting;
+    /// `SKYPULSE_RATE_BURST` sets the burst size.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(v) = std::env::var("SKYPULSE_RATE_LIMIT") {
+            let requests_per_sec = v.parse().with_context(|| format!("SKYPULSE_RATE_LIMIT '{}'", v))?;
+            let burst = cfg.as_ref().map_or_else(default_burst, |c| c.burst);
+            *cfg = Some(Self { requests_per_sec, burst });
+        }
+        if let (Ok(v), Some(cfg)) = (std::env::var("SKYPULSE_RATE_BURST"), cfg.as_mut()) {
+            cfg.burst = v.parse().with_context(|| format!("SKYPULSE_RATE_BURST '{}'", v))?;
+        }
+        if let Some(cfg) = cfg {
+            if cfg.requests_per_sec.is_nan() || cfg.requests_per_sec <= 0.0 || cfg.burst == 0 {
+                anyhow::bail!("rate_limit needs requests_per_sec > 0 and burst >= 1");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets are dropped once this many clients are tracked and theirs is full again.
+const PRUNE_ABOVE: usize = 10_000;
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    cfg: RateLimitConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: RateLimitConfig) -> Self {
+        Self { cfg, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Clients a bucket is currently kept for.
+    pub fn clients(&self) -> usize {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn refill(&self, b: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(b.updated).as_secs_f64();
+        b.tokens = (b.tokens + elapsed * self.cfg.requests_per_sec).min(self.cfg.burst as f64);
+        b.updated = now;
+    }
+
+    /// Take a token from `client`'s bucket, or return how long until one is available.
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() > PRUNE_ABOVE {
+            let burst = self.cfg.burst as f64;
+            buckets.retain(|_, b| {
+                self.refill(b, now);
+                b.tokens < burst
+            });
+        }
+        let b = buckets.entry(client.to_string()).or_insert(Bucket { tokens: self.cfg.burst as f64, updated: now });
+        self.refill(b, now);
+        if b.tokens >= 1.0 {
+            b.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - b.tokens) / self.cfg.requests_per_sec))
+        }
+    }
+}
+
+/// Middleware for the write routes. Runs after authentication, so an API key
+/// used as the bucket key has already been checked.
+pub async fn limit(req: Request, next: Next) -> Response {
+    let Some(state) = req.extensions().get::<Arc<crate::AppState>>() else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "missing state").into_response();
+    };
+    let Some(limiter) = &state.rate_limiter else { return next.run(req).await };
+    let token = if state.auth.enabled() { super::http::request_token(req.headers()) } else { None };
+    let client = match token {
+        Some(t) => format!("key:{}", t),
+        None => match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+            None => "unknown".to_string(),
+        },
+    };
+    match limiter.check(&client, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            state.metrics.rate_limited.inc_by(1);
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after.to_string())], "rate limit exceeded")
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_at_the_configured_rate() {
+        let rl = RateLimiter::new(RateLimitConfig { requests_per_sec: 2.0, burst: 2 });
+        let t0 = Instant::now();
+        assert!(rl.check("a", t0).is_ok());
+        assert!(rl.check("a", t0).is_ok());
+        let wait = rl.check("a", t0).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // other clients have their own bucket
+        assert!(rl.check("b", t0).is_ok());
+        assert!(rl.check("a", t0 + Duration::from_millis(500)).is_ok());
+        assert!(rl.check("a", t0 + Duration::from_millis(500)).is_err());
+    }
+}
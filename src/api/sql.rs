@@ -0,0 +1,95 @@
+// The SQL endpoint: a statement of `query::sql` in the `q` parameter of a
+// GET, or as the body of a POST, answered with the JSON of `/query`.
+
+use axum::{
+    extract::{Extension, Query},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use crate::query::{self, sql::Statement, QueryResult};
+
+#[derive(Deserialize)]
+pub struct SqlParams {
+    pub q: String,
+}
+
+async fn run(state: Arc<crate::AppState>, headers: &HeaderMap, sql: &str) -> Result<Json<QueryResult>, (StatusCode, String)> {
+    let statement = Statement::parse(sql, chrono::Utc::now()).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let filter = state.auth.filter(super::http::request_token(headers).as_deref());
+    super::query::check_derived(&statement.query.fields, filter)?;
+    let mut result = query::execute_isolated(state, statement.query.clone())
+        .await
+        .map_err(super::query::execution_error)?;
+    statement.project(&mut result);
+    Ok(Json(result))
+}
+
+/// GET /api/v1/sql?q=SELECT ...
+pub async fn get_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<SqlParams>,
+) -> Result<Json<QueryResult>, (StatusCode, String)> {
+    run(state, &headers, &params.q).await
+}
+
+/// POST /api/v1/sql with the statement as the body.
+pub async fn post_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<QueryResult>, (StatusCode, String)> {
+    run(state, &headers, &body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::{Observation, Timestamp};
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn answers_statements() {
+        let dir = crate::test_util::TempDir::new("sql");
+        let state = Arc::new(crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let rows: Vec<Observation> = (0..4)
+            .map(|i| {
+                let mut o = Observation::empty("A", Timestamp(1_735_689_600_000 + i * 600_000));
+                o.set_field("temp", 20.0 + i as f64);
+                o.set_field("humidity", 80.0);
+                o
+            })
+            .collect();
+        crate::ingest::append(&state, rows).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/sql", listener.local_addr().unwrap());
+        let app = super::super::http::router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let resp = client
+            .post(&url)
+            .body("SELECT max(temp), count(*) FROM A WHERE time >= '2025-01-01T00:10:00Z' GROUP BY time(20m)")
+            .send()
+            .await
+            .unwrap();
+        let v: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(v["rows"][0]["aggregates"], serde_json::json!({ "humidity": { "count": 1 }, "temp": { "count": 1, "max": 21.0 } }));
+        assert_eq!(v["rows"][1]["aggregates"]["temp"]["max"], 23.0);
+        let resp = client.get(&url).query(&[("q", "select temp from A order by time desc limit 1")]).send().await.unwrap();
+        let v: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(v["rows"], serde_json::json!([{ "station_id": "A", "time": "2025-01-01T00:30:00Z", "fields": { "temp": 23.0 } }]));
+        let resp = client.get(&url).query(&[("q", "SELECT temp, dew_point FROM A ORDER BY time DESC LIMIT 1")]).send().await.unwrap();
+        let v: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!((v["rows"][0]["fields"]["dew_point"].as_f64().unwrap() * 100.0).round(), 1936.0);
+        // the streamed rows of /query compute them too
+        let resp = client.get(url.replace("sql", "query")).query(&[("station_id", "A"), ("fields", "dew_point")]).send().await.unwrap();
+        let v: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(v["rows"].as_array().unwrap().iter().filter(|r| r["fields"]["dew_point"].is_f64()).count(), 4);
+        let resp = client.get(&url).query(&[("q", "SELECT temp FROM A WHERE humidity > 50")]).send().await.unwrap();
+        assert_eq!(resp.status(), 400);
+        assert_eq!(resp.text().await.unwrap(), "expected TIME, found humidity");
+    }
+}
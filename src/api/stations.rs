@@ -0,0 +1,174 @@
+use axum::{extract::{Extension, Path, Query}, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::health::Health;
+use crate::storage::manifest::StationInfo;
+use crate::stations::StationMeta;
+use crate::storage::memtable::Observation;
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    /// `health` lists the least healthy stations first.
+    pub sort: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Listing {
+    #[serde(flatten)]
+    info: StationInfo,
+    health: Health,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<StationMeta>,
+}
+
+/// GET /api/v1/stations[?sort=health]
+pub async fn list_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let mut registered: std::collections::BTreeMap<String, StationMeta> =
+        state.stations.list().await.into_iter().map(|m| (m.station_id.clone(), m)).collect();
+    let mut stations: Vec<Listing> = crate::health::assess_all(&state)
+        .await
+        .into_iter()
+        .map(|(info, health)| Listing { metadata: registered.remove(&info.station_id), info, health })
+        .collect();
+    // registered stations that have not reported yet
+    let now = chrono::Utc::now().timestamp_millis();
+    for (id, meta) in registered {
+        let health = state.health.assess(&id, None, now);
+        stations.push(Listing { info: StationInfo::new(&id), health, metadata: Some(meta) });
+    }
+    match params.sort.as_deref() {
+        None => {}
+        Some("health") => stations.sort_by(|a, b| a.health.score.total_cmp(&b.health.score)),
+        Some(other) => return Err((StatusCode::BAD_REQUEST, format!("unknown sort '{}'; expected health", other))),
+    }
+    Ok(Json(serde_json::json!({ "stations": stations })))
+}
+
+/// GET /api/v1/stations/:id
+pub async fn get_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Path(station_id): Path<String>,
+) -> Result<Json<StationMeta>, (StatusCode, String)> {
+    match state.stations.get(&station_id).await {
+        Some(meta) => Ok(Json(meta)),
+        None => Err((StatusCode::NOT_FOUND, format!("station {} is not registered", station_id))),
+    }
+}
+
+/// PUT /api/v1/stations/:id registers the station's metadata, replacing
+/// what it had.
+pub async fn put_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Path(station_id): Path<String>,
+    Json(mut meta): Json<StationMeta>,
+) -> Result<Json<StationMeta>, (StatusCode, String)> {
+    if let Some(reason) = state.write_refusal() {
+        return Err((StatusCode::FORBIDDEN, reason.to_string()));
+    }
+    if !meta.station_id.is_empty() && meta.station_id != station_id {
+        return Err((StatusCode::BAD_REQUEST, format!("body is for station {}, path for {}", meta.station_id, station_id)));
+    }
+    meta.station_id = station_id;
+    meta.validate().map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    state.stations.put(meta).await.map(Json).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))
+}
+
+/// DELETE /api/v1/stations/:id forgets the metadata; the readings stay.
+pub async fn delete_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Path(station_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let Some(reason) = state.write_refusal() {
+        return Err((StatusCode::FORBIDDEN, reason.to_string()));
+    }
+    match state.stations.remove(&station_id).await {
+        Ok(true) => Ok(Json(serde_json::json!({ "status": "ok" }))),
+        Ok(false) => Err((StatusCode::NOT_FOUND, format!("station {} is not registered", station_id))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    }
+}
+
+/// GET /api/v1/stations/:id/latest
+pub async fn latest_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Path(station_id): Path<String>,
+) -> Result<Json<Observation>, (StatusCode, String)> {
+    let id = station_id.clone();
+    let rt = state.runtimes.query.clone();
+    let latest = crate::query::isolate(&rt, "latest-value lookup", async move { crate::query::latest(&state, &id).await });
+    match latest.await {
+        Ok(Some(obs)) => Ok(Json(obs)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("no data for station {}", station_id))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TailParams {
+    /// Defaults to 100, capped at `query::MAX_TAIL`.
+    pub n: Option<usize>,
+}
+
+/// GET /api/v1/stations/:id/tail?n=100
+pub async fn tail_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    Path(station_id): Path<String>,
+    Query(params): Query<TailParams>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let n = params.n.unwrap_or(100);
+    if n == 0 || n > crate::query::MAX_TAIL {
+        return Err((StatusCode::BAD_REQUEST, format!("n must be between 1 and {}", crate::query::MAX_TAIL)));
+    }
+    let id = station_id.clone();
+    let rt = state.runtimes.query.clone();
+    let tail = crate::query::isolate(&rt, "tail lookup", async move { crate::query::tail(&state, &id, n).await });
+    match tail.await {
+        Ok(rows) if rows.is_empty() => Err((StatusCode::NOT_FOUND, format!("no data for station {}", station_id))),
+        Ok(rows) => Ok(Json(serde_json::json!({ "station_id": station_id, "observations": rows }))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn registers_and_enriches_with_metadata() {
+        let dir = crate::test_util::TempDir::new("station-meta");
+        let state = Arc::new(crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let mut o = Observation::empty("HK001", crate::storage::memtable::Timestamp(1_735_689_600_000));
+        o.set_field("temp", 20.0);
+        crate::ingest::append(&state, vec![o]).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/api/v1", listener.local_addr().unwrap());
+        let app = super::super::http::router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = crate::client::Client::new(&base.replace("/api/v1", "")).unwrap();
+        let meta: StationMeta =
+            serde_json::from_value(serde_json::json!({ "station_id": "HK001", "name": "Tsim Sha Tsui", "latitude": 22.3, "longitude": 114.17 })).unwrap();
+        let stored = client.register_station(&meta).await.unwrap();
+        assert_eq!((stored.name.as_deref(), client.station("HK001").await.unwrap()), (Some("Tsim Sha Tsui"), stored.clone()));
+        client.register_station(&StationMeta { station_id: "KP002".into(), latitude: None, longitude: None, ..meta }).await.unwrap();
+
+        let http = reqwest::Client::new();
+        let bad = http.put(format!("{}/stations/HK001", base)).json(&serde_json::json!({ "latitude": 95.0, "longitude": 0.0 })).send().await.unwrap();
+        assert_eq!(bad.status(), 400);
+        let listing: serde_json::Value = http.get(format!("{}/stations", base)).send().await.unwrap().json().await.unwrap();
+        let ids: Vec<&str> = listing["stations"].as_array().unwrap().iter().map(|s| s["station_id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["HK001", "KP002"]);
+        assert_eq!(listing["stations"][0]["metadata"]["latitude"], 22.3);
+
+        let q = crate::client::QueryRequest { metadata: true, ..crate::client::QueryRequest::new("HK001") };
+        assert_eq!(client.query(&q).await.unwrap().metadata, Some(stored));
+        assert!(client.query(&crate::client::QueryRequest::new("HK001")).await.unwrap().metadata.is_none());
+
+        assert_eq!(http.delete(format!("{}/stations/KP002", base)).send().await.unwrap().status(), 200);
+        assert_eq!(http.get(format!("{}/stations/KP002", base)).send().await.unwrap().status(), 404);
+    }
+}
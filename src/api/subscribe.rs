@@ -0,0 +1,156 @@
+// Live subscriptions over WebSocket. A client names stations, exactly or as
+// `*` patterns, in the query string or later in text frames:
+//
+//   {"subscribe": ["HK*", "TPE001"]}    {"unsubscribe": ["HK*"]}
+//
+// and is answered with the patterns now in effect. Every observation written
+// for a matching station then arrives as one JSON text frame, rewritten by
+// the key's response filter. Writers never wait for subscribers: one that
+// falls behind gets `{"lagged": n}` and continues with the newest writes.
+
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Extension, Query},
+    http::HeaderMap,
+    response::Response,
+};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use super::filter::ResponseFilter;
+use crate::storage::memtable::Observation;
+
+/// Patterns one connection may hold.
+const MAX_PATTERNS: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct SubscribeParams {
+    /// Comma-separated station ids or patterns to start with.
+    pub stations: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Command {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(default)]
+    unsubscribe: Vec<String>,
+}
+
+/// The station patterns of one connection.
+#[derive(Debug, Default)]
+struct Subscription {
+    patterns: BTreeSet<String>,
+}
+
+impl Subscription {
+    fn add(&mut self, patterns: impl IntoIterator<Item = String>) -> Result<(), String> {
+        for p in patterns.into_iter().map(|p| p.trim().to_string()).filter(|p| !p.is_empty()) {
+            if self.patterns.len() >= MAX_PATTERNS && !self.patterns.contains(&p) {
+                return Err(format!("at most {} station patterns per connection", MAX_PATTERNS));
+            }
+            self.patterns.insert(p);
+        }
+        Ok(())
+    }
+
+    /// Apply a client frame; the reply to send back.
+    fn command(&mut self, text: &str) -> String {
+        let cmd = match serde_json::from_str::<Command>(text) {
+            Ok(cmd) => cmd,
+            Err(e) => return serde_json::json!({ "error": format!("invalid message: {}", e) }).to_string(),
+        };
+        for p in &cmd.unsubscribe {
+            self.patterns.remove(p.trim());
+        }
+        if let Err(e) = self.add(cmd.subscribe) {
+            return serde_json::json!({ "error": e }).to_string();
+        }
+        serde_json::json!({ "subscribed": self.patterns }).to_string()
+    }
+
+    fn wants(&self, station_id: &str) -> bool {
+        self.patterns.iter().any(|p| crate::storage::retention::matches(p, station_id))
+    }
+
+    /// One frame per observation of a written batch the connection wants.
+    fn frames(&self, obs: &[Observation], filter: Option<&ResponseFilter>) -> Vec<String> {
+        obs.iter()
+            .filter(|o| self.wants(&o.station_id))
+            .filter_map(|o| {
+                let mut v = serde_json::to_value(o).ok()?;
+                if let Some(f) = filter {
+                    f.apply(&mut v);
+                }
+                Some(v.to_string())
+            })
+            .collect()
+    }
+}
+
+/// GET /api/v1/subscribe?stations=HK*,TPE001 (WebSocket)
+pub async fn subscribe_handler(
+    Extension(state): Extension<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<SubscribeParams>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+    let filter = state.auth.filter(super::http::request_token(&headers).as_deref()).cloned();
+    let mut sub = Subscription::default();
+    let initial = params.stations.iter().flat_map(|s| s.split(',')).map(str::to_string);
+    sub.add(initial).map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e))?;
+    Ok(ws.on_upgrade(move |socket| serve(state, socket, sub, filter)))
+}
+
+async fn serve(state: Arc<crate::AppState>, mut socket: WebSocket, mut sub: Subscription, filter: Option<ResponseFilter>) {
+    let mut live = state.live.subscribe();
+    loop {
+        let frames = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => vec![sub.command(&text)],
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // pings are answered by the socket itself
+                Some(Ok(_)) => continue,
+            },
+            batch = live.recv() => match batch {
+                Ok(obs) => sub.frames(&obs, filter.as_ref()),
+                Err(RecvError::Lagged(n)) => vec![serde_json::json!({ "lagged": n }).to_string()],
+                Err(RecvError::Closed) => break,
+            },
+        };
+        for frame in frames {
+            if socket.send(Message::Text(frame)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_patterns_and_applies_commands() {
+        let mut sub = Subscription::default();
+        sub.add(["HK*".to_string(), " TPE001 ".to_string(), String::new()]).unwrap();
+        assert_eq!(sub.patterns.iter().collect::<Vec<_>>(), ["HK*", "TPE001"]);
+
+        let at = |id: &str, temp: f64| {
+            let mut o = Observation::empty(id, "2025-01-01T00:00:00Z".parse().unwrap());
+            o.set_field("temp", temp);
+            o
+        };
+        let batch = [at("HK001", 21.437), at("TPE002", 1.0), at("TPE001", 2.0)];
+        let filter = ResponseFilter { round: [("temp".to_string(), 1)].into(), ..Default::default() };
+        let frames = sub.frames(&batch, Some(&filter));
+        assert_eq!(frames.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(&frames[0]).unwrap();
+        assert_eq!((first["station_id"].as_str(), first["fields"]["temp"].as_f64()), (Some("HK001"), Some(21.4)));
+
+        assert_eq!(sub.command(r#"{"unsubscribe": ["HK*"], "subscribe": ["TPE*"]}"#), r#"{"subscribed":["TPE*","TPE001"]}"#);
+        assert_eq!(sub.frames(&batch, None).len(), 2);
+        assert!(sub.command(r#"{"watch": ["A"]}"#).contains("error"));
+    }
+}
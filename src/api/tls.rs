@@ -0,0 +1,67 @@
+// Optional TLS termination for every HTTP listener. The certificate chain and
+// private key are PEM files; SIGHUP reloads them in place so a renewed
+// certificate takes effect without a restart or dropped connections.
+
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Sender as BroadcastSender;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    /// Enabled by setting both `SKYPULSE_TLS_CERT` and `SKYPULSE_TLS_KEY`.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        match (std::env::var("SKYPULSE_TLS_CERT"), std::env::var("SKYPULSE_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => *cfg = Some(Self { cert: cert.into(), key: key.into() }),
+            (Err(_), Err(_)) => {}
+            _ => anyhow::bail!("SKYPULSE_TLS_CERT and SKYPULSE_TLS_KEY must be set together"),
+        }
+        Ok(())
+    }
+}
+
+pub async fn load(cfg: &TlsConfig) -> Result<RustlsConfig> {
+    // more than one rustls crypto provider is compiled in, so pick one explicitly
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(&cfg.cert, &cfg.key)
+        .await
+        .with_context(|| format!("loading TLS certificate {} and key {}", cfg.cert.display(), cfg.key.display()))
+}
+
+/// Reload the certificate and key whenever the process receives SIGHUP. A
+/// failed reload keeps serving the previous certificate.
+pub async fn reload_on_sighup(rustls: RustlsConfig, cfg: TlsConfig, shutdown: BroadcastSender<()>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("cannot listen for SIGHUP, TLS reload disabled: {}", e);
+                return;
+            }
+        };
+        let mut shutdown_sub = shutdown.subscribe();
+        loop {
+            tokio::select! {
+                _ = shutdown_sub.recv() => break,
+                Some(()) = hup.recv() => {
+                    match rustls.reload_from_pem_file(&cfg.cert, &cfg.key).await {
+                        Ok(()) => tracing::info!("reloaded TLS certificate {}", cfg.cert.display()),
+                        Err(e) => tracing::error!("TLS reload failed, keeping the previous certificate: {}", e),
+                    }
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (rustls, cfg, shutdown);
+}
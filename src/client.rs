@@ -0,0 +1,368 @@
+// HTTP client for the JSON API, so ingestion agents and tools written in
+// Rust do not each hand-roll requests: typed single and batch writes and
+// range queries over reqwest, against the server's own data or a namespace.
+// Connection failures, timeouts, 429 and 502-504 are retried with
+// exponential backoff (honouring `Retry-After`); writes are safe to repeat
+// because the server keeps the last write of a (station, time) point.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use crate::stations::StationMeta;
+use crate::storage::memtable::Observation;
+use crate::storage::rollup::RollupRow;
+
+/// A request the server answered with an error status; other failures are
+/// transport errors. Reach it with `anyhow::Error::downcast_ref`.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server answered {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+pub struct ClientBuilder {
+    base_url: String,
+    api_key: Option<String>,
+    namespace: Option<String>,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl ClientBuilder {
+    /// Sent as `X-Api-Key` with every request.
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// Work on a namespace's data (`/api/v1/db/<name>`).
+    pub fn namespace(mut self, name: impl Into<String>) -> Self {
+        self.namespace = Some(name.into());
+        self
+    }
+
+    /// Per-attempt timeout (default 30s).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retries after the first attempt (default 5); 0 turns retrying off.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// First wait between attempts, doubled after each (default 100ms), and
+    /// the longest wait (default 10s).
+    pub fn backoff(mut self, first: Duration, max: Duration) -> Self {
+        (self.backoff, self.max_backoff) = (first, max.max(first));
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        let http = reqwest::Client::builder().timeout(self.timeout).build()?;
+        let root = self.base_url.trim_end_matches('/');
+        let base = match &self.namespace {
+            Some(ns) => format!("{}/api/v1/db/{}", root, ns),
+            None => format!("{}/api/v1", root),
+        };
+        Ok(Client { http, base, api_key: self.api_key, retries: self.retries, backoff: self.backoff, max_backoff: self.max_backoff })
+    }
+}
+
+/// Range query parameters; see `GET /api/v1/query`.
+#[derive(Debug, Clone, Default)]
+pub struct QueryRequest {
+    pub station_id: String,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    /// `raw`, `1m`, `1h` or `auto` (the server's default).
+    pub resolution: Option<String>,
+    /// Aggregations such as `mean` or `max`; rows are returned when empty.
+    pub agg: Vec<String>,
+    pub fields: Vec<String>,
+    /// Window for aggregations, e.g. `1h`.
+    pub interval: Option<String>,
+    /// IANA zone such as `Asia/Hong_Kong` that aligns `interval` windows.
+    pub tz: Option<String>,
+    /// `null`, `previous` or `linear` to also return `interval` windows
+    /// without observations.
+    pub fill: Option<String>,
+    /// `metric`, `imperial` or `field:unit` overrides such as `temp:F`.
+    pub units: Option<String>,
+    /// Also return the station's registered metadata.
+    pub metadata: bool,
+    /// Most recent first.
+    pub descending: bool,
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page of raw rows.
+    pub cursor: Option<String>,
+}
+
+impl QueryRequest {
+    pub fn new(station_id: impl Into<String>) -> Self {
+        Self { station_id: station_id.into(), ..Default::default() }
+    }
+
+    pub fn range(mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        (self.start, self.end) = (Some(start), Some(end));
+        self
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        let time = |t: &DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        let mut p = vec![("station_id", self.station_id.clone())];
+        p.extend(self.start.as_ref().map(|t| ("start", time(t))));
+        p.extend(self.end.as_ref().map(|t| ("end", time(t))));
+        p.extend(self.resolution.clone().map(|r| ("resolution", r)));
+        if !self.agg.is_empty() {
+            p.push(("agg", self.agg.join(",")));
+        }
+        if !self.fields.is_empty() {
+            p.push(("fields", self.fields.join(",")));
+        }
+        p.extend(self.interval.clone().map(|i| ("interval", i)));
+        p.extend(self.tz.clone().map(|z| ("tz", z)));
+        p.extend(self.fill.clone().map(|f| ("fill", f)));
+        p.extend(self.units.clone().map(|u| ("units", u)));
+        if self.metadata {
+            p.push(("metadata", "true".to_string()));
+        }
+        if self.descending {
+            p.push(("order", "desc".to_string()));
+        }
+        p.extend(self.limit.map(|l| ("limit", l.to_string())));
+        p.extend(self.cursor.clone().map(|c| ("cursor", c)));
+        p
+    }
+}
+
+/// field -> aggregation -> value; `None` when the field had no values.
+pub type Aggregates = BTreeMap<String, BTreeMap<String, Option<f64>>>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Bucket {
+    pub time: String,
+    pub aggregates: Aggregates,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Rows {
+    Raw(Vec<Observation>),
+    Rollup(Vec<RollupRow>),
+    Buckets(Vec<Bucket>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryResponse {
+    pub station_id: String,
+    /// Set when `QueryRequest::metadata` asked for it.
+    pub metadata: Option<StationMeta>,
+    /// Resolution the server used: `raw`, `1m` or `1h`.
+    pub resolution: String,
+    pub downgraded_from: Option<String>,
+    pub rows: Option<Rows>,
+    pub aggregates: Option<Aggregates>,
+    /// Set when `limit` cut raw rows short; see `QueryRequest::cursor`.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchError {
+    /// Position of the row in the batch.
+    pub index: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// Rows whose station and time were already buffered or earlier in the batch.
+    pub duplicates: usize,
+    pub errors: Vec<BatchError>,
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    /// `/api/v1` or a namespace's `/api/v1/db/<name>` on the server.
+    base: String,
+    api_key: Option<String>,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Client {
+    /// A client with the defaults for `base_url`, e.g. `http://localhost:8080`.
+    pub fn new(base_url: &str) -> Result<Self> {
+        Self::builder(base_url).build()
+    }
+
+    pub fn builder(base_url: &str) -> ClientBuilder {
+        ClientBuilder {
+            base_url: base_url.to_string(),
+            api_key: None,
+            namespace: None,
+            timeout: Duration::from_secs(30),
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+
+    /// Write one observation.
+    pub async fn write(&self, obs: &Observation) -> Result<()> {
+        let url = format!("{}/write", self.base);
+        self.send(|| self.http.post(&url).json(obs)).await?;
+        Ok(())
+    }
+
+    /// Write observations in one request; rows the server rejects are listed
+    /// in the report rather than failing the call.
+    pub async fn write_batch(&self, obs: &[Observation]) -> Result<BatchReport> {
+        let mut body = Vec::new();
+        for o in obs {
+            serde_json::to_writer(&mut body, o)?;
+            body.push(b'\n');
+        }
+        let url = format!("{}/write/batch", self.base);
+        let resp = self.send(|| self.http.post(&url).header("content-type", "application/x-ndjson").body(body.clone())).await?;
+        resp.json().await.context("decoding the batch report")
+    }
+
+    pub async fn query(&self, q: &QueryRequest) -> Result<QueryResponse> {
+        let url = format!("{}/query", self.base);
+        let params = q.params();
+        let resp = self.send(|| self.http.get(&url).query(&params)).await?;
+        resp.json().await.context("decoding the query result")
+    }
+
+    /// Run a SQL statement such as `SELECT mean(temp) FROM HK001 GROUP BY time(1h)`.
+    pub async fn sql(&self, statement: &str) -> Result<QueryResponse> {
+        let url = format!("{}/sql", self.base);
+        let resp = self.send(|| self.http.post(&url).body(statement.to_string())).await?;
+        resp.json().await.context("decoding the query result")
+    }
+
+    /// A station's registered metadata.
+    pub async fn station(&self, station_id: &str) -> Result<StationMeta> {
+        let url = format!("{}/stations/{}", self.base, station_id);
+        let resp = self.send(|| self.http.get(&url)).await?;
+        resp.json().await.context("decoding the station metadata")
+    }
+
+    /// Register a station's metadata, replacing what it had; returns it as stored.
+    pub async fn register_station(&self, meta: &StationMeta) -> Result<StationMeta> {
+        let url = format!("{}/stations/{}", self.base, meta.station_id);
+        let resp = self.send(|| self.http.put(&url).json(meta)).await?;
+        resp.json().await.context("decoding the station metadata")
+    }
+
+    /// Send the request `build` makes, retrying what may succeed later.
+    async fn send(&self, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+        let mut wait = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let mut req = build();
+            if let Some(key) = &self.api_key {
+                req = req.header("x-api-key", key);
+            }
+            let retry_after = match req.send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(resp),
+                Ok(resp) => {
+                    let status = resp.status();
+                    let retryable = matches!(status.as_u16(), 429 | 502 | 503 | 504);
+                    let after = resp
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok()?.parse().ok())
+                        .map(Duration::from_secs);
+                    let message = resp.text().await.unwrap_or_default();
+                    if !retryable || attempt >= self.retries {
+                        return Err(ApiError { status, message }.into());
+                    }
+                    after
+                }
+                Err(e) if (e.is_connect() || e.is_timeout()) && attempt < self.retries => None,
+                Err(e) => return Err(e.into()),
+            };
+            attempt += 1;
+            let delay = retry_after.unwrap_or(wait).min(self.max_backoff);
+            tracing::debug!("client: retrying in {:?} (attempt {} of {})", delay, attempt, self.retries);
+            tokio::time::sleep(delay).await;
+            wait = (wait * 2).min(self.max_backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use axum::routing::post;
+    use super::*;
+
+    async fn serve(app: axum::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn writes_and_queries_with_retries() {
+        let dir = crate::test_util::TempDir::new("client");
+        let state = Arc::new(crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let client = Client::new(&serve(crate::api::http::router(state)).await).unwrap();
+        let at = |t: &str, temp: f64| {
+            let mut o = Observation::empty("A", t.parse().unwrap());
+            o.set_field("temp", temp);
+            o
+        };
+        client.write(&at("2025-01-01T00:00:00Z", 20.0)).await.unwrap();
+        let report = client.write_batch(&[at("2025-01-01T00:10:00Z", 22.0), at("2025-01-01T00:10:00Z", 23.0)]).await.unwrap();
+        assert_eq!((report.accepted, report.rejected, report.duplicates), (1, 0, 1));
+
+        let raw = client.query(&QueryRequest { resolution: Some("raw".into()), ..QueryRequest::new("A") }).await.unwrap();
+        let Some(Rows::Raw(rows)) = raw.rows else { panic!("expected raw rows, got {:?}", raw.rows) };
+        assert_eq!(rows.len(), 2);
+        let mean = client.query(&QueryRequest { agg: vec!["mean".into()], fields: vec!["temp".into()], ..QueryRequest::new("A") }).await.unwrap();
+        assert_eq!(mean.aggregates.unwrap()["temp"]["mean"], Some(21.0));
+
+        let missing = client.query(&QueryRequest { resolution: Some("5s".into()), ..QueryRequest::new("A") }).await.unwrap_err();
+        assert_eq!(missing.downcast_ref::<ApiError>().unwrap().status, StatusCode::BAD_REQUEST);
+
+        // a server that is unavailable twice before it answers
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let flaky = axum::Router::new().route(
+            "/api/v1/db/ops/write",
+            post(move || {
+                let n = seen.fetch_add(1, Ordering::SeqCst);
+                async move { if n < 2 { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK } }
+            }),
+        );
+        let base = serve(flaky).await;
+        let quick = |retries| Client::builder(&base).namespace("ops").retries(retries).backoff(Duration::from_millis(1), Duration::from_millis(5)).build().unwrap();
+        quick(5).write(&at("2025-01-01T00:00:00Z", 1.0)).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        calls.store(0, Ordering::SeqCst);
+        assert!(quick(1).write(&at("2025-01-01T00:00:00Z", 1.0)).await.is_err());
+    }
+}
@@ -0,0 +1,392 @@
+// Columnar output for data-science tools: observations as Arrow record
+// batches, written as Parquet that pandas and Polars load directly or as an
+// Arrow IPC stream. The columns are `station_id`, `time` (UTC milliseconds),
+// one per field typed by the values it holds (float64, boolean, or string
+// when a field mixes types) and one string column per tag; a tag named like
+// a field or a fixed column is left out. Both formats fix the schema before
+// the first row, so callers collect it with `Columns::observe` in a first
+// pass over the rows; `scan_columns` spools the rows of that pass to disk so
+// the second writes those same rows, none with a column the schema lacks.
+// Aggregated query results become `time` plus one
+// column per `<field>_<aggregation>`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use arrow_array::{ArrayRef, BooleanArray, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray, UInt64Array};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde_json::Value;
+use tokio_stream::StreamExt;
+use crate::query::spill::{Spool, SpoolReader};
+use crate::storage::memtable::{FieldValue, Observation, Timestamp};
+use crate::AppState;
+
+/// Rows per record batch and Parquet row group. A streamed file hands out
+/// its bytes a row group at a time, so this also bounds what a writer holds
+/// in memory.
+const BATCH_ROWS: usize = 64 * 1024;
+/// Rows per batch when scanning a station.
+const SCAN_BATCH: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Only nulls so far; written as float64.
+    Unknown,
+    Number,
+    Bool,
+    Text,
+}
+
+impl Kind {
+    fn widen(self, v: &FieldValue) -> Kind {
+        let seen = match v {
+            FieldValue::Null => return self,
+            FieldValue::Number(_) => Kind::Number,
+            FieldValue::Bool(_) => Kind::Bool,
+            FieldValue::Text(_) => Kind::Text,
+        };
+        if self == Kind::Unknown || self == seen {
+            seen
+        } else {
+            Kind::Text
+        }
+    }
+
+    fn data_type(self) -> DataType {
+        match self {
+            Kind::Bool => DataType::Boolean,
+            Kind::Text => DataType::Utf8,
+            Kind::Unknown | Kind::Number => DataType::Float64,
+        }
+    }
+}
+
+fn text(v: Option<&FieldValue>) -> Option<String> {
+    match v? {
+        FieldValue::Number(n) => Some(n.to_string()),
+        FieldValue::Bool(b) => Some(b.to_string()),
+        FieldValue::Text(t) => Some(t.clone()),
+        FieldValue::Null => None,
+    }
+}
+
+fn time_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+}
+
+/// The columns of a set of observations.
+#[derive(Debug, Clone, Default)]
+pub struct Columns {
+    fields: BTreeMap<String, Kind>,
+    tags: BTreeSet<String>,
+    /// Set when only these fields are wanted.
+    only: Option<BTreeSet<String>>,
+}
+
+impl Columns {
+    /// Columns for the named fields only, typed by the rows observed; every
+    /// field seen when `fields` is empty. Tags are always included.
+    pub fn new(fields: &[String]) -> Self {
+        if fields.is_empty() {
+            return Self::default();
+        }
+        Self {
+            fields: fields.iter().map(|f| (f.clone(), Kind::Unknown)).collect(),
+            tags: BTreeSet::new(),
+            only: Some(fields.iter().cloned().collect()),
+        }
+    }
+
+    pub fn observe(&mut self, o: &Observation) {
+        for (name, v) in &o.fields {
+            if self.only.as_ref().is_some_and(|only| !only.contains(name)) {
+                continue;
+            }
+            let kind = self.fields.entry(name.clone()).or_insert(Kind::Unknown);
+            *kind = kind.widen(v);
+        }
+        self.tags.extend(o.tags.keys().cloned());
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &String> {
+        self.tags.iter().filter(|t| !self.fields.contains_key(*t) && *t != "station_id" && *t != "time")
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        let mut columns = vec![
+            Field::new("station_id", DataType::Utf8, false),
+            Field::new("time", time_type(), false),
+        ];
+        columns.extend(self.fields.iter().map(|(name, kind)| Field::new(name, kind.data_type(), true)));
+        columns.extend(self.tags().map(|t| Field::new(t, DataType::Utf8, true)));
+        Arc::new(Schema::new(columns))
+    }
+
+    /// The field columns, in order.
+    pub fn fields(&self) -> impl Iterator<Item = &String> {
+        self.fields.keys()
+    }
+
+    /// `rows` as one record batch of this schema.
+    pub fn batch(&self, rows: &[Observation]) -> Result<RecordBatch> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|o| o.station_id.as_str()))),
+            Arc::new(TimestampMillisecondArray::from_iter_values(rows.iter().map(|o| o.time.millis())).with_timezone("UTC")),
+        ];
+        for (name, kind) in &self.fields {
+            let values = rows.iter().map(|o| o.fields.get(name));
+            columns.push(match kind {
+                Kind::Bool => Arc::new(values.map(|v| match v {
+                    Some(FieldValue::Bool(b)) => Some(*b),
+                    _ => None,
+                }).collect::<BooleanArray>()),
+                Kind::Text => Arc::new(values.map(text).collect::<StringArray>()),
+                Kind::Unknown | Kind::Number => Arc::new(values.map(|v| v.and_then(FieldValue::as_f64)).collect::<Float64Array>()),
+            });
+        }
+        for tag in self.tags() {
+            columns.push(Arc::new(rows.iter().map(|o| o.tags.get(tag).map(String::as_str)).collect::<StringArray>()));
+        }
+        Ok(RecordBatch::try_new(self.schema(), columns)?)
+    }
+}
+
+/// The first pass over a station's raw rows: their columns, and the rows
+/// themselves to write in the second.
+pub struct Scan {
+    pub columns: Columns,
+    pub rows: SpoolReader,
+}
+
+/// The first `limit` of a station's raw rows in `[start, end)`, each row
+/// first passed through `map`.
+pub async fn scan_columns(
+    state: Arc<AppState>,
+    station_id: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    fields: &[String],
+    limit: Option<usize>,
+    map: impl Fn(Observation) -> Observation,
+) -> Result<Scan> {
+    let mut columns = Columns::new(fields);
+    let mut spool = Spool::create(&state.config.spill).await?;
+    let mut left = limit.unwrap_or(usize::MAX);
+    let mut batches = crate::query::stream::observations(state, station_id, start, end, SCAN_BATCH);
+    while left > 0 {
+        let Some(batch) = batches.next().await else { break };
+        let mut rows: Vec<Observation> = batch.map_err(|e| anyhow!("{}", e))?.observations.into_iter().map(&map).collect();
+        rows.truncate(left);
+        left -= rows.len();
+        rows.iter().for_each(|o| columns.observe(o));
+        spool.push(&rows).await?;
+    }
+    Ok(Scan { columns, rows: spool.read(SCAN_BATCH).await? })
+}
+
+/// A Parquet file being written row group by row group, zstd-compressed.
+pub struct ParquetWriter<W: Write + Send> {
+    columns: Columns,
+    writer: ArrowWriter<W>,
+    rows: usize,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    pub fn new(columns: Columns, out: W) -> Result<Self> {
+        let props = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .set_max_row_group_size(BATCH_ROWS)
+            .build();
+        let writer = ArrowWriter::try_new(out, columns.schema(), Some(props))?;
+        Ok(Self { columns, writer, rows: 0 })
+    }
+
+    pub fn write(&mut self, rows: &[Observation]) -> Result<()> {
+        if !rows.is_empty() {
+            self.writer.write(&self.columns.batch(rows)?)?;
+            self.rows += rows.len();
+        }
+        Ok(())
+    }
+
+    /// Rows written so far.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Write the last row group and the footer.
+    pub fn finish(self) -> Result<W> {
+        Ok(self.writer.into_inner()?)
+    }
+}
+
+impl ParquetWriter<Vec<u8>> {
+    /// The bytes of the row groups completed since the last call, for
+    /// streaming the file out as it is written.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer.inner_mut())
+    }
+}
+
+/// `rows` as record batches of at most `BATCH_ROWS`, with their schema.
+pub fn batches(rows: &[Observation]) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let mut columns = Columns::default();
+    rows.iter().for_each(|o| columns.observe(o));
+    let batches = rows.chunks(BATCH_ROWS).map(|chunk| columns.batch(chunk)).collect::<Result<_>>()?;
+    Ok((columns.schema(), batches))
+}
+
+/// Aggregated rows flattened to a table. Each row is a JSON object as the
+/// query API returns it, with an optional `station_id` and `time` and
+/// objects of `{field: {aggregation: value}}` (`fields` of rollups,
+/// `aggregates` of buckets) that become `<field>_<aggregation>` columns, in
+/// name order.
+pub struct AggregateTable<'a> {
+    /// Set when any row names a station.
+    pub station: Option<Vec<Option<&'a str>>>,
+    /// Set when any row has a time.
+    pub time: Option<Vec<Option<&'a str>>>,
+    pub columns: BTreeMap<String, Vec<Option<&'a serde_json::Number>>>,
+}
+
+impl<'a> AggregateTable<'a> {
+    pub fn new(rows: &'a [Value]) -> Result<Self> {
+        let mut station = Vec::new();
+        let mut time = Vec::new();
+        let mut columns: BTreeMap<String, Vec<Option<&serde_json::Number>>> = BTreeMap::new();
+        for (i, row) in rows.iter().enumerate() {
+            let row = row.as_object().ok_or_else(|| anyhow!("aggregate row {} is not an object", i))?;
+            station.push(row.get("station_id").and_then(Value::as_str));
+            time.push(row.get("time").and_then(Value::as_str));
+            for (field, aggs) in row.values().filter_map(Value::as_object).flat_map(|m| m.iter()) {
+                for (agg, v) in aggs.as_object().into_iter().flatten() {
+                    let column = columns.entry(format!("{}_{}", field, agg)).or_default();
+                    column.resize(i, None);
+                    column.push(v.as_number());
+                }
+            }
+        }
+        columns.values_mut().for_each(|c| c.resize(rows.len(), None));
+        let any = |v: Vec<Option<&'a str>>| v.iter().any(Option::is_some).then_some(v);
+        Ok(Self { station: any(station), time: any(time), columns })
+    }
+
+    /// The table as one record batch. Columns holding only unsigned
+    /// integers (the counts) are uint64, the rest float64.
+    pub fn batch(&self) -> Result<RecordBatch> {
+        let mut fields = Vec::new();
+        let mut columns: Vec<ArrayRef> = Vec::new();
+        if let Some(station) = &self.station {
+            fields.push(Field::new("station_id", DataType::Utf8, true));
+            columns.push(Arc::new(station.iter().copied().collect::<StringArray>()));
+        }
+        if let Some(time) = &self.time {
+            let millis = time.iter().map(|t| t.map(Timestamp::parse).transpose().map(|t| t.map(Timestamp::millis)));
+            fields.push(Field::new("time", time_type(), true));
+            columns.push(Arc::new(millis.collect::<Result<TimestampMillisecondArray>>()?.with_timezone("UTC")));
+        }
+        for (name, values) in &self.columns {
+            if values.iter().flatten().all(|n| n.is_u64()) {
+                fields.push(Field::new(name, DataType::UInt64, true));
+                columns.push(Arc::new(values.iter().map(|n| n.and_then(|n| n.as_u64())).collect::<UInt64Array>()));
+            } else {
+                fields.push(Field::new(name, DataType::Float64, true));
+                columns.push(Arc::new(values.iter().map(|n| n.and_then(|n| n.as_f64())).collect::<Float64Array>()));
+            }
+        }
+        let schema = Arc::new(Schema::new(fields));
+        if columns.is_empty() {
+            return Ok(RecordBatch::new_empty(schema));
+        }
+        Ok(RecordBatch::try_new(schema, columns)?)
+    }
+}
+
+/// `batches` in the Arrow IPC streaming format.
+pub fn write_arrow_stream(schema: &Schema, batches: &[RecordBatch], out: impl Write) -> Result<()> {
+    let mut writer = StreamWriter::try_new(out, schema)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Write `rows` as one Parquet file; returns the rows written.
+pub fn write_parquet(rows: &[Observation], out: impl Write + Send) -> Result<usize> {
+    let mut columns = Columns::default();
+    rows.iter().for_each(|o| columns.observe(o));
+    let mut writer = ParquetWriter::new(columns, out)?;
+    for chunk in rows.chunks(BATCH_ROWS) {
+        writer.write(chunk)?;
+    }
+    writer.finish()?;
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn types_columns_by_their_values_and_round_trips() {
+        let at = |t: &str| Observation::empty("A", t.parse().unwrap());
+        let mut a = at("2025-01-01T00:00:00Z");
+        a.set_field("temp", 20.5);
+        a.fields.insert("raining".into(), FieldValue::Bool(false));
+        a.fields.insert("state".into(), FieldValue::Text("ok".into()));
+        a.tags.insert("sensor".into(), "probe-1".into());
+        a.tags.insert("temp".into(), "shadowed".into());
+        let mut b = at("2025-01-01T00:10:00Z");
+        b.fields.insert("temp".into(), FieldValue::Null);
+        b.fields.insert("state".into(), FieldValue::Number(3.0));
+
+        let dir = crate::test_util::TempDir::new("columnar");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rows.parquet");
+        assert_eq!(write_parquet(&[a, b], std::fs::File::create(&path).unwrap()).unwrap(), 2);
+        let file = std::fs::File::open(&path).unwrap();
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(file).unwrap().build().unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        let schema = batch.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, ["station_id", "time", "raining", "state", "temp", "sensor"]);
+        let temp = batch.column_by_name("temp").unwrap().as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!((temp.value(0), temp.is_null(1)), (20.5, true));
+        let state = batch.column_by_name("state").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!((state.value(0), state.value(1)), ("ok", "3"));
+        let time = batch.column_by_name("time").unwrap().as_any().downcast_ref::<TimestampMillisecondArray>().unwrap();
+        assert_eq!(time.value(1) - time.value(0), 600_000);
+        assert!(batch.column_by_name("raining").unwrap().as_any().downcast_ref::<BooleanArray>().unwrap().is_null(1));
+    }
+
+    #[tokio::test]
+    async fn second_pass_writes_the_rows_of_the_first() {
+        let dir = crate::test_util::TempDir::new("columnar-scan");
+        let cfg = crate::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        let state = Arc::new(AppState::open(&cfg).await.unwrap());
+        let at = |t: &str, field: &str| {
+            let mut o = Observation::empty("A", t.parse().unwrap());
+            o.set_field(field, 1.0);
+            o
+        };
+        crate::ingest::append(&state, vec![at("2025-01-01T00:00:00Z", "temp"), at("2025-01-01T00:01:00Z", "temp")]).await.unwrap();
+        let mut scan = scan_columns(state.clone(), "A", None, None, &[], None, |o| o).await.unwrap();
+        // a late row with a new field lands between the passes
+        crate::ingest::append(&state, vec![at("2025-01-01T00:00:30Z", "wind")]).await.unwrap();
+
+        assert_eq!(scan.columns.fields().collect::<Vec<_>>(), ["temp"]);
+        let rows = scan.rows.next_batch().await.unwrap();
+        let times: Vec<String> = rows.iter().map(|o| o.time.to_string()).collect();
+        assert_eq!(times, ["2025-01-01T00:00:00Z", "2025-01-01T00:01:00Z"]);
+        assert!(scan.rows.next_batch().await.unwrap().is_empty());
+    }
+}
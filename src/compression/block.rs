@@ -0,0 +1,284 @@
+// Chunk-level block codec: the timestamps of a run of rows plus any number
+// of named float columns, in one self-describing blob. A column need not
+// have a value on every row: each column payload is a presence bitmap (one
+// bit per row, least significant first) followed by the tagged column of
+// the values present. The header lists every column with the offset and
+// length of its payload, so a reader decodes only the columns it asks for.
+// Layout (integers are LEB128):
+// - magic `SPB1`
+// - row count, column count, timestamps payload length
+// - per column: name length, name (UTF-8), payload offset, payload length
+// - the timestamps payload (delta-of-delta), then the column payloads, at
+//   offsets counted from the end of the header
+
+use std::collections::BTreeMap;
+use super::delta::{read_leb_u64, write_leb_u64};
+use super::{codec, delta, DecodeError, GorillaEncoder, TimestampEncoder};
+
+const MAGIC: &[u8; 4] = b"SPB1";
+
+/// Rows of timestamps and named columns. Every column has one value per
+/// timestamp; a column shorter than `timestamps` is missing on the last rows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Block {
+    pub timestamps: Vec<i64>,
+    pub columns: BTreeMap<String, Vec<Option<f64>>>,
+}
+
+impl Block {
+    pub fn encode(&self) -> Vec<u8> {
+        let rows = self.timestamps.len();
+        let mut ts = TimestampEncoder::new();
+        self.timestamps.iter().for_each(|&t| ts.push(t));
+        let ts = ts.finish();
+        let payloads: Vec<(&String, Vec<u8>)> = self.columns.iter().map(|(name, values)| (name, encode_values(values, rows))).collect();
+
+        let mut out = MAGIC.to_vec();
+        write_leb_u64(rows as u64, &mut out);
+        write_leb_u64(payloads.len() as u64, &mut out);
+        write_leb_u64(ts.len() as u64, &mut out);
+        let mut offset = ts.len();
+        for (name, payload) in &payloads {
+            write_leb_u64(name.len() as u64, &mut out);
+            out.extend_from_slice(name.as_bytes());
+            write_leb_u64(offset as u64, &mut out);
+            write_leb_u64(payload.len() as u64, &mut out);
+            offset += payload.len();
+        }
+        out.extend(ts);
+        for (_, payload) in payloads {
+            out.extend(payload);
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let reader = BlockReader::new(data)?;
+        let mut block = Block { timestamps: reader.timestamps()?, columns: BTreeMap::new() };
+        for (name, payload) in &reader.columns {
+            block.columns.insert(name.to_string(), decode_values(payload, reader.rows)?);
+        }
+        Ok(block)
+    }
+
+    /// What can be read of a damaged block, each part on its own: the
+    /// leading timestamps that decode, and the leading values of each
+    /// column, so a bad column costs only its own values. Also returns the
+    /// byte ranges (offset and length in `data`) of the parts that did not
+    /// decode in full; all of `data` when the header cannot be read.
+    pub fn salvage(data: &[u8]) -> (Self, Vec<(usize, usize)>) {
+        let Ok(reader) = BlockReader::parse(data, false) else { return (Self::default(), vec![(0, data.len())]) };
+        let mut unreadable = Vec::new();
+        let mut lost = |(offset, len): (usize, usize)| {
+            let (start, end) = (offset.min(data.len()), offset.saturating_add(len).min(data.len()));
+            if end > start {
+                unreadable.push((start, end - start));
+            }
+        };
+        let timestamps = reader.timestamps().unwrap_or_else(|_| {
+            lost(reader.spans[0]);
+            let mut ts = delta::decode_timestamps_lossy(reader.ts);
+            ts.truncate(reader.rows);
+            ts
+        });
+        let mut block = Block { timestamps, columns: BTreeMap::new() };
+        for ((name, payload), span) in reader.columns.iter().zip(&reader.spans[1..]) {
+            let mut values = decode_values(payload, reader.rows).unwrap_or_else(|_| {
+                lost(*span);
+                decode_values_lossy(payload, reader.rows)
+            });
+            values.truncate(block.timestamps.len());
+            block.columns.insert(name.to_string(), values);
+        }
+        (block, unreadable)
+    }
+}
+
+/// Reads the header of an encoded [`Block`] and decodes its parts on demand.
+pub struct BlockReader<'a> {
+    rows: usize,
+    ts: &'a [u8],
+    columns: Vec<(&'a str, &'a [u8])>,
+    /// Offset and length the header gives each part, timestamps first.
+    spans: Vec<(usize, usize)>,
+}
+
+impl<'a> BlockReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, DecodeError> {
+        Self::parse(data, true)
+    }
+
+    /// Unless `strict`, payloads running past the end of `data` are cut off
+    /// there instead of failing.
+    fn parse(data: &'a [u8], strict: bool) -> Result<Self, DecodeError> {
+        let corrupt = |reason| DecodeError::Corrupt { decoded: 0, reason };
+        let body = data.strip_prefix(MAGIC).ok_or(corrupt("not a block"))?;
+        let mut idx = 0;
+        let leb = |idx: &mut usize| read_leb_u64(body, idx, 0).map(|v| v as usize);
+        let rows = leb(&mut idx)?;
+        let count = leb(&mut idx)?;
+        let ts_len = leb(&mut idx)?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let len = leb(&mut idx)?;
+            let name = body.get(idx..idx.saturating_add(len)).ok_or(DecodeError::Truncated { decoded: 0 })?;
+            let name = std::str::from_utf8(name).map_err(|_| corrupt("column name is not UTF-8"))?;
+            idx += len;
+            entries.push((name, leb(&mut idx)?, leb(&mut idx)?));
+        }
+        let payloads = &body[idx..];
+        let slice = |offset: usize, len: usize| {
+            let end = offset.saturating_add(len);
+            match payloads.get(offset..end) {
+                Some(payload) => Ok(payload),
+                None if !strict => Ok(&payloads[offset.min(payloads.len())..end.min(payloads.len())]),
+                None => Err(DecodeError::Truncated { decoded: 0 }),
+            }
+        };
+        let ts = slice(0, ts_len)?;
+        let base = MAGIC.len() + idx;
+        let mut spans = vec![(base, ts_len)];
+        spans.extend(entries.iter().map(|(_, offset, len)| (base.saturating_add(*offset), *len)));
+        let columns = entries.into_iter().map(|(name, offset, len)| Ok((name, slice(offset, len)?))).collect::<Result<_, DecodeError>>()?;
+        Ok(Self { rows, ts, columns, spans })
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.columns.iter().map(|(name, _)| *name)
+    }
+
+    pub fn timestamps(&self) -> Result<Vec<i64>, DecodeError> {
+        let ts = delta::decode_timestamps(self.ts)?;
+        if ts.len() != self.rows {
+            return Err(DecodeError::Corrupt { decoded: ts.len(), reason: "timestamp count differs from the row count" });
+        }
+        Ok(ts)
+    }
+
+    /// One column, or `None` when the block has no column `name`.
+    pub fn column(&self, name: &str) -> Option<Result<Vec<Option<f64>>, DecodeError>> {
+        let (_, payload) = self.columns.iter().find(|(n, _)| *n == name)?;
+        Some(decode_values(payload, self.rows))
+    }
+}
+
+fn encode_values(values: &[Option<f64>], rows: usize) -> Vec<u8> {
+    let mut out = vec![0u8; rows.div_ceil(8)];
+    let mut present = Vec::new();
+    let mut gorilla = GorillaEncoder::new();
+    for (i, v) in values.iter().take(rows).enumerate() {
+        if let Some(v) = v {
+            out[i / 8] |= 1 << (i % 8);
+            present.push(*v);
+            gorilla.push(*v);
+        }
+    }
+    out.extend(codec::encode_column_streamed(&present, gorilla));
+    out
+}
+
+fn decode_values(payload: &[u8], rows: usize) -> Result<Vec<Option<f64>>, DecodeError> {
+    let (bitmap, present) = split_bitmap(payload, rows)?;
+    let set = present_count(bitmap, rows);
+    let present = codec::decode_column(present, set)?;
+    if present.len() != set {
+        return Err(DecodeError::Corrupt { decoded: present.len(), reason: "value count differs from the presence bitmap" });
+    }
+    Ok(expand(bitmap, present, rows))
+}
+
+/// The leading rows whose values could be read.
+fn decode_values_lossy(payload: &[u8], rows: usize) -> Vec<Option<f64>> {
+    let Ok((bitmap, present)) = split_bitmap(payload, rows) else { return Vec::new() };
+    expand(bitmap, codec::decode_column_lossy(present, present_count(bitmap, rows)), rows)
+}
+
+/// How many of the first `rows` rows `bitmap` marks as having a value.
+fn present_count(bitmap: &[u8], rows: usize) -> usize {
+    (0..rows).filter(|&i| bitmap[i / 8] & (1 << (i % 8)) != 0).count()
+}
+
+fn split_bitmap(payload: &[u8], rows: usize) -> Result<(&[u8], &[u8]), DecodeError> {
+    match payload.len() >= rows.div_ceil(8) {
+        true => Ok(payload.split_at(rows.div_ceil(8))),
+        false => Err(DecodeError::Truncated { decoded: 0 }),
+    }
+}
+
+/// Spread `present` over the rows set in `bitmap`, stopping at the first
+/// set row it has no value for.
+fn expand(bitmap: &[u8], present: Vec<f64>, rows: usize) -> Vec<Option<f64>> {
+    let mut present = present.into_iter();
+    let mut out = Vec::with_capacity(rows);
+    for i in 0..rows {
+        if bitmap[i / 8] & (1 << (i % 8)) == 0 {
+            out.push(None);
+            continue;
+        }
+        match present.next() {
+            Some(v) => out.push(Some(v)),
+            None => break,
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_sparse_columns() {
+        let timestamps: Vec<i64> = (0..20).map(|i| 1_735_689_600_000 + i * 60_000).collect();
+        let temp: Vec<Option<f64>> = (0..20).map(|i| Some(20.0 + i as f64 * 0.1)).collect();
+        let rain: Vec<Option<f64>> = (0..20).map(|i| (i % 3 == 0).then_some(0.2)).collect();
+        let block = Block { timestamps, columns: [("temp".to_string(), temp.clone()), ("rain".to_string(), rain.clone())].into() };
+        let data = block.encode();
+        assert_eq!(Block::decode(&data).unwrap(), block);
+
+        let reader = BlockReader::new(&data).unwrap();
+        assert_eq!((reader.rows(), reader.names().collect::<Vec<_>>()), (20, vec!["rain", "temp"]));
+        assert_eq!(reader.column("rain").unwrap().unwrap(), rain);
+        assert!(reader.column("wind").is_none());
+
+        // a short column is missing on the last rows
+        let short = Block { timestamps: vec![1, 2, 3], columns: [("x".to_string(), vec![Some(1.0)])].into() };
+        assert_eq!(Block::decode(&short.encode()).unwrap().columns["x"], vec![Some(1.0), None, None]);
+        assert_eq!(Block::decode(&Block::default().encode()).unwrap(), Block::default());
+
+        // the last column payload cut short: its last values are lost, the other columns kept whole
+        let cut = &data[..data.len() - 4];
+        assert!(matches!(Block::decode(cut), Err(DecodeError::Truncated { .. })));
+        let (salvaged, unreadable) = Block::salvage(cut);
+        let n = salvaged.columns["temp"].len();
+        assert!(n > 10 && n < 20, "{}", n);
+        assert_eq!((&salvaged.timestamps, &salvaged.columns["rain"]), (&block.timestamps, &rain));
+        assert_eq!(salvaged.columns["temp"][..], temp[..n]);
+        let (offset, len) = BlockReader::new(&data).unwrap().spans[2];
+        assert_eq!(unreadable, vec![(offset, len - 4)]);
+        assert!(matches!(Block::decode(b"SPB0"), Err(DecodeError::Corrupt { .. })));
+        assert_eq!(Block::salvage(b"SPB0"), (Block::default(), vec![(0, 4)]));
+    }
+
+    #[test]
+    fn runs_longer_than_the_block_are_corrupt() {
+        let block = Block { timestamps: vec![1, 2, 3], columns: [("x".to_string(), vec![Some(1.0); 3])].into() };
+        let mut data = block.encode();
+        // the column is one run of 3, its length the last byte
+        assert_eq!(data.pop(), Some(3));
+        data.push(0x7f);
+        assert!(matches!(Block::decode(&data), Err(DecodeError::Corrupt { decoded: 0, .. })));
+        assert!(Block::salvage(&data).0.columns["x"].is_empty());
+
+        // a run of 3 where the bitmap marks only 2 rows as present
+        let sparse = Block { timestamps: vec![1, 2, 3], columns: [("x".to_string(), vec![Some(1.0), None, Some(1.0)])].into() };
+        let mut data = sparse.encode();
+        assert_eq!(data.pop(), Some(2));
+        data.push(3);
+        assert!(matches!(Block::decode(&data), Err(DecodeError::Corrupt { decoded: 0, reason: "runs longer than the column" })));
+    }
+}
@@ -0,0 +1,131 @@
+// Adaptive column codec: picks the cheapest encoding for a float column and
+// prefixes the payload with a one-byte tag so readers can decode any column
+// without knowing how it was written.
+
+use super::{gorilla, rle, DecodeError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    Gorilla = 1,
+    Rle = 2,
+}
+
+impl Codec {
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Codec::Gorilla),
+            2 => Some(Codec::Rle),
+            _ => None,
+        }
+    }
+}
+
+/// Choose the codec producing the smaller payload for `values`.
+///
+/// RLE size is computed exactly from the run lengths. Gorilla spends at
+/// least one bit per value, so it is only tried when RLE needs more than that.
+pub fn choose(values: &[f64]) -> Codec {
+    pick(values, || gorilla::encode(values).len())
+}
+
+fn pick(values: &[f64], gorilla_len: impl FnOnce() -> usize) -> Codec {
+    let rle_len = rle::encoded_len(values);
+    if rle_len * 8 <= values.len() {
+        return Codec::Rle;
+    }
+    if rle_len < gorilla_len() { Codec::Rle } else { Codec::Gorilla }
+}
+
+/// Encode a column with the codec picked by [`choose`].
+pub fn encode_column(values: &[f64]) -> Vec<u8> {
+    encode_with(choose(values), values)
+}
+
+/// Like [`encode_column`], for `values` that were also pushed to `gorilla`
+/// as they were gathered, so its payload is not encoded a second time.
+pub fn encode_column_streamed(values: &[f64], gorilla: gorilla::GorillaEncoder) -> Vec<u8> {
+    let gorilla = gorilla.finish();
+    match pick(values, || gorilla.len()) {
+        Codec::Gorilla => [&[Codec::Gorilla as u8], gorilla.as_slice()].concat(),
+        Codec::Rle => encode_with(Codec::Rle, values),
+    }
+}
+
+pub fn encode_with(codec: Codec, values: &[f64]) -> Vec<u8> {
+    let mut out = vec![codec as u8];
+    match codec {
+        Codec::Gorilla => out.extend(gorilla::encode(values)),
+        Codec::Rle => out.extend(rle::encode(values)),
+    }
+    out
+}
+
+/// Decode a tagged column produced by [`encode_column`] or [`encode_with`],
+/// of at most `max_values` values.
+pub fn decode_column(data: &[u8], max_values: usize) -> Result<Vec<f64>, DecodeError> {
+    let Some((&tag, payload)) = data.split_first() else { return Err(DecodeError::Truncated { decoded: 0 }) };
+    match Codec::from_tag(tag) {
+        Some(Codec::Gorilla) => gorilla::decode(payload),
+        Some(Codec::Rle) => rle::decode(payload, max_values),
+        None => Err(DecodeError::UnknownCodec(tag)),
+    }
+}
+
+/// Like [`decode_column`], but returns the values read before any error;
+/// unknown tags decode to an empty column.
+pub fn decode_column_lossy(data: &[u8], max_values: usize) -> Vec<f64> {
+    let Some((&tag, payload)) = data.split_first() else { return Vec::new() };
+    match Codec::from_tag(tag) {
+        Some(Codec::Gorilla) => gorilla::decode_lossy(payload),
+        Some(Codec::Rle) => rle::decode_lossy(payload, max_values),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_series_uses_rle() {
+        let battery = vec![13.8f64; 720];
+        assert_eq!(choose(&battery), Codec::Rle);
+        let enc = encode_column(&battery);
+        assert_eq!(enc[0], Codec::Rle as u8);
+        assert_eq!(decode_column(&enc, battery.len()).unwrap(), battery);
+    }
+
+    #[test]
+    fn noisy_series_uses_gorilla() {
+        let temps: Vec<f64> = (0..500).map(|i| 20.0 + (i as f64 * 0.37).sin()).collect();
+        assert_eq!(choose(&temps), Codec::Gorilla);
+        assert_eq!(decode_column(&encode_column(&temps), temps.len()).unwrap(), temps);
+    }
+
+    #[test]
+    fn streamed_columns_match_buffered_ones() {
+        let columns = [vec![13.8f64; 720], (0..500).map(|i| 20.0 + (i as f64 * 0.37).sin()).collect()];
+        for values in columns {
+            let mut enc = gorilla::GorillaEncoder::new();
+            values.iter().for_each(|&v| enc.push(v));
+            assert_eq!(encode_column_streamed(&values, enc), encode_column(&values));
+        }
+    }
+
+    #[test]
+    fn slowly_changing_series_roundtrips() {
+        let status: Vec<f64> = (0..1000).map(|i| (i / 250) as f64).collect();
+        assert_eq!(choose(&status), Codec::Rle);
+        assert_eq!(decode_column(&encode_column(&status), status.len()).unwrap(), status);
+    }
+
+    #[test]
+    fn unknown_tag_is_an_error() {
+        assert_eq!(decode_column(&[0xff, 1, 2, 3], 4), Err(DecodeError::UnknownCodec(0xff)));
+        assert_eq!(decode_column(&[], 0), Err(DecodeError::Truncated { decoded: 0 }));
+        assert!(decode_column_lossy(&[0xff, 1, 2, 3], 4).is_empty());
+        let enc = encode_column(&[1.0, 2.5, 2.5, 4.0]);
+        assert_eq!(decode_column_lossy(&enc[..enc.len() - 1], 4), vec![1.0, 2.5, 2.5]);
+    }
+}
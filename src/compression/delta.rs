@@ -4,6 +4,8 @@
 // - first delta: ZigZag(i64) encoded as LEB128
 // - subsequent values: delta-of-delta ZigZag encoded as LEB128
 
+use super::DecodeError;
+
 fn zig_zag_encode(n: i64) -> u64 {
     ((n << 1) ^ (n >> 63)) as u64
 }
@@ -12,7 +14,7 @@ fn zig_zag_decode(u: u64) -> i64 {
     ((u >> 1) as i64) ^ -((u & 1) as i64)
 }
 
-fn write_leb_u64(mut v: u64, out: &mut Vec<u8>) {
+pub(crate) fn write_leb_u64(mut v: u64, out: &mut Vec<u8>) {
     while v >= 0x80 {
         out.push(((v as u8) & 0x7F) | 0x80);
         v >>= 7;
@@ -20,90 +22,146 @@ fn write_leb_u64(mut v: u64, out: &mut Vec<u8>) {
     out.push(v as u8);
 }
 
-fn read_leb_u64(data: &[u8], idx: &mut usize) -> Option<u64> {
+/// Read a LEB128 value at `idx`; `decoded` is the count reported on error.
+pub(crate) fn read_leb_u64(data: &[u8], idx: &mut usize, decoded: usize) -> Result<u64, DecodeError> {
     let mut shift = 0;
     let mut res = 0u64;
     loop {
-        if *idx >= data.len() { return None; }
+        if *idx >= data.len() { return Err(DecodeError::Truncated { decoded }); }
         let b = data[*idx];
         *idx += 1;
         res |= ((b & 0x7F) as u64) << shift;
         if (b & 0x80) == 0 { break; }
         shift += 7;
-        if shift >= 64 { return None; }
+        if shift >= 64 { return Err(DecodeError::Corrupt { decoded, reason: "varint longer than 64 bits" }); }
+    }
+    Ok(res)
+}
+
+/// Encodes timestamps as they arrive; [`finish`](Self::finish) gives the
+/// same bytes as [`encode_timestamps`] of all of them.
+#[derive(Debug, Default)]
+pub struct TimestampEncoder {
+    out: Vec<u8>,
+    count: usize,
+    prev_ts: i64,
+    prev_delta: i64,
+}
+
+impl TimestampEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, t: i64) {
+        match self.count {
+            // write first timestamp (i64 le)
+            0 => self.out.extend_from_slice(&t.to_le_bytes()),
+            1 => {
+                self.prev_delta = t - self.prev_ts;
+                write_leb_u64(zig_zag_encode(self.prev_delta), &mut self.out);
+            }
+            _ => {
+                let delta = t - self.prev_ts;
+                write_leb_u64(zig_zag_encode(delta - self.prev_delta), &mut self.out);
+                self.prev_delta = delta;
+            }
+        }
+        self.prev_ts = t;
+        self.count += 1;
+    }
+
+    /// Timestamps pushed so far.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.out
     }
-    Some(res)
 }
 
 pub fn encode_timestamps(ts: &[i64]) -> Vec<u8> {
-    if ts.is_empty() { return Vec::new(); }
-    let mut out = Vec::new();
-    // write first timestamp (i64 le)
-    out.extend_from_slice(&ts[0].to_le_bytes());
-    if ts.len() == 1 { return out; }
-
-    let first_delta = ts[1] - ts[0];
-    write_leb_u64(zig_zag_encode(first_delta), &mut out);
-    let mut prev_delta = first_delta;
-
-    for w in ts.iter().skip(2) {
-        let delta = *w - ts[((out.len()>0) as usize)]; // placeholder to satisfy borrow checker removed
-        // compute delta properly using previous timestamp
-    }
-
-    // Because the previous loop above uses a placeholder to satisfy editing,
-    // implement the iteration properly below.
-    out.truncate(8); // reset to first-timestamp-only
-    // write first delta again
-    write_leb_u64(zig_zag_encode(first_delta), &mut out);
-    prev_delta = first_delta;
-    let mut prev_ts = ts[1];
-    for &t in &ts[2..] {
-        let delta = t - prev_ts;
-        let dod = delta - prev_delta;
-        write_leb_u64(zig_zag_encode(dod), &mut out);
-        prev_delta = delta;
-        prev_ts = t;
-    }
-
-    out
+    let mut enc = TimestampEncoder::new();
+    for &t in ts {
+        enc.push(t);
+    }
+    enc.finish()
+}
+
+/// Iterates the timestamps of an [`encode_timestamps`] payload without
+/// buffering them. The first error is the last item.
+pub struct TimestampDecoder<'a> {
+    data: &'a [u8],
+    idx: usize,
+    decoded: usize,
+    prev_ts: i64,
+    prev_delta: i64,
+    done: bool,
 }
 
-pub fn decode_timestamps(data: &[u8]) -> Vec<i64> {
-    if data.len() < 8 { return Vec::new(); }
-    let mut idx = 0usize;
-    let first = i64::from_le_bytes(data[0..8].try_into().unwrap());
-    idx += 8;
-    let mut out = Vec::new();
-    out.push(first);
-    if idx >= data.len() { return out; }
-
-    // read first delta
-    let first_delta_u = match read_leb_u64(data, &mut idx) {
-        Some(v) => v,
-        None => return out,
-    };
-    let first_delta = zig_zag_decode(first_delta_u);
-    let second = first + first_delta;
-    out.push(second);
-
-    let mut prev_delta = first_delta;
-    let mut prev_ts = second;
-
-    while idx < data.len() {
-        let dod_u = match read_leb_u64(data, &mut idx) {
-            Some(v) => v,
-            None => break,
+impl<'a> TimestampDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, idx: 0, decoded: 0, prev_ts: 0, prev_delta: 0, done: false }
+    }
+
+    fn step(&mut self) -> Result<Option<i64>, DecodeError> {
+        if self.idx >= self.data.len() {
+            return Ok(None);
+        }
+        if self.decoded == 0 {
+            let Some(first) = self.data.get(..8) else { return Err(DecodeError::Truncated { decoded: 0 }) };
+            self.idx = 8;
+            self.prev_ts = i64::from_le_bytes(first.try_into().unwrap());
+            return Ok(Some(self.prev_ts));
+        }
+        // the first varint is a delta, the rest are deltas of deltas
+        let v = zig_zag_decode(read_leb_u64(self.data, &mut self.idx, self.decoded)?);
+        let delta = if self.decoded == 1 { Some(v) } else { self.prev_delta.checked_add(v) };
+        let Some((delta, ts)) = delta.and_then(|d| self.prev_ts.checked_add(d).map(|ts| (d, ts))) else {
+            return Err(DecodeError::Corrupt { decoded: self.decoded, reason: "timestamp out of range" });
         };
-        let dod = zig_zag_decode(dod_u);
-        let delta = prev_delta + dod;
-        let ts = prev_ts + delta;
-        out.push(ts);
-        prev_delta = delta;
-        prev_ts = ts;
+        self.prev_delta = delta;
+        self.prev_ts = ts;
+        Ok(Some(ts))
     }
+}
+
+impl Iterator for TimestampDecoder<'_> {
+    type Item = Result<i64, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.step() {
+            Ok(Some(t)) => {
+                self.decoded += 1;
+                Some(Ok(t))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
 
-    out
+pub fn decode_timestamps(data: &[u8]) -> Result<Vec<i64>, DecodeError> {
+    TimestampDecoder::new(data).collect()
+}
+
+/// Like [`decode_timestamps`], but returns the timestamps read before any error.
+pub fn decode_timestamps_lossy(data: &[u8]) -> Vec<i64> {
+    TimestampDecoder::new(data).map_while(Result::ok).collect()
 }
 
 #[cfg(test)]
@@ -114,7 +172,7 @@ mod tests {
     fn roundtrip_regular() {
         let ts: Vec<i64> = (0..1000).map(|i| i * 60).collect();
         let enc = encode_timestamps(&ts);
-        let dec = decode_timestamps(&enc);
+        let dec = decode_timestamps(&enc).unwrap();
         assert_eq!(ts, dec);
     }
 
@@ -122,7 +180,7 @@ mod tests {
     fn roundtrip_irregular() {
         let ts: Vec<i64> = vec![1000, 1010, 1030, 1500, 1501, 1510, 3000];
         let enc = encode_timestamps(&ts);
-        let dec = decode_timestamps(&enc);
+        let dec = decode_timestamps(&enc).unwrap();
         assert_eq!(ts, dec);
     }
 
@@ -133,7 +191,46 @@ mod tests {
 
         let b = vec![42i64];
         let enc = encode_timestamps(&b);
-        let dec = decode_timestamps(&enc);
+        let dec = decode_timestamps(&enc).unwrap();
         assert_eq!(b, dec);
+        assert!(decode_timestamps(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        let ts: Vec<i64> = vec![1000, 1010, 1030, 1500];
+        let enc = encode_timestamps(&ts);
+        assert_eq!(decode_timestamps(&enc[..5]), Err(DecodeError::Truncated { decoded: 0 }));
+
+        // a varint cut off by its continuation bit
+        let mut torn = enc.clone();
+        *torn.last_mut().unwrap() |= 0x80;
+        assert_eq!(decode_timestamps(&torn), Err(DecodeError::Truncated { decoded: 3 }));
+        assert_eq!(decode_timestamps_lossy(&torn), &ts[..3]);
+
+        let mut long = enc[..8].to_vec();
+        long.extend([0xff; 11]);
+        assert!(matches!(decode_timestamps(&long), Err(DecodeError::Corrupt { decoded: 1, .. })));
+
+        let mut overflow = i64::MAX.to_le_bytes().to_vec();
+        write_leb_u64(zig_zag_encode(1), &mut overflow);
+        assert!(matches!(decode_timestamps(&overflow), Err(DecodeError::Corrupt { decoded: 1, .. })));
+    }
+
+    #[test]
+    fn streams_timestamps() {
+        let ts: Vec<i64> = vec![1_735_689_600_000, 1_735_689_660_000, 1_735_689_720_000, 1_735_689_719_000, 1_735_690_000_000];
+        let mut enc = TimestampEncoder::new();
+        for &t in &ts {
+            enc.push(t);
+        }
+        assert_eq!(enc.len(), 5);
+        let bytes = enc.finish();
+        assert_eq!(bytes, encode_timestamps(&ts));
+        let mut dec = TimestampDecoder::new(&bytes);
+        assert_eq!(dec.by_ref().take(2).collect::<Result<Vec<_>, _>>().unwrap(), &ts[..2]);
+        assert_eq!(dec.collect::<Result<Vec<_>, _>>().unwrap(), &ts[2..]);
+        // bytes of the encoder that buffered the whole column, before this one
+        assert_eq!(bytes, [0, 124, 41, 31, 148, 1, 0, 0, 192, 169, 7, 0, 143, 185, 7, 160, 182, 34]);
     }
 }
@@ -1,7 +1,13 @@
 // Lightweight Gorilla-style floating point encoder/decoder.
 // This is a simplified implementation (no reuse of previous block header),
 // but compatible between `encode` and `decode` here.
+// Layout: 32-bit value count, first value verbatim (64 bits), then one
+// flag bit per value followed by the xor'd significant bits when non-zero.
+// The count lets the decoder ignore the zero padding in the last byte.
 
+use super::DecodeError;
+
+#[derive(Debug)]
 struct BitWriter {
     buf: Vec<u8>,
     cur: u8,
@@ -25,7 +31,7 @@ impl BitWriter {
         }
     }
 
-    fn write_bits(&mut self, mut value: u64, bits: usize) {
+    fn write_bits(&mut self, value: u64, bits: usize) {
         for i in (0..bits).rev() {
             let b = ((value >> i) & 1) as u8;
             self.push_bit(b);
@@ -69,87 +75,164 @@ impl<'a> BitReader<'a> {
     }
 }
 
-pub fn encode(values: &[f64]) -> Vec<u8> {
-    if values.is_empty() {
-        return Vec::new();
-    }
+/// Encodes values as they arrive; [`finish`](Self::finish) gives the same
+/// bytes as [`encode`] of all of them. At most `u32::MAX` values.
+#[derive(Debug)]
+pub struct GorillaEncoder {
+    w: BitWriter,
+    count: usize,
+    prev: u64,
+}
 
-    let mut w = BitWriter::new();
+impl Default for GorillaEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    // write first value verbatim (64 bits)
-    let first_bits = values[0].to_bits();
-    w.write_bits(first_bits, 64);
-    let mut prev = first_bits;
+impl GorillaEncoder {
+    pub fn new() -> Self {
+        let mut w = BitWriter::new();
+        // the count, filled in by `finish`
+        w.write_bits(0, 32);
+        Self { w, count: 0, prev: 0 }
+    }
 
-    for &v in &values[1..] {
+    pub fn push(&mut self, v: f64) {
         let cur = v.to_bits();
-        let x = prev ^ cur;
-        if x == 0 {
+        let x = self.prev ^ cur;
+        if self.count == 0 {
+            // first value verbatim (64 bits)
+            self.w.write_bits(cur, 64);
+        } else if x == 0 {
             // flag 0 -> same value
-            w.push_bit(0);
+            self.w.push_bit(0);
         } else {
-            w.push_bit(1); // non-zero xor
+            self.w.push_bit(1); // non-zero xor
             let lz = x.leading_zeros() as usize; // 0..64
             let tz = x.trailing_zeros() as usize;
             let siglen = 64 - lz - tz; // >0
             // encode lz in 6 bits (0..63), siglen-1 in 6 bits (0..63)
             let lz_enc = (lz as u64) & 0x3f;
             let sl_enc = ((siglen - 1) as u64) & 0x3f;
-            w.write_bits(lz_enc, 6);
-            w.write_bits(sl_enc, 6);
+            self.w.write_bits(lz_enc, 6);
+            self.w.write_bits(sl_enc, 6);
             let sigbits = (x >> tz) & ((1u128 << siglen) - 1) as u64;
-            w.write_bits(sigbits, siglen);
+            self.w.write_bits(sigbits, siglen);
+        }
+        self.prev = cur;
+        self.count += 1;
+    }
+
+    /// Values pushed so far.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        if self.count == 0 {
+            return Vec::new();
         }
-        prev = cur;
+        let count = u32::try_from(self.count).expect("a Gorilla column holds at most u32::MAX values");
+        let mut buf = self.w.finish();
+        buf[..4].copy_from_slice(&count.to_be_bytes());
+        buf
     }
+}
 
-    w.finish()
+pub fn encode(values: &[f64]) -> Vec<u8> {
+    let mut enc = GorillaEncoder::new();
+    for &v in values {
+        enc.push(v);
+    }
+    enc.finish()
 }
 
-pub fn decode(data: &[u8]) -> Vec<f64> {
-    if data.is_empty() {
-        return Vec::new();
-    }
-    let mut r = BitReader::new(data);
-    // read first 64 bits
-    let first = match r.read_bits(64) {
-        Some(v) => v,
-        None => return Vec::new(),
-    };
-    let mut out = Vec::new();
-    out.push(f64::from_bits(first));
-    let mut prev = first;
-
-    while r.remaining_bits() > 0 {
-        // need at least 1 bit
-        let flag = match r.read_bit() {
-            Some(b) => b,
-            None => break,
-        };
-        if flag == 0 {
-            out.push(f64::from_bits(prev));
-        } else {
-            // need 12 bits for lz and siglen
-            let lz = match r.read_bits(6) {
-                Some(v) => v as usize,
-                None => break,
-            };
-            let slm1 = match r.read_bits(6) {
-                Some(v) => v as usize,
-                None => break,
-            };
-            let siglen = slm1 + 1;
-            if r.remaining_bits() < siglen { break; }
-            let sig = r.read_bits(siglen).unwrap();
+/// Iterates the values of an [`encode`]d payload without buffering them.
+/// The first error is the last item.
+pub struct GorillaDecoder<'a> {
+    r: BitReader<'a>,
+    count: usize,
+    decoded: usize,
+    prev: u64,
+    done: bool,
+}
+
+impl<'a> GorillaDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { r: BitReader::new(data), count: 0, decoded: 0, prev: 0, done: false }
+    }
+
+    fn step(&mut self) -> Result<Option<f64>, DecodeError> {
+        let truncated = DecodeError::Truncated { decoded: self.decoded };
+        if self.decoded == 0 {
+            if self.r.buf.is_empty() {
+                return Ok(None);
+            }
+            self.count = self.r.read_bits(32).ok_or(truncated.clone())? as usize;
+            if self.count == 0 {
+                return Err(DecodeError::Corrupt { decoded: 0, reason: "zero value count" });
+            }
+            // read first 64 bits
+            self.prev = self.r.read_bits(64).ok_or(truncated)?;
+            return Ok(Some(f64::from_bits(self.prev)));
+        }
+        if self.decoded == self.count {
+            if self.r.remaining_bits() >= 8 {
+                return Err(DecodeError::Corrupt { decoded: self.decoded, reason: "trailing bytes after the last value" });
+            }
+            return Ok(None);
+        }
+        if self.r.read_bit().ok_or(truncated.clone())? == 1 {
+            // 6 bits each for lz and siglen - 1
+            let lz = self.r.read_bits(6).ok_or(truncated.clone())? as usize;
+            let siglen = self.r.read_bits(6).ok_or(truncated.clone())? as usize + 1;
+            if lz + siglen > 64 {
+                return Err(DecodeError::Corrupt { decoded: self.decoded, reason: "xor wider than 64 bits" });
+            }
+            let sig = self.r.read_bits(siglen).ok_or(truncated)?;
             let tz = 64 - lz - siglen;
-            let xor = sig << tz;
-            let cur = prev ^ xor;
-            out.push(f64::from_bits(cur));
-            prev = cur;
+            self.prev ^= sig << tz;
         }
+        Ok(Some(f64::from_bits(self.prev)))
     }
+}
+
+impl Iterator for GorillaDecoder<'_> {
+    type Item = Result<f64, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.step() {
+            Ok(Some(v)) => {
+                self.decoded += 1;
+                Some(Ok(v))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<f64>, DecodeError> {
+    GorillaDecoder::new(data).collect()
+}
 
-    out
+/// Like [`decode`], but returns the values read before any error.
+pub fn decode_lossy(data: &[u8]) -> Vec<f64> {
+    GorillaDecoder::new(data).map_while(Result::ok).collect()
 }
 
 #[cfg(test)]
@@ -160,7 +243,7 @@ mod tests {
     fn roundtrip_simple() {
         let vals = vec![0.0f64, 0.0, 1.0, 1.0000001, -5.5, -5.5, 12345.6789];
         let enc = encode(&vals);
-        let dec = decode(&enc);
+        let dec = decode(&enc).unwrap();
         assert_eq!(vals.len(), dec.len());
         for (a, b) in vals.iter().zip(dec.iter()) {
             if a.is_nan() {
@@ -175,7 +258,59 @@ mod tests {
     fn roundtrip_empty() {
         let v: Vec<f64> = vec![];
         let enc = encode(&v);
-        let dec = decode(&enc);
+        let dec = decode(&enc).unwrap();
         assert!(dec.is_empty());
     }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        let vals: Vec<f64> = (0..100).map(|i| 20.0 + (i as f64 * 0.37).sin()).collect();
+        let enc = encode(&vals);
+        assert_eq!(decode(&enc[..3]), Err(DecodeError::Truncated { decoded: 0 }));
+        let Err(DecodeError::Truncated { decoded }) = decode(&enc[..enc.len() / 2]) else { panic!("truncated") };
+        assert!(decoded > 1 && decoded < 100);
+        assert_eq!(decode_lossy(&enc[..enc.len() / 2]), &vals[..decoded]);
+
+        let mut trailing = enc.clone();
+        trailing.push(0);
+        assert!(matches!(decode(&trailing), Err(DecodeError::Corrupt { decoded: 100, .. })));
+
+        // a second value whose leading zeros and length add up past 64 bits
+        let mut w = BitWriter::new();
+        w.write_bits(2, 32);
+        w.write_bits(1.0f64.to_bits(), 64);
+        w.push_bit(1);
+        w.write_bits(40, 6);
+        w.write_bits(39, 6);
+        w.write_bits(0, 40);
+        assert!(matches!(decode(&w.finish()), Err(DecodeError::Corrupt { decoded: 1, .. })));
+    }
+
+    #[test]
+    fn streams_values() {
+        let vals: Vec<f64> = (0..300).map(|i| if i % 7 == 0 { f64::NAN } else { 1013.0 + (i / 10) as f64 * 0.1 }).collect();
+        let mut enc = GorillaEncoder::new();
+        for &v in &vals {
+            enc.push(v);
+        }
+        assert_eq!(enc.len(), 300);
+        let bytes = enc.finish();
+        assert_eq!(bytes, encode(&vals));
+        let mut dec = GorillaDecoder::new(&bytes);
+        for v in &vals {
+            assert_eq!(dec.next().unwrap().unwrap().to_bits(), v.to_bits());
+        }
+        assert!(dec.next().is_none());
+        assert!(GorillaEncoder::new().finish().is_empty());
+
+        // bytes of the encoder that buffered the whole column, before this one
+        let vals: Vec<f64> = (0..12).map(|i| if i % 7 == 0 { f64::NAN } else { 1013.0 + (i / 3) as f64 * 0.1 }).collect();
+        let mut enc = GorillaEncoder::new();
+        vals.iter().for_each(|&v| enc.push(v));
+        let fixture = [
+            0, 0, 0, 12, 127, 248, 0, 0, 0, 0, 0, 0, 132, 151, 238, 245, 88, 159, 51, 51, 51, 51, 52, 175, 69, 85, 85, 85, 85, 94, 23, 159, 187, 212,
+            204, 204, 204, 204, 205, 133, 231, 238, 245, 51, 51, 51, 51, 51, 107, 79, 255, 255, 255, 255, 254, 0,
+        ];
+        assert_eq!(enc.finish(), fixture);
+    }
 }
@@ -1,9 +1,37 @@
 pub mod gorilla;
 pub mod delta;
+pub mod rle;
+pub mod codec;
+pub mod block;
+
+use std::fmt;
 
 // Convenience re-exports and small helpers for callers.
-pub use gorilla::{decode as decode_floats, encode as encode_floats};
-pub use delta::{decode_timestamps, encode_timestamps};
+pub use gorilla::{decode as decode_floats, decode_lossy as decode_floats_lossy, encode as encode_floats, GorillaDecoder, GorillaEncoder};
+pub use delta::{decode_timestamps, decode_timestamps_lossy, encode_timestamps, TimestampDecoder, TimestampEncoder};
+pub use codec::{decode_column, decode_column_lossy, encode_column, Codec};
+pub use block::{Block, BlockReader};
+
+/// Why a payload could not be decoded. Each decoder also has a `_lossy`
+/// variant returning the values read before the error, for salvaging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The payload ends inside a value, after `decoded` complete ones.
+    Truncated { decoded: usize },
+    /// The payload holds something no encoder writes, `decoded` values in.
+    Corrupt { decoded: usize, reason: &'static str },
+    /// A column tagged with a codec this version does not know.
+    UnknownCodec(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { decoded } => write!(f, "payload truncated after {} values", decoded),
+            DecodeError::Corrupt { decoded, reason } => write!(f, "corrupt payload after {} values: {}", decoded, reason),
+            DecodeError::UnknownCodec(tag) => write!(f, "unknown column codec {}", tag),
+        }
+    }
+}
 
-// Higher-level helpers could be added here later (e.g., chunk-level
-// encode/decode that combine timestamps + columns).
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,131 @@
+// Run-length encoder for float columns that sit on the same value for long
+// stretches (battery voltage, status codes, calm wind direction).
+// Layout: repeated (value: 8 bytes little-endian f64 bits, run length: LEB128).
+// Values are compared bit-for-bit so NaN runs and -0.0 survive the roundtrip.
+
+use super::delta::{read_leb_u64, write_leb_u64};
+use super::DecodeError;
+
+pub fn encode(values: &[f64]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (bits, len) in runs(values) {
+        out.extend_from_slice(&bits.to_le_bytes());
+        write_leb_u64(len, &mut out);
+    }
+    out
+}
+
+/// Decode at most `max_values` values: run lengths come from the payload, so
+/// a corrupt one could otherwise ask for any amount of memory.
+pub fn decode(data: &[u8], max_values: usize) -> Result<Vec<f64>, DecodeError> {
+    let mut out = Vec::new();
+    decode_into(data, max_values, &mut out)?;
+    Ok(out)
+}
+
+/// Like [`decode`], but returns the values read before any error.
+pub fn decode_lossy(data: &[u8], max_values: usize) -> Vec<f64> {
+    let mut out = Vec::new();
+    let _ = decode_into(data, max_values, &mut out);
+    out
+}
+
+fn decode_into(data: &[u8], max_values: usize, out: &mut Vec<f64>) -> Result<(), DecodeError> {
+    let mut idx = 0usize;
+    while idx < data.len() {
+        if idx + 8 > data.len() {
+            return Err(DecodeError::Truncated { decoded: out.len() });
+        }
+        let bits = u64::from_le_bytes(data[idx..idx + 8].try_into().unwrap());
+        idx += 8;
+        let len = read_leb_u64(data, &mut idx, out.len())?;
+        if len == 0 {
+            return Err(DecodeError::Corrupt { decoded: out.len(), reason: "empty run" });
+        }
+        if len > (max_values - out.len()) as u64 {
+            return Err(DecodeError::Corrupt { decoded: out.len(), reason: "runs longer than the column" });
+        }
+        let v = f64::from_bits(bits);
+        out.extend(std::iter::repeat_n(v, len as usize));
+    }
+    Ok(())
+}
+
+/// Exact size in bytes that `encode` would produce, without allocating.
+pub fn encoded_len(values: &[f64]) -> usize {
+    runs(values).map(|(_, len)| 8 + leb_len(len)).sum()
+}
+
+fn runs(values: &[f64]) -> impl Iterator<Item = (u64, u64)> + '_ {
+    let mut i = 0usize;
+    std::iter::from_fn(move || {
+        if i >= values.len() { return None; }
+        let bits = values[i].to_bits();
+        let start = i;
+        while i < values.len() && values[i].to_bits() == bits {
+            i += 1;
+        }
+        Some((bits, (i - start) as u64))
+    })
+}
+
+fn leb_len(mut v: u64) -> usize {
+    let mut n = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        n += 1;
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_constant() {
+        let vals = vec![12.6f64; 5000];
+        let enc = encode(&vals);
+        assert_eq!(enc.len(), 8 + 2);
+        assert_eq!(enc.len(), encoded_len(&vals));
+        assert_eq!(decode(&enc, usize::MAX).unwrap(), vals);
+    }
+
+    #[test]
+    fn roundtrip_steps() {
+        let mut vals = vec![1.0f64; 300];
+        vals.extend(vec![1.5f64; 2]);
+        vals.extend(vec![f64::NAN; 3]);
+        vals.push(-0.0);
+        let dec = decode(&encode(&vals), vals.len()).unwrap();
+        assert_eq!(vals.len(), dec.len());
+        for (a, b) in vals.iter().zip(dec.iter()) {
+            assert_eq!(a.to_bits(), b.to_bits());
+        }
+    }
+
+    #[test]
+    fn roundtrip_empty() {
+        assert!(encode(&[]).is_empty());
+        assert!(decode(&[], 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_run_is_an_error() {
+        let mut enc = encode(&[1.0, 1.0, 2.0]);
+        enc.truncate(enc.len() - 3);
+        assert_eq!(decode(&enc, 3), Err(DecodeError::Truncated { decoded: 2 }));
+        assert_eq!(decode_lossy(&enc, 3), vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn runs_past_the_column_are_an_error() {
+        // one run claiming close to u64::MAX values
+        let mut enc = 1.0f64.to_le_bytes().to_vec();
+        write_leb_u64(u64::MAX >> 1, &mut enc);
+        assert_eq!(decode(&enc, 10), Err(DecodeError::Corrupt { decoded: 0, reason: "runs longer than the column" }));
+        let enc = encode(&[1.0, 1.0, 2.0, 2.0]);
+        assert!(matches!(decode(&enc, 3), Err(DecodeError::Corrupt { decoded: 2, .. })));
+        assert_eq!(decode_lossy(&enc, 3), vec![1.0, 1.0]);
+    }
+}
@@ -0,0 +1,602 @@
+// Server configuration: an optional TOML file (`--config` or
+// `SKYPULSE_CONFIG`) with every setting, overridden by the `SKYPULSE_*`
+// environment variables each subsystem already understands. Anything left
+// out falls back to the built-in defaults. The file may also carry
+// `[profiles.<name>]` tables; the selected profile (`--profile` or
+// `SKYPULSE_PROFILE`) is merged over the base settings key by key.
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::api::auth::AuthConfig;
+use crate::api::rate_limit::RateLimitConfig;
+use crate::api::tls::TlsConfig;
+use crate::ingest::demo::DemoConfig;
+use crate::ingest::file_drop::FileDropConfig;
+use crate::ingest::routing::RoutingConfig;
+use crate::ingest::scraper::ScrapeConfig;
+use crate::ingest::kafka::KafkaConfig;
+use crate::ingest::qc::QcConfig;
+use crate::ingest::udp::UdpConfig;
+use crate::logging::LoggingConfig;
+use crate::mirror::MirrorConfig;
+use crate::monitor::MonitorConfig;
+use crate::namespace::NamespaceConfig;
+use crate::replica::ReplicaConfig;
+use crate::replication::ReplicationConfig;
+use crate::export::ExportConfig;
+use crate::health::HealthConfig;
+use crate::alerts::AlertsConfig;
+use crate::continuous::ContinuousQuery;
+use crate::query::spill::SpillConfig;
+use crate::slo::SloConfig;
+use crate::runtime::RuntimeConfig;
+use crate::storage::archive::ArchivePolicy;
+use crate::storage::backend::ObjectStoreConfig;
+use crate::storage::columnar::ChunkFormat;
+use crate::storage::fragmentation::CompactionConfig;
+use crate::storage::integrity::IntegrityMode;
+use crate::storage::retention::RetentionPolicy;
+use crate::storage::tiering::TieringPolicy;
+use crate::storage::wal::WalConfig;
+use crate::units;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Addresses the HTTP API listens on, e.g. `["0.0.0.0:8080", "[::]:8080"]`.
+    /// A single address may be given as a plain string.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<SocketAddr>,
+    /// When set, `/metrics` and the admin endpoints are served only on these
+    /// addresses instead of alongside the public API.
+    #[serde(deserialize_with = "one_or_many")]
+    pub admin_bind: Vec<SocketAddr>,
+    /// Serve HTTPS on every listener when set.
+    pub tls: Option<TlsConfig>,
+    /// API surfaces left out of the router entirely; their paths answer 404.
+    pub disable: BTreeSet<Surface>,
+    /// Largest request body a handler reads into memory, counted after
+    /// gzip/zstd decoding; larger bodies answer 413. Streamed CSV imports
+    /// are bounded per record instead. Also the largest answer a key's
+    /// response filter rewrites.
+    #[serde(alias = "max_body_size", deserialize_with = "units::bytes")]
+    pub max_body_bytes: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec![SocketAddr::from(([127, 0, 0, 1], 8080))],
+            admin_bind: Vec::new(),
+            tls: None,
+            disable: BTreeSet::new(),
+            max_body_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn enabled(&self, surface: Surface) -> bool {
+        !self.disable.contains(&surface)
+    }
+}
+
+/// A group of endpoints that can be switched off with `server.disable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Surface {
+    /// `/api/v1/write` and `/api/v1/write/batch`.
+    Write,
+    /// `/api/v1/write/influx`.
+    Influx,
+    /// `/api/v1/prom/write`.
+    PromWrite,
+    /// `DELETE /api/v1/series`.
+    Delete,
+    /// `/api/v1/import/csv`.
+    Import,
+    /// `/api/v1/query` and `/api/v1/sql`.
+    Query,
+    /// The Prometheus query API under `/api/v1/prom/api/v1`.
+    Promql,
+    /// `/api/v1/stations`, station metadata and a station's latest readings and tail.
+    Stations,
+    /// `/api/v1/forecast` and `/api/v1/forecasters`.
+    Forecast,
+    /// `/api/v1/export/jobs`.
+    Export,
+    /// The `/api/v1/subscribe` WebSocket and a station's `/events` stream.
+    Subscribe,
+    /// Everything under `/api/v1/admin`.
+    Admin,
+    /// `/metrics`.
+    Metrics,
+}
+
+impl std::str::FromStr for Surface {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_value(serde_json::Value::String(s.trim().to_string())).map_err(|_| anyhow::anyhow!("unknown API surface '{}'", s.trim()))
+    }
+}
+
+fn one_or_many<'de, D>(d: D) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(a) => vec![a],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// Comma-separated socket addresses from an environment variable.
+fn env_addrs(name: &str) -> Result<Option<Vec<SocketAddr>>> {
+    let Ok(v) = std::env::var(name) else { return Ok(None) };
+    let mut addrs = Vec::new();
+    for part in v.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part.parse() {
+            Ok(a) => addrs.push(a),
+            Err(_) => bail!("{} must list addresses such as 0.0.0.0:8080, got '{}'", name, part),
+        }
+    }
+    Ok(Some(addrs))
+}
+
+/// MemTable flushing and the bounded queue in front of the flush worker.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FlushConfig {
+    #[serde(alias = "interval", deserialize_with = "units::secs")]
+    pub interval_secs: u64,
+    /// Buffers that may wait for the flush worker before writers see backpressure.
+    pub queue_size: usize,
+    /// How long the scheduler waits on a full queue before putting the buffer back.
+    #[serde(alias = "enqueue_timeout", deserialize_with = "units::secs")]
+    pub enqueue_timeout_secs: u64,
+    /// Stations flushing fewer rows than this share one packed chunk file
+    /// instead of getting a file each; 0 disables packing.
+    pub pack_below_rows: usize,
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        Self { interval_secs: 5, queue_size: 2, enqueue_timeout_secs: 2, pack_below_rows: 0 }
+    }
+}
+
+impl FlushConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    pub fn enqueue_timeout(&self) -> Duration {
+        Duration::from_secs(self.enqueue_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    /// Directory for the write-ahead log, e.g. on a fast SSD; `data_dir` when unset.
+    pub wal_dir: Option<PathBuf>,
+    /// Directory for chunk files, e.g. on a large disk; `data_dir/chunks` when unset.
+    pub chunk_dir: Option<PathBuf>,
+    /// Keep chunk files in an S3-compatible bucket; the manifest stays in the
+    /// chunk directory. Off when absent.
+    pub object_store: Option<ObjectStoreConfig>,
+    /// Keep recent chunks locally and move older ones to `object_store`,
+    /// which then holds the cold tier only. Off when absent.
+    pub tiering: Option<TieringPolicy>,
+    /// Serve queries only: no WAL, no flushes, no retention, and nothing under
+    /// `data_dir` is created or modified. Useful against a backup directory.
+    pub read_only: bool,
+    /// What to do when the chunk manifest and files disagree at startup.
+    pub integrity: IntegrityMode,
+    /// `block` stores numeric rows as columnar block chunks; NDJSON by default.
+    pub chunk_format: ChunkFormat,
+    pub server: ServerConfig,
+    /// API keys; authentication is off when none are configured.
+    pub auth: AuthConfig,
+    /// Per-client token buckets on the write endpoints; unlimited when absent.
+    pub rate_limit: Option<RateLimitConfig>,
+    pub flush: FlushConfig,
+    pub wal: WalConfig,
+    pub logging: LoggingConfig,
+    /// Latency targets for writes and queries, and the burn-rate alert.
+    pub slo: SloConfig,
+    /// How station health scores weigh staleness, rejected writes, anomaly
+    /// flags and device telemetry.
+    pub health: HealthConfig,
+    /// Flagging of implausible readings on ingest; off when absent.
+    pub qc: Option<QcConfig>,
+    /// Alert rules with webhook and mail notifications; off when absent.
+    pub alerts: Option<AlertsConfig>,
+    /// Scheduled aggregations written back as `<station>:<name>` series.
+    pub continuous_queries: Vec<ContinuousQuery>,
+    /// Temporary files for range scans too large to merge in memory.
+    pub spill: SpillConfig,
+    /// Bulk export jobs and where their part files are kept.
+    pub export: ExportConfig,
+    /// Dedicated runtimes for ingest, flush, compaction and query work.
+    pub runtime: RuntimeConfig,
+    pub retention: RetentionPolicy,
+    /// Yearly archive compaction; disabled when absent.
+    pub archive: Option<ArchivePolicy>,
+    /// Merging of fragmented stations' small chunks; disabled when absent.
+    pub compaction: Option<CompactionConfig>,
+    /// Directory watched for dropped CSV/NDJSON files; disabled when absent.
+    pub file_drop: Option<FileDropConfig>,
+    pub scrape: Option<ScrapeConfig>,
+    /// Consuming observations from Kafka topics; off when absent.
+    pub kafka: Option<KafkaConfig>,
+    /// Best-effort UDP listener for constrained devices; off when absent.
+    pub udp: Option<UdpConfig>,
+    pub routing: Option<RoutingConfig>,
+    /// Synthetic readings from virtual stations (`--demo`); off when absent.
+    pub demo: Option<DemoConfig>,
+    /// Asynchronous mirroring to other regions; off when absent.
+    pub mirror: Option<MirrorConfig>,
+    /// Following another node's WAL as a replica; off when absent.
+    pub replication: Option<ReplicationConfig>,
+    /// Serve queries only over a directory another process writes; implies
+    /// `read_only`. Off when absent.
+    pub replica: Option<ReplicaConfig>,
+    /// Named databases served under `/api/v1/db/<name>`, each with its own
+    /// data directory, WAL, retention and keys.
+    pub namespaces: Vec<NamespaceConfig>,
+    /// Self-monitoring into the `_internal` namespace; off when absent.
+    pub monitor: Option<MonitorConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("data"),
+            wal_dir: None,
+            chunk_dir: None,
+            object_store: None,
+            tiering: None,
+            read_only: false,
+            integrity: IntegrityMode::default(),
+            chunk_format: ChunkFormat::default(),
+            server: ServerConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: None,
+            flush: FlushConfig::default(),
+            wal: WalConfig::default(),
+            logging: LoggingConfig::default(),
+            slo: SloConfig::default(),
+            health: HealthConfig::default(),
+            qc: None,
+            alerts: None,
+            continuous_queries: Vec::new(),
+            spill: SpillConfig::default(),
+            export: ExportConfig::default(),
+            runtime: RuntimeConfig::default(),
+            retention: RetentionPolicy::default(),
+            archive: None,
+            compaction: None,
+            file_drop: None,
+            scrape: None,
+            kafka: None,
+            udp: None,
+            routing: None,
+            demo: None,
+            mirror: None,
+            replication: None,
+            replica: None,
+            namespaces: Vec::new(),
+            monitor: None,
+        }
+    }
+}
+
+/// Serialize a secret as `"***"`, so dumps of the config in effect never show it.
+pub(crate) fn redacted<S: serde::Serializer>(_: &String, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str("***")
+}
+
+/// Like `redacted`, keeping an unset secret visible as unset.
+pub(crate) fn redacted_opt<S: serde::Serializer>(v: &Option<String>, s: S) -> Result<S::Ok, S::Error> {
+    match v {
+        Some(_) => s.serialize_str("***"),
+        None => s.serialize_none(),
+    }
+}
+
+/// Merge `overlay` into `base`: tables merge key by key, anything else
+/// (including arrays) replaces the base value.
+fn merge_toml(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge_toml(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::parse_profile(text, None)
+    }
+
+    /// Parse a config file, layering the `[profiles.<profile>]` table over
+    /// the base settings when a profile is given.
+    pub fn parse_profile(text: &str, profile: Option<&str>) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let mut profiles = match table.remove("profiles") {
+            Some(toml::Value::Table(p)) => p,
+            Some(_) => bail!("profiles must be a table of [profiles.<name>] sections"),
+            None => toml::Table::new(),
+        };
+        if let Some(name) = profile {
+            match profiles.remove(name) {
+                Some(toml::Value::Table(overlay)) => merge_toml(&mut table, overlay),
+                Some(_) => bail!("profiles.{} must be a table", name),
+                None => {
+                    let mut known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+                    known.sort_unstable();
+                    bail!("unknown profile '{}' (defined: {})", name, if known.is_empty() { "none".to_string() } else { known.join(", ") });
+                }
+            }
+        }
+        let cfg: Config = table.try_into()?;
+        if let Some(scrape) = &cfg.scrape {
+            scrape.validate()?;
+        }
+        if let Some(kafka) = &cfg.kafka {
+            kafka.validate()?;
+        }
+        if let Some(udp) = &cfg.udp {
+            udp.validate()?;
+        }
+        cfg.auth.validate()?;
+        crate::namespace::validate(&cfg)?;
+        Ok(cfg)
+    }
+
+    /// Read `path` (or the file named by `SKYPULSE_CONFIG`) if given, layer
+    /// `profile` (or `SKYPULSE_PROFILE`) over it, then apply environment overrides.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let path = path.map(Path::to_path_buf).or_else(|| std::env::var("SKYPULSE_CONFIG").ok().map(PathBuf::from));
+        let profile = profile.map(str::to_string).or_else(|| std::env::var("SKYPULSE_PROFILE").ok());
+        let mut cfg = match path {
+            Some(p) => {
+                let text = std::fs::read_to_string(&p).with_context(|| format!("reading {}", p.display()))?;
+                Self::parse_profile(&text, profile.as_deref()).with_context(|| format!("parsing {}", p.display()))?
+            }
+            None if profile.is_some() => bail!("--profile needs a config file that defines it"),
+            None => Self::default(),
+        };
+        cfg.apply_env()?;
+        if cfg.replica.is_some() {
+            cfg.serve_as_replica();
+        }
+        Ok(cfg)
+    }
+
+    /// Serve as a read-only replica: no WAL, no write endpoints.
+    pub fn serve_as_replica(&mut self) {
+        self.replica.get_or_insert_with(ReplicaConfig::default);
+        self.read_only = true;
+        self.server.disable.extend(crate::replica::WRITE_SURFACES);
+    }
+
+    /// Directory holding the WAL segments.
+    pub fn wal_segments(&self) -> PathBuf {
+        self.wal_dir.as_ref().unwrap_or(&self.data_dir).join("wal")
+    }
+
+    /// `SKYPULSE_DATA_DIR`, `SKYPULSE_WAL_DIR`, `SKYPULSE_CHUNK_DIR`, `SKYPULSE_BIND` and `SKYPULSE_ADMIN_BIND`
+    /// (comma-separated), `SKYPULSE_TLS_CERT`/`SKYPULSE_TLS_KEY`, `SKYPULSE_API_KEYS`,
+    /// `SKYPULSE_RATE_LIMIT`/`SKYPULSE_RATE_BURST`, `SKYPULSE_FLUSH_INTERVAL_SECS`,
+    /// `SKYPULSE_FLUSH_QUEUE_SIZE`, `SKYPULSE_PACK_BELOW_ROWS`,
+    /// `SKYPULSE_CHUNK_FORMAT`, plus each subsystem's own variables.
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Ok(dir) = std::env::var("SKYPULSE_DATA_DIR") {
+            self.data_dir = PathBuf::from(dir);
+        }
+        if let Ok(dir) = std::env::var("SKYPULSE_WAL_DIR") {
+            self.wal_dir = Some(PathBuf::from(dir));
+        }
+        if let Ok(dir) = std::env::var("SKYPULSE_CHUNK_DIR") {
+            self.chunk_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(addrs) = env_addrs("SKYPULSE_BIND")? {
+            self.server.bind = addrs;
+        }
+        if let Some(addrs) = env_addrs("SKYPULSE_ADMIN_BIND")? {
+            self.server.admin_bind = addrs;
+        }
+        TlsConfig::apply_env(&mut self.server.tls)?;
+        if let Ok(v) = std::env::var("SKYPULSE_DISABLE") {
+            self.server.disable = v.split(',').filter(|s| !s.trim().is_empty()).map(str::parse).collect::<Result<_>>()?;
+        }
+        self.auth.apply_env()?;
+        RateLimitConfig::apply_env(&mut self.rate_limit)?;
+        if self.server.bind.is_empty() {
+            bail!("server.bind must list at least one address");
+        }
+        if let Some(secs) = units::env_secs("SKYPULSE_FLUSH_INTERVAL_SECS", "s")? {
+            self.flush.interval_secs = secs;
+        }
+        if let Ok(v) = std::env::var("SKYPULSE_FLUSH_QUEUE_SIZE") {
+            self.flush.queue_size = v.parse().with_context(|| format!("SKYPULSE_FLUSH_QUEUE_SIZE '{}'", v))?;
+        }
+        if let Ok(v) = std::env::var("SKYPULSE_PACK_BELOW_ROWS") {
+            self.flush.pack_below_rows = v.parse().with_context(|| format!("SKYPULSE_PACK_BELOW_ROWS '{}'", v))?;
+        }
+        match std::env::var("SKYPULSE_CHUNK_FORMAT").as_deref() {
+            Ok("ndjson") => self.chunk_format = ChunkFormat::Ndjson,
+            Ok("block") => self.chunk_format = ChunkFormat::Block,
+            Ok(other) => bail!("SKYPULSE_CHUNK_FORMAT must be ndjson or block, got '{}'", other),
+            Err(_) => {}
+        }
+        self.wal.apply_env()?;
+        self.logging.apply_env()?;
+        self.slo.apply_env()?;
+        self.runtime.apply_env()?;
+        self.retention.apply_env()?;
+        ArchivePolicy::apply_env(&mut self.archive)?;
+        CompactionConfig::apply_env(&mut self.compaction)?;
+        FileDropConfig::apply_env(&mut self.file_drop)?;
+        if let Some(scrape) = ScrapeConfig::from_env()? {
+            self.scrape = Some(scrape);
+        }
+        KafkaConfig::apply_env(&mut self.kafka)?;
+        UdpConfig::apply_env(&mut self.udp)?;
+        if let Some(routing) = RoutingConfig::from_env()? {
+            self.routing = Some(routing);
+        }
+        DemoConfig::apply_env(&mut self.demo)?;
+        MirrorConfig::apply_env(&mut self.mirror)?;
+        ReplicationConfig::apply_env(&mut self.replication)?;
+        ReplicaConfig::apply_env(&mut self.replica)?;
+        NamespaceConfig::apply_env(&mut self.namespaces);
+        MonitorConfig::apply_env(&mut self.monitor)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections_with_defaults() {
+        let cfg = Config::parse(
+            r#"
+            data_dir = "/var/lib/skypulse"
+            integrity = "repair"
+
+            [server]
+            bind = "0.0.0.0:9090"
+            admin_bind = ["127.0.0.1:9100", "[::1]:9100"]
+
+            [flush]
+            queue_size = 8
+
+            [logging]
+            format = "json"
+
+            [archive]
+            after_secs = 31536000
+
+            [file_drop]
+            watch_dir = "/srv/drop"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cfg.data_dir, PathBuf::from("/var/lib/skypulse"));
+        assert_eq!(cfg.integrity, IntegrityMode::Repair);
+        assert_eq!(cfg.server.bind, vec!["0.0.0.0:9090".parse().unwrap()]);
+        assert_eq!(cfg.server.admin_bind.len(), 2);
+        assert_eq!((cfg.flush.queue_size, cfg.flush.interval_secs), (8, 5));
+        assert_eq!(cfg.logging.format, crate::logging::LogFormat::Json);
+        assert_eq!(cfg.archive.unwrap().check_interval_secs, 86_400);
+        assert_eq!(cfg.file_drop.unwrap().archive_dir(), PathBuf::from("/srv/drop/archive"));
+        assert!(cfg.retention.max_age_secs.is_none());
+    }
+
+    #[test]
+    fn dumps_without_secrets() {
+        let cfg = Config::parse(
+            r#"
+            [auth]
+            keys = [{ key = "s3cret", scope = "admin", name = "ops" }]
+
+            [wal]
+            durability = "interval(50ms)"
+            "#,
+        )
+        .unwrap();
+        let dump = serde_json::to_value(&cfg).unwrap();
+        assert_eq!(dump["auth"]["keys"][0], serde_json::json!({"key": "***", "scope": "admin", "name": "ops", "filter": null}));
+        assert_eq!(dump["wal"]["durability"], "interval(50ms)");
+        assert!(!dump.to_string().contains("s3cret"));
+    }
+
+    #[test]
+    fn layers_the_selected_profile() {
+        let text = r#"
+            data_dir = "/var/lib/skypulse"
+
+            [server]
+            bind = ["0.0.0.0:8080", "[::]:8080"]
+
+            [flush]
+            queue_size = 8
+
+            [profiles.dev]
+            data_dir = "/tmp/skypulse"
+            server.bind = "127.0.0.1:8080"
+
+            [profiles.prod.flush]
+            interval_secs = 30
+        "#;
+        let base = Config::parse(text).unwrap();
+        assert_eq!(base.data_dir, PathBuf::from("/var/lib/skypulse"));
+
+        let dev = Config::parse_profile(text, Some("dev")).unwrap();
+        assert_eq!(dev.data_dir, PathBuf::from("/tmp/skypulse"));
+        assert_eq!(dev.server.bind, vec!["127.0.0.1:8080".parse().unwrap()]);
+
+        // nested tables merge key by key
+        let prod = Config::parse_profile(text, Some("prod")).unwrap();
+        assert_eq!((prod.flush.queue_size, prod.flush.interval_secs), (8, 30));
+        assert_eq!(prod.server.bind.len(), 2);
+
+        let err = Config::parse_profile(text, Some("staging")).unwrap_err();
+        assert!(err.to_string().contains("dev, prod"));
+    }
+
+    #[test]
+    fn accepts_units_on_durations_and_sizes() {
+        let cfg = Config::parse(
+            r#"
+            [flush]
+            interval = "1m"
+            enqueue_timeout_secs = 3
+
+            [wal]
+            segment_bytes = "16MiB"
+
+            [retention]
+            max_age = "90d"
+            check_interval = "15m"
+
+            [slo]
+            query_latency_ms = "2s"
+            "#,
+        )
+        .unwrap();
+        assert_eq!((cfg.flush.interval_secs, cfg.flush.enqueue_timeout_secs), (60, 3));
+        assert_eq!(cfg.wal.segment_bytes, 16 * 1024 * 1024);
+        assert_eq!(cfg.retention.max_age_secs, Some(90 * 86_400));
+        assert_eq!(cfg.retention.check_interval_secs, 900);
+        assert_eq!(cfg.slo.query_latency_ms, 2000);
+
+        let err = format!("{:#}", Config::parse("[retention]\nmax_age = \"90 days\"").unwrap_err());
+        assert!(err.contains("unknown unit 'days'"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(Config::parse("data_directory = \"x\"").is_err());
+    }
+}
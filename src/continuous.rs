@@ -0,0 +1,218 @@
+// Continuous queries: named aggregations that run on a schedule and write
+// their windows back as series of their own, so dashboards read a small
+// precomputed series instead of aggregating raw readings on every refresh.
+// A query such as "hourly mean and max of temp" writes the windows of
+// station `HK001` to station `HK001:hourly`, one row per window at its start
+// with fields like `temp_mean` and `temp_max`, and that series is queried
+// like any other station. Each run recomputes the completed windows of the
+// last `lookback`, so readings arriving late are counted; a rewrite replaces
+// the earlier row of the window. Only a node accepting writes runs them.
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use crate::query::aggregate::{self, AggFn, AggValue};
+use crate::query::{self, RangeQuery, ResolutionChoice, Rows};
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::{units, AppState};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContinuousQuery {
+    /// Also the suffix of the series written: `<station>:<name>`.
+    pub name: String,
+    /// Source stations; every station with data when empty, except the
+    /// series continuous queries write.
+    #[serde(default)]
+    pub stations: Vec<String>,
+    /// Fields aggregated; every numeric field when empty.
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// `mean` when empty.
+    #[serde(default)]
+    pub aggregations: Vec<String>,
+    #[serde(alias = "interval", deserialize_with = "units::secs")]
+    pub interval_secs: u64,
+    /// IANA zone whose wall clock the windows align to; UTC when unset.
+    #[serde(default)]
+    pub tz: Option<String>,
+    /// How often the query runs; `interval` when unset.
+    #[serde(default, alias = "every", deserialize_with = "units::opt_secs")]
+    pub every_secs: Option<u64>,
+    /// Completed windows within this of now are recomputed on every run;
+    /// twice `interval` when unset.
+    #[serde(default, alias = "lookback", deserialize_with = "units::opt_secs")]
+    pub lookback_secs: Option<u64>,
+}
+
+/// A checked continuous query, ready to run.
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    pub cq: ContinuousQuery,
+    aggregations: Vec<AggFn>,
+    tz: Option<Tz>,
+    /// Suffixes of every continuous query's series, left out as sources.
+    outputs: Vec<String>,
+}
+
+/// Check `queries` and prepare them to run.
+pub fn compile(queries: &[ContinuousQuery]) -> Result<Vec<Compiled>> {
+    let outputs: Vec<String> = queries.iter().map(|q| format!(":{}", q.name)).collect();
+    let mut compiled = Vec::with_capacity(queries.len());
+    for (i, cq) in queries.iter().enumerate() {
+        if cq.name.is_empty() || cq.name.contains(':') {
+            bail!("continuous query names must be non-empty and without ':', got '{}'", cq.name);
+        }
+        if queries[..i].iter().any(|q| q.name == cq.name) {
+            bail!("continuous query {} is defined twice", cq.name);
+        }
+        if cq.interval_secs == 0 {
+            bail!("continuous query {} needs an interval", cq.name);
+        }
+        let aggregations = AggFn::parse_list(&cq.aggregations.join(",")).with_context(|| format!("continuous query {}", cq.name))?;
+        let tz = cq.tz.as_deref().map(query::parse_tz).transpose().with_context(|| format!("continuous query {}", cq.name))?;
+        compiled.push(Compiled { cq: cq.clone(), aggregations, tz, outputs: outputs.clone() });
+    }
+    Ok(compiled)
+}
+
+impl Compiled {
+    pub fn every(&self) -> Duration {
+        Duration::from_secs(self.cq.every_secs.unwrap_or(self.cq.interval_secs).max(1))
+    }
+
+    /// The series the windows of `station_id` are written to.
+    pub fn output(&self, station_id: &str) -> String {
+        format!("{}:{}", station_id, self.cq.name)
+    }
+
+    /// Aggregate the completed windows of the lookback before `now` for
+    /// every source station and write them; returns the rows written.
+    pub async fn run_once(&self, state: &AppState, now: DateTime<Utc>) -> Result<usize> {
+        let interval = self.cq.interval_secs as i64;
+        let lookback = self.cq.lookback_secs.map_or(2 * interval, |l| l as i64);
+        let secs = now.timestamp();
+        let start = aggregate::window_start(secs - lookback, interval, self.tz);
+        let end = aggregate::window_start(secs, interval, self.tz);
+        let stations = match self.cq.stations.is_empty() {
+            false => self.cq.stations.clone(),
+            true => query::stations(state)
+                .await
+                .into_iter()
+                .map(|s| s.station_id)
+                .filter(|id| !self.outputs.iter().any(|suffix| id.ends_with(suffix.as_str())))
+                .collect(),
+        };
+        let mut rows = Vec::new();
+        for station in stations {
+            let q = RangeQuery {
+                resolution: ResolutionChoice::Raw,
+                fields: self.cq.fields.clone(),
+                aggregations: self.aggregations.clone(),
+                interval_secs: Some(interval),
+                tz: self.tz,
+                ..RangeQuery::new(&station, DateTime::from_timestamp(start, 0), DateTime::from_timestamp(end, 0))
+            };
+            let Some(Rows::Buckets(buckets)) = query::execute(state, &q).await?.rows else { continue };
+            for bucket in buckets {
+                let mut o = Observation::empty(self.output(&station), bucket.time.parse::<Timestamp>()?);
+                for (field, values) in &bucket.aggregates {
+                    for (agg, value) in values {
+                        match value {
+                            AggValue::Value(Some(v)) => o.set_field(format!("{}_{}", field, agg), *v),
+                            AggValue::Count(n) => o.set_field(format!("{}_{}", field, agg), *n as f64),
+                            AggValue::Value(None) => {}
+                        }
+                    }
+                }
+                o.tags.insert("cq".to_string(), self.cq.name.clone());
+                rows.push(o);
+            }
+        }
+        let n = rows.len();
+        if n > 0 {
+            crate::ingest::append(state, rows).await?;
+        }
+        Ok(n)
+    }
+}
+
+/// Run `cq` every `every` until shutdown.
+pub async fn run(state: Arc<AppState>, cq: Compiled, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let mut ticker = tokio::time::interval(cq.every());
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {
+                if state.write_refusal().is_some() {
+                    continue;
+                }
+                match cq.run_once(&state, Utc::now()).await {
+                    Ok(n) => tracing::debug!(name = cq.cq.name, "continuous query wrote {} rows", n),
+                    Err(e) => tracing::error!(name = cq.cq.name, "continuous query failed: {:#}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::FieldValue;
+
+    #[tokio::test]
+    async fn writes_windows_as_series() {
+        let dir = crate::test_util::TempDir::new("continuous");
+        let state = AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap();
+        let reading = |station: &str, t: &str, temp: f64| {
+            let mut o = Observation::empty(station, t.parse().unwrap());
+            o.set_field("temp", temp);
+            o
+        };
+        let rows = vec![
+            reading("A", "2025-01-01T00:10:00Z", 10.0),
+            reading("A", "2025-01-01T00:50:00Z", 20.0),
+            reading("A", "2025-01-01T01:20:00Z", 30.0),
+            reading("B", "2025-01-01T01:30:00Z", 5.0),
+            // the current window is not complete yet
+            reading("A", "2025-01-01T02:05:00Z", 99.0),
+        ];
+        crate::ingest::append(&state, rows).await.unwrap();
+        let cqs: Vec<ContinuousQuery> = toml::from_str::<toml::Table>(
+            r#"
+            continuous_queries = [
+              { name = "hourly", fields = ["temp"], aggregations = ["mean", "max", "count"], interval = "1h" },
+              { name = "daily", stations = ["A"], interval = "1d" },
+            ]
+            "#,
+        )
+        .unwrap()["continuous_queries"]
+            .clone()
+            .try_into()
+            .unwrap();
+        let compiled = compile(&cqs).unwrap();
+        let now = "2025-01-01T02:10:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(compiled[0].run_once(&state, now).await.unwrap(), 3);
+        // the series written are not sources of another run
+        assert_eq!(compiled[0].run_once(&state, now).await.unwrap(), 3);
+
+        let q = RangeQuery { resolution: ResolutionChoice::Raw, ..RangeQuery::new("A:hourly", None, None) };
+        let Some(Rows::Raw(hourly)) = query::execute(&state, &q).await.unwrap().rows else { panic!("raw rows") };
+        assert_eq!(hourly.len(), 2);
+        assert_eq!(hourly[0].time.to_string(), "2025-01-01T00:00:00Z");
+        assert_eq!(hourly[0].fields["temp_mean"], FieldValue::Number(15.0));
+        assert_eq!((hourly[1].number("temp_max"), hourly[1].number("temp_count")), (Some(30.0), Some(1.0)));
+        assert_eq!(hourly[0].tags["cq"], "hourly");
+        assert!(query::latest(&state, "B:hourly").await.unwrap().is_some());
+        assert!(query::latest(&state, "A:hourly:hourly").await.unwrap().is_none());
+
+        let bad = |toml: &str| compile(&[toml::from_str(toml).unwrap()]).is_err();
+        assert!(bad("name = \"x:y\"\ninterval = \"1h\""));
+        assert!(bad("name = \"x\"\ninterval = \"1h\"\naggregations = [\"median\"]"));
+        assert!(bad("name = \"x\"\ninterval = \"1h\"\ntz = \"Mars/Olympus\""));
+    }
+}
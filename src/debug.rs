@@ -0,0 +1,126 @@
+// Snapshot of a node's in-memory state for remote debugging of a stuck
+// instance: what is buffered, queued and cached, which background jobs run,
+// and the configuration in effect with secrets redacted. Every lock is held
+// only long enough to copy counts out, so taking one never stalls writes.
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::rebuild::Progress;
+use crate::mirror::MirrorStatus;
+use crate::AppState;
+
+#[derive(Debug, Serialize)]
+pub struct MemTableState {
+    pub rows: usize,
+    /// Buffered rows per station.
+    pub stations: BTreeMap<String, usize>,
+    /// Oldest WAL segment the buffered rows still pin.
+    pub wal_pin: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheState {
+    /// Stations in the last-value cache.
+    pub last_values: usize,
+    /// Connected live subscribers.
+    pub live_subscribers: usize,
+    /// Clients the rate limiter keeps a bucket for.
+    pub rate_limited_clients: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobState {
+    /// Background workers this node starts, by name.
+    pub workers: Vec<&'static str>,
+    /// The running or most recent admin rebuild.
+    pub rebuild: Option<Progress>,
+    pub mirror: Option<MirrorStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugState {
+    pub read_only: bool,
+    /// Why client writes are refused, if they are.
+    pub write_refusal: Option<&'static str>,
+    pub memtable: MemTableState,
+    /// Drained buffers waiting for a flush worker, namespaces included.
+    pub flush_queue_depth: i64,
+    pub wal_durability: Option<String>,
+    pub chunks: usize,
+    pub chunk_bytes: u64,
+    pub caches: CacheState,
+    pub jobs: JobState,
+    pub config: serde_json::Value,
+    /// The same for every namespace, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespaces: BTreeMap<String, DebugState>,
+}
+
+/// The background workers `run_server` starts for `state`'s configuration.
+fn workers(state: &AppState) -> Vec<&'static str> {
+    let cfg = &state.config;
+    // the SLO watch is server-wide; a namespace only runs its storage workers
+    let slo = state.namespace.is_none();
+    if cfg.read_only {
+        return [("replica", cfg.replica.is_some()), ("slo", slo)].into_iter().filter_map(|(name, on)| on.then_some(name)).collect();
+    }
+    let retention = state.retention.expires_anything() || state.router.as_ref().is_some_and(|r| r.has_retention_overrides());
+    [
+        ("flush", true),
+        ("retention", retention),
+        ("archive", state.archive.is_some()),
+        ("compaction", state.compaction.is_some()),
+        ("tiering", state.tiers.is_some()),
+        ("purge", true),
+        ("mirror", state.mirror.is_some()),
+        ("replication", state.replication.is_follower()),
+        ("slo", slo),
+        ("monitor", cfg.monitor.is_some()),
+        ("file_drop", cfg.file_drop.is_some()),
+        ("scrape", cfg.scrape.is_some()),
+        ("kafka", cfg.kafka.is_some()),
+        ("udp", cfg.udp.is_some()),
+        ("demo", cfg.demo.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, on)| on.then_some(name))
+    .collect()
+}
+
+pub async fn snapshot(state: &AppState) -> anyhow::Result<DebugState> {
+    let memtable = {
+        let mt = state.memtable.lock().await;
+        MemTableState {
+            rows: mt.buffer.values().map(Vec::len).sum(),
+            stations: mt.buffer.iter().map(|(s, rows)| (s.clone(), rows.len())).collect(),
+            wal_pin: mt.wal_pin.as_ref().map(|p| p.segment()),
+        }
+    };
+    let manifest = state.chunk_store.manifest().await;
+    let caches = CacheState {
+        last_values: state.last_values.lock().await.len(),
+        live_subscribers: state.live.receiver_count(),
+        rate_limited_clients: state.rate_limiter.as_ref().map(|l| l.clients()),
+    };
+    let mirror = match &state.mirror {
+        Some(m) => Some(m.status().await),
+        None => None,
+    };
+    let mut namespaces = BTreeMap::new();
+    for (name, ns) in &state.namespaces {
+        namespaces.insert(name.clone(), Box::pin(snapshot(ns)).await?);
+    }
+    Ok(DebugState {
+        read_only: state.read_only(),
+        write_refusal: state.write_refusal(),
+        memtable,
+        flush_queue_depth: state.metrics.flush_queue_depth.get(),
+        wal_durability: state.wal.as_ref().map(|w| w.durability().to_string()),
+        chunks: manifest.chunks.len(),
+        chunk_bytes: manifest.chunks.values().map(|c| c.bytes).sum(),
+        caches,
+        jobs: JobState { workers: workers(state), rebuild: state.rebuild.lock().await.clone(), mirror },
+        config: serde_json::to_value(&state.config)?,
+        namespaces,
+    })
+}
@@ -0,0 +1,167 @@
+// Range deletes. A delete drops the station's buffered rows at once and
+// records a tombstone: in the WAL, so it survives a crash before the next
+// flush, and in the manifest, where reads use it to mask the rows already in
+// chunk files. A background purge then removes those rows from the files,
+// recomputes the station's rollups and forgets the tombstone.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use anyhow::{bail, Result};
+use crate::storage::memtable::Timestamp;
+use crate::storage::rollup;
+use crate::storage::tombstone::Tombstone;
+use crate::AppState;
+
+/// How often the purge worker looks for pending tombstones.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, serde::Serialize)]
+pub struct Deleted {
+    /// Buffered rows dropped right away; rows in chunk files are masked
+    /// until the purge removes them.
+    pub removed_buffered: usize,
+    pub tombstone: Tombstone,
+}
+
+/// Reject a delete naming no station or an empty range.
+pub fn validate(station_id: &str, start: Option<Timestamp>, end: Option<Timestamp>) -> Result<()> {
+    if station_id.is_empty() {
+        bail!("station_id must not be empty");
+    }
+    if let (Some(s), Some(e)) = (start, end) {
+        if s >= e {
+            bail!("start must be before end");
+        }
+    }
+    Ok(())
+}
+
+/// Delete `station_id`'s rows in `[start, end)` (either side open when unset).
+pub async fn delete(state: &AppState, station_id: &str, start: Option<Timestamp>, end: Option<Timestamp>) -> Result<Deleted> {
+    if let Some(reason) = state.write_refusal() {
+        bail!("{}", reason);
+    }
+    apply(state, station_id, start, end).await
+}
+
+/// [`delete`] without the write refusal check, for deletes replicated from a
+/// leader. The tombstone is issued anew, masking the chunks written here so
+/// far, and queued for the mirror's peer regions.
+pub(crate) async fn apply(state: &AppState, station_id: &str, start: Option<Timestamp>, end: Option<Timestamp>) -> Result<Deleted> {
+    let deleted = apply_mirrored(state, station_id, start, end).await?;
+    if let Some(mirror) = &state.mirror {
+        mirror.record_delete(station_id, start, end).await?;
+    }
+    Ok(deleted)
+}
+
+/// [`apply`] for a delete that came from a peer region: never queued for
+/// mirroring again.
+pub(crate) async fn apply_mirrored(state: &AppState, station_id: &str, start: Option<Timestamp>, end: Option<Timestamp>) -> Result<Deleted> {
+    validate(station_id, start, end)?;
+    let Some(wal) = &state.wal else { bail!("server is read-only") };
+    // under the MemTable lock no write lands between the tombstone and the
+    // rows it removes
+    let mut mt = state.memtable.lock().await;
+    let tombstone = Tombstone { station_id: station_id.to_string(), start, end, issued: chrono::Utc::now().timestamp_millis() };
+    wal.append_tombstone(&tombstone).await?;
+    let removed_buffered = mt.remove_range(&tombstone);
+    state.chunk_store.add_tombstone(tombstone.clone()).await?;
+    drop(mt);
+    state.last_values.lock().await.remove(station_id);
+    tracing::info!(station_id, removed_buffered, "range delete issued");
+    Ok(Deleted { removed_buffered, tombstone })
+}
+
+/// Remove the rows of every pending tombstone from the chunk files and
+/// recompute the rollups of the chunks rewritten, each once however many
+/// tombstones hit it; returns the rows removed.
+pub async fn purge(state: &AppState) -> Result<usize> {
+    let level = state.archive.as_ref().map_or_else(crate::storage::archive::default_level, |a| a.level);
+    let _maintenance = state.chunk_store.maintenance().await;
+    let tombstones = state.chunk_store.tombstones(None).await;
+    let mut removed = 0;
+    // station -> chunks to roll up again
+    let mut touched: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for tombstone in &tombstones {
+        let (rows, chunks) = state.chunk_store.purge(tombstone, level).await?;
+        removed += rows;
+        touched.entry(tombstone.station_id.clone()).or_default().extend(chunks);
+    }
+    for (station_id, chunks) in touched {
+        recompute_rollups(state, &station_id, &chunks).await?;
+    }
+    for tombstone in &tombstones {
+        state.chunk_store.remove_tombstone(tombstone).await?;
+    }
+    Ok(removed)
+}
+
+/// Replace a station's rollups of `chunks` with rollups of the rows left in
+/// them; a chunk purged empty leaves none.
+async fn recompute_rollups(state: &AppState, station_id: &str, chunks: &BTreeSet<String>) -> Result<()> {
+    let slices: BTreeMap<String, _> = state.chunk_store.slices(station_id).await.into_iter().collect();
+    for name in chunks {
+        let rows = match slices.get(name) {
+            Some(slice) => state.chunk_store.read_slice(name, slice).await?,
+            None => Vec::new(),
+        };
+        state.rollups.replace(&rollup::file_for_chunk(name), Some(station_id), &rows).await?;
+    }
+    Ok(())
+}
+
+/// Purge worker: runs until `shutdown` fires.
+pub async fn run(state: std::sync::Arc<AppState>, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {
+                match purge(&state).await {
+                    Ok(removed) if removed > 0 => tracing::info!("purge: removed {} deleted rows", removed),
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("purge error: {}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Observation;
+
+    #[tokio::test]
+    async fn deletes_mask_then_purge() {
+        let dir = crate::test_util::TempDir::new("delete");
+        let cfg = crate::config::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        let state = AppState::open(&cfg).await.unwrap();
+        let at = |t: &str| {
+            let mut o = Observation::empty("A", t.parse().unwrap());
+            o.set_field("temp", 1.0);
+            o
+        };
+        let flushed = [at("2025-01-01T00:00:00Z"), at("2025-01-01T01:00:00Z"), at("2025-01-01T02:00:00Z")];
+        state.chunk_store.write_chunk("A", "flush-1", &flushed).await.unwrap();
+        state.rollups.write_chunk("A", "flush-1", &flushed).await.unwrap();
+        state.memtable.lock().await.insert(at("2025-01-01T01:30:00Z"));
+
+        let start = Some("2025-01-01T01:00:00Z".parse().unwrap());
+        let end = Some("2025-01-01T02:00:00Z".parse().unwrap());
+        let deleted = delete(&state, "A", start, end).await.unwrap();
+        assert_eq!(deleted.removed_buffered, 1);
+        assert_eq!(state.chunk_store.read_chunks("A").await.unwrap().len(), 2);
+
+        // a second tombstone on the same chunk is purged in the same pass
+        delete(&state, "A", None, Some("2025-01-01T00:30:00Z".parse().unwrap())).await.unwrap();
+
+        assert_eq!(purge(&state).await.unwrap(), 2);
+        assert!(state.chunk_store.tombstones(None).await.is_empty());
+        let rows = state.chunk_store.read_chunk_file("A-flush-1.ndjson").await.unwrap();
+        assert_eq!(rows.len(), 1);
+        let hourly = state.rollups.read("A", rollup::Resolution::Hour).await.unwrap();
+        assert_eq!(hourly.len(), 1);
+    }
+}
@@ -0,0 +1,153 @@
+// Embedded mode: the storage engine in-process, without the HTTP server, for
+// applications that want a local time-series store the way they would use
+// sled or RocksDB. Opening starts the same flush, retention and compaction
+// workers as the server (and self-monitoring when configured); the listeners,
+// mirroring, replication and the server-side ingest sources (file drop,
+// scraping, Kafka, UDP, demo) are not started. Call `close` to flush what is
+// buffered; dropping the handle leaves it in the WAL for the next open.
+
+use std::sync::Arc;
+use anyhow::{bail, Result};
+use crate::ingest::routing::RouteMeta;
+use crate::query::{QueryResult, RangeQuery};
+use crate::storage::memtable::Observation;
+use crate::{AppState, Config, Node};
+
+pub struct SkyPulseDb {
+    state: Arc<AppState>,
+    shutdown_tx: tokio::sync::broadcast::Sender<()>,
+    /// The database's own node first, then its namespaces.
+    nodes: Vec<Node>,
+}
+
+impl SkyPulseDb {
+    /// Open the database in `cfg.data_dir`, replaying its WAL, and start the
+    /// background workers. Needs a tokio runtime.
+    pub async fn open(cfg: Config) -> Result<Self> {
+        let state = Arc::new(AppState::open(&cfg).await?);
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel::<()>(1);
+        let mut nodes = vec![crate::start_node(state.clone(), &shutdown_tx)];
+        nodes.extend(state.namespaces.values().map(|ns| crate::start_node(ns.clone(), &shutdown_tx)));
+        if let (false, Some(monitor)) = (cfg.read_only, cfg.monitor.clone()) {
+            nodes[0].writers.push(state.runtimes.compaction.spawn(crate::monitor::run(state.clone(), monitor, shutdown_tx.subscribe())));
+        }
+        Ok(Self { state, shutdown_tx, nodes })
+    }
+
+    /// The state the server's handlers run on, to serve the database with
+    /// `api::http::router` or use the lower-level modules.
+    pub fn state(&self) -> Arc<AppState> {
+        self.state.clone()
+    }
+
+    /// Write one observation; durable once this returns, as the WAL's
+    /// durability setting defines it.
+    pub async fn write(&self, obs: Observation) -> Result<()> {
+        if let Some((_, reason)) = self.write_batch(vec![obs]).await?.into_iter().next() {
+            bail!(reason);
+        }
+        Ok(())
+    }
+
+    /// Write a batch under one WAL append. Returns `(index, reason)` for each
+    /// observation the routing rules rejected; the rest are written.
+    pub async fn write_batch(&self, obs: Vec<Observation>) -> Result<Vec<(usize, String)>> {
+        let mut admitted = Vec::with_capacity(obs.len());
+        let mut rejected = Vec::new();
+        // routing looks at each observation's own tags
+        for (i, o) in obs.into_iter().enumerate() {
+            let meta = RouteMeta { token: None, tags: o.tags.clone() };
+            let (ok, refused) = crate::ingest::admit(&self.state, vec![o], &meta).await;
+            admitted.extend(ok);
+            rejected.extend(refused.into_iter().map(|(_, reason)| (i, reason)));
+        }
+        if !admitted.is_empty() {
+            crate::ingest::append(&self.state, admitted).await?;
+        }
+        Ok(rejected)
+    }
+
+    pub async fn query(&self, q: &RangeQuery) -> Result<QueryResult> {
+        crate::query::execute(&self.state, q).await
+    }
+
+    /// The station's most recent observation.
+    pub async fn latest(&self, station_id: &str) -> Result<Option<Observation>> {
+        crate::query::latest(&self.state, station_id).await
+    }
+
+    /// Write everything buffered to chunks now instead of at the next flush
+    /// interval; returns the rows flushed.
+    pub async fn flush(&self) -> Result<usize> {
+        let mut rows = crate::snapshot::fence(&self.state, "flush").await?.0;
+        for ns in self.state.namespaces.values() {
+            rows += crate::snapshot::fence(ns, "flush").await?.0;
+        }
+        Ok(rows)
+    }
+
+    /// Stop the workers and flush what is buffered.
+    pub async fn close(self) {
+        let _ = self.shutdown_tx.send(());
+        for node in self.nodes {
+            node.stop().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_queries_flushes_and_reopens() {
+        let dir = crate::test_util::TempDir::new("embedded");
+        let cfg = Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        let at = |t: &str, temp: f64| {
+            let mut o = Observation::empty("A", t.parse().unwrap());
+            o.set_field("temp", temp);
+            o
+        };
+        let raw = || RangeQuery { resolution: crate::query::ResolutionChoice::Raw, ..RangeQuery::new("A", None, None) };
+        let rows = |r: QueryResult| match r.rows {
+            Some(crate::query::Rows::Raw(rows)) => rows.len(),
+            _ => 0,
+        };
+
+        let db = SkyPulseDb::open(cfg.clone()).await.unwrap();
+        db.write(at("2025-01-01T00:00:00Z", 20.5)).await.unwrap();
+        assert!(db.write_batch(vec![at("2025-01-01T00:01:00Z", 21.0)]).await.unwrap().is_empty());
+        assert_eq!(rows(db.query(&raw()).await.unwrap()), 2);
+
+        assert_eq!(db.flush().await.unwrap(), 2);
+        assert!(db.state().memtable.lock().await.buffer.is_empty());
+        assert_eq!(db.state().chunk_store.slices("A").await.len(), 1);
+
+        // close flushes what is still buffered
+        db.write(at("2025-01-01T00:02:00Z", 21.5)).await.unwrap();
+        db.close().await;
+        let db = SkyPulseDb::open(cfg).await.unwrap();
+        assert_eq!(db.state().chunk_store.slices("A").await.len(), 2);
+        assert_eq!(rows(db.query(&raw()).await.unwrap()), 3);
+        assert_eq!(db.latest("A").await.unwrap().unwrap().time, "2025-01-01T00:02:00Z".parse().unwrap());
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn flushes_in_the_same_millisecond_keep_their_chunks() {
+        let dir = crate::test_util::TempDir::new("embedded-ms");
+        let db = SkyPulseDb::open(Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap();
+        crate::snapshot::PINNED_MILLIS.with(|ms| ms.set(Some(chrono::Utc::now().timestamp_millis())));
+        for (t, temp) in [("2025-01-01T00:00:00Z", 20.5), ("2025-01-01T00:01:00Z", 21.0)] {
+            let mut o = Observation::empty("A", t.parse().unwrap());
+            o.set_field("temp", temp);
+            db.write(o).await.unwrap();
+            assert_eq!(db.flush().await.unwrap(), 1);
+        }
+        crate::snapshot::PINNED_MILLIS.with(|ms| ms.set(None));
+        assert_eq!(db.state().chunk_store.slices("A").await.len(), 2);
+        let q = RangeQuery { resolution: crate::query::ResolutionChoice::Raw, ..RangeQuery::new("A", None, None) };
+        assert!(matches!(db.query(&q).await.unwrap().rows, Some(crate::query::Rows::Raw(rows)) if rows.len() == 2));
+        db.close().await;
+    }
+}
@@ -0,0 +1,487 @@
+// Resumable bulk exports. A job writes a station's raw rows in a time range
+// into numbered NDJSON part files under `exports/<job id>`, and each part is
+// listed on the job with its rows, size, time span and SHA-256 once it is
+// complete. Clients poll the job, download finished parts in any order and
+// fetch a part again when its checksum does not match, so a multi-gigabyte
+// dump survives dropped connections with no session on the server: the
+// unguessable job id is the only handle.
+//
+// Parts for a key with a response filter are filtered as they are written,
+// so their checksums hold, and such a job is only served to keys with the
+// same filter. A job belongs to the namespace it was created in and is only
+// listed or served there, even when namespaces share an export dir. Jobs, finished or not, survive restarts; one interrupted by a
+// restart is marked failed. Finished jobs are removed after `keep`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use crate::api::filter::ResponseFilter;
+use crate::rebuild::JobState;
+use crate::units;
+use crate::AppState;
+
+const JOB_FILE: &str = "job.json";
+/// Rows read from storage at a time while writing parts.
+const READ_BATCH: usize = 4096;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    /// Where job directories go; `data_dir/exports` when unset. Exports are
+    /// off on a read-only server unless this is set.
+    pub dir: Option<PathBuf>,
+    /// Most rows per part file.
+    pub part_rows: usize,
+    /// Jobs materializing at once; more are refused until one finishes.
+    pub max_running: usize,
+    /// How long a finished job's parts stay downloadable.
+    #[serde(alias = "keep", deserialize_with = "units::secs")]
+    pub keep_secs: u64,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self { dir: None, part_rows: 100_000, max_running: 2, keep_secs: 86_400 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Part {
+    pub index: usize,
+    pub rows: usize,
+    pub bytes: u64,
+    /// Hex SHA-256 of the part file.
+    pub sha256: String,
+    pub first_time: String,
+    pub last_time: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub station_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    pub state: JobState,
+    /// Rows in the finished parts.
+    pub rows: usize,
+    pub parts: Vec<Part>,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    /// The error of a failed job.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Response filter the parts were written with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// The station's registered metadata when the job was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<crate::stations::StationMeta>,
+    /// The namespace the job exports from, `None` for the server's own data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// What to export, as given to [`Exports::create`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportRequest {
+    pub station_id: String,
+    /// Inclusive RFC3339 or relative start; the station's first row when unset.
+    pub start: Option<String>,
+    /// Exclusive RFC3339 end; up to the newest row when unset.
+    pub end: Option<String>,
+}
+
+/// Why a job could not be created or served; `Invalid` is the caller's fault.
+#[derive(Debug)]
+pub enum ExportError {
+    Invalid(String),
+    NotFound,
+    Busy(usize),
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ExportError {
+    fn from(e: anyhow::Error) -> Self {
+        ExportError::Failed(e)
+    }
+}
+
+/// The export jobs of this node.
+pub struct Exports {
+    /// `None` when exports are off.
+    dir: Option<PathBuf>,
+    cfg: ExportConfig,
+    namespace: Option<String>,
+    jobs: Mutex<BTreeMap<String, Job>>,
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+impl Exports {
+    /// Load the jobs of `namespace` kept in `dir`; jobs that were still
+    /// running when the server stopped are marked failed.
+    pub fn open(dir: Option<PathBuf>, cfg: ExportConfig, namespace: Option<&str>) -> Result<Self> {
+        let mut jobs = BTreeMap::new();
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path().join(JOB_FILE);
+                let Ok(data) = std::fs::read(&path) else { continue };
+                let mut job: Job = match serde_json::from_slice(&data) {
+                    Ok(job) => job,
+                    Err(e) => {
+                        tracing::warn!("skipping export job {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if job.namespace.as_deref() != namespace {
+                    continue;
+                }
+                if job.state == JobState::Running {
+                    job.state = JobState::Failed;
+                    job.finished_at = Some(now());
+                    job.message = Some("interrupted by a restart".to_string());
+                    std::fs::write(&path, serde_json::to_vec_pretty(&job)?)?;
+                }
+                jobs.insert(job.id.clone(), job);
+            }
+        }
+        Ok(Self { dir, cfg, namespace: namespace.map(str::to_string), jobs: Mutex::new(jobs) })
+    }
+
+    /// Whether a key with response filter `filter` may see `job`.
+    fn visible(&self, job: &Job, filter: Option<&str>) -> bool {
+        job.filter.as_deref() == filter && job.namespace == self.namespace
+    }
+
+    fn dir(&self) -> Result<&Path, ExportError> {
+        self.dir.as_deref().ok_or_else(|| ExportError::Invalid("exports are off: set export.dir on a read-only server".to_string()))
+    }
+
+    /// Start materializing `req` in the background and return the new job.
+    /// `filter` is the requesting key's response filter, by name.
+    pub async fn create(
+        &self,
+        state: Arc<AppState>,
+        req: ExportRequest,
+        filter: Option<(String, ResponseFilter)>,
+    ) -> Result<Job, ExportError> {
+        let dir = self.dir()?;
+        let at = Utc::now();
+        let bound = |name: &str, v: &Option<String>| {
+            v.as_deref()
+                .map(|v| crate::query::parse_time_at(v, at).map_err(|e| ExportError::Invalid(format!("invalid {}: {}", name, e))))
+                .transpose()
+        };
+        let (start, end) = (bound("start", &req.start)?, bound("end", &req.end)?);
+        if req.station_id.is_empty() {
+            return Err(ExportError::Invalid("station_id must not be empty".to_string()));
+        }
+        if let (Some(s), Some(e)) = (start, end) {
+            if s >= e {
+                return Err(ExportError::Invalid("start must be before end".to_string()));
+            }
+        }
+        self.expire().await;
+        let job = {
+            let metadata = state.stations.get(&req.station_id).await;
+            let mut jobs = self.jobs.lock().await;
+            let running = jobs.values().filter(|j| j.state == JobState::Running).count();
+            if running >= self.cfg.max_running.max(1) {
+                return Err(ExportError::Busy(running));
+            }
+            let job = Job {
+                id: new_id()?,
+                station_id: req.station_id,
+                start: start.map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                end: end.map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                state: JobState::Running,
+                rows: 0,
+                parts: Vec::new(),
+                created_at: now(),
+                finished_at: None,
+                message: None,
+                filter: filter.as_ref().map(|(name, _)| name.clone()),
+                metadata,
+                namespace: self.namespace.clone(),
+            };
+            tokio::fs::create_dir_all(dir.join(&job.id)).await.map_err(anyhow::Error::from)?;
+            save(dir, &job).await?;
+            jobs.insert(job.id.clone(), job.clone());
+            job
+        };
+        tracing::info!(id = job.id, station_id = job.station_id, "export started");
+        let id = job.id.clone();
+        let s = state.clone();
+        state.runtimes.compaction.spawn(async move {
+            let result = materialize(&s, &id, start, end, filter.map(|(_, f)| f)).await;
+            let exports = &s.exports;
+            let mut jobs = exports.jobs.lock().await;
+            let Some(job) = jobs.get_mut(&id) else { return };
+            job.finished_at = Some(now());
+            match result {
+                Ok(()) => {
+                    tracing::info!(id, rows = job.rows, parts = job.parts.len(), "export finished");
+                    job.state = JobState::Done;
+                }
+                Err(e) => {
+                    tracing::error!(id, "export failed: {:#}", e);
+                    job.state = JobState::Failed;
+                    job.message = Some(format!("{:#}", e));
+                }
+            }
+            if let Some(dir) = &exports.dir {
+                if let Err(e) = save(dir, job).await {
+                    tracing::warn!(id, "could not save export job: {}", e);
+                }
+            }
+        });
+        Ok(job)
+    }
+
+    /// Jobs visible to a key with response filter `filter`, newest first.
+    pub async fn list(&self, filter: Option<&str>) -> Vec<Job> {
+        self.expire().await;
+        let jobs = self.jobs.lock().await;
+        let mut out: Vec<Job> = jobs.values().filter(|j| self.visible(j, filter)).cloned().collect();
+        out.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        out
+    }
+
+    pub async fn get(&self, id: &str, filter: Option<&str>) -> Result<Job, ExportError> {
+        let jobs = self.jobs.lock().await;
+        jobs.get(id).filter(|j| self.visible(j, filter)).cloned().ok_or(ExportError::NotFound)
+    }
+
+    /// A finished part's path and listing.
+    pub async fn part(&self, id: &str, index: usize, filter: Option<&str>) -> Result<(PathBuf, Part), ExportError> {
+        let job = self.get(id, filter).await?;
+        let part = job.parts.into_iter().find(|p| p.index == index).ok_or(ExportError::NotFound)?;
+        Ok((self.dir()?.join(id).join(part_file(index)), part))
+    }
+
+    /// Remove a job and its parts; a running job stops at its next part.
+    pub async fn remove(&self, id: &str, filter: Option<&str>) -> Result<(), ExportError> {
+        let dir = self.dir()?;
+        let mut jobs = self.jobs.lock().await;
+        if jobs.get(id).filter(|j| self.visible(j, filter)).is_none() {
+            return Err(ExportError::NotFound);
+        }
+        jobs.remove(id);
+        tokio::fs::remove_dir_all(dir.join(id)).await.map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Drop finished jobs older than `keep`.
+    async fn expire(&self) {
+        let Some(dir) = &self.dir else { return };
+        let cutoff = Utc::now() - chrono::Duration::seconds(self.cfg.keep_secs as i64);
+        let mut jobs = self.jobs.lock().await;
+        let expired: Vec<String> = jobs
+            .values()
+            .filter(|j| j.namespace == self.namespace)
+            .filter(|j| {
+                let finished = j.finished_at.as_deref().and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+                finished.is_some_and(|t| t < cutoff)
+            })
+            .map(|j| j.id.clone())
+            .collect();
+        for id in expired {
+            jobs.remove(&id);
+            if let Err(e) = tokio::fs::remove_dir_all(dir.join(&id)).await {
+                tracing::warn!(id, "could not remove expired export: {}", e);
+            }
+        }
+    }
+}
+
+fn part_file(index: usize) -> String {
+    format!("part-{:05}.ndjson", index)
+}
+
+/// Persist a job's listing atomically.
+async fn save(dir: &Path, job: &Job) -> Result<()> {
+    let path = dir.join(&job.id).join(JOB_FILE);
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(job)?).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+    Ok(())
+}
+
+/// 128 random bits, so job ids cannot be guessed.
+fn new_id() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes).map_err(|_| anyhow!("no randomness available"))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// A part file being written, hashed as it goes.
+struct PartWriter {
+    part: Part,
+    path: PathBuf,
+    tmp: PathBuf,
+    file: tokio::io::BufWriter<tokio::fs::File>,
+    digest: ring::digest::Context,
+}
+
+impl PartWriter {
+    async fn create(dir: &Path, index: usize) -> Result<Self> {
+        let path = dir.join(part_file(index));
+        let tmp = path.with_extension("ndjson.tmp");
+        let file = tokio::fs::File::create(&tmp).await.context("job was removed")?;
+        Ok(Self {
+            part: Part { index, rows: 0, bytes: 0, sha256: String::new(), first_time: String::new(), last_time: String::new() },
+            path,
+            tmp,
+            file: tokio::io::BufWriter::new(file),
+            digest: ring::digest::Context::new(&ring::digest::SHA256),
+        })
+    }
+
+    async fn write(&mut self, line: &[u8], time: String) -> Result<()> {
+        self.file.write_all(line).await?;
+        self.digest.update(line);
+        self.part.bytes += line.len() as u64;
+        self.part.rows += 1;
+        if self.part.first_time.is_empty() {
+            self.part.first_time.clone_from(&time);
+        }
+        self.part.last_time = time;
+        Ok(())
+    }
+
+    /// Sync and rename the file into place and return its listing.
+    async fn finish(mut self) -> Result<Part> {
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+        tokio::fs::rename(&self.tmp, &self.path).await?;
+        self.part.sha256 = self.digest.finish().as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(self.part)
+    }
+}
+
+/// Write the job's rows as parts, a row at a time, listing each part on the
+/// job once it is on disk.
+async fn materialize(
+    state: &Arc<AppState>,
+    id: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    filter: Option<ResponseFilter>,
+) -> Result<()> {
+    let exports = &state.exports;
+    let Some(dir) = &exports.dir else { bail!("exports are off") };
+    let job_dir = dir.join(id);
+    let part_rows = exports.cfg.part_rows.max(1);
+    let station_id = exports.jobs.lock().await.get(id).map(|j| j.station_id.clone()).context("job was removed")?;
+    let mut batches = crate::query::stream::observations(state.clone(), &station_id, start, end, READ_BATCH.min(part_rows));
+    let mut writer: Option<PartWriter> = None;
+    let mut index = 0;
+    let mut line = Vec::new();
+    while let Some(batch) = batches.next().await {
+        for o in &batch.map_err(|e| anyhow!(e))?.observations {
+            let mut v = serde_json::to_value(o)?;
+            if let Some(f) = &filter {
+                f.apply(&mut v);
+            }
+            line.clear();
+            serde_json::to_writer(&mut line, &v)?;
+            line.push(b'\n');
+            let part = match &mut writer {
+                Some(w) => w,
+                None => writer.insert(PartWriter::create(&job_dir, index).await?),
+            };
+            part.write(&line, o.time.to_string()).await?;
+            if part.part.rows >= part_rows {
+                list_part(exports, dir, id, writer.take().unwrap().finish().await?).await?;
+                index += 1;
+            }
+        }
+    }
+    if let Some(w) = writer {
+        list_part(exports, dir, id, w.finish().await?).await?;
+    }
+    Ok(())
+}
+
+/// Add a finished part to its job.
+async fn list_part(exports: &Exports, dir: &Path, id: &str, part: Part) -> Result<()> {
+    let mut jobs = exports.jobs.lock().await;
+    let job = jobs.get_mut(id).context("job was removed")?;
+    job.rows += part.rows;
+    job.parts.push(part);
+    save(dir, job).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Observation;
+
+    #[tokio::test]
+    async fn exports_parts_with_checksums() {
+        let dir = crate::test_util::TempDir::new("export");
+        let mut cfg = crate::config::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        cfg.export.part_rows = 2;
+        let state = Arc::new(AppState::open(&cfg).await.unwrap());
+        let rows: Vec<Observation> = (0..5)
+            .map(|m| {
+                let mut o = Observation::empty("A", format!("2025-01-01T00:0{}:00Z", m).parse().unwrap());
+                o.set_field("temp", 20.25);
+                o
+            })
+            .collect();
+        state.chunk_store.write_chunk("A", "flush-1", &rows).await.unwrap();
+
+        let req = ExportRequest { station_id: "A".into(), start: Some("2025-01-01T00:01:00Z".into()), end: None };
+        let round = ResponseFilter { round: [("temp".to_string(), 0)].into(), ..Default::default() };
+        let job = state.exports.create(state.clone(), req, Some(("public".into(), round))).await.unwrap();
+        let job = loop {
+            let job = state.exports.get(&job.id, Some("public")).await.unwrap();
+            if job.state != JobState::Running {
+                break job;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!((job.state, job.rows, job.parts.len()), (JobState::Done, 4, 2));
+        assert_eq!(job.parts[1].first_time, "2025-01-01T00:03:00Z");
+        let (path, part) = state.exports.part(&job.id, 0, Some("public")).await.unwrap();
+        let data = std::fs::read(path).unwrap();
+        let sha: String = ring::digest::digest(&ring::digest::SHA256, &data).as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(sha, part.sha256);
+        assert!(String::from_utf8(data).unwrap().contains("\"temp\":20.0"));
+        // a job is only served to keys with the filter it was written with
+        assert!(matches!(state.exports.get(&job.id, None).await, Err(ExportError::NotFound)));
+        assert!(state.exports.list(None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn jobs_stay_in_their_namespace() {
+        let dir = crate::test_util::TempDir::new("export-ns");
+        let mut cfg = crate::config::Config { data_dir: dir.join("data"), ..Default::default() };
+        cfg.export.dir = Some(dir.join("exports"));
+        let state = Arc::new(AppState::open(&cfg).await.unwrap());
+        let req = ExportRequest { station_id: "A".into(), start: None, end: None };
+        let job = state.exports.create(state.clone(), req, None).await.unwrap();
+        assert_eq!(state.exports.list(None).await.len(), 1);
+
+        // another namespace reading the same export dir neither loads nor serves it
+        let other = Exports::open(cfg.export.dir.clone(), cfg.export.clone(), Some("marine")).unwrap();
+        assert!(other.list(None).await.is_empty());
+        assert!(matches!(other.get(&job.id, None).await, Err(ExportError::NotFound)));
+        assert!(matches!(other.remove(&job.id, None).await, Err(ExportError::NotFound)));
+    }
+}
@@ -0,0 +1,329 @@
+// Forecasting hooks. A forecaster predicts the next values of one field of a
+// station from its recent history, resampled to an even step. Forecasters are
+// looked up by name in a registry: Holt-Winters is built in, and embedders
+// register their own models by implementing `Forecaster`.
+//
+// Predictions are written back as observations of the station's forecast
+// namespace (`forecast.<station_id>`), tagged with the forecaster and the time
+// the forecast was issued, so they are stored, retained and queried like any
+// other data and can be returned next to the observations they extend.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use anyhow::{bail, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::units;
+
+/// Prefix of the station ids predictions are stored under.
+pub const NAMESPACE: &str = "forecast.";
+/// Most steps a single forecast may predict.
+pub const MAX_HORIZON: usize = 10_000;
+/// Most steps the history of a forecast may be resampled to.
+pub const MAX_HISTORY_STEPS: u64 = 100_000;
+
+/// The station id holding the predictions for `station_id`.
+pub fn station_id(station_id: &str) -> String {
+    format!("{}{}", NAMESPACE, station_id)
+}
+
+/// Evenly spaced values of one field: `values[i]` is the mean of the
+/// readings in `[start + i * step, start + (i + 1) * step)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub start: Timestamp,
+    pub step_ms: i64,
+    pub values: Vec<f64>,
+}
+
+impl Series {
+    /// Resample `obs` to buckets of `step_ms` aligned to the epoch. Buckets
+    /// without a reading are filled by linear interpolation between their
+    /// neighbours; nothing is invented before the first or after the last.
+    pub fn resample(obs: &[Observation], field: &str, step_ms: i64) -> Option<Self> {
+        let mut buckets: BTreeMap<i64, (f64, u32)> = BTreeMap::new();
+        for o in obs {
+            if let Some(v) = o.number(field) {
+                let b = buckets.entry(o.time.millis().div_euclid(step_ms)).or_default();
+                b.0 += v;
+                b.1 += 1;
+            }
+        }
+        let (&first, _) = buckets.first_key_value()?;
+        let mut values = Vec::new();
+        let mut prev: Option<(i64, f64)> = None;
+        for (&i, &(sum, n)) in &buckets {
+            let v = sum / n as f64;
+            if let Some((pi, pv)) = prev {
+                for gap in pi + 1..i {
+                    values.push(pv + (v - pv) * (gap - pi) as f64 / (i - pi) as f64);
+                }
+            }
+            values.push(v);
+            prev = Some((i, v));
+        }
+        Some(Self { start: Timestamp(first * step_ms), step_ms, values })
+    }
+
+    /// Start of the bucket `n` steps after the last one.
+    pub fn time_after(&self, n: usize) -> Timestamp {
+        Timestamp(self.start.millis() + (self.values.len() + n) as i64 * self.step_ms)
+    }
+}
+
+/// A model that extends a series.
+pub trait Forecaster: Send + Sync {
+    /// Predict the `horizon` values following `series`. `season` is the
+    /// length of a seasonal cycle in steps (e.g. 24 for a daily cycle of
+    /// hourly steps), or 0 for none.
+    fn forecast(&self, series: &Series, horizon: usize, season: usize) -> Result<Vec<f64>>;
+}
+
+/// Additive Holt-Winters (triple exponential smoothing); without a season,
+/// or with less than two cycles of history, Holt's linear trend method.
+#[derive(Debug, Clone, Copy)]
+pub struct HoltWinters {
+    /// Smoothing of the level.
+    pub alpha: f64,
+    /// Smoothing of the trend.
+    pub beta: f64,
+    /// Smoothing of the seasonal components.
+    pub gamma: f64,
+}
+
+impl Default for HoltWinters {
+    fn default() -> Self {
+        Self { alpha: 0.5, beta: 0.1, gamma: 0.3 }
+    }
+}
+
+impl Forecaster for HoltWinters {
+    fn forecast(&self, series: &Series, horizon: usize, season: usize) -> Result<Vec<f64>> {
+        let y = &series.values;
+        if y.len() < 2 {
+            bail!("need at least 2 steps of history, got {}", y.len());
+        }
+        let (a, b, g) = (self.alpha, self.beta, self.gamma);
+        if season < 2 || y.len() < 2 * season {
+            let (mut level, mut trend) = (y[0], y[1] - y[0]);
+            for &v in &y[1..] {
+                let prev = level;
+                level = a * v + (1.0 - a) * (level + trend);
+                trend = b * (level - prev) + (1.0 - b) * trend;
+            }
+            return Ok((1..=horizon).map(|h| level + h as f64 * trend).collect());
+        }
+        let mean = |s: &[f64]| s.iter().sum::<f64>() / s.len() as f64;
+        let (first, second) = (mean(&y[..season]), mean(&y[season..2 * season]));
+        let mut level = first;
+        let mut trend = (second - first) / season as f64;
+        let mut seasonal: Vec<f64> = y[..season].iter().map(|v| v - first).collect();
+        for (t, &v) in y.iter().enumerate() {
+            let s = seasonal[t % season];
+            let prev = level;
+            level = a * (v - s) + (1.0 - a) * (level + trend);
+            trend = b * (level - prev) + (1.0 - b) * trend;
+            seasonal[t % season] = g * (v - level) + (1.0 - g) * s;
+        }
+        let n = y.len();
+        Ok((1..=horizon).map(|h| level + h as f64 * trend + seasonal[(n + h - 1) % season]).collect())
+    }
+}
+
+/// Forecasters by name. `holt_winters` is always registered.
+pub struct Registry {
+    forecasters: RwLock<BTreeMap<String, Arc<dyn Forecaster>>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let registry = Self { forecasters: RwLock::new(BTreeMap::new()) };
+        registry.register("holt_winters", Arc::new(HoltWinters::default()));
+        registry
+    }
+}
+
+impl Registry {
+    /// Add a forecaster, replacing any registered under the same name.
+    pub fn register(&self, name: &str, forecaster: Arc<dyn Forecaster>) {
+        self.forecasters.write().unwrap().insert(name.to_string(), forecaster);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Forecaster>> {
+        self.forecasters.read().unwrap().get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.forecasters.read().unwrap().keys().cloned().collect()
+    }
+}
+
+fn default_forecaster() -> String {
+    "holt_winters".to_string()
+}
+
+fn default_step_ms() -> u64 {
+    3_600_000
+}
+
+fn default_history_ms() -> u64 {
+    7 * 86_400_000
+}
+
+fn default_horizon() -> usize {
+    24
+}
+
+fn default_store() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForecastRequest {
+    pub station_id: String,
+    pub field: String,
+    #[serde(default = "default_forecaster")]
+    pub forecaster: String,
+    /// Spacing of the resampled history and of the predictions.
+    #[serde(alias = "step", default = "default_step_ms", deserialize_with = "units::millis")]
+    pub step_ms: u64,
+    /// How far back from the station's newest reading the history goes.
+    #[serde(alias = "history", default = "default_history_ms", deserialize_with = "units::millis")]
+    pub history_ms: u64,
+    /// Number of steps to predict.
+    #[serde(default = "default_horizon")]
+    pub horizon: usize,
+    /// Seasonal cycle in steps; none when omitted.
+    #[serde(default)]
+    pub season: usize,
+    /// Write the predictions to the forecast namespace; otherwise only return them.
+    #[serde(default = "default_store")]
+    pub store: bool,
+}
+
+impl ForecastRequest {
+    pub fn validate(&self) -> Result<()> {
+        if self.step_ms == 0 || self.history_ms < self.step_ms {
+            bail!("step must be at least 1ms and no longer than history");
+        }
+        if self.history_ms / self.step_ms > MAX_HISTORY_STEPS {
+            bail!("history spans more than {} steps; shorten it or use a larger step", MAX_HISTORY_STEPS);
+        }
+        if self.horizon == 0 || self.horizon > MAX_HORIZON {
+            bail!("horizon must be between 1 and {}", MAX_HORIZON);
+        }
+        if self.station_id.starts_with(NAMESPACE) {
+            bail!("station {} is already a forecast", self.station_id);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Forecast {
+    pub station_id: String,
+    pub field: String,
+    pub forecaster: String,
+    pub issued: Timestamp,
+    /// Resampled steps the forecaster was given.
+    pub history_steps: usize,
+    pub stored: bool,
+    /// Predictions as observations of `forecast.<station_id>`.
+    pub points: Vec<Observation>,
+}
+
+/// Run `forecaster` over `history` (the station's raw rows) and build the
+/// prediction rows; nothing is stored here.
+pub fn predict(forecaster: &dyn Forecaster, req: &ForecastRequest, history: &[Observation]) -> Result<Forecast> {
+    let Some(series) = Series::resample(history, &req.field, req.step_ms as i64) else {
+        bail!("station {} has no numeric '{}' readings in the last {} ms", req.station_id, req.field, req.history_ms);
+    };
+    let values = forecaster.forecast(&series, req.horizon, req.season)?;
+    let issued = Timestamp::from_datetime(Utc::now());
+    let target = station_id(&req.station_id);
+    let points = values
+        .into_iter()
+        .enumerate()
+        .filter(|(_, v)| v.is_finite())
+        .map(|(i, v)| {
+            let mut o = Observation::empty(&target, series.time_after(i));
+            o.tags.insert("forecaster".into(), req.forecaster.clone());
+            o.tags.insert("issued".into(), issued.to_string());
+            o.set_field(req.field.as_str(), v);
+            o
+        })
+        .collect();
+    Ok(Forecast {
+        station_id: req.station_id.clone(),
+        field: req.field.clone(),
+        forecaster: req.forecaster.clone(),
+        issued,
+        history_steps: series.values.len(),
+        stored: false,
+        points,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(values: &[f64]) -> Series {
+        Series { start: Timestamp(0), step_ms: 1000, values: values.to_vec() }
+    }
+
+    #[test]
+    fn resamples_with_interpolated_gaps() {
+        let at = |ms: i64, v: f64| {
+            let mut o = Observation::empty("A", Timestamp(ms));
+            o.set_field("temp", v);
+            o
+        };
+        let obs = vec![at(60_500, 10.0), at(60_900, 12.0), at(241_000, 20.0), Observation::empty("A", Timestamp(300_000))];
+        let s = Series::resample(&obs, "temp", 60_000).unwrap();
+        assert_eq!(s.start, Timestamp(60_000));
+        assert_eq!(s.values, vec![11.0, 14.0, 17.0, 20.0]);
+        assert_eq!(s.time_after(0), Timestamp(300_000));
+        assert!(Series::resample(&obs, "humidity", 60_000).is_none());
+    }
+
+    #[test]
+    fn bounds_the_resampled_history() {
+        let req = |step: &str, history: &str| -> ForecastRequest {
+            serde_json::from_value(serde_json::json!({ "station_id": "A", "field": "temp", "step": step, "history": history })).unwrap()
+        };
+        assert!(req("1h", "7d").validate().is_ok());
+        assert!(req("1ms", "7d").validate().is_err());
+        assert!(req("1d", "1h").validate().is_err());
+    }
+
+    #[test]
+    fn holt_follows_a_trend() {
+        let hw = HoltWinters::default();
+        let out = hw.forecast(&series(&[1.0, 2.0, 3.0, 4.0, 5.0]), 3, 0).unwrap();
+        for (got, want) in out.iter().zip([6.0, 7.0, 8.0]) {
+            assert!((got - want).abs() < 1e-9, "{:?}", out);
+        }
+        assert!(hw.forecast(&series(&[1.0]), 3, 0).is_err());
+    }
+
+    #[test]
+    fn holt_winters_repeats_the_season() {
+        let cycle = [10.0, 14.0, 18.0, 14.0];
+        let values: Vec<f64> = cycle.iter().cycle().take(16).copied().collect();
+        let out = HoltWinters::default().forecast(&series(&values), 4, 4).unwrap();
+        for (got, want) in out.iter().zip(cycle) {
+            assert!((got - want).abs() < 0.5, "{:?}", out);
+        }
+    }
+
+    #[test]
+    fn registry_has_the_builtin() {
+        let r = Registry::default();
+        assert!(r.get("holt_winters").is_some());
+        r.register("flat", Arc::new(HoltWinters { alpha: 1.0, beta: 0.0, gamma: 0.0 }));
+        assert_eq!(r.names(), vec!["flat", "holt_winters"]);
+    }
+}
@@ -0,0 +1,372 @@
+// Per-station health for triaging a network at a glance. Four signals are
+// each scored from 0 (bad) to 1 (good): how fresh the newest reading is,
+// the share of writes passing validation, the share of readings not flagged
+// as anomalous, and the device telemetry checks passing on the newest
+// reading. Their weighted mean, scaled to 0-100, is the station's score.
+// Write and flag counts cover the current and previous window only, so a
+// station recovers once its bad readings age out. `/metrics` serves the
+// scores a background task last computed rather than assessing every
+// station per scrape.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Receiver as BroadcastReceiver;
+use crate::storage::manifest::StationInfo;
+use crate::storage::memtable::{FieldValue, Observation};
+use crate::units;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// A station whose newest reading is older than this starts losing
+    /// freshness; at twice the age it scores half.
+    #[serde(alias = "stale_after", deserialize_with = "units::secs")]
+    pub stale_after_secs: u64,
+    /// Width of the windows the validation and anomaly rates are counted in.
+    #[serde(alias = "window", deserialize_with = "units::secs")]
+    pub window_secs: u64,
+    /// Fields marking a reading as anomalous when true (or non-zero).
+    pub flag_fields: Vec<String>,
+    /// Device telemetry bounds checked on the newest reading.
+    pub telemetry: Vec<TelemetryCheck>,
+    pub weights: Weights,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            stale_after_secs: 3600,
+            window_secs: 3600,
+            flag_fields: vec!["anomaly".to_string()],
+            telemetry: Vec::new(),
+            weights: Weights::default(),
+        }
+    }
+}
+
+/// A telemetry field and the range it should stay in, e.g. battery voltage
+/// at least 11.5.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryCheck {
+    pub field: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl TelemetryCheck {
+    /// `None` when the reading lacks the field.
+    fn passes(&self, obs: &Observation) -> Option<bool> {
+        let v = obs.number(&self.field)?;
+        Some(self.min.is_none_or(|m| v >= m) && self.max.is_none_or(|m| v <= m))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Weights {
+    pub staleness: f64,
+    pub validation: f64,
+    pub anomalies: f64,
+    pub telemetry: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self { staleness: 0.4, validation: 0.2, anomalies: 0.2, telemetry: 0.2 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Score 80 and above.
+    Ok,
+    /// Score 50 to 80.
+    Degraded,
+    Critical,
+}
+
+impl Status {
+    pub fn of(score: f64) -> Self {
+        if score >= 80.0 {
+            Status::Ok
+        } else if score >= 50.0 {
+            Status::Degraded
+        } else {
+            Status::Critical
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Health {
+    /// 0 (down) to 100 (healthy).
+    pub score: f64,
+    pub status: Status,
+    /// Seconds since the newest reading; `None` without any.
+    pub age_secs: Option<i64>,
+    /// Share of writes rejected by validation over the recent windows.
+    pub rejection_rate: f64,
+    /// Share of accepted readings flagged as anomalous.
+    pub anomaly_rate: f64,
+    /// Telemetry fields out of range on the newest reading.
+    pub telemetry_failures: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    accepted: u64,
+    rejected: u64,
+    flagged: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+        self.flagged += other.flagged;
+    }
+}
+
+/// A station's counts in the current and previous window.
+#[derive(Debug, Default)]
+struct Windows {
+    start: i64,
+    current: Counts,
+    previous: Counts,
+}
+
+impl Windows {
+    fn roll(&mut self, now: i64, width: i64) -> &mut Counts {
+        if now - self.start >= width {
+            self.previous = if now - self.start < 2 * width { self.current } else { Counts::default() };
+            self.current = Counts::default();
+            self.start = now - (now - self.start) % width;
+        }
+        &mut self.current
+    }
+}
+
+/// How often the scores served at `/metrics` are recomputed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Write counts per station, fed by the ingest path, and the scores last
+/// computed for `/metrics`.
+pub struct HealthTracker {
+    cfg: HealthConfig,
+    stations: Mutex<HashMap<String, Windows>>,
+    scores: Mutex<Vec<(StationInfo, Health)>>,
+    /// Stations whose newest reading could not be read while assessing.
+    errors: AtomicU64,
+}
+
+impl HealthTracker {
+    pub fn new(cfg: HealthConfig) -> Self {
+        Self { cfg, stations: Mutex::new(HashMap::new()), scores: Mutex::new(Vec::new()), errors: AtomicU64::new(0) }
+    }
+
+    fn width(&self) -> i64 {
+        self.cfg.window_secs.max(1) as i64 * 1000
+    }
+
+    fn flagged(&self, obs: &Observation) -> bool {
+        self.cfg.flag_fields.iter().any(|f| match obs.fields.get(f) {
+            Some(FieldValue::Bool(b)) => *b,
+            Some(FieldValue::Number(v)) => *v != 0.0,
+            _ => false,
+        })
+    }
+
+    /// Count accepted readings at `now` (epoch milliseconds).
+    pub fn accepted(&self, obs: &[Observation], now: i64) {
+        let width = self.width();
+        let mut stations = self.stations.lock().unwrap();
+        for o in obs {
+            let counts = stations.entry(o.station_id.clone()).or_insert_with(|| Windows { start: now, ..Default::default() }).roll(now, width);
+            counts.accepted += 1;
+            counts.flagged += u64::from(self.flagged(o));
+        }
+    }
+
+    /// Count a write of `station_id` rejected by validation.
+    pub fn rejected(&self, station_id: &str, now: i64) {
+        let width = self.width();
+        let mut stations = self.stations.lock().unwrap();
+        stations.entry(station_id.to_string()).or_insert_with(|| Windows { start: now, ..Default::default() }).roll(now, width).rejected += 1;
+    }
+
+    /// Forget stations with no counts left in either window at `now`, so ids
+    /// that stopped writing (or were only ever rejected) do not pile up.
+    pub fn evict(&self, now: i64) {
+        let width = self.width();
+        self.stations.lock().unwrap().retain(|_, w| now - w.start < 2 * width);
+    }
+
+    fn counts(&self, station_id: &str, now: i64) -> Counts {
+        let width = self.width();
+        let mut stations = self.stations.lock().unwrap();
+        let Some(w) = stations.get_mut(station_id) else { return Counts::default() };
+        let mut total = *w.roll(now, width);
+        total.add(w.previous);
+        total
+    }
+
+    /// Score `station_id` at `now` (epoch milliseconds) given its newest reading.
+    pub fn assess(&self, station_id: &str, latest: Option<&Observation>, now: i64) -> Health {
+        let counts = self.counts(station_id, now);
+        let age_secs = latest.map(|o| ((now - o.time.millis()) / 1000).max(0));
+        let stale_after = self.cfg.stale_after_secs.max(1) as f64;
+        let freshness = age_secs.map_or(0.0, |age| (stale_after / age.max(1) as f64).min(1.0));
+        let writes = counts.accepted + counts.rejected;
+        let rejection_rate = if writes == 0 { 0.0 } else { counts.rejected as f64 / writes as f64 };
+        let anomaly_rate = if counts.accepted == 0 { 0.0 } else { counts.flagged as f64 / counts.accepted as f64 };
+        let checked: Vec<(&TelemetryCheck, bool)> =
+            self.cfg.telemetry.iter().filter_map(|c| Some((c, c.passes(latest?)?))).collect();
+        let telemetry = if checked.is_empty() {
+            1.0
+        } else {
+            checked.iter().filter(|(_, ok)| *ok).count() as f64 / checked.len() as f64
+        };
+        let w = &self.cfg.weights;
+        let total = w.staleness + w.validation + w.anomalies + w.telemetry;
+        let score = if total <= 0.0 {
+            100.0
+        } else {
+            let sum = w.staleness * freshness
+                + w.validation * (1.0 - rejection_rate)
+                + w.anomalies * (1.0 - anomaly_rate)
+                + w.telemetry * telemetry;
+            (sum / total * 1000.0).round() / 10.0
+        };
+        Health {
+            score,
+            status: Status::of(score),
+            age_secs,
+            rejection_rate,
+            anomaly_rate,
+            telemetry_failures: checked.into_iter().filter(|(_, ok)| !ok).map(|(c, _)| c.field.clone()).collect(),
+        }
+    }
+}
+
+/// Every known station with its health. A station whose newest reading
+/// cannot be read is scored as having none and counted as an error.
+pub async fn assess_all(state: &crate::AppState) -> Vec<(StationInfo, Health)> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut out = Vec::new();
+    for info in crate::query::stations(state).await {
+        let latest = match crate::query::latest(state, &info.station_id).await {
+            Ok(latest) => latest,
+            Err(e) => {
+                tracing::warn!(station_id = info.station_id, "health: reading the newest reading failed: {}", e);
+                state.health.errors.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        };
+        let health = state.health.assess(&info.station_id, latest.as_ref(), now);
+        out.push((info, health));
+    }
+    out
+}
+
+/// Recompute the scores served at `/metrics` and forget idle stations.
+pub async fn refresh(state: &crate::AppState) {
+    state.health.evict(chrono::Utc::now().timestamp_millis());
+    let scores = assess_all(state).await;
+    *state.health.scores.lock().unwrap() = scores;
+}
+
+/// Score refresher: runs until `shutdown` fires.
+pub async fn run(state: std::sync::Arc<crate::AppState>, mut shutdown: BroadcastReceiver<()>) {
+    let mut ticker = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => refresh(&state).await,
+        }
+    }
+}
+
+/// Prometheus text for the per-station scores last computed, so alert rules
+/// can fire on them, and the assessment error count.
+pub fn render(out: &mut String, tracker: &HealthTracker) {
+    let _ = writeln!(out, "# HELP skypulse_station_health Station health score, 0 (down) to 100 (healthy).");
+    let _ = writeln!(out, "# TYPE skypulse_station_health gauge");
+    for (info, h) in tracker.scores.lock().unwrap().iter() {
+        let id = info.station_id.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = writeln!(out, "skypulse_station_health{{station_id=\"{}\"}} {}", id, h.score);
+    }
+    let _ = writeln!(out, "# HELP skypulse_health_assessment_errors_total Stations whose newest reading could not be read while scoring.");
+    let _ = writeln!(out, "# TYPE skypulse_health_assessment_errors_total counter");
+    let _ = writeln!(out, "skypulse_health_assessment_errors_total {}", tracker.errors.load(Ordering::Relaxed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_the_signals() {
+        let cfg = HealthConfig {
+            telemetry: vec![TelemetryCheck { field: "battery".into(), min: Some(11.5), max: None }],
+            ..Default::default()
+        };
+        let tracker = HealthTracker::new(cfg);
+        let now = 10 * 3_600_000;
+        let mut o = Observation::empty("A", crate::storage::memtable::Timestamp(now - 60_000));
+        o.set_field("battery", 12.6);
+        tracker.accepted(&[o.clone()], now);
+        let h = tracker.assess("A", Some(&o), now);
+        assert_eq!((h.score, h.status), (100.0, Status::Ok));
+
+        // low battery, one flagged and one rejected write, two hours stale
+        o.set_field("battery", 11.0);
+        let mut flagged = o.clone();
+        flagged.set("anomaly", FieldValue::Bool(true));
+        tracker.accepted(&[flagged], now);
+        tracker.rejected("A", now);
+        let h = tracker.assess("A", None, now);
+        assert!((h.rejection_rate - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(h.anomaly_rate, 0.5);
+        assert_eq!(h.status, Status::Critical);
+
+        let later = now + 2 * 3_600_000;
+        let h = tracker.assess("A", Some(&o), later);
+        assert_eq!(h.telemetry_failures, vec!["battery".to_string()]);
+        assert_eq!(h.age_secs, Some(2 * 3600 + 60));
+        // counts from two windows back have aged out
+        assert_eq!((h.rejection_rate, h.anomaly_rate), (0.0, 0.0));
+        assert_eq!(h.status, Status::Degraded);
+    }
+
+    #[test]
+    fn evicts_stations_once_their_counts_age_out() {
+        let tracker = HealthTracker::new(HealthConfig::default());
+        let now = 10 * 3_600_000;
+        tracker.rejected("typo", now);
+        tracker.accepted(&[Observation::empty("A", crate::storage::memtable::Timestamp(now))], now + 3_600_000);
+        tracker.evict(now + 3_600_000);
+        assert_eq!(tracker.stations.lock().unwrap().len(), 2);
+        tracker.evict(now + 2 * 3_600_000);
+        assert!(tracker.stations.lock().unwrap().contains_key("A") && !tracker.stations.lock().unwrap().contains_key("typo"));
+    }
+
+    #[tokio::test]
+    async fn metrics_serve_the_refreshed_scores() {
+        let dir = crate::test_util::TempDir::new("health");
+        let state = crate::AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap();
+        state.chunk_store.write_chunk("A", "flush-1", &[Observation::empty("A", "2025-01-01T00:00:00Z".parse().unwrap())]).await.unwrap();
+        let mut body = String::new();
+        render(&mut body, &state.health);
+        assert!(!body.contains("station_id=\"A\"") && body.contains("skypulse_health_assessment_errors_total 0"));
+        refresh(&state).await;
+        let mut body = String::new();
+        render(&mut body, &state.health);
+        assert!(body.contains("skypulse_station_health{station_id=\"A\"}"), "{}", body);
+    }
+}
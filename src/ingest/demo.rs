@@ -0,0 +1,172 @@
+// Demo mode: a synthetic generator feeding a few virtual stations so the
+// query API, rollups and dashboards have something to show without any
+// hardware. Readings follow a daily temperature cycle with noise, humidity
+// moving against temperature, a slowly wandering pressure and gusty wind.
+// On start the last `backfill_hours` are filled in at one-minute spacing.
+
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::units;
+use crate::AppState;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DemoConfig {
+    /// Number of virtual stations, named `DEMO001`, `DEMO002`, ...
+    pub stations: usize,
+    /// Seconds between live readings of each station.
+    #[serde(alias = "interval", deserialize_with = "units::secs")]
+    pub interval_secs: u64,
+    /// History generated at startup, in hours; 0 skips the backfill.
+    #[serde(alias = "backfill", deserialize_with = "backfill_hours")]
+    pub backfill_hours: u64,
+}
+
+impl Default for DemoConfig {
+    fn default() -> Self {
+        Self { stations: 5, interval_secs: 10, backfill_hours: 24 }
+    }
+}
+
+/// Whole hours from a number of hours or a duration such as `"2d"`.
+fn backfill_hours<'de, D: serde::Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    units::duration_in(d, "h")
+}
+
+impl DemoConfig {
+    /// `SKYPULSE_DEMO_STATIONS` enables demo mode with that many stations.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(v) = std::env::var("SKYPULSE_DEMO_STATIONS") {
+            let stations = v.parse().with_context(|| format!("SKYPULSE_DEMO_STATIONS '{}'", v))?;
+            cfg.get_or_insert_with(Self::default).stations = stations;
+        }
+        Ok(())
+    }
+}
+
+/// One virtual station's climate and the state of its random walks.
+struct Station {
+    id: String,
+    /// Daily mean temperature and half the day/night swing, in °C.
+    mean_temp: f64,
+    swing: f64,
+    pressure: f64,
+    wind: f64,
+    rng: u64,
+}
+
+impl Station {
+    fn new(i: usize, seed: u64) -> Self {
+        Self {
+            id: format!("DEMO{:03}", i + 1),
+            mean_temp: 12.0 + 4.0 * i as f64,
+            swing: 4.0 + (i % 3) as f64,
+            pressure: 1013.0 - 2.0 * i as f64,
+            wind: 3.0,
+            rng: seed ^ (i as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15),
+        }
+    }
+
+    /// Uniform in [-1, 1) (xorshift64*).
+    fn noise(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (r >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+
+    fn reading(&mut self, at: DateTime<Utc>) -> Observation {
+        // coldest around 05:00, warmest around 15:00 (UTC stands in for local time)
+        let hour = at.hour() as f64 + at.minute() as f64 / 60.0;
+        let phase = (hour - 9.0) / 24.0 * std::f64::consts::TAU;
+        let temp = self.mean_temp + self.swing * phase.sin() + 0.3 * self.noise();
+        let humidity = (70.0 - 2.5 * (temp - self.mean_temp) + 2.0 * self.noise()).clamp(5.0, 100.0);
+        self.pressure = (self.pressure + 0.05 * self.noise()).clamp(985.0, 1035.0);
+        self.wind = (self.wind + 0.4 * self.noise()).clamp(0.0, 20.0);
+        let gust = (self.wind + 1.5 * self.noise().abs()).max(0.0);
+        let wind_dir = ((self.noise() + 1.0) * 180.0) as u16 % 360;
+
+        let mut obs = Observation::empty(self.id.clone(), Timestamp(at.timestamp() * 1000));
+        obs.set_field("temp", round1(temp));
+        obs.set_field("humidity", round1(humidity));
+        obs.set_field("pressure", round1(self.pressure));
+        obs.set_field("wind_speed", round1(gust));
+        obs.set_field("wind_dir", wind_dir as f64);
+        obs
+    }
+}
+
+fn round1(v: f64) -> f64 {
+    (v * 10.0).round() / 10.0
+}
+
+fn stations(cfg: &DemoConfig) -> Vec<Station> {
+    // RandomState is randomly keyed per instance, which is plenty for a demo seed.
+    let seed = std::collections::hash_map::RandomState::new().build_hasher().finish() | 1;
+    (0..cfg.stations).map(|i| Station::new(i, seed)).collect()
+}
+
+/// Backfill history, then write a reading per station every interval until
+/// `shutdown` fires.
+pub async fn run(state: Arc<AppState>, cfg: DemoConfig, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let mut stations = stations(&cfg);
+    let now = Utc::now();
+    let minutes = cfg.backfill_hours as i64 * 60;
+    // an hour of history per write keeps batches reasonable
+    for start in (0..minutes).step_by(60) {
+        let mut batch = Vec::new();
+        for m in start..(start + 60).min(minutes) {
+            let at = now - chrono::Duration::minutes(minutes - m);
+            batch.extend(stations.iter_mut().map(|s| s.reading(at)));
+        }
+        if let Err(e) = super::write_observations(&state, batch).await {
+            tracing::error!("demo backfill failed: {:#}", e);
+            break;
+        }
+    }
+    if minutes > 0 {
+        tracing::info!("demo: backfilled {}h for {} stations", cfg.backfill_hours, stations.len());
+    }
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(cfg.interval_secs.max(1)));
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {
+                let at = Utc::now();
+                let batch: Vec<Observation> = stations.iter_mut().map(|s| s.reading(at)).collect();
+                if let Err(e) = super::write_observations(&state, batch).await {
+                    tracing::warn!("demo write failed: {:#}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readings_stay_plausible() {
+        let mut s = Station::new(0, 42);
+        let base = crate::query::parse_time("2025-07-01T00:00:00Z").unwrap();
+        let temps: Vec<f64> = (0..24)
+            .map(|h| {
+                let o = s.reading(base + chrono::Duration::hours(h));
+                assert_eq!(o.station_id, "DEMO001");
+                assert!((0.0..=100.0).contains(&o.number("humidity").unwrap()));
+                assert!(o.number("wind_dir").unwrap() < 360.0);
+                o.number("temp").unwrap()
+            })
+            .collect();
+        // the afternoon is warmer than the early morning
+        assert!(temps[15] > temps[5] + 4.0);
+    }
+}
@@ -0,0 +1,303 @@
+// File-drop ingest: legacy systems that can only write files to a shared
+// folder drop CSV or NDJSON files into a watched directory. Each file is
+// imported through the normal write path, recorded in a ledger, and moved to
+// the archive folder (or `archive/failed` when nothing could be imported).
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::units;
+use crate::AppState;
+
+pub const LEDGER_FILE: &str = "ledger.ndjson";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileDropConfig {
+    pub watch_dir: PathBuf,
+    /// Defaults to `<watch_dir>/archive`.
+    #[serde(default)]
+    pub archive_dir: Option<PathBuf>,
+    #[serde(alias = "poll", default = "default_poll_secs", deserialize_with = "units::secs")]
+    pub poll_secs: u64,
+    /// Files modified more recently than this are assumed to still be written.
+    #[serde(alias = "settle", default = "default_settle_secs", deserialize_with = "units::secs")]
+    pub settle_secs: u64,
+}
+
+fn default_poll_secs() -> u64 {
+    10
+}
+
+fn default_settle_secs() -> u64 {
+    2
+}
+
+impl FileDropConfig {
+    /// Enabled by `SKYPULSE_INGEST_DIR`; `SKYPULSE_INGEST_ARCHIVE_DIR` and
+    /// `SKYPULSE_INGEST_POLL_SECS` override the configured values.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(dir) = std::env::var("SKYPULSE_INGEST_DIR") {
+            let c = cfg.get_or_insert_with(|| Self {
+                watch_dir: PathBuf::new(),
+                archive_dir: None,
+                poll_secs: default_poll_secs(),
+                settle_secs: default_settle_secs(),
+            });
+            c.watch_dir = PathBuf::from(dir);
+        }
+        let Some(c) = cfg else { return Ok(()) };
+        if let Ok(dir) = std::env::var("SKYPULSE_INGEST_ARCHIVE_DIR") {
+            c.archive_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(poll) = units::env_secs("SKYPULSE_INGEST_POLL_SECS", "s")? {
+            c.poll_secs = poll;
+        }
+        Ok(())
+    }
+
+    pub fn archive_dir(&self) -> PathBuf {
+        self.archive_dir.clone().unwrap_or_else(|| self.watch_dir.join("archive"))
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_secs.max(1))
+    }
+
+    pub fn settle_time(&self) -> Duration {
+        Duration::from_secs(self.settle_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Csv,
+    Ndjson,
+}
+
+impl FileFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "csv" => Some(FileFormat::Csv),
+            "ndjson" | "jsonl" => Some(FileFormat::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+/// One ledger line per processed file.
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerEntry {
+    pub file: String,
+    pub processed_at: String,
+    /// "imported" or "failed".
+    pub status: &'static str,
+    pub accepted: usize,
+    pub rejected: usize,
+    /// First few row errors (or the file-level error), for diagnosis.
+    pub errors: Vec<String>,
+}
+
+const MAX_LEDGER_ERRORS: usize = 20;
+
+/// Parse a file body; returns the valid observations and one message per rejected row.
+pub fn parse(format: FileFormat, data: &[u8]) -> (Vec<Observation>, Vec<String>) {
+    match format {
+        FileFormat::Ndjson => parse_ndjson(data),
+        FileFormat::Csv => parse_csv(data),
+    }
+}
+
+pub fn parse_ndjson(data: &[u8]) -> (Vec<Observation>, Vec<String>) {
+    let mut obs = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in data.split(|b| *b == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        match serde_json::from_slice::<Observation>(line) {
+            Ok(o) => obs.push(o),
+            Err(e) => errors.push(format!("line {}: {}", i + 1, e)),
+        }
+    }
+    (obs, errors)
+}
+
+/// CSV with a header row of `station_id`, `time` and one column per numeric
+/// field (`station_id,time,temp,rain,...`). Empty cells are treated as
+/// missing values.
+pub fn parse_csv(data: &[u8]) -> (Vec<Observation>, Vec<String>) {
+    let mut obs = Vec::new();
+    let mut errors = Vec::new();
+    let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data);
+    let headers = match rdr.headers() {
+        Ok(h) => h.clone(),
+        Err(e) => return (obs, vec![format!("line 1: {}", e)]),
+    };
+    for (i, row) in rdr.records().enumerate() {
+        match row.map_err(anyhow::Error::from).and_then(|r| csv_row(&headers, &r)) {
+            // header is line 1
+            Ok(o) => obs.push(o),
+            Err(e) => errors.push(format!("line {}: {}", i + 2, e)),
+        }
+    }
+    (obs, errors)
+}
+
+fn csv_row(headers: &csv::StringRecord, row: &csv::StringRecord) -> Result<Observation> {
+    let cell = |name: &str| headers.iter().position(|h| h == name).and_then(|i| row.get(i)).filter(|c| !c.is_empty());
+    let station_id = cell("station_id").ok_or_else(|| anyhow!("missing station_id"))?;
+    let time = Timestamp::parse(cell("time").ok_or_else(|| anyhow!("missing time"))?)?;
+    let mut obs = Observation::empty(station_id, time);
+    for (name, value) in headers.iter().zip(row.iter()) {
+        if name == "station_id" || name == "time" || value.is_empty() {
+            continue;
+        }
+        match value.parse::<f64>() {
+            Ok(v) if v.is_finite() => obs.set_field(name, v),
+            _ => anyhow::bail!("{}: invalid number '{}'", name, value),
+        }
+    }
+    Ok(obs)
+}
+
+/// Import one file and return its ledger entry. Does not move the file.
+pub async fn import_file(state: &AppState, path: &Path) -> LedgerEntry {
+    let file = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut entry = LedgerEntry {
+        file,
+        processed_at: chrono::Utc::now().to_rfc3339(),
+        status: "failed",
+        accepted: 0,
+        rejected: 0,
+        errors: Vec::new(),
+    };
+    let Some(format) = FileFormat::from_path(path) else {
+        entry.errors.push("unsupported file extension".to_string());
+        return entry;
+    };
+    let data = match tokio::fs::read(path).await {
+        Ok(d) => d,
+        Err(e) => {
+            entry.errors.push(e.to_string());
+            return entry;
+        }
+    };
+    let (obs, mut errors) = parse(format, &data);
+    let (obs, rejected) = super::admit(state, obs, &super::routing::RouteMeta::default()).await;
+    errors.extend(rejected.into_iter().map(|(i, e)| format!("row {}: {}", i + 1, e)));
+    entry.rejected = errors.len();
+    entry.errors = errors.into_iter().take(MAX_LEDGER_ERRORS).collect();
+    match super::append(state, obs).await {
+        Ok(n) => entry.accepted = n,
+        Err(e) => entry.errors.push(format!("write failed: {}", e)),
+    }
+    if entry.accepted > 0 || (entry.rejected == 0 && entry.errors.is_empty()) {
+        entry.status = "imported";
+    }
+    entry
+}
+
+/// Scan the watch directory once and import every settled CSV/NDJSON file.
+pub async fn poll_once(state: &AppState, cfg: &FileDropConfig) -> Result<Vec<LedgerEntry>> {
+    let archive_dir = cfg.archive_dir();
+    let failed_dir = archive_dir.join("failed");
+    tokio::fs::create_dir_all(&failed_dir).await?;
+
+    let mut ready = Vec::new();
+    let mut rd = tokio::fs::read_dir(&cfg.watch_dir).await?;
+    while let Some(entry) = rd.next_entry().await? {
+        let path = entry.path();
+        let meta = entry.metadata().await?;
+        if !meta.is_file() || FileFormat::from_path(&path).is_none() {
+            continue;
+        }
+        let age = meta.modified().ok().and_then(|m| SystemTime::now().duration_since(m).ok());
+        if age.is_some_and(|a| a >= cfg.settle_time()) {
+            ready.push(path);
+        }
+    }
+    ready.sort();
+
+    let mut entries = Vec::new();
+    for path in ready {
+        let entry = import_file(state, &path).await;
+        let dest_dir = if entry.status == "imported" { &archive_dir } else { &failed_dir };
+        // prefix with a timestamp so re-dropped files with the same name don't collide
+        let dest = dest_dir.join(format!("{}-{}", chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"), entry.file));
+        tokio::fs::rename(&path, &dest).await?;
+        append_ledger(&archive_dir, &entry).await?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+async fn append_ledger(archive_dir: &Path, entry: &LedgerEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(archive_dir.join(LEDGER_FILE))
+        .await?;
+    file.write_all(&line).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Background watcher loop; stops when `shutdown` fires.
+pub async fn run(state: Arc<AppState>, cfg: FileDropConfig, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let mut ticker = tokio::time::interval(cfg.poll_interval());
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {
+                match poll_once(&state, &cfg).await {
+                    Ok(entries) => {
+                        for e in entries {
+                            tracing::info!(file = %e.file, status = e.status, accepted = e.accepted, rejected = e.rejected, "file-drop processed");
+                        }
+                    }
+                    Err(e) => tracing::error!("file-drop error: {}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_csv_with_empty_cells() {
+        let data = b"station_id,time,temp,humidity,wind_dir,rain\nA,2025-01-01T00:00:00Z,12.5,,180,0.4\nB,2025-01-01T00:00:00Z,abc,50,,\n";
+        let (obs, errors) = parse_csv(data);
+        assert_eq!(obs.len(), 1);
+        assert_eq!(obs[0].number("temp"), Some(12.5));
+        assert!(!obs[0].fields.contains_key("humidity"));
+        assert_eq!(obs[0].number("wind_dir"), Some(180.0));
+        assert_eq!(obs[0].number("rain"), Some(0.4));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("line 3"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn parses_ndjson_and_reports_bad_lines() {
+        let data = b"{\"station_id\":\"A\",\"time\":\"2025-01-01T00:00:00Z\",\"temp\":1.0}\n\nnot json\n";
+        let (obs, errors) = parse_ndjson(data);
+        assert_eq!(obs.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("line 3"));
+    }
+
+    #[test]
+    fn detects_format_from_extension() {
+        assert_eq!(FileFormat::from_path(Path::new("a/b.CSV")), Some(FileFormat::Csv));
+        assert_eq!(FileFormat::from_path(Path::new("b.jsonl")), Some(FileFormat::Ndjson));
+        assert_eq!(FileFormat::from_path(Path::new("b.txt")), None);
+    }
+}
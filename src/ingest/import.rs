@@ -0,0 +1,385 @@
+// Bulk CSV import of historical station logs, behind `POST /api/v1/import/csv`
+// and the `import` subcommand. The header row names the columns; a mapping
+// says which hold the station and the time, renames the others to fields and
+// marks some as tags or skips them. The body is parsed as it arrives and
+// written through routing, validation and the WAL in batches, so a file of
+// several years never has to fit in memory. Rows that cannot be used are
+// reported by line and do not stop the import.
+
+use std::collections::BTreeMap;
+use anyhow::{anyhow, bail, Result};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+use crate::ingest::routing::RouteMeta;
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::AppState;
+
+/// Rows appended to the WAL together.
+const IMPORT_BATCH: usize = 5000;
+/// Row errors listed in the report; the rest are only counted.
+const MAX_ERRORS: usize = 100;
+/// Longest record held while waiting for its end; past it the body has no
+/// newline or a quote left open, and the import stops.
+const MAX_RECORD_BYTES: usize = 1024 * 1024;
+
+/// How a CSV's columns map onto observations.
+#[derive(Debug, Clone, Default)]
+pub struct CsvMapping {
+    /// Station of rows without a station column or with an empty cell in it.
+    pub station_id: Option<String>,
+    /// `station_id` when unset.
+    pub station_column: Option<String>,
+    /// `time` when unset.
+    pub time_column: Option<String>,
+    /// chrono format of the time column, read as UTC, or `unix` / `unix_ms`;
+    /// RFC3339 when unset.
+    pub time_format: Option<String>,
+    /// Column -> field name, for columns not named like their field.
+    pub fields: BTreeMap<String, String>,
+    /// Columns stored as tags instead of fields.
+    pub tags: Vec<String>,
+    /// Columns left out.
+    pub skip: Vec<String>,
+    /// `,` when unset.
+    pub delimiter: Option<u8>,
+}
+
+/// Why an import stopped; `Invalid` is the caller's fault. Rows written
+/// before it stay written.
+#[derive(Debug)]
+pub enum ImportError {
+    Invalid(String),
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ImportError {
+    fn from(e: anyhow::Error) -> Self {
+        ImportError::Failed(e)
+    }
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Invalid(msg) => f.write_str(msg),
+            ImportError::Failed(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// A delimiter given as one character, or `tab`.
+pub fn parse_delimiter(s: &str) -> Result<u8> {
+    match s {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => bail!("delimiter must be a single ASCII character or 'tab', got '{}'", s),
+    }
+}
+
+impl CsvMapping {
+    /// Parse `column:field` pairs separated by commas.
+    pub fn parse_fields(s: &str) -> Result<BTreeMap<String, String>> {
+        let mut fields = BTreeMap::new();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (column, field) = pair.split_once(':').ok_or_else(|| anyhow!("field mapping '{}' must be column:field", pair))?;
+            fields.insert(column.trim().to_string(), field.trim().to_string());
+        }
+        Ok(fields)
+    }
+
+    fn parse_time(&self, cell: &str) -> Result<Timestamp> {
+        match self.time_format.as_deref() {
+            None => Timestamp::parse(cell),
+            Some("unix") => Ok(Timestamp(cell.parse::<i64>().map_err(|_| anyhow!("invalid unix time '{}'", cell))?.saturating_mul(1000))),
+            Some("unix_ms") => Ok(Timestamp(cell.parse().map_err(|_| anyhow!("invalid unix_ms time '{}'", cell))?)),
+            Some(format) => {
+                let t = NaiveDateTime::parse_from_str(cell, format).map_err(|e| anyhow!("invalid time '{}' for format '{}': {}", cell, format, e))?;
+                Ok(Timestamp::from_datetime(Utc.from_utc_datetime(&t)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RowError {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// The first rejected rows with their reasons.
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    fn reject(&mut self, line: usize, error: String) {
+        self.rejected += 1;
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(RowError { line, error });
+        }
+    }
+}
+
+/// What each header column becomes.
+#[derive(Debug, Clone, PartialEq)]
+enum Column {
+    Station,
+    Time,
+    Field(String),
+    Tag(String),
+    Skip,
+}
+
+/// An import in progress: feed it the body as it arrives, then finish it.
+pub struct CsvImport<'a> {
+    state: &'a AppState,
+    mapping: CsvMapping,
+    token: Option<String>,
+    columns: Option<Vec<Column>>,
+    /// Bytes of a record not yet complete.
+    pending: Vec<u8>,
+    /// How much of `pending` has been scanned for a record end, and whether
+    /// that stopped inside quotes.
+    scanned: usize,
+    quoted: bool,
+    /// Line number of the first byte in `pending`.
+    line: usize,
+    rows: Vec<(usize, Observation)>,
+    report: ImportReport,
+}
+
+impl<'a> CsvImport<'a> {
+    /// `token` is the API key routing sees, as for HTTP writes.
+    pub fn new(state: &'a AppState, mapping: CsvMapping, token: Option<String>) -> Self {
+        Self {
+            state,
+            mapping,
+            token,
+            columns: None,
+            pending: Vec::new(),
+            scanned: 0,
+            quoted: false,
+            line: 1,
+            rows: Vec::new(),
+            report: ImportReport::default(),
+        }
+    }
+
+    /// Parse the complete records in `chunk` and write them once a batch is full.
+    pub async fn feed(&mut self, chunk: &[u8]) -> Result<(), ImportError> {
+        self.pending.extend_from_slice(chunk);
+        // a newline inside quotes belongs to the record
+        let mut end = None;
+        for (i, b) in self.pending.iter().enumerate().skip(self.scanned) {
+            match b {
+                b'"' => self.quoted = !self.quoted,
+                b'\n' if !self.quoted => end = Some(i + 1),
+                _ => {}
+            }
+        }
+        if let Some(end) = end {
+            let complete: Vec<u8> = self.pending.drain(..end).collect();
+            self.parse(&complete)?;
+        }
+        self.scanned = self.pending.len();
+        if self.pending.len() > MAX_RECORD_BYTES {
+            return Err(ImportError::Invalid(format!(
+                "the record from line {} runs past {} bytes without ending; is a quote left open?",
+                self.line, MAX_RECORD_BYTES
+            )));
+        }
+        if self.rows.len() >= IMPORT_BATCH {
+            self.write().await?;
+        }
+        Ok(())
+    }
+
+    /// Parse what is left and write the last batch.
+    pub async fn finish(mut self) -> Result<ImportReport, ImportError> {
+        let rest = std::mem::take(&mut self.pending);
+        if !rest.iter().all(u8::is_ascii_whitespace) {
+            self.parse(&rest)?;
+        }
+        if self.columns.is_none() {
+            return Err(ImportError::Invalid("the CSV has no header row".to_string()));
+        }
+        self.write().await?;
+        Ok(self.report)
+    }
+
+    fn parse(&mut self, data: &[u8]) -> Result<(), ImportError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .delimiter(self.mapping.delimiter.unwrap_or(b','))
+            .from_reader(data);
+        let first_line = self.line;
+        for record in rdr.records() {
+            let record = match record {
+                Ok(r) => r,
+                Err(e) => {
+                    let line = first_line + e.position().map_or(0, |p| p.line() as usize - 1);
+                    self.report.reject(line, e.to_string());
+                    continue;
+                }
+            };
+            let line = first_line + record.position().map_or(0, |p| p.line() as usize - 1);
+            let Some(columns) = &self.columns else {
+                let header = record.iter().map(|h| h.trim_start_matches('\u{feff}'));
+                self.columns = Some(self.header(header.collect()).map_err(|e| ImportError::Invalid(e.to_string()))?);
+                continue;
+            };
+            if record.iter().all(str::is_empty) {
+                continue;
+            }
+            match self.row(columns, &record) {
+                Ok(o) => self.rows.push((line, o)),
+                Err(e) => self.report.reject(line, format!("{:#}", e)),
+            }
+        }
+        self.line += data.iter().filter(|b| **b == b'\n').count();
+        Ok(())
+    }
+
+    fn header(&self, names: Vec<&str>) -> Result<Vec<Column>> {
+        let m = &self.mapping;
+        let station = m.station_column.as_deref().unwrap_or("station_id");
+        let time = m.time_column.as_deref().unwrap_or("time");
+        let columns: Vec<Column> = names
+            .iter()
+            .map(|&name| match name {
+                _ if name == station => Column::Station,
+                _ if name == time => Column::Time,
+                _ if m.skip.iter().any(|s| s == name) => Column::Skip,
+                _ if m.tags.iter().any(|t| t == name) => Column::Tag(name.to_string()),
+                _ => Column::Field(m.fields.get(name).cloned().unwrap_or_else(|| name.to_string())),
+            })
+            .collect();
+        if !columns.contains(&Column::Time) {
+            bail!("the header has no '{}' column", time);
+        }
+        if !columns.contains(&Column::Station) && m.station_id.is_none() {
+            bail!("the header has no '{}' column and no station_id was given", station);
+        }
+        Ok(columns)
+    }
+
+    fn row(&self, columns: &[Column], record: &csv::StringRecord) -> Result<Observation> {
+        let mut station = self.mapping.station_id.clone();
+        let mut time = None;
+        let mut tags = BTreeMap::new();
+        let mut fields = Vec::new();
+        for (column, cell) in columns.iter().zip(record.iter()) {
+            if cell.is_empty() {
+                continue;
+            }
+            match column {
+                Column::Station => station = Some(cell.to_string()),
+                Column::Time => time = Some(self.mapping.parse_time(cell)?),
+                Column::Tag(name) => {
+                    tags.insert(name.clone(), cell.to_string());
+                }
+                Column::Field(name) => match cell.parse::<f64>() {
+                    Ok(v) if v.is_finite() => fields.push((name, v)),
+                    _ => bail!("{}: invalid number '{}'", name, cell),
+                },
+                Column::Skip => {}
+            }
+        }
+        let station = station.ok_or_else(|| anyhow!("missing station_id"))?;
+        let mut obs = Observation::empty(station, time.ok_or_else(|| anyhow!("missing time"))?);
+        obs.tags = tags;
+        for (name, v) in fields {
+            obs.set_field(name.as_str(), v);
+        }
+        Ok(obs)
+    }
+
+    async fn write(&mut self) -> Result<(), ImportError> {
+        let mut admitted = Vec::with_capacity(self.rows.len());
+        for (line, obs) in std::mem::take(&mut self.rows) {
+            let meta = RouteMeta { token: self.token.clone(), tags: obs.tags.clone() };
+            let (ok, rejected) = super::admit(self.state, vec![obs], &meta).await;
+            admitted.extend(ok);
+            for (_, reason) in rejected {
+                self.report.reject(line, reason);
+            }
+        }
+        if !admitted.is_empty() {
+            self.report.accepted += super::append(self.state, admitted).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn maps_columns_and_reports_bad_rows() {
+        let dir = crate::test_util::TempDir::new("import");
+        let state = AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap();
+        let mapping = CsvMapping {
+            station_id: Some("HK001".into()),
+            time_column: Some("Date".into()),
+            time_format: Some("%Y-%m-%d %H:%M".into()),
+            fields: CsvMapping::parse_fields("Temperature (C):temp, RH:humidity").unwrap(),
+            tags: vec!["Sensor".into()],
+            skip: vec!["Notes".into()],
+            ..Default::default()
+        };
+        let body = "\u{feff}Date,Temperature (C),RH,Sensor,Notes\n\
+                    2024-07-01 00:00,28.5,81,probe-1,\"hot,\n humid\"\n\
+                    2024-07-01 00:10,n/a,80,probe-1,\n\
+                    yesterday,28.1,80,,\n\
+                    \n\
+                    2024-07-01 00:20,28.0,,probe-2,";
+        let mut import = CsvImport::new(&state, mapping, None);
+        // split mid-record and inside the quoted newline
+        for chunk in body.as_bytes().chunks(17) {
+            import.feed(chunk).await.unwrap();
+        }
+        let report = import.finish().await.unwrap();
+        assert_eq!((report.accepted, report.rejected), (2, 2));
+        let lines: Vec<usize> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [4, 5]);
+        assert!(report.errors[0].error.contains("temp: invalid number 'n/a'"));
+
+        let mt = state.memtable.lock().await;
+        let rows = &mt.buffer["HK001"];
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].number("temp"), rows[0].number("humidity")), (Some(28.5), Some(81.0)));
+        assert_eq!(rows[0].tags["Sensor"], "probe-1");
+        assert!(!rows[0].fields.contains_key("Notes"));
+        assert_eq!(rows[1].time, "2024-07-01T00:20:00Z".parse().unwrap());
+        drop(mt);
+
+        let import = CsvImport::new(&state, CsvMapping::default(), None);
+        assert!(import.finish().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_record_that_never_ends() {
+        let dir = crate::test_util::TempDir::new("import-unended");
+        let state = AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap();
+        let mut import = CsvImport::new(&state, CsvMapping::default(), None);
+        import.feed(b"station_id,time,temp\nA,2025-01-01T00:00:00Z,\"1\n").await.unwrap();
+        let chunk = vec![b'9'; 64 * 1024];
+        let mut fed = 0;
+        let err = loop {
+            match import.feed(&chunk).await {
+                Ok(()) => fed += chunk.len(),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(err, ImportError::Invalid(ref msg) if msg.contains("line 2")), "{}", err);
+        assert!(fed <= MAX_RECORD_BYTES);
+    }
+}
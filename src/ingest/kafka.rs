@@ -0,0 +1,314 @@
+// Kafka ingest: consume observation records from Kafka topics as a member of
+// a consumer group, so station fleets already routed through Kafka need no
+// extra bridge. Each message holds what `/api/v1/write/batch` accepts (one
+// JSON write, an array, NDJSON or a shared-metadata batch). Messages are
+// appended to the WAL in batches and their offsets committed only once the
+// append is synced to disk, whatever the WAL's durability policy; a crash
+// before that redelivers them, and a redelivered point simply overwrites
+// itself. Needs the `kafka` cargo feature.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use crate::storage::memtable::Observation;
+use crate::units;
+use crate::AppState;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaConfig {
+    /// Bootstrap servers as `host:port`.
+    pub brokers: Vec<String>,
+    pub topics: Vec<String>,
+    /// Consumer group; its committed offsets are where a restart resumes.
+    #[serde(default = "default_group")]
+    pub group: String,
+    /// Messages appended to the WAL together, and committed together.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// How long a batch waits for more messages once one has arrived.
+    #[serde(alias = "linger", default = "default_linger_ms", deserialize_with = "units::millis")]
+    pub linger_ms: u64,
+    /// Further librdkafka settings, such as `security.protocol` or
+    /// `sasl.username`; they override the ones above.
+    #[serde(default, serialize_with = "redacted_options")]
+    pub options: BTreeMap<String, String>,
+}
+
+fn default_group() -> String {
+    "skypulsedb".to_string()
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_linger_ms() -> u64 {
+    100
+}
+
+/// Options with their passwords and secrets shown as `***`.
+fn redacted_options<S: serde::Serializer>(options: &BTreeMap<String, String>, s: S) -> Result<S::Ok, S::Error> {
+    let secret = |k: &str| k.contains("password") || k.contains("secret");
+    s.collect_map(options.iter().map(|(k, v)| (k, if secret(k) { "***" } else { v.as_str() })))
+}
+
+impl KafkaConfig {
+    /// Enabled by `SKYPULSE_KAFKA_BROKERS`; `SKYPULSE_KAFKA_TOPICS` and
+    /// `SKYPULSE_KAFKA_GROUP` override the configured values. Lists are
+    /// comma-separated.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        let list = |v: String| v.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect();
+        if let Ok(brokers) = std::env::var("SKYPULSE_KAFKA_BROKERS") {
+            let c = cfg.get_or_insert_with(|| Self {
+                brokers: Vec::new(),
+                topics: Vec::new(),
+                group: default_group(),
+                batch_size: default_batch_size(),
+                linger_ms: default_linger_ms(),
+                options: BTreeMap::new(),
+            });
+            c.brokers = list(brokers);
+        }
+        let Some(c) = cfg else { return Ok(()) };
+        if let Ok(topics) = std::env::var("SKYPULSE_KAFKA_TOPICS") {
+            c.topics = list(topics);
+        }
+        if let Ok(group) = std::env::var("SKYPULSE_KAFKA_GROUP") {
+            c.group = group;
+        }
+        c.validate()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.brokers.is_empty() {
+            bail!("kafka.brokers must name at least one broker");
+        }
+        if self.topics.is_empty() {
+            bail!("kafka.topics must name at least one topic");
+        }
+        if self.group.trim().is_empty() {
+            bail!("kafka.group must not be empty");
+        }
+        if self.batch_size == 0 {
+            bail!("kafka.batch_size must be at least 1");
+        }
+        Ok(())
+    }
+
+    pub fn linger(&self) -> Duration {
+        Duration::from_millis(self.linger_ms)
+    }
+}
+
+/// The observations in one message, and why any item in it was unusable.
+pub fn decode(payload: &[u8]) -> (Vec<Observation>, Vec<String>) {
+    match crate::api::http::parse_batch(payload) {
+        Ok(items) => {
+            let mut obs = Vec::with_capacity(items.len());
+            let mut errors = Vec::new();
+            for (n, item) in items {
+                match item {
+                    Ok(req) => obs.push(req.to_observation()),
+                    Err(e) => errors.push(format!("item {}: {}", n, e)),
+                }
+            }
+            (obs, errors)
+        }
+        Err(e) => (Vec::new(), vec![e]),
+    }
+}
+
+/// Messages read for one append: their observations, why any item was
+/// unusable, and the next offset to commit per topic and partition.
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub obs: Vec<Observation>,
+    pub errors: Vec<String>,
+    pub offsets: BTreeMap<(String, i32), i64>,
+    pub messages: usize,
+}
+
+impl Batch {
+    pub fn add(&mut self, topic: &str, partition: i32, offset: i64, payload: &[u8]) {
+        self.messages += 1;
+        let next = self.offsets.entry((topic.to_string(), partition)).or_default();
+        *next = (*next).max(offset + 1);
+        let (decoded, errors) = decode(payload);
+        self.obs.extend(decoded);
+        self.errors.extend(errors.into_iter().map(|e| format!("{}/{}@{}: {}", topic, partition, offset, e)));
+    }
+}
+
+/// Append `obs` and sync the WAL, so offsets committed afterwards never
+/// cover rows a power failure could still lose.
+pub async fn append_durably(state: &AppState, obs: Vec<Observation>) -> Result<usize> {
+    let n = crate::ingest::append(state, obs).await?;
+    if let Some(wal) = &state.wal {
+        wal.sync().await?;
+    }
+    Ok(n)
+}
+
+#[cfg(feature = "kafka")]
+pub use consumer::{consumer, run, Consumer};
+
+#[cfg(feature = "kafka")]
+mod consumer {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use anyhow::Result;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::consumer::{CommitMode, Consumer as _, StreamConsumer};
+    use rdkafka::{Message, Offset, TopicPartitionList};
+    use super::{Batch, KafkaConfig};
+    use crate::AppState;
+
+    /// A consumer subscribed to the configured topics.
+    pub struct Consumer(StreamConsumer);
+
+    pub fn consumer(cfg: &KafkaConfig) -> Result<Consumer> {
+        let mut client = ClientConfig::new();
+        client
+            .set("bootstrap.servers", cfg.brokers.join(","))
+            .set("group.id", &cfg.group)
+            .set("enable.auto.commit", "false")
+            // a new group starts with what the topics still hold
+            .set("auto.offset.reset", "earliest");
+        for (k, v) in &cfg.options {
+            client.set(k, v);
+        }
+        let consumer: StreamConsumer = client.create()?;
+        consumer.subscribe(&cfg.topics.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Ok(Consumer(consumer))
+    }
+
+    /// Consume until `shutdown` fires. A batch whose append fails is retried
+    /// with backoff and nothing further is read until it is written.
+    pub async fn run(state: Arc<AppState>, consumer: Consumer, cfg: KafkaConfig, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+        let Consumer(consumer) = consumer;
+        loop {
+            let mut batch = Batch::default();
+            let linger = tokio::time::sleep(cfg.linger());
+            tokio::pin!(linger);
+            while batch.messages < cfg.batch_size {
+                let received = tokio::select! {
+                    _ = shutdown.recv() => return,
+                    _ = &mut linger, if batch.messages > 0 => break,
+                    m = consumer.recv() => m,
+                };
+                let m = match received {
+                    Ok(m) => m,
+                    Err(e) => {
+                        tracing::warn!("kafka: {}", e);
+                        continue;
+                    }
+                };
+                if batch.messages == 0 {
+                    linger.as_mut().reset(tokio::time::Instant::now() + cfg.linger());
+                }
+                batch.add(m.topic(), m.partition(), m.offset(), m.payload().unwrap_or_default());
+            }
+
+            let Batch { obs, errors, offsets, .. } = batch;
+            let (obs, rejected) = crate::ingest::admit(&state, obs, &crate::ingest::routing::RouteMeta::default()).await;
+            state.metrics.writes_rejected.inc_by(errors.len() as u64);
+            if let Some(first) = errors.first().or(rejected.first().map(|(_, e)| e)) {
+                tracing::warn!("kafka: skipped {} unusable records, first: {}", errors.len() + rejected.len(), first);
+            }
+            let mut backoff = Duration::from_secs(1);
+            while !obs.is_empty() {
+                match super::append_durably(&state, obs.clone()).await {
+                    Ok(_) => break,
+                    Err(e) => {
+                        tracing::error!("kafka: appending {} observations: {:#}; retrying in {:?}", obs.len(), e, backoff);
+                        tokio::select! {
+                            _ = shutdown.recv() => return,
+                            _ = tokio::time::sleep(backoff) => {}
+                        }
+                        backoff = (backoff * 2).min(Duration::from_secs(30));
+                    }
+                }
+            }
+
+            let mut tpl = TopicPartitionList::new();
+            for ((topic, partition), next) in offsets {
+                if let Err(e) = tpl.add_partition_offset(&topic, partition, Offset::Offset(next)) {
+                    tracing::warn!("kafka: {}", e);
+                }
+            }
+            if let Err(e) = consumer.commit(&tpl, CommitMode::Async) {
+                tracing::warn!("kafka: committing offsets: {}", e);
+            }
+        }
+    }
+}
+
+/// Stands in for the consumer in builds without the `kafka` feature.
+#[cfg(not(feature = "kafka"))]
+pub enum Consumer {}
+
+#[cfg(not(feature = "kafka"))]
+pub fn consumer(_: &KafkaConfig) -> Result<Consumer> {
+    bail!("this build has no Kafka support; rebuild with `--features kafka`")
+}
+
+#[cfg(not(feature = "kafka"))]
+pub async fn run(_: std::sync::Arc<crate::AppState>, consumer: Consumer, _: KafkaConfig, _: tokio::sync::broadcast::Receiver<()>) {
+    match consumer {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_messages_and_redacts_secrets() {
+        let (obs, errors) = decode(b"{\"station_id\":\"A\",\"time\":\"2025-01-01T00:00:00Z\",\"temp\":1.5}\n{\"station_id\":\"B\"}\n");
+        assert_eq!((obs.len(), obs[0].number("temp")), (1, Some(1.5)));
+        assert!(errors[0].starts_with("item 2: "));
+        let (obs, _) = decode(br#"{"station_id": "C", "readings": [["2025-01-01T00:00:00Z", {"rain": 0.2}], ["2025-01-01T00:01:00Z", {}]]}"#);
+        assert_eq!(obs.len(), 2);
+        assert_eq!(decode(b"[1").1.len(), 1);
+
+        let cfg: KafkaConfig = toml::from_str(
+            r#"
+            brokers = ["kafka-1:9092"]
+            topics = ["observations"]
+            linger = "250ms"
+            options = { "sasl.username" = "skypulse", "sasl.password" = "hunter2" }
+            "#,
+        )
+        .unwrap();
+        cfg.validate().unwrap();
+        assert_eq!((cfg.group.as_str(), cfg.batch_size, cfg.linger()), ("skypulsedb", 500, Duration::from_millis(250)));
+        let shown = serde_json::to_value(&cfg).unwrap();
+        assert_eq!(shown["options"], serde_json::json!({"sasl.password": "***", "sasl.username": "skypulse"}));
+        assert!(KafkaConfig { topics: Vec::new(), ..cfg }.validate().is_err());
+    }
+
+    #[test]
+    fn batches_track_the_next_offset_per_partition() {
+        let mut batch = Batch::default();
+        batch.add("observations", 0, 41, br#"{"station_id":"A","time":"2025-01-01T00:00:00Z","temp":1.5}"#);
+        batch.add("observations", 1, 7, b"[1");
+        batch.add("observations", 0, 40, br#"{"station_id":"A","time":"2025-01-01T00:01:00Z"}"#);
+        assert_eq!((batch.messages, batch.obs.len()), (3, 2));
+        assert_eq!(batch.offsets, BTreeMap::from([(("observations".to_string(), 0), 42), (("observations".to_string(), 1), 8)]));
+        assert!(batch.errors[0].starts_with("observations/1@7: "));
+    }
+
+    #[tokio::test]
+    async fn durable_appends_survive_a_reopen() {
+        let dir = crate::test_util::TempDir::new("kafka");
+        let mut cfg = crate::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        cfg.wal.durability = crate::storage::wal::Durability::Never;
+        let state = AppState::open(&cfg).await.unwrap();
+        let (obs, _) = decode(br#"{"station_id":"A","time":"2025-01-01T00:00:00Z","temp":1.5}"#);
+        assert_eq!(append_durably(&state, obs).await.unwrap(), 1);
+        drop(state);
+        let state = AppState::open(&cfg).await.unwrap();
+        assert_eq!(state.memtable.lock().await.buffer["A"][0].number("temp"), Some(1.5));
+    }
+}
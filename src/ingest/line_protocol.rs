@@ -0,0 +1,250 @@
+// InfluxDB line protocol, so Telegraf and existing gateways can write without
+// custom code:
+//
+//     weather,station_id=HKO01,network=hko temp=21.5,humidity=63i 1735689600000000000
+//
+// The station comes from the `station_id` (or `station`) tag; every field is
+// stored under its own name and the remaining tags are kept on the
+// observation, where tenant routing sees them. Points for the same station and timestamp
+// (for example separate measurements) are merged with `merge_by_time` before
+// writing.
+
+use std::collections::BTreeMap;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use crate::storage::memtable::{self, Observation, Timestamp};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    Str(String),
+}
+
+impl FieldValue {
+    fn to_stored(&self) -> memtable::FieldValue {
+        match self {
+            FieldValue::Float(v) => memtable::FieldValue::Number(*v),
+            FieldValue::Int(v) => memtable::FieldValue::Number(*v as f64),
+            FieldValue::UInt(v) => memtable::FieldValue::Number(*v as f64),
+            FieldValue::Bool(v) => memtable::FieldValue::Bool(*v),
+            FieldValue::Str(v) => memtable::FieldValue::Text(v.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, FieldValue>,
+    /// In the request's precision; `None` means "now".
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    #[default]
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Precision {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "ns" | "n" => Ok(Precision::Ns),
+            "us" | "u" => Ok(Precision::Us),
+            "ms" => Ok(Precision::Ms),
+            "s" => Ok(Precision::S),
+            other => bail!("unknown precision '{}', expected ns, us, ms or s", other),
+        }
+    }
+
+    fn to_time(self, ts: i64) -> Option<DateTime<Utc>> {
+        match self {
+            Precision::Ns => Some(DateTime::from_timestamp_nanos(ts)),
+            Precision::Us => DateTime::from_timestamp_micros(ts),
+            Precision::Ms => DateTime::from_timestamp_millis(ts),
+            Precision::S => DateTime::from_timestamp(ts, 0),
+        }
+    }
+}
+
+/// Split on `sep` outside backslash escapes (and, if `quotes`, outside double quotes).
+fn split_unescaped(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut in_quotes = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if quotes && c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&n)) if matches!(n, ',' | '=' | ' ' | '"' | '\\') => {
+                out.push(n);
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn parse_field_value(raw: &str) -> Result<FieldValue> {
+    if let Some(inner) = raw.strip_prefix('"').and_then(|r| r.strip_suffix('"')) {
+        return Ok(FieldValue::Str(unescape(inner)));
+    }
+    if let Some(n) = raw.strip_suffix('i') {
+        return Ok(FieldValue::Int(n.parse().map_err(|_| anyhow!("invalid integer '{}'", raw))?));
+    }
+    if let Some(n) = raw.strip_suffix('u') {
+        return Ok(FieldValue::UInt(n.parse().map_err(|_| anyhow!("invalid unsigned integer '{}'", raw))?));
+    }
+    match raw {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(FieldValue::Bool(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(FieldValue::Bool(false)),
+        _ => {}
+    }
+    match raw.parse::<f64>() {
+        Ok(v) if v.is_finite() => Ok(FieldValue::Float(v)),
+        _ => bail!("invalid field value '{}'", raw),
+    }
+}
+
+pub fn parse_line(line: &str) -> Result<Point> {
+    let sections = split_unescaped(line.trim(), ' ', true);
+    let sections: Vec<&str> = sections.into_iter().filter(|s| !s.is_empty()).collect();
+    let (key, fields, ts) = match sections.as_slice() {
+        [key, fields] => (*key, *fields, None),
+        [key, fields, ts] => (*key, *fields, Some(*ts)),
+        _ => bail!("expected '<measurement>[,tags] <fields> [timestamp]'"),
+    };
+
+    let mut key_parts = split_unescaped(key, ',', false).into_iter();
+    let measurement = unescape(key_parts.next().unwrap_or_default());
+    if measurement.is_empty() {
+        bail!("missing measurement");
+    }
+    let mut tags = BTreeMap::new();
+    for tag in key_parts {
+        match split_unescaped(tag, '=', false).as_slice() {
+            [k, v] if !k.is_empty() && !v.is_empty() => {
+                tags.insert(unescape(k), unescape(v));
+            }
+            _ => bail!("invalid tag '{}'", tag),
+        }
+    }
+
+    let mut field_map = BTreeMap::new();
+    for field in split_unescaped(fields, ',', true) {
+        let Some((k, v)) = field.split_once('=') else { bail!("invalid field '{}'", field) };
+        if k.is_empty() {
+            bail!("invalid field '{}'", field);
+        }
+        field_map.insert(unescape(k), parse_field_value(v)?);
+    }
+
+    let timestamp = ts.map(|t| t.parse::<i64>().map_err(|_| anyhow!("invalid timestamp '{}'", t))).transpose()?;
+    Ok(Point { measurement, tags, fields: field_map, timestamp })
+}
+
+/// Parse a request body; returns `(line number, result)` for every non-blank,
+/// non-comment line.
+pub fn parse(body: &str) -> Vec<(usize, Result<Point>)> {
+    body.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+        .map(|(i, l)| (i + 1, parse_line(l)))
+        .collect()
+}
+
+/// Map a point onto an observation carrying every tag but the station tag.
+pub fn to_observation(point: &Point, precision: Precision, now: DateTime<Utc>) -> Result<Observation> {
+    let mut tags = point.tags.clone();
+    let station_id = tags
+        .remove("station_id")
+        .or_else(|| tags.remove("station"))
+        .ok_or_else(|| anyhow!("missing station_id tag"))?;
+    let time = match point.timestamp {
+        Some(ts) => precision.to_time(ts).ok_or_else(|| anyhow!("timestamp out of range"))?,
+        None => now,
+    };
+    let mut obs = Observation::empty(station_id, Timestamp::from_datetime(time));
+    obs.tags = tags;
+    for (name, value) in &point.fields {
+        obs.set(name.as_str(), value.to_stored());
+    }
+    Ok(obs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tags_fields_and_escapes() {
+        let p = parse_line(r#"weather\ obs,station_id=HKO01,site=Kai\ Tak temp=21.5,humidity=63i,ok=t,note="a, b" 1735689600000000000"#).unwrap();
+        assert_eq!(p.measurement, "weather obs");
+        assert_eq!(p.tags["site"], "Kai Tak");
+        assert_eq!(p.fields["temp"], FieldValue::Float(21.5));
+        assert_eq!(p.fields["humidity"], FieldValue::Int(63));
+        assert_eq!(p.fields["ok"], FieldValue::Bool(true));
+        assert_eq!(p.fields["note"], FieldValue::Str("a, b".into()));
+        assert_eq!(p.timestamp, Some(1_735_689_600_000_000_000));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_line("weather").is_err());
+        assert!(parse_line("weather,station_id temp=1").is_err());
+        assert!(parse_line("weather temp=abc").is_err());
+        assert!(parse_line("weather temp=1 notatime").is_err());
+        let lines = parse("# comment\n\nweather,station_id=A temp=1\nbad\n");
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].0, 4);
+        assert!(lines[1].1.is_err());
+    }
+
+    #[test]
+    fn maps_and_merges_observations() {
+        let now = Utc::now();
+        let a = parse_line("temperature,station_id=A,network=hko temp=20.5 1735689600").unwrap();
+        let b = parse_line("humidity,station=A rh=1,humidity=70i 1735689600").unwrap();
+        let a = to_observation(&a, Precision::S, now).unwrap();
+        let b = to_observation(&b, Precision::S, now).unwrap();
+        assert_eq!(a.time.to_string(), "2025-01-01T00:00:00Z");
+        assert_eq!(a.tags["network"], "hko");
+        let merged = super::super::merge_by_time(vec![a, b]);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].number("temp"), Some(20.5));
+        assert_eq!(merged[0].number("humidity"), Some(70.0));
+        assert_eq!(merged[0].number("rh"), Some(1.0));
+
+        let text = parse_line(r#"x,station_id=A rain=1,sky="overcast""#).unwrap();
+        let obs = to_observation(&text, Precision::Ns, now).unwrap();
+        assert_eq!(obs.fields["sky"], memtable::FieldValue::Text("overcast".into()));
+        let no_station = parse_line("x temp=1").unwrap();
+        assert!(to_observation(&no_station, Precision::Ns, now).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use crate::storage::memtable::{FieldValue, Observation, Timestamp};
+use crate::AppState;
+
+pub mod demo;
+pub mod file_drop;
+pub mod line_protocol;
+pub mod prom_remote;
+pub mod routing;
+pub mod kafka;
+pub mod qc;
+pub mod udp;
+pub mod import;
+pub mod scraper;
+
+use routing::RouteMeta;
+
+/// Route each observation to its tenant and apply the tenant's validation.
+/// Returns the admitted observations and `(index, reason)` for each rejected one.
+/// Without routing rules everything is admitted.
+pub async fn admit(state: &AppState, obs: Vec<Observation>, meta: &RouteMeta) -> (Vec<Observation>, Vec<(usize, String)>) {
+    let Some(router) = &state.router else { return (obs, Vec::new()) };
+    let mut admitted = Vec::with_capacity(obs.len());
+    let mut rejected = Vec::new();
+    for (i, o) in obs.into_iter().enumerate() {
+        match router.admit(&o, meta).await {
+            Ok(_) => admitted.push(o),
+            Err(e) => {
+                state.health.rejected(&o.station_id, chrono::Utc::now().timestamp_millis());
+                rejected.push((i, format!("{:#}", e)));
+            }
+        }
+    }
+    state.metrics.writes_rejected.inc_by(rejected.len() as u64);
+    (admitted, rejected)
+}
+
+/// Append already-admitted observations to the WAL as one batch, then insert the whole
+/// batch into the MemTable under one lock. With quality control on, implausible readings
+/// are flagged first. With mirroring on they are queued for the peer regions too, and a
+/// standby region refuses them.
+pub async fn append(state: &AppState, mut obs: Vec<Observation>) -> Result<usize> {
+    if let Some(reason) = state.write_refusal() {
+        bail!(reason);
+    }
+    let checked = state.qc.as_ref().map(|qc| qc.check(&mut obs));
+    if let Some(mirror) = &state.mirror {
+        mirror.supersede(state, &obs).await?;
+    }
+    let n = append_mirrored(state, obs.clone()).await?;
+    if let (Some(qc), Some(checked)) = (&state.qc, checked) {
+        qc.commit(checked);
+    }
+    if let Some(mirror) = &state.mirror {
+        // the rows are in the WAL either way; a retry writes the same points again
+        mirror.record_writes(&obs).await.with_context(|| format!("queueing {} writes for the mirror peers", obs.len()))?;
+    }
+    Ok(n)
+}
+
+/// [`append`] for writes that came from a peer region: applied even on a
+/// standby and never queued for mirroring again.
+pub async fn append_mirrored(state: &AppState, obs: Vec<Observation>) -> Result<usize> {
+    let Some(wal) = &state.wal else { bail!("server is read-only") };
+    let records = obs.iter().map(serde_json::to_vec).collect::<Result<Vec<_>, _>>()?;
+    let started = std::time::Instant::now();
+    let pin = wal.append_batch(&records).await?;
+    state.metrics.wal_append.observe(started.elapsed());
+    state.metrics.writes.inc_by(obs.len() as u64);
+    state.health.accepted(&obs, chrono::Utc::now().timestamp_millis());
+    {
+        let mut lv = state.last_values.lock().await;
+        for o in &obs {
+            lv.observe(o);
+        }
+    }
+    let n = obs.len();
+    if state.live.receiver_count() > 0 {
+        let _ = state.live.send(obs.as_slice().into());
+    }
+    let mut mt = state.memtable.lock().await;
+    for o in obs {
+        mt.insert(o);
+    }
+    mt.retain_pin(pin);
+    Ok(n)
+}
+
+/// Fold observations with the same station and time into one, first value
+/// winning; a value fills in a field the earlier point only had as null.
+pub fn merge_by_time(points: Vec<Observation>) -> Vec<Observation> {
+    let mut out: Vec<Observation> = Vec::with_capacity(points.len());
+    let mut index: BTreeMap<(String, Timestamp), usize> = BTreeMap::new();
+    for obs in points {
+        let key = (obs.station_id.clone(), obs.time);
+        match index.get(&key) {
+            Some(&i) => {
+                let existing = &mut out[i];
+                for (name, value) in obs.fields {
+                    match existing.fields.get(&name) {
+                        Some(FieldValue::Null) if value != FieldValue::Null => {
+                            existing.fields.insert(name, value);
+                        }
+                        Some(_) => {}
+                        None => {
+                            existing.fields.insert(name, value);
+                        }
+                    }
+                }
+                for (k, v) in obs.tags {
+                    existing.tags.entry(k).or_insert(v);
+                }
+            }
+            None => {
+                index.insert(key, out.len());
+                out.push(obs);
+            }
+        }
+    }
+    out
+}
+
+/// Merge, admit each point with its own tags and append the admitted ones.
+/// Returns the number written and one message per rejected point.
+pub async fn write_tagged(state: &AppState, token: Option<String>, points: Vec<Observation>) -> Result<(usize, Vec<String>)> {
+    let mut admitted = Vec::with_capacity(points.len());
+    let mut errors = Vec::new();
+    for obs in merge_by_time(points) {
+        let meta = RouteMeta { token: token.clone(), tags: obs.tags.clone() };
+        let station = obs.station_id.clone();
+        let (ok, rejected) = admit(state, vec![obs], &meta).await;
+        admitted.extend(ok);
+        errors.extend(rejected.into_iter().map(|(_, reason)| format!("station {}: {}", station, reason)));
+    }
+    Ok((append(state, admitted).await?, errors))
+}
+
+/// Shared write path for ingest sources without request metadata. The batch
+/// is rejected as a whole if any observation fails its tenant's validation.
+pub async fn write_observations(state: &AppState, obs: Vec<Observation>) -> Result<usize> {
+    let (admitted, rejected) = admit(state, obs, &RouteMeta::default()).await;
+    if let Some((i, reason)) = rejected.first() {
+        bail!("observation {}: {}", i, reason);
+    }
+    append(state, admitted).await
+}
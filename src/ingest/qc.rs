@@ -0,0 +1,240 @@
+// Quality control on the write path. A reading outside a field's plausible
+// range, or one that moved further than the field can since the station's
+// previous reading a moment ago, is stored flagged rather than as if it
+// were good: the flag field (`anomaly`) is set to true and the `qc` tag
+// names the failed checks, e.g. `humidity:range,temp:step`. Values are kept
+// as sent, so a flag can be reviewed and nothing real is lost. Flagged
+// readings count against the station's health and per station in
+// `skypulse_qc_flagged_total` on `/metrics`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use super::routing::Range;
+use crate::storage::memtable::{FieldValue, Observation};
+use crate::units;
+
+/// Baselines kept before stale ones are first dropped.
+const PRUNE_MIN: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QcConfig {
+    /// Field set to true on flagged readings; `health.flag_fields` should
+    /// list it for them to count as anomalous.
+    pub flag_field: String,
+    /// Inclusive plausible range per field.
+    pub ranges: BTreeMap<String, Range>,
+    /// Largest change per field from the station's previous reading.
+    pub steps: BTreeMap<String, Step>,
+}
+
+impl Default for QcConfig {
+    fn default() -> Self {
+        let range = |min, max| Range { min, max };
+        Self {
+            flag_field: "anomaly".to_string(),
+            ranges: [
+                ("temp".to_string(), range(-90.0, 60.0)),
+                ("humidity".to_string(), range(0.0, 100.0)),
+                ("pressure".to_string(), range(850.0, 1090.0)),
+                ("wind_speed".to_string(), range(0.0, 120.0)),
+            ]
+            .into(),
+            steps: [("temp".to_string(), Step { max_change: 10.0, within_secs: 60 })].into(),
+        }
+    }
+}
+
+/// A field may change by at most `max_change` between readings less than
+/// `within_secs` apart; readings further apart are not compared.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub max_change: f64,
+    #[serde(alias = "within", deserialize_with = "units::secs")]
+    pub within_secs: u64,
+}
+
+/// The QC stage of one node.
+pub struct Qc {
+    cfg: QcConfig,
+    /// Time (epoch milliseconds) and value of the newest stored reading per
+    /// station and stepped field that passed its step check.
+    previous: Mutex<Baselines>,
+    flagged: Mutex<BTreeMap<String, u64>>,
+}
+
+#[derive(Default)]
+struct Baselines {
+    by_key: HashMap<(String, String), (i64, f64)>,
+    /// Newest reading time seen, which baselines are aged against.
+    latest: i64,
+    /// Size at which stale baselines are next dropped.
+    prune_at: usize,
+}
+
+/// What [`Qc::check`] found for one batch, applied by [`Qc::commit`] once
+/// the batch is stored.
+#[derive(Default)]
+pub struct Checked {
+    baselines: HashMap<(String, String), (i64, f64)>,
+    flagged: BTreeMap<String, u64>,
+}
+
+impl Checked {
+    /// How many readings were flagged.
+    pub fn flagged(&self) -> u64 {
+        self.flagged.values().sum()
+    }
+}
+
+impl Qc {
+    pub fn new(cfg: QcConfig) -> Self {
+        Self { cfg, previous: Mutex::new(Baselines { prune_at: PRUNE_MIN, ..Default::default() }), flagged: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Flag the readings of `obs` failing a check. The step baselines and
+    /// flagged counts only move on [`Qc::commit`], so a batch that is never
+    /// stored leaves them as they were.
+    pub fn check(&self, obs: &mut [Observation]) -> Checked {
+        let previous = self.previous.lock().unwrap();
+        let mut checked = Checked::default();
+        for o in obs.iter_mut() {
+            let mut failed = Vec::new();
+            for (field, range) in &self.cfg.ranges {
+                if o.number(field).is_some_and(|v| v < range.min || v > range.max) {
+                    failed.push(format!("{}:range", field));
+                }
+            }
+            let time = o.time.millis();
+            for (field, step) in &self.cfg.steps {
+                let Some(v) = o.number(field) else { continue };
+                let key = (o.station_id.clone(), field.clone());
+                match checked.baselines.get(&key).or_else(|| previous.by_key.get(&key)) {
+                    // late readings are not compared and do not move the baseline
+                    Some(&(t, _)) if t >= time => continue,
+                    Some(&(t, last)) if time - t < step.within_secs as i64 * 1000 && (v - last).abs() > step.max_change => {
+                        failed.push(format!("{}:step", field));
+                        continue;
+                    }
+                    _ => {}
+                }
+                checked.baselines.insert(key, (time, v));
+            }
+            if failed.is_empty() {
+                continue;
+            }
+            o.set(self.cfg.flag_field.clone(), FieldValue::Bool(true));
+            o.tags.insert("qc".to_string(), failed.join(","));
+            *checked.flagged.entry(o.station_id.clone()).or_default() += 1;
+        }
+        checked
+    }
+
+    /// Record a checked batch as stored. Baselines older than the longest
+    /// step window behind the newest reading can no longer flag anything
+    /// and are dropped as the map grows.
+    pub fn commit(&self, checked: Checked) {
+        let mut previous = self.previous.lock().unwrap();
+        for (key, (time, v)) in checked.baselines {
+            previous.latest = previous.latest.max(time);
+            // another batch may have stored a newer reading in the meantime
+            if previous.by_key.get(&key).is_none_or(|&(t, _)| t < time) {
+                previous.by_key.insert(key, (time, v));
+            }
+        }
+        if previous.by_key.len() > previous.prune_at {
+            let window = self.cfg.steps.values().map(|s| s.within_secs as i64 * 1000).max().unwrap_or(0);
+            let horizon = previous.latest - window;
+            previous.by_key.retain(|_, &mut (t, _)| t >= horizon);
+            previous.prune_at = (previous.by_key.len() * 2).max(PRUNE_MIN);
+        }
+        drop(previous);
+        let mut flagged = self.flagged.lock().unwrap();
+        for (station, n) in checked.flagged {
+            *flagged.entry(station).or_default() += n;
+        }
+    }
+
+    /// Prometheus text for the flagged counts per station.
+    pub fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP skypulse_qc_flagged_total Readings flagged by quality control.");
+        let _ = writeln!(out, "# TYPE skypulse_qc_flagged_total counter");
+        for (station, n) in self.flagged.lock().unwrap().iter() {
+            let id = station.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(out, "skypulse_qc_flagged_total{{station_id=\"{}\"}} {}", id, n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Timestamp;
+
+    #[test]
+    fn flags_implausible_readings() {
+        let qc = Qc::new(QcConfig::default());
+        let reading = |station: &str, secs: i64, temp: f64, humidity: f64| {
+            let mut o = Observation::empty(station, Timestamp(1_735_689_600_000 + secs * 1000));
+            o.set_field("temp", temp);
+            o.set_field("humidity", humidity);
+            o
+        };
+        let mut obs = vec![
+            reading("A", 0, 20.0, 50.0),
+            reading("A", 30, 50.0, -3.0),
+            reading("A", 50, 21.0, 50.0),
+            reading("B", 30, 45.0, 50.0),
+            reading("A", 600, 35.0, 50.0),
+        ];
+        let checked = qc.check(&mut obs);
+        assert_eq!(checked.flagged(), 1);
+        qc.commit(checked);
+        assert!(!obs[0].fields.contains_key("anomaly"));
+        assert_eq!(obs[1].fields["anomaly"], FieldValue::Bool(true));
+        assert_eq!(obs[1].tags["qc"], "humidity:range,temp:step");
+        // the spike did not become the baseline, and a jump after a long gap is not one
+        assert!(!obs[2].tags.contains_key("qc") && !obs[4].tags.contains_key("qc"));
+        assert_eq!(obs[1].number("temp"), Some(50.0));
+
+        let mut spike = vec![reading("B", 40, 10.0, 50.0)];
+        let checked = qc.check(&mut spike);
+        qc.commit(checked);
+        assert_eq!(spike[0].tags["qc"], "temp:step");
+        let mut out = String::new();
+        qc.render(&mut out);
+        assert!(out.contains("skypulse_qc_flagged_total{station_id=\"A\"} 1\n"));
+        assert!(out.contains("skypulse_qc_flagged_total{station_id=\"B\"} 1\n"));
+    }
+
+    #[test]
+    fn only_stored_readings_move_the_baseline() {
+        let qc = Qc::new(QcConfig::default());
+        let reading = |station: &str, secs: i64, temp: f64| {
+            let mut o = Observation::empty(station, Timestamp(1_735_689_600_000 + secs * 1000));
+            o.set_field("temp", temp);
+            o
+        };
+        // a batch whose append failed is never committed
+        let _ = qc.check(&mut [reading("A", 0, 40.0)]);
+        let mut obs = [reading("A", 10, 20.0)];
+        let checked = qc.check(&mut obs);
+        assert_eq!(checked.flagged(), 0);
+        qc.commit(checked);
+        let mut obs = [reading("A", 20, 40.0)];
+        assert_eq!(qc.check(&mut obs).flagged(), 1);
+
+        // stations gone quiet for longer than the step window are forgotten
+        for i in 0..PRUNE_MIN as i64 + 1 {
+            qc.commit(qc.check(&mut [reading(&format!("S{i}"), i * 60, 20.0)]));
+        }
+        assert!(qc.previous.lock().unwrap().by_key.len() < PRUNE_MIN);
+        // nor was the uncommitted flag counted
+        let mut out = String::new();
+        qc.render(&mut out);
+        assert!(!out.contains("station_id=\"A\""));
+    }
+}
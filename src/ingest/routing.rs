@@ -0,0 +1,327 @@
+// Routing of incoming observations to tenants. When several networks push to
+// the same endpoint, rules decide which tenant a reading belongs to; the
+// tenant's validation is applied before the write and its retention is
+// applied to the station's chunks by the retention worker.
+//
+// Rules are small expressions: conditions joined by `&&`, each comparing a
+// subject with a quoted string using `==`, `!=` or `^=` (starts with).
+// Subjects are `station_id`, `token` (API key sent by the client) and
+// `tag.<name>` (tags sent in the write payload), for example
+//
+//     station_id ^= "HKO" && tag.network == "hko"
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::storage::memtable::Observation;
+use crate::units;
+
+pub const ASSIGNMENTS_FILE: &str = "tenants.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    StartsWith,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Subject {
+    StationId,
+    Token,
+    Tag(String),
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    subject: Subject,
+    op: Op,
+    value: String,
+}
+
+/// A parsed rule expression.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    conditions: Vec<Condition>,
+}
+
+impl Expr {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut conditions = Vec::new();
+        for part in src.split("&&") {
+            conditions.push(parse_condition(part.trim()).with_context(|| format!("in rule '{}'", src))?);
+        }
+        Ok(Self { conditions })
+    }
+
+    pub fn matches(&self, meta: &RouteMeta, station_id: &str) -> bool {
+        self.conditions.iter().all(|c| {
+            let actual = match &c.subject {
+                Subject::StationId => Some(station_id),
+                Subject::Token => meta.token.as_deref(),
+                Subject::Tag(k) => meta.tags.get(k).map(String::as_str),
+            };
+            match (c.op, actual) {
+                (Op::Eq, Some(a)) => a == c.value,
+                (Op::StartsWith, Some(a)) => a.starts_with(&c.value),
+                (Op::Ne, a) => a != Some(c.value.as_str()),
+                (_, None) => false,
+            }
+        })
+    }
+}
+
+fn parse_condition(s: &str) -> Result<Condition> {
+    // the earliest operator wins, so operators inside the quoted value are left alone
+    let (idx, op, op_len) = [("==", Op::Eq), ("!=", Op::Ne), ("^=", Op::StartsWith)]
+        .into_iter()
+        .filter_map(|(tok, op)| s.find(tok).map(|i| (i, op, tok.len())))
+        .min_by_key(|(i, _, _)| *i)
+        .ok_or_else(|| anyhow!("expected ==, != or ^= in '{}'", s))?;
+    let subject = match s[..idx].trim() {
+        "station_id" => Subject::StationId,
+        "token" => Subject::Token,
+        other => match other.strip_prefix("tag.") {
+            Some(k) if !k.is_empty() => Subject::Tag(k.to_string()),
+            _ => bail!("unknown subject '{}'", other),
+        },
+    };
+    let raw = s[idx + op_len..].trim();
+    let value = raw
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(|| anyhow!("value must be a double-quoted string, got {}", raw))?;
+    Ok(Condition { subject, op, value: value.to_string() })
+}
+
+/// Request metadata available to routing rules.
+#[derive(Debug, Clone, Default)]
+pub struct RouteMeta {
+    pub token: Option<String>,
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Validation {
+    /// Fields that must be present.
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Inclusive allowed range per field.
+    #[serde(default)]
+    pub ranges: BTreeMap<String, Range>,
+}
+
+impl Validation {
+    pub fn check(&self, obs: &Observation) -> Result<()> {
+        for name in &self.required {
+            if obs.number(name).is_none() {
+                bail!("missing required field '{}'", name);
+            }
+        }
+        for (name, range) in &self.ranges {
+            if let Some(v) = obs.number(name) {
+                if v < range.min || v > range.max {
+                    bail!("{} = {} outside allowed range [{}, {}]", name, v, range.min, range.max);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn retention_secs<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    units::duration(d, "d").map(|age| Some(age.as_secs()))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tenant {
+    pub name: String,
+    /// Overrides the global retention for this tenant's stations: a number
+    /// of days (`retention_days: 30`) or a duration (`retention: "12w"`).
+    #[serde(rename = "retention_days", alias = "retention", default, deserialize_with = "retention_secs")]
+    pub retention_secs: Option<u64>,
+    #[serde(default)]
+    pub validation: Validation,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RuleConfig {
+    /// Redacted when dumped, since rules may compare API keys.
+    #[serde(rename = "match", serialize_with = "crate::config::redacted")]
+    pub expr: String,
+    pub tenant: String,
+}
+
+/// Routing configuration, from the `[routing]` config section or the JSON file
+/// named by `SKYPULSE_ROUTING_CONFIG`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub tenants: Vec<Tenant>,
+    /// Evaluated in order; the first match wins.
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// Tenant for observations no rule matches; unrouted data is accepted as-is when unset.
+    #[serde(default)]
+    pub default_tenant: Option<String>,
+}
+
+impl RoutingConfig {
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("SKYPULSE_ROUTING_CONFIG") {
+            Ok(path) => {
+                let data = std::fs::read(&path).with_context(|| format!("reading {}", path))?;
+                Ok(Some(serde_json::from_slice(&data)?))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+pub struct TenantRouter {
+    tenants: BTreeMap<String, Tenant>,
+    rules: Vec<(Expr, String)>,
+    default_tenant: Option<String>,
+    /// station_id -> tenant, persisted so retention can apply tenant policies.
+    assignments: Mutex<BTreeMap<String, String>>,
+    path: Option<PathBuf>,
+}
+
+impl TenantRouter {
+    /// Build a router; assignments are loaded from and saved to
+    /// `data_dir/tenants.json` when `data_dir` is given.
+    pub fn new(cfg: RoutingConfig, data_dir: Option<&Path>) -> Result<Self> {
+        let tenants: BTreeMap<String, Tenant> = cfg.tenants.into_iter().map(|t| (t.name.clone(), t)).collect();
+        let known = |name: &str| -> Result<()> {
+            if tenants.contains_key(name) { Ok(()) } else { bail!("unknown tenant '{}'", name) }
+        };
+        let mut rules = Vec::new();
+        for r in cfg.rules {
+            known(&r.tenant)?;
+            rules.push((Expr::parse(&r.expr)?, r.tenant));
+        }
+        if let Some(d) = &cfg.default_tenant {
+            known(d)?;
+        }
+        let path = data_dir.map(|d| d.join(ASSIGNMENTS_FILE));
+        let assignments = match &path {
+            Some(p) if p.exists() => serde_json::from_slice(&std::fs::read(p)?)?,
+            _ => BTreeMap::new(),
+        };
+        Ok(Self { tenants, rules, default_tenant: cfg.default_tenant, assignments: Mutex::new(assignments), path })
+    }
+
+    pub fn route(&self, meta: &RouteMeta, station_id: &str) -> Option<&Tenant> {
+        let name = self
+            .rules
+            .iter()
+            .find(|(expr, _)| expr.matches(meta, station_id))
+            .map(|(_, t)| t)
+            .or(self.default_tenant.as_ref())?;
+        self.tenants.get(name)
+    }
+
+    /// Route and validate one observation, recording the station's tenant.
+    /// Returns the tenant name, if any rule (or the default) applied.
+    pub async fn admit(&self, obs: &Observation, meta: &RouteMeta) -> Result<Option<String>> {
+        let Some(tenant) = self.route(meta, &obs.station_id) else { return Ok(None) };
+        tenant.validation.check(obs).with_context(|| format!("tenant '{}' rejected observation", tenant.name))?;
+        let mut assignments = self.assignments.lock().await;
+        if assignments.get(&obs.station_id) != Some(&tenant.name) {
+            assignments.insert(obs.station_id.clone(), tenant.name.clone());
+            if let Some(path) = &self.path {
+                tokio::fs::write(path, serde_json::to_vec_pretty(&*assignments)?).await?;
+            }
+        }
+        Ok(Some(tenant.name.clone()))
+    }
+
+    /// Retention overrides in seconds per station, from each station's tenant.
+    pub async fn retention_overrides(&self) -> BTreeMap<String, u64> {
+        let assignments = self.assignments.lock().await;
+        assignments
+            .iter()
+            .filter_map(|(station, tenant)| {
+                let secs = self.tenants.get(tenant)?.retention_secs?;
+                Some((station.clone(), secs))
+            })
+            .collect()
+    }
+
+    pub async fn assignments(&self) -> BTreeMap<String, String> {
+        self.assignments.lock().await.clone()
+    }
+
+    pub fn has_retention_overrides(&self) -> bool {
+        self.tenants.values().any(|t| t.retention_secs.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(token: Option<&str>, tags: &[(&str, &str)]) -> RouteMeta {
+        RouteMeta {
+            token: token.map(String::from),
+            tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    fn router() -> TenantRouter {
+        let cfg: RoutingConfig = serde_json::from_str(
+            r#"{
+                "tenants": [
+                    {"name": "hko", "retention_days": 30, "validation": {"ranges": {"temp": {"min": -20, "max": 50}}}},
+                    {"name": "research", "validation": {"required": ["pressure"]}},
+                    {"name": "public"}
+                ],
+                "rules": [
+                    {"match": "station_id ^= \"HKO\" && tag.network != \"test\"", "tenant": "hko"},
+                    {"match": "token == \"lab-key\"", "tenant": "research"}
+                ],
+                "default_tenant": "public"
+            }"#,
+        )
+        .unwrap();
+        TenantRouter::new(cfg, None).unwrap()
+    }
+
+    #[test]
+    fn routes_by_prefix_token_and_tag() {
+        let r = router();
+        assert_eq!(r.route(&meta(None, &[]), "HKO01").unwrap().name, "hko");
+        assert_eq!(r.route(&meta(None, &[("network", "test")]), "HKO01").unwrap().name, "public");
+        assert_eq!(r.route(&meta(Some("lab-key"), &[]), "X1").unwrap().name, "research");
+        assert_eq!(r.route(&meta(None, &[]), "X1").unwrap().name, "public");
+    }
+
+    #[tokio::test]
+    async fn applies_tenant_validation_and_retention() {
+        let r = router();
+        let mut obs = Observation::empty("HKO01", "2025-01-01T00:00:00Z".parse().unwrap());
+        obs.set_field("temp", 80.0);
+        assert!(r.admit(&obs, &RouteMeta::default()).await.is_err());
+        obs.set_field("temp", 25.0);
+        assert_eq!(r.admit(&obs, &RouteMeta::default()).await.unwrap().as_deref(), Some("hko"));
+        assert_eq!(r.retention_overrides().await.get("HKO01"), Some(&(30 * 86_400)));
+
+        let lab = Observation::empty("LAB1", "2025-01-01T00:00:00Z".parse().unwrap());
+        assert!(r.admit(&lab, &meta(Some("lab-key"), &[])).await.is_err());
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        assert!(Expr::parse("station_id = \"A\"").is_err());
+        assert!(Expr::parse("region == \"A\"").is_err());
+        assert!(Expr::parse("token == A").is_err());
+    }
+}
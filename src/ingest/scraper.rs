@@ -0,0 +1,282 @@
+// Pull-based scraping for stations that expose readings over HTTP but can't
+// push. Each target is fetched on its own interval (plus jitter so targets
+// don't fire in lockstep), the JSON or XML body is mapped onto observation
+// fields, and the result goes through the normal write path.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::units;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    Xml,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScrapeTarget {
+    pub station_id: String,
+    pub url: String,
+    #[serde(alias = "interval", default = "default_interval", deserialize_with = "units::secs")]
+    pub interval_secs: u64,
+    #[serde(alias = "timeout", default = "default_timeout", deserialize_with = "units::secs")]
+    pub timeout_secs: u64,
+    /// Upper bound of the random delay added to each interval.
+    #[serde(alias = "jitter", default, deserialize_with = "units::secs")]
+    pub jitter_secs: u64,
+    #[serde(default)]
+    pub format: PayloadFormat,
+    /// Observation field name -> location in the payload. JSON uses JSON
+    /// pointers (`/current/temp`); XML uses element paths from the root
+    /// (`obs/temp`), with `@name` selecting an attribute (`obs/wind@dir`).
+    pub fields: BTreeMap<String, String>,
+    /// Location of the reading's timestamp (RFC3339 or epoch seconds).
+    /// When absent the fetch time is used.
+    #[serde(default)]
+    pub time: Option<String>,
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// Scrape targets, from the `[scrape]` config section or the JSON file named
+/// by `SKYPULSE_SCRAPE_CONFIG`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ScrapeConfig {
+    pub targets: Vec<ScrapeTarget>,
+}
+
+impl ScrapeConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let cfg: ScrapeConfig = serde_json::from_slice(&data)?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for t in &self.targets {
+            for name in t.fields.keys() {
+                if name.is_empty() {
+                    bail!("scrape target {}: empty field name", t.station_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("SKYPULSE_SCRAPE_CONFIG") {
+            Ok(path) => Ok(Some(Self::load(Path::new(&path))?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    s.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+/// Accept RFC3339 or integer epoch seconds.
+fn normalize_time(raw: &str) -> Result<Timestamp> {
+    let raw = raw.trim();
+    if let Ok(t) = Timestamp::parse(raw) {
+        return Ok(t);
+    }
+    let secs: i64 = raw.parse().map_err(|_| anyhow!("unrecognised timestamp '{}'", raw))?;
+    let t = chrono::DateTime::from_timestamp(secs, 0).ok_or_else(|| anyhow!("timestamp out of range"))?;
+    Ok(Timestamp::from_datetime(t))
+}
+
+/// The current time truncated to whole seconds.
+fn now() -> Timestamp {
+    Timestamp(chrono::Utc::now().timestamp() * 1000)
+}
+
+/// Map a payload onto an observation using `lookup` to resolve mapping paths.
+/// Fails if none of the mapped fields are present.
+fn build_observation(target: &ScrapeTarget, lookup: impl Fn(&str) -> Option<String>) -> Result<Observation> {
+    let time = match &target.time {
+        Some(path) => normalize_time(&lookup(path).ok_or_else(|| anyhow!("time not found at '{}'", path))?)?,
+        None => now(),
+    };
+    let mut obs = Observation::empty(&target.station_id, time);
+    let mut found = 0;
+    for (field, path) in &target.fields {
+        if let Some(v) = lookup(path).as_deref().and_then(parse_number) {
+            obs.set_field(field.as_str(), v);
+            found += 1;
+        }
+    }
+    if found == 0 {
+        bail!("none of the mapped fields were found in the payload");
+    }
+    Ok(obs)
+}
+
+pub fn extract_json(target: &ScrapeTarget, body: &[u8]) -> Result<Observation> {
+    let doc: serde_json::Value = serde_json::from_slice(body)?;
+    build_observation(target, |path| match doc.pointer(path)? {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    })
+}
+
+pub fn extract_xml(target: &ScrapeTarget, body: &str) -> Result<Observation> {
+    let values = flatten_xml(body)?;
+    build_observation(target, |path| values.get(path).cloned())
+}
+
+/// Flatten an XML document into `a/b/c` -> text and `a/b@attr` -> value.
+/// The first occurrence of a path wins.
+fn flatten_xml(body: &str) -> Result<HashMap<String, String>> {
+    use quick_xml::events::{BytesStart, Event};
+
+    fn record_attrs(e: &BytesStart, path: &str, out: &mut HashMap<String, String>) -> Result<()> {
+        for attr in e.attributes() {
+            let attr = attr?;
+            let key = format!("{}@{}", path, String::from_utf8_lossy(attr.key.as_ref()));
+            out.entry(key).or_insert(attr.unescape_value()?.into_owned());
+        }
+        Ok(())
+    }
+
+    let mut reader = quick_xml::Reader::from_str(body);
+    reader.config_mut().trim_text(true);
+    let mut stack: Vec<String> = Vec::new();
+    let mut out = HashMap::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                stack.push(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                record_attrs(&e, &stack.join("/"), &mut out)?;
+            }
+            Event::Empty(e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).into_owned();
+                let path = if stack.is_empty() { name } else { format!("{}/{}", stack.join("/"), name) };
+                record_attrs(&e, &path, &mut out)?;
+            }
+            Event::Text(t) if !stack.is_empty() => {
+                out.entry(stack.join("/")).or_insert(t.unescape()?.into_owned());
+            }
+            Event::End(_) => {
+                stack.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+/// Fetch one target and ingest the resulting observation.
+pub async fn scrape_once(client: &reqwest::Client, state: &AppState, target: &ScrapeTarget) -> Result<()> {
+    let resp = client
+        .get(&target.url)
+        .timeout(Duration::from_secs(target.timeout_secs))
+        .send()
+        .await?
+        .error_for_status()?;
+    let body = resp.bytes().await?;
+    let obs = match target.format {
+        PayloadFormat::Json => extract_json(target, &body)?,
+        PayloadFormat::Xml => extract_xml(target, &String::from_utf8_lossy(&body))?,
+    };
+    super::write_observations(state, vec![obs]).await?;
+    Ok(())
+}
+
+fn jitter(max_secs: u64) -> Duration {
+    if max_secs == 0 {
+        return Duration::ZERO;
+    }
+    // RandomState is randomly keyed per instance, which is plenty for spreading timers.
+    let r = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    Duration::from_millis(r % (max_secs * 1000))
+}
+
+/// Run one scrape loop per target until `shutdown` fires.
+pub async fn run(state: Arc<AppState>, cfg: ScrapeConfig, shutdown: tokio::sync::broadcast::Sender<()>) {
+    let client = reqwest::Client::new();
+    for target in cfg.targets {
+        let state = state.clone();
+        let client = client.clone();
+        let mut shutdown_sub = shutdown.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let delay = Duration::from_secs(target.interval_secs.max(1)) + jitter(target.jitter_secs);
+                tokio::select! {
+                    _ = shutdown_sub.recv() => break,
+                    _ = tokio::time::sleep(delay) => {
+                        if let Err(e) = scrape_once(&client, &state, &target).await {
+                            tracing::warn!(station_id = %target.station_id, url = %target.url, "scrape failed: {:#}", e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(format: PayloadFormat, fields: &[(&str, &str)], time: Option<&str>) -> ScrapeTarget {
+        ScrapeTarget {
+            station_id: "REMOTE1".into(),
+            url: "http://example.invalid".into(),
+            interval_secs: 60,
+            timeout_secs: 5,
+            jitter_secs: 0,
+            format,
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            time: time.map(String::from),
+        }
+    }
+
+    #[test]
+    fn maps_json_payload() {
+        let t = target(PayloadFormat::Json, &[("temp", "/current/t"), ("humidity", "/current/rh")], Some("/ts"));
+        let obs = extract_json(&t, br#"{"ts": 1735689600, "current": {"t": 21.5, "rh": "63"}}"#).unwrap();
+        assert_eq!(obs.time.to_string(), "2025-01-01T00:00:00Z");
+        assert_eq!(obs.number("temp"), Some(21.5));
+        assert_eq!(obs.number("humidity"), Some(63.0));
+        assert_eq!(obs.station_id, "REMOTE1");
+    }
+
+    #[test]
+    fn maps_xml_payload_with_attributes() {
+        let t = target(
+            PayloadFormat::Xml,
+            &[("temp", "station/obs/temp"), ("wind_dir", "station/obs/wind@dir")],
+            Some("station/obs@time"),
+        );
+        let body = r#"<station><obs time="2025-01-01T08:00:00+08:00"><temp>18.2</temp><wind dir="270" speed="3"/></obs></station>"#;
+        let obs = extract_xml(&t, body).unwrap();
+        assert_eq!(obs.time.to_string(), "2025-01-01T00:00:00Z");
+        assert_eq!(obs.number("temp"), Some(18.2));
+        assert_eq!(obs.number("wind_dir"), Some(270.0));
+    }
+
+    #[test]
+    fn rejects_payload_without_mapped_fields() {
+        let t = target(PayloadFormat::Json, &[("temp", "/missing")], None);
+        assert!(extract_json(&t, br#"{"other": 1}"#).is_err());
+    }
+}
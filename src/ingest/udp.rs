@@ -0,0 +1,202 @@
+// UDP ingest for stations behind LoRa or cellular gateways, where a TCP
+// handshake and HTTP headers cost more than the reading itself. Each datagram
+// carries JSON as `/api/v1/write/batch` accepts it or Influx line protocol,
+// and goes through the same routing and write path as HTTP writes. Delivery
+// is best effort: there is no acknowledgement and no authentication, and
+// datagrams that cannot be decoded, readings in them that do not parse or
+// fail validation, and datagrams arriving while the write queue is full are
+// dropped and counted in `skypulse_udp_*` instead.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use crate::ingest::line_protocol::{self, Precision};
+use crate::storage::memtable::Observation;
+use crate::AppState;
+
+/// Largest UDP payload over IPv4.
+const MAX_DATAGRAM: usize = 65_507;
+/// Decoded datagrams appended together at most.
+const WRITE_BATCH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DatagramFormat {
+    /// JSON when the datagram starts with `{` or `[`, line protocol otherwise.
+    #[default]
+    Auto,
+    Json,
+    Line,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+    pub bind: SocketAddr,
+    #[serde(default)]
+    pub format: DatagramFormat,
+    /// Unit of line-protocol timestamps: `ns`, `us`, `ms` or `s`.
+    #[serde(default = "default_precision")]
+    pub precision: String,
+    /// Decoded datagrams waiting for the WAL; more are dropped.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+fn default_precision() -> String {
+    "ns".to_string()
+}
+
+fn default_queue_size() -> usize {
+    1024
+}
+
+impl UdpConfig {
+    /// Enabled by `SKYPULSE_UDP_BIND`.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        let Ok(v) = std::env::var("SKYPULSE_UDP_BIND") else { return Ok(()) };
+        let bind = v.trim().parse().with_context(|| format!("SKYPULSE_UDP_BIND must be an address such as 0.0.0.0:8089, got '{}'", v))?;
+        match cfg {
+            Some(c) => c.bind = bind,
+            None => *cfg = Some(Self { bind, format: DatagramFormat::Auto, precision: default_precision(), queue_size: default_queue_size() }),
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        Precision::parse(&self.precision).context("udp.precision")?;
+        if self.queue_size == 0 {
+            bail!("udp.queue_size must be at least 1");
+        }
+        Ok(())
+    }
+
+    fn precision(&self) -> Precision {
+        Precision::parse(&self.precision).unwrap_or_default()
+    }
+}
+
+/// The observations in one datagram and the number of readings in it that
+/// did not parse; no observations when nothing in it is usable.
+pub fn decode(datagram: &[u8], cfg: &UdpConfig) -> (Vec<Observation>, usize) {
+    let json = match cfg.format {
+        DatagramFormat::Json => true,
+        DatagramFormat::Line => false,
+        DatagramFormat::Auto => matches!(datagram.trim_ascii_start().first(), Some(b'{' | b'[')),
+    };
+    let mut unparsed = 0;
+    let obs: Vec<Observation> = if json {
+        let Ok(items) = crate::api::http::parse_batch(datagram) else { return (Vec::new(), 0) };
+        items.into_iter().filter_map(|(_, item)| item.inspect_err(|_| unparsed += 1).ok()).map(|req| req.to_observation()).collect()
+    } else {
+        let now = chrono::Utc::now();
+        let Ok(text) = std::str::from_utf8(datagram) else { return (Vec::new(), 0) };
+        line_protocol::parse(text)
+            .into_iter()
+            .filter_map(|(_, p)| p.and_then(|p| line_protocol::to_observation(&p, cfg.precision(), now)).inspect_err(|_| unparsed += 1).ok())
+            .collect()
+    };
+    (obs, unparsed)
+}
+
+/// Bind the socket up front so a taken port fails startup.
+pub fn bind(cfg: &UdpConfig) -> Result<std::net::UdpSocket> {
+    let socket = std::net::UdpSocket::bind(cfg.bind).with_context(|| format!("failed to bind udp {}", cfg.bind))?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Append queued datagrams, several at a time, until the queue closes.
+async fn write(state: Arc<AppState>, mut rx: mpsc::Receiver<Vec<Observation>>) {
+    let mut queued = Vec::new();
+    while rx.recv_many(&mut queued, WRITE_BATCH).await > 0 {
+        let points: Vec<Observation> = queued.drain(..).flatten().collect();
+        let n = points.len();
+        match super::write_tagged(&state, None, points).await {
+            Ok((_, rejected)) if !rejected.is_empty() => {
+                state.metrics.udp_rejected.inc_by(rejected.len() as u64);
+                tracing::debug!("udp: {} rejected, first: {}", rejected.len(), rejected[0]);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                state.metrics.udp_dropped.inc_by(n as u64);
+                tracing::warn!("udp: dropping {} observations: {:#}", n, e);
+            }
+        }
+    }
+}
+
+/// Receive datagrams until `shutdown` fires, then wait for the queue to drain.
+pub async fn run(state: Arc<AppState>, socket: std::net::UdpSocket, cfg: UdpConfig, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let socket = match tokio::net::UdpSocket::from_std(socket) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("udp: {}", e);
+            return;
+        }
+    };
+    let (tx, rx) = mpsc::channel(cfg.queue_size.max(1));
+    let writer = tokio::spawn(write(state.clone(), rx));
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let len = tokio::select! {
+            _ = shutdown.recv() => break,
+            r = socket.recv_from(&mut buf) => match r {
+                Ok((len, _)) => len,
+                Err(e) => {
+                    tracing::warn!("udp: {}", e);
+                    continue;
+                }
+            },
+        };
+        state.metrics.udp_datagrams.inc_by(1);
+        let (obs, unparsed) = decode(&buf[..len], &cfg);
+        if obs.is_empty() {
+            state.metrics.udp_invalid.inc_by(1);
+            continue;
+        }
+        state.metrics.udp_rejected.inc_by(unparsed as u64);
+        if let Err(mpsc::error::TrySendError::Full(obs)) = tx.try_send(obs) {
+            state.metrics.udp_dropped.inc_by(obs.len() as u64);
+        }
+    }
+    drop(tx);
+    let _ = writer.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn datagrams_feed_the_write_path() {
+        let dir = crate::test_util::TempDir::new("udp");
+        let state = Arc::new(AppState::open(&crate::Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap());
+        let cfg: UdpConfig = toml::from_str("bind = \"127.0.0.1:0\"\nprecision = \"s\"").unwrap();
+        cfg.validate().unwrap();
+        let socket = bind(&cfg).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
+        let listener = tokio::spawn(run(state.clone(), socket, cfg, shutdown_tx.subscribe()));
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(br#"{"station_id":"A","time":"2025-01-01T00:00:00Z","temp":1.5}"#, addr).await.unwrap();
+        client.send_to(b"weather,station_id=B temp=2.5 1735689600", addr).await.unwrap();
+        client.send_to(b"not a reading", addr).await.unwrap();
+        // one good line next to two that do not parse
+        client.send_to(b"weather,station_id=C temp=3.5 1735689600\nweather,station_id=C temp= 1\nweather temp=1.5", addr).await.unwrap();
+        while state.metrics.udp_datagrams.get() < 4 || state.metrics.udp_rejected.get() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let _ = shutdown_tx.send(());
+        listener.await.unwrap();
+
+        let mt = state.memtable.lock().await;
+        assert_eq!(mt.buffer["A"][0].number("temp"), Some(1.5));
+        assert_eq!(mt.buffer["B"][0].time, "2025-01-01T00:00:00Z".parse().unwrap());
+        assert_eq!(mt.buffer["C"].len(), 1);
+        assert_eq!((state.metrics.udp_invalid.get(), state.metrics.udp_rejected.get(), state.metrics.udp_dropped.get()), (1, 2, 0));
+    }
+}
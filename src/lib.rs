@@ -3,97 +3,430 @@ use tokio::sync::Mutex;
 pub mod storage;
 pub mod compression;
 pub mod api;
+pub mod query;
+pub mod ingest;
+pub mod logging;
+pub mod metrics;
+pub mod runtime;
+pub mod config;
+pub mod rebuild;
+pub mod slo;
+pub mod units;
+pub mod mirror;
+pub mod forecast;
+pub mod debug;
+pub mod delete;
+pub mod snapshot;
+pub mod health;
+pub mod alerts;
+pub mod continuous;
+pub mod export;
+pub mod stations;
+pub mod replication;
+pub mod replica;
+pub mod replay;
+pub mod namespace;
+pub mod monitor;
+pub mod offline;
+pub mod columnar;
+pub mod client;
+pub mod embedded;
+#[cfg(test)]
+mod test_util;
+
+pub use config::Config;
+pub use embedded::SkyPulseDb;
+pub use query::stream::{ObservationBatch, QueryError};
 
 pub struct AppState {
+    /// The configuration in effect, for the debug snapshot.
+    pub config: Config,
+    /// Root of the node's files; the WAL and chunks may live elsewhere.
+    pub data_dir: std::path::PathBuf,
     pub memtable: Arc<Mutex<storage::MemTable>>,
-    pub wal: Arc<storage::WAL>,
+    pub last_values: Arc<Mutex<storage::LastValues>>,
+    /// `None` when the server is read-only.
+    pub wal: Option<Arc<storage::WAL>>,
+    /// Held while the node may write `data_dir`; `None` when read-only.
+    pub dir_lock: Option<storage::DirLock>,
     pub chunk_store: Arc<storage::ChunkStore>,
+    pub rollups: Arc<storage::RollupStore>,
+    pub retention: storage::retention::RetentionPolicy,
+    /// Yearly archive compaction of old chunks, when enabled.
+    pub archive: Option<storage::archive::ArchivePolicy>,
+    /// Small-chunk compaction settings, when enabled.
+    pub compaction: Option<storage::fragmentation::CompactionConfig>,
+    /// Tenant routing rules, when configured.
+    pub router: Option<Arc<ingest::routing::TenantRouter>>,
+    pub runtimes: Arc<runtime::Runtimes>,
+    pub metrics: Arc<metrics::Metrics>,
+    pub slo: Arc<slo::SloTracker>,
+    /// Recent accepted, rejected and flagged writes per station.
+    pub health: health::HealthTracker,
+    /// Flagging of implausible readings on ingest, when configured.
+    pub qc: Option<ingest::qc::Qc>,
+    /// Alert rules and the state of their alerts, when configured.
+    pub alerts: Option<alerts::Alerts>,
+    /// Every admitted write batch, for live subscribers (see `query::stream::subscribe`).
+    pub live: tokio::sync::broadcast::Sender<Arc<[storage::memtable::Observation]>>,
+    pub auth: api::auth::AuthConfig,
+    /// Per-client limiter for the write endpoints, when configured.
+    pub rate_limiter: Option<api::rate_limit::RateLimiter>,
+    /// The running or most recent admin rebuild.
+    pub rebuild: Mutex<Option<rebuild::Progress>>,
+    /// Bulk export jobs and their part files.
+    pub exports: export::Exports,
+    /// Registered station metadata.
+    pub stations: stations::Registry,
+    /// The hot and cold chunk tiers, when tiering is on.
+    pub tiers: Option<Arc<storage::tiering::TieredBackend>>,
+    /// Followers of this node's WAL, and the leader it follows if any.
+    pub replication: replication::Replication,
+    /// Cross-region mirroring, when configured.
+    pub mirror: Option<Arc<mirror::Mirror>>,
+    /// Forecasters runnable through the API; embedders may register more.
+    pub forecasters: forecast::Registry,
+    /// The namespace this node holds, `None` for the server's own data.
+    pub namespace: Option<String>,
+    /// Namespaces served under `/api/v1/db/<name>`, each a node of its own.
+    pub namespaces: std::collections::BTreeMap<String, Arc<AppState>>,
 }
 
-async fn flush_once(state: Arc<AppState>) {
-    let chunk_name = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-        Ok(dur) => dur.as_secs().to_string(),
-        Err(_) => "0".to_string(),
-    };
+/// Persist one station's flushed observations as a raw chunk plus its rollups.
+/// Returns false if the raw chunk could not be written.
+async fn flush_station(state: &AppState, station_id: &str, chunk_name: &str, obs: &[storage::memtable::Observation]) -> bool {
+    let started = std::time::Instant::now();
+    if let Err(e) = state.chunk_store.write_chunk(station_id, chunk_name, obs).await {
+        tracing::error!(station_id, "flush error: {}", e);
+        state.metrics.flush_errors.inc_by(1);
+        return false;
+    }
+    if let Err(e) = state.rollups.write_chunk(station_id, chunk_name, obs).await {
+        tracing::error!(station_id, "rollup error: {}", e);
+    }
+    state.metrics.flushes.inc_by(1);
+    state.metrics.flush_duration.observe(started.elapsed());
+    true
+}
+
+/// Persist several low-volume stations' observations as one packed chunk.
+async fn flush_packed(state: &AppState, chunk_name: &str, obs: Vec<storage::memtable::Observation>) -> bool {
+    let started = std::time::Instant::now();
+    if let Err(e) = state.chunk_store.write_packed(chunk_name, &obs).await {
+        tracing::error!(chunk_name, "flush error: {}", e);
+        state.metrics.flush_errors.inc_by(1);
+        return false;
+    }
+    if let Err(e) = state.rollups.write_packed(chunk_name, &obs).await {
+        tracing::error!(chunk_name, "rollup error: {}", e);
+    }
+    state.metrics.flushes.inc_by(1);
+    state.metrics.flush_duration.observe(started.elapsed());
+    true
+}
+
+/// A drained MemTable buffer, the pin on the WAL segments holding it and
+/// when it was drained (epoch milliseconds).
+type FlushItem = (Vec<(String, Vec<storage::memtable::Observation>)>, Option<storage::wal::SegmentPin>, i64);
 
-    // take ownership of memtable buffer
-    let buffer = {
-        let mut mt = state.memtable.lock().await;
-        if mt.buffer.is_empty() {
-            return;
+/// Drop the rows of a drained buffer that range deletes issued since the
+/// drain cover; the MemTable no longer held them when they were issued.
+pub(crate) async fn mask_drained(state: &AppState, buf: &mut [(String, Vec<storage::memtable::Observation>)], drained_at: i64) {
+    let tombstones = state.chunk_store.tombstones(None).await;
+    if tombstones.is_empty() {
+        return;
+    }
+    for (_, rows) in buf.iter_mut() {
+        storage::tombstone::mask(rows, drained_at, &tombstones);
+    }
+}
+
+/// Flush one drained MemTable buffer. Stations with fewer than `pack_below`
+/// rows share a packed chunk when there are at least two of them. Returns
+/// false if any rows could not be written.
+pub(crate) async fn flush_buffer(
+    state: &AppState,
+    chunk_name: &str,
+    buf: Vec<(String, Vec<storage::memtable::Observation>)>,
+    pack_below: usize,
+) -> bool {
+    let (small, large): (Vec<_>, Vec<_>) = buf.into_iter().partition(|(_, obs)| obs.len() < pack_below);
+    let mut ok = true;
+    for (station_id, obs_vec) in large {
+        ok &= flush_station(state, &station_id, chunk_name, &obs_vec).await;
+    }
+    ok & match small.len() {
+        0 => true,
+        1 => flush_station(state, &small[0].0, chunk_name, &small[0].1).await,
+        _ => flush_packed(state, chunk_name, small.into_iter().flat_map(|(_, obs)| obs).collect()).await,
+    }
+}
+
+/// Write batches a live subscriber may fall behind by before it starts skipping.
+const LIVE_QUEUE: usize = 1024;
+
+impl AppState {
+    /// Open the storage under `opts.data_dir` (and the WAL and chunk
+    /// directories, if placed elsewhere), creating it unless read-only, and
+    /// that of every configured namespace.
+    pub async fn open(opts: &Config) -> anyhow::Result<Self> {
+        namespace::validate(opts)?;
+        let mut state = Self::open_node(opts, None).await?;
+        for ns in &opts.namespaces {
+            let node = Self::open_node(&ns.node_config(opts), Some((&ns.name, &state))).await?;
+            state.namespaces.insert(ns.name.clone(), Arc::new(node));
         }
-        std::mem::take(&mut mt.buffer)
-    };
+        if let (Some(monitor), false) = (&opts.monitor, opts.read_only) {
+            let mut node = Self::open_node(&monitor.namespace().node_config(opts), Some((monitor::NAMESPACE, &state))).await?;
+            // its own counters, so writing the samples does not count itself
+            node.metrics = Default::default();
+            state.namespaces.insert(monitor::NAMESPACE.to_string(), Arc::new(node));
+        }
+        Ok(state)
+    }
+
+    /// Open one node's storage; a namespace shares the server's runtimes,
+    /// metrics and SLO tracker.
+    async fn open_node(opts: &Config, namespace: Option<(&str, &AppState)>) -> anyhow::Result<Self> {
+        let data_dir = opts.data_dir.clone();
+        let chunk_dir = storage::chunk_store::locate(&data_dir, opts.chunk_dir.as_deref())?;
+        let (dir_lock, wal, rollups) = if opts.read_only {
+            if !data_dir.is_dir() {
+                anyhow::bail!("data directory {} does not exist", data_dir.display());
+            }
+            (None, None, storage::RollupStore::open_read_only(data_dir.clone()))
+        } else {
+            tokio::fs::create_dir_all(&data_dir).await?;
+            (
+                Some(storage::DirLock::acquire(&data_dir)?),
+                Some(Arc::new(storage::WAL::open(opts.wal_segments(), &opts.wal).await?)),
+                storage::RollupStore::new(data_dir.clone())?,
+            )
+        };
+        let mut tiers = None;
+        let chunk_store = match (&opts.object_store, &opts.tiering) {
+            (Some(cfg), Some(policy)) => {
+                let cold = Arc::new(storage::backend::S3Backend::new(cfg.clone())?);
+                let backend = Arc::new(storage::tiering::TieredBackend::new(chunk_dir.clone(), cold, policy, &data_dir)?);
+                tiers = Some(backend.clone());
+                storage::ChunkStore::open_backend(chunk_dir, backend, opts.integrity, opts.read_only).await?
+            }
+            (Some(cfg), None) => {
+                let backend = Arc::new(storage::backend::S3Backend::new(cfg.clone())?);
+                storage::ChunkStore::open_backend(chunk_dir, backend, opts.integrity, opts.read_only).await?
+            }
+            (None, Some(_)) => anyhow::bail!("tiering needs an [object_store] for the cold tier"),
+            (None, None) if opts.read_only => storage::ChunkStore::open_read_only(chunk_dir, opts.integrity)?,
+            (None, None) => storage::ChunkStore::new(chunk_dir, opts.integrity)?,
+        }
+        .with_format(opts.chunk_format);
+        let router = match &opts.routing {
+            Some(cfg) => {
+                let dir = (!opts.read_only).then_some(data_dir.as_path());
+                Some(Arc::new(ingest::routing::TenantRouter::new(cfg.clone(), dir)?))
+            }
+            None => None,
+        };
+        let export_dir = opts.export.dir.clone().or_else(|| (!opts.read_only).then(|| data_dir.join("exports")));
+        let exports = export::Exports::open(export_dir, opts.export.clone(), namespace.map(|(name, _)| name))?;
+        let stations = stations::Registry::open(&data_dir, opts.read_only)?;
+        let alerts = opts.alerts.clone().map(|cfg| alerts::Alerts::open(cfg, &data_dir, opts.read_only)).transpose()?;
+        let replication = replication::Replication::open(&data_dir, wal.as_deref(), opts.replication.clone())?;
+        if opts.mirror.is_some() && !opts.server.enabled(config::Surface::Admin) {
+            anyhow::bail!("mirroring needs the admin API: peers post to /api/v1/admin/mirror/apply");
+        }
+        let mirror = match (&opts.mirror, opts.read_only) {
+            (Some(cfg), false) => Some(Arc::new(mirror::Mirror::open(&data_dir, cfg.clone()).await?)),
+            _ => None,
+        };
+        // rows still only in the WAL were not flushed before the last stop
+        let mut memtable = storage::MemTable::new();
+        let mut last_values = storage::LastValues::new();
+        if let Some(wal) = &wal {
+            let replay = wal.replay().await?;
+            let report = &replay.report;
+            if let Some(c) = &report.corruption {
+                tracing::warn!(
+                    records = report.records,
+                    segments = report.segments.len(),
+                    skipped_bytes = report.skipped_bytes,
+                    truncated_bytes = report.truncated_bytes,
+                    "WAL replay recovered around damage, the first in segment {} at offset {} ({})",
+                    c.segment,
+                    c.offset,
+                    c.reason
+                );
+            } else if report.records > 0 {
+                tracing::info!("replayed {} records from {} WAL segments", report.records, report.segments.len());
+            }
+            if let Some(&first) = report.segments.first() {
+                for o in replay.observations {
+                    last_values.observe(&o);
+                    memtable.insert(o);
+                }
+                memtable.retain_pin(wal.pin(first));
+            }
+            // deletes logged after the last flush still have to mask the chunks
+            for t in replay.tombstones {
+                chunk_store.add_tombstone(t).await?;
+            }
+        }
+        Ok(Self {
+            config: opts.clone(),
+            data_dir,
+            memtable: Arc::new(Mutex::new(memtable)),
+            last_values: Arc::new(Mutex::new(last_values)),
+            wal,
+            dir_lock,
+            chunk_store: Arc::new(chunk_store),
+            rollups: Arc::new(rollups),
+            retention: opts.retention.clone(),
+            archive: opts.archive.clone(),
+            compaction: opts.compaction.clone(),
+            router,
+            runtimes: match namespace {
+                Some((_, server)) => server.runtimes.clone(),
+                None => Arc::new(runtime::Runtimes::build(&opts.runtime, tokio::runtime::Handle::current())?),
+            },
+            metrics: namespace.map_or_else(Default::default, |(_, server)| server.metrics.clone()),
+            slo: namespace.map_or_else(|| Arc::new(slo::SloTracker::new(opts.slo.clone())), |(_, server)| server.slo.clone()),
+            health: health::HealthTracker::new(opts.health.clone()),
+            qc: opts.qc.clone().map(ingest::qc::Qc::new),
+            alerts,
+            live: tokio::sync::broadcast::channel(LIVE_QUEUE).0,
+            auth: opts.auth.clone(),
+            rate_limiter: opts.rate_limit.clone().map(api::rate_limit::RateLimiter::new),
+            rebuild: Mutex::new(None),
+            exports,
+            stations,
+            tiers,
+            replication,
+            mirror,
+            forecasters: forecast::Registry::default(),
+            namespace: namespace.map(|(name, _)| name.to_string()),
+            namespaces: Default::default(),
+        })
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.wal.is_none()
+    }
+
+    /// Why client writes are refused, if they are: a read-only server, a
+    /// replication follower, or a standby region waiting to be promoted.
+    pub fn write_refusal(&self) -> Option<&'static str> {
+        if self.read_only() {
+            Some("server is read-only")
+        } else if self.replication.is_follower() {
+            Some("node is a replication follower; write to the leader")
+        } else if self.mirror.as_ref().is_some_and(|m| m.is_standby()) {
+            Some("region is a mirror standby; write to the active region or promote this one")
+        } else {
+            None
+        }
+    }
 
-    for (station_id, obs_vec) in buffer.into_iter() {
-        let _ = state.chunk_store.write_chunk(&station_id, &chunk_name, &obs_vec).await;
+    /// Gauges for `/metrics` that are read off the current state.
+    pub async fn metrics_snapshot(&self) -> metrics::Snapshot {
+        let manifest = self.chunk_store.manifest().await;
+        let mt = self.memtable.lock().await;
+        metrics::Snapshot {
+            chunks: manifest.chunks.len(),
+            chunk_bytes: manifest.chunks.values().map(|c| c.bytes).sum(),
+            memtable_rows: mt.buffer.values().map(Vec::len).sum(),
+            memtable_stations: mt.buffer.len(),
+        }
     }
 }
 
-pub async fn run_server() -> anyhow::Result<()> {
-    let data_dir = std::path::PathBuf::from("data");
-    tokio::fs::create_dir_all(&data_dir).await?;
-    let wal = storage::WAL::open(data_dir.join("wal.log")).await?;
-    let memtable = storage::MemTable::new();
-    let chunk_store = storage::ChunkStore::new(data_dir.clone())?;
-    let state = Arc::new(AppState {
-        memtable: Arc::new(Mutex::new(memtable)),
-        wal: Arc::new(wal),
-        chunk_store: Arc::new(chunk_store),
-    });
+/// A node's flush pipeline and the tasks that feed it; shutdown hands the
+/// rest of the MemTable to the flush worker once the writers are done.
+pub(crate) struct Node {
+    state: Arc<AppState>,
+    flush_tx: tokio::sync::mpsc::Sender<FlushItem>,
+    flush_worker: Option<tokio::task::JoinHandle<()>>,
+    /// tasks that may still put rows into the MemTable; shutdown waits for
+    /// them before the final flush
+    writers: Vec<tokio::task::JoinHandle<()>>,
+}
 
-    // bounded flush queue (backpressure) - each item is a vector of (station_id, observations)
-    let (flush_tx, mut flush_rx) = tokio::sync::mpsc::channel::<Vec<(String, Vec<storage::memtable::Observation>)>>(2);
+/// Start the flush pipeline and storage workers of the server's own data or
+/// of a namespace, in the server or an embedded database.
+pub(crate) fn start_node(state: Arc<AppState>, shutdown_tx: &tokio::sync::broadcast::Sender<()>) -> Node {
+    let cfg = state.config.clone();
 
-    // broadcast channel for shutdown signaling
-    let (shutdown_tx, _) = tokio::sync::broadcast::channel::<()>(1);
+    // bounded flush queue (backpressure) - each item is a vector of (station_id, observations)
+    // and the pin keeping their WAL segments until they are written
+    let (flush_tx, flush_rx) = tokio::sync::mpsc::channel::<FlushItem>(cfg.flush.queue_size.max(1));
 
-    // flush worker: consumes queued buffers and writes them sequentially
-    {
-        let cs = state.chunk_store.clone();
+    // flush worker: consumes queued buffers and writes them sequentially; it
+    // exits once every sender is gone and the queue is empty
+    let flush_worker = if !cfg.read_only {
+        let s = state.clone();
         let mut rx = flush_rx;
-        let mut shutdown_sub = shutdown_tx.subscribe();
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    biased;
-                    _ = shutdown_sub.recv() => {
-                        // drain remaining items then exit
-                        while let Ok(buf) = rx.try_recv() {
-                            for (station_id, obs_vec) in buf {
-                                let _ = cs.write_chunk(&station_id, "shutdown", &obs_vec).await;
-                            }
-                        }
-                        break;
+        let pack_below = cfg.flush.pack_below_rows;
+        Some(state.runtimes.flush.spawn(async move {
+            let mut last = 0;
+            // pins of buffers that failed to flush: their WAL segments stay for the next replay
+            let mut failed = Vec::new();
+            while let Some((mut buf, pin, drained_at)) = rx.recv().await {
+                s.metrics.flush_queue_depth.add(-1);
+                mask_drained(&s, &mut buf, drained_at).await;
+                // millisecond names, bumped so back-to-back flushes never share one
+                let ts = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or(0)
+                    .max(last + 1);
+                last = ts;
+                if !flush_buffer(&s, &format!("flush-{}", ts), buf, pack_below).await {
+                    if let Some(pin) = pin {
+                        tracing::warn!("keeping WAL segments from {} on for replay after a failed flush", pin.segment());
+                        failed.push(pin);
                     }
-                    Some(buf) = rx.recv() => {
-                        for (station_id, obs_vec) in buf {
-                            let ts = std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .map(|d| d.as_secs())
-                                .unwrap_or(0);
-                            let _ = cs.write_chunk(&station_id, &format!("flush-{}", ts), &obs_vec).await;
-                        }
+                    continue;
+                }
+                drop(pin);
+                if let Some(wal) = &s.wal {
+                    if let Err(e) = wal.remove_flushed().await {
+                        tracing::error!("removing flushed WAL segments: {}", e);
                     }
                 }
             }
-        });
-    }
+        }))
+    } else {
+        None
+    };
+
+    // tasks that may still put rows into the MemTable; shutdown waits for them
+    // before the final flush
+    let mut writers = Vec::new();
 
     // periodic scheduler: extract memtable and enqueue for background flush
-    {
+    if !cfg.read_only {
         let s = state.clone();
         let tx = flush_tx.clone();
-        let shutdown_sub = shutdown_tx.clone();
-        tokio::spawn(async move {
+        let flush = cfg.flush.clone();
+        let mut shutdown_sub = shutdown_tx.subscribe();
+        // a buffer it fails to enqueue goes back into the MemTable
+        writers.push(state.runtimes.flush.spawn(async move {
             loop {
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                // take buffer
-                let buffer = {
+                tokio::select! {
+                    _ = shutdown_sub.recv() => break,
+                    _ = tokio::time::sleep(flush.interval()) => {}
+                }
+                // take buffer, and start a new WAL segment for the rows that follow
+                let (buffer, pin, drained_at) = {
                     let mut mt = s.memtable.lock().await;
                     if mt.buffer.is_empty() { continue; }
-                    std::mem::take(&mut mt.buffer)
+                    let (buffer, pin) = mt.drain();
+                    (buffer, pin, chrono::Utc::now().timestamp_millis())
                 };
+                if let Some(wal) = &s.wal {
+                    if let Err(e) = wal.rotate().await {
+                        tracing::error!("rotating the WAL: {}", e);
+                    }
+                }
 
                 // convert into sendable vector
                 let mut to_send = Vec::with_capacity(buffer.len());
@@ -101,38 +434,274 @@ pub async fn run_server() -> anyhow::Result<()> {
                     to_send.push((k, v));
                 }
 
-                // try send without blocking; if full, wait up to 2s then give up and reinsert
-                match tx.try_send(to_send) {
-                    Ok(_) => {}
-                    Err(tokio::sync::mpsc::error::TrySendError::Full(buf)) => {
-                        let send_fut = tx.send(buf);
-                        match tokio::time::timeout(std::time::Duration::from_secs(2), send_fut).await {
-                            Ok(Ok(_)) => {}
-                            _ => {
+                // try send without blocking; if full, wait a little then give up and reinsert
+                match tx.try_send((to_send, pin, drained_at)) {
+                    Ok(_) => s.metrics.flush_queue_depth.add(1),
+                    Err(tokio::sync::mpsc::error::TrySendError::Full(item)) => {
+                        use tokio::sync::mpsc::error::SendTimeoutError;
+                        match tx.send_timeout(item, flush.enqueue_timeout()).await {
+                            Ok(_) => s.metrics.flush_queue_depth.add(1),
+                            Err(SendTimeoutError::Timeout((mut buf, pin, _))) | Err(SendTimeoutError::Closed((mut buf, pin, _))) => {
                                 // backpressure: reinsert observations into memtable to avoid data loss
+                                mask_drained(&s, &mut buf, drained_at).await;
                                 let mut mt = s.memtable.lock().await;
                                 for (k, v) in buf {
                                     mt.buffer.entry(k).or_default().extend(v);
                                 }
+                                if let Some(pin) = pin {
+                                    mt.retain_pin(pin);
+                                }
                             }
                         }
                     }
                     Err(_) => {}
                 }
-                // loop continues until shutdown signal
+            }
+        }));
+    }
+
+    // retention worker: periodically deletes chunks older than the configured max age
+    let tenant_retention = state.router.as_ref().is_some_and(|r| r.has_retention_overrides());
+    if !cfg.read_only && (state.retention.expires_anything() || tenant_retention) {
+        let s = state.clone();
+        let mut shutdown_sub = shutdown_tx.subscribe();
+        state.runtimes.compaction.spawn(async move {
+            let mut ticker = tokio::time::interval(s.retention.check_interval());
+            loop {
+                tokio::select! {
+                    _ = shutdown_sub.recv() => break,
+                    _ = ticker.tick() => {
+                        match storage::retention::enforce(&s.chunk_store, &s.retention, s.router.as_deref(), chrono::Utc::now()).await {
+                            Ok(deleted) if !deleted.is_empty() => {
+                                tracing::info!("retention: deleted {} expired chunks", deleted.len());
+                            }
+                            Ok(_) => {}
+                            Err(e) => tracing::error!("retention error: {}", e),
+                        }
+                    }
+                }
             }
         });
     }
 
+    // archive compaction: rewrite old chunks into compressed per-year archives
+    if let (false, Some(policy)) = (cfg.read_only, state.archive.clone()) {
+        let s = state.clone();
+        let mut shutdown_sub = shutdown_tx.subscribe();
+        state.runtimes.compaction.spawn(async move {
+            let mut ticker = tokio::time::interval(policy.check_interval());
+            loop {
+                tokio::select! {
+                    _ = shutdown_sub.recv() => break,
+                    _ = ticker.tick() => {
+                        match storage::archive::compact(&s.chunk_store, &policy, chrono::Utc::now()).await {
+                            Ok(written) if !written.is_empty() => {
+                                tracing::info!("archive: wrote {} yearly archive chunks", written.len());
+                            }
+                            Ok(_) => {}
+                            Err(e) => tracing::error!("archive compaction error: {}", e),
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // small-file compaction: merge the chunks of stations scoring above the fragmentation limit
+    if let (false, Some(cfg)) = (cfg.read_only, state.compaction.clone()) {
+        let s = state.clone();
+        let mut shutdown_sub = shutdown_tx.subscribe();
+        state.runtimes.compaction.spawn(async move {
+            let mut ticker = tokio::time::interval(cfg.check_interval());
+            loop {
+                tokio::select! {
+                    _ = shutdown_sub.recv() => break,
+                    _ = ticker.tick() => {
+                        if let Err(e) = storage::fragmentation::compact(&s.chunk_store, &cfg).await {
+                            tracing::error!("fragmentation compaction error: {}", e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // tiering: move chunks past the hot window to object storage
+    if let (false, Some(tiers), Some(policy)) = (cfg.read_only, state.tiers.clone(), cfg.tiering.clone()) {
+        state.runtimes.compaction.spawn(storage::tiering::run(state.chunk_store.clone(), tiers, policy, shutdown_tx.subscribe()));
+    }
+
+    // range deletes: remove masked rows from the chunk files
+    if !cfg.read_only {
+        state.runtimes.compaction.spawn(delete::run(state.clone(), shutdown_tx.subscribe()));
+    }
+
+    // replica: pick up chunks the directory's owner flushes
+    if let Some(cfg) = cfg.replica.clone() {
+        state.runtimes.compaction.spawn(replica::run(state.clone(), cfg, shutdown_tx.subscribe()));
+    }
+
+    Node { state, flush_tx, flush_worker, writers }
+}
+
+impl Node {
+    /// Wait for the writers, then flush what is left and wait for the flush worker.
+    pub(crate) async fn stop(self) {
+        let Node { state, flush_tx, flush_worker, writers } = self;
+        for task in writers {
+            let _ = task.await;
+        }
+
+        // nothing writes any more: hand what is left in the MemTable to the flush
+        // worker, close the queue and wait for it to be written out
+        let (buffer, pin) = state.memtable.lock().await.drain();
+        let drained_at = chrono::Utc::now().timestamp_millis();
+        if let Some(worker) = flush_worker {
+            if !buffer.is_empty() {
+                let rows: usize = buffer.values().map(Vec::len).sum();
+                tracing::info!("flushing {} buffered observations from {} stations", rows, buffer.len());
+                if let Some(wal) = &state.wal {
+                    if let Err(e) = wal.rotate().await {
+                        tracing::error!("rotating the WAL: {}", e);
+                    }
+                }
+                if flush_tx.send((buffer.into_iter().collect(), pin, drained_at)).await.is_ok() {
+                    state.metrics.flush_queue_depth.add(1);
+                }
+            }
+            drop(flush_tx);
+            if let Err(e) = worker.await {
+                tracing::error!("flush worker failed during shutdown: {}", e);
+            }
+        }
+    }
+}
+
+pub async fn run_server(opts: Config) -> anyhow::Result<()> {
+    let continuous_queries = continuous::compile(&opts.continuous_queries)?;
+    let state = Arc::new(AppState::open(&opts).await?);
+    if opts.replica.is_some() {
+        tracing::info!("serving {} as a read-only replica", opts.data_dir.display());
+    } else if opts.read_only {
+        tracing::info!("serving {} read-only", opts.data_dir.display());
+    }
+    if !state.namespaces.is_empty() {
+        tracing::info!("serving namespaces {}", state.namespaces.keys().cloned().collect::<Vec<_>>().join(", "));
+    }
+
+    // broadcast channel for shutdown signaling
+    let (shutdown_tx, _) = tokio::sync::broadcast::channel::<()>(1);
+
+    let mut node = start_node(state.clone(), &shutdown_tx);
+    let namespaces: Vec<Node> = state.namespaces.values().map(|ns| start_node(ns.clone(), &shutdown_tx)).collect();
+    let writers = &mut node.writers;
+
+    // cross-region mirroring: ship local writes to the peer regions
+    if let Some(m) = &state.mirror {
+        tracing::info!(region = m.region(), standby = m.is_standby(), "mirroring to {} peer regions", opts.mirror.as_ref().map_or(0, |c| c.peers.len()));
+        state.runtimes.ingest.spawn(mirror::run(state.clone(), shutdown_tx.subscribe()));
+    }
+
+    // replication: follow the leader's WAL
+    if let (false, Some(cfg)) = (opts.read_only, &opts.replication) {
+        tracing::info!(name = cfg.name, "following the leader at {}", cfg.leader);
+        state.runtimes.ingest.spawn(replication::run(state.clone(), shutdown_tx.subscribe()));
+    }
+
+    // self-monitoring: sample the metrics into the _internal namespace
+    if let (false, Some(cfg)) = (opts.read_only, opts.monitor.clone()) {
+        writers.push(state.runtimes.compaction.spawn(monitor::run(state.clone(), cfg, shutdown_tx.subscribe())));
+    }
+
+    // latency SLO burn-rate alerting, when a threshold is configured
+    state.runtimes.compaction.spawn(slo::watch(state.slo.clone(), shutdown_tx.subscribe()));
+
+    // station health scores for /metrics
+    state.runtimes.query.spawn(health::run(state.clone(), shutdown_tx.subscribe()));
+
+    // alert rules over the stored readings
+    if let Some(alerts) = &state.alerts {
+        tracing::info!("evaluating {} alert rules every {:?}", opts.alerts.as_ref().map_or(0, |c| c.rules.len()), alerts.interval());
+        state.runtimes.query.spawn(alerts::run(state.clone(), shutdown_tx.subscribe()));
+    }
+
+    // continuous queries: scheduled aggregations written back as series
+    if !opts.read_only {
+        for cq in continuous_queries {
+            tracing::info!(name = cq.cq.name, "running continuous query every {:?}", cq.every());
+            writers.push(state.runtimes.query.spawn(continuous::run(state.clone(), cq, shutdown_tx.subscribe())));
+        }
+    }
+
+    // file-drop ingest: import CSV/NDJSON files dropped into a watched directory
+    if !opts.read_only {
+        if let Some(cfg) = opts.file_drop.clone() {
+            tracing::info!("watching {} for dropped files", cfg.watch_dir.display());
+            writers.push(state.runtimes.ingest.spawn(ingest::file_drop::run(state.clone(), cfg, shutdown_tx.subscribe())));
+        }
+    }
+
+    // pull-based scraping of stations that expose readings over HTTP
+    if !opts.read_only {
+        if let Some(cfg) = opts.scrape.clone() {
+            tracing::info!("scraping {} remote stations", cfg.targets.len());
+            writers.push(state.runtimes.ingest.spawn(ingest::scraper::run(state.clone(), cfg, shutdown_tx.clone())));
+        }
+    }
+
+    // Kafka ingest: consume observations from the configured topics
+    if !opts.read_only {
+        if let Some(cfg) = opts.kafka.clone() {
+            tracing::info!(group = cfg.group, "consuming {} from {}", cfg.topics.join(", "), cfg.brokers.join(","));
+            let consumer = ingest::kafka::consumer(&cfg)?;
+            writers.push(state.runtimes.ingest.spawn(ingest::kafka::run(state.clone(), consumer, cfg, shutdown_tx.subscribe())));
+        }
+    }
+
+    // UDP ingest: best-effort datagrams from constrained devices
+    if !opts.read_only {
+        if let Some(cfg) = opts.udp.clone() {
+            let socket = ingest::udp::bind(&cfg)?;
+            tracing::info!("listening for {:?} datagrams on udp://{}", cfg.format, socket.local_addr()?);
+            writers.push(state.runtimes.ingest.spawn(ingest::udp::run(state.clone(), socket, cfg, shutdown_tx.subscribe())));
+        }
+    }
+
+    // demo mode: synthetic readings from virtual stations
+    if let Some(cfg) = opts.demo.clone() {
+        if opts.read_only {
+            tracing::warn!("demo mode needs a writable data directory; not generating data");
+        } else {
+            tracing::info!("demo mode: generating readings for {} virtual stations", cfg.stations);
+            writers.push(state.runtimes.ingest.spawn(ingest::demo::run(state.clone(), cfg, shutdown_tx.subscribe())));
+        }
+    }
+
     // run HTTP server in background; it will be shut down via broadcast signal
     let http_state = state.clone();
     let http_shutdown = shutdown_tx.clone();
-    tokio::spawn(async move {
-        api::http::run(http_state, http_shutdown).await;
-    });
+    let listeners = api::http::bind(&opts.server)?;
+    let tls = match &opts.server.tls {
+        Some(cfg) => {
+            let rustls = api::tls::load(cfg).await?;
+            state.runtimes.ingest.spawn(api::tls::reload_on_sighup(rustls.clone(), cfg.clone(), shutdown_tx.clone()));
+            Some(rustls)
+        }
+        None => None,
+    };
+    writers.push(state.runtimes.ingest.spawn(async move {
+        api::http::run(http_state, listeners, tls, http_shutdown).await;
+    }));
 
     // wait for CTRL-C then signal shutdown
     tokio::signal::ctrl_c().await?;
+    tracing::info!("shutting down: finishing in-flight writes");
     let _ = shutdown_tx.send(());
+    // the server's node waits for the HTTP server, after which nothing writes to the namespaces either
+    node.stop().await;
+    for ns in namespaces {
+        ns.stop().await;
+    }
+    tracing::info!("shutdown complete");
     Ok(())
 }
@@ -0,0 +1,224 @@
+// Logging setup: plain text or JSON lines, per-module level filters, and
+// optional file output rotated by size and/or time period.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use anyhow::{bail, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPeriod {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl RotationPeriod {
+    fn secs(self) -> Option<i64> {
+        match self {
+            RotationPeriod::Never => None,
+            RotationPeriod::Hourly => Some(3600),
+            RotationPeriod::Daily => Some(86_400),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// `EnvFilter` directives, e.g. `info,skypulsedb::ingest=debug`.
+    pub filter: String,
+    /// Log to this file instead of stdout.
+    pub file: Option<PathBuf>,
+    /// Rotate the file once it would grow past this many bytes.
+    #[serde(alias = "max_size", deserialize_with = "crate::units::opt_bytes")]
+    pub max_bytes: Option<u64>,
+    pub rotation: RotationPeriod,
+    /// Rotated files to keep; older ones are deleted.
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            filter: "info".to_string(),
+            file: None,
+            max_bytes: None,
+            rotation: RotationPeriod::Never,
+            max_files: 7,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Override with `SKYPULSE_LOG` (filter, falling back to `RUST_LOG`),
+    /// `SKYPULSE_LOG_FORMAT` (text/json), `SKYPULSE_LOG_FILE`,
+    /// `SKYPULSE_LOG_MAX_BYTES`, `SKYPULSE_LOG_ROTATION` (never/hourly/daily)
+    /// and `SKYPULSE_LOG_MAX_FILES`.
+    pub fn apply_env(&mut self) -> Result<()> {
+        let cfg = self;
+        if let Ok(f) = std::env::var("SKYPULSE_LOG").or_else(|_| std::env::var("RUST_LOG")) {
+            cfg.filter = f;
+        }
+        if let Ok(f) = std::env::var("SKYPULSE_LOG_FORMAT") {
+            cfg.format = match f.as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                other => bail!("unknown log format '{}', expected text or json", other),
+            };
+        }
+        if let Ok(f) = std::env::var("SKYPULSE_LOG_FILE") {
+            cfg.file = Some(PathBuf::from(f));
+        }
+        if let Some(bytes) = crate::units::env_size("SKYPULSE_LOG_MAX_BYTES")? {
+            cfg.max_bytes = Some(bytes);
+        }
+        if let Ok(r) = std::env::var("SKYPULSE_LOG_ROTATION") {
+            cfg.rotation = match r.as_str() {
+                "never" => RotationPeriod::Never,
+                "hourly" => RotationPeriod::Hourly,
+                "daily" => RotationPeriod::Daily,
+                other => bail!("unknown log rotation '{}', expected never, hourly or daily", other),
+            };
+        }
+        if let Ok(v) = std::env::var("SKYPULSE_LOG_MAX_FILES") {
+            cfg.max_files = v.parse()?;
+        }
+        Ok(())
+    }
+}
+
+/// Install the global subscriber.
+pub fn init(cfg: &LoggingConfig) -> Result<()> {
+    let filter = EnvFilter::try_new(&cfg.filter)?;
+    let (writer, ansi) = match &cfg.file {
+        Some(path) => {
+            let file = RotatingFile::open(path.clone(), cfg.max_bytes, cfg.rotation, cfg.max_files)?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(io::stdout), true),
+    };
+    let layer = match cfg.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_writer(writer).boxed(),
+    };
+    tracing_subscriber::registry().with(filter).with(layer).try_init()?;
+    Ok(())
+}
+
+/// A log file that is renamed to `<name>.<timestamp>` when it exceeds its
+/// size limit or its rotation period ends, keeping the newest `max_files`.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    period: RotationPeriod,
+    max_files: usize,
+    file: File,
+    size: u64,
+    period_index: i64,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_bytes: Option<u64>, period: RotationPeriod, max_files: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_bytes, period, max_files, file, size, period_index: Self::current_period(period) })
+    }
+
+    fn current_period(period: RotationPeriod) -> i64 {
+        period.secs().map_or(0, |s| Utc::now().timestamp().div_euclid(s))
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        let over_size = self.max_bytes.is_some_and(|max| self.size > 0 && self.size + incoming as u64 > max);
+        over_size || Self::current_period(self.period) != self.period_index
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let name = self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let rotated = self.path.with_file_name(format!("{}.{}", name, Utc::now().format("%Y%m%dT%H%M%S%.3f")));
+        std::fs::rename(&self.path, rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.period_index = Self::current_period(self.period);
+        self.prune(&name)
+    }
+
+    fn prune(&self, name: &str) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = format!("{}.", name);
+        let mut rotated: Vec<PathBuf> = std::fs::read_dir(&dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|e| e.path())
+            .collect();
+        // timestamps sort lexically, oldest first
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.max_files);
+        for old in &rotated[..excess] {
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_prunes() {
+        let dir = crate::test_util::TempDir::new("log");
+        let path = dir.join("server.log");
+        let mut f = RotatingFile::open(path.clone(), Some(100), RotationPeriod::Never, 2).unwrap();
+        for _ in 0..5 {
+            f.write_all(&[b'x'; 80]).unwrap();
+            // rotated names carry millisecond timestamps
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        let rotated = std::fs::read_dir(&dir).unwrap().filter(|e| e.as_ref().unwrap().path() != path).count();
+        assert_eq!(rotated, 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 80);
+    }
+}
@@ -1,8 +1,440 @@
-use skypulsedb::run_server;
+use std::io::Write;
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use skypulsedb::{run_server, AppState, Config};
+use skypulsedb::storage::integrity::IntegrityMode;
+use skypulsedb::query::{self, aggregate, Order, RangeQuery, ResolutionChoice};
+
+#[derive(Parser)]
+#[command(name = "skypulsedb", version, about = "Time-series database for weather observations")]
+struct Cli {
+    /// TOML configuration file (defaults to `SKYPULSE_CONFIG`, if set).
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Config profile to layer over the base settings, e.g. dev or prod
+    /// (defaults to `SKYPULSE_PROFILE`, if set).
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Args, Default)]
+struct DataArgs {
+    /// Data directory (live data dir or a snapshot/backup copy); overrides the config file.
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Open the data directory read-only: no WAL, no flushes, no retention.
+    #[arg(long)]
+    read_only: bool,
+    /// Serve queries only, over a directory another process writes: read-only,
+    /// without the write endpoints, picking up newly flushed chunks.
+    #[arg(long)]
+    replica: bool,
+    /// Refuse to start if the chunk manifest and files disagree.
+    #[arg(long, group = "integrity")]
+    strict: bool,
+    /// Rebuild the manifest from the chunk files if they disagree.
+    #[arg(long, group = "integrity")]
+    repair: bool,
+    /// Log manifest/chunk inconsistencies and continue (default).
+    #[arg(long, group = "integrity")]
+    ignore: bool,
+    /// Feed a few virtual stations with synthetic readings.
+    #[arg(long)]
+    demo: bool,
+    /// Install this snapshot into the data directory before serving; the
+    /// data found there is moved aside.
+    #[arg(long, value_name = "SNAPSHOT_DIR")]
+    restore: Option<PathBuf>,
+}
+
+impl DataArgs {
+    /// Command-line flags take precedence over the config file.
+    fn apply(&self, cfg: &mut Config) {
+        if let Some(dir) = &self.data_dir {
+            cfg.data_dir = dir.clone();
+        }
+        cfg.read_only |= self.read_only;
+        if self.replica {
+            cfg.serve_as_replica();
+        }
+        if self.demo && cfg.demo.is_none() {
+            cfg.demo = Some(Default::default());
+        }
+        if self.strict {
+            cfg.integrity = IntegrityMode::Strict;
+        } else if self.repair {
+            cfg.integrity = IntegrityMode::Repair;
+        } else if self.ignore {
+            cfg.integrity = IntegrityMode::Ignore;
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the HTTP server (default).
+    Serve(DataArgs),
+    /// Run a range query against a data directory (opened read-only) and print JSON.
+    Query {
+        #[arg(long, default_value = "data")]
+        data_dir: PathBuf,
+        #[arg(long)]
+        station_id: String,
+        /// Inclusive start: RFC3339 or relative such as `now-6h`.
+        #[arg(long, allow_hyphen_values = true)]
+        start: Option<String>,
+        /// Exclusive end, like `--start`.
+        #[arg(long, allow_hyphen_values = true)]
+        end: Option<String>,
+        /// raw, 1m, 1h or auto.
+        #[arg(long, default_value = "auto")]
+        resolution: String,
+        /// Comma-separated aggregations (min, max, mean, sum, count, first, last, nulls, missing).
+        #[arg(long)]
+        agg: Option<String>,
+        /// Comma-separated fields to aggregate.
+        #[arg(long)]
+        fields: Option<String>,
+        /// GROUP BY time window such as 5m or 1h.
+        #[arg(long)]
+        interval: Option<String>,
+        /// IANA time zone the --interval windows align to (UTC by default).
+        #[arg(long, requires = "interval")]
+        tz: Option<String>,
+        /// Emit --interval windows without values: null, previous or linear.
+        #[arg(long, requires = "interval")]
+        fill: Option<String>,
+        /// Units to return values in: metric, imperial and/or field:unit, comma-separated.
+        #[arg(long)]
+        units: Option<String>,
+        /// Latency budget; slower plans fall back to coarser rollups.
+        #[arg(long)]
+        max_latency_ms: Option<u64>,
+        /// asc or desc (most recent first).
+        #[arg(long, default_value = "asc")]
+        order: String,
+        /// Maximum number of rows or buckets to return.
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Compare the chunks of two data directories and report missing or divergent files.
+    Diff { dir_a: PathBuf, dir_b: PathBuf },
+    /// Write a historical range from a data directory (opened read-only) again,
+    /// paced like the original, into a running instance.
+    Replay {
+        #[arg(long, default_value = "data")]
+        data_dir: PathBuf,
+        /// Station to replay; repeat or separate with commas. Every station when omitted.
+        #[arg(long, value_delimiter = ',')]
+        station_id: Vec<String>,
+        /// Inclusive RFC3339 start.
+        #[arg(long)]
+        start: String,
+        /// Exclusive RFC3339 end.
+        #[arg(long)]
+        end: String,
+        /// Playback speed: 1 is real time, 60 plays an hour per minute, 0 sends as fast as possible.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Move the range to start at this RFC3339 instant, or `now`.
+        #[arg(long)]
+        shift_to: Option<String>,
+        /// Station id for the written rows, `{id}` being the original, e.g. `{id}-replay`.
+        #[arg(long)]
+        rename: Option<String>,
+        /// Base URL of the instance to write to; the local server when omitted.
+        #[arg(long)]
+        target: Option<String>,
+        /// Sent as `X-Api-Key` (defaults to `SKYPULSE_REPLAY_API_KEY`, if set);
+        /// needs the write scope on the target.
+        #[arg(long)]
+        api_key: Option<String>,
+        /// Most rows per write request.
+        #[arg(long, default_value_t = 500)]
+        batch_size: usize,
+    },
+    /// Print the rows of a chunk file as NDJSON.
+    Inspect {
+        file: PathBuf,
+        /// Print the chunk's metadata (stations, rows, time range, checksum) instead.
+        #[arg(long)]
+        meta: bool,
+        /// Write the rows to this Parquet file instead.
+        #[arg(long, conflicts_with = "meta")]
+        parquet: Option<PathBuf>,
+    },
+    /// Compact fragmented stations now; stop the server writing the directory first.
+    Compact {
+        #[arg(long, default_value = "data")]
+        data_dir: PathBuf,
+        /// Work on this namespace's data instead of the server's own.
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Merge every station with two or more chunks, whatever its fragmentation score.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Read every chunk file and check it against its checksum footer and the
+    /// manifest; exits with status 1 on a damaged, missing or unlisted file.
+    Verify {
+        #[arg(long, default_value = "data")]
+        data_dir: PathBuf,
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Rewrite damaged chunks with the rows that can still be read, keep
+        /// the originals in `<data-dir>/quarantine` and rebuild the manifest.
+        /// Writes to the data directory; stop the server first.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Write a station's raw rows as CSV (`station_id,time,<field>...`) or Parquet.
+    Export {
+        #[arg(long, default_value = "data")]
+        data_dir: PathBuf,
+        #[arg(long)]
+        namespace: Option<String>,
+        #[arg(long)]
+        station_id: String,
+        /// Inclusive RFC3339 start.
+        #[arg(long)]
+        start: Option<String>,
+        /// Exclusive RFC3339 end.
+        #[arg(long)]
+        end: Option<String>,
+        /// Comma-separated columns; every field the range holds when omitted.
+        #[arg(long, value_delimiter = ',')]
+        fields: Vec<String>,
+        /// File to write; standard output when omitted.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// `csv` or `parquet`; parquet when the output file ends in `.parquet`, csv otherwise.
+        #[arg(long)]
+        format: Option<String>,
+    },
+    /// Load a CSV with a header row into the data directory, as
+    /// `/api/v1/import/csv` does; stop the server writing the directory first.
+    /// Prints the report and exits with status 1 when any row was rejected.
+    Import {
+        file: PathBuf,
+        #[arg(long, default_value = "data")]
+        data_dir: PathBuf,
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Station of rows without a station column.
+        #[arg(long)]
+        station_id: Option<String>,
+        /// Column holding the station id [default: station_id].
+        #[arg(long)]
+        station_column: Option<String>,
+        /// Column holding the time [default: time].
+        #[arg(long)]
+        time_column: Option<String>,
+        /// chrono format of the time column (read as UTC), `unix` or `unix_ms`; RFC3339 when omitted.
+        #[arg(long)]
+        time_format: Option<String>,
+        /// Comma-separated `column:field` renames.
+        #[arg(long)]
+        fields: Option<String>,
+        /// Comma-separated columns stored as tags.
+        #[arg(long, value_delimiter = ',')]
+        tags: Vec<String>,
+        /// Comma-separated columns left out.
+        #[arg(long, value_delimiter = ',')]
+        skip: Vec<String>,
+        /// One character, or `tab` [default: ,].
+        #[arg(long)]
+        delimiter: Option<String>,
+    },
+}
+
+/// The configuration for a command working on one data directory without
+/// the server: `namespace`'s when given, and never the other namespaces.
+fn offline(cfg: Config, data_dir: PathBuf, namespace: Option<&str>, read_only: bool) -> anyhow::Result<Config> {
+    let mut cfg = Config { data_dir, read_only, ..cfg };
+    if let Some(name) = namespace {
+        let ns = if name == skypulsedb::monitor::NAMESPACE {
+            cfg.monitor.clone().unwrap_or_default().namespace()
+        } else {
+            cfg.namespaces.iter().find(|ns| ns.name == name).cloned().ok_or_else(|| anyhow::anyhow!("no namespace '{}' is configured", name))?
+        };
+        cfg = ns.node_config(&cfg);
+    }
+    cfg.namespaces.clear();
+    cfg.monitor = None;
+    Ok(cfg)
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    run_server().await?;
+    let cli = Cli::parse();
+    let mut cfg = Config::load(cli.config.as_deref(), cli.profile.as_deref())?;
+    skypulsedb::logging::init(&cfg.logging)?;
+    let command = cli.command.unwrap_or(Command::Serve(DataArgs::default()));
+    match command {
+        Command::Serve(args) => {
+            args.apply(&mut cfg);
+            if let Some(snapshot) = &args.restore {
+                let restored = skypulsedb::snapshot::restore(&cfg, snapshot).await?;
+                if let Some(dir) = &restored.replaced {
+                    tracing::info!("previous data moved to {}", dir.display());
+                }
+            }
+            run_server(cfg).await?
+        }
+        Command::Query { data_dir, station_id, start, end, resolution, agg, fields, interval, tz, fill, units, max_latency_ms, order, limit } => {
+            let state = AppState::open(&offline(cfg, data_dir, None, true)?).await?;
+            let now = chrono::Utc::now();
+            let q = RangeQuery {
+                station_id,
+                start: start.as_deref().map(|t| query::parse_time_at(t, now)).transpose()?,
+                end: end.as_deref().map(|t| query::parse_time_at(t, now)).transpose()?,
+                resolution: ResolutionChoice::parse(&resolution)?,
+                fields: aggregate::parse_fields(fields.as_deref()),
+                aggregations: aggregate::AggFn::parse_list(agg.as_deref().unwrap_or(""))?,
+                interval_secs: interval.as_deref().map(query::parse_interval).transpose()?,
+                tz: tz.as_deref().map(query::parse_tz).transpose()?,
+                fill: aggregate::Fill::parse(fill.as_deref().unwrap_or("none"))?,
+                units: query::convert::Units::parse(units.as_deref().unwrap_or(""))?,
+                max_latency_ms,
+                order: Order::parse(&order)?,
+                limit,
+                forecast: false,
+            };
+            let result = query::execute(&state, &q).await?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+        Command::Replay { data_dir, station_id, start, end, speed, shift_to, rename, target, api_key, batch_size } => {
+            let target = match target {
+                Some(url) => url,
+                None => {
+                    let mut addr = *cfg.server.bind.first().ok_or_else(|| anyhow::anyhow!("no server.bind address; pass --target"))?;
+                    if addr.ip().is_unspecified() {
+                        addr.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+                    }
+                    let scheme = if cfg.server.tls.is_some() { "https" } else { "http" };
+                    format!("{}://{}", scheme, addr)
+                }
+            };
+            let opts = skypulsedb::replay::ReplayOptions {
+                stations: station_id,
+                start: query::parse_time(&start)?,
+                end: query::parse_time(&end)?,
+                speed,
+                shift_to: match shift_to.as_deref() {
+                    Some("now") => Some(chrono::Utc::now()),
+                    Some(t) => Some(query::parse_time(t)?),
+                    None => None,
+                },
+                rename,
+                batch_size,
+            };
+            let source = std::sync::Arc::new(AppState::open(&offline(cfg, data_dir, None, true)?).await?);
+            let api_key = api_key.or_else(|| std::env::var("SKYPULSE_REPLAY_API_KEY").ok());
+            let mut sink = skypulsedb::replay::HttpSink::new(&target, api_key)?;
+            let report = skypulsedb::replay::replay(source, &opts, &mut sink).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Command::Inspect { file, meta, parquet } => {
+            let dump = skypulsedb::offline::inspect(&file)?;
+            if let Some(path) = parquet {
+                let rows = skypulsedb::columnar::write_parquet(&dump.rows, std::fs::File::create(&path)?)?;
+                eprintln!("wrote {} rows to {}", rows, path.display());
+            } else if meta {
+                println!("{}", serde_json::to_string_pretty(&dump.meta)?);
+            } else {
+                let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                for row in &dump.rows {
+                    serde_json::to_writer(&mut out, row)?;
+                    writeln!(out)?;
+                }
+                out.flush()?;
+            }
+        }
+        Command::Compact { data_dir, namespace, force } => {
+            let cfg = offline(cfg, data_dir, namespace.as_deref(), false)?;
+            let state = AppState::open(&cfg).await?;
+            let compacted = skypulsedb::offline::compact(&state, cfg.compaction.clone(), force).await?;
+            println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "compacted": compacted }))?);
+        }
+        Command::Verify { data_dir, namespace, repair: true } => {
+            let cfg = Config { integrity: IntegrityMode::Ignore, ..offline(cfg, data_dir, namespace.as_deref(), false)? };
+            let report = skypulsedb::offline::repair(&AppState::open(&cfg).await?).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.after.is_clean() {
+                std::process::exit(1);
+            }
+        }
+        Command::Verify { data_dir, namespace, repair: false } => {
+            let cfg = Config { integrity: IntegrityMode::Ignore, ..offline(cfg, data_dir, namespace.as_deref(), true)? };
+            let report = skypulsedb::offline::verify(&AppState::open(&cfg).await?).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.is_clean() {
+                std::process::exit(1);
+            }
+        }
+        Command::Export { data_dir, namespace, station_id, start, end, fields, output, format } => {
+            let parquet = match format.as_deref() {
+                Some("parquet") => true,
+                Some("csv") => false,
+                Some(other) => anyhow::bail!("unknown format '{}'; expected csv or parquet", other),
+                None => output.as_ref().is_some_and(|p| p.extension().is_some_and(|e| e == "parquet")),
+            };
+            let state = std::sync::Arc::new(AppState::open(&offline(cfg, data_dir, namespace.as_deref(), true)?).await?);
+            let start = start.as_deref().map(query::parse_time).transpose()?;
+            let end = end.as_deref().map(query::parse_time).transpose()?;
+            let out: Box<dyn Write + Send> = match &output {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(std::io::stdout()),
+            };
+            let out = std::io::BufWriter::new(out);
+            let rows = if parquet {
+                skypulsedb::offline::export_parquet(state, &station_id, start, end, &fields, out).await?
+            } else {
+                skypulsedb::offline::export_csv(state, &station_id, start, end, &fields, out).await?
+            };
+            eprintln!("exported {} rows of {}", rows, station_id);
+        }
+        Command::Import { file, data_dir, namespace, station_id, station_column, time_column, time_format, fields, tags, skip, delimiter } => {
+            use skypulsedb::ingest::import::{self, CsvImport, CsvMapping};
+            use tokio::io::AsyncReadExt;
+            let mapping = CsvMapping {
+                station_id,
+                station_column,
+                time_column,
+                time_format,
+                fields: fields.as_deref().map(CsvMapping::parse_fields).transpose()?.unwrap_or_default(),
+                tags,
+                skip,
+                delimiter: delimiter.as_deref().map(import::parse_delimiter).transpose()?,
+            };
+            let mut input = tokio::fs::File::open(&file).await.map_err(|e| anyhow::anyhow!("{}: {}", file.display(), e))?;
+            let db = skypulsedb::SkyPulseDb::open(offline(cfg, data_dir, namespace.as_deref(), false)?).await?;
+            let state = db.state();
+            let mut csv = CsvImport::new(&state, mapping, None);
+            let mut buf = vec![0u8; 1 << 20];
+            let report = loop {
+                let n = input.read(&mut buf).await?;
+                let fed = if n == 0 { break csv.finish().await } else { csv.feed(&buf[..n]).await };
+                if let Err(e) = fed {
+                    break Err(e);
+                }
+            };
+            db.close().await;
+            let report = report.map_err(|e| anyhow::anyhow!("{}", e))?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if report.rejected > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Diff { dir_a, dir_b } => {
+            let report = skypulsedb::storage::diff::diff_dirs(&dir_a, &dir_b)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.is_consistent() {
+                std::process::exit(1);
+            }
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,192 @@
+// Internal metrics rendered in the Prometheus text format at `/metrics`.
+// Counters and histograms are updated on the write and flush paths; gauges
+// that can be read off the state (chunk count, MemTable size) are sampled at
+// scrape time.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, n: i64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Latency histogram with fixed upper bounds in seconds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Non-cumulative count per bound, plus one for +Inf.
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(), sum_micros: AtomicU64::new(0) }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let i = self.bounds.iter().position(|b| secs <= *b).unwrap_or(self.bounds.len());
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Observations so far.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Sum of the observed durations.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = self.bounds.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+const WAL_BOUNDS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+const FLUSH_BOUNDS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub writes: Counter,
+    pub writes_rejected: Counter,
+    /// Write requests refused with 429 by the rate limiter.
+    pub rate_limited: Counter,
+    pub wal_append: Histogram,
+    /// Buffers waiting in the flush queue.
+    pub flush_queue_depth: Gauge,
+    pub flushes: Counter,
+    pub flush_errors: Counter,
+    pub flush_duration: Histogram,
+    pub query_spill_bytes: Counter,
+    /// Latest-value lookups answered by the last-value cache, and those that were not.
+    pub last_value_hits: Counter,
+    pub last_value_misses: Counter,
+    /// Datagrams received by the UDP listener, those that held nothing
+    /// usable, and observations dropped because the write queue was full or
+    /// the write failed.
+    pub udp_datagrams: Counter,
+    pub udp_invalid: Counter,
+    pub udp_rejected: Counter,
+    pub udp_dropped: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            writes: Counter::default(),
+            writes_rejected: Counter::default(),
+            rate_limited: Counter::default(),
+            wal_append: Histogram::new(WAL_BOUNDS),
+            flush_queue_depth: Gauge::default(),
+            flushes: Counter::default(),
+            flush_errors: Counter::default(),
+            flush_duration: Histogram::new(FLUSH_BOUNDS),
+            query_spill_bytes: Counter::default(),
+            last_value_hits: Counter::default(),
+            last_value_misses: Counter::default(),
+            udp_datagrams: Counter::default(),
+            udp_invalid: Counter::default(),
+            udp_rejected: Counter::default(),
+            udp_dropped: Counter::default(),
+        }
+    }
+}
+
+/// Values sampled from the state when `/metrics` is scraped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Snapshot {
+    pub chunks: usize,
+    pub chunk_bytes: u64,
+    pub memtable_rows: usize,
+    pub memtable_stations: usize,
+}
+
+fn scalar(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+}
+
+impl Metrics {
+    pub fn render(&self, snap: &Snapshot) -> String {
+        let mut out = String::new();
+        scalar(&mut out, "skypulse_writes_total", "counter", "Observations written.", self.writes.get());
+        scalar(&mut out, "skypulse_writes_rejected_total", "counter", "Observations rejected by validation.", self.writes_rejected.get());
+        scalar(&mut out, "skypulse_rate_limited_total", "counter", "Write requests refused by the rate limiter.", self.rate_limited.get());
+        self.wal_append.render(&mut out, "skypulse_wal_append_seconds", "WAL append latency per write batch.");
+        scalar(&mut out, "skypulse_flush_queue_depth", "gauge", "MemTable buffers waiting to be flushed.", self.flush_queue_depth.get());
+        scalar(&mut out, "skypulse_flushes_total", "counter", "Station flushes completed.", self.flushes.get());
+        scalar(&mut out, "skypulse_flush_errors_total", "counter", "Station flushes that failed.", self.flush_errors.get());
+        self.flush_duration.render(&mut out, "skypulse_flush_duration_seconds", "Time to write one station's chunk and rollups.");
+        scalar(&mut out, "skypulse_query_spill_bytes_total", "counter", "Bytes range scans spilled to temporary files.", self.query_spill_bytes.get());
+        scalar(&mut out, "skypulse_last_value_hits_total", "counter", "Latest-value lookups served from the cache.", self.last_value_hits.get());
+        scalar(&mut out, "skypulse_last_value_misses_total", "counter", "Latest-value lookups that read storage.", self.last_value_misses.get());
+        scalar(&mut out, "skypulse_udp_datagrams_total", "counter", "Datagrams received by the UDP listener.", self.udp_datagrams.get());
+        scalar(&mut out, "skypulse_udp_invalid_total", "counter", "UDP datagrams without a usable observation.", self.udp_invalid.get());
+        scalar(&mut out, "skypulse_udp_rejected_total", "counter", "UDP readings that did not parse or failed validation.", self.udp_rejected.get());
+        scalar(&mut out, "skypulse_udp_dropped_total", "counter", "UDP observations dropped on a full queue or failed write.", self.udp_dropped.get());
+        scalar(&mut out, "skypulse_chunks", "gauge", "Chunk files in the manifest.", snap.chunks);
+        scalar(&mut out, "skypulse_chunk_bytes", "gauge", "Total size of chunk files.", snap.chunk_bytes);
+        scalar(&mut out, "skypulse_memtable_rows", "gauge", "Observations buffered in the MemTable.", snap.memtable_rows);
+        scalar(&mut out, "skypulse_memtable_stations", "gauge", "Stations with buffered observations.", snap.memtable_stations);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_histogram() {
+        let m = Metrics::default();
+        m.writes.inc_by(3);
+        m.wal_append.observe(Duration::from_micros(50));
+        m.wal_append.observe(Duration::from_millis(3));
+        m.wal_append.observe(Duration::from_secs(2));
+        let text = m.render(&Snapshot { chunks: 4, ..Default::default() });
+        assert!(text.contains("skypulse_writes_total 3\n"));
+        assert!(text.contains("skypulse_wal_append_seconds_bucket{le=\"0.0001\"} 1\n"));
+        assert!(text.contains("skypulse_wal_append_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(text.contains("skypulse_wal_append_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("skypulse_wal_append_seconds_count 3\n"));
+        assert!(text.contains("skypulse_chunks 4\n"));
+    }
+}
@@ -0,0 +1,752 @@
+// Asynchronous mirroring between regions for disaster recovery. Every write
+// and range delete accepted locally is appended to an outbox under
+// `data_dir/mirror` and shipped in batches to each peer region's
+// `/api/v1/admin/mirror/apply`. Peers apply what they receive without
+// queueing it again, so two regions can mirror each other; with more
+// regions every region lists all others.
+//
+// Conflicts are settled per point (station and time) by arrival: each write
+// or range delete carries the time its origin first accepted it, plus the
+// origin region as a tie-breaker, and an earlier arrival never replaces a
+// later one. Versions are remembered for `conflict_window`; a point whose
+// version has been forgotten is applied as it comes. A mirrored delete is
+// issued as a new tombstone, so it also masks rows of later writes already
+// flushed here.
+//
+// A standby region applies mirrored writes but refuses client writes until
+// it is promoted; the role survives restarts in `mirror/ROLE`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::units;
+use crate::AppState;
+
+const ROLE_FILE: &str = "ROLE";
+const OUTBOX_FILE: &str = "outbox.ndjson";
+const OFFSETS_FILE: &str = "offsets.json";
+/// Most outbox bytes read for one batch, well under the peer's request body limit.
+const MAX_BATCH_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub name: String,
+    /// Base URL of the peer's admin API, e.g. `https://sg.example.com:8081`.
+    pub url: String,
+    /// Sent as `X-Api-Key`; needs the admin scope on the peer.
+    #[serde(default, serialize_with = "crate::config::redacted_opt")]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// This region's name, recorded as the origin of its writes.
+    pub region: String,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+    /// Start as a standby that refuses client writes until promoted.
+    #[serde(default)]
+    pub standby: bool,
+    /// Most entries shipped per request.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Pause between shipping rounds once a peer is caught up.
+    #[serde(alias = "interval", default = "default_interval_ms", deserialize_with = "units::millis")]
+    pub interval_ms: u64,
+    /// How long a point's version is kept for conflict resolution.
+    #[serde(alias = "conflict_window", default = "default_conflict_window_secs", deserialize_with = "units::secs")]
+    pub conflict_window_secs: u64,
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_interval_ms() -> u64 {
+    1000
+}
+
+fn default_conflict_window_secs() -> u64 {
+    3600
+}
+
+impl MirrorConfig {
+    /// `SKYPULSE_MIRROR_REGION` enables mirroring; `SKYPULSE_MIRROR_PEERS`
+    /// lists peers as `name=url` pairs separated by commas, all using
+    /// `SKYPULSE_MIRROR_API_KEY`; `SKYPULSE_MIRROR_STANDBY=1` starts as standby.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(region) = std::env::var("SKYPULSE_MIRROR_REGION") {
+            cfg.get_or_insert_with(|| Self {
+                region: String::new(),
+                peers: Vec::new(),
+                standby: false,
+                batch_size: default_batch_size(),
+                interval_ms: default_interval_ms(),
+                conflict_window_secs: default_conflict_window_secs(),
+            })
+            .region = region;
+        }
+        let Some(c) = cfg else { return Ok(()) };
+        if let Ok(v) = std::env::var("SKYPULSE_MIRROR_PEERS") {
+            let key = std::env::var("SKYPULSE_MIRROR_API_KEY").ok();
+            c.peers = v
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| match p.split_once('=') {
+                    Some((name, url)) => Ok(PeerConfig { name: name.to_string(), url: url.to_string(), api_key: key.clone() }),
+                    None => Err(anyhow!("SKYPULSE_MIRROR_PEERS entries look like sg=https://sg.example.com:8081, got '{}'", p)),
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Ok(v) = std::env::var("SKYPULSE_MIRROR_STANDBY") {
+            c.standby = matches!(v.as_str(), "1" | "true" | "yes");
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.region.is_empty() {
+            bail!("mirror.region must name this region");
+        }
+        let mut names = BTreeSet::new();
+        for p in &self.peers {
+            if p.name == self.region || !names.insert(p.name.as_str()) {
+                bail!("mirror peer '{}' is listed twice or is this region", p.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.max(10))
+    }
+}
+
+/// When and where a point was first accepted; later arrivals win.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Version {
+    arrival: i64,
+    origin: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Write(Observation),
+    /// Range delete of a station's points in `[start, end)`, either side
+    /// open when unset.
+    Delete { station_id: String, start: Option<Timestamp>, end: Option<Timestamp> },
+}
+
+impl Op {
+    /// Whether this is a delete whose range holds `time` of `station_id`.
+    fn covers(&self, station_id: &str, time: Timestamp) -> bool {
+        match self {
+            Op::Delete { station_id: s, start, end } => s == station_id && start.is_none_or(|t| time >= t) && end.is_none_or(|t| time < t),
+            Op::Write(_) => false,
+        }
+    }
+
+    /// Whether having applied this decides the point or range `op` touches.
+    fn settles(&self, op: &Op) -> bool {
+        match (self, op) {
+            (Op::Write(h), Op::Write(o)) => h.station_id == o.station_id && h.time == o.time,
+            (Op::Delete { .. }, Op::Write(o)) => self.covers(&o.station_id, o.time),
+            (Op::Delete { station_id, start, end }, Op::Delete { station_id: s, start: a, end: b }) => (station_id, start, end) == (s, a, b),
+            (Op::Write(_), Op::Delete { .. }) => false,
+        }
+    }
+}
+
+/// One mirrored change, as stored in the outbox and shipped to peers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub origin: String,
+    /// Epoch milliseconds when the origin accepted the change.
+    pub arrival: i64,
+    pub op: Op,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApplyReport {
+    pub applied: usize,
+    /// Entries older than (or the same as) the version already held.
+    pub skipped: usize,
+    /// Local rows removed because a later arrival or a tombstone replaced them.
+    pub replaced: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerStatus {
+    pub name: String,
+    pub url: String,
+    /// Outbox bytes not yet acknowledged by the peer.
+    pub pending_bytes: u64,
+    /// Age of the oldest entry the peer has not acknowledged; 0 when caught up.
+    pub lag_ms: i64,
+    pub shipped: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_shipped: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InboundStatus {
+    pub origin: String,
+    pub applied: u64,
+    pub skipped: u64,
+    pub last_received: String,
+    /// Delay between the origin accepting the newest received entry and its arrival here.
+    pub lag_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorStatus {
+    pub region: String,
+    /// `active` or `standby`.
+    pub role: &'static str,
+    pub outbox_bytes: u64,
+    pub tracked_points: usize,
+    pub peers: Vec<PeerStatus>,
+    pub inbound: Vec<InboundStatus>,
+}
+
+/// Append-only file of entries plus how far each peer has acknowledged it.
+struct Outbox {
+    file: tokio::fs::File,
+    len: u64,
+    offsets: BTreeMap<String, u64>,
+}
+
+pub struct Mirror {
+    cfg: MirrorConfig,
+    dir: PathBuf,
+    standby: AtomicBool,
+    outbox: Mutex<Outbox>,
+    versions: std::sync::Mutex<HashMap<(String, Timestamp), Version>>,
+    /// Range deletes applied within the conflict window, so a write they
+    /// replaced that arrives late is not applied again.
+    deletes: std::sync::Mutex<Vec<(Op, Version)>>,
+    /// Held while a peer's batch is applied.
+    applying: Mutex<()>,
+    peers: std::sync::Mutex<BTreeMap<String, PeerStatus>>,
+    inbound: std::sync::Mutex<BTreeMap<String, InboundStatus>>,
+}
+
+fn now_ms() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+impl Mirror {
+    /// Open the outbox under `data_dir/mirror`, creating it if needed.
+    pub async fn open(data_dir: &Path, cfg: MirrorConfig) -> Result<Self> {
+        cfg.validate()?;
+        let dir = data_dir.join("mirror");
+        tokio::fs::create_dir_all(&dir).await.with_context(|| format!("creating {}", dir.display()))?;
+        let standby = match tokio::fs::read_to_string(dir.join(ROLE_FILE)).await {
+            Ok(role) => role.trim() == "standby",
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => cfg.standby,
+            Err(e) => return Err(e).context("reading the mirror role"),
+        };
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).read(true).open(dir.join(OUTBOX_FILE)).await?;
+        let len = file.metadata().await?.len();
+        let mut offsets: BTreeMap<String, u64> = match tokio::fs::read(dir.join(OFFSETS_FILE)).await {
+            Ok(data) => serde_json::from_slice(&data).context("reading mirror offsets")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        // a new peer starts with what is still in the outbox
+        offsets.retain(|name, _| cfg.peers.iter().any(|p| &p.name == name));
+        for p in &cfg.peers {
+            let offset = offsets.entry(p.name.clone()).or_insert(0);
+            *offset = (*offset).min(len);
+        }
+        let peers = cfg
+            .peers
+            .iter()
+            .map(|p| (p.name.clone(), PeerStatus { name: p.name.clone(), url: p.url.clone(), ..Default::default() }))
+            .collect();
+        Ok(Self {
+            cfg,
+            dir,
+            standby: AtomicBool::new(standby),
+            outbox: Mutex::new(Outbox { file, len, offsets }),
+            versions: std::sync::Mutex::new(HashMap::new()),
+            deletes: std::sync::Mutex::new(Vec::new()),
+            applying: Mutex::new(()),
+            peers: std::sync::Mutex::new(peers),
+            inbound: std::sync::Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn region(&self) -> &str {
+        &self.cfg.region
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Relaxed)
+    }
+
+    /// Switch between active and standby, recording the role for restarts.
+    pub async fn set_standby(&self, standby: bool) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", ROLE_FILE));
+        tokio::fs::write(&tmp, if standby { "standby\n" } else { "active\n" }).await?;
+        tokio::fs::rename(&tmp, self.dir.join(ROLE_FILE)).await?;
+        self.standby.store(standby, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove the local rows that new local writes replace, so both regions
+    /// end up with the later arrival. Only points still within the conflict
+    /// window are known to exist.
+    pub async fn supersede(&self, state: &AppState, obs: &[Observation]) -> Result<usize> {
+        let mut remove: BTreeMap<String, BTreeSet<Timestamp>> = BTreeMap::new();
+        {
+            let versions = self.versions.lock().unwrap();
+            for o in obs.iter().filter(|o| versions.contains_key(&(o.station_id.clone(), o.time))) {
+                remove.entry(o.station_id.clone()).or_default().insert(o.time);
+            }
+        }
+        remove_local(state, &remove).await
+    }
+
+    /// Record locally accepted writes and queue them for every peer.
+    pub async fn record_writes(&self, obs: &[Observation]) -> Result<()> {
+        let arrival = now_ms();
+        let entries = obs.iter().map(|o| Entry { origin: self.cfg.region.clone(), arrival, op: Op::Write(o.clone()) });
+        self.record(entries.collect()).await
+    }
+
+    /// Record a local range delete and queue it for every peer.
+    pub async fn record_delete(&self, station_id: &str, start: Option<Timestamp>, end: Option<Timestamp>) -> Result<()> {
+        let op = Op::Delete { station_id: station_id.to_string(), start, end };
+        self.record(vec![Entry { origin: self.cfg.region.clone(), arrival: now_ms(), op }]).await
+    }
+
+    /// Hold `version` for the points `op` touches.
+    fn hold(&self, op: &Op, version: Version) {
+        match op {
+            Op::Write(o) => {
+                self.versions.lock().unwrap().insert((o.station_id.clone(), o.time), version);
+            }
+            Op::Delete { .. } => {
+                self.versions.lock().unwrap().retain(|(station, time), held| !(op.covers(station, *time) && *held < version));
+                self.deletes.lock().unwrap().push((op.clone(), version));
+            }
+        }
+    }
+
+    /// Whether the version held for what `op` touches, here or among the
+    /// `staged` changes of the batch being applied, is as late as `version`.
+    fn holds_later(&self, op: &Op, version: &Version, staged: &[(Op, Version)]) -> bool {
+        let later = |(held, v): &(Op, Version)| v >= version && held.settles(op);
+        if staged.iter().any(later) || self.deletes.lock().unwrap().iter().any(later) {
+            return true;
+        }
+        match op {
+            Op::Write(o) => self.versions.lock().unwrap().get(&(o.station_id.clone(), o.time)).is_some_and(|held| held >= version),
+            Op::Delete { .. } => false,
+        }
+    }
+
+    async fn record(&self, entries: Vec<Entry>) -> Result<()> {
+        for e in &entries {
+            self.hold(&e.op, Version { arrival: e.arrival, origin: e.origin.clone() });
+        }
+        if self.cfg.peers.is_empty() {
+            return Ok(());
+        }
+        let mut data = Vec::new();
+        for e in &entries {
+            serde_json::to_writer(&mut data, e)?;
+            data.push(b'\n');
+        }
+        let mut outbox = self.outbox.lock().await;
+        outbox.file.write_all(&data).await?;
+        outbox.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Apply entries shipped by a peer: entries that lose against the
+    /// version held here are skipped, winning writes replace any local row
+    /// for their point and winning deletes are issued here as tombstones.
+    /// Rollups covering replaced rows are stale until the next rollup rebuild.
+    ///
+    /// Versions are only held once the change is in the WAL, so a batch that
+    /// fails part way is applied again when the peer resends it. Batches are
+    /// applied one at a time.
+    pub async fn apply(&self, state: &AppState, entries: Vec<Entry>) -> Result<ApplyReport> {
+        let _applying = self.applying.lock().await;
+        let mut report = ApplyReport::default();
+        let mut writes = Vec::new();
+        let mut remove: BTreeMap<String, BTreeSet<Timestamp>> = BTreeMap::new();
+        let mut per_origin: BTreeMap<String, (u64, u64, i64)> = BTreeMap::new();
+        // applied but not yet durable
+        let mut staged: Vec<(Op, Version)> = Vec::new();
+        for e in entries {
+            let version = Version { arrival: e.arrival, origin: e.origin.clone() };
+            let stats = per_origin.entry(e.origin).or_default();
+            stats.2 = stats.2.max(e.arrival);
+            if self.holds_later(&e.op, &version, &staged) {
+                report.skipped += 1;
+                stats.1 += 1;
+                continue;
+            }
+            report.applied += 1;
+            stats.0 += 1;
+            match e.op {
+                Op::Write(o) => {
+                    let op = Op::Write(o.clone());
+                    let known = self.versions.lock().unwrap().contains_key(&(o.station_id.clone(), o.time));
+                    if known || staged.iter().any(|(held, _)| matches!(held, Op::Write(_)) && held.settles(&op)) {
+                        remove.entry(o.station_id.clone()).or_default().insert(o.time);
+                    }
+                    staged.push((op, version));
+                    writes.push(o);
+                }
+                Op::Delete { station_id, start, end } => {
+                    // writes before the delete in this batch land first, so it removes them
+                    report.replaced += remove_local(state, &std::mem::take(&mut remove)).await?;
+                    crate::ingest::append_mirrored(state, std::mem::take(&mut writes)).await?;
+                    let deleted = crate::delete::apply_mirrored(state, &station_id, start, end).await?;
+                    report.replaced += deleted.removed_buffered;
+                    staged.push((Op::Delete { station_id, start, end }, version));
+                    for (op, version) in staged.drain(..) {
+                        self.hold(&op, version);
+                    }
+                }
+            }
+        }
+
+        report.replaced += remove_local(state, &remove).await?;
+        crate::ingest::append_mirrored(state, writes).await?;
+        for (op, version) in staged {
+            self.hold(&op, version);
+        }
+
+        let received = now();
+        let mut inbound = self.inbound.lock().unwrap();
+        for (origin, (applied, skipped, newest)) in per_origin {
+            let s = inbound.entry(origin.clone()).or_insert_with(|| InboundStatus {
+                origin,
+                applied: 0,
+                skipped: 0,
+                last_received: String::new(),
+                lag_ms: 0,
+            });
+            s.applied += applied;
+            s.skipped += skipped;
+            s.last_received = received.clone();
+            s.lag_ms = (now_ms() - newest).max(0);
+        }
+        Ok(report)
+    }
+
+    /// Forget versions that arrived before the conflict window.
+    fn prune(&self) {
+        let cutoff = now_ms() - self.cfg.conflict_window_secs as i64 * 1000;
+        self.versions.lock().unwrap().retain(|_, v| v.arrival >= cutoff);
+        self.deletes.lock().unwrap().retain(|(_, v)| v.arrival >= cutoff);
+    }
+
+    pub async fn status(&self) -> MirrorStatus {
+        let outbox_bytes = self.outbox.lock().await.len;
+        MirrorStatus {
+            region: self.cfg.region.clone(),
+            role: if self.is_standby() { "standby" } else { "active" },
+            outbox_bytes,
+            tracked_points: self.versions.lock().unwrap().len(),
+            peers: self.peers.lock().unwrap().values().cloned().collect(),
+            inbound: self.inbound.lock().unwrap().values().cloned().collect(),
+        }
+    }
+
+    /// The next batch for `peer`: its entries, the outbox offset after them
+    /// and the bytes the peer has yet to acknowledge.
+    async fn next_batch(&self, peer: &str) -> Result<(Vec<Entry>, u64, u64)> {
+        let mut outbox = self.outbox.lock().await;
+        let start = outbox.offsets.get(peer).copied().unwrap_or(0);
+        let pending = outbox.len - start;
+        if pending == 0 {
+            return Ok((Vec::new(), start, 0));
+        }
+        let mut data = Vec::new();
+        outbox.file.seek(std::io::SeekFrom::Start(start)).await?;
+        (&mut outbox.file).take(pending.min(MAX_BATCH_BYTES)).read_to_end(&mut data).await?;
+        let mut entries = Vec::new();
+        let mut end = start;
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            if entries.len() >= self.cfg.batch_size.max(1) || !line.ends_with(b"\n") {
+                break;
+            }
+            entries.push(serde_json::from_slice(line).with_context(|| format!("corrupt mirror outbox entry at byte {}", end))?);
+            end += line.len() as u64;
+        }
+        Ok((entries, end, pending))
+    }
+
+    /// Record that `peer` acknowledged the outbox up to `end`; once every
+    /// peer has everything the outbox is emptied.
+    async fn acknowledge(&self, peer: &str, end: u64) -> Result<()> {
+        let mut outbox = self.outbox.lock().await;
+        outbox.offsets.insert(peer.to_string(), end);
+        if outbox.offsets.values().all(|&o| o == outbox.len) {
+            outbox.file.set_len(0).await?;
+            outbox.len = 0;
+            outbox.offsets.values_mut().for_each(|o| *o = 0);
+        }
+        let tmp = self.dir.join(format!("{}.tmp", OFFSETS_FILE));
+        tokio::fs::write(&tmp, serde_json::to_vec(&outbox.offsets)?).await?;
+        tokio::fs::rename(&tmp, self.dir.join(OFFSETS_FILE)).await?;
+        Ok(())
+    }
+
+    fn update_peer(&self, peer: &str, f: impl FnOnce(&mut PeerStatus)) {
+        if let Some(s) = self.peers.lock().unwrap().get_mut(peer) {
+            f(s);
+        }
+    }
+
+    /// Send one batch to `peer`; returns whether anything was sent.
+    async fn ship_once(&self, client: &reqwest::Client, peer: &PeerConfig) -> Result<bool> {
+        let (entries, end, pending) = self.next_batch(&peer.name).await?;
+        self.update_peer(&peer.name, |s| {
+            s.pending_bytes = pending;
+            s.lag_ms = entries.first().map_or(0, |e| (now_ms() - e.arrival).max(0));
+        });
+        if entries.is_empty() {
+            return Ok(false);
+        }
+        let url = format!("{}/api/v1/admin/mirror/apply", peer.url.trim_end_matches('/'));
+        let mut req = client.post(&url).json(&entries);
+        if let Some(key) = &peer.api_key {
+            req = req.header("x-api-key", key);
+        }
+        let resp = req.send().await.with_context(|| format!("sending to {}", url))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            bail!("{} answered {}: {}", url, status, resp.text().await.unwrap_or_default());
+        }
+        self.acknowledge(&peer.name, end).await?;
+        let shipped = entries.len() as u64;
+        self.update_peer(&peer.name, |s| {
+            s.shipped += shipped;
+            s.last_shipped = Some(now());
+            s.last_error = None;
+        });
+        Ok(true)
+    }
+}
+
+/// Remove rows from the MemTable and the raw chunks; the last-value cache
+/// of the affected stations is dropped so it is looked up again.
+async fn remove_local(state: &AppState, points: &BTreeMap<String, BTreeSet<Timestamp>>) -> Result<usize> {
+    let mut removed = 0;
+    for (station, times) in points {
+        removed += state.memtable.lock().await.remove_points(station, times);
+        removed += state.chunk_store.remove_points(station, times).await?;
+        state.last_values.lock().await.remove(station);
+    }
+    Ok(removed)
+}
+
+/// Ship the outbox to every peer until `shutdown` fires. A peer that is
+/// behind is sent batches back to back; errors are retried every interval.
+pub async fn run(state: Arc<AppState>, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let Some(mirror) = state.mirror.clone() else { return };
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(30)).build().unwrap_or_default();
+    let mut tasks = Vec::new();
+    for peer in mirror.cfg.peers.clone() {
+        let mirror = mirror.clone();
+        let client = client.clone();
+        let mut shutdown = shutdown.resubscribe();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                let busy = match mirror.ship_once(&client, &peer).await {
+                    Ok(sent) => sent,
+                    Err(e) => {
+                        tracing::warn!(peer = peer.name, "mirror: {:#}", e);
+                        mirror.update_peer(&peer.name, |s| s.last_error = Some(format!("{:#}", e)));
+                        false
+                    }
+                };
+                if busy {
+                    continue;
+                }
+                tokio::select! {
+                    _ = shutdown.recv() => break,
+                    _ = tokio::time::sleep(mirror.cfg.interval()) => {}
+                }
+            }
+        }));
+    }
+    let mut ticker = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => mirror.prune(),
+        }
+    }
+    for t in tasks {
+        let _ = t.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_arrivals_win_with_origin_as_tie_breaker() {
+        let v = |arrival, origin: &str| Version { arrival, origin: origin.to_string() };
+        assert!(v(2, "hk") > v(1, "sg"));
+        assert!(v(1, "sg") > v(1, "hk"));
+        assert!(v(1, "hk") >= v(1, "hk"));
+    }
+
+    #[tokio::test]
+    async fn outbox_is_emptied_once_every_peer_has_it() {
+        let dir = crate::test_util::TempDir::new("mirror");
+        let peer = |name: &str| PeerConfig { name: name.into(), url: "http://localhost:1".into(), api_key: None };
+        let cfg = MirrorConfig {
+            region: "hk".into(),
+            peers: vec![peer("sg"), peer("tw")],
+            standby: false,
+            batch_size: 2,
+            interval_ms: 10,
+            conflict_window_secs: 60,
+        };
+        let m = Mirror::open(&dir, cfg.clone()).await.unwrap();
+        let obs: Vec<Observation> = ["2025-01-01T00:00:00Z", "2025-01-01T00:01:00Z", "2025-01-01T00:02:00Z"]
+            .iter()
+            .map(|t| Observation::empty("A", t.parse().unwrap()))
+            .collect();
+        m.record_writes(&obs).await.unwrap();
+
+        let (batch, end, _) = m.next_batch("sg").await.unwrap();
+        assert_eq!(batch.len(), 2);
+        m.acknowledge("sg", end).await.unwrap();
+        let (rest, end, _) = m.next_batch("sg").await.unwrap();
+        assert_eq!(rest.len(), 1);
+        assert!(matches!(&rest[0].op, Op::Write(o) if o.time.to_string() == "2025-01-01T00:02:00Z"));
+        m.acknowledge("sg", end).await.unwrap();
+        assert!(m.next_batch("sg").await.unwrap().0.is_empty());
+        assert_eq!(m.status().await.outbox_bytes, end);
+
+        // offsets survive a restart; tw still has everything to fetch
+        drop(m);
+        let m = Mirror::open(&dir, cfg).await.unwrap();
+        let (all, end, pending) = m.next_batch("tw").await.unwrap();
+        assert_eq!((all.len(), pending), (2, m.status().await.outbox_bytes));
+        m.acknowledge("tw", end).await.unwrap();
+        let (_, end, _) = m.next_batch("tw").await.unwrap();
+        m.acknowledge("tw", end).await.unwrap();
+        assert_eq!(m.status().await.outbox_bytes, 0);
+
+        m.set_standby(true).await.unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("mirror").join(ROLE_FILE)).unwrap(), "standby\n");
+    }
+    /// A region with one peer, `peer_url`, in a fresh data directory.
+    async fn region(name: &str, peer: &str, peer_url: &str) -> (Arc<AppState>, crate::test_util::TempDir) {
+        let dir = crate::test_util::TempDir::new(&format!("mirror-{}", name));
+        let mirror = MirrorConfig {
+            region: name.into(),
+            peers: vec![PeerConfig { name: peer.into(), url: peer_url.into(), api_key: None }],
+            standby: false,
+            batch_size: 100,
+            interval_ms: 10,
+            conflict_window_secs: 60,
+        };
+        let cfg = crate::Config { data_dir: dir.to_path_buf(), mirror: Some(mirror), ..Default::default() };
+        (Arc::new(AppState::open(&cfg).await.unwrap()), dir)
+    }
+
+    #[tokio::test]
+    async fn deletes_reach_the_peer_region() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let b_url = format!("http://{}", listener.local_addr().unwrap());
+        let (b, _b_dir) = region("sg", "hk", "http://localhost:1").await;
+        let app = crate::api::http::router(b.clone()).into_make_service_with_connect_info::<std::net::SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let (a, _a_dir) = region("hk", "sg", &b_url).await;
+        let a_mirror = a.mirror.clone().unwrap();
+        let peer = a_mirror.cfg.peers[0].clone();
+        let client = reqwest::Client::new();
+
+        let rows: Vec<Observation> = ["2025-01-01T00:00:00Z", "2025-01-01T00:01:00Z"]
+            .iter()
+            .map(|t| {
+                let mut o = Observation::empty("A", t.parse().unwrap());
+                o.set_field("temp", 20.0);
+                o
+            })
+            .collect();
+        crate::ingest::append(&a, rows).await.unwrap();
+        assert!(a_mirror.ship_once(&client, &peer).await.unwrap());
+        let raw = crate::query::RangeQuery { resolution: crate::query::ResolutionChoice::Raw, ..crate::query::RangeQuery::new("A", None, None) };
+        let count = |state: Arc<AppState>| {
+            let raw = raw.clone();
+            async move {
+                match crate::query::execute(&state, &raw).await.unwrap().rows {
+                    Some(crate::query::Rows::Raw(rows)) => rows.len(),
+                    _ => 0,
+                }
+            }
+        };
+        assert_eq!(count(b.clone()).await, 2);
+
+        crate::delete::delete(&a, "A", Some("2025-01-01T00:01:00Z".parse().unwrap()), None).await.unwrap();
+        assert!(a_mirror.ship_once(&client, &peer).await.unwrap());
+        assert_eq!(count(a.clone()).await, 1);
+        assert_eq!(count(b.clone()).await, 1);
+        // the delete was not queued again on the peer
+        assert_eq!(b.mirror.as_ref().unwrap().status().await.outbox_bytes, 0);
+    }
+    #[tokio::test]
+    async fn concurrent_versions_settle_on_the_latest_arrival() {
+        let (state, _dir) = region("sg", "hk", "http://localhost:1").await;
+        let m = state.mirror.clone().unwrap();
+        let time: Timestamp = "2025-01-01T00:00:00Z".parse().unwrap();
+        let entry = |origin: &str, arrival: i64, temp: f64| {
+            let mut o = Observation::empty("A", time);
+            o.set_field("temp", temp);
+            Entry { origin: origin.into(), arrival, op: Op::Write(o) }
+        };
+        let temp = || async {
+            let q = crate::query::RangeQuery { resolution: crate::query::ResolutionChoice::Raw, ..crate::query::RangeQuery::new("A", None, None) };
+            match crate::query::execute(&state, &q).await.unwrap().rows {
+                Some(crate::query::Rows::Raw(rows)) => rows.iter().map(|r| r.number("temp").unwrap()).collect::<Vec<_>>(),
+                _ => Vec::new(),
+            }
+        };
+
+        // a batch failing part way holds none of its versions
+        let bad = Entry { origin: "hk".into(), arrival: 100, op: Op::Delete { station_id: String::new(), start: None, end: None } };
+        assert!(m.apply(&state, vec![entry("hk", 100, 1.0), bad]).await.is_err());
+        assert_eq!(m.apply(&state, vec![entry("hk", 100, 1.0)]).await.unwrap().applied, 1);
+
+        // two origins racing for the point: the later arrival wins whichever lands first
+        let (a, b) = tokio::join!(m.apply(&state, vec![entry("tw", 300, 3.0)]), m.apply(&state, vec![entry("hk", 200, 2.0)]));
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(temp().await, [3.0]);
+        let stale = m.apply(&state, vec![entry("hk", 250, 2.5), entry("sg", 400, 3.5)]).await.unwrap();
+        assert_eq!((stale.applied, stale.skipped), (1, 1));
+        assert_eq!(temp().await, [3.5]);
+    }
+}
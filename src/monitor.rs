@@ -0,0 +1,222 @@
+// Self-monitoring: the server's own operational metrics (ingest rate, WAL
+// and flush latency, flush queue, last-value cache hit rate, storage size)
+// sampled every `interval` and written as ordinary observations into the
+// reserved `_internal` namespace. A standalone deployment gets their history
+// from the same query API (`/api/v1/db/_internal/query?station_id=ingest`)
+// without running Prometheus. The namespace keeps its own counters, so
+// writing the samples does not show up in them.
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::metrics::Metrics;
+use crate::namespace::NamespaceConfig;
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::storage::retention::RetentionPolicy;
+use crate::{units, AppState};
+
+/// The namespace the samples are written to.
+pub const NAMESPACE: &str = "_internal";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorConfig {
+    /// How often the metrics are sampled.
+    #[serde(alias = "interval", deserialize_with = "units::secs")]
+    pub interval_secs: u64,
+    /// How long samples are kept.
+    #[serde(alias = "retention", deserialize_with = "units::secs")]
+    pub retention_secs: u64,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self { interval_secs: 10, retention_secs: 7 * 86_400 }
+    }
+}
+
+impl MonitorConfig {
+    /// `SKYPULSE_MONITOR=1` turns self-monitoring on; `SKYPULSE_MONITOR_INTERVAL`
+    /// sets the sampling interval.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(v) = std::env::var("SKYPULSE_MONITOR") {
+            if matches!(v.as_str(), "1" | "true" | "yes") {
+                cfg.get_or_insert_with(Self::default);
+            }
+        }
+        if let (Some(c), Some(d)) = (cfg.as_mut(), units::env_secs("SKYPULSE_MONITOR_INTERVAL", "s")?) {
+            c.interval_secs = d;
+        }
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    /// The `_internal` namespace, with the samples' retention.
+    pub fn namespace(&self) -> NamespaceConfig {
+        let retention = RetentionPolicy { max_age_secs: Some(self.retention_secs), ..Default::default() };
+        NamespaceConfig { retention: Some(retention), ..NamespaceConfig::named(NAMESPACE) }
+    }
+}
+
+/// Counter readings at the last sample; rates are taken over the difference.
+#[derive(Debug, Clone, Copy, Default)]
+struct Reading {
+    writes: u64,
+    rejected: u64,
+    rate_limited: u64,
+    wal_appends: u64,
+    wal_append: Duration,
+    flushes: u64,
+    flush_errors: u64,
+    flush: Duration,
+    hits: u64,
+    misses: u64,
+}
+
+impl Reading {
+    fn of(m: &Metrics) -> Self {
+        Self {
+            writes: m.writes.get(),
+            rejected: m.writes_rejected.get(),
+            rate_limited: m.rate_limited.get(),
+            wal_appends: m.wal_append.count(),
+            wal_append: m.wal_append.sum(),
+            flushes: m.flush_duration.count(),
+            flush_errors: m.flush_errors.get(),
+            flush: m.flush_duration.sum(),
+            hits: m.last_value_hits.get(),
+            misses: m.last_value_misses.get(),
+        }
+    }
+}
+
+/// Mean in milliseconds of the durations observed between two readings.
+fn mean_ms(sum: Duration, prev_sum: Duration, count: u64, prev_count: u64) -> Option<f64> {
+    let n = count.saturating_sub(prev_count);
+    (n > 0).then(|| sum.saturating_sub(prev_sum).as_secs_f64() * 1000.0 / n as f64)
+}
+
+/// Rows for the metrics since `prev`, `elapsed` ago, stamped `at`.
+async fn sample(state: &AppState, prev: &Reading, now: &Reading, elapsed: Duration, at: Timestamp) -> Vec<Observation> {
+    let secs = elapsed.as_secs_f64().max(0.001);
+    let rate = |now: u64, prev: u64| now.saturating_sub(prev) as f64 / secs;
+    let row = |id: &str, fields: &[(&str, Option<f64>)]| {
+        let mut o = Observation::empty(id, at);
+        for (name, value) in fields {
+            if let Some(v) = value {
+                o.set_field(*name, *v);
+            }
+        }
+        o
+    };
+
+    // storage of the server's own data and every user namespace
+    let mut storage = crate::metrics::Snapshot::default();
+    let mut cached = 0;
+    let nodes = std::iter::once(state).chain(state.namespaces.iter().filter(|(n, _)| *n != NAMESPACE).map(|(_, ns)| ns.as_ref()));
+    for node in nodes {
+        let snap = node.metrics_snapshot().await;
+        storage.chunks += snap.chunks;
+        storage.chunk_bytes += snap.chunk_bytes;
+        storage.memtable_rows += snap.memtable_rows;
+        storage.memtable_stations += snap.memtable_stations;
+        cached += node.last_values.lock().await.len();
+    }
+    let lookups = (now.hits + now.misses).saturating_sub(prev.hits + prev.misses);
+    vec![
+        row(
+            "ingest",
+            &[
+                ("writes_per_sec", Some(rate(now.writes, prev.writes))),
+                ("rejected_per_sec", Some(rate(now.rejected, prev.rejected))),
+                ("rate_limited_per_sec", Some(rate(now.rate_limited, prev.rate_limited))),
+                ("wal_append_ms", mean_ms(now.wal_append, prev.wal_append, now.wal_appends, prev.wal_appends)),
+            ],
+        ),
+        row(
+            "flush",
+            &[
+                ("flushes", Some(now.flushes.saturating_sub(prev.flushes) as f64)),
+                ("errors", Some(now.flush_errors.saturating_sub(prev.flush_errors) as f64)),
+                ("duration_ms", mean_ms(now.flush, prev.flush, now.flushes, prev.flushes)),
+                ("queue_depth", Some(state.metrics.flush_queue_depth.get() as f64)),
+            ],
+        ),
+        row(
+            "cache",
+            &[
+                ("last_value_hit_rate", (lookups > 0).then(|| now.hits.saturating_sub(prev.hits) as f64 / lookups as f64)),
+                ("last_value_entries", Some(cached as f64)),
+            ],
+        ),
+        row(
+            "storage",
+            &[
+                ("chunks", Some(storage.chunks as f64)),
+                ("chunk_bytes", Some(storage.chunk_bytes as f64)),
+                ("memtable_rows", Some(storage.memtable_rows as f64)),
+                ("memtable_stations", Some(storage.memtable_stations as f64)),
+            ],
+        ),
+    ]
+}
+
+/// Sample the server's metrics into the `_internal` namespace every interval
+/// until `shutdown` fires.
+pub async fn run(state: Arc<AppState>, cfg: MonitorConfig, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let Some(internal) = state.namespaces.get(NAMESPACE).cloned() else { return };
+    let mut ticker = tokio::time::interval(cfg.interval());
+    ticker.tick().await;
+    let mut prev = Reading::of(&state.metrics);
+    let mut last = tokio::time::Instant::now();
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {}
+        }
+        let now = Reading::of(&state.metrics);
+        let at = Timestamp(chrono::Utc::now().timestamp_millis());
+        let rows = sample(&state, &prev, &now, last.elapsed(), at).await;
+        (prev, last) = (now, tokio::time::Instant::now());
+        if let Err(e) = crate::ingest::append(&internal, rows).await {
+            tracing::warn!("monitor: writing samples: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[tokio::test]
+    async fn samples_land_in_the_internal_namespace() {
+        let dir = crate::test_util::TempDir::new("monitor");
+        let cfg = Config { data_dir: dir.to_path_buf(), monitor: Some(MonitorConfig::default()), ..Default::default() };
+        let state = AppState::open(&cfg).await.unwrap();
+        let internal = state.namespaces[NAMESPACE].clone();
+        assert_eq!(internal.retention.max_age_secs, Some(7 * 86_400));
+
+        let prev = Reading::of(&state.metrics);
+        let at = |t: &str| Observation::empty("A", t.parse().unwrap());
+        crate::ingest::append(&state, vec![at("2025-01-01T00:00:00Z"), at("2025-01-01T00:01:00Z")]).await.unwrap();
+        crate::query::latest(&state, "A").await.unwrap();
+        let now = Reading::of(&state.metrics);
+        let rows = sample(&state, &prev, &now, Duration::from_secs(2), Timestamp(1_000)).await;
+        crate::ingest::append(&internal, rows).await.unwrap();
+
+        let ingest = crate::query::latest(&internal, "ingest").await.unwrap().unwrap();
+        assert_eq!(ingest.fields["writes_per_sec"], crate::storage::memtable::FieldValue::Number(1.0));
+        assert!(ingest.fields.contains_key("wal_append_ms"));
+        let cache = crate::query::latest(&internal, "cache").await.unwrap().unwrap();
+        assert_eq!(cache.fields["last_value_hit_rate"], crate::storage::memtable::FieldValue::Number(1.0));
+        let storage = crate::query::latest(&internal, "storage").await.unwrap().unwrap();
+        assert_eq!(storage.fields["memtable_rows"], crate::storage::memtable::FieldValue::Number(2.0));
+        // the samples' own writes stay out of the server's counters
+        assert_eq!(state.metrics.writes.get(), 2);
+    }
+}
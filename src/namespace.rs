@@ -0,0 +1,151 @@
+// Namespaces: named databases sharing one server, so several deployments or
+// teams can use it without seeing each other's stations. Each namespace is a
+// node of its own with its data directory, WAL, flushes, retention and API
+// keys, served under `/api/v1/db/<name>/...` next to the server's own data at
+// `/api/v1/...`. Runtimes, listeners, metrics and the SLO tracker are shared.
+// Replication, mirroring, object storage and the server-side ingest sources
+// (file drop, scraping, Kafka, UDP, demo) only ever work on the server's own
+// data.
+
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use crate::api::auth::AuthConfig;
+use crate::storage::retention::RetentionPolicy;
+use crate::Config;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    /// Path segment of the namespace's API: letters, digits, `-` and `_`.
+    pub name: String,
+    /// `data_dir/db/<name>` when unset.
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    /// The server's retention policy when unset.
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    /// Keys valid in this namespace only; the server's keys when unset.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+impl NamespaceConfig {
+    pub fn named(name: &str) -> Self {
+        Self { name: name.to_string(), data_dir: None, retention: None, auth: None }
+    }
+
+    /// `SKYPULSE_NAMESPACES=name,name` adds namespaces with the defaults
+    /// next to the configured ones.
+    pub fn apply_env(namespaces: &mut Vec<Self>) {
+        let Ok(v) = std::env::var("SKYPULSE_NAMESPACES") else { return };
+        for name in v.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if !namespaces.iter().any(|ns| ns.name == name) {
+                namespaces.push(Self::named(name));
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let ok = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if self.name.is_empty() || self.name.len() > 64 || !self.name.chars().all(ok) {
+            bail!("namespace name '{}' must be 1-64 letters, digits, '-' or '_'", self.name);
+        }
+        if self.name.starts_with('_') {
+            bail!("namespace names starting with '_' are reserved, like {}", crate::monitor::NAMESPACE);
+        }
+        if let Some(auth) = &self.auth {
+            auth.validate()?;
+        }
+        Ok(())
+    }
+
+    /// The configuration the namespace's node is opened with: the server's,
+    /// moved under the namespace's directories and stripped of everything
+    /// that only applies to the server's own data.
+    pub fn node_config(&self, server: &Config) -> Config {
+        let under = |root: &Path| root.join("db").join(&self.name);
+        let mut cfg = server.clone();
+        cfg.data_dir = self.data_dir.clone().unwrap_or_else(|| under(&server.data_dir));
+        cfg.wal_dir = server.wal_dir.as_deref().map(under);
+        cfg.chunk_dir = server.chunk_dir.as_deref().map(under);
+        cfg.export.dir = server.export.dir.as_deref().map(under);
+        if let Some(retention) = &self.retention {
+            cfg.retention = retention.clone();
+        }
+        if let Some(auth) = &self.auth {
+            cfg.auth = auth.clone();
+        }
+        cfg.object_store = None;
+        cfg.tiering = None;
+        cfg.file_drop = None;
+        cfg.scrape = None;
+        cfg.kafka = None;
+        cfg.udp = None;
+        cfg.routing = None;
+        cfg.demo = None;
+        cfg.mirror = None;
+        cfg.replication = None;
+        cfg.namespaces = Vec::new();
+        cfg.monitor = None;
+        cfg
+    }
+}
+
+/// Check every namespace and that no two share a name or a data directory.
+pub fn validate(server: &Config) -> Result<()> {
+    let mut dirs = vec![server.data_dir.clone()];
+    for (i, ns) in server.namespaces.iter().enumerate() {
+        ns.validate()?;
+        if server.namespaces[..i].iter().any(|other| other.name == ns.name) {
+            bail!("namespace '{}' is configured twice", ns.name);
+        }
+        let dir = ns.node_config(server).data_dir;
+        if dirs.contains(&dir) {
+            bail!("namespace '{}' shares the data directory {}", ns.name, dir.display());
+        }
+        dirs.push(dir);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::api::auth::{ApiKey, Scope};
+    use crate::storage::memtable::Observation;
+    use crate::AppState;
+    use super::*;
+
+    #[tokio::test]
+    async fn namespaces_keep_their_own_data_and_keys() {
+        let dir = crate::test_util::TempDir::new("namespace");
+        let key = |key: &str| ApiKey { key: key.into(), scope: Scope::Write, name: None, filter: None };
+        let mut team = NamespaceConfig::named("team-a");
+        team.auth = Some(AuthConfig { keys: vec![key("team-key")], ..Default::default() });
+        let cfg = Config {
+            data_dir: dir.to_path_buf(),
+            auth: AuthConfig { keys: vec![key("server-key")], ..Default::default() },
+            namespaces: vec![team, NamespaceConfig::named("bad name")],
+            ..Default::default()
+        };
+        assert!(validate(&cfg).is_err());
+        let cfg = Config { namespaces: cfg.namespaces[..1].to_vec(), ..cfg };
+        validate(&cfg).unwrap();
+
+        let state = AppState::open(&cfg).await.unwrap();
+        let ns = state.namespaces["team-a"].clone();
+        assert_eq!(ns.data_dir, dir.join("db").join("team-a"));
+        assert!(ns.auth.authorize(Some("server-key"), Scope::Write).is_err());
+        assert!(ns.auth.authorize(Some("team-key"), Scope::Write).is_ok());
+        assert!(Arc::ptr_eq(&ns.runtimes, &state.runtimes));
+
+        crate::ingest::append(&ns, vec![Observation::empty("A", "2025-01-01T00:00:00Z".parse().unwrap())]).await.unwrap();
+        assert!(ns.memtable.lock().await.buffer.contains_key("A"));
+        assert!(state.memtable.lock().await.buffer.is_empty());
+        // the namespace's WAL brings the row back after a restart
+        drop((state, ns));
+        let state = AppState::open(&cfg).await.unwrap();
+        assert!(state.namespaces["team-a"].memtable.lock().await.buffer.contains_key("A"));
+    }
+}
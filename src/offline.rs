@@ -0,0 +1,339 @@
+// Offline maintenance behind the CLI subcommands: dumping a chunk file,
+// forcing a compaction, checking chunk files for damage and salvaging them,
+// and exporting a station to CSV or Parquet, run straight against a data
+// directory without the HTTP server. Only `compact` and `verify --repair`
+// write; run them while no server is writing the directory.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::storage::checksum::{self, Damage, DamagedChunk};
+use crate::storage::diff::{diff_manifests, ChunkDiff};
+use crate::storage::fragmentation::CompactionConfig;
+use crate::storage::manifest::{ChunkMeta, Manifest};
+use crate::storage::memtable::{FieldValue, Observation};
+use crate::query::spill::SpoolReader;
+use crate::AppState;
+
+/// Where `repair` keeps damaged chunk files, under the data directory.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Serialize)]
+pub struct ChunkDump {
+    /// Metadata as a manifest scan would record it.
+    pub meta: ChunkMeta,
+    pub rows: Vec<Observation>,
+}
+
+/// Decode the chunk file at `path`, raw, archived or a block. A file failing
+/// its footer checksum or a block that does not decode is an error rather
+/// than an empty dump.
+pub fn inspect(path: &Path) -> Result<ChunkDump> {
+    let name = path.file_name().and_then(|n| n.to_str()).ok_or_else(|| anyhow!("{} is not a chunk file", path.display()))?;
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let rows = checksum::checked_rows(name, &data, None).map_err(|damage| anyhow!("{} is damaged: {}", path.display(), damage))?;
+    Ok(ChunkDump { meta: Manifest::describe(name, &data), rows })
+}
+
+/// Compact fragmented stations now, under `cfg` or the defaults; with
+/// `force` every station with two or more raw chunks is merged whatever its
+/// score. Returns the stations compacted.
+pub async fn compact(state: &AppState, cfg: Option<CompactionConfig>, force: bool) -> Result<Vec<String>> {
+    let mut cfg = cfg.unwrap_or_default();
+    if force {
+        (cfg.max_fragmentation, cfg.min_files) = (f64::NEG_INFINITY, 2);
+    }
+    crate::storage::fragmentation::compact(&state.chunk_store, &cfg).await
+}
+
+/// Outcome of `verify`.
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub chunks: usize,
+    /// Files failing their footer or manifest checksum, or holding torn or
+    /// unreadable rows.
+    pub damaged: Vec<DamagedChunk>,
+    /// The manifest (`a`) against the files (`b`).
+    #[serde(flatten)]
+    pub manifest: ChunkDiff,
+}
+
+impl Verification {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty() && self.manifest.is_consistent()
+    }
+}
+
+/// Read every chunk file, check it against its checksum footer and compare
+/// it with the manifest: damaged files, missing files, files the manifest
+/// does not list, and checksum or size mismatches.
+pub async fn verify(state: &AppState) -> Result<Verification> {
+    let (scanned, damaged) = state.chunk_store.verify().await?;
+    let manifest = diff_manifests(&state.chunk_store.manifest().await, &scanned);
+    Ok(Verification { chunks: scanned.chunks.len(), damaged, manifest })
+}
+
+/// A damaged chunk rewritten with the rows that could be read from it.
+#[derive(Debug, Serialize)]
+pub struct Repair {
+    pub file: String,
+    pub damage: Damage,
+    /// Rows kept; the chunk was deleted when none could be read.
+    pub rows: usize,
+    /// Rows the chunk's footer says it held, when the footer survived.
+    pub expected_rows: Option<u64>,
+    /// Byte ranges of the damaged file, as offset and length, that could not be read.
+    pub unreadable: Vec<(u64, u64)>,
+    /// The damaged file as it was.
+    pub quarantined: PathBuf,
+}
+
+/// Outcome of `verify --repair`.
+#[derive(Debug, Serialize)]
+pub struct RepairReport {
+    pub repaired: Vec<Repair>,
+    /// The chunk directory once repaired, with the manifest rebuilt from it.
+    pub after: Verification,
+}
+
+/// Salvage every damaged chunk: the rows that can still be read are written
+/// back in its place and the damaged file is moved to
+/// `data_dir/quarantine`, so one bad sector costs the rows it held rather
+/// than the whole chunk. The manifest is then rebuilt from the files, which
+/// also drops chunks that are missing and takes up unlisted ones. The
+/// rollups of a repaired chunk are recomputed from the rows kept, and the
+/// cached last values of its stations are dropped to be read again.
+pub async fn repair(state: &AppState) -> Result<RepairReport> {
+    let level = state.archive.as_ref().map_or_else(crate::storage::archive::default_level, |a| a.level);
+    let quarantine = state.data_dir.join(QUARANTINE_DIR);
+    let (_, damaged) = state.chunk_store.verify().await?;
+    let recorded = state.chunk_store.manifest().await;
+    let mut repaired = Vec::with_capacity(damaged.len());
+    let mut stations = BTreeSet::new();
+    for DamagedChunk { file, damage } in damaged {
+        let (salvage, quarantined) = state.chunk_store.salvage(&file, &quarantine, level).await?;
+        tracing::warn!(file, rows = salvage.rows.len(), "salvaged damaged chunk ({}); the original is in {}", damage, quarantined.display());
+        stations.extend(recorded.chunks.get(&file).into_iter().flat_map(|m| m.slices()).map(|s| s.station_id));
+        stations.extend(salvage.rows.iter().map(|o| o.station_id.clone()));
+        state.rollups.replace(&crate::storage::rollup::file_for_chunk(&file), None, &salvage.rows).await?;
+        repaired.push(Repair {
+            file,
+            damage,
+            rows: salvage.rows.len(),
+            expected_rows: salvage.expected_rows,
+            unreadable: salvage.unreadable,
+            quarantined,
+        });
+    }
+    state.chunk_store.rebuild_manifest().await?;
+    let mut last_values = state.last_values.lock().await;
+    for station_id in &stations {
+        last_values.remove(station_id);
+    }
+    drop(last_values);
+    Ok(RepairReport { repaired, after: verify(state).await? })
+}
+
+/// One CSV cell; raw and aggregate CSV answers both format through it.
+pub(crate) fn csv_cell(value: Option<&FieldValue>) -> String {
+    match value {
+        Some(FieldValue::Number(n)) => n.to_string(),
+        Some(FieldValue::Bool(b)) => b.to_string(),
+        Some(FieldValue::Text(t)) => t.clone(),
+        Some(FieldValue::Null) | None => String::new(),
+    }
+}
+
+/// Rows as CSV in the layout file drops import: `station_id,time` and the
+/// given fields, in that order. Tags are left out.
+pub struct CsvRows<W: std::io::Write> {
+    fields: Vec<String>,
+    writer: csv::Writer<W>,
+    rows: usize,
+}
+
+impl<W: std::io::Write> CsvRows<W> {
+    /// Writes the header row.
+    pub fn new(fields: Vec<String>, out: W) -> Result<Self> {
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(["station_id", "time"].into_iter().map(str::to_string).chain(fields.iter().cloned()))?;
+        Ok(Self { fields, writer, rows: 0 })
+    }
+
+    pub fn write(&mut self, rows: &[Observation]) -> Result<()> {
+        for o in rows {
+            let cells = self.fields.iter().map(|f| csv_cell(o.fields.get(f)));
+            self.writer.write_record([o.station_id.clone(), o.time.to_string()].into_iter().chain(cells))?;
+        }
+        self.rows += rows.len();
+        Ok(())
+    }
+
+    /// Rows written so far.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn finish(self) -> Result<W> {
+        self.writer.into_inner().map_err(|e| anyhow!("{}", e.error()))
+    }
+}
+
+impl CsvRows<Vec<u8>> {
+    /// The CSV written since the last call, for streaming it out.
+    pub fn take(&mut self) -> Result<Vec<u8>> {
+        // a fresh writer carries on where this one stopped; the header is out already
+        let writer = std::mem::replace(&mut self.writer, csv::Writer::from_writer(Vec::new()));
+        writer.into_inner().map_err(|e| anyhow!("{}", e.error()))
+    }
+}
+
+/// Write a station's raw rows in `[start, end)` as CSV in the layout file
+/// drops import (`station_id,time,<field>...`); every field the range holds
+/// when `fields` is empty, found by a first pass. Returns the rows written.
+pub async fn export_csv(
+    state: Arc<AppState>,
+    station_id: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    fields: &[String],
+    out: impl std::io::Write,
+) -> Result<usize> {
+    let mut scan = crate::columnar::scan_columns(state, station_id, start, end, fields, None, |o| o).await?;
+    let mut writer = CsvRows::new(scan.columns.fields().cloned().collect(), out)?;
+    write_scanned(&mut scan.rows, |rows| writer.write(rows)).await?;
+    let rows = writer.rows();
+    writer.finish()?.flush()?;
+    Ok(rows)
+}
+
+/// Write a station's raw rows in `[start, end)` as Parquet, with the
+/// columns of `columnar`; `fields` picks the field columns as for CSV.
+/// Returns the rows written.
+pub async fn export_parquet(
+    state: Arc<AppState>,
+    station_id: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    fields: &[String],
+    out: impl std::io::Write + Send,
+) -> Result<usize> {
+    let mut scan = crate::columnar::scan_columns(state, station_id, start, end, fields, None, |o| o).await?;
+    let mut writer = crate::columnar::ParquetWriter::new(scan.columns, out)?;
+    write_scanned(&mut scan.rows, |rows| writer.write(rows)).await?;
+    let rows = writer.rows();
+    writer.finish()?;
+    Ok(rows)
+}
+
+/// Hand the rows of a first pass to `write`, a batch at a time.
+async fn write_scanned(rows: &mut SpoolReader, mut write: impl FnMut(&[Observation]) -> Result<()>) -> Result<()> {
+    loop {
+        let batch = rows.next_batch().await?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        write(&batch)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::columnar::ChunkFormat;
+
+    fn at(t: &str, temp: f64) -> Observation {
+        let mut o = Observation::empty("A", t.parse().unwrap());
+        o.set_field("temp", temp);
+        o
+    }
+
+    /// Station A's rows in two flushed block chunks of a fresh data directory.
+    async fn open(name: &str) -> (Arc<AppState>, crate::test_util::TempDir, PathBuf) {
+        let dir = crate::test_util::TempDir::new(&format!("offline-{}", name));
+        let cfg = crate::Config { data_dir: dir.to_path_buf(), chunk_format: ChunkFormat::Block, ..Default::default() };
+        let state = Arc::new(AppState::open(&cfg).await.unwrap());
+        let first = state.chunk_store.write_chunk("A", "flush-1", &[at("2025-01-01T00:00:00Z", 20.5)]).await.unwrap();
+        let mut rain = at("2025-01-01T00:10:00Z", 21.0);
+        rain.set_field("rain", 0.2);
+        state.chunk_store.write_chunk("A", "flush-2", &[rain]).await.unwrap();
+        (state, dir, first)
+    }
+
+    /// Compact station A into one chunk and cut it off mid-file, in its
+    /// columns; returns the file and what is left of it.
+    async fn truncate_compacted(state: &AppState) -> (PathBuf, Vec<u8>) {
+        compact(state, None, true).await.unwrap();
+        let (merged, _) = state.chunk_store.slices("A").await.remove(0);
+        let path = state.chunk_store.dir().await.join(merged);
+        let data = std::fs::read(&path).unwrap();
+        let cut = data[..data.len() / 2].to_vec();
+        std::fs::write(&path, &cut).unwrap();
+        (path, cut)
+    }
+
+    #[tokio::test]
+    async fn inspects_verifies_compacts_and_exports() {
+        let (state, _dir, first) = open("export").await;
+        let dump = inspect(&first).unwrap();
+        assert_eq!((dump.meta.rows, dump.rows.len()), (1, 1));
+        assert!(verify(&state).await.unwrap().is_clean());
+
+        assert!(compact(&state, None, false).await.unwrap().is_empty());
+        assert_eq!(compact(&state, None, true).await.unwrap(), ["A"]);
+        assert_eq!(state.chunk_store.slices("A").await.len(), 1);
+        assert!(verify(&state).await.unwrap().is_clean());
+
+        let mut csv = Vec::new();
+        assert_eq!(export_csv(state.clone(), "A", None, None, &[], &mut csv).await.unwrap(), 2);
+        let text = String::from_utf8(csv).unwrap();
+        assert_eq!(text, "station_id,time,rain,temp\nA,2025-01-01T00:00:00Z,,20.5\nA,2025-01-01T00:10:00Z,0.2,21\n");
+        assert_eq!(crate::ingest::file_drop::parse_csv(text.as_bytes()).0.len(), 2);
+        let mut parquet = Vec::new();
+        assert_eq!(export_parquet(state.clone(), "A", None, None, &["temp".to_string()], &mut parquet).await.unwrap(), 2);
+        assert!(parquet.starts_with(b"PAR1") && parquet.ends_with(b"PAR1"));
+    }
+
+    #[tokio::test]
+    async fn verify_reports_truncated_chunks() {
+        let (state, _dir, _) = open("verify").await;
+        truncate_compacted(&state).await;
+        let report = verify(&state).await.unwrap();
+        assert!(!report.is_clean());
+        assert!(matches!(report.damaged[0].damage, crate::storage::checksum::Damage::Truncated { .. }));
+        assert!(state.chunk_store.read_chunks("A").await.unwrap_err().to_string().contains("is damaged"));
+    }
+
+    #[tokio::test]
+    async fn inspect_refuses_damaged_blocks() {
+        let (state, _dir, _) = open("inspect").await;
+        let (path, _) = truncate_compacted(&state).await;
+        assert!(inspect(&path).unwrap_err().to_string().contains("is damaged"));
+    }
+
+    #[tokio::test]
+    async fn repair_salvages_and_quarantines_damaged_chunks() {
+        let (state, dir, _) = open("repair").await;
+        let (path, cut) = truncate_compacted(&state).await;
+        // rollups and a cached last value from before the damage
+        let merged = path.file_name().unwrap().to_str().unwrap();
+        state.rollups.replace(&crate::storage::rollup::file_for_chunk(merged), None, &[at("2025-01-01T00:00:00Z", 99.0)]).await.unwrap();
+        state.last_values.lock().await.observe(&at("2025-01-01T00:10:00Z", 99.0));
+        let report = repair(&state).await.unwrap();
+        assert!(report.after.is_clean());
+        let fixed = &report.repaired[0];
+        // both rows keep their times; only the columns cut off are reported unreadable
+        assert_eq!((fixed.rows, fixed.expected_rows, fixed.unreadable.len()), (2, None, 1));
+        let (offset, len) = fixed.unreadable[0];
+        assert!(offset > 0 && offset + len == cut.len() as u64, "{:?}", fixed.unreadable);
+        assert!(fixed.quarantined.starts_with(dir.join(QUARANTINE_DIR)));
+        assert_eq!(std::fs::read(&fixed.quarantined).unwrap(), cut);
+        assert_eq!(state.chunk_store.read_chunks("A").await.unwrap().len(), 2);
+        assert!(state.last_values.lock().await.get("A").is_none());
+        let hourly = state.rollups.read("A", crate::storage::rollup::Resolution::Hour).await.unwrap();
+        assert!(hourly.iter().all(|r| r.fields.get("temp").is_none_or(|agg| agg.max < 99.0)), "{:?}", hourly);
+    }
+}
@@ -0,0 +1,481 @@
+use std::collections::{BTreeMap, BTreeSet};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, SecondsFormat, TimeZone};
+use chrono_tz::Tz;
+use serde::Serialize;
+use crate::storage::memtable::{FieldValue, Observation};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggFn {
+    Min,
+    Max,
+    Mean,
+    Sum,
+    Count,
+    First,
+    Last,
+    /// Observations where the sensor explicitly reported no data.
+    Nulls,
+    /// Observations that did not include the field at all.
+    Missing,
+}
+
+impl AggFn {
+    pub const ALL: [AggFn; 9] = [
+        AggFn::Min,
+        AggFn::Max,
+        AggFn::Mean,
+        AggFn::Sum,
+        AggFn::Count,
+        AggFn::First,
+        AggFn::Last,
+        AggFn::Nulls,
+        AggFn::Missing,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AggFn::Min => "min",
+            AggFn::Max => "max",
+            AggFn::Mean => "mean",
+            AggFn::Sum => "sum",
+            AggFn::Count => "count",
+            AggFn::First => "first",
+            AggFn::Last => "last",
+            AggFn::Nulls => "nulls",
+            AggFn::Missing => "missing",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match Self::ALL.into_iter().find(|f| f.as_str() == s) {
+            Some(f) => Ok(f),
+            None => bail!("unknown aggregation '{}', expected one of min, max, mean, sum, count, first, last, nulls, missing", s),
+        }
+    }
+
+    /// Parse a comma-separated list such as `min,max,mean`.
+    pub fn parse_list(s: &str) -> Result<Vec<Self>> {
+        s.split(',').map(str::trim).filter(|p| !p.is_empty()).map(Self::parse).collect()
+    }
+}
+
+/// Aggregate output: `count`, `nulls` and `missing` are integers, everything else is `null` when
+/// the field had no values in the range.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AggValue {
+    Count(u64),
+    Value(Option<f64>),
+}
+
+/// field name -> aggregation name -> value
+pub type Aggregates = BTreeMap<String, BTreeMap<&'static str, AggValue>>;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Accumulator {
+    min: Option<f64>,
+    max: Option<f64>,
+    sum: f64,
+    count: u64,
+    first: Option<f64>,
+    last: Option<f64>,
+    nulls: u64,
+    missing: u64,
+}
+
+impl Accumulator {
+    fn push(&mut self, v: f64) {
+        self.min = Some(self.min.map_or(v, |m| m.min(v)));
+        self.max = Some(self.max.map_or(v, |m| m.max(v)));
+        self.sum += v;
+        self.count += 1;
+        self.first.get_or_insert(v);
+        self.last = Some(v);
+    }
+
+    fn value(&self, f: AggFn) -> AggValue {
+        let has = self.count > 0;
+        match f {
+            AggFn::Min => AggValue::Value(self.min),
+            AggFn::Max => AggValue::Value(self.max),
+            AggFn::Mean => AggValue::Value(has.then(|| self.sum / self.count as f64)),
+            AggFn::Sum => AggValue::Value(has.then_some(self.sum)),
+            AggFn::Count => AggValue::Count(self.count),
+            AggFn::First => AggValue::Value(self.first),
+            AggFn::Last => AggValue::Value(self.last),
+            AggFn::Nulls => AggValue::Count(self.nulls),
+            AggFn::Missing => AggValue::Count(self.missing),
+        }
+    }
+}
+
+/// Per-field accumulators for one group of observations.
+struct Group<'a> {
+    accs: BTreeMap<&'a str, Accumulator>,
+}
+
+impl<'a> Group<'a> {
+    fn new(fields: &'a [String]) -> Self {
+        Self { accs: fields.iter().map(|f| (f.as_str(), Accumulator::default())).collect() }
+    }
+
+    fn push(&mut self, o: &Observation) {
+        for (name, acc) in self.accs.iter_mut() {
+            match o.fields.get(*name) {
+                Some(FieldValue::Number(v)) => acc.push(*v),
+                Some(FieldValue::Null) => acc.nulls += 1,
+                Some(_) => {}
+                None => acc.missing += 1,
+            }
+        }
+    }
+
+    fn finish(self, aggs: &[AggFn]) -> Aggregates {
+        self.accs
+            .into_iter()
+            .map(|(field, acc)| (field.to_string(), aggs.iter().map(|&f| (f.as_str(), acc.value(f))).collect()))
+            .collect()
+    }
+}
+
+/// The requested fields, or when none were requested every field of `obs`
+/// holding numbers or nulls.
+fn resolve_fields(obs: &[Observation], fields: &[String]) -> Vec<String> {
+    if !fields.is_empty() {
+        return fields.to_vec();
+    }
+    let names: BTreeSet<&String> =
+        obs.iter().flat_map(|o| o.fields.iter().filter(|(_, v)| v.is_numeric()).map(|(name, _)| name)).collect();
+    names.into_iter().cloned().collect()
+}
+
+/// Aggregate `fields` of `obs` (every numeric field when empty), which must
+/// already be ordered by time so that `first`/`last` are meaningful.
+/// Boolean and text values are skipped.
+pub fn aggregate(obs: &[Observation], fields: &[String], aggs: &[AggFn]) -> Aggregates {
+    let fields = resolve_fields(obs, fields);
+    let mut group = Group::new(&fields);
+    for o in obs {
+        group.push(o);
+    }
+    group.finish(aggs)
+}
+
+/// Aggregates for one fixed time window; `time` is the window start.
+#[derive(Debug, Clone, Serialize)]
+pub struct BucketRow {
+    pub time: String,
+    pub aggregates: Aggregates,
+}
+
+/// Most windows a filled query emits.
+pub const MAX_FILLED_WINDOWS: i64 = 100_000;
+
+/// A filled query that would emit more than [`MAX_FILLED_WINDOWS`] windows,
+/// with the count; the API answers it with 400 rather than as an execution
+/// error.
+#[derive(Debug)]
+pub struct TooManyWindows(pub i64);
+
+impl std::fmt::Display for TooManyWindows {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "filling would emit {} windows, more than {}; narrow the range or widen the interval", self.0, MAX_FILLED_WINDOWS)
+    }
+}
+
+impl std::error::Error for TooManyWindows {}
+
+/// What a bucketed query does about windows without values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fill {
+    /// Leave out windows without observations.
+    #[default]
+    None,
+    /// Emit every window of the range, with null values where it had none.
+    Null,
+    /// Emit every window and carry the last value forward into gaps.
+    Previous,
+    /// Emit every window and interpolate gaps between the values either
+    /// side by window time; gaps at the ends stay null.
+    Linear,
+}
+
+impl Fill {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Fill::None),
+            "null" => Ok(Fill::Null),
+            "previous" => Ok(Fill::Previous),
+            "linear" => Ok(Fill::Linear),
+            _ => bail!("unknown fill '{}', expected null, previous, linear or none", s),
+        }
+    }
+}
+
+/// How a bucketed query cuts time into windows.
+#[derive(Debug, Clone, Copy)]
+pub struct Windows {
+    pub interval_secs: i64,
+    /// Zone whose wall clock windows align to; UTC when unset.
+    pub tz: Option<Tz>,
+    pub fill: Fill,
+    /// Epoch seconds `[start, end)` whose every window a fill emits; the
+    /// first and last observed windows stand in for missing bounds.
+    pub span: (Option<i64>, Option<i64>),
+}
+
+impl Windows {
+    pub fn new(interval_secs: i64, tz: Option<Tz>) -> Self {
+        Self { interval_secs, tz, fill: Fill::None, span: (None, None) }
+    }
+
+    /// Start of the window after the one starting at `start`.
+    fn next(&self, start: i64) -> i64 {
+        // local windows are an hour longer or shorter across DST changes
+        (0..96)
+            .map(|quarter| window_start(start + self.interval_secs + 900 * quarter, self.interval_secs, self.tz))
+            .find(|&w| w > start)
+            .unwrap_or(start + self.interval_secs)
+    }
+}
+
+/// The first instant of local time `naive` in `tz`, or of the first valid
+/// local time after it when a DST change skips it.
+fn local_start(tz: Tz, naive: NaiveDateTime) -> Option<i64> {
+    (0..=96).map(|quarter| naive + Duration::minutes(15 * quarter)).find_map(|n| match tz.from_local_datetime(&n) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t.timestamp()),
+        LocalResult::None => None,
+    })
+}
+
+/// Start, in epoch seconds, of the window of `interval_secs` holding `secs`.
+/// Windows are epoch-aligned in UTC, or with `tz` on that zone's wall clock:
+/// whole-day widths start at local midnight, so a day with a DST change
+/// lasts 23 or 25 hours, and shorter widths at local multiples of the width.
+pub fn window_start(secs: i64, interval_secs: i64, tz: Option<Tz>) -> i64 {
+    let utc_start = secs.div_euclid(interval_secs) * interval_secs;
+    let Some((tz, t)) = tz.zip(DateTime::from_timestamp(secs, 0)) else { return utc_start };
+    let local = t.with_timezone(&tz).naive_local().and_utc().timestamp();
+    if interval_secs % 86_400 != 0 {
+        return secs - local.rem_euclid(interval_secs);
+    }
+    let midnight = DateTime::from_timestamp(local.div_euclid(interval_secs) * interval_secs, 0).map(|t| t.naive_utc());
+    midnight.and_then(|m| local_start(tz, m)).unwrap_or(utc_start)
+}
+
+/// Fill the null values of each field's aggregation across time-ordered
+/// `windows` as `fill` says. Counts are never filled, and neither is a field
+/// in a window where the station reported it as null (`reported_null`).
+fn fill_values(rows: &mut [(i64, Aggregates)], reported_null: &[BTreeSet<&str>], fill: Fill) {
+    let Some((_, first)) = rows.first() else { return };
+    let names: Vec<(String, Vec<&'static str>)> = first.iter().map(|(f, aggs)| (f.clone(), aggs.keys().copied().collect())).collect();
+    for (field, aggs) in names {
+        for agg in aggs {
+            let value = |a: &Aggregates| match a.get(&field).and_then(|f| f.get(agg)) {
+                Some(AggValue::Value(v)) => Some(*v),
+                _ => None,
+            };
+            // (index, time, value) of the windows holding a value
+            let known: Vec<(usize, i64, f64)> =
+                rows.iter().enumerate().filter_map(|(i, (t, a))| Some((i, *t, value(a)??))).collect();
+            for pair in known.windows(2).chain(known.last().map(std::slice::from_ref).filter(|_| fill == Fill::Previous)) {
+                let (i0, t0, v0) = pair[0];
+                let end = pair.get(1).map_or(rows.len(), |p| p.0);
+                for ((t, a), nulls) in rows[i0 + 1..end].iter_mut().zip(&reported_null[i0 + 1..end]) {
+                    if nulls.contains(field.as_str()) {
+                        continue;
+                    }
+                    let v = match (fill, pair.get(1)) {
+                        (Fill::Previous, _) => v0,
+                        (Fill::Linear, Some(&(_, t1, v1))) => v0 + (v1 - v0) * (*t - t0) as f64 / (t1 - t0) as f64,
+                        _ => continue,
+                    };
+                    if let Some(slot @ AggValue::Value(None)) = a.get_mut(&field).and_then(|f| f.get_mut(agg)) {
+                        *slot = AggValue::Value(Some(v));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Group time-ordered `obs` into windows aligned as [`window_start`] says
+/// and aggregate each window. Windows without observations are omitted
+/// unless `windows.fill` asks for them. Window times carry the zone's
+/// offset when one is set.
+pub fn aggregate_buckets(obs: &[Observation], windows: &Windows, fields: &[String], aggs: &[AggFn]) -> Result<Vec<BucketRow>> {
+    let (interval_secs, tz) = (windows.interval_secs, windows.tz);
+    let fields = resolve_fields(obs, fields);
+    let mut groups: BTreeMap<i64, Group> = BTreeMap::new();
+    for o in obs {
+        let start = window_start(o.time.secs(), interval_secs, tz);
+        groups.entry(start).or_insert_with(|| Group::new(&fields)).push(o);
+    }
+    if windows.fill != Fill::None {
+        let first = windows.span.0.map(|s| window_start(s, interval_secs, tz)).or(groups.keys().next().copied());
+        let last = groups.keys().next_back().map(|&w| w + 1);
+        let end = windows.span.1.max(last).unwrap_or(i64::MIN);
+        if let Some(first) = first.filter(|&f| f < end) {
+            let count = (end - first - 1) / interval_secs + 1;
+            if count > MAX_FILLED_WINDOWS {
+                return Err(TooManyWindows(count).into());
+            }
+            let mut start = first;
+            while start < end {
+                groups.entry(start).or_insert_with(|| Group::new(&fields));
+                start = windows.next(start);
+            }
+        }
+    }
+    let reported_null: Vec<BTreeSet<&str>> =
+        groups.values().map(|g| g.accs.iter().filter(|(_, acc)| acc.nulls > 0).map(|(f, _)| *f).collect()).collect();
+    let mut rows: Vec<(i64, Aggregates)> = groups.into_iter().map(|(start, group)| (start, group.finish(aggs))).collect();
+    if matches!(windows.fill, Fill::Previous | Fill::Linear) {
+        fill_values(&mut rows, &reported_null, windows.fill);
+    }
+    let label = |start: i64| {
+        let t = DateTime::from_timestamp(start, 0);
+        match tz {
+            Some(tz) => t.map(|t| t.with_timezone(&tz).to_rfc3339_opts(SecondsFormat::Secs, true)),
+            None => t.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        }
+    };
+    Ok(rows.into_iter().map(|(start, aggregates)| BucketRow { time: label(start).unwrap_or_default(), aggregates }).collect())
+}
+
+/// Split a comma-separated field list; an empty list selects every field.
+pub fn parse_fields(s: Option<&str>) -> Vec<String> {
+    s.unwrap_or("").split(',').map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obs(time: &str, temp: Option<f64>) -> Observation {
+        let mut o = Observation::empty("s", time.parse().unwrap());
+        if let Some(v) = temp {
+            o.set_field("temp", v);
+        }
+        o
+    }
+
+    #[test]
+    fn aggregates_all_functions() {
+        let mut rows: Vec<Observation> = [Some(3.0), None, Some(9.0), Some(6.0), None]
+            .into_iter()
+            .enumerate()
+            .map(|(h, temp)| obs(&format!("2025-01-01T0{}:00:00Z", h), temp))
+            .collect();
+        rows[4].set_null("temp");
+        rows[0].set("ok", FieldValue::Bool(true));
+        let out = aggregate(&rows, &["temp".into(), "humidity".into()], &AggFn::ALL);
+        let t = &out["temp"];
+        assert_eq!(t["min"], AggValue::Value(Some(3.0)));
+        assert_eq!(t["max"], AggValue::Value(Some(9.0)));
+        assert_eq!(t["mean"], AggValue::Value(Some(6.0)));
+        assert_eq!(t["sum"], AggValue::Value(Some(18.0)));
+        assert_eq!(t["count"], AggValue::Count(3));
+        assert_eq!(t["first"], AggValue::Value(Some(3.0)));
+        assert_eq!(t["last"], AggValue::Value(Some(6.0)));
+        assert_eq!(t["nulls"], AggValue::Count(1));
+        assert_eq!(t["missing"], AggValue::Count(1));
+        assert_eq!(out["humidity"]["max"], AggValue::Value(None));
+        assert_eq!(out["humidity"]["count"], AggValue::Count(0));
+        let all = aggregate(&rows, &[], &[AggFn::Count]);
+        assert_eq!(all.keys().collect::<Vec<_>>(), vec!["temp"]);
+    }
+
+    #[test]
+    fn groups_into_time_buckets() {
+        let rows = vec![
+            obs("2025-01-01T00:01:00Z", Some(1.0)),
+            obs("2025-01-01T00:04:59Z", Some(3.0)),
+            obs("2025-01-01T00:05:00Z", Some(10.0)),
+            obs("2025-01-01T00:20:00Z", Some(7.0)),
+        ];
+        let buckets = aggregate_buckets(&rows, &Windows::new(300, None), &["temp".into()], &[AggFn::Mean, AggFn::Count]).unwrap();
+        let times: Vec<&str> = buckets.iter().map(|b| b.time.as_str()).collect();
+        assert_eq!(times, vec!["2025-01-01T00:00:00Z", "2025-01-01T00:05:00Z", "2025-01-01T00:20:00Z"]);
+        assert_eq!(buckets[0].aggregates["temp"]["mean"], AggValue::Value(Some(2.0)));
+        assert_eq!(buckets[0].aggregates["temp"]["count"], AggValue::Count(2));
+        assert_eq!(buckets[1].aggregates["temp"]["mean"], AggValue::Value(Some(10.0)));
+    }
+
+    #[test]
+    fn fills_empty_windows() {
+        let secs = |t: &str| t.parse::<crate::storage::memtable::Timestamp>().unwrap().secs();
+        let rows = vec![obs("2025-01-01T00:10:00Z", Some(1.0)), obs("2025-01-01T00:40:00Z", Some(4.0))];
+        let run = |fill: Fill| {
+            let windows = Windows { interval_secs: 600, tz: None, fill, span: (Some(secs("2025-01-01T00:00:00Z")), Some(secs("2025-01-01T01:00:00Z"))) };
+            let buckets = aggregate_buckets(&rows, &windows, &["temp".into()], &[AggFn::Mean, AggFn::Count]).unwrap();
+            let means = buckets.iter().map(|b| match b.aggregates["temp"]["mean"] {
+                AggValue::Value(v) => v,
+                AggValue::Count(_) => unreachable!(),
+            });
+            (buckets.len(), means.collect::<Vec<_>>(), buckets[0].aggregates["temp"]["count"])
+        };
+        assert_eq!(run(Fill::None).1, vec![Some(1.0), Some(4.0)]);
+        assert_eq!(run(Fill::Null), (6, vec![None, Some(1.0), None, None, Some(4.0), None], AggValue::Count(0)));
+        assert_eq!(run(Fill::Previous).1, vec![None, Some(1.0), Some(1.0), Some(1.0), Some(4.0), Some(4.0)]);
+        assert_eq!(run(Fill::Linear).1, vec![None, Some(1.0), Some(2.0), Some(3.0), Some(4.0), None]);
+
+        // days across London's autumn change, one of them 25 hours long
+        let windows = Windows { fill: Fill::Null, span: (Some(secs("2025-10-25T12:00:00Z")), Some(secs("2025-10-28T00:00:00Z"))), ..Windows::new(86_400, Some(chrono_tz::Europe::London)) };
+        let times: Vec<String> = aggregate_buckets(&[], &windows, &["temp".into()], &[AggFn::Max]).unwrap().into_iter().map(|b| b.time).collect();
+        assert_eq!(times, ["2025-10-25T00:00:00+01:00", "2025-10-26T00:00:00+01:00", "2025-10-27T00:00:00Z"]);
+        let endless = Windows { fill: Fill::Null, span: (Some(0), Some(secs("2025-01-01T00:00:00Z"))), ..Windows::new(60, None) };
+        assert!(aggregate_buckets(&rows, &endless, &[], &[AggFn::Max]).unwrap_err().is::<TooManyWindows>());
+        assert!(Fill::parse("zero").is_err());
+    }
+
+    #[test]
+    fn leaves_reported_nulls_unfilled() {
+        let secs = |t: &str| t.parse::<crate::storage::memtable::Timestamp>().unwrap().secs();
+        let mut flagged = obs("2025-01-01T00:10:00Z", None);
+        flagged.set_null("temp");
+        let rows = vec![obs("2025-01-01T00:00:00Z", Some(1.0)), flagged, obs("2025-01-01T00:30:00Z", Some(4.0))];
+        let run = |fill: Fill| {
+            let windows = Windows { interval_secs: 600, tz: None, fill, span: (Some(secs("2025-01-01T00:00:00Z")), Some(secs("2025-01-01T00:40:00Z"))) };
+            let buckets = aggregate_buckets(&rows, &windows, &["temp".into()], &[AggFn::Mean]).unwrap();
+            buckets.iter().map(|b| b.aggregates["temp"]["mean"]).collect::<Vec<_>>()
+        };
+        // the 00:10 window reported no data; only the empty 00:20 window is filled
+        let v = AggValue::Value;
+        assert_eq!(run(Fill::Previous), vec![v(Some(1.0)), v(None), v(Some(1.0)), v(Some(4.0))]);
+        assert_eq!(run(Fill::Linear), vec![v(Some(1.0)), v(None), v(Some(3.0)), v(Some(4.0))]);
+    }
+
+    #[test]
+    fn aligns_windows_to_local_time() {
+        let secs = |t: &str| t.parse::<crate::storage::memtable::Timestamp>().unwrap().secs();
+        let start = |t: &str, width: i64, tz: &str| {
+            DateTime::from_timestamp(window_start(secs(t), width, Some(tz.parse().unwrap())), 0).unwrap().to_rfc3339()
+        };
+        // local midnight in Hong Kong is 16:00 UTC
+        assert_eq!(start("2025-01-01T15:59:59Z", 86_400, "Asia/Hong_Kong"), "2024-12-31T16:00:00+00:00");
+        assert_eq!(start("2025-01-01T16:00:00Z", 86_400, "Asia/Hong_Kong"), "2025-01-01T16:00:00+00:00");
+        // London's spring-forward day is 23 hours, and the repeated hour of
+        // the autumn change makes two hourly windows
+        assert_eq!(start("2025-03-30T22:59:59Z", 86_400, "Europe/London"), "2025-03-30T00:00:00+00:00");
+        assert_eq!(start("2025-03-30T23:00:00Z", 86_400, "Europe/London"), "2025-03-30T23:00:00+00:00");
+        assert_eq!(start("2025-10-26T00:30:00Z", 3600, "Europe/London"), "2025-10-26T00:00:00+00:00");
+        assert_eq!(start("2025-10-26T01:30:00Z", 3600, "Europe/London"), "2025-10-26T01:00:00+00:00");
+        // Sao Paulo's clocks skipped midnight, so that day began at 01:00 local
+        assert_eq!(start("2018-11-04T12:00:00Z", 86_400, "America/Sao_Paulo"), "2018-11-04T03:00:00+00:00");
+
+        let rows = vec![obs("2025-01-01T15:00:00Z", Some(1.0)), obs("2025-01-01T17:00:00Z", Some(3.0))];
+        let buckets = aggregate_buckets(&rows, &Windows::new(86_400, Some(chrono_tz::Asia::Hong_Kong)), &[], &[AggFn::Max]).unwrap();
+        let times: Vec<&str> = buckets.iter().map(|b| b.time.as_str()).collect();
+        assert_eq!(times, vec!["2025-01-01T00:00:00+08:00", "2025-01-02T00:00:00+08:00"]);
+    }
+
+    #[test]
+    fn parses_lists() {
+        assert_eq!(AggFn::parse_list("min, max").unwrap(), vec![AggFn::Min, AggFn::Max]);
+        assert!(AggFn::parse_list("median").is_err());
+        assert_eq!(parse_fields(Some("temp, rain")), vec!["temp".to_string(), "rain".to_string()]);
+        assert!(parse_fields(None).is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+// Unit conversion of query output. Temperatures, pressures, wind speeds and
+// precipitation are stored in °C, hPa, m/s and mm unless a row's
+// `unit.<field>` tag names another unit. `units=imperial` or `units=metric`
+// converts every such field, and `field:unit` items override single fields:
+// `units=imperial,wind_speed:kn`. A converted row's `unit.<field>` tag names
+// the unit returned. Rollups, aggregates, windows and derived fields are
+// computed from values converted to the canonical units first, so stations
+// reporting in different units aggregate together.
+
+use std::collections::BTreeMap;
+use anyhow::{anyhow, bail, Result};
+use crate::storage::memtable::{FieldValue, Observation};
+use crate::storage::rollup::RollupRow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Pressure,
+    Speed,
+    Length,
+}
+
+/// A unit as `canonical = value * scale + offset`; `names[0]` is how
+/// output names it, the rest are accepted spellings.
+#[derive(Debug, PartialEq)]
+pub struct Unit {
+    names: &'static [&'static str],
+    quantity: Quantity,
+    scale: f64,
+    offset: f64,
+}
+
+const UNITS: [Unit; 13] = [
+    Unit { names: &["C", "°C", "degC", "celsius"], quantity: Quantity::Temperature, scale: 1.0, offset: 0.0 },
+    Unit { names: &["F", "°F", "degF", "fahrenheit"], quantity: Quantity::Temperature, scale: 5.0 / 9.0, offset: -160.0 / 9.0 },
+    Unit { names: &["K", "kelvin"], quantity: Quantity::Temperature, scale: 1.0, offset: -273.15 },
+    Unit { names: &["hPa", "mbar", "mb"], quantity: Quantity::Pressure, scale: 1.0, offset: 0.0 },
+    Unit { names: &["kPa"], quantity: Quantity::Pressure, scale: 10.0, offset: 0.0 },
+    Unit { names: &["inHg"], quantity: Quantity::Pressure, scale: 33.863_886_67, offset: 0.0 },
+    Unit { names: &["mmHg"], quantity: Quantity::Pressure, scale: 1.333_223_874, offset: 0.0 },
+    Unit { names: &["m/s", "mps"], quantity: Quantity::Speed, scale: 1.0, offset: 0.0 },
+    Unit { names: &["km/h", "kmh", "kph"], quantity: Quantity::Speed, scale: 1.0 / 3.6, offset: 0.0 },
+    Unit { names: &["mph"], quantity: Quantity::Speed, scale: 0.447_04, offset: 0.0 },
+    Unit { names: &["kn", "kt", "knots"], quantity: Quantity::Speed, scale: 1852.0 / 3600.0, offset: 0.0 },
+    Unit { names: &["mm"], quantity: Quantity::Length, scale: 1.0, offset: 0.0 },
+    Unit { names: &["in", "inch"], quantity: Quantity::Length, scale: 25.4, offset: 0.0 },
+];
+
+impl Unit {
+    pub fn find(s: &str) -> Option<&'static Unit> {
+        UNITS.iter().find(|u| u.names.iter().any(|n| n.eq_ignore_ascii_case(s)))
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.names[0]
+    }
+
+    fn canonical(&self, v: f64) -> f64 {
+        v * self.scale + self.offset
+    }
+
+    fn of_canonical(&self, v: f64) -> f64 {
+        (v - self.offset) / self.scale
+    }
+}
+
+impl Quantity {
+    /// What a field holds when no unit tag says otherwise.
+    pub fn of_field(name: &str) -> Option<Self> {
+        match name {
+            "temp" | "temperature" | "dew_point" | "heat_index" | "wind_chill" => Some(Quantity::Temperature),
+            "pressure" => Some(Quantity::Pressure),
+            "wind_speed" | "wind_gust" | "gust" => Some(Quantity::Speed),
+            "rain" | "precipitation" => Some(Quantity::Length),
+            _ => None,
+        }
+    }
+
+    fn unit(self, name: &str) -> &'static Unit {
+        UNITS.iter().find(|u| u.quantity == self && u.name() == name).unwrap_or(&UNITS[0])
+    }
+
+    fn metric(self) -> &'static Unit {
+        match self {
+            Quantity::Temperature => self.unit("C"),
+            Quantity::Pressure => self.unit("hPa"),
+            Quantity::Speed => self.unit("m/s"),
+            Quantity::Length => self.unit("mm"),
+        }
+    }
+
+    fn imperial(self) -> &'static Unit {
+        match self {
+            Quantity::Temperature => self.unit("F"),
+            Quantity::Pressure => self.unit("inHg"),
+            Quantity::Speed => self.unit("mph"),
+            Quantity::Length => self.unit("in"),
+        }
+    }
+}
+
+/// The unit a row's `unit.<field>` tag names for `field`, unless it is not
+/// one of the field's quantity.
+fn tagged(o: &Observation, field: &str) -> Option<&'static Unit> {
+    let unit = Unit::find(o.tags.get(&format!("unit.{}", field))?)?;
+    Quantity::of_field(field).is_none_or(|q| q == unit.quantity).then_some(unit)
+}
+
+/// `v`, a value of `o`'s `field`, in the canonical unit.
+pub fn canonical_value(o: &Observation, field: &str, v: f64) -> f64 {
+    if o.tags.is_empty() {
+        return v;
+    }
+    tagged(o, field).map_or(v, |unit| unit.canonical(v))
+}
+
+/// Convert the fields of `o` stored in another unit than the canonical one,
+/// dropping their `unit.<field>` tags.
+pub fn canonical(o: &mut Observation) {
+    if !o.tags.keys().any(|k| k.starts_with("unit.")) {
+        return;
+    }
+    let units: Vec<(String, &'static Unit)> = o.fields.keys().filter_map(|f| Some((f.clone(), tagged(o, f)?))).collect();
+    for (field, unit) in units {
+        if let Some(FieldValue::Number(v)) = o.fields.get_mut(&field) {
+            *v = unit.canonical(*v);
+            o.tags.remove(&format!("unit.{}", field));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum System {
+    Metric,
+    Imperial,
+}
+
+/// The units a query's output is converted to; empty returns values as stored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Units {
+    system: Option<System>,
+    fields: BTreeMap<String, &'static Unit>,
+}
+
+impl Units {
+    /// Parse a comma-separated list of `metric`, `imperial` and `field:unit` items.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut units = Units::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            match item.split_once(':').map(|(field, unit)| (field.trim(), unit.trim())) {
+                None => {
+                    units.system = Some(match item.to_ascii_lowercase().as_str() {
+                        "metric" => System::Metric,
+                        "imperial" => System::Imperial,
+                        _ => bail!("unknown unit system '{}', expected metric, imperial or field:unit", item),
+                    });
+                }
+                Some((field, unit)) => {
+                    let unit = Unit::find(unit).ok_or_else(|| anyhow!("unknown unit '{}' for {}", unit, field))?;
+                    if Quantity::of_field(field).is_some_and(|q| q != unit.quantity) {
+                        bail!("{} cannot be converted to {}", field, unit.name());
+                    }
+                    units.fields.insert(field.to_string(), unit);
+                }
+            }
+        }
+        Ok(units)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.system.is_none() && self.fields.is_empty()
+    }
+
+    fn target(&self, field: &str, quantity: Quantity) -> Option<&'static Unit> {
+        if let Some(unit) = self.fields.get(field) {
+            return (unit.quantity == quantity).then_some(*unit);
+        }
+        self.system.map(|s| match s {
+            System::Metric => quantity.metric(),
+            System::Imperial => quantity.imperial(),
+        })
+    }
+
+    /// Convert the numeric fields of `o` that have a target unit.
+    pub fn apply(&self, o: &mut Observation) {
+        if self.is_empty() {
+            return;
+        }
+        for (name, value) in o.fields.iter_mut() {
+            let FieldValue::Number(v) = value else { continue };
+            let tag = format!("unit.{}", name);
+            let from = match o.tags.get(&tag) {
+                Some(stored) => Unit::find(stored),
+                None => Quantity::of_field(name).map(Quantity::metric),
+            };
+            let Some((from, to)) = from.and_then(|f| Some((f, self.target(name, f.quantity)?))) else { continue };
+            *v = to.of_canonical(from.canonical(*v));
+            o.tags.insert(tag, to.name().to_string());
+        }
+    }
+
+    /// Convert the aggregates of a rollup row, whose fields are in the
+    /// canonical units.
+    pub fn apply_rollup(&self, row: &mut RollupRow) {
+        for (name, agg) in row.fields.iter_mut() {
+            let Some(quantity) = Quantity::of_field(name) else { continue };
+            let Some(to) = self.target(name, quantity) else { continue };
+            let convert = |v: f64| to.of_canonical(v);
+            (agg.min, agg.max, agg.avg) = (convert(agg.min), convert(agg.max), convert(agg.avg));
+            agg.sum = (agg.sum - to.offset * agg.count as f64) / to.scale;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Timestamp;
+    use crate::storage::rollup::FieldAgg;
+
+    #[test]
+    fn converts_fields_and_rollups() {
+        let mut o = Observation::empty("A", Timestamp(0));
+        o.set_field("temp", 20.0);
+        o.set_field("pressure", 1013.25);
+        o.set_field("wind_speed", 10.0);
+        o.set_field("humidity", 60.0);
+        o.set_field("rain", 9.0);
+        o.tags.insert("unit.rain".into(), "in".into());
+        Units::parse("imperial, wind_speed:kn").unwrap().apply(&mut o);
+        let close = |o: &Observation, name: &str, v: f64| (o.number(name).unwrap() - v).abs() < 0.005;
+        assert!(close(&o, "temp", 68.0) && close(&o, "pressure", 29.92) && close(&o, "wind_speed", 19.44));
+        assert_eq!((o.number("humidity"), o.number("rain")), (Some(60.0), Some(9.0)));
+        assert_eq!(o.tags["unit.temp"], "F");
+        assert_eq!(o.tags["unit.wind_speed"], "kn");
+        Units::parse("metric").unwrap().apply(&mut o);
+        assert!(close(&o, "temp", 20.0) && close(&o, "rain", 228.6));
+        assert_eq!(o.tags["unit.rain"], "mm");
+
+        let mut agg = FieldAgg::new(0.0);
+        agg.merge(&FieldAgg::new(100.0));
+        let mut row = RollupRow { station_id: "A".into(), time: "2025-01-01T00:00:00Z".into(), fields: [("temp".to_string(), agg)].into() };
+        Units::parse("temp:F").unwrap().apply_rollup(&mut row);
+        let agg = row.fields["temp"];
+        assert!((agg.min - 32.0).abs() < 1e-9 && (agg.max - 212.0).abs() < 1e-9 && (agg.avg - 122.0).abs() < 1e-9);
+        assert!((agg.sum - 244.0).abs() < 1e-9);
+
+        assert!(Units::parse("temp:mph").is_err() && Units::parse("furlongs").is_err() && Units::parse("temp:R").is_err());
+        assert!(Units::parse(" temp :mph").is_err());
+        assert_eq!(Units::parse("temp :F").unwrap(), Units::parse("temp:F").unwrap());
+        assert!(Units::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn brings_tagged_rows_to_canonical_units() {
+        let mut o = Observation::empty("A", Timestamp(0));
+        o.set_field("temp", 212.0);
+        o.set_field("pressure", 30.0);
+        o.tags.insert("unit.temp".into(), "F".into());
+        o.tags.insert("unit.pressure".into(), "mph".into());
+        assert!((canonical_value(&o, "temp", 32.0)).abs() < 1e-9);
+        assert_eq!(canonical_value(&o, "pressure", 30.0), 30.0);
+        let celsius = {
+            let mut c = Observation::empty("B", Timestamp(0));
+            c.set_field("temp", 0.0);
+            c
+        };
+        let rollup = crate::storage::rollup::compute(&[o.clone(), celsius], crate::storage::rollup::Resolution::Hour);
+        let (a, b) = (rollup[0].fields["temp"], rollup[1].fields["temp"]);
+        assert!((a.avg - 100.0).abs() < 1e-9 && b.avg == 0.0);
+
+        canonical(&mut o);
+        assert!((o.number("temp").unwrap() - 100.0).abs() < 1e-9);
+        assert!(!o.tags.contains_key("unit.temp"));
+        // not a unit of the field's quantity, so the tag stays with the value
+        assert_eq!((o.number("pressure"), o.tags.get("unit.pressure").map(String::as_str)), (Some(30.0), Some("mph")));
+    }
+}
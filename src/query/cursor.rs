@@ -0,0 +1,83 @@
+// Continuation cursors for paging through raw rows. A cursor names the
+// station, the row order and the time of the last row a page returned; the
+// next page starts just past that time. A station has one row per time (a
+// rewrite of a point replaces it), so pages neither skip nor repeat rows
+// whatever is written between them. Cursors are hex so that clients treat
+// them as opaque and pass them back unchanged.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Duration;
+use super::{Order, RangeQuery};
+use crate::storage::memtable::Timestamp;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub station_id: String,
+    pub order: Order,
+    /// Time of the last row returned.
+    pub time: Timestamp,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let order = match self.order {
+            Order::Asc => "a",
+            Order::Desc => "d",
+        };
+        format!("{}:{}:{}", order, self.time.millis(), self.station_id).bytes().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn decode(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid cursor '{}'", s);
+        if !s.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let bytes = (0..s.len()).step_by(2).map(|i| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok())).collect::<Option<Vec<u8>>>();
+        let text = bytes.and_then(|b| String::from_utf8(b).ok()).ok_or_else(invalid)?;
+        let mut parts = text.splitn(3, ':');
+        let order = match parts.next() {
+            Some("a") => Order::Asc,
+            Some("d") => Order::Desc,
+            _ => return Err(invalid()),
+        };
+        let time = parts.next().and_then(|t| t.parse().ok()).map(Timestamp).ok_or_else(invalid)?;
+        let station_id = parts.next().ok_or_else(invalid)?.to_string();
+        Ok(Self { station_id, order, time })
+    }
+
+    /// Narrow `q` to the rows after the cursor in its order. Fails for a
+    /// cursor of another station or order.
+    pub fn apply(&self, q: &mut RangeQuery) -> Result<()> {
+        if self.station_id != q.station_id || self.order != q.order {
+            bail!("cursor belongs to a query of another station or order");
+        }
+        let at = self.time.to_datetime();
+        match self.order {
+            Order::Asc => q.start = Some(q.start.map_or(at + Duration::milliseconds(1), |s| s.max(at + Duration::milliseconds(1)))),
+            Order::Desc => q.end = Some(q.end.map_or(at, |e| e.min(at))),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_narrows_the_range() {
+        let cursor = Cursor { station_id: "HK:001".into(), order: Order::Asc, time: Timestamp(1_735_689_600_000) };
+        let token = cursor.encode();
+        assert!(token.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(Cursor::decode(&token).unwrap(), cursor);
+        assert!(Cursor::decode("zz").is_err() && Cursor::decode(&token[1..]).is_err());
+
+        let mut q = RangeQuery::new("HK:001", None, None);
+        cursor.apply(&mut q).unwrap();
+        assert_eq!(q.start.unwrap().timestamp_millis(), 1_735_689_600_001);
+        let mut q = RangeQuery { order: Order::Desc, ..RangeQuery::new("HK:001", None, None) };
+        assert!(cursor.apply(&mut q).is_err());
+        Cursor { order: Order::Desc, ..cursor }.apply(&mut q).unwrap();
+        assert_eq!(q.end.unwrap().timestamp_millis(), 1_735_689_600_000);
+    }
+}
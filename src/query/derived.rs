@@ -0,0 +1,165 @@
+// Meteorological fields computed at query time from the stored readings,
+// so that every client gets the same dew point or heat index instead of
+// carrying its own formulas. Inputs are `temp` and `dew_point` in °C,
+// `humidity` in percent and `wind_speed` in m/s, converted there from the
+// unit a row's `unit.<field>` tag names. A derived field is added to
+// a row only when its inputs are there and the formula applies, and never
+// replaces a stored field of the same name.
+
+use crate::storage::memtable::Observation;
+
+/// Magnus coefficients (Alduchov and Eskridge, 1996).
+const MAGNUS_A: f64 = 17.625;
+const MAGNUS_B: f64 = 243.04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Derived {
+    DewPoint,
+    HeatIndex,
+    WindChill,
+    RelativeHumidity,
+}
+
+impl Derived {
+    pub const ALL: [Derived; 4] = [Derived::DewPoint, Derived::HeatIndex, Derived::WindChill, Derived::RelativeHumidity];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Derived::DewPoint => "dew_point",
+            Derived::HeatIndex => "heat_index",
+            Derived::WindChill => "wind_chill",
+            Derived::RelativeHumidity => "relative_humidity",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|d| d.name() == name)
+    }
+
+    /// Stored fields the value is computed from.
+    pub fn inputs(self) -> &'static [&'static str] {
+        match self {
+            Derived::DewPoint | Derived::HeatIndex => &["temp", "humidity"],
+            Derived::WindChill => &["temp", "wind_speed"],
+            Derived::RelativeHumidity => &["temp", "dew_point"],
+        }
+    }
+
+    /// The value in °C, from inputs in whatever unit the row stores them.
+    pub fn compute(self, o: &Observation) -> Option<f64> {
+        let input = |field: &str| o.number(field).map(|v| super::convert::canonical_value(o, field, v));
+        let temp = input("temp")?;
+        match self {
+            Derived::DewPoint => dew_point(temp, input("humidity")?),
+            Derived::HeatIndex => Some(heat_index(temp, input("humidity")?)),
+            Derived::WindChill => Some(wind_chill(temp, input("wind_speed")?)),
+            Derived::RelativeHumidity => Some(relative_humidity(temp, input("dew_point")?)),
+        }
+    }
+}
+
+/// The derived fields named in `fields`.
+pub fn requested(fields: &[String]) -> Vec<Derived> {
+    fields.iter().filter_map(|f| Derived::parse(f)).collect()
+}
+
+/// Add each of `derived` that `o` does not store itself.
+pub fn apply(o: &mut Observation, derived: &[Derived]) {
+    for d in derived {
+        if o.fields.contains_key(d.name()) {
+            continue;
+        }
+        if let Some(v) = d.compute(o).filter(|v| v.is_finite()) {
+            o.set_field(d.name(), v);
+        }
+    }
+}
+
+fn magnus(temp: f64) -> f64 {
+    MAGNUS_A * temp / (MAGNUS_B + temp)
+}
+
+fn dew_point(temp: f64, humidity: f64) -> Option<f64> {
+    if humidity <= 0.0 {
+        return None;
+    }
+    let gamma = (humidity.min(100.0) / 100.0).ln() + magnus(temp);
+    Some(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+}
+
+fn relative_humidity(temp: f64, dew_point: f64) -> f64 {
+    (100.0 * (magnus(dew_point) - magnus(temp)).exp()).min(100.0)
+}
+
+/// The NWS heat index: Steadman's simple formula, and the Rothfusz
+/// regression with its low- and high-humidity adjustments where that
+/// averages 80 °F or more. Worked in °F as published.
+fn heat_index(temp: f64, humidity: f64) -> f64 {
+    let (t, rh) = (temp * 9.0 / 5.0 + 32.0, humidity);
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.04901523 * t + 10.14333127 * rh - 0.22475541 * t * rh - 0.00683783 * t * t - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        hi
+    };
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// The North American wind chill index; the air temperature itself above
+/// 10 °C or in wind under 4.8 km/h, where the index is not defined.
+fn wind_chill(temp: f64, wind_speed: f64) -> f64 {
+    let kmh = wind_speed * 3.6;
+    if temp > 10.0 || kmh <= 4.8 {
+        return temp;
+    }
+    let v = kmh.powf(0.16);
+    13.12 + 0.6215 * temp - 11.37 * v + 0.3965 * temp * v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Timestamp;
+
+    #[test]
+    fn computes_published_values() {
+        let close = |a: Option<f64>, b: f64| a.is_some_and(|a| (a - b).abs() < 0.1);
+        let mut o = Observation::empty("A", Timestamp(0));
+        o.set_field("temp", 20.0);
+        o.set_field("humidity", 50.0);
+        o.set_field("wind_speed", 20.0 / 3.6);
+        apply(&mut o, &Derived::ALL);
+        assert!(close(o.number("dew_point"), 9.3));
+        assert!(close(o.number("relative_humidity"), 50.0));
+        assert!(close(o.number("heat_index"), 19.4));
+        assert_eq!(o.number("wind_chill"), Some(20.0));
+
+        // NWS: 90 °F at 70% feels like 106 °F; Environment Canada: -10 °C in 20 km/h feels like -18
+        assert!((heat_index(32.2222, 70.0) - 41.1).abs() < 0.1);
+        assert!((wind_chill(-10.0, 20.0 / 3.6) + 17.9).abs() < 0.1);
+
+        let mut stored = Observation::empty("A", Timestamp(0));
+        stored.set_field("temp", 20.0);
+        stored.set_field("dew_point", 12.0);
+        apply(&mut stored, &requested(&["dew_point".into(), "heat_index".into(), "temp".into()]));
+        assert_eq!(stored.number("dew_point"), Some(12.0));
+        assert!(!stored.fields.contains_key("heat_index"));
+
+        // inputs stored in °F are read as °C
+        let mut fahrenheit = Observation::empty("A", Timestamp(0));
+        fahrenheit.set_field("temp", 68.0);
+        fahrenheit.set_field("humidity", 50.0);
+        fahrenheit.tags.insert("unit.temp".into(), "F".into());
+        apply(&mut fahrenheit, &[Derived::DewPoint]);
+        assert!(close(fahrenheit.number("dew_point"), 9.3));
+    }
+}
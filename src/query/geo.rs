@@ -0,0 +1,129 @@
+// Areas for multi-station queries: a circle of some radius around a point,
+// or a bounding box given as `min_lon,min_lat,max_lon,max_lat` (GeoJSON's
+// order; a box whose west edge is east of its east edge crosses the
+// antimeridian). Stations are placed by the coordinates in their registered
+// metadata, and distances are great-circle distances on a spherical Earth.
+
+use anyhow::{anyhow, bail, Result};
+
+/// Mean Earth radius (IUGG).
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Area {
+    Radius { lat: f64, lon: f64, km: f64 },
+    BBox { min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64 },
+}
+
+fn numbers(s: &str, n: usize, what: &str) -> Result<Vec<f64>> {
+    let parts: Vec<f64> = s.split(',').map(|p| p.trim().parse::<f64>()).collect::<Result<_, _>>().map_err(|_| anyhow!("invalid {} '{}'", what, s))?;
+    if parts.len() != n || parts.iter().any(|p| !p.is_finite()) {
+        bail!("{} takes {} comma-separated numbers, got '{}'", what, n, s);
+    }
+    Ok(parts)
+}
+
+fn check(lat: f64, lon: f64) -> Result<()> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        bail!("latitude must be within ±90 and longitude within ±180 degrees");
+    }
+    Ok(())
+}
+
+/// A distance in kilometres: `25`, `25km`, `800m` or `10mi`.
+pub fn parse_km(s: &str) -> Result<f64> {
+    let s = s.trim();
+    let (number, scale) = if let Some(n) = s.strip_suffix("km") {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix("mi") {
+        (n, 1.609_344)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 0.001)
+    } else {
+        (s, 1.0)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v * scale),
+        _ => bail!("invalid radius '{}', expected a distance such as 25km", s),
+    }
+}
+
+impl Area {
+    /// The area of `near` (`lat,lon`) and `radius`, or of `bbox`.
+    pub fn parse(near: Option<&str>, radius: Option<&str>, bbox: Option<&str>) -> Result<Self> {
+        match (near, radius, bbox) {
+            (Some(near), Some(radius), None) => {
+                let p = numbers(near, 2, "near")?;
+                check(p[0], p[1])?;
+                Ok(Area::Radius { lat: p[0], lon: p[1], km: parse_km(radius)? })
+            }
+            (None, None, Some(bbox)) => {
+                let b = numbers(bbox, 4, "bbox")?;
+                check(b[1], b[0])?;
+                check(b[3], b[2])?;
+                if b[1] > b[3] {
+                    bail!("bbox is min_lon,min_lat,max_lon,max_lat; min_lat is above max_lat");
+                }
+                Ok(Area::BBox { min_lon: b[0], min_lat: b[1], max_lon: b[2], max_lat: b[3] })
+            }
+            (Some(_), None, None) | (None, Some(_), None) => bail!("near and radius go together"),
+            _ => bail!("give either near and radius or bbox"),
+        }
+    }
+
+    /// Whether a station at `lat`, `lon` is in the area, with its distance
+    /// from the centre of a radius.
+    pub fn locate(&self, lat: f64, lon: f64) -> Option<Option<f64>> {
+        match *self {
+            Area::Radius { lat: clat, lon: clon, km } => {
+                let d = distance_km(clat, clon, lat, lon);
+                (d <= km).then_some(Some(d))
+            }
+            Area::BBox { min_lon, min_lat, max_lon, max_lat } => {
+                let in_lon = if min_lon <= max_lon { (min_lon..=max_lon).contains(&lon) } else { lon >= min_lon || lon <= max_lon };
+                (in_lon && (min_lat..=max_lat).contains(&lat)).then_some(None)
+            }
+        }
+    }
+}
+
+/// Haversine distance between two points.
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (p1, p2) = (lat1.to_radians(), lat2.to_radians());
+    let (dp, dl) = ((lat2 - lat1).to_radians(), (lon2 - lon1).to_radians());
+    let a = (dp / 2.0).sin().powi(2) + p1.cos() * p2.cos() * (dl / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_stations_in_areas() {
+        // the Hong Kong Observatory is about 66 km from Macau
+        let d = distance_km(22.302, 114.174, 22.199, 113.544);
+        assert!((d - 65.9).abs() < 1.0, "{}", d);
+        let hk = Area::parse(Some("22.302, 114.174"), Some("20km"), None).unwrap();
+        assert!(hk.locate(22.199, 113.544).is_none());
+        assert!(hk.locate(22.38, 114.19).unwrap().unwrap() < 10.0);
+        assert_eq!(parse_km("800m").unwrap(), 0.8);
+        assert!((parse_km("10mi").unwrap() - 16.09344).abs() < 1e-9);
+
+        let pacific = Area::parse(None, None, Some("170,-20,-170,0")).unwrap();
+        assert_eq!(pacific.locate(-10.0, 179.0), Some(None));
+        assert_eq!(pacific.locate(-10.0, -175.0), Some(None));
+        assert!(pacific.locate(-10.0, 0.0).is_none());
+
+        for (near, radius, bbox) in [
+            (Some("22.3"), Some("5km"), None),
+            (Some("22.3,114.2"), None, None),
+            (Some("91,0"), Some("5km"), None),
+            (Some("22.3,114.2"), Some("-5km"), None),
+            (None, None, Some("0,10,1,5")),
+            (Some("22.3,114.2"), Some("5km"), Some("0,0,1,1")),
+        ] {
+            assert!(Area::parse(near, radius, bbox).is_err(), "{:?}", (near, radius, bbox));
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// Merging rows from several chunks (and the MemTable) into one time-ordered
+// run. Chunks are sorted by time when flushed, but late data means their
+// ranges overlap, so rows from different sources interleave. Client retries
+// also put the same point in several chunks; the last write wins.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use crate::storage::memtable::{dedup_last, Observation};
+
+/// Merge runs of rows, given oldest write first, into one run ordered by
+/// time. Runs that are not already sorted (chunks written before flushes
+/// sorted them, or MemTable rows in arrival order) are sorted first. Of rows
+/// with equal times only the one from the latest run is kept.
+pub fn by_time(runs: Vec<Vec<Observation>>) -> Vec<Observation> {
+    let total = runs.iter().map(Vec::len).sum();
+    let mut iters: Vec<_> = runs
+        .into_iter()
+        .map(|mut run| {
+            if !run.is_sorted_by_key(|o| o.time) {
+                run.sort_by_key(|o| o.time);
+            }
+            run.into_iter().peekable()
+        })
+        .collect();
+    let mut heap = BinaryHeap::with_capacity(iters.len());
+    for (i, it) in iters.iter_mut().enumerate() {
+        if let Some(o) = it.peek() {
+            heap.push(Reverse((o.time, i)));
+        }
+    }
+    let mut out = Vec::with_capacity(total);
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let it = &mut iters[i];
+        out.extend(it.next());
+        if let Some(o) = it.peek() {
+            heap.push(Reverse((o.time, i)));
+        }
+    }
+    dedup_last(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(station: &str, times: &[&str]) -> Vec<Observation> {
+        times.iter().map(|t| Observation::empty(station, format!("2025-01-01T{}:00Z", t).parse().unwrap())).collect()
+    }
+
+    #[test]
+    fn interleaves_overlapping_runs() {
+        let merged = by_time(vec![
+            rows("s", &["00:00", "00:02", "00:04"]),
+            // late rows flushed into a later chunk
+            rows("s", &["00:01", "00:03"]),
+            // MemTable rows in arrival order
+            rows("s", &["00:05", "00:00"]),
+        ]);
+        let times: Vec<String> = merged.iter().map(|o| o.time.to_string()[11..16].to_string()).collect();
+        assert_eq!(times, vec!["00:00", "00:01", "00:02", "00:03", "00:04", "00:05"]);
+    }
+
+    #[test]
+    fn duplicates_keep_the_latest_run() {
+        let mut a = rows("s", &["00:00"]);
+        a[0].set_field("temp", 1.0);
+        let mut b = rows("s", &["00:00"]);
+        b[0].set_field("temp", 2.0);
+        let merged = by_time(vec![a, Vec::new(), b]);
+        assert_eq!(merged.iter().map(|o| o.number("temp").unwrap()).collect::<Vec<_>>(), vec![2.0]);
+    }
+}
@@ -0,0 +1,745 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use crate::storage::last_values;
+use crate::storage::manifest::StationInfo;
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::storage::rollup::{self, Resolution, RollupRow};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use crate::AppState;
+
+pub mod aggregate;
+pub mod convert;
+pub mod cursor;
+pub mod derived;
+pub mod geo;
+pub mod merge;
+pub mod promql;
+pub mod spill;
+pub mod sql;
+pub mod stream;
+
+use aggregate::{AggFn, Aggregates, BucketRow};
+
+/// Ranges longer than this are answered from hourly rollups when `resolution=auto`.
+const AUTO_HOUR_SPAN_SECS: i64 = 2 * 86_400;
+/// Ranges longer than this (and up to the hourly threshold) use minute rollups.
+const AUTO_MINUTE_SPAN_SECS: i64 = 2 * 3600;
+/// Rough NDJSON read-and-parse throughput used to cost scans for `max_latency_ms`.
+const SCAN_BYTES_PER_MS: u64 = 20_000;
+/// Approximate serialized size of one rollup row (all fields).
+const ROLLUP_ROW_BYTES: u64 = 400;
+
+/// Parse a bucket width such as `30s`, `5m`, `1h`, `1d` or `1w` into whole seconds.
+pub fn parse_interval(s: &str) -> Result<i64> {
+    let d = crate::units::parse_duration(s).map_err(|e| anyhow::anyhow!("interval: {}", e))?;
+    if d.as_secs() == 0 {
+        bail!("interval '{}' must be at least one second", s.trim());
+    }
+    if d.subsec_millis() != 0 {
+        bail!("interval '{}' must be a whole number of seconds", s.trim());
+    }
+    i64::try_from(d.as_secs()).map_err(|_| anyhow::anyhow!("interval '{}' is out of range", s.trim()))
+}
+
+/// Parse an IANA time zone name such as `Asia/Hong_Kong`.
+pub fn parse_tz(s: &str) -> Result<Tz> {
+    s.trim().parse().map_err(|_| anyhow::anyhow!("unknown time zone '{}', expected an IANA name such as Asia/Hong_Kong", s.trim()))
+}
+
+/// Parse an RFC3339 query bound.
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(s)?.with_timezone(&Utc))
+}
+
+/// Parse a query bound given as RFC3339, `now`, or `now` plus or minus a
+/// duration (`now-6h`, `now+15m`); a bare `-6h` is `now-6h`. The bounds of
+/// one request are read against the same `now`. A `+` decoded from a query
+/// string arrives as a space, which counts as `+`.
+pub fn parse_time_at(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let trimmed = s.trim_start();
+    let Some(rel) = trimmed.strip_prefix("now").or_else(|| trimmed.starts_with('-').then_some(trimmed)) else { return parse_time(s.trim()) };
+    let rel = rel.trim_end();
+    if rel.is_empty() {
+        return Ok(now);
+    }
+    let invalid = |why: String| anyhow::anyhow!("invalid relative time '{}': {}", s.trim(), why);
+    let (back, d) = match (rel.strip_prefix('-'), rel.strip_prefix('+').or_else(|| rel.strip_prefix(' '))) {
+        (Some(d), _) => (true, d),
+        (None, Some(d)) => (false, d),
+        _ => return Err(invalid("expected now, now-<duration> or now+<duration>".to_string())),
+    };
+    let d = crate::units::parse_duration(d.trim()).map_err(|e| invalid(e.to_string()))?;
+    let d = chrono::Duration::from_std(d).map_err(|e| invalid(e.to_string()))?;
+    let t = if back { now.checked_sub_signed(d) } else { now.checked_add_signed(d) };
+    t.ok_or_else(|| invalid("out of range".to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolutionChoice {
+    Raw,
+    Rollup(Resolution),
+    /// Pick from the queried time span (see `AUTO_*_SPAN_SECS`).
+    Auto,
+}
+
+impl ResolutionChoice {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(ResolutionChoice::Raw),
+            "auto" => Ok(ResolutionChoice::Auto),
+            other => match Resolution::parse(other) {
+                Some(r) => Ok(ResolutionChoice::Rollup(r)),
+                None => bail!("unknown resolution '{}', expected raw, 1m, 1h or auto", other),
+            },
+        }
+    }
+}
+
+/// Row order of a range query's result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    #[default]
+    Asc,
+    /// Most recent first.
+    Desc,
+}
+
+impl Order {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "asc" => Ok(Order::Asc),
+            "desc" => Ok(Order::Desc),
+            other => bail!("unknown order '{}', expected asc or desc", other),
+        }
+    }
+}
+
+/// Put `rows` (ascending) into `order` and keep at most `limit` of them.
+fn apply_order<T>(rows: &mut Vec<T>, order: Order, limit: Option<usize>) {
+    if order == Order::Desc {
+        rows.reverse();
+    }
+    if let Some(n) = limit {
+        rows.truncate(n);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RangeQuery {
+    pub station_id: String,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub resolution: ResolutionChoice,
+    /// Fields to aggregate; empty selects every numeric field in the range.
+    /// Names of `derived` fields add them to raw rows.
+    pub fields: Vec<String>,
+    /// When non-empty the query returns aggregates over the raw range instead of rows.
+    pub aggregations: Vec<AggFn>,
+    /// GROUP BY time: bucket width in seconds. Implies aggregation (`mean` if none given).
+    pub interval_secs: Option<i64>,
+    /// Zone whose wall clock the `interval_secs` windows align to; UTC when unset.
+    pub tz: Option<Tz>,
+    /// Emit and fill the `interval_secs` windows without values.
+    pub fill: aggregate::Fill,
+    /// Units values are returned in; as stored when empty.
+    pub units: convert::Units,
+    /// Latency budget for row queries; coarser rollups are used when the
+    /// chosen resolution is estimated to take longer.
+    pub max_latency_ms: Option<u64>,
+    /// Order of returned rows or buckets.
+    pub order: Order,
+    /// Return at most this many rows or buckets, counted in `order`.
+    pub limit: Option<usize>,
+    /// Also return the station's stored forecast for the range.
+    pub forecast: bool,
+}
+
+impl RangeQuery {
+    /// A row query over `[start, end)` at the automatic resolution.
+    pub fn new(station_id: &str, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Self {
+        Self {
+            station_id: station_id.to_string(),
+            start,
+            end,
+            resolution: ResolutionChoice::Auto,
+            fields: Vec::new(),
+            aggregations: Vec::new(),
+            interval_secs: None,
+            tz: None,
+            fill: aggregate::Fill::None,
+            units: convert::Units::default(),
+            max_latency_ms: None,
+            order: Order::Asc,
+            limit: None,
+            forecast: false,
+        }
+    }
+
+    fn contains(&self, t: Option<DateTime<Utc>>) -> bool {
+        if self.start.is_none() && self.end.is_none() {
+            return true;
+        }
+        let Some(t) = t else { return false };
+        self.start.is_none_or(|s| t >= s) && self.end.is_none_or(|e| t < e)
+    }
+
+    /// Resolve `Auto` to a concrete choice. An open-ended range counts as
+    /// long; derived fields are only computed from raw rows.
+    pub fn effective_resolution(&self) -> ResolutionChoice {
+        match self.resolution {
+            ResolutionChoice::Auto if !derived::requested(&self.fields).is_empty() => ResolutionChoice::Raw,
+            ResolutionChoice::Auto => {
+                let span = match (self.start, self.end) {
+                    (Some(s), Some(e)) => (e - s).num_seconds(),
+                    (Some(s), None) => (Utc::now() - s).num_seconds(),
+                    _ => i64::MAX,
+                };
+                if span > AUTO_HOUR_SPAN_SECS {
+                    ResolutionChoice::Rollup(Resolution::Hour)
+                } else if span > AUTO_MINUTE_SPAN_SECS {
+                    ResolutionChoice::Rollup(Resolution::Minute)
+                } else {
+                    ResolutionChoice::Raw
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+impl ResolutionChoice {
+    /// Coarser resolutions, finest first; `Auto` has to be resolved before use.
+    const LADDER: [ResolutionChoice; 3] =
+        [ResolutionChoice::Raw, ResolutionChoice::Rollup(Resolution::Minute), ResolutionChoice::Rollup(Resolution::Hour)];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResolutionChoice::Raw => "raw",
+            ResolutionChoice::Rollup(r) => r.as_str(),
+            ResolutionChoice::Auto => "auto",
+        }
+    }
+}
+
+/// What a query would have to read, from the manifest alone.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanEstimate {
+    pub raw_bytes: u64,
+    pub raw_rows: u64,
+    pub span_secs: i64,
+}
+
+impl ScanEstimate {
+    pub fn cost_ms(&self, choice: ResolutionChoice) -> u64 {
+        let bytes = match choice {
+            ResolutionChoice::Rollup(r) => {
+                let buckets = (self.span_secs / r.secs() + 1).max(0) as u64;
+                buckets.min(self.raw_rows) * ROLLUP_ROW_BYTES
+            }
+            _ => self.raw_bytes,
+        };
+        bytes / SCAN_BYTES_PER_MS
+    }
+}
+
+/// Step down from `choice` to coarser resolutions until the estimated cost
+/// fits `budget_ms`; the coarsest resolution is used if nothing fits.
+pub fn fit_budget(choice: ResolutionChoice, est: &ScanEstimate, budget_ms: u64) -> ResolutionChoice {
+    let start = ResolutionChoice::LADDER.iter().position(|c| *c == choice).unwrap_or(0);
+    let ladder = &ResolutionChoice::LADDER[start..];
+    ladder.iter().copied().find(|c| est.cost_ms(*c) <= budget_ms).unwrap_or(ladder[ladder.len() - 1])
+}
+
+/// Estimate a query's scan size from the chunks of its station overlapping the range.
+pub async fn estimate(state: &AppState, q: &RangeQuery) -> ScanEstimate {
+    let manifest = state.chunk_store.manifest().await;
+    let parse = |t: &Option<String>| t.as_deref().and_then(|t| parse_time(t).ok());
+    let mut est = ScanEstimate::default();
+    let (mut first, mut last): (Option<DateTime<Utc>>, Option<DateTime<Utc>>) = (None, None);
+    for slice in manifest.chunks.values().flat_map(|m| m.slices_for(&q.station_id)) {
+        let (min, max) = (parse(&slice.min_time), parse(&slice.max_time));
+        let overlaps = q.start.is_none_or(|s| max.is_none_or(|m| m >= s)) && q.end.is_none_or(|e| min.is_none_or(|m| m < e));
+        if !overlaps {
+            continue;
+        }
+        est.raw_bytes += slice.len;
+        est.raw_rows += slice.rows as u64;
+        if let Some(min) = min {
+            first = Some(first.map_or(min, |f| f.min(min)));
+        }
+        last = last.max(max);
+    }
+    if let (Some(s), Some(e)) = (q.start.or(first), q.end.or(last)) {
+        est.span_secs = (e - s).num_seconds().max(0);
+    }
+    est
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Rows {
+    Raw(Vec<Observation>),
+    Rollup(Vec<RollupRow>),
+    Buckets(Vec<BucketRow>),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryResult {
+    pub station_id: String,
+    /// The station's registered metadata, when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<crate::stations::StationMeta>,
+    /// Resolution actually used: "raw", "1m" or "1h".
+    pub resolution: &'static str,
+    /// Set when `max_latency_ms` forced a coarser resolution than planned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downgraded_from: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<Rows>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregates: Option<Aggregates>,
+    /// Predicted rows from the forecast namespace, when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forecast: Option<Vec<Observation>>,
+    /// Set when `limit` cut raw rows short: pass it back as `cursor` for the
+    /// next page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl QueryResult {
+    /// A raw result with nothing in it yet.
+    pub fn empty(station_id: &str) -> Self {
+        Self {
+            station_id: station_id.to_string(),
+            metadata: None,
+            resolution: "raw",
+            downgraded_from: None,
+            rows: None,
+            aggregates: None,
+            forecast: None,
+            next_cursor: None,
+        }
+    }
+}
+
+/// Raw observations for the query range from chunks plus the unflushed
+/// MemTable, ordered by time. Only chunks whose range overlaps the query are
+/// read; their rows are merged by time, since late data makes them overlap,
+/// spilling to disk when the range holds more rows than `spill.memory_rows`.
+pub async fn scan_raw(state: &AppState, q: &RangeQuery) -> Result<Vec<Observation>> {
+    let parse = |t: &Option<String>| t.as_deref().and_then(|t| parse_time(t).ok());
+    let mut runs = spill::ExternalMerge::new(&state.config.spill);
+    for (name, slice) in state.chunk_store.slices(&q.station_id).await {
+        let (min, max) = (parse(&slice.min_time), parse(&slice.max_time));
+        if q.start.is_some_and(|s| max.is_some_and(|m| m < s)) || q.end.is_some_and(|e| min.is_some_and(|m| m >= e)) {
+            continue;
+        }
+        let mut rows = state.chunk_store.read_slice(&name, &slice).await?;
+        rows.retain(|o| q.contains(Some(o.timestamp())));
+        runs.push(rows).await?;
+    }
+    let buffered: Vec<Observation> = {
+        let mt = state.memtable.lock().await;
+        mt.buffer.get(&q.station_id).map_or_else(Vec::new, |b| b.iter().filter(|o| q.contains(Some(o.timestamp()))).cloned().collect())
+    };
+    runs.push(buffered).await?;
+    if runs.spilled_bytes() == 0 {
+        return runs.finish().collect();
+    }
+    state.metrics.query_spill_bytes.inc_by(runs.spilled_bytes());
+    tracing::info!(station_id = q.station_id, "query spilled {} bytes to disk", runs.spilled_bytes());
+    let merged = runs.finish();
+    tokio::task::spawn_blocking(move || merged.collect()).await?
+}
+
+/// Raw rows in the query's order and limit. Most-recent-first pages walk the
+/// chunks backwards from the newest and stop once the page is full, instead of
+/// reading the whole range.
+pub async fn scan_rows(state: &AppState, q: &RangeQuery) -> Result<Vec<Observation>> {
+    if let (Order::Desc, Some(n)) = (q.order, q.limit) {
+        let mut rows = newest_rows(state, &q.station_id, n, q.start, q.end).await?;
+        rows.reverse();
+        return Ok(rows);
+    }
+    let mut rows = scan_raw(state, q).await?;
+    apply_order(&mut rows, q.order, q.limit);
+    Ok(rows)
+}
+
+/// Stored rollups merged with rollups of the unflushed MemTable data.
+pub async fn scan_rollups(state: &AppState, q: &RangeQuery, res: Resolution) -> Result<Vec<RollupRow>> {
+    // stored rollups still count rows a pending range delete hides
+    let bound = |t: Option<DateTime<Utc>>| t.map(Timestamp::from_datetime);
+    let deleted = state.chunk_store.tombstones(Some(&q.station_id)).await;
+    let rows = if deleted.iter().any(|t| t.overlaps(bound(q.start), bound(q.end))) {
+        rollup::compute(&scan_raw(state, q).await?, res)
+    } else {
+        let mut rows = state.rollups.read(&q.station_id, res).await?;
+        let mt = state.memtable.lock().await;
+        if let Some(buffered) = mt.buffer.get(&q.station_id) {
+            rows.extend(rollup::compute(buffered, res));
+        }
+        rows
+    };
+    let mut rows = rollup::merge(rows);
+    rows.retain(|r| {
+        let t = DateTime::parse_from_rfc3339(&r.time).ok().map(|t| t.with_timezone(&Utc));
+        q.contains(t)
+    });
+    Ok(rows)
+}
+
+/// Newest observation for a station: the last-value cache (fed by the write
+/// path), then the MemTable, then only the newest chunk per the manifest.
+/// The MemTable is considered last so its writes win a tie.
+pub async fn latest(state: &AppState, station_id: &str) -> Result<Option<Observation>> {
+    if let Some(obs) = state.last_values.lock().await.get(station_id) {
+        state.metrics.last_value_hits.inc_by(1);
+        return Ok(Some(obs.clone()));
+    }
+    state.metrics.last_value_misses.inc_by(1);
+    let mut newest: Option<Observation> = None;
+    let mut consider = |o: &Observation| {
+        if newest.as_ref().is_none_or(|n| !last_values::is_newer(n, o)) {
+            newest = Some(o.clone());
+        }
+    };
+    // the newest chunk with rows left once range deletes are masked
+    for (chunk, slice) in state.chunk_store.chunks_newest_first(station_id).await {
+        let rows = state.chunk_store.read_slice(&chunk, &slice).await?;
+        if !rows.is_empty() {
+            rows.iter().for_each(&mut consider);
+            break;
+        }
+    }
+    if let Some(buffered) = state.memtable.lock().await.buffer.get(station_id) {
+        buffered.iter().for_each(&mut consider);
+    }
+    if let Some(obs) = &newest {
+        state.last_values.lock().await.observe(obs);
+    }
+    Ok(newest)
+}
+
+/// Upper bound on `n` for [`tail`].
+pub const MAX_TAIL: usize = 10_000;
+
+/// Sort `rows` by time, keep the last write (highest rank) of duplicate
+/// times and drop all but the newest `n`.
+fn keep_newest(rows: &mut Vec<(usize, Observation)>, n: usize) {
+    let mut by_time: BTreeMap<Timestamp, (usize, Observation)> = BTreeMap::new();
+    for (rank, o) in rows.drain(..) {
+        match by_time.entry(o.time) {
+            Entry::Occupied(kept) if kept.get().0 > rank => {}
+            Entry::Occupied(mut kept) => {
+                kept.insert((rank, o));
+            }
+            Entry::Vacant(slot) => {
+                slot.insert((rank, o));
+            }
+        }
+    }
+    let excess = by_time.len().saturating_sub(n);
+    rows.extend(by_time.into_values().skip(excess));
+}
+
+/// The newest `n` observations of a station within `[start, end)`, oldest
+/// first. Chunks are read newest-first and the walk stops as soon as the next
+/// chunk ends before the oldest row already kept (or before `start`), so older
+/// history is never touched. Rows are ranked by the write order of their
+/// chunk (MemTable last) to settle duplicates.
+async fn newest_rows(
+    state: &AppState,
+    station_id: &str,
+    n: usize,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+) -> Result<Vec<Observation>> {
+    let in_range = |o: &Observation| {
+        let t = o.timestamp();
+        start.is_none_or(|s| t >= s) && end.is_none_or(|e| t < e)
+    };
+    let rank: HashMap<String, usize> =
+        state.chunk_store.slices(station_id).await.into_iter().enumerate().map(|(i, (name, _))| (name, i)).collect();
+    let mut rows: Vec<(usize, Observation)> = {
+        let mt = state.memtable.lock().await;
+        let buffered = mt.buffer.get(station_id).into_iter().flatten();
+        buffered.filter(|o| in_range(o)).map(|o| (usize::MAX, o.clone())).collect()
+    };
+    for (name, slice) in state.chunk_store.chunks_newest_first(station_id).await {
+        let chunk_min = slice.min_time.as_deref().and_then(|t| parse_time(t).ok());
+        let chunk_max = slice.max_time.as_deref().and_then(|t| parse_time(t).ok());
+        if let (Some(e), Some(min)) = (end, chunk_min) {
+            if min >= e {
+                continue;
+            }
+        }
+        if let (Some(s), Some(max)) = (start, chunk_max) {
+            if max < s {
+                break;
+            }
+        }
+        if rows.len() >= n {
+            keep_newest(&mut rows, n);
+            if let (Some(max), Some(oldest)) = (chunk_max, rows.first().map(|(_, o)| o.timestamp())) {
+                if max < oldest {
+                    break;
+                }
+            }
+        }
+        let r = rank.get(&name).copied().unwrap_or(0);
+        rows.extend(state.chunk_store.read_slice(&name, &slice).await?.into_iter().filter(|o| in_range(o)).map(|o| (r, o)));
+    }
+    keep_newest(&mut rows, n);
+    Ok(rows.into_iter().map(|(_, o)| o).collect())
+}
+
+/// The `n` most recent observations of a station, oldest first.
+pub async fn tail(state: &AppState, station_id: &str, n: usize) -> Result<Vec<Observation>> {
+    newest_rows(state, station_id, n, None, None).await
+}
+
+/// Every known station with its time span and point count, from the chunk
+/// manifest plus the unflushed MemTable.
+pub async fn stations(state: &AppState) -> Vec<StationInfo> {
+    let mut index = state.chunk_store.manifest().await.stations();
+    {
+        let mt = state.memtable.lock().await;
+        for (station_id, buffered) in &mt.buffer {
+            let info = index.entry(station_id.clone()).or_insert_with(|| StationInfo::new(station_id));
+            let min = buffered.iter().map(|o| o.time).min().map(|t| t.to_string());
+            let max = buffered.iter().map(|o| o.time).max().map(|t| t.to_string());
+            info.extend(buffered.len(), min.as_ref(), max.as_ref());
+        }
+    }
+    index.into_values().collect()
+}
+
+/// Run `fut` as its own task on `rt` so a panic (for example from a
+/// pathological chunk) comes back as an error for this request only.
+pub async fn isolate<T, F>(rt: &Handle, what: &str, fut: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: std::future::Future<Output = Result<T>> + Send + 'static,
+{
+    match rt.spawn(fut).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => {
+            let payload = e.into_panic();
+            let msg = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            tracing::error!("{} panicked: {}", what, msg);
+            bail!("{} panicked: {}", what, msg)
+        }
+        Err(e) => bail!("{} failed: {}", what, e),
+    }
+}
+
+/// [`execute`] on the query runtime, with panics isolated to the query.
+pub async fn execute_isolated(state: Arc<AppState>, q: RangeQuery) -> Result<QueryResult> {
+    let what = format!("query for station {}", q.station_id);
+    let rt = state.runtimes.query.clone();
+    isolate(&rt, &what, async move { execute(&state, &q).await }).await
+}
+
+/// The newest prediction for each time in the query range from the
+/// station's forecast namespace; being later writes, later forecasts replace
+/// earlier ones.
+pub async fn scan_forecast(state: &AppState, q: &RangeQuery) -> Result<Vec<Observation>> {
+    let fq = RangeQuery { station_id: crate::forecast::station_id(&q.station_id), ..q.clone() };
+    scan_raw(state, &fq).await
+}
+
+/// A raw row as a query returns it: with the derived fields it names, in
+/// the units it asks for.
+pub fn shape(o: &mut Observation, derived: &[derived::Derived], units: &convert::Units) {
+    derived::apply(o, derived);
+    units.apply(o);
+}
+
+pub async fn execute(state: &AppState, q: &RangeQuery) -> Result<QueryResult> {
+    let mut result = QueryResult::empty(&q.station_id);
+    if q.forecast {
+        let mut forecast = scan_forecast(state, q).await?;
+        forecast.iter_mut().for_each(|o| q.units.apply(o));
+        result.forecast = Some(forecast);
+    }
+    let derived = derived::requested(&q.fields);
+    let scan = || async {
+        let mut obs = scan_raw(state, q).await?;
+        // rows in other units would skew the windows and aggregates
+        obs.iter_mut().for_each(|o| {
+            convert::canonical(o);
+            shape(o, &derived, &q.units);
+        });
+        Ok::<_, anyhow::Error>(obs)
+    };
+    if let Some(interval) = q.interval_secs {
+        let aggs = if q.aggregations.is_empty() { vec![AggFn::Mean] } else { q.aggregations.clone() };
+        let obs = scan().await?;
+        // filled windows run up to now when the range is open
+        let end = q.end.unwrap_or_else(Utc::now);
+        let windows = aggregate::Windows { interval_secs: interval, tz: q.tz, fill: q.fill, span: (q.start.map(|s| s.timestamp()), Some(end.timestamp())) };
+        let mut buckets = aggregate::aggregate_buckets(&obs, &windows, &q.fields, &aggs)?;
+        apply_order(&mut buckets, q.order, q.limit);
+        result.rows = Some(Rows::Buckets(buckets));
+        return Ok(result);
+    }
+    if !q.aggregations.is_empty() {
+        // aggregates are always computed over the merged raw view
+        let obs = scan().await?;
+        result.aggregates = Some(aggregate::aggregate(&obs, &q.fields, &q.aggregations));
+        return Ok(result);
+    }
+    let mut choice = q.effective_resolution();
+    if !derived.is_empty() && choice != ResolutionChoice::Raw {
+        bail!("{} is derived from raw rows; use resolution=raw, or an interval for windows", derived[0].name());
+    }
+    if let Some(budget) = q.max_latency_ms.filter(|_| derived.is_empty()) {
+        let fitted = fit_budget(choice, &estimate(state, q).await, budget);
+        if fitted != choice {
+            result.downgraded_from = Some(choice.as_str());
+            choice = fitted;
+        }
+    }
+    match choice {
+        ResolutionChoice::Rollup(res) => {
+            result.resolution = res.as_str();
+            let mut rows = scan_rollups(state, q, res).await?;
+            apply_order(&mut rows, q.order, q.limit);
+            rows.iter_mut().for_each(|r| q.units.apply_rollup(r));
+            result.rows = Some(Rows::Rollup(rows));
+        }
+        _ => {
+            // one row past the page tells whether there is another
+            let mut rows = scan_rows(state, &RangeQuery { limit: q.limit.map(|n| n.saturating_add(1)), ..q.clone() }).await?;
+            rows.iter_mut().for_each(|o| shape(o, &derived, &q.units));
+            if let Some(n) = q.limit.filter(|&n| rows.len() > n) {
+                rows.truncate(n);
+                result.next_cursor = rows.last().map(|o| cursor::Cursor { station_id: q.station_id.clone(), order: q.order, time: o.time }.encode());
+            }
+            result.rows = Some(Rows::Raw(rows));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc))
+    }
+
+    fn query(start: &str, end: &str) -> RangeQuery {
+        RangeQuery {
+            station_id: "s".into(),
+            start: at(start),
+            end: at(end),
+            resolution: ResolutionChoice::Auto,
+            fields: Vec::new(),
+            aggregations: Vec::new(),
+            interval_secs: None,
+            tz: None,
+            fill: aggregate::Fill::None,
+            units: convert::Units::default(),
+            max_latency_ms: None,
+            order: Order::Asc,
+            limit: None,
+            forecast: false,
+        }
+    }
+
+    #[test]
+    fn keeps_newest_rows_in_order() {
+        let mut rows: Vec<(usize, Observation)> =
+            [(0, "2025-01-01T00:03:00Z"), (0, "2025-01-01T00:01:00Z"), (2, "2025-01-01T00:02:00Z"), (1, "2025-01-01T00:02:00Z")]
+                .iter()
+                .map(|(rank, t)| (*rank, Observation::empty("s", t.parse().unwrap())))
+                .collect();
+        keep_newest(&mut rows, 2);
+        let kept: Vec<(usize, String)> = rows.iter().map(|(rank, o)| (*rank, o.time.to_string())).collect();
+        assert_eq!(kept, vec![(2, "2025-01-01T00:02:00Z".to_string()), (0, "2025-01-01T00:03:00Z".to_string())]);
+    }
+
+    #[test]
+    fn orders_and_limits_rows() {
+        let mut rows = vec![1, 2, 3, 4];
+        apply_order(&mut rows, Order::Desc, Some(3));
+        assert_eq!(rows, vec![4, 3, 2]);
+        let mut rows = vec![1, 2, 3];
+        apply_order(&mut rows, Order::Asc, Some(2));
+        assert_eq!(rows, vec![1, 2]);
+        assert!(Order::parse("sideways").is_err());
+    }
+
+    #[test]
+    fn auto_resolution_follows_span() {
+        let q = query("2025-01-01T00:00:00Z", "2025-01-01T01:00:00Z");
+        assert_eq!(q.effective_resolution(), ResolutionChoice::Raw);
+        let q = query("2025-01-01T00:00:00Z", "2025-01-01T12:00:00Z");
+        assert_eq!(q.effective_resolution(), ResolutionChoice::Rollup(Resolution::Minute));
+        let q = query("2025-01-01T00:00:00Z", "2025-02-01T00:00:00Z");
+        assert_eq!(q.effective_resolution(), ResolutionChoice::Rollup(Resolution::Hour));
+    }
+
+    #[test]
+    fn budget_steps_down_to_rollups() {
+        // ~10 MB of raw chunks over 30 days of 10s readings
+        let est = ScanEstimate { raw_bytes: 10_000_000, raw_rows: 259_200, span_secs: 30 * 86_400 };
+        assert_eq!(fit_budget(ResolutionChoice::Raw, &est, 1000), ResolutionChoice::Raw);
+        assert_eq!(fit_budget(ResolutionChoice::Raw, &est, 100), ResolutionChoice::Rollup(Resolution::Hour));
+        let short = ScanEstimate { raw_bytes: 200_000, raw_rows: 2000, span_secs: 3600 };
+        assert_eq!(fit_budget(ResolutionChoice::Raw, &short, 5), ResolutionChoice::Rollup(Resolution::Minute));
+        // never gets finer than requested, and falls back to the coarsest
+        assert_eq!(fit_budget(ResolutionChoice::Rollup(Resolution::Hour), &short, 1000), ResolutionChoice::Rollup(Resolution::Hour));
+        assert_eq!(fit_budget(ResolutionChoice::Raw, &est, 0), ResolutionChoice::Rollup(Resolution::Hour));
+    }
+
+    #[tokio::test]
+    async fn isolates_panics() {
+        let rt = Handle::current();
+        let err = isolate(&rt, "test query", async { panic!("bad chunk") as Result<()> }).await.unwrap_err();
+        assert!(err.to_string().contains("test query panicked: bad chunk"));
+        assert_eq!(isolate(&rt, "ok", async { Ok(1) }).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn parses_resolution() {
+        assert_eq!(ResolutionChoice::parse("1h").unwrap(), ResolutionChoice::Rollup(Resolution::Hour));
+        assert_eq!(ResolutionChoice::parse("raw").unwrap(), ResolutionChoice::Raw);
+        assert!(ResolutionChoice::parse("5m").is_err());
+    }
+
+    #[test]
+    fn parses_relative_times() {
+        let now = at("2025-01-01T12:00:00Z").unwrap();
+        assert_eq!(parse_time_at("now", now).unwrap(), now);
+        assert_eq!(parse_time_at("now-6h", now).unwrap(), at("2025-01-01T06:00:00Z").unwrap());
+        assert_eq!(parse_time_at("-1d", now).unwrap(), at("2024-12-31T12:00:00Z").unwrap());
+        assert_eq!(parse_time_at("now 1h30m", now).unwrap(), at("2025-01-01T13:30:00Z").unwrap());
+        assert_eq!(parse_time_at("2025-01-01T00:00:00Z", now).unwrap(), at("2025-01-01T00:00:00Z").unwrap());
+        assert!(parse_time_at("now*2", now).is_err() && parse_time_at("now-", now).is_err() && parse_time_at("now-999999999y", now).is_err());
+    }
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(parse_interval("5m").unwrap(), 300);
+        assert_eq!(parse_interval("1d").unwrap(), 86_400);
+        assert!(parse_interval("0m").is_err());
+        assert_eq!(parse_interval("1w").unwrap(), 604_800);
+        assert_eq!(parse_interval("1h30m").unwrap(), 5400);
+        assert!(parse_interval("1500ms").is_err());
+        assert!(parse_interval("m").is_err());
+    }
+}
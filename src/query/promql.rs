@@ -0,0 +1,741 @@
+// A subset of PromQL over station fields, so Grafana's Prometheus data source
+// can chart SkyPulseDB. Series are named the way remote write stores them
+// (see `ingest::prom_remote`): the metric is a field, with an optional
+// `skypulse_` prefix, `station_id` is the station and the other labels are
+// the row's tags. Supported are instant and range selectors with `=`, `!=`,
+// `=~` and `!~` matchers, `rate`, `increase` and the `<agg>_over_time`
+// functions, `sum`, `avg`, `min`, `max` and `count` with an optional
+// `by (...)`, and arithmetic with a number on one side. Offsets, subqueries
+// and operators between two vectors are not.
+
+use std::collections::{BTreeMap, BTreeSet};
+use anyhow::{anyhow, bail, Result};
+use regex::Regex;
+use super::{derived::Derived, RangeQuery};
+use crate::api::filter::ResponseFilter;
+use crate::storage::memtable::Timestamp;
+use crate::AppState;
+
+/// How far back an instant selector looks for a station's latest sample.
+pub const LOOKBACK_MS: i64 = 5 * 60_000;
+/// Most points a range query may return per series.
+pub const MAX_STEPS: i64 = 11_000;
+/// Most rows the selectors of one query may read, over all stations.
+pub const MAX_ROWS: usize = 1_000_000;
+
+/// A query whose selectors read more than [`MAX_ROWS`] rows; the API answers
+/// it with 400 rather than as an execution error.
+#[derive(Debug)]
+pub struct TooManyRows;
+
+impl std::fmt::Display for TooManyRows {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "query reads more than {} rows; narrow the range or the selectors", MAX_ROWS)
+    }
+}
+
+impl std::error::Error for TooManyRows {}
+
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Clone)]
+pub enum MatchOp {
+    Eq,
+    Ne,
+    Re(Regex),
+    NotRe(Regex),
+}
+
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub label: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+impl Matcher {
+    fn matches(&self, v: &str) -> bool {
+        match &self.op {
+            MatchOp::Eq => v == self.value,
+            MatchOp::Ne => v != self.value,
+            MatchOp::Re(r) => r.is_match(v),
+            MatchOp::NotRe(r) => !r.is_match(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Selector {
+    /// Metric name as written.
+    pub metric: String,
+    pub matchers: Vec<Matcher>,
+    /// Window of a range selector such as `[5m]`.
+    pub range_ms: Option<i64>,
+}
+
+impl Selector {
+    pub fn field(&self) -> &str {
+        self.metric.strip_prefix("skypulse_").unwrap_or(&self.metric)
+    }
+
+    /// Whether a series with `labels` is selected; a missing label matches as empty.
+    fn matches(&self, labels: &Labels) -> bool {
+        self.matchers.iter().all(|m| m.matches(labels.get(&m.label).map_or("", String::as_str)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Func {
+    Rate,
+    Increase,
+    AvgOverTime,
+    MinOverTime,
+    MaxOverTime,
+    SumOverTime,
+    CountOverTime,
+    LastOverTime,
+}
+
+impl Func {
+    const ALL: [(&'static str, Func); 8] = [
+        ("rate", Func::Rate),
+        ("increase", Func::Increase),
+        ("avg_over_time", Func::AvgOverTime),
+        ("min_over_time", Func::MinOverTime),
+        ("max_over_time", Func::MaxOverTime),
+        ("sum_over_time", Func::SumOverTime),
+        ("count_over_time", Func::CountOverTime),
+        ("last_over_time", Func::LastOverTime),
+    ];
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.iter().find(|(name, _)| *name == s).map(|(_, f)| *f)
+    }
+
+    /// The function over the samples of one window, oldest first.
+    /// `rate` and `increase` count a drop as a counter reset, and unlike
+    /// Prometheus do not extrapolate to the edges of the window: `rate` is
+    /// the increase over the time between the first and last sample.
+    fn apply(self, window: &[(i64, f64)]) -> Option<f64> {
+        let values = window.iter().map(|(_, v)| *v);
+        let n = window.len() as f64;
+        match self {
+            Func::Rate | Func::Increase => {
+                let (first, last) = (window.first()?, window.last()?);
+                if window.len() < 2 {
+                    return None;
+                }
+                let increase: f64 = window.windows(2).map(|w| if w[1].1 < w[0].1 { w[1].1 } else { w[1].1 - w[0].1 }).sum();
+                if self == Func::Increase {
+                    return Some(increase);
+                }
+                Some(increase / ((last.0 - first.0) as f64 / 1000.0))
+            }
+            Func::AvgOverTime => (n > 0.0).then(|| values.sum::<f64>() / n),
+            Func::MinOverTime => values.reduce(f64::min),
+            Func::MaxOverTime => values.reduce(f64::max),
+            Func::SumOverTime => (n > 0.0).then(|| values.sum()),
+            Func::CountOverTime => (n > 0.0).then_some(n),
+            Func::LastOverTime => window.last().map(|(_, v)| *v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl AggOp {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sum" => Some(AggOp::Sum),
+            "avg" => Some(AggOp::Avg),
+            "min" => Some(AggOp::Min),
+            "max" => Some(AggOp::Max),
+            "count" => Some(AggOp::Count),
+            _ => None,
+        }
+    }
+
+    fn apply(self, values: &[f64]) -> f64 {
+        let it = values.iter().copied();
+        match self {
+            AggOp::Sum => it.sum(),
+            AggOp::Avg => it.sum::<f64>() / values.len() as f64,
+            AggOp::Min => it.fold(f64::INFINITY, f64::min),
+            AggOp::Max => it.fold(f64::NEG_INFINITY, f64::max),
+            AggOp::Count => values.len() as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinOp {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => a * b,
+            BinOp::Div => a / b,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    Call(Func, Selector),
+    Aggregate { op: AggOp, by: Vec<String>, arg: Box<Expr> },
+    Binary { op: BinOp, lhs: Box<Expr>, rhs: Box<Expr> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub labels: Labels,
+    /// `(millis, value)`, oldest first.
+    pub points: Vec<(i64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    /// One point per evaluation step.
+    Vector(Vec<Series>),
+    /// The raw samples of a range selector, from an instant query.
+    Matrix(Vec<Series>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(f64),
+    /// The text between `[` and `]`.
+    Range(String),
+    Sym(&'static str),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            c if c.is_ascii_alphabetic() || c == '_' || c == ':' => {
+                let mut ident = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == ':') {
+                    ident.push(c);
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut num = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '.') {
+                    num.push(c);
+                }
+                tokens.push(Token::Number(num.parse().map_err(|_| anyhow!("invalid number '{}'", num))?));
+            }
+            '"' | '\'' | '`' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c != '`' => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(x) => text.push(x),
+                            None => bail!("unterminated string"),
+                        },
+                        Some(x) => text.push(x),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                tokens.push(Token::Str(text));
+            }
+            '[' => {
+                let range: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                tokens.push(Token::Range(range));
+            }
+            _ => {
+                let sym = match (c, chars.peek()) {
+                    ('!', Some('=')) => "!=",
+                    ('!', Some('~')) => "!~",
+                    ('=', Some('~')) => "=~",
+                    ('=', Some('=')) => bail!("comparison operators are not supported"),
+                    ('=', _) => "=",
+                    ('{', _) => "{",
+                    ('}', _) => "}",
+                    ('(', _) => "(",
+                    (')', _) => ")",
+                    (',', _) => ",",
+                    ('+', _) => "+",
+                    ('-', _) => "-",
+                    ('*', _) => "*",
+                    ('/', _) => "/",
+                    _ => bail!("unexpected '{}'", c),
+                };
+                if sym.len() == 2 {
+                    chars.next();
+                }
+                tokens.push(Token::Sym(sym));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn unexpected(&self, expected: &str) -> anyhow::Error {
+        match self.peek() {
+            Some(t) => anyhow!("expected {}, found {:?}", expected, t),
+            None => anyhow!("expected {}, found the end of the query", expected),
+        }
+    }
+
+    fn symbol(&mut self, sym: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Sym(s)) if *s == sym);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, sym: &str) -> Result<()> {
+        if !self.symbol(sym) {
+            return Err(self.unexpected(&format!("'{}'", sym)));
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self, what: &str) -> Result<String> {
+        match self.peek() {
+            Some(Token::Ident(i)) => {
+                let i = i.clone();
+                self.pos += 1;
+                Ok(i)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    /// `a + b`, `a - b`
+    fn expr(&mut self) -> Result<Expr> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.symbol("+") {
+                BinOp::Add
+            } else if self.symbol("-") {
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(self.term()?) };
+        }
+    }
+
+    /// `a * b`, `a / b`
+    fn term(&mut self) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.symbol("*") {
+                BinOp::Mul
+            } else if self.symbol("/") {
+                BinOp::Div
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(self.unary()?) };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.symbol("-") {
+            let arg = self.unary()?;
+            return Ok(Expr::Binary { op: BinOp::Mul, lhs: Box::new(Expr::Number(-1.0)), rhs: Box::new(arg) });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        if let Some(Token::Number(n)) = self.peek() {
+            let n = *n;
+            self.pos += 1;
+            return Ok(Expr::Number(n));
+        }
+        if self.symbol("(") {
+            let e = self.expr()?;
+            self.expect(")")?;
+            return Ok(e);
+        }
+        if self.symbol("{") {
+            return self.selector(None);
+        }
+        let name = self.ident("a metric, function or aggregation")?;
+        let call = matches!(self.peek(), Some(Token::Sym("(")));
+        if let Some(op) = AggOp::parse(&name).filter(|_| call || matches!(self.peek(), Some(Token::Ident(i)) if i == "by")) {
+            let mut by = self.by()?;
+            self.expect("(")?;
+            let arg = self.expr()?;
+            self.expect(")")?;
+            if by.is_empty() {
+                by = self.by()?;
+            }
+            return Ok(Expr::Aggregate { op, by, arg: Box::new(arg) });
+        }
+        if call {
+            let func = Func::parse(&name).ok_or_else(|| anyhow!("unsupported function '{}'", name))?;
+            self.expect("(")?;
+            let Expr::Selector(sel) = self.primary()? else { bail!("{}() takes a range selector such as temp[5m]", name) };
+            if sel.range_ms.is_none() {
+                bail!("{}() takes a range selector such as temp[5m]", name);
+            }
+            self.expect(")")?;
+            return Ok(Expr::Call(func, sel));
+        }
+        let braces = self.symbol("{");
+        if braces {
+            return self.selector(Some(name));
+        }
+        self.range(Selector { metric: name, matchers: Vec::new(), range_ms: None })
+    }
+
+    /// An optional `by (label, ...)`.
+    fn by(&mut self) -> Result<Vec<String>> {
+        if !matches!(self.peek(), Some(Token::Ident(i)) if i == "by") {
+            return Ok(Vec::new());
+        }
+        self.pos += 1;
+        self.expect("(")?;
+        let mut labels = Vec::new();
+        while !self.symbol(")") {
+            labels.push(self.ident("a label")?);
+            if !self.symbol(",") {
+                self.expect(")")?;
+                break;
+            }
+        }
+        Ok(labels)
+    }
+
+    /// The matchers of a selector after its `{`.
+    fn selector(&mut self, metric: Option<String>) -> Result<Expr> {
+        let mut matchers = Vec::new();
+        while !self.symbol("}") {
+            let label = self.ident("a label")?;
+            let op = match self.peek() {
+                Some(Token::Sym(op @ ("=" | "!=" | "=~" | "!~"))) => *op,
+                _ => return Err(self.unexpected("=, !=, =~ or !~")),
+            };
+            self.pos += 1;
+            let Some(Token::Str(value)) = self.peek().cloned() else { return Err(self.unexpected("a quoted label value")) };
+            self.pos += 1;
+            // Prometheus anchors regular expressions at both ends
+            let re = || Regex::new(&format!("^(?:{})$", value)).map_err(|e| anyhow!("invalid regex '{}': {}", value, e));
+            let op = match op {
+                "=" => MatchOp::Eq,
+                "!=" => MatchOp::Ne,
+                "=~" => MatchOp::Re(re()?),
+                _ => MatchOp::NotRe(re()?),
+            };
+            matchers.push(Matcher { label, op, value });
+            if !self.symbol(",") {
+                self.expect("}")?;
+                break;
+            }
+        }
+        let named = matchers.iter().position(|m| m.label == "__name__" && matches!(m.op, MatchOp::Eq));
+        let metric = match (metric, named) {
+            (Some(m), _) => m,
+            (None, Some(i)) => matchers.remove(i).value,
+            (None, None) => bail!("a selector needs a metric name"),
+        };
+        self.range(Selector { metric, matchers, range_ms: None })
+    }
+
+    fn range(&mut self, mut sel: Selector) -> Result<Expr> {
+        if let Some(Token::Range(r)) = self.peek() {
+            let d = crate::units::parse_duration(r).map_err(|e| anyhow!("range: {}", e))?;
+            if d.is_zero() {
+                bail!("range [{}] must be positive", r);
+            }
+            sel.range_ms = Some(d.as_millis() as i64);
+            self.pos += 1;
+        }
+        Ok(Expr::Selector(sel))
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut p = Parser { tokens: tokenize(s)?, pos: 0 };
+        let e = p.expr()?;
+        if p.pos < p.tokens.len() {
+            return Err(p.unexpected("the end of the query"));
+        }
+        Ok(e)
+    }
+
+    /// Selectors in evaluation order.
+    fn selectors<'a>(&'a self, out: &mut Vec<&'a Selector>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Selector(s) | Expr::Call(_, s) => out.push(s),
+            Expr::Aggregate { arg, .. } => arg.selectors(out),
+            Expr::Binary { lhs, rhs, .. } => {
+                lhs.selectors(out);
+                rhs.selectors(out);
+            }
+        }
+    }
+
+    /// Evaluate at `steps` with the series each selector loaded, in the
+    /// order of `selectors`.
+    fn eval(&self, loaded: &mut impl Iterator<Item = Vec<Series>>, steps: &[i64]) -> Result<Value> {
+        let mut next = || loaded.next().ok_or_else(|| anyhow!("selector without data"));
+        Ok(match self {
+            Expr::Number(n) => Value::Scalar(*n),
+            Expr::Selector(Selector { range_ms: Some(range), .. }) => {
+                let [t] = steps else { bail!("a range selector needs a function such as rate() in a range query") };
+                let series = next()?.into_iter().filter_map(|s| {
+                    let (lo, hi) = window(&s.points, *t, *range);
+                    (hi > lo).then(|| Series { points: s.points[lo..hi].to_vec(), labels: s.labels })
+                });
+                Value::Matrix(series.collect())
+            }
+            Expr::Selector(_) => Value::Vector(per_step(next()?, steps, true, |points, t| {
+                let (lo, hi) = window(points, t, LOOKBACK_MS);
+                (hi > lo).then(|| points[hi - 1].1)
+            })),
+            Expr::Call(func, sel) => {
+                let range = sel.range_ms.unwrap_or(LOOKBACK_MS);
+                Value::Vector(per_step(next()?, steps, false, |points, t| {
+                    let (lo, hi) = window(points, t, range);
+                    func.apply(&points[lo..hi])
+                }))
+            }
+            Expr::Aggregate { op, by, arg } => {
+                let Value::Vector(series) = arg.eval(loaded, steps)? else { bail!("aggregations take an instant vector") };
+                let mut groups: BTreeMap<Labels, BTreeMap<i64, Vec<f64>>> = BTreeMap::new();
+                for s in series {
+                    let key = by.iter().filter_map(|l| s.labels.get(l).map(|v| (l.clone(), v.clone()))).collect();
+                    let group = groups.entry(key).or_default();
+                    for (t, v) in s.points {
+                        group.entry(t).or_default().push(v);
+                    }
+                }
+                let series = groups.into_iter().map(|(labels, points)| Series {
+                    labels,
+                    points: points.into_iter().map(|(t, values)| (t, op.apply(&values))).collect(),
+                });
+                Value::Vector(series.collect())
+            }
+            Expr::Binary { op, lhs, rhs } => {
+                // arithmetic on a vector drops the metric name, as in Prometheus
+                let map = |v: Vec<Series>, f: &dyn Fn(f64) -> f64| {
+                    let series = v.into_iter().map(|mut s| {
+                        s.labels.remove("__name__");
+                        Series { labels: s.labels, points: s.points.into_iter().map(|(t, x)| (t, f(x))).collect() }
+                    });
+                    Value::Vector(series.collect())
+                };
+                match (lhs.eval(loaded, steps)?, rhs.eval(loaded, steps)?) {
+                    (Value::Scalar(a), Value::Scalar(b)) => Value::Scalar(op.apply(a, b)),
+                    (Value::Vector(v), Value::Scalar(n)) => map(v, &|x| op.apply(x, n)),
+                    (Value::Scalar(n), Value::Vector(v)) => map(v, &|x| op.apply(n, x)),
+                    _ => bail!("operators between two vectors, or on a range selector, are not supported"),
+                }
+            }
+        })
+    }
+}
+
+/// The bounds of the samples in `(t - range, t]`.
+fn window(points: &[(i64, f64)], t: i64, range: i64) -> (usize, usize) {
+    (points.partition_point(|p| p.0 <= t - range), points.partition_point(|p| p.0 <= t))
+}
+
+/// `f` of each series at each step, dropping the series without a value at any.
+fn per_step(series: Vec<Series>, steps: &[i64], keep_name: bool, f: impl Fn(&[(i64, f64)], i64) -> Option<f64>) -> Vec<Series> {
+    let out = series.into_iter().filter_map(|mut s| {
+        let points: Vec<(i64, f64)> = steps.iter().filter_map(|&t| f(&s.points, t).map(|v| (t, v))).collect();
+        if !keep_name {
+            s.labels.remove("__name__");
+        }
+        (!points.is_empty()).then_some(Series { labels: s.labels, points })
+    });
+    out.collect()
+}
+
+/// Evaluate `expr` at each step from `start_ms` to `end_ms`, both in
+/// milliseconds; an instant query has `start_ms == end_ms`. The key's
+/// `filter` hides and rounds fields and tags as in JSON answers.
+pub async fn evaluate(state: &AppState, expr: &Expr, start_ms: i64, end_ms: i64, step_ms: i64, filter: Option<&ResponseFilter>) -> Result<Value> {
+    if end_ms < start_ms {
+        bail!("end is before start");
+    }
+    if step_ms <= 0 {
+        bail!("step must be positive");
+    }
+    if (end_ms - start_ms) / step_ms >= MAX_STEPS {
+        bail!("more than {} points per series; use a larger step", MAX_STEPS);
+    }
+    let steps: Vec<i64> = (0..).map(|i| start_ms + i * step_ms).take_while(|t| *t <= end_ms).collect();
+    let mut selectors = Vec::new();
+    expr.selectors(&mut selectors);
+    let mut loaded = Vec::new();
+    let mut budget = MAX_ROWS;
+    for sel in selectors {
+        loaded.push(load(state, sel, start_ms - sel.range_ms.unwrap_or(LOOKBACK_MS), end_ms, filter, &mut budget).await?);
+    }
+    expr.eval(&mut loaded.into_iter(), &steps)
+}
+
+/// The series `sel` selects with their samples in `(from_ms, to_ms]`. Rows
+/// read count against `budget`, and running past it fails with [`TooManyRows`].
+async fn load(state: &AppState, sel: &Selector, from_ms: i64, to_ms: i64, filter: Option<&ResponseFilter>, budget: &mut usize) -> Result<Vec<Series>> {
+    let field = sel.field();
+    // computed for each row unless it leaks a field the filter redacts
+    let derived: Vec<Derived> = Derived::parse(field).filter(|d| filter.is_none_or(|f| f.redacted_input(*d).is_none())).into_iter().collect();
+    let station_matchers: Vec<&Matcher> = sel.matchers.iter().filter(|m| m.label == "station_id").collect();
+    let mut series: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+    for info in super::stations(state).await {
+        if !station_matchers.iter().all(|m| m.matches(&info.station_id)) {
+            continue;
+        }
+        let q = RangeQuery::new(&info.station_id, Some(Timestamp(from_ms + 1).to_datetime()), Some(Timestamp(to_ms + 1).to_datetime()));
+        let rows = super::scan_raw(state, &q).await?;
+        *budget = budget.checked_sub(rows.len()).ok_or(TooManyRows)?;
+        for mut o in rows {
+            super::convert::canonical(&mut o);
+            super::derived::apply(&mut o, &derived);
+            let Some(v) = o.number(field) else { continue };
+            let Some(v) = filter.map_or(Some(v), |f| f.number(field, v)) else { continue };
+            let mut labels: Labels = o.tags.into_iter().filter(|(k, _)| filter.is_none_or(|f| !f.redact.contains(k))).collect();
+            labels.insert("__name__".into(), sel.metric.clone());
+            labels.insert("station_id".into(), o.station_id);
+            if sel.matches(&labels) {
+                series.entry(labels).or_default().push((o.time.millis(), v));
+            }
+        }
+    }
+    Ok(series.into_iter().map(|(labels, points)| Series { labels, points }).collect())
+}
+
+/// Values of `label` for Grafana's label browser, from each station's
+/// newest reading: field names for `__name__`, station ids, or tag values.
+pub async fn label_values(state: &AppState, label: &str, filter: Option<&ResponseFilter>) -> Result<BTreeSet<String>> {
+    let mut values = BTreeSet::new();
+    if filter.is_some_and(|f| f.redact.iter().any(|r| r == label)) {
+        return Ok(values);
+    }
+    for info in super::stations(state).await {
+        if label == "station_id" {
+            values.insert(info.station_id);
+            continue;
+        }
+        let Some(o) = super::latest(state, &info.station_id).await? else { continue };
+        let visible = |name: &String| filter.is_none_or(|f| !f.redact.contains(name));
+        match label {
+            "__name__" => values.extend(o.fields.into_iter().filter(|(k, v)| v.is_numeric() && visible(k)).map(|(k, _)| k)),
+            tag => values.extend(o.tags.get(tag).cloned()),
+        }
+    }
+    Ok(values)
+}
+
+/// Label names for Grafana's label browser: the tags of each station's newest reading.
+pub async fn label_names(state: &AppState, filter: Option<&ResponseFilter>) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::from(["__name__".to_string(), "station_id".to_string()]);
+    for info in super::stations(state).await {
+        if let Some(o) = super::latest(state, &info.station_id).await? {
+            names.extend(o.tags.into_keys().filter(|k| filter.is_none_or(|f| !f.redact.contains(k))));
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(station: &str, points: &[(i64, f64)]) -> Series {
+        let labels = Labels::from([("__name__".to_string(), "rain".to_string()), ("station_id".to_string(), station.to_string())]);
+        Series { labels, points: points.to_vec() }
+    }
+
+    fn eval(q: &str, data: Vec<Vec<Series>>, steps: &[i64]) -> Value {
+        Expr::parse(q).unwrap().eval(&mut data.into_iter(), steps).unwrap()
+    }
+
+    #[test]
+    fn parses_and_evaluates_the_subset() {
+        let e = Expr::parse(r#"sum by (region) (rate(skypulse_rain{station_id=~"HK.*", region!=""}[5m])) * 3600"#).unwrap();
+        let Expr::Binary { lhs, .. } = &e else { panic!("{:?}", e) };
+        let Expr::Aggregate { op: AggOp::Sum, by, arg } = &**lhs else { panic!("{:?}", lhs) };
+        assert_eq!(by, &["region"]);
+        let Expr::Call(Func::Rate, sel) = &**arg else { panic!("{:?}", arg) };
+        assert_eq!((sel.field(), sel.range_ms, sel.matchers.len()), ("rain", Some(300_000), 2));
+        assert!(Expr::parse("{__name__=\"temp\"}").is_ok() && Expr::parse("avg(temp) by (station_id)").is_ok());
+        for bad in ["rate(temp)", "temp > 1", "histogram_quantile(0.9, temp)", "temp{station_id=~\"(\"}", "{station_id=\"A\"}", "temp[0s]"] {
+            assert!(Expr::parse(bad).is_err(), "{}", bad);
+        }
+
+        assert_eq!(eval("1 + 1", vec![], &[0]), Value::Scalar(2.0));
+        // the counter resets between 60s and 120s
+        let a = series("A", &[(0, 1.0), (60_000, 4.0), (120_000, 2.0), (400_000, 9.0)]);
+        let b = series("B", &[(60_000, 10.0)]);
+        let Value::Vector(v) = eval("increase(rain[5m])", vec![vec![a.clone()]], &[120_000]) else { panic!() };
+        assert_eq!(v[0].points, [(120_000, 5.0)]);
+        assert!(!v[0].labels.contains_key("__name__"));
+        let Value::Vector(v) = eval("rate(rain[5m])", vec![vec![a.clone()]], &[120_000]) else { panic!() };
+        assert_eq!(v[0].points, [(120_000, 5.0 / 120.0)]);
+        // the latest sample within the lookback, and none after it runs out
+        let Value::Vector(v) = eval("rain", vec![vec![a.clone(), b.clone()]], &[90_000, 400_000]) else { panic!() };
+        assert_eq!((v[0].points.clone(), v[1].points.clone()), (vec![(90_000, 4.0), (400_000, 9.0)], vec![(90_000, 10.0)]));
+        let Value::Vector(v) = eval("max(rain)", vec![vec![a.clone(), b.clone()]], &[90_000]) else { panic!() };
+        assert_eq!((v[0].labels.len(), v[0].points.clone()), (0, vec![(90_000, 10.0)]));
+        let Value::Vector(v) = eval("10 - avg_over_time(rain[2m])", vec![vec![a.clone()]], &[120_000]) else { panic!() };
+        assert_eq!(v[0].points, [(120_000, 7.0)]);
+        let Value::Matrix(m) = eval("rain[2m]", vec![vec![a]], &[120_000]) else { panic!() };
+        assert_eq!(m[0].points, [(60_000, 4.0), (120_000, 2.0)]);
+    }
+
+    #[tokio::test]
+    async fn selectors_stop_at_the_row_budget() {
+        let dir = crate::test_util::TempDir::new("promql");
+        let cfg = crate::config::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        let state = AppState::open(&cfg).await.unwrap();
+        for (station, t) in [("A", 1_000), ("A", 2_000), ("B", 1_000)] {
+            let mut o = crate::storage::memtable::Observation::empty(station, Timestamp(t));
+            o.set_field("rain", 1.0);
+            state.memtable.lock().await.insert(o);
+        }
+        let Expr::Selector(sel) = Expr::parse("rain").unwrap() else { panic!() };
+        let mut budget = 3;
+        assert_eq!(load(&state, &sel, 0, 5_000, None, &mut budget).await.unwrap().len(), 2);
+        assert_eq!(budget, 0);
+        let mut budget = 2;
+        let err = load(&state, &sel, 0, 5_000, None, &mut budget).await.unwrap_err();
+        assert!(err.is::<TooManyRows>());
+    }
+}
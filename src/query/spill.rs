@@ -0,0 +1,319 @@
+// External merge for range scans too large to merge in memory. Runs are
+// collected until they hold `memory_rows` rows, then merged and written to a
+// temporary NDJSON file, so at most one batch of input runs is in memory
+// next to the result. The spilled files are merged as the result is read,
+// holding back only the rows of one time. A query that would need more than `max_disk_bytes` of temporary files
+// fails instead of filling the disk.
+//
+// A `Spool` is the simpler case of one ordered run written once and read
+// back, for two-pass exports: the second pass writes exactly the rows the
+// first took the columns from, whatever is written to the station meanwhile.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::units;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpillConfig {
+    /// Where temporary files go; the system temp directory when unset.
+    pub dir: Option<PathBuf>,
+    /// Rows a scan merges in memory before spilling them.
+    pub memory_rows: usize,
+    /// Temporary disk space one query may use.
+    #[serde(alias = "max_disk", deserialize_with = "units::bytes")]
+    pub max_disk_bytes: u64,
+}
+
+impl Default for SpillConfig {
+    fn default() -> Self {
+        Self { dir: None, memory_rows: 2_000_000, max_disk_bytes: 4 * 1024 * 1024 * 1024 }
+    }
+}
+
+/// Tells the temporary files of concurrent queries apart.
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// A spilled run, removed when dropped.
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl SpillFile {
+    /// A new temporary file name in `dir`.
+    fn new(dir: &Path) -> Self {
+        Self { path: dir.join(format!("skypulse-spill-{}-{}.ndjson", std::process::id(), NEXT_FILE.fetch_add(1, Ordering::Relaxed))) }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Merges runs, given oldest write first, into one time-ordered run with
+/// [`super::merge::by_time`]'s rules, spilling to disk past the memory limit.
+pub struct ExternalMerge<'a> {
+    cfg: &'a SpillConfig,
+    pending: Vec<Vec<Observation>>,
+    pending_rows: usize,
+    spilled: Vec<SpillFile>,
+    spilled_bytes: u64,
+}
+
+impl<'a> ExternalMerge<'a> {
+    pub fn new(cfg: &'a SpillConfig) -> Self {
+        Self { cfg, pending: Vec::new(), pending_rows: 0, spilled: Vec::new(), spilled_bytes: 0 }
+    }
+
+    /// Add the next run; past the memory limit the pending runs are merged
+    /// and written out on a blocking thread.
+    pub async fn push(&mut self, run: Vec<Observation>) -> Result<()> {
+        self.pending_rows += run.len();
+        self.pending.push(run);
+        if self.pending_rows <= self.cfg.memory_rows.max(1) {
+            return Ok(());
+        }
+        let runs = std::mem::take(&mut self.pending);
+        self.pending_rows = 0;
+        let dir = self.cfg.dir.clone().unwrap_or_else(std::env::temp_dir);
+        let (file, bytes) = tokio::task::spawn_blocking(move || spill(&dir, runs)).await??;
+        self.spilled_bytes += bytes;
+        self.spilled.push(file);
+        if self.spilled_bytes > self.cfg.max_disk_bytes {
+            bail!(
+                "query needs more than {} bytes of temporary disk space; narrow the range or raise spill.max_disk_bytes",
+                self.cfg.max_disk_bytes
+            );
+        }
+        Ok(())
+    }
+
+    /// Bytes written to temporary files so far.
+    pub fn spilled_bytes(&self) -> u64 {
+        self.spilled_bytes
+    }
+
+    /// The merged rows, in time order. Spilled runs are read back one row at
+    /// a time as the iterator advances, which blocks on the files; the files
+    /// go when it is dropped.
+    pub fn finish(mut self) -> Merged {
+        let last = super::merge::by_time(std::mem::take(&mut self.pending));
+        Merged { files: std::mem::take(&mut self.spilled), last, runs: Vec::new(), heads: Vec::new(), heap: BinaryHeap::new(), group: Vec::new(), ready: VecDeque::new() }
+    }
+}
+
+/// Merge `runs` and write them to a new temporary file in `dir`; returns the
+/// file and its size.
+fn spill(dir: &Path, runs: Vec<Vec<Observation>>) -> Result<(SpillFile, u64)> {
+    let merged = super::merge::by_time(runs);
+    std::fs::create_dir_all(dir)?;
+    let file = SpillFile::new(dir);
+    let mut out = BufWriter::new(File::create(&file.path).with_context(|| format!("creating {}", file.path.display()))?);
+    for o in &merged {
+        serde_json::to_writer(&mut out, o)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    let bytes = std::fs::metadata(&file.path)?.len();
+    Ok((file, bytes))
+}
+
+/// The rows of an [`ExternalMerge`], merged from its runs as they are read.
+/// Rows of one time are held back until the next time shows up, so each
+/// station keeps only its latest write of that time.
+pub struct Merged {
+    files: Vec<SpillFile>,
+    /// The runs still in memory, merged.
+    last: Vec<Observation>,
+    /// Every run, opened on the first read.
+    runs: Vec<Box<dyn Iterator<Item = Result<Observation>> + Send>>,
+    /// The next row of each run.
+    heads: Vec<Option<Observation>>,
+    heap: BinaryHeap<Reverse<(Timestamp, usize)>>,
+    group: Vec<Observation>,
+    ready: VecDeque<Observation>,
+}
+
+impl Merged {
+    fn open(&mut self) -> Result<()> {
+        for file in &self.files {
+            let f = File::open(&file.path).with_context(|| format!("opening {}", file.path.display()))?;
+            self.runs.push(Box::new(BufReader::new(f).lines().map(|line| Ok(serde_json::from_str(&line?)?))));
+        }
+        self.runs.push(Box::new(std::mem::take(&mut self.last).into_iter().map(Ok)));
+        for (i, run) in self.runs.iter_mut().enumerate() {
+            let head = run.next().transpose()?;
+            if let Some(o) = &head {
+                self.heap.push(Reverse((o.time, i)));
+            }
+            self.heads.push(head);
+        }
+        Ok(())
+    }
+
+    /// The next row of the runs by time, ties going to the earlier run.
+    fn pop(&mut self) -> Result<Option<Observation>> {
+        if self.runs.is_empty() {
+            self.open()?;
+        }
+        let Some(Reverse((_, i))) = self.heap.pop() else { return Ok(None) };
+        let row = self.heads[i].take();
+        self.heads[i] = self.runs[i].next().transpose()?;
+        if let Some(o) = &self.heads[i] {
+            self.heap.push(Reverse((o.time, i)));
+        }
+        Ok(row)
+    }
+}
+
+impl Iterator for Merged {
+    type Item = Result<Observation>;
+
+    fn next(&mut self) -> Option<Result<Observation>> {
+        loop {
+            if let Some(o) = self.ready.pop_front() {
+                return Some(Ok(o));
+            }
+            match self.pop() {
+                Err(e) => return Some(Err(e)),
+                Ok(None) if self.group.is_empty() => return None,
+                Ok(None) => self.ready.extend(self.group.drain(..)),
+                Ok(Some(o)) => {
+                    if self.group.first().is_some_and(|g| g.time != o.time) {
+                        self.ready.extend(self.group.drain(..));
+                    }
+                    // equal times pop in run order, so the later write replaces
+                    match self.group.iter_mut().find(|g| g.station_id == o.station_id) {
+                        Some(g) => *g = o,
+                        None => self.group.push(o),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rows written once to a temporary file and read back in the same order.
+pub struct Spool {
+    file: SpillFile,
+    out: tokio::io::BufWriter<tokio::fs::File>,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+impl Spool {
+    pub async fn create(cfg: &SpillConfig) -> Result<Self> {
+        let dir = cfg.dir.clone().unwrap_or_else(std::env::temp_dir);
+        tokio::fs::create_dir_all(&dir).await?;
+        let file = SpillFile::new(&dir);
+        let out = tokio::fs::File::create(&file.path).await.with_context(|| format!("creating {}", file.path.display()))?;
+        Ok(Self { file, out: tokio::io::BufWriter::new(out), bytes: 0, max_bytes: cfg.max_disk_bytes })
+    }
+
+    pub async fn push(&mut self, rows: &[Observation]) -> Result<()> {
+        let mut data = Vec::new();
+        for o in rows {
+            serde_json::to_writer(&mut data, o)?;
+            data.push(b'\n');
+        }
+        self.bytes += data.len() as u64;
+        if self.bytes > self.max_bytes {
+            bail!("export needs more than {} bytes of temporary disk space; narrow the range or raise spill.max_disk_bytes", self.max_bytes);
+        }
+        self.out.write_all(&data).await?;
+        Ok(())
+    }
+
+    /// Stop writing and read the rows back, `batch` at a time; the file goes
+    /// when the reader is dropped.
+    pub async fn read(mut self, batch: usize) -> Result<SpoolReader> {
+        self.out.flush().await?;
+        let file = tokio::fs::File::open(&self.file.path).await.with_context(|| format!("opening {}", self.file.path.display()))?;
+        Ok(SpoolReader { _file: self.file, lines: tokio::io::BufReader::new(file).lines(), batch: batch.max(1) })
+    }
+}
+
+/// The rows of a [`Spool`], in the order they were pushed.
+pub struct SpoolReader {
+    _file: SpillFile,
+    lines: tokio::io::Lines<tokio::io::BufReader<tokio::fs::File>>,
+    batch: usize,
+}
+
+impl SpoolReader {
+    /// The next rows; empty once all are read.
+    pub async fn next_batch(&mut self) -> Result<Vec<Observation>> {
+        let mut rows = Vec::new();
+        while rows.len() < self.batch {
+            let Some(line) = self.lines.next_line().await? else { break };
+            rows.push(serde_json::from_str(&line)?);
+        }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(times: &[u32], temp: f64) -> Vec<Observation> {
+        times
+            .iter()
+            .map(|m| {
+                let mut o = Observation::empty("s", format!("2025-01-01T00:{:02}:00Z", m).parse().unwrap());
+                o.set_field("temp", temp);
+                o
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn spilled_merge_matches_in_memory() {
+        let runs = vec![run(&[0, 2, 4, 6], 1.0), run(&[1, 3, 5], 2.0), run(&[2, 7], 3.0), run(&[8, 0], 4.0)];
+        let dir = crate::test_util::TempDir::new("spill-test");
+        let cfg = SpillConfig { dir: Some(dir.to_path_buf()), memory_rows: 3, ..Default::default() };
+        let mut merge = ExternalMerge::new(&cfg);
+        for r in runs.clone() {
+            merge.push(r).await.unwrap();
+        }
+        assert!(merge.spilled_bytes() > 0);
+        let merged: Vec<Observation> = merge.finish().collect::<Result<_>>().unwrap();
+        assert_eq!(merged, super::super::merge::by_time(runs.clone()));
+        // the later writes of minutes 0 and 2 win
+        assert_eq!(merged[0].number("temp"), Some(4.0));
+        assert_eq!(merged[2].number("temp"), Some(3.0));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // stations interleaved at one time keep their own latest writes
+        let mut other = run(&[0, 2], 5.0);
+        other.iter_mut().for_each(|o| o.station_id = "t".into());
+        let runs = vec![run(&[0], 1.0), other, run(&[0], 2.0)];
+        let mut merge = ExternalMerge::new(&cfg);
+        for r in runs.clone() {
+            merge.push(r).await.unwrap();
+        }
+        let merged: Vec<Observation> = merge.finish().collect::<Result<_>>().unwrap();
+        assert_eq!(merged, super::super::merge::by_time(runs));
+        let temps: Vec<(&str, f64)> = merged.iter().map(|o| (o.station_id.as_str(), o.number("temp").unwrap())).collect();
+        assert_eq!(temps, [("s", 2.0), ("t", 5.0), ("t", 5.0)]);
+
+        let tight = SpillConfig { dir: Some(dir.to_path_buf()), memory_rows: 1, max_disk_bytes: 10 };
+        let mut merge = ExternalMerge::new(&tight);
+        let mut pushed = Ok(());
+        for r in run(&[0, 1, 2], 1.0).chunks(1) {
+            pushed = pushed.and(merge.push(r.to_vec()).await);
+        }
+        assert!(pushed.is_err());
+        drop(merge);
+    }
+}
@@ -0,0 +1,429 @@
+// A small SQL dialect over range queries, for ad-hoc analysis without
+// assembling query parameters by hand:
+//
+//   SELECT mean(temp), max(gust) FROM HK001
+//   WHERE time >= '2025-01-01T00:00:00Z' AND time < '2025-01-02T00:00:00Z'
+//   GROUP BY time(1h) ORDER BY time DESC LIMIT 12
+//
+// A statement is planned into a `RangeQuery` plus the columns it selects, so
+// it reads exactly what the matching `/query` call with `resolution=raw`
+// would. Field lists return
+// raw rows, aggregate functions return whole-range aggregates, or one row per
+// window with GROUP BY time(...). Keywords are case-insensitive; station ids
+// and fields that are not plain words go in double quotes, times in single
+// quotes or as `now()` minus or plus a duration. A trailing
+// `tz('Asia/Hong_Kong')` aligns GROUP BY time windows to local time, and
+// `fill(null|previous|linear)` after GROUP BY time(...) emits empty windows.
+
+use std::fmt;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use super::aggregate::{AggFn, Aggregates};
+use super::{Order, QueryResult, RangeQuery, ResolutionChoice, Rows};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Field(String),
+    /// An aggregation of one field, or of every numeric field for `*`.
+    Agg(AggFn, String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub query: RangeQuery,
+    /// Selected columns; empty for `SELECT *`.
+    pub columns: Vec<Column>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    /// A double-quoted name.
+    Name(String),
+    /// A single-quoted string.
+    Str(String),
+    Sym(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "{}", w),
+            Token::Name(n) => write!(f, "\"{}\"", n),
+            Token::Str(s) => write!(f, "'{}'", s),
+            Token::Sym(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if is_word(c) {
+            let mut word = c.to_string();
+            while let Some(c) = chars.next_if(|c| is_word(*c)) {
+                word.push(c);
+            }
+            tokens.push(Token::Word(word));
+            continue;
+        }
+        if c == '\'' || c == '"' {
+            // a doubled quote stands for itself
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some(q) if q == c && chars.next_if_eq(&c).is_none() => break,
+                    Some(x) => text.push(x),
+                    None => bail!("unterminated {} quote", c),
+                }
+            }
+            tokens.push(if c == '\'' { Token::Str(text) } else { Token::Name(text) });
+            continue;
+        }
+        let or_equal = |chars: &mut std::iter::Peekable<std::str::Chars>| chars.next_if_eq(&'=').is_some();
+        let sym = match c {
+            '(' => "(",
+            ')' => ")",
+            ',' => ",",
+            '*' => "*",
+            '=' => "=",
+            ';' => ";",
+            '-' => "-",
+            '+' => "+",
+            '<' if or_equal(&mut chars) => "<=",
+            '<' => "<",
+            '>' if or_equal(&mut chars) => ">=",
+            '>' => ">",
+            other => bail!("unexpected '{}'", other),
+        };
+        tokens.push(Token::Sym(sym));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    now: DateTime<Utc>,
+}
+
+impl Parser {
+    fn unexpected(&self, expected: &str) -> anyhow::Error {
+        match self.tokens.get(self.pos) {
+            Some(t) => anyhow!("expected {}, found {}", expected, t),
+            None => anyhow!("expected {}, found the end of the statement", expected),
+        }
+    }
+
+    /// Consume the keyword `kw` if it comes next.
+    fn keyword(&mut self, kw: &str) -> bool {
+        let found = matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w.eq_ignore_ascii_case(kw));
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect_keyword(&mut self, kw: &str) -> Result<()> {
+        if !self.keyword(kw) {
+            return Err(self.unexpected(&kw.to_ascii_uppercase()));
+        }
+        Ok(())
+    }
+
+    fn symbol(&mut self, sym: &str) -> bool {
+        let found = matches!(self.tokens.get(self.pos), Some(Token::Sym(s)) if *s == sym);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect_symbol(&mut self, sym: &str) -> Result<()> {
+        if !self.symbol(sym) {
+            return Err(self.unexpected(&format!("'{}'", sym)));
+        }
+        Ok(())
+    }
+
+    fn name(&mut self, what: &str) -> Result<String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w) | Token::Name(w)) => {
+                let name = w.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    /// A quoted time, RFC3339 or relative like `'now-6h'`, or `now()`
+    /// optionally minus or plus a duration.
+    fn time(&mut self) -> Result<DateTime<Utc>> {
+        if let Some(Token::Str(s)) = self.tokens.get(self.pos) {
+            let t = super::parse_time_at(s, self.now).map_err(|e| anyhow!("invalid time '{}': {}", s, e))?;
+            self.pos += 1;
+            return Ok(t);
+        }
+        if !self.keyword("now") {
+            return Err(self.unexpected("a quoted time or now()"));
+        }
+        self.expect_symbol("(")?;
+        self.expect_symbol(")")?;
+        let sign = if self.symbol("-") {
+            '-'
+        } else if self.symbol("+") {
+            '+'
+        } else {
+            return Ok(self.now);
+        };
+        let d = self.name("a duration such as 6h")?;
+        super::parse_time_at(&format!("now{}{}", sign, d), self.now)
+    }
+
+    fn column(&mut self) -> Result<Column> {
+        let name = self.name("a field or aggregate function")?;
+        if !self.symbol("(") {
+            return Ok(Column::Field(name));
+        }
+        let agg = AggFn::parse(&name.to_ascii_lowercase())?;
+        let field = if self.symbol("*") { "*".to_string() } else { self.name("a field")? };
+        self.expect_symbol(")")?;
+        Ok(Column::Agg(agg, field))
+    }
+
+    /// One `time <op> '...'` or `time BETWEEN '...' AND '...'` condition.
+    fn condition(&mut self, q: &mut RangeQuery) -> Result<()> {
+        self.expect_keyword("time")?;
+        let after = |t: DateTime<Utc>| t + Duration::milliseconds(1);
+        let (start, end) = if self.keyword("between") {
+            let low = self.time()?;
+            self.expect_keyword("and")?;
+            (Some(low), Some(after(self.time()?)))
+        } else {
+            let op = match self.tokens.get(self.pos) {
+                Some(Token::Sym(op @ (">=" | ">" | "<" | "<=" | "="))) => *op,
+                _ => return Err(self.unexpected("a comparison or BETWEEN")),
+            };
+            self.pos += 1;
+            let t = self.time()?;
+            match op {
+                ">=" => (Some(t), None),
+                ">" => (Some(after(t)), None),
+                "<" => (None, Some(t)),
+                "<=" => (None, Some(after(t))),
+                _ => (Some(t), Some(after(t))),
+            }
+        };
+        // conditions joined by AND narrow the range
+        if let Some(s) = start {
+            q.start = Some(q.start.map_or(s, |old| old.max(s)));
+        }
+        if let Some(e) = end {
+            q.end = Some(q.end.map_or(e, |old| old.min(e)));
+        }
+        Ok(())
+    }
+}
+
+impl Statement {
+    /// Parse `sql`, reading relative times against `now`.
+    pub fn parse(sql: &str, now: DateTime<Utc>) -> Result<Self> {
+        let mut p = Parser { tokens: tokenize(sql)?, pos: 0, now };
+        p.expect_keyword("select")?;
+        let mut columns = Vec::new();
+        if !p.symbol("*") {
+            loop {
+                columns.push(p.column()?);
+                if !p.symbol(",") {
+                    break;
+                }
+            }
+        }
+        p.expect_keyword("from")?;
+        let mut query = RangeQuery::new(&p.name("a station id")?, None, None);
+        // never `Auto`: a statement without a start counts as a long range
+        // there and would answer with hourly rollups instead of the rows or
+        // exact aggregates it asks for
+        query.resolution = ResolutionChoice::Raw;
+        if p.keyword("where") {
+            loop {
+                p.condition(&mut query)?;
+                if !p.keyword("and") {
+                    break;
+                }
+            }
+        }
+        if p.keyword("group") {
+            p.expect_keyword("by")?;
+            p.expect_keyword("time")?;
+            p.expect_symbol("(")?;
+            let width = p.name("a window such as 5m")?;
+            query.interval_secs = Some(super::parse_interval(&width)?);
+            p.expect_symbol(")")?;
+            if p.keyword("fill") {
+                p.expect_symbol("(")?;
+                query.fill = super::aggregate::Fill::parse(&p.name("null, previous or linear")?.to_ascii_lowercase())?;
+                p.expect_symbol(")")?;
+            }
+        }
+        if p.keyword("order") {
+            p.expect_keyword("by")?;
+            p.expect_keyword("time")?;
+            if p.keyword("desc") {
+                query.order = Order::Desc;
+            } else {
+                p.keyword("asc");
+            }
+        }
+        if p.keyword("limit") {
+            let n = p.name("a row count")?;
+            query.limit = Some(n.parse().map_err(|_| anyhow!("LIMIT must be a row count, got {}", n))?);
+        }
+        if p.keyword("tz") {
+            p.expect_symbol("(")?;
+            let Some(Token::Str(zone)) = p.tokens.get(p.pos).cloned() else { return Err(p.unexpected("a quoted time zone")) };
+            p.pos += 1;
+            query.tz = Some(super::parse_tz(&zone)?);
+            p.expect_symbol(")")?;
+        }
+        p.symbol(";");
+        if p.pos < p.tokens.len() {
+            return Err(p.unexpected("the end of the statement"));
+        }
+
+        let aggs = columns.iter().filter(|c| matches!(c, Column::Agg(..))).count();
+        if aggs > 0 && aggs < columns.len() {
+            bail!("cannot select fields next to aggregate functions");
+        }
+        if query.interval_secs.is_some() && aggs == 0 {
+            bail!("GROUP BY time needs aggregate functions in the select list");
+        }
+        if query.tz.is_some() && query.interval_secs.is_none() {
+            bail!("tz() aligns GROUP BY time windows; add GROUP BY time(...)");
+        }
+        // `*` in any aggregate leaves `fields` empty, selecting every numeric field
+        let every = columns.iter().any(|c| matches!(c, Column::Agg(_, f) if f == "*"));
+        for c in &columns {
+            let field = match c {
+                Column::Agg(agg, field) => {
+                    if !query.aggregations.contains(agg) {
+                        query.aggregations.push(*agg);
+                    }
+                    field
+                }
+                Column::Field(field) => field,
+            };
+            if !every && !query.fields.contains(field) {
+                query.fields.push(field.clone());
+            }
+        }
+        Ok(Self { query, columns })
+    }
+
+    /// Drop what the select list did not ask for from `result`: other
+    /// fields of raw rows, and other field and aggregation pairs.
+    pub fn project(&self, result: &mut QueryResult) {
+        // the SQL endpoint takes no cursor
+        result.next_cursor = None;
+        if self.columns.is_empty() {
+            return;
+        }
+        match &mut result.rows {
+            Some(Rows::Raw(rows)) => rows.iter_mut().for_each(|o| o.fields.retain(|name, _| self.query.fields.contains(name))),
+            Some(Rows::Buckets(buckets)) => buckets.iter_mut().for_each(|b| self.keep(&mut b.aggregates)),
+            _ => {}
+        }
+        if let Some(aggregates) = &mut result.aggregates {
+            self.keep(aggregates);
+        }
+    }
+
+    fn keep(&self, aggregates: &mut Aggregates) {
+        let selected = |field: &str, agg: &str| {
+            self.columns.iter().any(|c| matches!(c, Column::Agg(f, of) if f.as_str() == agg && (of == "*" || of == field)))
+        };
+        aggregates.retain(|field, aggs| {
+            aggs.retain(|agg, _| selected(field, agg));
+            !aggs.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::aggregate::AggValue;
+    use crate::storage::memtable::Observation;
+
+    #[test]
+    fn plans_and_projects_statements() {
+        let s = Statement::parse(
+            "select mean(temp), MAX(gust) from \"HK:001\" where time >= '2025-01-01T00:00:00Z' and time BETWEEN '2024-12-31T00:00:00Z' AND '2025-01-01T23:59:59Z' group by time(1h) order by time desc limit 12;",
+            Utc::now(),
+        )
+        .unwrap();
+        let q = &s.query;
+        assert_eq!(q.station_id, "HK:001");
+        assert_eq!(q.start, super::super::parse_time("2025-01-01T00:00:00Z").ok());
+        assert_eq!(q.end.unwrap().timestamp_millis(), super::super::parse_time("2025-01-01T23:59:59Z").unwrap().timestamp_millis() + 1);
+        assert_eq!((q.interval_secs, q.order, q.limit), (Some(3600), Order::Desc, Some(12)));
+        assert_eq!((q.aggregations.clone(), q.fields.clone()), (vec![AggFn::Mean, AggFn::Max], vec!["temp".to_string(), "gust".to_string()]));
+
+        let mut aggregates = Aggregates::new();
+        for field in ["temp", "gust"] {
+            aggregates.insert(field.into(), [("mean", AggValue::Value(Some(1.0))), ("max", AggValue::Value(Some(2.0)))].into());
+        }
+        s.keep(&mut aggregates);
+        assert_eq!(aggregates["temp"].keys().copied().collect::<Vec<_>>(), ["mean"]);
+        assert_eq!(aggregates["gust"].keys().copied().collect::<Vec<_>>(), ["max"]);
+
+        let s = Statement::parse("SELECT temp FROM HK001 WHERE time < '2025-01-01T00:00:00Z'", Utc::now()).unwrap();
+        assert_eq!((s.query.resolution, s.query.start), (ResolutionChoice::Raw, None));
+        let mut o = Observation::empty("HK001", "2024-12-31T00:00:00Z".parse().unwrap());
+        o.set_field("temp", 1.0);
+        o.set_field("humidity", 80.0);
+        let mut result = QueryResult {
+            station_id: "HK001".into(),
+            metadata: None,
+            resolution: "raw",
+            downgraded_from: None,
+            rows: Some(Rows::Raw(vec![o])),
+            aggregates: None,
+            forecast: None,
+            next_cursor: Some("00".into()),
+        };
+        s.project(&mut result);
+        let Some(Rows::Raw(rows)) = &result.rows else { panic!("raw rows") };
+        assert_eq!(rows[0].fields.keys().collect::<Vec<_>>(), ["temp"]);
+        assert!(result.next_cursor.is_none());
+
+        let now = super::super::parse_time("2025-01-02T00:00:00Z").unwrap();
+        let s = Statement::parse("SELECT * FROM A WHERE time > now() - 1d AND time <= now()", now).unwrap();
+        assert_eq!(s.query.start.unwrap().timestamp_millis(), now.timestamp_millis() - 86_400_000 + 1);
+        assert_eq!(s.query.end.unwrap().timestamp_millis(), now.timestamp_millis() + 1);
+        assert_eq!(Statement::parse("SELECT * FROM A WHERE time >= 'now-1d'", now).unwrap().query.start, s.query.start.map(|t| t - Duration::milliseconds(1)));
+
+        let s = Statement::parse("SELECT max(temp) FROM A GROUP BY time(1d) fill(linear) tz('Asia/Hong_Kong');", now).unwrap();
+        assert_eq!((s.query.tz, s.query.fill), (Some(chrono_tz::Asia::Hong_Kong), super::super::aggregate::Fill::Linear));
+
+        for bad in [
+            "SELECT temp, max(temp) FROM A",
+            "SELECT temp FROM A GROUP BY time(5m)",
+            "SELECT median(temp) FROM A",
+            "SELECT * FROM A WHERE time > 2025",
+            "SELECT * FROM A LIMIT ten",
+            "SELECT max(temp) FROM A tz('Asia/Hong_Kong')",
+            "SELECT max(temp) FROM A GROUP BY time(1h) fill(zero)",
+            "SELECT max(temp) FROM A GROUP BY time(1d) tz('Mars/Olympus')",
+            "SELECT * FROM A WHERE time >= '2025-01-01T00:00:00Z' OR time < '2024-01-01T00:00:00Z'",
+            "SELECT * FROM 'A",
+        ] {
+            assert!(Statement::parse(bad, Utc::now()).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// Typed async streams for embedding SkyPulseDB as a library: range scans and
+// live subscriptions yield `ObservationBatch`es or a `QueryError` instead of
+// going through HTTP. Range scans are produced into a small bounded channel,
+// so a slow consumer holds the reader back instead of buffering the range.
+
+use std::fmt;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use crate::storage::manifest::StationSlice;
+use crate::storage::memtable::Observation;
+use crate::AppState;
+
+/// Consecutive observations of one station, in time order.
+#[derive(Debug, Clone)]
+pub struct ObservationBatch {
+    pub station_id: String,
+    pub observations: Vec<Observation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// The request itself is unusable, e.g. a zero batch size or an empty range.
+    InvalidQuery(String),
+    /// Reading chunk data failed; the stream ends after this item.
+    Storage(String),
+    /// A subscriber fell behind and this many write batches were skipped.
+    Lagged(u64),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::InvalidQuery(msg) => write!(f, "invalid query: {}", msg),
+            QueryError::Storage(msg) => write!(f, "storage error: {}", msg),
+            QueryError::Lagged(n) => write!(f, "subscriber lagged, {} write batches skipped", n),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Batches a range scan may have queued ahead of its consumer.
+const SCAN_QUEUE: usize = 2;
+
+/// Where a part of the range lives: a station's run in a chunk file, or the
+/// rows still buffered in the MemTable.
+enum Source {
+    Chunk(String, StationSlice),
+    Buffered(Vec<Observation>),
+}
+
+fn parse(t: Option<&str>) -> Option<DateTime<Utc>> {
+    t.and_then(|t| super::parse_time(t).ok())
+}
+
+/// Sources ordered by start time and grouped so no two groups overlap in
+/// time; each group is read and merged by time on its own, which keeps the
+/// stream in time order while only holding one group in memory. Within a
+/// group sources keep their given (write) order, so duplicates resolve to
+/// the last write.
+fn overlap_groups(sources: Vec<(DateTime<Utc>, DateTime<Utc>, Source)>) -> Vec<Vec<Source>> {
+    let mut sources: Vec<_> = sources.into_iter().enumerate().collect();
+    sources.sort_by_key(|(_, (min, _, _))| *min);
+    let mut groups: Vec<(DateTime<Utc>, Vec<_>)> = Vec::new();
+    for (i, (min, max, source)) in sources {
+        match groups.last_mut() {
+            Some((end, group)) if min <= *end => {
+                *end = (*end).max(max);
+                group.push((i, source));
+            }
+            _ => groups.push((max, vec![(i, source)])),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(_, mut g)| {
+            g.sort_by_key(|(i, _)| *i);
+            g.into_iter().map(|(_, s)| s).collect()
+        })
+        .collect()
+}
+
+async fn scan(
+    state: &AppState,
+    station_id: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    batch_size: usize,
+    tx: &mpsc::Sender<Result<ObservationBatch, QueryError>>,
+) -> anyhow::Result<()> {
+    let in_range = |t: DateTime<Utc>| start.is_none_or(|s| t >= s) && end.is_none_or(|e| t < e);
+    // unparseable bounds widen a source to the whole timeline so it is merged with everything
+    let mut sources = Vec::new();
+    for (name, slice) in state.chunk_store.slices(station_id).await {
+        let min = parse(slice.min_time.as_deref()).unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let max = parse(slice.max_time.as_deref()).unwrap_or(DateTime::<Utc>::MAX_UTC);
+        if start.is_some_and(|s| max < s) || end.is_some_and(|e| min >= e) {
+            continue;
+        }
+        sources.push((min, max, Source::Chunk(name, slice)));
+    }
+    let buffered: Vec<Observation> = {
+        let mt = state.memtable.lock().await;
+        mt.buffer.get(station_id).map(|b| b.iter().filter(|o| in_range(o.timestamp())).cloned().collect()).unwrap_or_default()
+    };
+    if !buffered.is_empty() {
+        let min = buffered.iter().map(|o| o.time).min().unwrap_or_default().to_datetime();
+        let max = buffered.iter().map(|o| o.time).max().unwrap_or_default().to_datetime();
+        sources.push((min, max, Source::Buffered(buffered)));
+    }
+
+    for group in overlap_groups(sources) {
+        let mut runs = Vec::with_capacity(group.len());
+        for source in group {
+            let mut rows = match source {
+                Source::Chunk(name, slice) => state.chunk_store.read_slice(&name, &slice).await?,
+                Source::Buffered(obs) => obs,
+            };
+            rows.retain(|o| in_range(o.timestamp()));
+            runs.push(rows);
+        }
+        let mut rows = super::merge::by_time(runs).into_iter().peekable();
+        while rows.peek().is_some() {
+            let observations: Vec<Observation> = rows.by_ref().take(batch_size).collect();
+            let batch = ObservationBatch { station_id: station_id.to_string(), observations };
+            if tx.send(Ok(batch)).await.is_err() {
+                // the consumer dropped the stream
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stream a station's raw observations in `[start, end)`, oldest first, in
+/// batches of up to `batch_size`. Chunks are read lazily as the consumer
+/// pulls; dropping the stream stops the scan.
+pub fn observations(
+    state: Arc<AppState>,
+    station_id: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    batch_size: usize,
+) -> impl Stream<Item = Result<ObservationBatch, QueryError>> {
+    let (tx, rx) = mpsc::channel(SCAN_QUEUE);
+    let station_id = station_id.to_string();
+    let invalid = match (start, end) {
+        _ if batch_size == 0 => Some("batch_size must be at least 1"),
+        (Some(s), Some(e)) if s >= e => Some("start must be before end"),
+        _ => None,
+    };
+    let runtime = state.runtimes.query.clone();
+    runtime.spawn(async move {
+        if let Some(msg) = invalid {
+            let _ = tx.send(Err(QueryError::InvalidQuery(msg.to_string()))).await;
+            return;
+        }
+        if let Err(e) = scan(&state, &station_id, start, end, batch_size, &tx).await {
+            let _ = tx.send(Err(QueryError::Storage(format!("{:#}", e)))).await;
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Stream observations as they are written, grouped per station and write
+/// batch, optionally for one station only. Writers never wait for
+/// subscribers: one that falls too far behind gets `QueryError::Lagged`
+/// and continues with the newest writes.
+pub fn subscribe(state: &AppState, station_id: Option<&str>) -> impl Stream<Item = Result<ObservationBatch, QueryError>> {
+    let station_id = station_id.map(str::to_string);
+    let mut live = state.live.subscribe();
+    let (tx, rx) = mpsc::channel(SCAN_QUEUE);
+    state.runtimes.query.spawn(async move {
+        loop {
+            let items = match live.recv().await {
+                Ok(obs) => split_by_station(&obs, station_id.as_deref()).into_iter().map(Ok).collect(),
+                Err(RecvError::Lagged(n)) => vec![Err(QueryError::Lagged(n))],
+                Err(RecvError::Closed) => break,
+            };
+            for item in items {
+                if tx.send(item).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Group a written batch by station (keeping first-seen order), dropping
+/// other stations when `only` is set.
+fn split_by_station(obs: &[Observation], only: Option<&str>) -> Vec<ObservationBatch> {
+    let mut batches: Vec<ObservationBatch> = Vec::new();
+    for o in obs.iter().filter(|o| only.is_none_or(|s| o.station_id == s)) {
+        match batches.iter_mut().find(|b| b.station_id == o.station_id) {
+            Some(b) => b.observations.push(o.clone()),
+            None => batches.push(ObservationBatch { station_id: o.station_id.clone(), observations: vec![o.clone()] }),
+        }
+    }
+    for b in &mut batches {
+        b.observations.sort_by_key(|o| o.time);
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Timestamp;
+
+    fn at(h: u32) -> DateTime<Utc> {
+        super::super::parse_time(&format!("2025-01-01T{:02}:00:00Z", h)).unwrap()
+    }
+
+    #[test]
+    fn groups_only_overlapping_sources() {
+        let sources = vec![
+            (at(5), at(6), Source::Buffered(Vec::new())),
+            (at(0), at(2), Source::Buffered(Vec::new())),
+            (at(1), at(3), Source::Buffered(Vec::new())),
+            (at(4), at(4), Source::Buffered(Vec::new())),
+        ];
+        let sizes: Vec<usize> = overlap_groups(sources).iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![2, 1, 1]);
+
+        // a group lists its sources in the order given, not by start time
+        let row = |h: u32| vec![Observation::empty("A", Timestamp::from_datetime(at(h)))];
+        let sources = vec![(at(1), at(3), Source::Buffered(row(1))), (at(0), at(2), Source::Buffered(row(0)))];
+        let first: Vec<Timestamp> = overlap_groups(sources)[0]
+            .iter()
+            .map(|s| match s {
+                Source::Buffered(obs) => obs[0].time,
+                Source::Chunk(..) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(first, vec![Timestamp::from_datetime(at(1)), Timestamp::from_datetime(at(0))]);
+    }
+
+    #[test]
+    fn splits_writes_by_station() {
+        let obs = vec![
+            Observation::empty("A", "2025-01-01T01:00:00Z".parse().unwrap()),
+            Observation::empty("B", "2025-01-01T01:00:00Z".parse().unwrap()),
+            Observation::empty("A", "2025-01-01T00:00:00Z".parse().unwrap()),
+        ];
+        let all = split_by_station(&obs, None);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].observations[0].time.to_string(), "2025-01-01T00:00:00Z");
+        let only_b = split_by_station(&obs, Some("B"));
+        assert_eq!((only_b.len(), only_b[0].station_id.as_str()), (1, "B"));
+    }
+}
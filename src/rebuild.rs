@@ -0,0 +1,177 @@
+// Admin rebuilds: recompute derived structures (rollups, the last-value cache,
+// the chunk manifest) from the raw chunk files. One rebuild runs at a time in
+// the background; its progress is kept on `AppState` for the admin API.
+
+use std::sync::Arc;
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use crate::storage::rollup;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Rollups,
+    LastValues,
+    Manifest,
+}
+
+impl Target {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Target::Rollups => "rollups",
+            Target::LastValues => "last_values",
+            Target::Manifest => "manifest",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub what: Target,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub station_id: Option<String>,
+    pub state: JobState,
+    /// Units of work: chunks for rollups, stations for last values, one for
+    /// the manifest.
+    pub total: usize,
+    pub done: usize,
+    pub started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    /// Outcome summary, or the error of a failed rebuild.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Start a rebuild in the background. `Err` carries the rebuild that is
+/// already running.
+pub async fn start(state: Arc<AppState>, what: Target, station_id: Option<String>) -> Result<Progress, Progress> {
+    let progress = {
+        let mut current = state.rebuild.lock().await;
+        if let Some(p) = current.as_ref().filter(|p| p.state == JobState::Running) {
+            return Err(p.clone());
+        }
+        let p = Progress {
+            what,
+            station_id: station_id.clone(),
+            state: JobState::Running,
+            total: 0,
+            done: 0,
+            started_at: now(),
+            finished_at: None,
+            message: None,
+        };
+        *current = Some(p.clone());
+        p
+    };
+    tracing::info!(what = what.as_str(), station_id = station_id.as_deref().unwrap_or("*"), "rebuild started");
+    let s = state.clone();
+    state.runtimes.compaction.spawn(async move {
+        let result = run(&s, what, station_id.as_deref()).await;
+        let mut current = s.rebuild.lock().await;
+        if let Some(p) = current.as_mut() {
+            p.finished_at = Some(now());
+            match result {
+                Ok(message) => {
+                    tracing::info!(what = what.as_str(), "rebuild finished: {}", message);
+                    p.state = JobState::Done;
+                    p.message = Some(message);
+                }
+                Err(e) => {
+                    tracing::error!(what = what.as_str(), "rebuild failed: {}", e);
+                    p.state = JobState::Failed;
+                    p.message = Some(e.to_string());
+                }
+            }
+        }
+    });
+    Ok(progress)
+}
+
+async fn set_total(state: &AppState, total: usize) {
+    if let Some(p) = state.rebuild.lock().await.as_mut() {
+        p.total = total;
+    }
+}
+
+async fn step(state: &AppState) {
+    if let Some(p) = state.rebuild.lock().await.as_mut() {
+        p.done += 1;
+        if p.done % 100 == 0 {
+            tracing::info!(what = p.what.as_str(), "rebuild progress {}/{}", p.done, p.total);
+        }
+    }
+}
+
+async fn run(state: &AppState, what: Target, station_id: Option<&str>) -> Result<String> {
+    match what {
+        Target::Rollups => rebuild_rollups(state, station_id).await,
+        Target::LastValues => rebuild_last_values(state, station_id).await,
+        Target::Manifest => {
+            set_total(state, 1).await;
+            let report = state.chunk_store.rebuild_manifest().await?;
+            step(state).await;
+            Ok(if report.is_clean() { "manifest was consistent".to_string() } else { report.summary() })
+        }
+    }
+}
+
+/// Drop the stored rollups and recompute them chunk by chunk.
+async fn rebuild_rollups(state: &AppState, station_id: Option<&str>) -> Result<String> {
+    let manifest = state.chunk_store.manifest().await;
+    let chunks: Vec<&String> = manifest
+        .chunks
+        .iter()
+        .filter(|(_, meta)| station_id.is_none_or(|s| !meta.slices_for(s).is_empty()))
+        .map(|(name, _)| name)
+        .collect();
+    set_total(state, chunks.len()).await;
+    let cleared = state.rollups.clear(station_id).await?;
+    for name in &chunks {
+        let mut rows = state.chunk_store.read_chunk_file(name).await?;
+        if let Some(s) = station_id {
+            rows.retain(|o| o.station_id == s);
+        }
+        state.rollups.replace(&rollup::file_for_chunk(name), station_id, &rows).await?;
+        step(state).await;
+    }
+    Ok(format!("replaced {} rollup files with rollups of {} chunks", cleared, chunks.len()))
+}
+
+/// Forget cached last values and reload them from the chunks and MemTable.
+async fn rebuild_last_values(state: &AppState, station_id: Option<&str>) -> Result<String> {
+    let stations: Vec<String> = match station_id {
+        Some(s) => vec![s.to_string()],
+        None => crate::query::stations(state).await.into_iter().map(|i| i.station_id).collect(),
+    };
+    set_total(state, stations.len()).await;
+    {
+        let mut lv = state.last_values.lock().await;
+        match station_id {
+            Some(s) => lv.remove(s),
+            None => lv.clear(),
+        }
+    }
+    let mut found = 0;
+    for s in &stations {
+        if crate::query::latest(state, s).await?.is_some() {
+            found += 1;
+        }
+        step(state).await;
+    }
+    Ok(format!("cached last values for {} of {} stations", found, stations.len()))
+}
@@ -0,0 +1,259 @@
+// Replay of historical data: the rows of a time range are read back from a
+// data directory and written again in their original rhythm, sped up by
+// `speed` (or as fast as the target takes them), for testing alert rules,
+// dashboards and downstream consumers against a real past event such as a
+// typhoon passage. Rows may be moved in time (`shift_to`, e.g. to now, so
+// the event plays out live) and renamed so they do not land on the original
+// stations. Every station is read by its own task against a shared clock,
+// so rows go out in time order across stations.
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use crate::storage::memtable::{Observation, Timestamp};
+use crate::AppState;
+
+/// Rows are sent at least this often while a replay is paced.
+const SEND_EVERY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Stations to replay; every station with data when empty.
+    pub stations: Vec<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// 1 plays in real time, 60 an hour per minute; 0 sends as fast as the target accepts.
+    pub speed: f64,
+    /// Move the range so it starts at this instant, keeping the spacing of the rows.
+    pub shift_to: Option<DateTime<Utc>>,
+    /// Station id template for the written rows; `{id}` is the original id.
+    pub rename: Option<String>,
+    /// Most rows per write.
+    pub batch_size: usize,
+}
+
+impl ReplayOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.start >= self.end {
+            bail!("start must be before end");
+        }
+        if !self.speed.is_finite() || self.speed < 0.0 {
+            bail!("speed must be 0 (unpaced) or a positive factor, got {}", self.speed);
+        }
+        if self.rename.as_deref().is_some_and(|r| !r.contains("{id}")) {
+            bail!("rename must contain {{id}} so stations stay apart");
+        }
+        if self.batch_size == 0 {
+            bail!("batch_size must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Apply the time shift and renaming to a replayed row.
+    fn transform(&self, mut o: Observation) -> Observation {
+        if let Some(to) = self.shift_to {
+            o.time = Timestamp(o.time.0 + (to - self.start).num_milliseconds());
+        }
+        if let Some(template) = &self.rename {
+            o.station_id = template.replace("{id}", &o.station_id);
+        }
+        o
+    }
+
+    /// How long after the replay starts a row from `time` is due.
+    fn due(&self, time: Timestamp) -> Option<Duration> {
+        (self.speed > 0.0).then(|| {
+            let since_start = (time.0 - self.start.timestamp_millis()).max(0) as f64 / 1000.0;
+            Duration::from_secs_f64(since_start / self.speed)
+        })
+    }
+}
+
+/// Where replayed rows are written.
+#[async_trait]
+pub trait Sink: Send {
+    fn describe(&self) -> String;
+    /// Write `rows`; returns how many were accepted.
+    async fn send(&mut self, rows: Vec<Observation>) -> Result<usize>;
+}
+
+/// Another (or this) instance's batch write API.
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl HttpSink {
+    /// `base_url` is the instance's API, e.g. `http://localhost:8080`.
+    pub fn new(base_url: &str, api_key: Option<String>) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(Duration::from_secs(60)).build()?;
+        Ok(Self { client, url: format!("{}/api/v1/write/batch", base_url.trim_end_matches('/')), api_key })
+    }
+}
+
+#[async_trait]
+impl Sink for HttpSink {
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn send(&mut self, rows: Vec<Observation>) -> Result<usize> {
+        let mut body = Vec::new();
+        for o in &rows {
+            serde_json::to_writer(&mut body, o)?;
+            body.push(b'\n');
+        }
+        let mut req = self.client.post(&self.url).header("content-type", "application/x-ndjson").body(body);
+        if let Some(key) = &self.api_key {
+            req = req.header("x-api-key", key);
+        }
+        let resp = req.send().await.with_context(|| format!("sending to {}", self.url))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            bail!("{} answered {}: {}", self.url, status, resp.text().await.unwrap_or_default());
+        }
+        let report: serde_json::Value = resp.json().await?;
+        if let Some(error) = report["errors"].get(0) {
+            tracing::warn!("replay: {} rows rejected, first: {}", report["rejected"], error["error"]);
+        }
+        Ok(report["accepted"].as_u64().unwrap_or(0) as usize)
+    }
+}
+
+/// A node opened in this process, written through the same checks as the API.
+pub struct LocalSink(pub Arc<AppState>);
+
+#[async_trait]
+impl Sink for LocalSink {
+    fn describe(&self) -> String {
+        self.0.data_dir.display().to_string()
+    }
+
+    async fn send(&mut self, rows: Vec<Observation>) -> Result<usize> {
+        let meta = crate::ingest::routing::RouteMeta { token: None, tags: Default::default() };
+        let (admitted, rejected) = crate::ingest::admit(&self.0, rows, &meta).await;
+        if let Some((_, reason)) = rejected.first() {
+            tracing::warn!("replay: {} rows rejected, first: {}", rejected.len(), reason);
+        }
+        crate::ingest::append(&self.0, admitted).await
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayReport {
+    pub stations: usize,
+    pub rows: usize,
+    pub accepted: usize,
+    pub elapsed_ms: u64,
+}
+
+/// Replay `opts`' range from `source` into `sink`.
+pub async fn replay(source: Arc<AppState>, opts: &ReplayOptions, sink: &mut dyn Sink) -> Result<ReplayReport> {
+    opts.validate()?;
+    let stations = if opts.stations.is_empty() {
+        crate::query::stations(&source).await.into_iter().map(|s| s.station_id).collect()
+    } else {
+        opts.stations.clone()
+    };
+    tracing::info!("replaying {} stations from {} to {} into {}", stations.len(), opts.start, opts.end, sink.describe());
+    let started = tokio::time::Instant::now();
+    let (tx, mut rx) = mpsc::channel::<Result<Observation>>(opts.batch_size * 2);
+    let mut readers = Vec::new();
+    for station_id in &stations {
+        let (source, opts, tx, station_id) = (source.clone(), opts.clone(), tx.clone(), station_id.clone());
+        readers.push(tokio::spawn(async move {
+            let mut batches = crate::query::stream::observations(source, &station_id, Some(opts.start), Some(opts.end), opts.batch_size);
+            while let Some(batch) = batches.next().await {
+                let rows = match batch {
+                    Ok(b) => b.observations,
+                    Err(e) => {
+                        let _ = tx.send(Err(anyhow!("reading {}: {}", station_id, e))).await;
+                        return;
+                    }
+                };
+                for o in rows {
+                    if let Some(due) = opts.due(o.time) {
+                        tokio::time::sleep_until(started + due).await;
+                    }
+                    if tx.send(Ok(opts.transform(o))).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }));
+    }
+    drop(tx);
+
+    let mut report = ReplayReport { stations: stations.len(), ..Default::default() };
+    let mut pending = Vec::with_capacity(opts.batch_size);
+    let mut ticker = tokio::time::interval(SEND_EVERY);
+    let result: Result<()> = async {
+        loop {
+            let (done, tick) = tokio::select! {
+                row = rx.recv() => match row {
+                    Some(row) => {
+                        pending.push(row?);
+                        (false, false)
+                    }
+                    None => (true, false),
+                },
+                _ = ticker.tick() => (false, true),
+            };
+            if !pending.is_empty() && (done || tick || pending.len() >= opts.batch_size) {
+                report.rows += pending.len();
+                report.accepted += sink.send(std::mem::take(&mut pending)).await?;
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+    .await;
+    for r in readers {
+        r.abort();
+    }
+    result?;
+    report.elapsed_ms = started.elapsed().as_millis() as u64;
+    tracing::info!("replayed {} rows ({} accepted) in {} ms", report.rows, report.accepted, report.elapsed_ms);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_a_range_shifted_and_renamed() {
+        let dir = crate::test_util::TempDir::new("replay");
+        let source = Arc::new(AppState::open(&crate::Config { data_dir: dir.join("source"), ..Default::default() }).await.unwrap());
+        let at = |id: &str, t: &str| Observation::empty(id, t.parse().unwrap());
+        source.chunk_store.write_chunk("A", "flush-1", &[at("A", "2025-09-01T00:00:00Z"), at("A", "2025-09-01T00:10:00Z")]).await.unwrap();
+        source.chunk_store.write_chunk("B", "flush-1", &[at("B", "2025-09-01T00:05:00Z"), at("B", "2025-09-02T00:00:00Z")]).await.unwrap();
+        let target = Arc::new(AppState::open(&crate::Config { data_dir: dir.join("target"), ..Default::default() }).await.unwrap());
+
+        let opts = ReplayOptions {
+            stations: Vec::new(),
+            start: "2025-09-01T00:00:00Z".parse().unwrap(),
+            end: "2025-09-01T12:00:00Z".parse().unwrap(),
+            speed: 0.0,
+            shift_to: Some("2026-01-01T00:00:00Z".parse().unwrap()),
+            rename: Some("{id}-replay".into()),
+            batch_size: 2,
+        };
+        assert_eq!(opts.due(at("A", "2025-09-01T00:10:00Z").time), None);
+        assert_eq!(ReplayOptions { speed: 60.0, ..opts.clone() }.due(at("A", "2025-09-01T00:10:00Z").time), Some(Duration::from_secs(10)));
+        let report = replay(source, &opts, &mut LocalSink(target.clone())).await.unwrap();
+        assert_eq!((report.stations, report.rows, report.accepted), (2, 3, 3));
+
+        let mt = target.memtable.lock().await;
+        let times = |id: &str| mt.buffer[id].iter().map(|o| o.time.to_string()).collect::<Vec<_>>();
+        assert_eq!(times("A-replay"), ["2026-01-01T00:00:00Z", "2026-01-01T00:10:00Z"]);
+        assert_eq!(times("B-replay"), ["2026-01-01T00:05:00Z"]);
+    }
+}
@@ -0,0 +1,112 @@
+// Read-only replica serving: a node that only answers queries over a data
+// directory another process writes, such as a replication follower on the
+// same host or a directory snapshots are restored into. The node opens the
+// directory read-only (no WAL, no flushes), leaves the write endpoints out
+// of its router, and re-reads the chunk manifest every `refresh_interval` so
+// chunks and deletes the owner flushes since become visible; a new manifest
+// also empties the last-value cache, which is then filled from the chunks
+// again. Rows still buffered in the owner's MemTable are not seen until it
+// flushes them.
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::config::Surface;
+use crate::units;
+use crate::AppState;
+
+/// Endpoints a replica leaves out: everything that would write.
+pub const WRITE_SURFACES: [Surface; 5] = [Surface::Write, Surface::Influx, Surface::PromWrite, Surface::Delete, Surface::Import];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicaConfig {
+    /// How often the chunk manifest is read again.
+    #[serde(alias = "refresh_interval", deserialize_with = "units::secs")]
+    pub refresh_interval_secs: u64,
+}
+
+impl Default for ReplicaConfig {
+    fn default() -> Self {
+        Self { refresh_interval_secs: 30 }
+    }
+}
+
+impl ReplicaConfig {
+    /// `SKYPULSE_REPLICA=1` serves as a replica; `SKYPULSE_REPLICA_REFRESH`
+    /// sets the refresh interval.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(v) = std::env::var("SKYPULSE_REPLICA") {
+            if matches!(v.as_str(), "1" | "true" | "yes") {
+                cfg.get_or_insert_with(Self::default);
+            }
+        }
+        if let (Some(c), Some(d)) = (cfg.as_mut(), units::env_secs("SKYPULSE_REPLICA_REFRESH", "s")?) {
+            c.refresh_interval_secs = d;
+        }
+        Ok(())
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_interval_secs.max(1))
+    }
+}
+
+/// Re-read the chunk manifest; when it changed, forget the cached last
+/// values, which may predate its chunks or deletes. Returns whether it did.
+pub async fn refresh(state: &AppState) -> Result<bool> {
+    let changed = state.chunk_store.reload().await?;
+    if changed {
+        state.last_values.lock().await.clear();
+    }
+    Ok(changed)
+}
+
+/// Re-read the manifest every refresh interval until `shutdown` fires.
+pub async fn run(state: Arc<AppState>, cfg: ReplicaConfig, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let mut ticker = tokio::time::interval(cfg.refresh_interval());
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => match refresh(&state).await.context("reloading the chunk manifest") {
+                Ok(true) => tracing::debug!("replica: picked up a new chunk manifest"),
+                Ok(false) => {}
+                // e.g. a manifest moved away by a restore; the next round tries again
+                Err(e) => tracing::warn!("replica: {:#}", e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::memtable::Observation;
+    use crate::{AppState, Config};
+
+    #[tokio::test]
+    async fn replica_sees_chunks_flushed_after_it_opened() {
+        let dir = crate::test_util::TempDir::new("replica");
+        let owner = AppState::open(&Config { data_dir: dir.to_path_buf(), ..Default::default() }).await.unwrap();
+        let at = |t: &str| Observation::empty("A", t.parse().unwrap());
+        owner.chunk_store.write_chunk("A", "flush-1", &[at("2025-01-01T00:00:00Z")]).await.unwrap();
+
+        let mut cfg = Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        cfg.serve_as_replica();
+        assert!(cfg.read_only && !cfg.server.enabled(crate::config::Surface::Write));
+        let replica = AppState::open(&cfg).await.unwrap();
+        assert_eq!(replica.chunk_store.read_chunks("A").await.unwrap().len(), 1);
+        let newest = crate::query::latest(&replica, "A").await.unwrap().unwrap();
+        assert_eq!(newest.time.to_string(), "2025-01-01T00:00:00Z");
+
+        owner.chunk_store.write_chunk("A", "flush-2", &[at("2025-01-01T00:01:00Z")]).await.unwrap();
+        assert!(super::refresh(&replica).await.unwrap());
+        assert!(!super::refresh(&replica).await.unwrap());
+        assert_eq!(replica.chunk_store.read_chunks("A").await.unwrap().len(), 2);
+        // the cached last value went with the old manifest
+        let newest = crate::query::latest(&replica, "A").await.unwrap().unwrap();
+        assert_eq!(newest.time.to_string(), "2025-01-01T00:01:00Z");
+        assert!(owner.chunk_store.reload().await.is_err());
+    }
+}
@@ -0,0 +1,530 @@
+// Leader-follower replication by WAL streaming. A follower polls the
+// leader's `/api/v1/admin/replication/wal` from a (segment, offset) position
+// and applies the records it gets, in log order, through its own WAL and
+// MemTable; from there its flushes, rollups and retention run as on any node.
+// Range deletes are issued again locally. The position is saved under
+// `data_dir/replication` after every applied batch, so a restarted follower
+// carries on where it stopped. A follower refuses client writes and is a
+// place for heavy queries and a warm standby.
+//
+// The leader pins the segments each follower has yet to read, so flushes do
+// not remove them; followers silent for longer than `FOLLOWER_TIMEOUT` are
+// forgotten. A follower set up from a snapshot of the leader starts at the
+// WAL position the snapshot recorded; without one it starts from the
+// leader's oldest segment, which only holds what was not yet flushed.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use crate::storage::memtable::Observation;
+use crate::storage::wal::{self, Record, SegmentPin, WAL};
+use crate::units;
+use crate::AppState;
+
+/// A snapshot's WAL position, for followers bootstrapped from it.
+pub const SNAPSHOT_POSITION_FILE: &str = "replication-position.json";
+const POSITION_FILE: &str = "position.json";
+const FOLLOWERS_FILE: &str = "followers.json";
+/// A follower not heard from for this long no longer holds back WAL cleanup.
+const FOLLOWER_TIMEOUT: Duration = Duration::from_secs(3600);
+/// Most record bytes the leader sends for one request.
+pub const MAX_READ_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Base URL of the leader's admin API, e.g. `http://leader:8081`.
+    pub leader: String,
+    /// Sent as `X-Api-Key`; needs the admin scope on the leader.
+    #[serde(default, serialize_with = "crate::config::redacted_opt")]
+    pub api_key: Option<String>,
+    /// This follower's name on the leader, unique among its followers.
+    pub name: String,
+    /// Pause between polls once caught up.
+    #[serde(alias = "poll_interval", default = "default_poll_interval_ms", deserialize_with = "units::millis")]
+    pub poll_interval_ms: u64,
+    /// Most record bytes asked for per poll.
+    #[serde(alias = "max_batch", default = "default_max_batch_bytes", deserialize_with = "units::bytes")]
+    pub max_batch_bytes: u64,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    500
+}
+
+fn default_max_batch_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+impl ReplicationConfig {
+    /// `SKYPULSE_REPLICATION_LEADER` makes this node a follower named
+    /// `SKYPULSE_REPLICATION_NAME` (the host name when unset), using
+    /// `SKYPULSE_REPLICATION_API_KEY`.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(leader) = std::env::var("SKYPULSE_REPLICATION_LEADER") {
+            cfg.get_or_insert_with(|| Self {
+                leader: String::new(),
+                api_key: None,
+                name: std::env::var("HOSTNAME").unwrap_or_default(),
+                poll_interval_ms: default_poll_interval_ms(),
+                max_batch_bytes: default_max_batch_bytes(),
+            })
+            .leader = leader;
+        }
+        let Some(c) = cfg else { return Ok(()) };
+        if let Ok(name) = std::env::var("SKYPULSE_REPLICATION_NAME") {
+            c.name = name;
+        }
+        if let Ok(key) = std::env::var("SKYPULSE_REPLICATION_API_KEY") {
+            c.api_key = Some(key);
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.leader.is_empty() {
+            bail!("replication.leader must be the leader's admin URL");
+        }
+        if self.name.is_empty() || !self.name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+            bail!("replication.name must be letters, digits, '-', '_' or '.', got '{}'", self.name);
+        }
+        Ok(())
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms.max(10))
+    }
+}
+
+/// Where in the leader's WAL a follower is; segment 0 means the oldest one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FollowerStatus {
+    pub name: String,
+    pub position: Position,
+    pub last_seen: String,
+    /// Segments between the follower's and the active one.
+    pub lag_segments: u64,
+    #[serde(skip)]
+    seen_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpstreamStatus {
+    pub leader: String,
+    pub position: Position,
+    /// The leader's active segment as of the last poll.
+    pub leader_segment: u64,
+    /// Whether the last poll found nothing more to read.
+    pub caught_up: bool,
+    pub applied_records: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_applied: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationStatus {
+    /// `leader`, or `follower` when this node follows another.
+    pub role: &'static str,
+    /// Followers reading this node's WAL.
+    pub followers: Vec<FollowerStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<UpstreamStatus>,
+}
+
+struct Follower {
+    status: FollowerStatus,
+    _pin: SegmentPin,
+}
+
+/// The follower side: where this node is in its leader's WAL.
+struct Upstream {
+    cfg: ReplicationConfig,
+    status: std::sync::Mutex<UpstreamStatus>,
+}
+
+pub struct Replication {
+    /// `data_dir/replication`, or `None` on a read-only node.
+    dir: Option<PathBuf>,
+    followers: std::sync::Mutex<BTreeMap<String, Follower>>,
+    upstream: Option<Upstream>,
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn read_json<T: serde::de::DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).with_context(|| format!("reading {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(value)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+impl Replication {
+    /// Load follower positions from `data_dir/replication` and pin their
+    /// segments in `wal` again; with `cfg`, load where this node is in its leader's WAL.
+    pub fn open(data_dir: &Path, wal: Option<&WAL>, cfg: Option<ReplicationConfig>) -> Result<Self> {
+        let dir = match wal {
+            Some(_) => {
+                let dir = data_dir.join("replication");
+                std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+                Some(dir)
+            }
+            None => None,
+        };
+        let mut followers = BTreeMap::new();
+        if let (Some(dir), Some(wal)) = (&dir, wal) {
+            let cutoff = Utc::now().timestamp_millis() - FOLLOWER_TIMEOUT.as_millis() as i64;
+            let saved: BTreeMap<String, (Position, i64)> = read_json(&dir.join(FOLLOWERS_FILE))?;
+            for (name, (position, seen_at)) in saved.into_iter().filter(|(_, (_, seen))| *seen >= cutoff) {
+                let last_seen = chrono::DateTime::from_timestamp_millis(seen_at).map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_default();
+                let status = FollowerStatus { name: name.clone(), position, last_seen, lag_segments: 0, seen_at };
+                followers.insert(name, Follower { status, _pin: wal.pin(position.segment) });
+            }
+        }
+        let upstream = match cfg {
+            Some(cfg) => {
+                cfg.validate()?;
+                let Some(dir) = &dir else { bail!("a read-only node cannot be a replication follower") };
+                let position: Position = read_json(&dir.join(POSITION_FILE))?;
+                if position.segment == 0 {
+                    tracing::warn!("replication: no saved position; starting from the oldest WAL segment on {}", cfg.leader);
+                }
+                let status = UpstreamStatus { leader: cfg.leader.clone(), position, ..Default::default() };
+                Some(Upstream { cfg, status: std::sync::Mutex::new(status) })
+            }
+            None => None,
+        };
+        Ok(Self { dir, followers: std::sync::Mutex::new(followers), upstream })
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.upstream.is_some()
+    }
+
+    /// Record that follower `name` asked for `position`: it has everything
+    /// before it, so only segments from there on stay pinned for it.
+    pub fn seen(&self, wal: &WAL, name: &str, position: Position) -> Result<()> {
+        let seen_at = Utc::now().timestamp_millis();
+        let cutoff = seen_at - FOLLOWER_TIMEOUT.as_millis() as i64;
+        let mut followers = self.followers.lock().unwrap();
+        let moved = followers.get(name).is_none_or(|f| f.status.position.segment != position.segment);
+        let status = FollowerStatus { name: name.to_string(), position, last_seen: now(), lag_segments: 0, seen_at };
+        followers.insert(name.to_string(), Follower { status, _pin: wal.pin(position.segment) });
+        let before = followers.len();
+        followers.retain(|_, f| f.status.seen_at >= cutoff);
+        if let (Some(dir), true) = (&self.dir, moved || followers.len() != before) {
+            let saved: BTreeMap<&String, (Position, i64)> = followers.iter().map(|(n, f)| (n, (f.status.position, f.status.seen_at))).collect();
+            write_json(&dir.join(FOLLOWERS_FILE), &saved)?;
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, wal: Option<&WAL>) -> ReplicationStatus {
+        let active = wal.map_or(0, WAL::active_segment);
+        let followers = self
+            .followers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|f| FollowerStatus { lag_segments: active.saturating_sub(f.status.position.segment), ..f.status.clone() })
+            .collect();
+        ReplicationStatus {
+            role: if self.is_follower() { "follower" } else { "leader" },
+            followers,
+            upstream: self.upstream.as_ref().map(|u| u.status.lock().unwrap().clone()),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut UpstreamStatus)) {
+        if let Some(u) = &self.upstream {
+            f(&mut u.status.lock().unwrap());
+        }
+    }
+
+    fn save_position(&self, position: Position) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            write_json(&dir.join(POSITION_FILE), &position)?;
+        }
+        self.update(|s| s.position = position);
+        Ok(())
+    }
+}
+
+/// Copy a snapshot's WAL position into `data_dir`, so a follower restored
+/// from the snapshot starts reading where it was taken.
+pub fn install_snapshot_position(snapshot: &Path, data_dir: &Path) -> Result<Option<Position>> {
+    let position: Option<Position> = read_json(&snapshot.join(SNAPSHOT_POSITION_FILE))?;
+    let Some(position) = position else { return Ok(None) };
+    let dir = data_dir.join("replication");
+    std::fs::create_dir_all(&dir)?;
+    write_json(&dir.join(POSITION_FILE), &position)?;
+    Ok(Some(position))
+}
+
+/// Apply records from the leader in order: runs of writes go through the
+/// WAL and MemTable as one batch, deletes are issued again here.
+pub(crate) async fn apply(state: &AppState, records: Vec<Record>) -> Result<usize> {
+    let n = records.len();
+    let mut writes: Vec<Observation> = Vec::new();
+    for r in records {
+        match r {
+            Record::Write(o) => writes.push(o),
+            Record::Delete { tombstone } => {
+                if !writes.is_empty() {
+                    crate::ingest::append_mirrored(state, std::mem::take(&mut writes)).await?;
+                }
+                crate::delete::apply(state, &tombstone.station_id, tombstone.start, tombstone.end).await?;
+            }
+        }
+    }
+    if !writes.is_empty() {
+        crate::ingest::append_mirrored(state, writes).await?;
+    }
+    Ok(n)
+}
+
+fn header(resp: &reqwest::Response, name: &str) -> Result<Option<u64>> {
+    resp.headers()
+        .get(name)
+        .map(|v| v.to_str().ok().and_then(|v| v.parse().ok()).with_context(|| format!("bad {} header", name)))
+        .transpose()
+}
+
+/// Fetch and apply one batch; returns whether there may be more to read right away.
+async fn poll_once(state: &AppState, client: &reqwest::Client, cfg: &ReplicationConfig) -> Result<bool> {
+    let position = state.replication.status(None).upstream.map(|s| s.position).unwrap_or_default();
+    let url = format!("{}/api/v1/admin/replication/wal", cfg.leader.trim_end_matches('/'));
+    let mut req = client.get(&url).query(&[
+        ("follower", cfg.name.clone()),
+        ("segment", position.segment.to_string()),
+        ("offset", position.offset.to_string()),
+        ("max_bytes", cfg.max_batch_bytes.to_string()),
+    ]);
+    if let Some(key) = &cfg.api_key {
+        req = req.header("x-api-key", key);
+    }
+    let resp = req.send().await.with_context(|| format!("polling {}", url))?;
+    if resp.status() == reqwest::StatusCode::GONE {
+        bail!(
+            "the leader no longer has WAL segment {}; restore a snapshot of the leader into this follower and restart it",
+            position.segment
+        );
+    }
+    if !resp.status().is_success() {
+        let status = resp.status();
+        bail!("{} answered {}: {}", url, status, resp.text().await.unwrap_or_default());
+    }
+    let segment = header(&resp, "x-wal-segment")?.context("missing x-wal-segment")?;
+    let next_offset = header(&resp, "x-wal-next-offset")?.context("missing x-wal-next-offset")?;
+    let next_segment = header(&resp, "x-wal-next-segment")?;
+    let active = header(&resp, "x-wal-active")?.unwrap_or(segment);
+    let body = resp.bytes().await?;
+    let records = wal::decode_records(&body).with_context(|| format!("records from WAL segment {}", segment))?;
+    let applied = apply(state, records).await? as u64;
+    let next = match next_segment {
+        Some(s) => Position { segment: s, offset: 0 },
+        None => Position { segment, offset: next_offset },
+    };
+    let caught_up = applied == 0 && next_segment.is_none();
+    state.replication.save_position(next)?;
+    state.replication.update(|s| {
+        s.leader_segment = active;
+        s.caught_up = caught_up;
+        s.last_error = None;
+        if applied > 0 {
+            s.applied_records += applied;
+            s.last_applied = Some(now());
+        }
+    });
+    Ok(!caught_up)
+}
+
+/// Follow the leader until `shutdown` fires: polls back to back while
+/// behind, every `poll_interval` once caught up or after an error.
+pub async fn run(state: Arc<AppState>, mut shutdown: tokio::sync::broadcast::Receiver<()>) {
+    let Some(cfg) = state.replication.upstream.as_ref().map(|u| u.cfg.clone()) else { return };
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(60)).build().unwrap_or_default();
+    loop {
+        let busy = match poll_once(&state, &client, &cfg).await {
+            Ok(more) => more,
+            Err(e) => {
+                tracing::warn!(leader = cfg.leader, "replication: {:#}", e);
+                state.replication.update(|s| s.last_error = Some(format!("{:#}", e)));
+                false
+            }
+        };
+        if busy {
+            continue;
+        }
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = tokio::time::sleep(cfg.poll_interval()) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Timestamp;
+
+    #[tokio::test]
+    async fn follower_applies_the_leader_wal_in_order() {
+        let dir = crate::test_util::TempDir::new("replication");
+        let leader = AppState::open(&crate::Config { data_dir: dir.join("leader"), ..Default::default() }).await.unwrap();
+        let at = |t: &str| Observation::empty("A", t.parse().unwrap());
+        crate::ingest::append(&leader, vec![at("2025-01-01T00:00:00Z"), at("2025-01-01T00:01:00Z")]).await.unwrap();
+        let (start, end): (Timestamp, Timestamp) = ("2025-01-01T00:00:00Z".parse().unwrap(), "2025-01-01T00:01:00Z".parse().unwrap());
+        crate::delete::delete(&leader, "A", Some(start), Some(end)).await.unwrap();
+        crate::ingest::append(&leader, vec![at("2025-01-01T00:00:00Z")]).await.unwrap();
+
+        let wal = leader.wal.as_ref().unwrap();
+        let tail = wal.read_records(0, 0, 1 << 20).await.unwrap().unwrap();
+        assert_eq!(tail.next_segment, None);
+        leader.replication.seen(wal, "f1", Position { segment: tail.segment, offset: tail.next_offset }).unwrap();
+        assert_eq!(leader.replication.status(Some(wal)).followers[0].lag_segments, 0);
+
+        let cfg = ReplicationConfig {
+            leader: "http://localhost:1".into(),
+            api_key: None,
+            name: "f1".into(),
+            poll_interval_ms: 10,
+            max_batch_bytes: 1 << 20,
+        };
+        let follower = AppState::open(&crate::Config { data_dir: dir.join("follower"), replication: Some(cfg), ..Default::default() })
+            .await
+            .unwrap();
+        assert!(follower.write_refusal().is_some());
+        let records = wal::decode_records(&tail.records).unwrap();
+        assert_eq!(apply(&follower, records).await.unwrap(), 4);
+        // the delete landed between the writes: one row before it survives, the rewrite after it too
+        let buffered = follower.memtable.lock().await.buffer.get("A").cloned().unwrap_or_default();
+        let times: Vec<String> = buffered.iter().map(|o| o.time.to_string()).collect();
+        assert_eq!(times, ["2025-01-01T00:01:00Z", "2025-01-01T00:00:00Z"]);
+    }
+
+    #[tokio::test]
+    async fn follower_reads_past_a_damaged_segment() {
+        let dir = crate::test_util::TempDir::new("replication-damage");
+        let leader = AppState::open(&crate::Config { data_dir: dir.join("leader"), ..Default::default() }).await.unwrap();
+        for minute in 0..3 {
+            let at = format!("2025-01-01T00:0{}:00Z", minute).parse().unwrap();
+            crate::ingest::append(&leader, vec![Observation::empty("A", at)]).await.unwrap();
+        }
+        let wal = leader.wal.as_ref().unwrap();
+        wal.rotate().await.unwrap();
+        crate::ingest::append(&leader, vec![Observation::empty("A", "2025-01-01T00:03:00Z".parse().unwrap())]).await.unwrap();
+
+        // a bit flips in the middle record of the sealed segment
+        let path = wal.dir().join(format!("{:020}.wal", 1));
+        let mut data = std::fs::read(&path).unwrap();
+        let record_len = (data.len() - 8) / 3;
+        data[8 + record_len + 12] ^= 0x20;
+        std::fs::write(&path, &data).unwrap();
+
+        let mut position = Position::default();
+        let mut times = Vec::new();
+        loop {
+            let tail = wal.read_records(position.segment, position.offset, 64).await.unwrap().unwrap();
+            let records = wal::decode_records(&tail.records).unwrap();
+            position = match tail.next_segment {
+                Some(segment) => Position { segment, offset: 0 },
+                None => Position { segment: tail.segment, offset: tail.next_offset },
+            };
+            if records.is_empty() && tail.next_segment.is_none() {
+                break;
+            }
+            times.extend(records.into_iter().filter_map(|r| match r {
+                Record::Write(o) => Some(o.time.to_string()),
+                Record::Delete { .. } => None,
+            }));
+        }
+        assert_eq!(times, ["2025-01-01T00:00:00Z", "2025-01-01T00:02:00Z", "2025-01-01T00:03:00Z"]);
+    }
+
+    /// A leader's WAL endpoint answering from `tail` with the records from
+    /// offset 0 and nothing after them; or 410 when `gone`.
+    async fn stub_leader(tail: Arc<wal::Tail>, gone: bool) -> String {
+        use axum::extract::Query;
+        use axum::http::StatusCode;
+        use axum::response::IntoResponse;
+
+        let serve = move |Query(q): Query<std::collections::HashMap<String, String>>| {
+            let tail = tail.clone();
+            async move {
+                if gone {
+                    return StatusCode::GONE.into_response();
+                }
+                let body = if q["offset"] == "0" { tail.records.clone() } else { Vec::new() };
+                let headers = [
+                    ("x-wal-segment", tail.segment.to_string()),
+                    ("x-wal-next-offset", tail.next_offset.to_string()),
+                    ("x-wal-active", tail.segment.to_string()),
+                ];
+                (headers, body).into_response()
+            }
+        };
+        let app = axum::Router::new().route("/api/v1/admin/replication/wal", axum::routing::get(serve));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn poll_once_applies_batches_until_caught_up() {
+        let dir = crate::test_util::TempDir::new("replication-poll");
+        let leader = AppState::open(&crate::Config { data_dir: dir.join("leader"), ..Default::default() }).await.unwrap();
+        let at = |t: &str| Observation::empty("A", t.parse().unwrap());
+        crate::ingest::append(&leader, vec![at("2025-01-01T00:00:00Z"), at("2025-01-01T00:01:00Z")]).await.unwrap();
+        let tail = Arc::new(leader.wal.as_ref().unwrap().read_records(0, 0, 1 << 20).await.unwrap().unwrap());
+
+        let cfg = ReplicationConfig {
+            leader: stub_leader(tail.clone(), false).await,
+            api_key: None,
+            name: "f1".into(),
+            poll_interval_ms: 10,
+            max_batch_bytes: 1 << 20,
+        };
+        let follower = AppState::open(&crate::Config { data_dir: dir.join("follower"), replication: Some(cfg.clone()), ..Default::default() })
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+        assert!(poll_once(&follower, &client, &cfg).await.unwrap());
+        assert!(!poll_once(&follower, &client, &cfg).await.unwrap());
+        let status = follower.replication.status(None).upstream.unwrap();
+        assert_eq!((status.position, status.applied_records, status.caught_up), (Position { segment: tail.segment, offset: tail.next_offset }, 2, true));
+        assert_eq!(follower.memtable.lock().await.buffer["A"].len(), 2);
+        // the position survives a restart
+        drop(follower);
+        let follower = AppState::open(&crate::Config { data_dir: dir.join("follower"), replication: Some(cfg.clone()), ..Default::default() })
+            .await
+            .unwrap();
+        assert_eq!(follower.replication.status(None).upstream.unwrap().position.offset, tail.next_offset);
+
+        // a leader that dropped the segment asks for a snapshot
+        let gone = ReplicationConfig { leader: stub_leader(tail, true).await, ..cfg };
+        assert!(poll_once(&follower, &client, &gone).await.unwrap_err().to_string().contains("restore a snapshot"));
+    }
+}
@@ -0,0 +1,106 @@
+// Runtime topology: ingest, flush, compaction and query work can each get a
+// dedicated tokio runtime so heavy analytical queries don't add jitter to the
+// real-time ingest path. Pools without a configured size share the main runtime.
+
+use anyhow::{bail, Result};
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// Worker threads per pool; `None` (or 0) shares the main runtime.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// HTTP server, file-drop and scraping.
+    pub ingest_threads: Option<usize>,
+    /// MemTable flush scheduling and chunk/rollup writes.
+    pub flush_threads: Option<usize>,
+    /// Retention and other background maintenance.
+    pub compaction_threads: Option<usize>,
+    /// Query evaluation.
+    pub query_threads: Option<usize>,
+}
+
+impl RuntimeConfig {
+    /// Override with `SKYPULSE_{INGEST,FLUSH,COMPACTION,QUERY}_THREADS`.
+    pub fn apply_env(&mut self) -> Result<()> {
+        let threads = |name: &str| -> Result<Option<usize>> {
+            match std::env::var(name) {
+                Ok(v) => match v.parse::<usize>() {
+                    Ok(n) => Ok(Some(n)),
+                    Err(_) => bail!("{} must be a thread count, got '{}'", name, v),
+                },
+                Err(_) => Ok(None),
+            }
+        };
+        for (name, slot) in [
+            ("SKYPULSE_INGEST_THREADS", &mut self.ingest_threads),
+            ("SKYPULSE_FLUSH_THREADS", &mut self.flush_threads),
+            ("SKYPULSE_COMPACTION_THREADS", &mut self.compaction_threads),
+            ("SKYPULSE_QUERY_THREADS", &mut self.query_threads),
+        ] {
+            if let Some(n) = threads(name)? {
+                *slot = (n > 0).then_some(n);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Handles to spawn each kind of work on, owning any dedicated runtimes.
+pub struct Runtimes {
+    pub ingest: Handle,
+    pub flush: Handle,
+    pub compaction: Handle,
+    pub query: Handle,
+    owned: Vec<Runtime>,
+}
+
+impl Runtimes {
+    /// Every pool on `main`.
+    pub fn shared(main: Handle) -> Self {
+        Self { ingest: main.clone(), flush: main.clone(), compaction: main.clone(), query: main, owned: Vec::new() }
+    }
+
+    pub fn build(cfg: &RuntimeConfig, main: Handle) -> Result<Self> {
+        let mut owned = Vec::new();
+        let mut pool = |name: &str, threads: Option<usize>| -> Result<Handle> {
+            let Some(n) = threads.filter(|n| *n > 0) else { return Ok(main.clone()) };
+            let rt = Builder::new_multi_thread()
+                .worker_threads(n)
+                .thread_name(format!("skypulse-{}", name))
+                .enable_all()
+                .build()?;
+            let handle = rt.handle().clone();
+            owned.push(rt);
+            Ok(handle)
+        };
+        let ingest = pool("ingest", cfg.ingest_threads)?;
+        let flush = pool("flush", cfg.flush_threads)?;
+        let compaction = pool("compaction", cfg.compaction_threads)?;
+        let query = pool("query", cfg.query_threads)?;
+        Ok(Self { ingest, flush, compaction, query, owned })
+    }
+}
+
+impl Drop for Runtimes {
+    fn drop(&mut self) {
+        // dropping a runtime normally blocks, which panics inside async code
+        for rt in self.owned.drain(..) {
+            rt.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedicated_pools_run_on_their_own_threads() {
+        let main = Builder::new_current_thread().enable_all().build().unwrap();
+        let cfg = RuntimeConfig { query_threads: Some(1), ..Default::default() };
+        let rts = Runtimes::build(&cfg, main.handle().clone()).unwrap();
+        let name = |h: &Handle| main.block_on(h.spawn(async { std::thread::current().name().map(String::from) })).unwrap();
+        assert_eq!(name(&rts.query).as_deref(), Some("skypulse-query"));
+        assert_ne!(name(&rts.ingest).as_deref(), Some("skypulse-query"));
+    }
+}
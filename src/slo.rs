@@ -0,0 +1,279 @@
+// Latency SLOs for the server's own write and query endpoints. Every request
+// is classed good or bad (slower than the target, or a 5xx) into per-minute
+// buckets; burn rates compare the bad fraction over a window with the error
+// budget `1 - objective`. With `alert_burn_rate` set, a watcher logs a warning
+// while both the 5m and 1h burn rates exceed it (the usual multiwindow rule).
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use axum::{extract::Request, middleware::Next, response::Response};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Receiver as BroadcastReceiver;
+use crate::units;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SloConfig {
+    /// Write requests slower than this count against the write SLO.
+    #[serde(alias = "write_latency", deserialize_with = "units::millis")]
+    pub write_latency_ms: u64,
+    /// Query requests slower than this count against the query SLO.
+    #[serde(alias = "query_latency", deserialize_with = "units::millis")]
+    pub query_latency_ms: u64,
+    /// Fraction of requests that must be good, e.g. 0.99.
+    pub objective: f64,
+    /// Warn while the 5m and 1h burn rates both exceed this; off when unset.
+    pub alert_burn_rate: Option<f64>,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self { write_latency_ms: 100, query_latency_ms: 1000, objective: 0.99, alert_burn_rate: None }
+    }
+}
+
+impl SloConfig {
+    /// `SKYPULSE_SLO_WRITE_MS`, `SKYPULSE_SLO_QUERY_MS`, `SKYPULSE_SLO_OBJECTIVE`
+    /// and `SKYPULSE_SLO_ALERT_BURN_RATE`.
+    pub fn apply_env(&mut self) -> Result<()> {
+        fn var<T: std::str::FromStr>(name: &str) -> Result<Option<T>>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            match std::env::var(name) {
+                Ok(v) => Ok(Some(v.parse().with_context(|| format!("{} '{}'", name, v))?)),
+                Err(_) => Ok(None),
+            }
+        }
+        if let Some(d) = units::env_duration("SKYPULSE_SLO_WRITE_MS", "ms")? {
+            self.write_latency_ms = d.as_millis() as u64;
+        }
+        if let Some(d) = units::env_duration("SKYPULSE_SLO_QUERY_MS", "ms")? {
+            self.query_latency_ms = d.as_millis() as u64;
+        }
+        if let Some(v) = var("SKYPULSE_SLO_OBJECTIVE")? {
+            self.objective = v;
+        }
+        if let Some(v) = var("SKYPULSE_SLO_ALERT_BURN_RATE")? {
+            self.alert_burn_rate = Some(v);
+        }
+        if !(self.objective > 0.0 && self.objective < 1.0) {
+            anyhow::bail!("slo.objective must be between 0 and 1, got {}", self.objective);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Write,
+    Query,
+}
+
+impl Kind {
+    const ALL: [Kind; 2] = [Kind::Write, Kind::Query];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Write => "write",
+            Kind::Query => "query",
+        }
+    }
+}
+
+/// Burn-rate windows, in minutes.
+pub const WINDOWS: [(&str, i64); 2] = [("5m", 5), ("1h", 60)];
+
+/// Per-minute (minute, total, bad) counts covering the longest window.
+#[derive(Debug, Default)]
+struct Series {
+    minutes: VecDeque<(i64, u64, u64)>,
+    total: u64,
+    bad: u64,
+}
+
+impl Series {
+    fn record(&mut self, minute: i64, bad: bool) {
+        match self.minutes.back_mut() {
+            Some(last) if last.0 == minute => {
+                last.1 += 1;
+                last.2 += bad as u64;
+            }
+            _ => self.minutes.push_back((minute, 1, bad as u64)),
+        }
+        let oldest = minute - WINDOWS[WINDOWS.len() - 1].1;
+        while self.minutes.front().is_some_and(|m| m.0 <= oldest) {
+            self.minutes.pop_front();
+        }
+        self.total += 1;
+        self.bad += bad as u64;
+    }
+
+    /// Bad fraction over the `window` minutes ending at `minute`.
+    fn bad_ratio(&self, minute: i64, window: i64) -> f64 {
+        let (total, bad) = self
+            .minutes
+            .iter()
+            .filter(|m| m.0 > minute - window && m.0 <= minute)
+            .fold((0, 0), |(t, b), m| (t + m.1, b + m.2));
+        if total == 0 {
+            0.0
+        } else {
+            bad as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SloTracker {
+    cfg: SloConfig,
+    series: [Mutex<Series>; 2],
+    alerting: [AtomicBool; 2],
+}
+
+fn now_minute() -> i64 {
+    chrono::Utc::now().timestamp().div_euclid(60)
+}
+
+impl SloTracker {
+    pub fn new(cfg: SloConfig) -> Self {
+        Self { cfg, series: Default::default(), alerting: Default::default() }
+    }
+
+    fn target(&self, kind: Kind) -> Duration {
+        Duration::from_millis(match kind {
+            Kind::Write => self.cfg.write_latency_ms,
+            Kind::Query => self.cfg.query_latency_ms,
+        })
+    }
+
+    fn series(&self, kind: Kind) -> std::sync::MutexGuard<'_, Series> {
+        self.series[kind as usize].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn record(&self, kind: Kind, latency: Duration, failed: bool) {
+        self.record_at(kind, now_minute(), failed || latency > self.target(kind));
+    }
+
+    fn record_at(&self, kind: Kind, minute: i64, bad: bool) {
+        self.series(kind).record(minute, bad);
+    }
+
+    /// How fast the error budget is being spent over the last `window`
+    /// minutes; 1.0 spends exactly the budget.
+    pub fn burn_rate(&self, kind: Kind, window: i64) -> f64 {
+        self.burn_rate_at(kind, now_minute(), window)
+    }
+
+    fn burn_rate_at(&self, kind: Kind, minute: i64, window: i64) -> f64 {
+        self.series(kind).bad_ratio(minute, window) / (1.0 - self.cfg.objective)
+    }
+
+    /// Re-evaluate the alert rule, logging when an SLO starts or stops burning.
+    fn evaluate(&self, threshold: f64) {
+        for kind in Kind::ALL {
+            let rates: Vec<f64> = WINDOWS.iter().map(|(_, w)| self.burn_rate(kind, *w)).collect();
+            let firing = rates.iter().all(|r| *r > threshold);
+            let was = self.alerting[kind as usize].swap(firing, Ordering::Relaxed);
+            if firing && !was {
+                tracing::warn!(
+                    slo = kind.as_str(),
+                    "latency SLO burning error budget at {:.1}x (5m) / {:.1}x (1h), threshold {}",
+                    rates[0],
+                    rates[1],
+                    threshold
+                );
+            } else if was && !firing {
+                tracing::info!(slo = kind.as_str(), "latency SLO burn rate back under {}", threshold);
+            }
+        }
+    }
+
+    /// Prometheus text for the SLO counters, burn rates and alert state.
+    pub fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP skypulse_slo_requests_total Requests counted against a latency SLO.");
+        let _ = writeln!(out, "# TYPE skypulse_slo_requests_total counter");
+        for kind in Kind::ALL {
+            let s = self.series(kind);
+            let _ = writeln!(out, "skypulse_slo_requests_total{{slo=\"{}\",outcome=\"good\"}} {}", kind.as_str(), s.total - s.bad);
+            let _ = writeln!(out, "skypulse_slo_requests_total{{slo=\"{}\",outcome=\"bad\"}} {}", kind.as_str(), s.bad);
+        }
+        let _ = writeln!(out, "# HELP skypulse_slo_target_seconds Latency target of each SLO.");
+        let _ = writeln!(out, "# TYPE skypulse_slo_target_seconds gauge");
+        for kind in Kind::ALL {
+            let _ = writeln!(out, "skypulse_slo_target_seconds{{slo=\"{}\"}} {}", kind.as_str(), self.target(kind).as_secs_f64());
+        }
+        let _ = writeln!(out, "# HELP skypulse_slo_burn_rate Error budget burn rate over a window (1 = on budget).");
+        let _ = writeln!(out, "# TYPE skypulse_slo_burn_rate gauge");
+        for kind in Kind::ALL {
+            for (name, window) in WINDOWS {
+                let _ = writeln!(
+                    out,
+                    "skypulse_slo_burn_rate{{slo=\"{}\",window=\"{}\"}} {}",
+                    kind.as_str(),
+                    name,
+                    self.burn_rate(kind, window)
+                );
+            }
+        }
+        if self.cfg.alert_burn_rate.is_some() {
+            let _ = writeln!(out, "# HELP skypulse_slo_alerting Whether the burn-rate alert rule is firing.");
+            let _ = writeln!(out, "# TYPE skypulse_slo_alerting gauge");
+            for kind in Kind::ALL {
+                let firing = self.alerting[kind as usize].load(Ordering::Relaxed) as u8;
+                let _ = writeln!(out, "skypulse_slo_alerting{{slo=\"{}\"}} {}", kind.as_str(), firing);
+            }
+        }
+    }
+}
+
+/// Check the alert rule every 30 seconds until shutdown.
+pub async fn watch(tracker: Arc<SloTracker>, mut shutdown: BroadcastReceiver<()>) {
+    let Some(threshold) = tracker.cfg.alert_burn_rate else { return };
+    let mut ticker = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => tracker.evaluate(threshold),
+        }
+    }
+}
+
+/// Middleware timing a route group against the `kind` SLO.
+pub async fn track(kind: Kind, req: Request, next: Next) -> Response {
+    let tracker = req.extensions().get::<Arc<crate::AppState>>().map(|s| s.slo.clone());
+    let started = Instant::now();
+    let resp = next.run(req).await;
+    if let Some(tracker) = tracker {
+        tracker.record(kind, started.elapsed(), resp.status().is_server_error());
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burn_rate_is_bad_fraction_over_budget() {
+        let slo = SloTracker::new(SloConfig { objective: 0.99, ..Default::default() });
+        // 2 bad out of 100 in the current minute: 2% against a 1% budget
+        for i in 0..100 {
+            slo.record_at(Kind::Write, 1000, i < 2);
+        }
+        assert!((slo.burn_rate_at(Kind::Write, 1000, 5) - 2.0).abs() < 1e-9);
+        // an hour later those requests have left both windows
+        assert_eq!(slo.burn_rate_at(Kind::Write, 1060, 60), 0.0);
+        // 100 good requests 10 minutes on: only the 1h window still sees the bad ones
+        for _ in 0..100 {
+            slo.record_at(Kind::Write, 1010, false);
+        }
+        assert_eq!(slo.burn_rate_at(Kind::Write, 1010, 5), 0.0);
+        assert!((slo.burn_rate_at(Kind::Write, 1010, 60) - 1.0).abs() < 1e-9);
+        assert_eq!(slo.burn_rate_at(Kind::Query, 1010, 60), 0.0);
+    }
+}
@@ -0,0 +1,274 @@
+// Online backups. A snapshot first fences the WAL: the MemTable is drained
+// and the WAL rotated under the MemTable lock, and the drained rows are
+// flushed to chunks right away. The chunk directory is then copied into a
+// timestamped directory while ingestion carries on. The copy holds every row
+// acknowledged before the fence (and possibly some written after it) with a
+// manifest matching its files, and the station registry; rollups are derived
+// data and are rebuilt from the chunks when a snapshot is restored.
+//
+// The snapshot also records the WAL segment started at the fence, so a
+// replication follower restored from it reads the leader's WAL from there.
+//
+// Restoring happens at startup, before the data directory is opened: the
+// snapshot is checked against its manifest checksums and its files are
+// copied and synced into a staging directory next to the chunk directory.
+// Only then are the current chunks and WAL moved aside and the staging
+// directory renamed into place, so a failed or interrupted copy leaves the
+// current data as it was. The copy is checked again and its rollups
+// recomputed.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use crate::storage::integrity::{self, IntegrityMode};
+use crate::storage::manifest::MANIFEST_FILE;
+use crate::stations::STATIONS_FILE;
+use crate::storage::{rollup, ChunkStore, RollupStore};
+use crate::{replication, AppState, Config};
+
+/// Snapshots go here when no directory is given.
+pub const DEFAULT_DIR: &str = "snapshots";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    /// Directory holding the chunk files and manifest.
+    pub path: PathBuf,
+    pub created_at: String,
+    /// Buffered rows flushed at the fence.
+    pub flushed_rows: usize,
+    pub chunks: usize,
+    pub bytes: u64,
+    /// Chunks written or changed during the copy and copied again.
+    pub recopied: usize,
+    /// First WAL segment holding writes after the fence.
+    pub wal_segment: Option<u64>,
+}
+
+/// Take a snapshot into a new timestamped directory under `base`
+/// (`data_dir/snapshots` when `None`).
+pub async fn create(state: &AppState, base: Option<&Path>) -> Result<Snapshot> {
+    let now = chrono::Utc::now();
+    let base = base.map_or_else(|| state.data_dir.join(DEFAULT_DIR), Path::to_path_buf);
+    let path = base.join(now.format("%Y%m%dT%H%M%S%.3fZ").to_string());
+    let (flushed_rows, wal_segment) = fence(state, "snapshot").await?;
+    let copied = async {
+        let copy = state.chunk_store.snapshot(&path).await?;
+        let stations = state.data_dir.join(STATIONS_FILE);
+        if stations.is_file() {
+            tokio::fs::copy(&stations, path.join(STATIONS_FILE)).await.context("copying the station registry")?;
+        }
+        if let Some(segment) = wal_segment {
+            let position = replication::Position { segment, offset: 0 };
+            tokio::fs::write(path.join(replication::SNAPSHOT_POSITION_FILE), serde_json::to_vec(&position)?).await?;
+        }
+        anyhow::Ok(copy)
+    };
+    let copy = match copied.await {
+        Ok(copy) => copy,
+        Err(e) => {
+            if let Err(cleanup) = tokio::fs::remove_dir_all(&path).await {
+                tracing::warn!("could not remove partial snapshot in {}: {}", path.display(), cleanup);
+            }
+            return Err(e);
+        }
+    };
+    tracing::info!("snapshot of {} chunks ({} bytes) written to {}", copy.chunks, copy.bytes, path.display());
+    Ok(Snapshot {
+        path,
+        created_at: now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        flushed_rows,
+        chunks: copy.chunks,
+        bytes: copy.bytes,
+        recopied: copy.recopied,
+        wal_segment,
+    })
+}
+
+/// Tells apart the chunks of fences drained in the same millisecond, which
+/// would otherwise overwrite each other.
+static FENCES: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+thread_local! {
+    /// Stops the fence clock for the fences of this thread.
+    pub(crate) static PINNED_MILLIS: std::cell::Cell<Option<i64>> = const { std::cell::Cell::new(None) };
+}
+
+/// When a fence drains the MemTable, in milliseconds.
+fn now_millis() -> i64 {
+    #[cfg(test)]
+    if let Some(ms) = PINNED_MILLIS.with(std::cell::Cell::get) {
+        return ms;
+    }
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Drain the MemTable, start a new WAL segment and flush the drained rows
+/// into chunks named `<what>-<millis>-<n>`; returns how many there were and
+/// the new segment. Rows of a failed flush go back into the MemTable.
+pub(crate) async fn fence(state: &AppState, what: &str) -> Result<(usize, Option<u64>)> {
+    let Some(wal) = &state.wal else { return Ok((0, None)) };
+    let (buffer, pin, drained_at, segment) = {
+        let mut mt = state.memtable.lock().await;
+        let (buffer, pin) = mt.drain();
+        wal.rotate().await?;
+        (buffer, pin, now_millis(), wal.active_segment())
+    };
+    let mut buf: Vec<_> = buffer.into_iter().collect();
+    crate::mask_drained(state, &mut buf, drained_at).await;
+    let rows = buf.iter().map(|(_, rows)| rows.len()).sum();
+    if rows == 0 {
+        return Ok((0, Some(segment)));
+    }
+    let name = format!("{}-{}-{}", what, drained_at, FENCES.fetch_add(1, Ordering::Relaxed));
+    if !crate::flush_buffer(state, &name, buf.clone(), state.config.flush.pack_below_rows).await {
+        let mut mt = state.memtable.lock().await;
+        for (k, v) in buf {
+            mt.buffer.entry(k).or_default().extend(v);
+        }
+        if let Some(pin) = pin {
+            mt.retain_pin(pin);
+        }
+        bail!("flushing {} buffered rows failed", rows);
+    }
+    drop(pin);
+    wal.remove_flushed().await?;
+    Ok((rows, Some(segment)))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Restored {
+    pub chunks: usize,
+    pub bytes: u64,
+    /// Where the chunks and WAL found in the data directory were moved.
+    pub replaced: Option<PathBuf>,
+}
+
+/// Install the snapshot in `from` as `cfg`'s data. Nothing is touched unless
+/// every snapshot file matches its manifest checksum; existing chunks and WAL
+/// segments are moved to `data_dir/replaced-<time>` rather than deleted.
+pub async fn restore(cfg: &Config, from: &Path) -> Result<Restored> {
+    if cfg.read_only {
+        bail!("cannot restore into a read-only data directory");
+    }
+    if cfg.object_store.is_some() {
+        bail!("cannot restore into object storage; restore into a local chunk directory and copy the chunks to the bucket");
+    }
+    if !from.join(MANIFEST_FILE).is_file() {
+        bail!("{} is not a snapshot: it has no {}", from.display(), MANIFEST_FILE);
+    }
+    let dir = from.to_path_buf();
+    let (manifest, _) = tokio::task::spawn_blocking(move || integrity::open_manifest(&dir, IntegrityMode::Strict, false)).await??;
+
+    tokio::fs::create_dir_all(&cfg.data_dir).await?;
+    let chunk_dir = crate::storage::chunk_store::locate(&cfg.data_dir, cfg.chunk_dir.as_deref())?;
+    let staging = chunk_dir.with_extension("restoring");
+    stage(from, &manifest.chunks.keys().map(String::as_str).chain([MANIFEST_FILE]).collect::<Vec<_>>(), &staging).await?;
+    let aside = cfg.data_dir.join(format!("replaced-{}", chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+    let mut replaced = None;
+    let stations = cfg.data_dir.join(STATIONS_FILE);
+    if from.join(STATIONS_FILE).is_file() && stations.is_file() {
+        tokio::fs::create_dir_all(&aside).await?;
+        tokio::fs::rename(&stations, aside.join(STATIONS_FILE)).await.context("moving the station registry aside")?;
+        replaced = Some(aside.clone());
+    }
+    for (current, name) in [(&chunk_dir, "chunks"), (&cfg.wal_segments(), "wal")] {
+        if !current.is_dir() || tokio::fs::read_dir(current).await?.next_entry().await?.is_none() {
+            continue;
+        }
+        tokio::fs::create_dir_all(&aside).await?;
+        tokio::fs::rename(current, aside.join(name))
+            .await
+            .with_context(|| format!("moving {} aside to {}", current.display(), aside.display()))?;
+        replaced = Some(aside.clone());
+    }
+
+    if chunk_dir.is_dir() {
+        // emptied, so nothing is lost
+        tokio::fs::remove_dir(&chunk_dir).await.with_context(|| format!("removing the empty {}", chunk_dir.display()))?;
+    }
+    tokio::fs::rename(&staging, &chunk_dir).await.with_context(|| format!("moving the restored chunks to {}", chunk_dir.display()))?;
+    if let Some(parent) = chunk_dir.parent() {
+        tokio::fs::File::open(parent).await?.sync_all().await?;
+    }
+    let store = ChunkStore::new(chunk_dir, IntegrityMode::Strict)?;
+    let rollups = RollupStore::new(cfg.data_dir.clone())?;
+    rollups.clear(None).await?;
+    for name in manifest.chunks.keys() {
+        let rows = store.read_chunk_file(name).await?;
+        rollups.replace(&rollup::file_for_chunk(name), None, &rows).await?;
+    }
+    if from.join(STATIONS_FILE).is_file() {
+        tokio::fs::copy(from.join(STATIONS_FILE), &stations).await.context("copying the station registry")?;
+    }
+    if let Some(position) = replication::install_snapshot_position(from, &cfg.data_dir)? {
+        tracing::info!("a replication follower restored from this snapshot starts at WAL segment {}", position.segment);
+    }
+    tracing::info!("restored {} chunks from {}", manifest.chunks.len(), from.display());
+    Ok(Restored { chunks: manifest.chunks.len(), bytes: manifest.chunks.values().map(|c| c.bytes).sum(), replaced })
+}
+
+/// Copy `names` from `from` into a fresh `staging` directory and sync the
+/// files and the directory, so a rename of it can be trusted.
+async fn stage(from: &Path, names: &[&str], staging: &Path) -> Result<()> {
+    if staging.exists() {
+        // left by an interrupted restore
+        tokio::fs::remove_dir_all(staging).await.with_context(|| format!("removing {}", staging.display()))?;
+    }
+    tokio::fs::create_dir_all(staging).await?;
+    for name in names {
+        let target = staging.join(name);
+        tokio::fs::copy(from.join(name), &target).await.with_context(|| format!("copying {}", name))?;
+        tokio::fs::File::open(&target).await?.sync_all().await?;
+    }
+    tokio::fs::File::open(staging).await?.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Observation;
+
+    #[tokio::test]
+    async fn snapshot_round_trips_through_restore() {
+        let dir = crate::test_util::TempDir::new("snapshot");
+        let cfg = crate::config::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        let state = AppState::open(&cfg).await.unwrap();
+        let o = Observation::empty("A", "2025-01-01T00:00:00Z".parse().unwrap());
+        state.chunk_store.write_chunk("A", "flush-1", std::slice::from_ref(&o)).await.unwrap();
+        crate::ingest::append(&state, vec![Observation::empty("A", "2025-01-01T00:01:00Z".parse().unwrap())]).await.unwrap();
+
+        let meta = crate::stations::StationMeta { station_id: "A".into(), ..serde_json::from_str("{}").unwrap() };
+        state.stations.put(meta).await.unwrap();
+
+        let snap = create(&state, None).await.unwrap();
+        assert_eq!((snap.flushed_rows, snap.chunks), (1, 2));
+        assert!(state.memtable.lock().await.buffer.is_empty());
+        let copy = ChunkStore::open_read_only(snap.path.clone(), IntegrityMode::Strict).unwrap();
+        assert_eq!(copy.read_chunks("A").await.unwrap().len(), 2);
+        drop(state);
+
+        let target = dir.join("restored");
+        let cfg = Config { data_dir: target.clone(), ..Default::default() };
+        let restored = restore(&cfg, &snap.path).await.unwrap();
+        assert_eq!((restored.chunks, restored.replaced), (2, None));
+        // a follower restored from the snapshot reads the WAL from the fence on
+        assert!(snap.wal_segment.is_some() && target.join("replication").join("position.json").is_file());
+        let state = AppState::open(&cfg).await.unwrap();
+        assert_eq!(state.chunk_store.read_chunks("A").await.unwrap().len(), 2);
+        assert_eq!(state.rollups.read("A", rollup::Resolution::Minute).await.unwrap().len(), 2);
+        assert!(state.stations.get("A").await.is_some());
+
+        // a second restore moves the restored data aside, and the staging
+        // directory of an interrupted one goes
+        drop(state);
+        std::fs::create_dir_all(target.join("chunks.restoring")).unwrap();
+        std::fs::write(target.join("chunks.restoring").join("stale.ndjson"), b"").unwrap();
+        let again = restore(&cfg, &snap.path).await.unwrap();
+        assert!(again.replaced.is_some_and(|p| p.join("chunks").join(MANIFEST_FILE).is_file()));
+        assert!(!target.join("chunks.restoring").exists() && !target.join("chunks").join("stale.ndjson").exists());
+        assert_eq!(std::fs::read_dir(target.join("chunks")).unwrap().count(), 3);
+    }
+}
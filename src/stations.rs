@@ -0,0 +1,186 @@
+// The station metadata registry: name, location, elevation and sensor
+// inventory per station, kept in `stations.json` in the data directory next
+// to the chunks and carried by snapshots. Readings do not need a registered
+// station. Registered metadata shows up in the station listing, and queries
+// and export jobs can carry it so that results come with their location.
+// The file is rewritten whole on every change, written aside and renamed
+// into place, which suits the few thousand stations a node holds.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+pub const STATIONS_FILE: &str = "stations.json";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sensor {
+    /// Unique within the station: a serial number or a name like `probe-2`.
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Fields the sensor reports.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+    /// RFC3339 time the sensor went into service.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_at: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StationMeta {
+    /// Taken from the request path when registering.
+    #[serde(default)]
+    pub station_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// WGS84 degrees; named so that response filters snap them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    /// Metres above mean sea level.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elevation_m: Option<f64>,
+    #[serde(default)]
+    pub sensors: Vec<Sensor>,
+    /// Set by the server on every change.
+    #[serde(default)]
+    pub updated_at: String,
+}
+
+impl StationMeta {
+    pub fn validate(&self) -> Result<()> {
+        if self.station_id.is_empty() {
+            bail!("station_id must not be empty");
+        }
+        match (self.latitude, self.longitude) {
+            (Some(lat), Some(lon)) => {
+                if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+                    bail!("latitude must be within ±90 and longitude within ±180 degrees");
+                }
+            }
+            (None, None) => {}
+            _ => bail!("latitude and longitude go together"),
+        }
+        if self.elevation_m.is_some_and(|e| !e.is_finite()) {
+            bail!("elevation_m must be a number");
+        }
+        let mut ids = std::collections::BTreeSet::new();
+        for s in &self.sensors {
+            if s.id.is_empty() || !ids.insert(s.id.as_str()) {
+                bail!("sensor ids must be present and unique, found '{}' twice or empty", s.id);
+            }
+            if let Some(t) = &s.installed_at {
+                chrono::DateTime::parse_from_rfc3339(t).map_err(|e| anyhow!("sensor {}: invalid installed_at: {}", s.id, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The registered stations of one node.
+pub struct Registry {
+    /// `None` when the server is read-only and the registry cannot change.
+    path: Option<PathBuf>,
+    stations: RwLock<BTreeMap<String, StationMeta>>,
+}
+
+impl Registry {
+    /// Load the registry of `data_dir`; empty when there is no file yet.
+    pub fn open(data_dir: &Path, read_only: bool) -> Result<Self> {
+        let path = data_dir.join(STATIONS_FILE);
+        let stations = match std::fs::read(&path) {
+            Ok(data) => {
+                let list: Vec<StationMeta> = serde_json::from_slice(&data).with_context(|| format!("reading {}", path.display()))?;
+                list.into_iter().map(|m| (m.station_id.clone(), m)).collect()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        Ok(Self { path: (!read_only).then_some(path), stations: RwLock::new(stations) })
+    }
+
+    pub async fn get(&self, station_id: &str) -> Option<StationMeta> {
+        self.stations.read().await.get(station_id).cloned()
+    }
+
+    /// Every registered station, by id.
+    pub async fn list(&self) -> Vec<StationMeta> {
+        self.stations.read().await.values().cloned().collect()
+    }
+
+    /// Register `meta`, replacing what the station had; returns it as stored.
+    pub async fn put(&self, mut meta: StationMeta) -> Result<StationMeta> {
+        meta.validate()?;
+        let path = self.path.as_ref().ok_or_else(|| anyhow!("server is read-only"))?;
+        meta.updated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let mut stations = self.stations.write().await;
+        let previous = stations.insert(meta.station_id.clone(), meta.clone());
+        if let Err(e) = save(path, &stations).await {
+            // keep memory and file in step
+            match previous {
+                Some(p) => stations.insert(p.station_id.clone(), p),
+                None => stations.remove(&meta.station_id),
+            };
+            return Err(e);
+        }
+        Ok(meta)
+    }
+
+    /// Forget a station's metadata; false when it had none.
+    pub async fn remove(&self, station_id: &str) -> Result<bool> {
+        let path = self.path.as_ref().ok_or_else(|| anyhow!("server is read-only"))?;
+        let mut stations = self.stations.write().await;
+        let Some(previous) = stations.remove(station_id) else { return Ok(false) };
+        if let Err(e) = save(path, &stations).await {
+            stations.insert(previous.station_id.clone(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+}
+
+async fn save(path: &Path, stations: &BTreeMap<String, StationMeta>) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let data = serde_json::to_vec_pretty(&stations.values().collect::<Vec<_>>())?;
+    tokio::fs::write(&tmp, data).await.with_context(|| format!("writing {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path).await.with_context(|| format!("replacing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persists_registrations() {
+        let dir = crate::test_util::TempDir::new("stations");
+        std::fs::create_dir_all(&dir).unwrap();
+        let registry = Registry::open(&dir, false).unwrap();
+        let meta: StationMeta = serde_json::from_value(serde_json::json!({
+            "station_id": "HK001",
+            "name": "Tsim Sha Tsui",
+            "latitude": 22.3, "longitude": 114.17, "elevation_m": 32.0,
+            "sensors": [{ "id": "probe-1", "model": "HMP155", "fields": ["temp", "humidity"], "installed_at": "2024-06-01T00:00:00Z" }],
+        }))
+        .unwrap();
+        let stored = registry.put(meta.clone()).await.unwrap();
+        assert!(!stored.updated_at.is_empty());
+        assert!(registry.put(StationMeta { latitude: Some(91.0), ..meta.clone() }).await.is_err());
+        assert!(registry.put(StationMeta { longitude: None, ..meta.clone() }).await.is_err());
+        let twice = StationMeta { sensors: [meta.sensors.clone(), meta.sensors.clone()].concat(), ..meta.clone() };
+        assert!(registry.put(twice).await.is_err());
+        registry.put(StationMeta { station_id: "HK002".into(), sensors: Vec::new(), ..meta }).await.unwrap();
+        assert!(registry.remove("HK002").await.unwrap());
+        assert!(!registry.remove("HK002").await.unwrap());
+
+        let reopened = Registry::open(&dir, true).unwrap();
+        assert_eq!(reopened.list().await, vec![stored]);
+        assert!(reopened.remove("HK001").await.is_err());
+    }
+}
@@ -0,0 +1,260 @@
+// Cold-storage compaction: observations older than the archive age are moved
+// out of raw chunks into one file per station and year,
+// `{station}-archive-{year}.ndjson.zst`. The file is a sequence of
+// independent zstd frames, one per month, compressed at a high level; the
+// frame offsets are kept in the manifest as a coarse block index. Chunks that
+// also hold newer rows are rewritten with just those rows, and late data for
+// an already archived year is merged into the existing archive. Rows of
+// pending range deletes are dropped on the way.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Utc};
+use serde::{Deserialize, Serialize};
+use crate::storage::checksum;
+use crate::storage::manifest::{ChunkMeta, Manifest};
+use crate::storage::memtable::{dedup_last, Observation};
+use crate::storage::tombstone;
+use crate::storage::ChunkStore;
+use crate::units;
+
+pub const ARCHIVE_SUFFIX: &str = ".ndjson.zst";
+
+/// One zstd frame of an archive chunk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockMeta {
+    pub offset: u64,
+    pub len: u64,
+    pub rows: usize,
+    pub min_time: Option<String>,
+    pub max_time: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivePolicy {
+    /// Observations older than this are archived.
+    #[serde(alias = "after", deserialize_with = "units::secs")]
+    pub after_secs: u64,
+    /// Defaults to daily.
+    #[serde(alias = "check_interval", default = "default_check_interval_secs", deserialize_with = "units::secs")]
+    pub check_interval_secs: u64,
+    /// zstd compression level; defaults to the maximum.
+    #[serde(default = "default_level")]
+    pub level: i32,
+}
+
+fn default_check_interval_secs() -> u64 {
+    86_400
+}
+
+pub fn default_level() -> i32 {
+    *zstd::compression_level_range().end()
+}
+
+impl ArchivePolicy {
+    /// Enabled by `SKYPULSE_ARCHIVE_AFTER_DAYS` (for example 365);
+    /// `SKYPULSE_ARCHIVE_CHECK_SECS` and `SKYPULSE_ARCHIVE_LEVEL` override the
+    /// configured values.
+    pub fn apply_env(policy: &mut Option<Self>) -> Result<()> {
+        if let Some(after) = units::env_secs("SKYPULSE_ARCHIVE_AFTER_DAYS", "d")? {
+            let p = policy.get_or_insert_with(|| Self {
+                after_secs: 0,
+                check_interval_secs: default_check_interval_secs(),
+                level: default_level(),
+            });
+            p.after_secs = after;
+        }
+        let Some(p) = policy else { return Ok(()) };
+        if let Some(every) = units::env_secs("SKYPULSE_ARCHIVE_CHECK_SECS", "s")? {
+            p.check_interval_secs = every;
+        }
+        if let Some(level) = std::env::var("SKYPULSE_ARCHIVE_LEVEL").ok().and_then(|v| v.parse().ok()) {
+            p.level = level;
+        }
+        Ok(())
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs.max(1))
+    }
+}
+
+pub fn archive_name(station_id: &str, year: i32) -> String {
+    format!("{}-archive-{}{}", station_id, year, ARCHIVE_SUFFIX)
+}
+
+pub fn is_archive(name: &str) -> bool {
+    name.ends_with(ARCHIVE_SUFFIX)
+}
+
+/// Compress time-ordered rows into one zstd frame per calendar month, with a
+/// checksum footer after the last.
+pub fn encode(obs: &[Observation], level: i32) -> Result<(Vec<u8>, Vec<BlockMeta>)> {
+    let mut months: BTreeMap<(i32, u32), Vec<&Observation>> = BTreeMap::new();
+    for o in obs {
+        let t = o.timestamp();
+        months.entry((t.year(), t.month())).or_default().push(o);
+    }
+    // each frame carries zstd's content checksum, so damage is narrowed to a month
+    let mut compressor = zstd::bulk::Compressor::new(level)?;
+    compressor.include_checksum(true)?;
+    let mut data = Vec::new();
+    let mut blocks = Vec::with_capacity(months.len());
+    for rows in months.values() {
+        let mut raw = Vec::new();
+        for o in rows {
+            serde_json::to_writer(&mut raw, o)?;
+            raw.push(b'\n');
+        }
+        let frame = compressor.compress(&raw)?;
+        blocks.push(BlockMeta {
+            offset: data.len() as u64,
+            len: frame.len() as u64,
+            rows: rows.len(),
+            min_time: rows.iter().map(|o| o.time).min().map(|t| t.to_string()),
+            max_time: rows.iter().map(|o| o.time).max().map(|t| t.to_string()),
+        });
+        data.extend_from_slice(&frame);
+    }
+    checksum::seal_frame(&mut data, obs.len());
+    Ok((data, blocks))
+}
+
+/// Decompress every frame of an archive chunk back to NDJSON bytes.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::stream::decode_all(data)?)
+}
+
+fn cutoff(now: DateTime<Utc>, after_secs: u64) -> Option<DateTime<Utc>> {
+    i64::try_from(after_secs).ok().and_then(|s| now.checked_sub_signed(chrono::Duration::seconds(s)))
+}
+
+/// Raw (non-archive) chunks holding observations older than `cutoff`, going
+/// by their `min_time`, grouped by station. A packed chunk is listed under
+/// each station whose run in it is old enough.
+pub fn candidates(manifest: &Manifest, cutoff: DateTime<Utc>) -> BTreeMap<String, Vec<String>> {
+    let mut out: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, meta) in manifest.chunks.iter().filter(|(name, _)| !is_archive(name)) {
+        for slice in meta.slices() {
+            let old = slice
+                .min_time
+                .as_deref()
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                .is_some_and(|t| t.with_timezone(&Utc) < cutoff);
+            if old {
+                out.entry(slice.station_id).or_default().push(name.clone());
+            }
+        }
+    }
+    out
+}
+
+/// Archive every observation older than the policy's age; returns the
+/// archive files written.
+pub async fn compact(store: &ChunkStore, policy: &ArchivePolicy, now: DateTime<Utc>) -> Result<Vec<String>> {
+    let Some(cutoff) = cutoff(now, policy.after_secs) else { return Ok(Vec::new()) };
+    let _maintenance = store.maintenance().await;
+    let manifest = store.manifest().await;
+    let mut written = Vec::new();
+    let written_at = |name: &str| manifest.chunks.get(name).map_or(0, |m| m.written);
+    for (station_id, mut chunks) in candidates(&manifest, cutoff) {
+        // oldest write first, so duplicates stay in write order through the stable sort
+        chunks.sort_by_key(|name| written_at(name));
+        let newest_write = chunks.iter().map(|name| written_at(name)).max().unwrap_or(0);
+        let mut by_year: BTreeMap<i32, Vec<Observation>> = BTreeMap::new();
+        // source chunk -> rows that stay in it, including other stations' rows
+        // of a packed chunk
+        let mut sources = Vec::new();
+        for name in chunks {
+            let mut keep = Vec::new();
+            let mut rows = store.read_chunk_file(&name).await?;
+            tombstone::mask(&mut rows, written_at(&name), &manifest.tombstones);
+            for o in rows {
+                let t = o.timestamp();
+                if t < cutoff && o.station_id == station_id {
+                    by_year.entry(t.year()).or_default().push(o);
+                } else {
+                    keep.push(o);
+                }
+            }
+            sources.push((name, keep));
+        }
+        for (year, new_rows) in by_year {
+            let name = archive_name(&station_id, year);
+            // what is archived already was written before the rows joining it
+            let mut rows = if manifest.chunks.contains_key(&name) { store.read_chunk_file(&name).await? } else { Vec::new() };
+            tombstone::mask(&mut rows, written_at(&name), &manifest.tombstones);
+            rows.extend(new_rows);
+            rows.sort_by_key(|o| o.time);
+            dedup_last(&mut rows);
+            let (data, blocks) = encode(&rows, policy.level)?;
+            let mut meta = ChunkMeta::from_contents(&station_id, &rows, &data);
+            meta.blocks = blocks;
+            meta.written = newest_write.max(written_at(&name));
+            store.write_file(&name, &data, meta).await?;
+            written.push(name);
+        }
+        // only touch the sources once every year they feed has been written
+        let mut emptied = Vec::new();
+        for (name, keep) in sources {
+            if keep.is_empty() {
+                emptied.push(name);
+            } else {
+                store.rewrite_chunk(&name, &station_id, &keep).await?;
+            }
+        }
+        store.delete_chunks(&emptied).await?;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obs(time: &str, temp: f64) -> Observation {
+        let mut o = Observation::empty("A", time.parse().unwrap());
+        o.set_field("temp", temp);
+        o
+    }
+
+    #[test]
+    fn encodes_one_frame_per_month() {
+        let rows = vec![obs("2024-01-03T00:00:00Z", 1.0), obs("2024-01-20T00:00:00Z", 2.0), obs("2024-03-01T00:00:00Z", 3.0)];
+        let (data, blocks) = encode(&rows, 19).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].rows, 2);
+        assert_eq!(blocks[1].offset, blocks[0].len);
+        assert_eq!(blocks[1].min_time.as_deref(), Some("2024-03-01T00:00:00Z"));
+        let ndjson = decompress(&data).unwrap();
+        let back = crate::storage::manifest::parse_rows(&ndjson);
+        assert_eq!(back.len(), 3);
+        assert_eq!(back[2].number("temp"), Some(3.0));
+        // each frame decodes on its own
+        let second = &data[blocks[1].offset as usize..(blocks[1].offset + blocks[1].len) as usize];
+        assert_eq!(crate::storage::manifest::parse_rows(&decompress(second).unwrap()).len(), 1);
+    }
+
+    #[test]
+    fn selects_raw_chunks_with_old_rows() {
+        let meta = |min: &str| ChunkMeta {
+            station_id: "A".into(),
+            rows: 1,
+            bytes: 1,
+            crc32: 0,
+            min_time: Some(min.into()),
+            max_time: None,
+            blocks: Vec::new(),
+            stations: Vec::new(),
+            written: 0,
+        };
+        let mut m = Manifest::default();
+        m.chunks.insert("A-old.ndjson".into(), meta("2024-01-01T00:00:00Z"));
+        m.chunks.insert("A-new.ndjson".into(), meta("2025-12-01T00:00:00Z"));
+        m.chunks.insert(archive_name("A", 2023), meta("2023-12-31T00:00:00Z"));
+        let now = DateTime::parse_from_rfc3339("2026-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let c = candidates(&m, cutoff(now, 365 * 86_400).unwrap());
+        assert_eq!(c["A"], vec!["A-old.ndjson".to_string()]);
+    }
+}
@@ -0,0 +1,351 @@
+// Chunk file checksums. Every chunk file ends in a footer giving the length
+// and CRC32 of the bytes before it and the rows they hold, so a file that was
+// damaged or cut short is caught by reading it alone; the manifest is no help
+// there, since a scan rebuilds it from whatever is on disk. NDJSON chunks end
+// in a JSON line `{"footer":{...}}`, which row readers skip, and archives and
+// block chunks in a zstd skippable frame, which zstd decoders skip. Chunks
+// written before footers have none; their lines are checked instead, so a
+// torn last row or a line of garbage is reported, not quietly left out.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::storage::{archive, columnar};
+use crate::storage::manifest::{parse_chunk, ChunkMeta};
+use crate::storage::memtable::Observation;
+
+/// First bytes of the footer line of an NDJSON chunk.
+const NDJSON_FOOTER: &[u8] = b"{\"footer\":";
+/// Magic number of the skippable frame closing an archive (zstd reserves
+/// 0x184D2A50 to 0x184D2A5F for them).
+const FRAME_MAGIC: u32 = 0x184D_2A5C;
+/// Magic number starting every zstd frame, as stored.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+/// Skippable frame header plus `bytes`, `rows` and `crc32`.
+const FRAME_LEN: usize = 8 + 8 + 8 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Footer {
+    /// Length of the file before the footer.
+    pub bytes: u64,
+    pub rows: u64,
+    pub crc32: u32,
+}
+
+#[derive(Deserialize)]
+struct FooterLine {
+    footer: Footer,
+}
+
+/// What is wrong with a damaged chunk file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Damage {
+    /// Shorter than its footer or manifest entry says.
+    Truncated { expected_bytes: u64, found_bytes: u64 },
+    /// The contents do not match the recorded checksum.
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// A chunk without a footer ending in an incomplete row at `offset`.
+    TornRow { offset: u64 },
+    /// Byte offsets of lines that are not rows, in a chunk without a footer.
+    BadRows { offsets: Vec<u64> },
+    /// An archive without a footer that does not decompress, or a block
+    /// chunk that does not decode.
+    Undecodable { error: String },
+    /// Intact by its checksum, but holding other than the rows its footer records.
+    RowCount { expected_rows: u64, found_rows: u64 },
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Damage::Truncated { expected_bytes, found_bytes } => write!(f, "truncated to {} of {} bytes", found_bytes, expected_bytes),
+            Damage::ChecksumMismatch { expected, found } => write!(f, "checksum {:08x} does not match the recorded {:08x}", found, expected),
+            Damage::TornRow { offset } => write!(f, "ends in an incomplete row at byte {}", offset),
+            Damage::BadRows { offsets } => write!(f, "{} unreadable lines, the first at byte {}", offsets.len(), offsets[0]),
+            Damage::Undecodable { error } => write!(f, "cannot be decoded: {}", error),
+            Damage::RowCount { expected_rows, found_rows } => write!(f, "holds {} rows where its footer records {}", found_rows, expected_rows),
+        }
+    }
+}
+
+/// A damaged chunk file found by a check of the chunk directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct DamagedChunk {
+    pub file: String,
+    #[serde(flatten)]
+    pub damage: Damage,
+}
+
+fn footer_of(data: &[u8], rows: usize) -> Footer {
+    Footer { bytes: data.len() as u64, rows: rows as u64, crc32: crc32fast::hash(data) }
+}
+
+/// Append the footer line to an NDJSON chunk holding `rows` rows.
+pub fn seal_ndjson(data: &mut Vec<u8>, rows: usize) {
+    let footer = footer_of(data, rows);
+    let line = format!("{{\"footer\":{{\"bytes\":{},\"rows\":{},\"crc32\":{}}}}}\n", footer.bytes, footer.rows, footer.crc32);
+    data.extend_from_slice(line.as_bytes());
+}
+
+/// Append the footer frame to an archive or block chunk holding `rows` rows.
+pub fn seal_frame(data: &mut Vec<u8>, rows: usize) {
+    let footer = footer_of(data, rows);
+    data.extend_from_slice(&FRAME_MAGIC.to_le_bytes());
+    data.extend_from_slice(&((FRAME_LEN - 8) as u32).to_le_bytes());
+    data.extend_from_slice(&footer.bytes.to_le_bytes());
+    data.extend_from_slice(&footer.rows.to_le_bytes());
+    data.extend_from_slice(&footer.crc32.to_le_bytes());
+}
+
+/// The footer of chunk `name` and where it starts, if the file has one.
+pub fn footer(name: &str, data: &[u8]) -> Option<(Footer, usize)> {
+    if archive::is_archive(name) || columnar::is_block(name) {
+        let start = data.len().checked_sub(FRAME_LEN)?;
+        let frame = &data[start..];
+        let word = |at: usize, n: usize| frame[at..at + n].iter().rev().fold(0u64, |v, b| v << 8 | *b as u64);
+        if word(0, 4) != FRAME_MAGIC as u64 || word(4, 4) != (FRAME_LEN - 8) as u64 {
+            return None;
+        }
+        return Some((Footer { bytes: word(8, 8), rows: word(16, 8), crc32: word(24, 4) as u32 }, start));
+    }
+    let body = data.strip_suffix(b"\n")?;
+    let start = body.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    let line = &body[start..];
+    if !line.starts_with(NDJSON_FOOTER) {
+        return None;
+    }
+    serde_json::from_slice::<FooterLine>(line).ok().map(|l| (l.footer, start))
+}
+
+/// Chunk `name` without its footer.
+pub fn body<'a>(name: &str, data: &'a [u8]) -> &'a [u8] {
+    footer(name, data).map_or(data, |(_, start)| &data[..start])
+}
+
+/// Check chunk `name` against its footer. A chunk without one is checked
+/// against `recorded`, its manifest entry, when given, and otherwise line by
+/// line; `recorded` also catches a file cut short just before its footer.
+/// Only pass it when no writer can be rewriting the file meanwhile.
+pub fn check(name: &str, data: &[u8], recorded: Option<&ChunkMeta>) -> Result<(), Damage> {
+    match check_recorded(name, data, recorded) {
+        Some(checked) => checked,
+        None => rows_of_contents(name, data).map(drop),
+    }
+}
+
+/// The rows of chunk `name`, checked as [`check`] does and against the row
+/// count in its footer. A chunk without a footer is parsed once, for both.
+pub fn checked_rows(name: &str, data: &[u8], recorded: Option<&ChunkMeta>) -> Result<Vec<Observation>, Damage> {
+    match check_recorded(name, data, recorded) {
+        Some(checked) => checked?,
+        None => return rows_of_contents(name, data),
+    }
+    let rows = match columnar::is_block(name) {
+        true => columnar::decode(body(name, data)).map_err(|e| Damage::Undecodable { error: e.to_string() })?,
+        false => parse_chunk(name, data),
+    };
+    match footer(name, data) {
+        Some((footer, _)) if footer.rows != rows.len() as u64 => Err(Damage::RowCount { expected_rows: footer.rows, found_rows: rows.len() as u64 }),
+        _ => Ok(rows),
+    }
+}
+
+/// The check against the footer, or else `recorded`; `None` when the chunk
+/// has neither.
+fn check_recorded(name: &str, data: &[u8], recorded: Option<&ChunkMeta>) -> Option<Result<(), Damage>> {
+    let found_bytes = data.len() as u64;
+    if let Some(meta) = recorded.filter(|m| found_bytes < m.bytes) {
+        return Some(Err(Damage::Truncated { expected_bytes: meta.bytes, found_bytes }));
+    }
+    if let Some((footer, start)) = footer(name, data) {
+        let body = &data[..start];
+        if footer.bytes > body.len() as u64 {
+            return Some(Err(Damage::Truncated { expected_bytes: footer.bytes, found_bytes: body.len() as u64 }));
+        }
+        let found = crc32fast::hash(body);
+        if found != footer.crc32 || footer.bytes != body.len() as u64 {
+            return Some(Err(Damage::ChecksumMismatch { expected: footer.crc32, found }));
+        }
+        return Some(Ok(()));
+    }
+    let meta = recorded?;
+    let found = crc32fast::hash(data);
+    Some(match found == meta.crc32 && found_bytes == meta.bytes {
+        true => Ok(()),
+        false => Err(Damage::ChecksumMismatch { expected: meta.crc32, found }),
+    })
+}
+
+/// The rows of a chunk without a footer, which must all decode.
+fn rows_of_contents(name: &str, data: &[u8]) -> Result<Vec<Observation>, Damage> {
+    if archive::is_archive(name) {
+        let raw = archive::decompress(data).map_err(|e| Damage::Undecodable { error: e.to_string() })?;
+        return rows_of_lines(&raw);
+    }
+    if columnar::is_block(name) {
+        return columnar::decode(data).map_err(|e| Damage::Undecodable { error: e.to_string() });
+    }
+    rows_of_lines(data)
+}
+
+/// What could be read of a damaged chunk.
+#[derive(Debug, Clone, Default)]
+pub struct Salvage {
+    pub rows: Vec<Observation>,
+    /// Rows the footer says the chunk held, when it still has one.
+    pub expected_rows: Option<u64>,
+    /// Byte ranges of the file, as offset and length, that could not be read.
+    pub unreadable: Vec<(u64, u64)>,
+}
+
+/// Every row of chunk `name` that can still be read: each line of an NDJSON
+/// chunk that parses, the rows of each archive frame that decompresses and
+/// passes its zstd checksum, and the rows of a block chunk with each column
+/// read as far as it decodes.
+pub fn salvage(name: &str, data: &[u8]) -> Salvage {
+    let mut out = Salvage::default();
+    let body = match footer(name, data) {
+        Some((footer, start)) => {
+            out.expected_rows = Some(footer.rows);
+            &data[..start]
+        }
+        None => data,
+    };
+    if columnar::is_block(name) {
+        (out.rows, out.unreadable) = columnar::salvage(body);
+        return out;
+    }
+    if !archive::is_archive(name) {
+        salvage_lines(body, &mut out);
+        return out;
+    }
+    let mut at = 0;
+    while at < body.len() {
+        let frame = zstd::zstd_safe::find_frame_compressed_size(&body[at..]).ok().filter(|n| *n > 0 && at + n <= body.len());
+        match frame.map(|n| (n, zstd::stream::decode_all(&body[at..at + n]))) {
+            Some((n, Ok(raw))) => {
+                // offsets within the decompressed rows mean nothing in the file: report the frame
+                let mut lines = Salvage::default();
+                salvage_lines(&raw, &mut lines);
+                out.rows.extend(lines.rows);
+                if !lines.unreadable.is_empty() {
+                    out.unreadable.push((at as u64, n as u64));
+                }
+                at += n;
+            }
+            _ => {
+                // skip to the next frame header, if there is one
+                let next = (at + 1..body.len().saturating_sub(3)).find(|&i| body[i..i + 4] == ZSTD_MAGIC).unwrap_or(body.len());
+                out.unreadable.push((at as u64, (next - at) as u64));
+                at = next;
+            }
+        }
+    }
+    out
+}
+
+fn salvage_lines(data: &[u8], out: &mut Salvage) {
+    let mut at = 0;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        match serde_json::from_slice::<Observation>(text) {
+            Ok(o) => out.rows.push(o),
+            Err(_) if text.is_empty() => {}
+            Err(_) => out.unreadable.push((at as u64, line.len() as u64)),
+        }
+        at += line.len();
+    }
+}
+
+/// Rows of a chunk without a footer: every non-empty line must be one, and
+/// the last must be complete.
+fn rows_of_lines(data: &[u8]) -> Result<Vec<Observation>, Damage> {
+    let mut rows = Vec::new();
+    let mut offsets = Vec::new();
+    let mut at = 0;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        if !text.is_empty() {
+            match serde_json::from_slice::<Observation>(text) {
+                Ok(o) => rows.push(o),
+                Err(_) if at + line.len() == data.len() && !line.ends_with(b"\n") => return Err(Damage::TornRow { offset: at as u64 }),
+                Err(_) => offsets.push(at as u64),
+            }
+        }
+        at += line.len();
+    }
+    match offsets.is_empty() {
+        true => Ok(rows),
+        false => Err(Damage::BadRows { offsets }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::manifest::encode_ndjson;
+
+    #[test]
+    fn detects_damaged_chunks() {
+        let rows: Vec<Observation> = ["2024-01-01T00:00:00Z", "2024-02-01T00:00:00Z", "2024-02-02T00:00:00Z"]
+            .iter()
+            .map(|t| {
+                let mut o = Observation::empty("A", t.parse().unwrap());
+                o.set_field("temp", 20.5);
+                o
+            })
+            .collect();
+        let (data, meta) = encode_ndjson("A", &rows).unwrap();
+        let name = "A-flush-1.ndjson";
+        assert_eq!(footer(name, &data).unwrap().0.rows, 3);
+        assert_eq!(check(name, &data, Some(&meta)), Ok(()));
+        assert_eq!(parse_chunk(name, &data).len(), 3);
+
+        let mut flipped = data.clone();
+        flipped[30] ^= 0x01;
+        assert!(matches!(check(name, &flipped, None), Err(Damage::ChecksumMismatch { .. })));
+        // cut mid-row, and cut exactly before the footer, which only the manifest shows
+        let first_row = data.iter().position(|b| *b == b'\n').unwrap() + 1;
+        assert_eq!(check(name, &data[..first_row + 10], None), Err(Damage::TornRow { offset: first_row as u64 }));
+        let before_footer = footer(name, &data).unwrap().1;
+        assert_eq!(check(name, &data[..before_footer], None), Ok(()));
+        assert!(matches!(check(name, &data[..before_footer], Some(&meta)), Err(Damage::Truncated { .. })));
+        let garbage = [&data[..first_row], b"\0\0\0\n", &data[first_row..before_footer]].concat();
+        assert_eq!(check(name, &garbage, None), Err(Damage::BadRows { offsets: vec![first_row as u64] }));
+        // a footer vouching for the bytes but not the rows they hold
+        let mut miscounted = data[..before_footer].to_vec();
+        seal_ndjson(&mut miscounted, 4);
+        assert_eq!(check(name, &miscounted, None), Ok(()));
+        assert_eq!(checked_rows(name, &miscounted, None), Err(Damage::RowCount { expected_rows: 4, found_rows: 3 }));
+        assert_eq!(checked_rows(name, &data[..before_footer], None).unwrap(), rows);
+        let saved = salvage(name, &garbage);
+        assert_eq!((saved.rows.len(), saved.expected_rows, saved.unreadable), (3, None, vec![(first_row as u64, 4)]));
+        assert_eq!(salvage(name, &data[..first_row + 10]).rows.len(), 1);
+
+        let (archived, blocks) = archive::encode(&rows, 3).unwrap();
+        let name = archive::archive_name("A", 2024);
+        assert_eq!(footer(&name, &archived).unwrap().0.rows, 3);
+        assert_eq!(check(&name, &archived, None), Ok(()));
+        assert_eq!(parse_chunk(&name, &archived).len(), 3);
+        let mut flipped = archived.clone();
+        flipped[20] ^= 0x01;
+        assert!(matches!(check(&name, &flipped, None), Err(Damage::ChecksumMismatch { .. })));
+        assert!(matches!(check(&name, &archived[..archived.len() - 40], None), Err(Damage::Undecodable { .. })));
+        // damage in February's frame costs February's rows only
+        let mut february = archived.clone();
+        february[(blocks[1].offset + blocks[1].len / 2) as usize] ^= 0xff;
+        let saved = salvage(&name, &february);
+        assert_eq!((saved.rows.len(), saved.expected_rows), (1, Some(3)));
+        assert_eq!(saved.unreadable, vec![(blocks[1].offset, blocks[1].len)]);
+    }
+
+    #[test]
+    fn checks_and_reads_footerless_blocks_in_one_decode() {
+        let rows = vec![Observation::empty("A", "2024-01-01T00:00:00Z".parse().unwrap())];
+        let (data, _) = columnar::encode("A", &rows).unwrap();
+        let name = "A-flush-1.block";
+        let legacy = body(name, &data);
+        assert_eq!(checked_rows(name, legacy, None), Ok(rows));
+        assert!(matches!(checked_rows(name, &legacy[..legacy.len() - 2], None), Err(Damage::Undecodable { .. })));
+    }
+}
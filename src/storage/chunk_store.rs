@@ -1,71 +1,794 @@
-use std::path::PathBuf;
-use anyhow::Result;
-use crate::storage::memtable::Observation;
-use tokio::io::AsyncWriteExt;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use crate::storage::backend::{ChunkBackend, LocalBackend};
+use crate::storage::integrity::{self, IntegrityMode};
+use crate::storage::archive;
+use crate::storage::checksum::{self, Damage, DamagedChunk, Salvage};
+use crate::storage::columnar::{self, ChunkFormat, BLOCK_SUFFIX};
+use crate::storage::manifest::{encode_chunk, ChunkMeta, Manifest, StationSlice, MANIFEST_FILE, PACKED_PREFIX};
+use crate::storage::memtable::{dedup_last, Observation, Timestamp};
+use crate::storage::tombstone::{self, Tombstone};
+use tokio::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
 
-pub struct ChunkStore {
+/// File under the data directory recording where the chunks were moved to
+/// by [`ChunkStore::relocate`]; it takes precedence over the configured path.
+pub const LOCATION_FILE: &str = "CHUNK_DIR";
+
+/// The chunk directory to open: a recorded relocation, else `configured`,
+/// else `data_dir/chunks`.
+pub fn locate(data_dir: &Path, configured: Option<&Path>) -> Result<PathBuf> {
+    let default = configured.map_or_else(|| data_dir.join("chunks"), Path::to_path_buf);
+    match std::fs::read_to_string(data_dir.join(LOCATION_FILE)) {
+        Ok(text) => {
+            let moved = PathBuf::from(text.trim());
+            if moved != default {
+                tracing::warn!(
+                    "chunks were relocated to {}; using it instead of {} (update chunk_dir in the config)",
+                    moved.display(),
+                    default.display()
+                );
+            }
+            Ok(moved)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(default),
+        Err(e) => Err(e).with_context(|| format!("reading {}", data_dir.join(LOCATION_FILE).display())),
+    }
+}
+
+/// Outcome of moving the chunk directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct Relocation {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub chunks: usize,
+    pub bytes: u64,
+    /// Chunks written or changed while the bulk copy ran, copied again under the lock.
+    pub recopied: usize,
+}
+
+/// A consistent copy of the chunk directory made by [`ChunkStore::snapshot`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotCopy {
+    pub chunks: usize,
+    pub bytes: u64,
+    /// Chunks written or changed while the bulk copy ran, copied again under the lock.
+    pub recopied: usize,
+}
+
+/// The directory holding the manifest and the backend holding the chunk
+/// files: the same directory unless chunks are kept in object storage.
+struct Location {
     dir: PathBuf,
+    backend: Arc<dyn ChunkBackend>,
+}
+
+impl Location {
+    fn local(dir: PathBuf) -> Self {
+        Self { backend: Arc::new(LocalBackend::new(dir.clone())), dir }
+    }
+}
+
+pub struct ChunkStore {
+    /// Held for reading around every file access so a relocation can swap it.
+    location: RwLock<Location>,
+    manifest: Mutex<Manifest>,
+    read_only: bool,
+    format: ChunkFormat,
+    /// Held by the jobs rewriting existing chunks (archiving, retention,
+    /// tiering, purges and compaction) so they never work on the same
+    /// chunks at once.
+    maintenance: Mutex<()>,
 }
 
 impl ChunkStore {
-    /// Create a new ChunkStore rooted at `data_dir/chunks`.
-    pub fn new(data_dir: PathBuf) -> Result<Self> {
-        let dir = data_dir.join("chunks");
+    /// Create a new ChunkStore rooted at `dir`.
+    /// The manifest is loaded from disk and checked against the chunk files;
+    /// `mode` decides what happens when they disagree.
+    pub fn new(dir: PathBuf, mode: IntegrityMode) -> Result<Self> {
         std::fs::create_dir_all(&dir)?;
-        Ok(Self { dir })
+        let (manifest, _) = integrity::open_manifest(&dir, mode, true)?;
+        Ok(Self { location: RwLock::new(Location::local(dir)), manifest: Mutex::new(manifest), read_only: false, format: ChunkFormat::default(), maintenance: Mutex::default() })
+    }
+
+    /// Open an existing chunk directory (for example a backup) without creating
+    /// or modifying anything. Repairs are applied in memory only.
+    pub fn open_read_only(dir: PathBuf, mode: IntegrityMode) -> Result<Self> {
+        if !dir.is_dir() {
+            anyhow::bail!("chunk directory {} does not exist", dir.display());
+        }
+        let (manifest, _) = integrity::open_manifest(&dir, mode, false)?;
+        Ok(Self { location: RwLock::new(Location::local(dir)), manifest: Mutex::new(manifest), read_only: true, format: ChunkFormat::default(), maintenance: Mutex::default() })
+    }
+
+    /// Take up the manifest the process owning a read-only store's directory
+    /// has saved since, with the chunks and tombstones it lists; repairs made
+    /// in memory at open are dropped. Returns whether anything changed.
+    pub async fn reload(&self) -> Result<bool> {
+        if !self.read_only {
+            anyhow::bail!("only a read-only chunk store follows the manifest on disk");
+        }
+        let dir = self.location.read().await.dir.clone();
+        let loaded = tokio::task::spawn_blocking(move || Manifest::load(&dir)).await??.unwrap_or_default();
+        let mut manifest = self.manifest.lock().await;
+        if *manifest == loaded {
+            return Ok(false);
+        }
+        *manifest = loaded;
+        Ok(true)
+    }
+
+    /// Open a store whose chunk files are kept in `backend`, with the
+    /// manifest in `dir`. The startup check compares the manifest with the
+    /// backend's listing rather than every file's checksum; only files the
+    /// manifest lacks are read.
+    pub async fn open_backend(dir: PathBuf, backend: Arc<dyn ChunkBackend>, mode: IntegrityMode, read_only: bool) -> Result<Self> {
+        if read_only {
+            if !dir.is_dir() {
+                anyhow::bail!("chunk directory {} does not exist", dir.display());
+            }
+        } else {
+            tokio::fs::create_dir_all(&dir).await?;
+        }
+        // an unreadable manifest fails the start unless a repair is to
+        // rebuild it from the objects
+        let stored = {
+            let load_dir = dir.clone();
+            match tokio::task::spawn_blocking(move || Manifest::load(&load_dir)).await? {
+                Ok(stored) => stored.unwrap_or_default(),
+                Err(e) if mode == IntegrityMode::Repair => {
+                    tracing::warn!("manifest in {} unreadable, rebuilding it from the objects: {:#}", dir.display(), e);
+                    Manifest::default()
+                }
+                Err(e) => return Err(e.context(format!("reading the manifest in {}", dir.display()))),
+            }
+        };
+        let mut listed = Manifest::default();
+        for name in backend.list().await.with_context(|| format!("listing chunks in {}", backend.describe()))? {
+            let meta = match stored.chunks.get(&name) {
+                Some(meta) => meta.clone(),
+                None => Manifest::describe(&name, &backend.read(&name).await?),
+            };
+            listed.chunks.insert(name, meta);
+        }
+        let check_dir = dir.clone();
+        let (manifest, _) =
+            tokio::task::spawn_blocking(move || integrity::check(&check_dir, listed, mode, !read_only)).await??;
+        tracing::info!("chunks kept in {}", backend.describe());
+        Ok(Self { location: RwLock::new(Location { dir, backend }), manifest: Mutex::new(manifest), read_only, format: ChunkFormat::default(), maintenance: Mutex::default() })
+    }
+
+    /// Write chunks in `format` from now on; NDJSON unless set.
+    pub fn with_format(mut self, format: ChunkFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Wait for any other maintenance job to finish, and hold off the next
+    /// one until the guard is dropped.
+    pub async fn maintenance(&self) -> MutexGuard<'_, ()> {
+        self.maintenance.lock().await
+    }
+
+    /// The suffix a chunk of `obs`, all rows of `station_id`, is written with.
+    pub fn suffix_for(&self, station_id: &str, obs: &[Observation]) -> &'static str {
+        match self.format == ChunkFormat::Block && columnar::fits(station_id, obs) {
+            true => BLOCK_SUFFIX,
+            false => ".ndjson",
+        }
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!("chunk store is read-only");
+        }
+        Ok(())
+    }
+
+    /// The directory currently holding the manifest and, unless they are
+    /// kept in object storage, the chunk files.
+    pub async fn dir(&self) -> PathBuf {
+        self.location.read().await.dir.clone()
+    }
+
+    /// Where the chunk files are, for logs and errors.
+    pub async fn describe(&self) -> String {
+        self.location.read().await.backend.describe()
+    }
+
+    /// A manifest rebuilt from the chunk files' actual contents, reading every file.
+    pub async fn scan(&self) -> Result<Manifest> {
+        let loc = self.location.read().await;
+        scan_backend(loc.backend.as_ref()).await
+    }
+
+    /// Snapshot of the current chunk manifest.
+    pub async fn manifest(&self) -> Manifest {
+        self.manifest.lock().await.clone()
     }
 
     /// Write a chunk file for `station_id` with `chunk_name` (for example a date)
-    /// Observations are written as newline-delimited JSON (JSONL).
+    /// Observations are written as newline-delimited JSON (JSONL), or as a
+    /// block chunk when the store writes blocks and they fit one, sorted by
+    /// time so rows that arrived late and out of order land in place. Of rows
+    /// with the same time (in write order) only the last is kept. A chunk of
+    /// the same name written before in the other format is replaced too.
     pub async fn write_chunk(&self, station_id: &str, chunk_name: &str, obs: &[Observation]) -> Result<PathBuf> {
-        let fname = format!("{}-{}.ndjson", station_id, chunk_name);
-        let path = self.dir.join(fname);
-        let mut file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&path)
-            .await?;
+        self.ensure_writable()?;
+        let mut obs = obs.to_vec();
+        obs.sort_by_key(|o| o.time);
+        dedup_last(&mut obs);
+        let suffix = self.suffix_for(station_id, &obs);
+        let other = if suffix == BLOCK_SUFFIX { ".ndjson" } else { BLOCK_SUFFIX };
+        let fname = format!("{}-{}{}", station_id, chunk_name, suffix);
+        let (data, meta) = encode_chunk(&fname, station_id, &obs)?;
+        self.replace_file(&fname, &data, meta, Some(&format!("{}-{}{}", station_id, chunk_name, other))).await?;
+        Ok(self.dir().await.join(fname))
+    }
+
+    /// Write the rows of several low-volume stations into one shared chunk,
+    /// indexed by station in the manifest.
+    pub async fn write_packed(&self, chunk_name: &str, obs: &[Observation]) -> Result<PathBuf> {
+        self.ensure_writable()?;
+        let mut obs = obs.to_vec();
+        obs.sort_by(|a, b| a.station_id.cmp(&b.station_id).then_with(|| a.time.cmp(&b.time)));
+        dedup_last(&mut obs);
+        let fname = format!("{}{}.ndjson", PACKED_PREFIX, chunk_name);
+        self.rewrite_chunk(&fname, "", &obs).await?;
+        Ok(self.dir().await.join(fname))
+    }
+
+    /// Replace the contents of the raw chunk `fname`, in the format its name
+    /// gives. Rows of more than one station (grouped by station) make a
+    /// packed NDJSON chunk; `station_id` labels the chunk when `obs` is empty.
+    pub async fn rewrite_chunk(&self, fname: &str, station_id: &str, obs: &[Observation]) -> Result<()> {
+        let (data, meta) = encode_chunk(fname, station_id, obs)?;
+        self.write_file(fname, &data, meta).await
+    }
+
+    /// Write an already encoded chunk file and record `meta` for it. Unless
+    /// `meta.written` is set, a rewritten chunk keeps its write time and a new
+    /// one is stamped with the current time.
+    pub async fn write_file(&self, fname: &str, data: &[u8], meta: ChunkMeta) -> Result<()> {
+        self.replace_file(fname, data, meta, None).await
+    }
+
+    /// [`Self::write_file`], also removing the chunk `stale` (the same rows
+    /// in another format) in the same manifest update.
+    async fn replace_file(&self, fname: &str, data: &[u8], mut meta: ChunkMeta, stale: Option<&str>) -> Result<()> {
+        self.ensure_writable()?;
+        let loc = self.location.read().await;
+        loc.backend.write(fname, data).await?;
+
+        let mut manifest = self.manifest.lock().await;
+        let stale = stale.and_then(|name| manifest.chunks.remove_entry(name));
+        if let Some((name, _)) = &stale {
+            loc.backend.delete(name).await?;
+        }
+        if meta.written == 0 {
+            meta.written = match manifest.chunks.get(fname).or(stale.as_ref().map(|(_, old)| old)) {
+                Some(old) => old.written,
+                None => chrono::Utc::now().timestamp_millis(),
+            };
+        }
+        manifest.chunks.insert(fname.to_string(), meta);
+        save_manifest(&loc.dir, &manifest).await
+    }
+
+    /// Remove chunk files by name and drop them from the manifest.
+    /// Files that are already gone are only removed from the manifest.
+    pub async fn delete_chunks(&self, names: &[String]) -> Result<()> {
+        self.ensure_writable()?;
+        let loc = self.location.read().await;
+        let mut manifest = self.manifest.lock().await;
+        for name in names {
+            loc.backend.delete(name).await?;
+            manifest.chunks.remove(name);
+        }
+        save_manifest(&loc.dir, &manifest).await
+    }
+
+    /// Re-scan the chunk files and repair the manifest from them, keeping
+    /// recorded metadata for files that still match.
+    pub async fn rebuild_manifest(&self) -> Result<integrity::IntegrityReport> {
+        self.ensure_writable()?;
+        let loc = self.location.read().await;
+        let dir = loc.dir.clone();
+        let mut manifest = self.manifest.lock().await;
+        let (rebuilt, report) = match loc.backend.local_dir() {
+            Some(_) => tokio::task::spawn_blocking(move || integrity::open_manifest(&dir, IntegrityMode::Repair, true)).await??,
+            None => {
+                let scanned = scan_backend(loc.backend.as_ref()).await?;
+                tokio::task::spawn_blocking(move || integrity::check(&dir, scanned, IntegrityMode::Repair, true)).await??
+            }
+        };
+        *manifest = rebuilt;
+        Ok(report)
+    }
+
+    /// Move every chunk file and the manifest to `to`, verifying each copy
+    /// against its recorded checksum, then switch over and remove the old
+    /// files. Reads and writes continue during the bulk copy; only chunks
+    /// written meanwhile are copied again while writers are held off. The new
+    /// location is recorded under `data_dir` before anything is removed. `to`
+    /// must be empty or not exist yet; a failed move leaves it as it was.
+    pub async fn relocate(&self, data_dir: &Path, to: PathBuf) -> Result<Relocation> {
+        self.ensure_writable()?;
+        let (from, backend) = {
+            let loc = self.location.read().await;
+            if loc.backend.local_dir().is_none() {
+                anyhow::bail!("chunks are kept in {}; only a local chunk directory can be relocated", loc.backend.describe());
+            }
+            (loc.dir.clone(), loc.backend.clone())
+        };
+        if to == from {
+            anyhow::bail!("chunks are already in {}", to.display());
+        }
+        let existed = tokio::fs::try_exists(&to).await?;
+        tokio::fs::create_dir_all(&to).await.with_context(|| format!("creating {}", to.display()))?;
+        if tokio::fs::read_dir(&to).await?.next_entry().await?.is_some() {
+            anyhow::bail!("{} is not empty", to.display());
+        }
+        let copied = self.manifest().await;
+        let result = async {
+            for (name, meta) in &copied.chunks {
+                copy_verified(backend.as_ref(), &to, name, meta).await?;
+            }
+            let loc = self.location.write().await;
+            let manifest = self.manifest.lock().await;
+            let mut recopied = 0;
+            for (name, meta) in &manifest.chunks {
+                if copied.chunks.get(name) != Some(meta) {
+                    copy_verified(backend.as_ref(), &to, name, meta).await?;
+                    recopied += 1;
+                }
+            }
+            for name in copied.chunks.keys().filter(|n| !manifest.chunks.contains_key(*n)) {
+                remove_if_exists(&to.join(name)).await?;
+            }
+            save_manifest(&to, &manifest).await?;
+            let tmp = data_dir.join(format!("{}.tmp", LOCATION_FILE));
+            tokio::fs::write(&tmp, to.to_string_lossy().as_bytes()).await?;
+            tokio::fs::rename(&tmp, data_dir.join(LOCATION_FILE)).await?;
+            Ok::<_, anyhow::Error>((loc, manifest, recopied))
+        }
+        .await;
+        let (mut loc, manifest, recopied) = match result {
+            Ok(r) => r,
+            Err(e) => {
+                // leave the old directory in charge and drop the partial copy,
+                // keeping a target that was there before (a mount point, say)
+                if let Err(cleanup) = remove_copy(&to, existed).await {
+                    tracing::warn!("could not remove partial copy in {}: {}", to.display(), cleanup);
+                }
+                return Err(e);
+            }
+        };
+        *loc = Location::local(to.clone());
+        let report = Relocation {
+            from: from.clone(),
+            to,
+            chunks: manifest.chunks.len(),
+            bytes: manifest.chunks.values().map(|c| c.bytes).sum(),
+            recopied,
+        };
+        let names: Vec<String> = manifest.chunks.keys().cloned().collect();
+        drop(manifest);
+        drop(loc);
+
+        for name in names.iter().map(String::as_str).chain([MANIFEST_FILE]) {
+            if let Err(e) = remove_if_exists(&from.join(name)).await {
+                tracing::warn!("could not remove {} after relocation: {}", from.join(name).display(), e);
+            }
+        }
+        if tokio::fs::remove_dir(&from).await.is_err() {
+            tracing::info!("left {} in place: it still holds files not tracked by the manifest", from.display());
+        }
+        Ok(report)
+    }
 
-        for o in obs {
-            let line = serde_json::to_vec(o)?;
-            file.write_all(&line).await?;
-            file.write_all(b"\n").await?;
+    /// Run `op` with writers held off if chunk `name` still has `meta`, and
+    /// return whether it ran. Lets a chunk move between backends without
+    /// losing a rewrite that raced the move.
+    pub async fn unchanged_while(&self, name: &str, meta: &ChunkMeta, op: impl std::future::Future<Output = Result<()>>) -> Result<bool> {
+        self.ensure_writable()?;
+        let _loc = self.location.write().await;
+        if self.manifest.lock().await.chunks.get(name) != Some(meta) {
+            return Ok(false);
         }
-        file.flush().await?;
-        Ok(path)
+        op.await?;
+        Ok(true)
     }
 
-    /// Read all observations for a given `station_id` by scanning chunk files.
+    /// Copy every chunk file and the manifest into `to`, which must be empty
+    /// or not exist yet. Like [`Self::relocate`] the bulk copy runs alongside
+    /// reads and writes and only chunks changed meanwhile are copied again
+    /// while writers are held off, so the copy matches its manifest.
+    pub async fn snapshot(&self, to: &Path) -> Result<SnapshotCopy> {
+        tokio::fs::create_dir_all(to).await.with_context(|| format!("creating {}", to.display()))?;
+        if tokio::fs::read_dir(to).await?.next_entry().await?.is_some() {
+            anyhow::bail!("{} is not empty", to.display());
+        }
+        let backend = self.location.read().await.backend.clone();
+        let mut copied = self.manifest().await;
+        // a chunk being rewritten fails its checksum; it changed, so it is copied again below
+        let mut torn = Vec::new();
+        for (name, meta) in &copied.chunks {
+            if copy_verified(backend.as_ref(), to, name, meta).await.is_err() {
+                remove_if_exists(&to.join(name)).await?;
+                torn.push(name.clone());
+            }
+        }
+        for name in torn {
+            copied.chunks.remove(&name);
+        }
+        let loc = self.location.write().await;
+        let manifest = self.manifest.lock().await;
+        let mut recopied = 0;
+        for (name, meta) in &manifest.chunks {
+            if copied.chunks.get(name) != Some(meta) {
+                copy_verified(loc.backend.as_ref(), to, name, meta).await?;
+                recopied += 1;
+            }
+        }
+        for name in copied.chunks.keys().filter(|n| !manifest.chunks.contains_key(*n)) {
+            remove_if_exists(&to.join(name)).await?;
+        }
+        save_manifest(to, &manifest).await?;
+        Ok(SnapshotCopy { chunks: manifest.chunks.len(), bytes: manifest.chunks.values().map(|c| c.bytes).sum(), recopied })
+    }
+
+    /// The station's runs in every chunk of the manifest, shared chunks
+    /// included, oldest write first (see [`ChunkMeta::written`]).
+    pub async fn slices(&self, station_id: &str) -> Vec<(String, StationSlice)> {
+        let manifest = self.manifest.lock().await;
+        let mut chunks: Vec<(&String, &ChunkMeta)> = manifest.chunks.iter().collect();
+        chunks.sort_by_key(|(_, meta)| meta.written);
+        let mut out = Vec::new();
+        for (name, meta) in chunks {
+            out.extend(meta.slices_for(station_id).into_iter().map(|s| (name.clone(), s)));
+        }
+        out
+    }
+
+    /// Read all observations for a given `station_id` from the chunks the
+    /// manifest lists for it.
     pub async fn read_chunks(&self, station_id: &str) -> Result<Vec<Observation>> {
         let mut out = Vec::new();
-        let mut rd = tokio::fs::read_dir(&self.dir).await?;
-        while let Some(entry) = rd.next_entry().await? {
-            let name = entry.file_name().into_string().unwrap_or_default();
-            if !name.starts_with(&format!("{}-", station_id)) {
+        for (name, slice) in self.slices(station_id).await {
+            out.extend(self.read_slice(&name, &slice).await?);
+        }
+        Ok(out)
+    }
+
+    /// A station's runs ordered by their newest observation, most recent first.
+    pub async fn chunks_newest_first(&self, station_id: &str) -> Vec<(String, StationSlice)> {
+        let mut chunks: Vec<(String, StationSlice)> =
+            self.slices(station_id).await.into_iter().filter(|(_, s)| s.max_time.is_some()).collect();
+        let max = |s: &StationSlice| s.max_time.as_deref().and_then(|t| Timestamp::parse(t).ok());
+        chunks.sort_by_key(|(_, s)| std::cmp::Reverse(max(s)));
+        chunks
+    }
+
+    /// Remove a station's rows at `times` from every raw chunk whose range
+    /// covers one of them, rewriting the file or deleting it once empty.
+    /// Archive chunks are left alone. Returns the rows removed.
+    pub async fn remove_points(&self, station_id: &str, times: &BTreeSet<Timestamp>) -> Result<usize> {
+        self.ensure_writable()?;
+        let parse = |t: &Option<String>| t.as_deref().and_then(|t| Timestamp::parse(t).ok());
+        let mut names = BTreeSet::new();
+        for (name, slice) in self.slices(station_id).await {
+            if let (Some(min), Some(max)) = (parse(&slice.min_time), parse(&slice.max_time)) {
+                if !archive::is_archive(&name) && times.range(min..=max).next().is_some() {
+                    names.insert(name);
+                }
+            }
+        }
+        let mut removed = 0;
+        let mut emptied = Vec::new();
+        for name in names {
+            let rows = self.read_chunk_file(&name).await?;
+            let before = rows.len();
+            let kept: Vec<Observation> =
+                rows.into_iter().filter(|o| o.station_id != station_id || !times.contains(&o.time)).collect();
+            removed += before - kept.len();
+            if kept.is_empty() {
+                emptied.push(name);
+            } else if kept.len() < before {
+                self.rewrite_chunk(&name, station_id, &kept).await?;
+            }
+        }
+        self.delete_chunks(&emptied).await?;
+        Ok(removed)
+    }
+
+    /// Record a range delete in the manifest; reads mask its rows from then on.
+    pub async fn add_tombstone(&self, tombstone: Tombstone) -> Result<()> {
+        self.ensure_writable()?;
+        let loc = self.location.read().await;
+        let mut manifest = self.manifest.lock().await;
+        if manifest.tombstones.contains(&tombstone) {
+            return Ok(());
+        }
+        manifest.tombstones.push(tombstone);
+        save_manifest(&loc.dir, &manifest).await
+    }
+
+    /// Pending range deletes, of one station or all of them.
+    pub async fn tombstones(&self, station_id: Option<&str>) -> Vec<Tombstone> {
+        let manifest = self.manifest.lock().await;
+        manifest.tombstones.iter().filter(|t| station_id.is_none_or(|s| t.station_id == s)).cloned().collect()
+    }
+
+    /// Forget a range delete once its rows are gone from the chunk files.
+    pub async fn remove_tombstone(&self, tombstone: &Tombstone) -> Result<()> {
+        self.ensure_writable()?;
+        let loc = self.location.read().await;
+        let mut manifest = self.manifest.lock().await;
+        manifest.tombstones.retain(|t| t != tombstone);
+        save_manifest(&loc.dir, &manifest).await
+    }
+
+    /// Physically remove the rows `tombstone` deletes from every chunk it
+    /// applies to, rewriting the file (archives recompressed at
+    /// `archive_level`) or deleting it once empty. Rewritten chunks keep
+    /// their write time. Returns the rows removed and the chunks rewritten or
+    /// deleted; the tombstone itself stays until [`Self::remove_tombstone`].
+    pub async fn purge(&self, tombstone: &Tombstone, archive_level: i32) -> Result<(usize, Vec<String>)> {
+        self.ensure_writable()?;
+        let manifest = self.manifest().await;
+        let parse = |t: &Option<String>| t.as_deref().and_then(|t| Timestamp::parse(t).ok());
+        let mut removed = 0;
+        let mut touched = Vec::new();
+        let mut emptied = Vec::new();
+        for (name, meta) in &manifest.chunks {
+            let hit = meta.slices_for(&tombstone.station_id).iter().any(|s| tombstone.overlaps(parse(&s.min_time), parse(&s.max_time)));
+            if !hit || !tombstone.applies_to(meta.written) {
+                continue;
+            }
+            let mut rows = self.read_chunk_file(name).await?;
+            let dropped = tombstone::mask(&mut rows, meta.written, std::slice::from_ref(tombstone));
+            if dropped == 0 {
                 continue;
             }
-            let data = tokio::fs::read(entry.path()).await?;
-            for line in data.split(|b| *b == b'\n') {
-                if line.is_empty() { continue; }
-                if let Ok(obs) = serde_json::from_slice::<Observation>(line) {
-                    out.push(obs);
+            removed += dropped;
+            touched.push(name.clone());
+            if rows.is_empty() {
+                emptied.push(name.clone());
+            } else if archive::is_archive(name) {
+                let (data, blocks) = archive::encode(&rows, archive_level)?;
+                let mut rewritten = ChunkMeta::from_contents(&meta.station_id, &rows, &data);
+                rewritten.blocks = blocks;
+                rewritten.written = meta.written;
+                self.write_file(name, &data, rewritten).await?;
+            } else {
+                self.rewrite_chunk(name, &meta.station_id, &rows).await?;
+            }
+        }
+        self.delete_chunks(&emptied).await?;
+        Ok((removed, touched))
+    }
+
+    /// Read one station's rows from a chunk, touching only its byte range of
+    /// a packed file. Rows of pending range deletes are left out. Fails on a
+    /// damaged chunk rather than returning what could be read of it.
+    pub async fn read_slice(&self, name: &str, slice: &StationSlice) -> Result<Vec<Observation>> {
+        let packed = self.manifest.lock().await.chunks.get(name).is_some_and(|m| m.is_packed());
+        let rows = if packed {
+            self.read_packed(name, slice).await?
+        } else {
+            self.read_chunk_file(name).await?
+        };
+        let mut rows: Vec<Observation> = rows.into_iter().filter(|o| o.station_id == slice.station_id).collect();
+        let (written, tombstones) = {
+            let manifest = self.manifest.lock().await;
+            let written = manifest.chunks.get(name).map_or(0, |m| m.written);
+            (written, manifest.tombstones.iter().filter(|t| t.station_id == slice.station_id).cloned().collect::<Vec<_>>())
+        };
+        tombstone::mask(&mut rows, written, &tombstones);
+        Ok(rows)
+    }
+
+    /// Read the observations stored in a single chunk file, checking it
+    /// against its footer first.
+    pub async fn read_chunk_file(&self, name: &str) -> Result<Vec<Observation>> {
+        let data = self.backend().await.read(name).await?;
+        checksum::checked_rows(name, &data, None).map_err(|damage| damaged(name, &damage))
+    }
+
+    /// Read a station's byte range of a packed chunk, checking it against the
+    /// checksum recorded for it.
+    async fn read_packed(&self, name: &str, slice: &StationSlice) -> Result<Vec<Observation>> {
+        let mut slice = slice.clone();
+        loop {
+            let data = self.backend().await.read_range(name, slice.offset, slice.len).await?;
+            let found = crc32fast::hash(&data);
+            if slice.crc32.is_none_or(|crc| crc == found) && data.len() as u64 == slice.len {
+                return Ok(crate::storage::manifest::parse_rows(&data));
+            }
+            // the chunk may have been rewritten since the slice was looked up
+            let current = self.manifest.lock().await.chunks.get(name).and_then(|m| m.slices_for(&slice.station_id).into_iter().next());
+            match current {
+                Some(current) if current != slice => slice = current,
+                _ => {
+                    let damage = match data.len() as u64 == slice.len {
+                        true => Damage::ChecksumMismatch { expected: slice.crc32.unwrap_or_default(), found },
+                        false => Damage::Truncated { expected_bytes: slice.offset + slice.len, found_bytes: slice.offset + data.len() as u64 },
+                    };
+                    return Err(damaged(name, &damage));
                 }
             }
         }
-        Ok(out)
     }
 
-    /// List chunk file paths for a station.
-    pub async fn list_chunks(&self, station_id: &str) -> Result<Vec<PathBuf>> {
-        let mut res = Vec::new();
-        let mut rd = tokio::fs::read_dir(&self.dir).await?;
-        while let Some(entry) = rd.next_entry().await? {
-            let name = entry.file_name().into_string().unwrap_or_default();
-            if name.starts_with(&format!("{}-", station_id)) {
-                res.push(entry.path());
+    /// Rewrite a damaged chunk with the rows that can still be read from it,
+    /// after copying it as it was into `quarantine`; a chunk with none left
+    /// is deleted. Archives are recompressed at `archive_level`, and the
+    /// chunk keeps its write time. Returns what was salvaged and where the
+    /// damaged file was kept.
+    pub async fn salvage(&self, name: &str, quarantine: &Path, archive_level: i32) -> Result<(Salvage, PathBuf)> {
+        self.ensure_writable()?;
+        let data = self.backend().await.read(name).await?;
+        tokio::fs::create_dir_all(quarantine).await.with_context(|| format!("creating {}", quarantine.display()))?;
+        let kept = quarantine.join(format!("{}-{}", chrono::Utc::now().timestamp_millis(), name));
+        tokio::fs::write(&kept, &data).await.with_context(|| format!("writing {}", kept.display()))?;
+        let salvage = checksum::salvage(name, &data);
+        let recorded = self.manifest.lock().await.chunks.get(name).cloned();
+        let label = recorded.as_ref().map(|m| m.station_id.clone()).unwrap_or_default();
+        if salvage.rows.is_empty() {
+            self.delete_chunks(&[name.to_string()]).await?;
+        } else if archive::is_archive(name) {
+            let (data, blocks) = archive::encode(&salvage.rows, archive_level)?;
+            let mut meta = ChunkMeta::from_contents(&salvage.rows[0].station_id, &salvage.rows, &data);
+            meta.blocks = blocks;
+            self.write_file(name, &data, meta).await?;
+        } else if columnar::is_block(name) {
+            self.rewrite_chunk(name, &salvage.rows[0].station_id, &salvage.rows).await?;
+        } else {
+            self.rewrite_chunk(name, &label, &salvage.rows).await?;
+        }
+        Ok((salvage, kept))
+    }
+
+    /// Read every chunk file and check it against its footer and manifest
+    /// entry. Returns the manifest a scan would rebuild from the files and
+    /// the damaged ones; run it while no writer is rewriting chunks.
+    pub async fn verify(&self) -> Result<(Manifest, Vec<DamagedChunk>)> {
+        let recorded = self.manifest().await;
+        let loc = self.location.read().await;
+        let mut scanned = Manifest::default();
+        let mut damaged = Vec::new();
+        let mut names = loc.backend.list().await?;
+        names.sort();
+        for name in names {
+            let data = loc.backend.read(&name).await?;
+            if let Err(damage) = checksum::checked_rows(&name, &data, recorded.chunks.get(&name)) {
+                damaged.push(DamagedChunk { file: name.clone(), damage });
             }
+            scanned.chunks.insert(name.clone(), Manifest::describe(&name, &data));
         }
-        Ok(res)
+        Ok((scanned, damaged))
+    }
+
+    /// The backend, held so a relocation waits for the access to finish.
+    async fn backend(&self) -> RwLockReadGuard<'_, Arc<dyn ChunkBackend>> {
+        RwLockReadGuard::map(self.location.read().await, |loc| &loc.backend)
+    }
+
+    /// List chunk file paths holding data for a station, shared chunks included.
+    pub async fn list_chunks(&self, station_id: &str) -> Result<Vec<PathBuf>> {
+        let mut names: Vec<String> = self.slices(station_id).await.into_iter().map(|(name, _)| name).collect();
+        names.dedup();
+        let dir = self.dir().await;
+        Ok(names.into_iter().map(|n| dir.join(n)).collect())
+    }
+}
+
+fn damaged(name: &str, damage: &Damage) -> anyhow::Error {
+    anyhow::anyhow!("chunk {} is damaged: {}; `skypulsedb verify` lists every damaged chunk", name, damage)
+}
+
+/// Persist the manifest atomically (write to a temp file, then rename).
+async fn save_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    tokio::fs::write(&tmp, manifest.to_bytes()?).await?;
+    tokio::fs::rename(&tmp, dir.join(MANIFEST_FILE)).await?;
+    Ok(())
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Copy chunk `name` from `from` into the directory `to`, checking it
+/// against `meta` first.
+async fn copy_verified(from: &dyn ChunkBackend, to: &Path, name: &str, meta: &ChunkMeta) -> Result<()> {
+    let data = from.read(name).await.with_context(|| format!("copying {}", name))?;
+    if data.len() as u64 != meta.bytes || crc32fast::hash(&data) != meta.crc32 {
+        anyhow::bail!("copy of {} does not match its manifest checksum", name);
+    }
+    tokio::fs::write(to.join(name), &data).await.with_context(|| format!("copying {}", name))?;
+    Ok(())
+}
+
+/// Remove what a failed relocation copied into `to`, and `to` itself unless
+/// it `existed` beforehand. It was empty then, so everything in it is the copy.
+async fn remove_copy(to: &Path, existed: bool) -> Result<()> {
+    if !existed {
+        return Ok(tokio::fs::remove_dir_all(to).await?);
+    }
+    let mut entries = tokio::fs::read_dir(to).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        tokio::fs::remove_file(entry.path()).await?;
+    }
+    Ok(())
+}
+
+/// A manifest of every chunk file in `backend`, from their contents.
+async fn scan_backend(backend: &dyn ChunkBackend) -> Result<Manifest> {
+    let mut manifest = Manifest::default();
+    for name in backend.list().await? {
+        let data = backend.read(&name).await?;
+        manifest.chunks.insert(name.clone(), Manifest::describe(&name, &data));
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store(dir: &Path) -> ChunkStore {
+        let store = ChunkStore::new(dir.join("chunks"), IntegrityMode::Strict).unwrap();
+        let rows: Vec<Observation> = (0..3).map(|m| Observation::empty("A", format!("2025-01-01T00:0{}:00Z", m).parse().unwrap())).collect();
+        store.write_chunk("A", "flush-1", &rows[..2]).await.unwrap();
+        store.write_chunk("A", "flush-2", &rows[2..]).await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn relocates_chunks_and_records_the_new_home() {
+        let dir = crate::test_util::TempDir::new("relocate");
+        let store = store(&dir).await;
+        let to = dir.join("disk2").join("chunks");
+        let report = store.relocate(&dir, to.clone()).await.unwrap();
+        assert_eq!((report.chunks, report.recopied), (2, 0));
+
+        assert!(!dir.join("chunks").exists());
+        assert!(to.join(MANIFEST_FILE).exists());
+        assert_eq!(locate(&dir, None).unwrap(), to);
+        assert_eq!(store.read_chunks("A").await.unwrap().len(), 3);
+        // a reopened store finds the chunks where they were moved
+        let reopened = ChunkStore::new(locate(&dir, None).unwrap(), IntegrityMode::Strict).unwrap();
+        assert_eq!(reopened.manifest().await.chunks.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_non_empty_target_and_keeps_an_existing_one() {
+        let dir = crate::test_util::TempDir::new("relocate-target");
+        let store = store(&dir).await;
+        let busy = dir.join("busy");
+        std::fs::create_dir_all(&busy).unwrap();
+        std::fs::write(busy.join("keep.txt"), b"mine").unwrap();
+        assert!(store.relocate(&dir, busy.clone()).await.unwrap_err().to_string().contains("not empty"));
+        assert_eq!(std::fs::read(busy.join("keep.txt")).unwrap(), b"mine");
+
+        // a copy that fails its checksum leaves the empty target in place
+        let mount = dir.join("mount");
+        std::fs::create_dir_all(&mount).unwrap();
+        let name = store.manifest().await.chunks.keys().next().unwrap().clone();
+        let path = dir.join("chunks").join(&name);
+        let mut data = std::fs::read(&path).unwrap();
+        data[0] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        assert!(store.relocate(&dir, mount.clone()).await.is_err());
+        assert_eq!(std::fs::read_dir(&mount).unwrap().count(), 0);
+        assert!(!dir.join(LOCATION_FILE).exists());
     }
 }
@@ -0,0 +1,144 @@
+// Columnar chunks: a station's flushed rows stored as a compression `Block`,
+// one float column per field, instead of a JSON line per row. Written only
+// with `chunk_format = "block"`, and then for rows that carry no tags and
+// whose every field is a number or an explicit null; other chunks stay NDJSON. A null is stored as NaN, which a JSON row
+// cannot hold either (NaN is written to NDJSON as null). The file is the
+// station id (LEB128 length, then UTF-8), the block, and the checksum footer
+// frame that archives also end in.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use crate::compression::delta::{read_leb_u64, write_leb_u64};
+use crate::compression::{Block, DecodeError};
+use crate::storage::checksum;
+use crate::storage::manifest::ChunkMeta;
+use crate::storage::memtable::{FieldValue, Observation, Timestamp};
+
+pub const BLOCK_SUFFIX: &str = ".block";
+
+/// How chunks are written. Either is read whatever the setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkFormat {
+    /// A JSON line per row.
+    #[default]
+    Ndjson,
+    /// Block chunks for rows that [`fits`] one, NDJSON for the rest.
+    Block,
+}
+
+pub fn is_block(name: &str) -> bool {
+    name.ends_with(BLOCK_SUFFIX)
+}
+
+/// Whether `obs`, all rows of `station_id`, can be stored as a block.
+pub fn fits(station_id: &str, obs: &[Observation]) -> bool {
+    obs.iter().all(|o| o.station_id == station_id && o.tags.is_empty() && o.fields.values().all(FieldValue::is_numeric))
+}
+
+/// Encode time-ordered rows of `station_id` as a sealed block chunk.
+pub fn encode(station_id: &str, obs: &[Observation]) -> Result<(Vec<u8>, ChunkMeta)> {
+    if !fits(station_id, obs) {
+        bail!("rows with tags or non-numeric fields cannot be stored in a block chunk");
+    }
+    let mut block = Block { timestamps: obs.iter().map(|o| o.time.millis()).collect(), ..Default::default() };
+    for (i, o) in obs.iter().enumerate() {
+        for (field, value) in &o.fields {
+            let column = block.columns.entry(field.clone()).or_insert_with(|| vec![None; obs.len()]);
+            column[i] = Some(value.as_f64().unwrap_or(f64::NAN));
+        }
+    }
+    let mut data = Vec::new();
+    write_leb_u64(station_id.len() as u64, &mut data);
+    data.extend_from_slice(station_id.as_bytes());
+    data.extend(block.encode());
+    checksum::seal_frame(&mut data, obs.len());
+    let meta = ChunkMeta::from_contents(station_id, obs, &data);
+    Ok((data, meta))
+}
+
+/// Rows of a block chunk, `body` being the file without its footer.
+pub fn decode(body: &[u8]) -> Result<Vec<Observation>, DecodeError> {
+    let (station_id, block) = split(body)?;
+    Ok(rows(station_id, Block::decode(block)?))
+}
+
+/// The rows of a damaged block chunk that can still be read, each field as
+/// far as its column decodes (see [`Block::salvage`]), and the byte ranges
+/// of `body`, as offset and length, that could not be.
+pub fn salvage(body: &[u8]) -> (Vec<Observation>, Vec<(u64, u64)>) {
+    match split(body) {
+        Ok((station_id, block)) => {
+            let at = body.len() - block.len();
+            let (block, unreadable) = Block::salvage(block);
+            (rows(station_id, block), unreadable.into_iter().map(|(offset, len)| ((at + offset) as u64, len as u64)).collect())
+        }
+        Err(_) => (Vec::new(), vec![(0, body.len() as u64)]),
+    }
+}
+
+fn split(body: &[u8]) -> Result<(&str, &[u8]), DecodeError> {
+    let mut idx = 0;
+    let len = read_leb_u64(body, &mut idx, 0)? as usize;
+    let id = body.get(idx..idx.saturating_add(len)).ok_or(DecodeError::Truncated { decoded: 0 })?;
+    let id = std::str::from_utf8(id).map_err(|_| DecodeError::Corrupt { decoded: 0, reason: "station id is not UTF-8" })?;
+    Ok((id, &body[idx + len..]))
+}
+
+fn rows(station_id: &str, block: Block) -> Vec<Observation> {
+    let mut out: Vec<Observation> = block.timestamps.iter().map(|&t| Observation::empty(station_id, Timestamp(t))).collect();
+    for (field, values) in block.columns {
+        for (o, value) in out.iter_mut().zip(values) {
+            match value {
+                Some(v) if v.is_nan() => o.set(field.clone(), FieldValue::Null),
+                Some(v) => o.set_field(field.clone(), v),
+                None => {}
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_numeric_rows_as_columns() {
+        let rows: Vec<Observation> = (0..50)
+            .map(|i| {
+                let mut o = Observation::empty("HK001", Timestamp(1_735_689_600_000 + i * 60_000));
+                o.set_field("temp", 20.0 + (i % 7) as f64 * 0.5);
+                if i % 10 == 0 {
+                    o.set_field("rain", 0.2);
+                }
+                if i == 3 {
+                    o.set("humidity", FieldValue::Null);
+                }
+                o
+            })
+            .collect();
+        let (data, meta) = encode("HK001", &rows).unwrap();
+        assert_eq!((meta.rows, meta.station_id.as_str()), (50, "HK001"));
+        let (footer, start) = checksum::footer("HK001-flush-1.block", &data).unwrap();
+        assert_eq!(footer.rows, 50);
+        assert_eq!(decode(&data[..start]).unwrap(), rows);
+        let ndjson = crate::storage::manifest::encode_ndjson("HK001", &rows).unwrap().0;
+        assert!(data.len() * 4 < ndjson.len(), "{} vs {}", data.len(), ndjson.len());
+
+        // the end of the temp column is lost; every row keeps its time and rain
+        let cut = &data[..start - 6];
+        assert!(decode(cut).is_err());
+        let (kept, unreadable) = salvage(cut);
+        assert_eq!(kept.len(), 50);
+        let whole = kept.iter().zip(&rows).take_while(|(k, r)| k == r).count();
+        assert!(whole > 0 && whole < 50, "{}", whole);
+        assert!(kept[whole..].iter().all(|o| !o.fields.contains_key("temp")));
+        assert_eq!((unreadable.len(), unreadable[0].0 + unreadable[0].1), (1, cut.len() as u64));
+
+        let mut tagged = rows.clone();
+        tagged[1].tags.insert("qc".into(), "temp:step".into());
+        assert!(!fits("HK001", &tagged) && encode("HK001", &tagged).is_err());
+        assert!(!fits("HK002", &rows));
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::path::Path;
+use anyhow::{Context, Result};
+
+/// File under the data directory that a writing process holds an exclusive
+/// lock on, so a server and an offline command never write the same files.
+pub const LOCK_FILE: &str = "LOCK";
+
+/// An exclusive lock on a data directory, released when dropped (or when
+/// the process dies, so a crash leaves nothing to clean up).
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock `dir`, failing at once if another process (or another open node
+    /// in this one) holds it.
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        let file = File::options().create(true).truncate(false).write(true).open(&path).with_context(|| format!("opening {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(std::fs::TryLockError::WouldBlock) => {
+                anyhow::bail!("data directory {} is in use by another process; stop the server first", dir.display())
+            }
+            Err(std::fs::TryLockError::Error(e)) => Err(e).with_context(|| format!("locking {}", path.display())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn a_writing_node_holds_its_data_dir() {
+        let dir = crate::test_util::TempDir::new("dir-lock");
+        let cfg = crate::Config { data_dir: dir.to_path_buf(), ..Default::default() };
+        let server = crate::AppState::open(&cfg).await.unwrap();
+        // an offline compact or repair opens the directory for writing too
+        let err = crate::AppState::open(&cfg).await.err().unwrap();
+        assert!(err.to_string().contains("in use"), "{:#}", err);
+        crate::AppState::open(&crate::Config { read_only: true, ..cfg.clone() }).await.unwrap();
+        drop(server);
+        crate::AppState::open(&cfg).await.unwrap();
+    }
+}
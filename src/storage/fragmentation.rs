@@ -0,0 +1,258 @@
+// Small-file accounting and compaction. Every flush writes a file per station
+// (or a packed file shared by quiet stations), so a station's history ends up
+// spread over many small chunks whose time ranges can overlap when late data
+// arrives. The score compares the files a station has with the files its data
+// would need at the target chunk size, inflated by the share of files
+// overlapping an earlier one; 1.0 is ideal. Stations scoring above the
+// configured limit get their raw chunks merged into time-ordered files.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::storage::archive;
+use crate::storage::manifest::{encode_chunk, ChunkMeta, Manifest};
+use crate::storage::memtable::{dedup_last, Observation, Timestamp};
+use crate::storage::tombstone;
+use crate::storage::ChunkStore;
+use crate::units;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactionConfig {
+    /// Compact a station once its fragmentation score exceeds this.
+    pub max_fragmentation: f64,
+    /// Stations with fewer raw chunk files than this are left alone.
+    pub min_files: usize,
+    /// Size of the files compaction writes, and the yardstick for the score.
+    #[serde(alias = "target_chunk_size", deserialize_with = "units::bytes")]
+    pub target_chunk_bytes: u64,
+    #[serde(alias = "check_interval", deserialize_with = "units::secs")]
+    pub check_interval_secs: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self { max_fragmentation: 4.0, min_files: 8, target_chunk_bytes: 16 * 1024 * 1024, check_interval_secs: 600 }
+    }
+}
+
+impl CompactionConfig {
+    /// `SKYPULSE_COMPACT_MAX_FRAGMENTATION` enables compaction with that limit;
+    /// `SKYPULSE_COMPACT_TARGET_BYTES` sets the file size.
+    pub fn apply_env(cfg: &mut Option<Self>) -> Result<()> {
+        if let Ok(v) = std::env::var("SKYPULSE_COMPACT_MAX_FRAGMENTATION") {
+            let limit = v.parse().map_err(|_| anyhow::anyhow!("SKYPULSE_COMPACT_MAX_FRAGMENTATION must be a number, got '{}'", v))?;
+            cfg.get_or_insert_with(Self::default).max_fragmentation = limit;
+        }
+        if let Some(bytes) = units::env_size("SKYPULSE_COMPACT_TARGET_BYTES")? {
+            cfg.get_or_insert_with(Self::default).target_chunk_bytes = bytes;
+        }
+        Ok(())
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs.max(1))
+    }
+}
+
+/// How scattered one station's raw chunks are.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StationFragmentation {
+    pub station_id: String,
+    /// Raw chunk files holding rows of the station, shared files included.
+    pub files: usize,
+    /// Of those, files shared with other stations.
+    pub packed_files: usize,
+    pub rows: usize,
+    pub bytes: u64,
+    /// Files whose time range starts before an earlier file's ends.
+    pub overlapping: usize,
+    /// Files the data would need at the target chunk size.
+    pub ideal_files: usize,
+    pub score: f64,
+}
+
+/// A slice's `(min_time, max_time)`.
+type TimeRange = (Option<Timestamp>, Option<Timestamp>);
+
+/// Score every station's raw (non-archive) chunks against `target_bytes`.
+pub fn analyze(manifest: &Manifest, target_bytes: u64) -> BTreeMap<String, StationFragmentation> {
+    let mut ranges: BTreeMap<String, Vec<TimeRange>> = BTreeMap::new();
+    let mut out: BTreeMap<String, StationFragmentation> = BTreeMap::new();
+    for (_, meta) in manifest.chunks.iter().filter(|(name, _)| !archive::is_archive(name)) {
+        for slice in meta.slices() {
+            let f = out.entry(slice.station_id.clone()).or_insert_with(|| StationFragmentation {
+                station_id: slice.station_id.clone(),
+                files: 0,
+                packed_files: 0,
+                rows: 0,
+                bytes: 0,
+                overlapping: 0,
+                ideal_files: 0,
+                score: 0.0,
+            });
+            f.files += 1;
+            f.packed_files += usize::from(meta.is_packed());
+            f.rows += slice.rows;
+            f.bytes += slice.len;
+            let parse = |t: Option<String>| t.and_then(|t| Timestamp::parse(&t).ok());
+            ranges.entry(slice.station_id).or_default().push((parse(slice.min_time), parse(slice.max_time)));
+        }
+    }
+    for (station, f) in out.iter_mut() {
+        let mut spans = ranges.remove(station).unwrap_or_default();
+        spans.sort();
+        let mut latest: Option<Timestamp> = None;
+        for (min, max) in spans {
+            if min.is_some() && latest.is_some() && min < latest {
+                f.overlapping += 1;
+            }
+            latest = latest.max(max);
+        }
+        f.ideal_files = f.bytes.div_ceil(target_bytes.max(1)).max(1) as usize;
+        let overlap = f.overlapping as f64 / f.files as f64;
+        f.score = f.files as f64 / f.ideal_files as f64 * (1.0 + overlap);
+    }
+    out
+}
+
+/// Merge the raw chunks of every station scoring above the limit into
+/// time-ordered files of about the target size; returns the stations
+/// compacted. Of duplicate points only the last write is kept, and rows of
+/// pending range deletes are dropped. The merged files are written before
+/// any source is touched, so a crash in between leaves duplicate rows rather
+/// than lost ones. Waits for any other job rewriting chunks to finish first.
+pub async fn compact(store: &ChunkStore, cfg: &CompactionConfig) -> Result<Vec<String>> {
+    let _maintenance = store.maintenance().await;
+    let manifest = store.manifest().await;
+    let mut compacted = Vec::new();
+    for f in analyze(&manifest, cfg.target_chunk_bytes).into_values() {
+        if f.files < cfg.min_files.max(2) || f.score <= cfg.max_fragmentation {
+            continue;
+        }
+        let station_id = f.station_id;
+        let mut rows = Vec::new();
+        let mut written = 0;
+        let mut deleted = 0;
+        // packed sources keep the other stations' rows
+        let mut sources = Vec::new();
+        let mut chunks: Vec<(&String, &ChunkMeta)> =
+            manifest.chunks.iter().filter(|(name, meta)| !archive::is_archive(name) && !meta.slices_for(&station_id).is_empty()).collect();
+        // oldest write first, so the stable sort below leaves duplicates in write order
+        chunks.sort_by_key(|(_, meta)| meta.written);
+        for (name, meta) in chunks {
+            let (mut mine, others): (Vec<Observation>, Vec<Observation>) =
+                store.read_chunk_file(name).await?.into_iter().partition(|o| o.station_id == station_id);
+            deleted += tombstone::mask(&mut mine, meta.written, &manifest.tombstones);
+            rows.extend(mine);
+            written = written.max(meta.written);
+            sources.push((name.clone(), others));
+        }
+        rows.sort_by_key(|o| o.time);
+        let duplicates = dedup_last(&mut rows);
+
+        let per_file = (rows.len() as u64 * cfg.target_chunk_bytes / f.bytes.max(1)).max(1) as usize;
+        let stamp = chrono::Utc::now().timestamp_millis();
+        for (i, part) in rows.chunks(per_file).enumerate() {
+            let suffix = store.suffix_for(&station_id, part);
+            let name = format!("{}-compact-{}-{:04}{}", station_id, stamp, i, suffix);
+            let (data, mut meta) = encode_chunk(&name, &station_id, part)?;
+            // as new as the newest source, not the compaction, so later flushes still win
+            meta.written = written;
+            store.write_file(&name, &data, meta).await?;
+        }
+        let mut emptied = Vec::new();
+        for (name, others) in sources {
+            if others.is_empty() {
+                emptied.push(name);
+            } else {
+                store.rewrite_chunk(&name, "", &others).await?;
+            }
+        }
+        store.delete_chunks(&emptied).await?;
+        tracing::info!(
+            station_id,
+            files = f.files,
+            score = f.score,
+            duplicates,
+            deleted,
+            "compacted {} rows into {} files",
+            rows.len(),
+            rows.len().div_ceil(per_file)
+        );
+        compacted.push(station_id);
+    }
+    Ok(compacted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(station: &str, times: &[&str]) -> ChunkMeta {
+        let rows: Vec<Observation> = times.iter().map(|t| Observation::empty(station, t.parse().unwrap())).collect();
+        crate::storage::manifest::encode_ndjson(station, &rows).unwrap().1
+    }
+
+    #[test]
+    fn scores_file_count_and_overlap() {
+        let mut m = Manifest::default();
+        m.chunks.insert("A-1.ndjson".into(), chunk("A", &["2025-01-01T00:00:00Z", "2025-01-01T02:00:00Z"]));
+        m.chunks.insert("A-2.ndjson".into(), chunk("A", &["2025-01-01T03:00:00Z"]));
+        // late data reaching back into the first file's range
+        m.chunks.insert("A-3.ndjson".into(), chunk("A", &["2025-01-01T01:00:00Z"]));
+        m.chunks.insert("B-1.ndjson".into(), chunk("B", &["2025-01-01T00:00:00Z"]));
+        let f = analyze(&m, 1 << 20);
+        assert_eq!((f["A"].files, f["A"].rows, f["A"].overlapping, f["A"].ideal_files), (3, 4, 1, 1));
+        assert!((f["A"].score - 4.0).abs() < 1e-9);
+        assert_eq!(f["B"].score, 1.0);
+
+        // at a tiny target size every file is warranted
+        let small = analyze(&m, 1);
+        assert!(small["A"].score < 1.5);
+
+        // times are compared as times: "…00.500Z" sorts before "…00Z" as text
+        m.chunks.insert("C-1.ndjson".into(), chunk("C", &["2025-01-01T03:00:00.500Z"]));
+        m.chunks.insert("C-2.ndjson".into(), chunk("C", &["2025-01-01T03:00:00Z", "2025-01-01T03:00:01Z"]));
+        assert_eq!(analyze(&m, 1 << 20)["C"].overlapping, 1);
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_the_last_write_of_duplicates() {
+        let dir = crate::test_util::TempDir::new("fragmentation");
+        let store = ChunkStore::new(dir.to_path_buf(), crate::storage::integrity::IntegrityMode::Ignore).unwrap();
+        let at = |t: &str, temp: f64| {
+            let mut o = Observation::empty("A", t.parse().unwrap());
+            o.set_field("temp", temp);
+            o
+        };
+        store.write_chunk("A", "flush-1", &[at("2025-01-01T00:00:00Z", 1.0), at("2025-01-01T00:01:00Z", 1.0)]).await.unwrap();
+        // a client retry landing in the next flush
+        store.write_chunk("A", "flush-2", &[at("2025-01-01T00:01:00Z", 2.0)]).await.unwrap();
+        let cfg = CompactionConfig { max_fragmentation: 0.0, min_files: 2, ..Default::default() };
+        assert_eq!(compact(&store, &cfg).await.unwrap(), vec!["A".to_string()]);
+        let rows = store.read_chunks("A").await.unwrap();
+        assert_eq!(rows.iter().map(|o| o.number("temp").unwrap()).collect::<Vec<_>>(), vec![1.0, 2.0]);
+        assert_eq!(store.manifest().await.chunks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn compaction_waits_for_a_concurrent_rewrite() {
+        let dir = crate::test_util::TempDir::new("fragmentation-race");
+        let store = ChunkStore::new(dir.to_path_buf(), crate::storage::integrity::IntegrityMode::Ignore).unwrap();
+        let at = |t: &str| Observation::empty("A", t.parse().unwrap());
+        for (i, t) in ["2024-01-01T00:00:00Z", "2024-02-01T00:00:00Z", "2024-03-01T00:00:00Z"].iter().enumerate() {
+            store.write_chunk("A", &format!("flush-{}", i), &[at(t)]).await.unwrap();
+        }
+        // archiving rewrites the same chunks compaction merges
+        let cfg = CompactionConfig { max_fragmentation: 0.0, min_files: 2, ..Default::default() };
+        let policy = archive::ArchivePolicy { after_secs: 86_400, check_interval_secs: 86_400, level: 1 };
+        let (compacted, archived) = tokio::join!(compact(&store, &cfg), archive::compact(&store, &policy, chrono::Utc::now()));
+        compacted.unwrap();
+        archived.unwrap();
+        let mut times: Vec<String> = store.read_chunks("A").await.unwrap().iter().map(|o| o.time.to_string()).collect();
+        times.sort();
+        assert_eq!(times, ["2024-01-01T00:00:00Z", "2024-02-01T00:00:00Z", "2024-03-01T00:00:00Z"]);
+    }
+}
@@ -0,0 +1,150 @@
+// Startup integrity check: the stored manifest is compared with a fresh scan
+// of the chunk files, which is the source of truth after an unclean shutdown.
+// What happens on a mismatch is the operator's choice of `IntegrityMode`.
+
+use std::path::Path;
+use anyhow::{bail, Result};
+use crate::storage::diff::{diff_manifests, ChunkDiff};
+use crate::storage::manifest::{Manifest, MANIFEST_FILE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntegrityMode {
+    /// Refuse to start if anything is inconsistent.
+    Strict,
+    /// Rebuild the manifest from the chunk files and save it.
+    Repair,
+    /// Log problems and continue with the stored manifest.
+    #[default]
+    Ignore,
+}
+
+/// Differences between the stored manifest (`a`) and the files on disk (`b`).
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport {
+    /// Set when there was no manifest or it could not be parsed.
+    pub manifest_problem: Option<String>,
+    pub diff: ChunkDiff,
+    /// A temp manifest left behind by an interrupted save.
+    pub stale_tmp: bool,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.manifest_problem.is_none() && self.diff.is_consistent() && !self.stale_tmp
+    }
+
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(p) = &self.manifest_problem {
+            parts.push(p.clone());
+        }
+        if !self.diff.only_in_a.is_empty() {
+            parts.push(format!("{} chunks missing on disk ({})", self.diff.only_in_a.len(), self.diff.only_in_a.join(", ")));
+        }
+        if !self.diff.only_in_b.is_empty() {
+            parts.push(format!("{} chunks not in manifest ({})", self.diff.only_in_b.len(), self.diff.only_in_b.join(", ")));
+        }
+        if !self.diff.divergent.is_empty() {
+            let files: Vec<&str> = self.diff.divergent.iter().map(|d| d.file.as_str()).collect();
+            parts.push(format!("{} chunks changed since recorded ({})", files.len(), files.join(", ")));
+        }
+        if self.stale_tmp {
+            parts.push("stale temporary manifest".to_string());
+        }
+        parts.join("; ")
+    }
+}
+
+/// Load the manifest for `chunk_dir`, check it against the chunk files and
+/// apply `mode`. `persist` is false for read-only stores, where repairs stay
+/// in memory.
+pub fn open_manifest(chunk_dir: &Path, mode: IntegrityMode, persist: bool) -> Result<(Manifest, IntegrityReport)> {
+    check(chunk_dir, Manifest::scan(chunk_dir)?, mode, persist)
+}
+
+/// Like [`open_manifest`] for chunk files kept elsewhere (see
+/// [`crate::storage::backend`]), given the manifest `scanned` from them.
+pub fn check(chunk_dir: &Path, scanned: Manifest, mode: IntegrityMode, persist: bool) -> Result<(Manifest, IntegrityReport)> {
+    let mut report = IntegrityReport {
+        stale_tmp: chunk_dir.join(format!("{}.tmp", MANIFEST_FILE)).exists(),
+        ..Default::default()
+    };
+    let stored = match Manifest::load(chunk_dir) {
+        Ok(Some(m)) => Some(m),
+        Ok(None) => {
+            // a fresh directory has nothing to be inconsistent with
+            if !scanned.chunks.is_empty() {
+                report.manifest_problem = Some("manifest missing".to_string());
+            }
+            None
+        }
+        Err(e) => {
+            report.manifest_problem = Some(format!("manifest unreadable: {}", e));
+            None
+        }
+    };
+    if let Some(m) = &stored {
+        report.diff = diff_manifests(m, &scanned);
+    }
+    if report.is_clean() {
+        return Ok((stored.unwrap_or(scanned), report));
+    }
+
+    match mode {
+        IntegrityMode::Strict => bail!("integrity check failed for {}: {}", chunk_dir.display(), report.summary()),
+        IntegrityMode::Repair => {
+            tracing::warn!("repairing {}: {}", chunk_dir.display(), report.summary());
+            let mut scanned = scanned;
+            // keep recorded metadata (such as archive block indexes) for files that still match
+            if let Some(stored) = &stored {
+                for (name, meta) in scanned.chunks.iter_mut() {
+                    if let Some(old) = stored.chunks.get(name).filter(|o| o.crc32 == meta.crc32 && o.bytes == meta.bytes) {
+                        *meta = old.clone();
+                    }
+                }
+                // pending deletes are not recorded in the files
+                scanned.tombstones = stored.tombstones.clone();
+            }
+            if persist {
+                let tmp = chunk_dir.join(format!("{}.tmp", MANIFEST_FILE));
+                std::fs::write(&tmp, scanned.to_bytes()?)?;
+                std::fs::rename(&tmp, chunk_dir.join(MANIFEST_FILE))?;
+            }
+            Ok((scanned, report))
+        }
+        IntegrityMode::Ignore => {
+            tracing::warn!("ignoring integrity problems in {}: {}", chunk_dir.display(), report.summary());
+            Ok((stored.unwrap_or(scanned), report))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(tag: &str) -> crate::test_util::TempDir {
+        let dir = crate::test_util::TempDir::new(&format!("integrity-{}", tag));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn modes_handle_untracked_chunk() {
+        let dir = temp_dir("modes");
+        std::fs::write(dir.join("A-1.ndjson"), "{\"station_id\":\"A\",\"time\":\"2025-01-01T00:00:00Z\"}\n").unwrap();
+        std::fs::write(dir.join(MANIFEST_FILE), Manifest::default().to_bytes().unwrap()).unwrap();
+
+        assert!(open_manifest(&dir, IntegrityMode::Strict, true).is_err());
+
+        let (m, report) = open_manifest(&dir, IntegrityMode::Ignore, true).unwrap();
+        assert!(m.chunks.is_empty());
+        assert_eq!(report.diff.only_in_b, vec!["A-1.ndjson".to_string()]);
+
+        let (m, _) = open_manifest(&dir, IntegrityMode::Repair, true).unwrap();
+        assert_eq!(m.chunks.len(), 1);
+        let (_, report) = open_manifest(&dir, IntegrityMode::Strict, true).unwrap();
+        assert!(report.is_clean());
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use crate::storage::memtable::Observation;
+
+/// Newest observation per station, kept up to date by the write path so the
+/// latest-value endpoint doesn't have to touch chunk files.
+#[derive(Debug, Default)]
+pub struct LastValues {
+    by_station: HashMap<String, Observation>,
+}
+
+/// Whether `a` is strictly newer than `b`.
+pub fn is_newer(a: &Observation, b: &Observation) -> bool {
+    a.time > b.time
+}
+
+impl LastValues {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `obs` unless what is cached for its station is newer, so late
+    /// out-of-order writes never move the latest value backwards while a
+    /// rewrite of the same time replaces it (last write wins).
+    pub fn observe(&mut self, obs: &Observation) {
+        match self.by_station.get(&obs.station_id) {
+            Some(cur) if is_newer(cur, obs) => {}
+            _ => {
+                self.by_station.insert(obs.station_id.clone(), obs.clone());
+            }
+        }
+    }
+
+    /// Stations with a cached value.
+    pub fn len(&self) -> usize {
+        self.by_station.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_station.is_empty()
+    }
+
+    pub fn get(&self, station_id: &str) -> Option<&Observation> {
+        self.by_station.get(station_id)
+    }
+
+    pub fn remove(&mut self, station_id: &str) {
+        self.by_station.remove(station_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.by_station.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_newest_by_time() {
+        let mut lv = LastValues::new();
+        lv.observe(&Observation::empty("A", "2025-01-01T10:00:00Z".parse().unwrap()));
+        lv.observe(&Observation::empty("A", "2025-01-01T09:00:00Z".parse().unwrap()));
+        assert_eq!(lv.get("A").unwrap().time.to_string(), "2025-01-01T10:00:00Z");
+        // offsets are compared as instants, not strings
+        lv.observe(&Observation::empty("A", "2025-01-01T19:30:00+08:00".parse().unwrap()));
+        assert_eq!(lv.get("A").unwrap().time.to_string(), "2025-01-01T11:30:00Z");
+        assert!(lv.get("B").is_none());
+    }
+}
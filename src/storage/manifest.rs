@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::storage::archive::{self, BlockMeta};
+use crate::storage::{checksum, columnar};
+use crate::storage::memtable::Observation;
+use crate::storage::tombstone::Tombstone;
+
+pub const MANIFEST_FILE: &str = "MANIFEST.json";
+/// File name prefix of chunks shared by several low-volume stations.
+pub const PACKED_PREFIX: &str = "_packed-";
+
+/// One station's contiguous run of rows inside a chunk file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StationSlice {
+    pub station_id: String,
+    pub rows: usize,
+    pub min_time: Option<String>,
+    pub max_time: Option<String>,
+    /// Byte range of the run within the file.
+    pub offset: u64,
+    pub len: u64,
+    /// CRC32 of the byte range, so reading one station's rows of a packed
+    /// chunk is checked without reading the whole file; absent for chunks
+    /// recorded before it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<u32>,
+}
+
+/// Metadata recorded for every chunk file, keyed by file name in [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkMeta {
+    /// Empty for packed chunks, which list their stations in `stations`.
+    pub station_id: String,
+    pub rows: usize,
+    pub bytes: u64,
+    pub crc32: u32,
+    pub min_time: Option<String>,
+    pub max_time: Option<String>,
+    /// Frame index of yearly archive chunks; empty for raw chunks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocks: Vec<BlockMeta>,
+    /// Station index of packed chunks; empty for single-station chunks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stations: Vec<StationSlice>,
+    /// Epoch milliseconds of the newest write the chunk holds. Duplicate
+    /// points are settled in favour of the chunk written last; 0 for chunks
+    /// recorded before this was tracked, which then go by name.
+    #[serde(default)]
+    pub written: i64,
+}
+
+impl ChunkMeta {
+    /// Build metadata for a chunk from its rows and the exact bytes on disk.
+    pub fn from_contents(station_id: &str, obs: &[Observation], data: &[u8]) -> Self {
+        Self {
+            station_id: station_id.to_string(),
+            rows: obs.len(),
+            bytes: data.len() as u64,
+            crc32: crc32fast::hash(data),
+            min_time: obs.iter().map(|o| o.time).min().map(|t| t.to_string()),
+            max_time: obs.iter().map(|o| o.time).max().map(|t| t.to_string()),
+            blocks: Vec::new(),
+            stations: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Metadata for an NDJSON chunk whose `i`th row occupies the byte range
+    /// `spans[i]` of `data`. Rows from more than one station make a packed
+    /// chunk indexed by station; `station_id` labels an empty chunk.
+    fn from_ndjson(station_id: &str, obs: &[Observation], spans: &[(u64, u64)], data: &[u8]) -> Self {
+        let single = obs.first().map_or(station_id, |o| o.station_id.as_str());
+        let mut meta = Self::from_contents(single, obs, data);
+        if obs.iter().all(|o| o.station_id == single) {
+            return meta;
+        }
+        meta.station_id = String::new();
+        let mut start = 0;
+        while start < obs.len() {
+            let id = &obs[start].station_id;
+            let end = obs[start..].iter().position(|o| &o.station_id != id).map_or(obs.len(), |n| start + n);
+            let run = &obs[start..end];
+            meta.stations.push(StationSlice {
+                station_id: id.clone(),
+                rows: run.len(),
+                min_time: run.iter().map(|o| o.time).min().map(|t| t.to_string()),
+                max_time: run.iter().map(|o| o.time).max().map(|t| t.to_string()),
+                offset: spans[start].0,
+                len: spans[end - 1].0 + spans[end - 1].1 - spans[start].0,
+                crc32: None,
+            });
+            let slice = meta.stations.last_mut().unwrap();
+            slice.crc32 = Some(crc32fast::hash(&data[slice.offset as usize..(slice.offset + slice.len) as usize]));
+            start = end;
+        }
+        meta
+    }
+
+    pub fn is_packed(&self) -> bool {
+        !self.stations.is_empty()
+    }
+
+    /// Per-station runs; a single-station chunk is one run covering the file.
+    pub fn slices(&self) -> Vec<StationSlice> {
+        if self.is_packed() {
+            return self.stations.clone();
+        }
+        vec![StationSlice {
+            station_id: self.station_id.clone(),
+            rows: self.rows,
+            min_time: self.min_time.clone(),
+            max_time: self.max_time.clone(),
+            offset: 0,
+            len: self.bytes,
+            crc32: None,
+        }]
+    }
+
+    pub fn slices_for(&self, station_id: &str) -> Vec<StationSlice> {
+        self.slices().into_iter().filter(|s| s.station_id == station_id).collect()
+    }
+}
+
+/// Serialize rows as NDJSON with a checksum footer and describe the result.
+/// Rows should be grouped by station so each station of a packed chunk is
+/// one contiguous run.
+pub fn encode_ndjson(station_id: &str, obs: &[Observation]) -> Result<(Vec<u8>, ChunkMeta)> {
+    let mut data = Vec::new();
+    let mut spans = Vec::with_capacity(obs.len());
+    for o in obs {
+        let start = data.len() as u64;
+        serde_json::to_writer(&mut data, o)?;
+        data.push(b'\n');
+        spans.push((start, data.len() as u64 - start));
+    }
+    checksum::seal_ndjson(&mut data, obs.len());
+    let meta = ChunkMeta::from_ndjson(station_id, obs, &spans, &data);
+    Ok((data, meta))
+}
+
+/// Encode rows for the chunk file `name`: a block chunk for `.block` files,
+/// NDJSON otherwise.
+pub fn encode_chunk(name: &str, station_id: &str, obs: &[Observation]) -> Result<(Vec<u8>, ChunkMeta)> {
+    match columnar::is_block(name) {
+        true => columnar::encode(station_id, obs),
+        false => encode_ndjson(station_id, obs),
+    }
+}
+
+/// Per-station summary derived from the manifest.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StationInfo {
+    pub station_id: String,
+    pub first_time: Option<String>,
+    pub last_time: Option<String>,
+    pub points: usize,
+    pub chunks: usize,
+}
+
+impl StationInfo {
+    pub fn new(station_id: &str) -> Self {
+        Self { station_id: station_id.to_string(), first_time: None, last_time: None, points: 0, chunks: 0 }
+    }
+
+    /// Widen the time span to cover `[min, max]` and add `rows` points.
+    pub fn extend(&mut self, rows: usize, min: Option<&String>, max: Option<&String>) {
+        self.points += rows;
+        if let Some(min) = min {
+            if self.first_time.as_ref().is_none_or(|f| min < f) {
+                self.first_time = Some(min.clone());
+            }
+        }
+        if let Some(max) = max {
+            if self.last_time.as_ref().is_none_or(|l| max > l) {
+                self.last_time = Some(max.clone());
+            }
+        }
+    }
+}
+
+/// Index of all chunk files in a chunk directory, persisted as `MANIFEST.json`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub chunks: BTreeMap<String, ChunkMeta>,
+    /// Range deletes whose rows may still be in the chunk files.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tombstones: Vec<Tombstone>,
+}
+
+impl Manifest {
+    /// Load the manifest stored in `chunk_dir`, if there is one.
+    pub fn load(chunk_dir: &Path) -> Result<Option<Self>> {
+        let path = chunk_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(&path)?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Rebuild a manifest by reading every chunk file in `chunk_dir` and
+    /// checksumming its actual contents.
+    pub fn scan(chunk_dir: &Path) -> Result<Self> {
+        let mut manifest = Manifest::default();
+        for entry in std::fs::read_dir(chunk_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().unwrap_or_default();
+            if !is_chunk_file(&name) {
+                continue;
+            }
+            let data = std::fs::read(entry.path())?;
+            let meta = Self::describe(&name, &data);
+            manifest.chunks.insert(name, meta);
+        }
+        Ok(manifest)
+    }
+
+    /// Metadata of chunk file `name` from its actual contents.
+    pub fn describe(name: &str, data: &[u8]) -> ChunkMeta {
+        let fallback = name.split('-').next().unwrap_or_default().to_string();
+        if archive::is_archive(name) || columnar::is_block(name) {
+            // archive block indexes are not recovered by a scan; they are only an optimisation
+            let obs = parse_chunk(name, data);
+            ChunkMeta::from_contents(obs.first().map_or(&fallback, |o| &o.station_id), &obs, data)
+        } else {
+            let (obs, spans) = parse_rows_with_spans(data);
+            ChunkMeta::from_ndjson(&fallback, &obs, &spans, data)
+        }
+    }
+
+    /// Summarise chunks by station without touching the chunk files.
+    pub fn stations(&self) -> BTreeMap<String, StationInfo> {
+        let mut out: BTreeMap<String, StationInfo> = BTreeMap::new();
+        for meta in self.chunks.values() {
+            let mut seen: Vec<String> = Vec::new();
+            for slice in meta.slices() {
+                let info = out.entry(slice.station_id.clone()).or_insert_with(|| StationInfo::new(&slice.station_id));
+                if !seen.contains(&slice.station_id) {
+                    info.chunks += 1;
+                    seen.push(slice.station_id.clone());
+                }
+                info.extend(slice.rows, slice.min_time.as_ref(), slice.max_time.as_ref());
+            }
+        }
+        out
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+pub fn is_chunk_file(name: &str) -> bool {
+    name.ends_with(".ndjson") || archive::is_archive(name) || columnar::is_block(name)
+}
+
+/// Rows of a chunk file, decompressing yearly archives and decoding block
+/// chunks. A damaged archive or block reads as empty, like unparseable
+/// NDJSON lines; chunk reads and `inspect` check the file first to report
+/// the damage instead.
+pub(crate) fn parse_chunk(name: &str, data: &[u8]) -> Vec<Observation> {
+    if columnar::is_block(name) {
+        return columnar::decode(checksum::body(name, data)).unwrap_or_default();
+    }
+    if archive::is_archive(name) {
+        return archive::decompress(data).map(|raw| parse_rows(&raw)).unwrap_or_default();
+    }
+    parse_rows(data)
+}
+
+/// Parse NDJSON rows along with the byte range of each parsed line.
+fn parse_rows_with_spans(data: &[u8]) -> (Vec<Observation>, Vec<(u64, u64)>) {
+    let mut obs = Vec::new();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        if let Ok(o) = serde_json::from_slice::<Observation>(text) {
+            obs.push(o);
+            spans.push((start as u64, line.len() as u64));
+        }
+        start += line.len();
+    }
+    (obs, spans)
+}
+
+pub(crate) fn parse_rows(data: &[u8]) -> Vec<Observation> {
+    data.split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| serde_json::from_slice::<Observation>(line).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(station: &str, rows: usize, min: &str, max: &str) -> ChunkMeta {
+        ChunkMeta {
+            station_id: station.into(),
+            rows,
+            bytes: 0,
+            crc32: 0,
+            min_time: Some(min.into()),
+            max_time: Some(max.into()),
+            blocks: Vec::new(),
+            stations: Vec::new(),
+            written: 0,
+        }
+    }
+
+    #[test]
+    fn indexes_packed_chunks_by_station() {
+        let rows: Vec<Observation> = [("A", "2025-01-01T00:00:00Z"), ("A", "2025-01-01T01:00:00Z"), ("B", "2025-01-01T00:30:00Z")]
+            .iter()
+            .map(|(s, t)| Observation::empty(*s, t.parse().unwrap()))
+            .collect();
+        let (data, meta) = encode_ndjson("", &rows).unwrap();
+        assert!(meta.is_packed());
+        assert_eq!(meta.rows, 3);
+        let b = &meta.slices_for("B")[0];
+        assert_eq!((b.rows, b.min_time.as_deref()), (1, Some("2025-01-01T00:30:00Z")));
+        let slice = &data[b.offset as usize..(b.offset + b.len) as usize];
+        assert_eq!(parse_rows(slice)[0].station_id, "B");
+        let (obs, spans) = parse_rows_with_spans(&data);
+        assert_eq!(ChunkMeta::from_ndjson("", &obs, &spans, &data), meta);
+
+        let (_, single) = encode_ndjson("A", &rows[..2]).unwrap();
+        assert!(!single.is_packed());
+        assert_eq!(single.slices_for("A")[0].len, single.bytes);
+    }
+
+    #[test]
+    fn summarises_stations() {
+        let mut m = Manifest::default();
+        m.chunks.insert("A-1.ndjson".into(), meta("A", 3, "2025-01-02T00:00:00Z", "2025-01-02T05:00:00Z"));
+        m.chunks.insert("A-2.ndjson".into(), meta("A", 2, "2025-01-01T00:00:00Z", "2025-01-01T01:00:00Z"));
+        m.chunks.insert("B-1.ndjson".into(), meta("B", 1, "2025-01-03T00:00:00Z", "2025-01-03T00:00:00Z"));
+        let mut packed = meta("", 2, "2025-01-04T00:00:00Z", "2025-01-04T00:00:00Z");
+        packed.stations = vec![
+            StationSlice { station_id: "B".into(), rows: 1, min_time: packed.min_time.clone(), max_time: packed.max_time.clone(), offset: 0, len: 1, crc32: None },
+            StationSlice { station_id: "C".into(), rows: 1, min_time: packed.min_time.clone(), max_time: packed.max_time.clone(), offset: 1, len: 1, crc32: None },
+        ];
+        m.chunks.insert("_packed-1.ndjson".into(), packed);
+        let s = m.stations();
+        assert_eq!(s.len(), 3);
+        assert_eq!((s["B"].points, s["B"].chunks), (2, 2));
+        assert_eq!(s["C"].last_time.as_deref(), Some("2025-01-04T00:00:00Z"));
+        assert_eq!(s["A"].points, 5);
+        assert_eq!(s["A"].chunks, 2);
+        assert_eq!(s["A"].first_time.as_deref(), Some("2025-01-01T00:00:00Z"));
+        assert_eq!(s["A"].last_time.as_deref(), Some("2025-01-02T05:00:00Z"));
+    }
+}
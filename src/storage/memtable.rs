@@ -1,34 +1,328 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
+use crate::storage::tombstone::Tombstone;
+use crate::storage::wal::SegmentPin;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Milliseconds since the Unix epoch, UTC. Written out as RFC3339 (whole
+/// seconds unless there is a fraction); read from RFC3339 with any offset or
+/// from an integer number of milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub i64);
+
+impl Timestamp {
+    /// Parse an RFC3339 time such as `2025-01-02T10:00:00Z` or `2025-01-02T18:00:00+08:00`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let t = DateTime::parse_from_rfc3339(s.trim())
+            .map_err(|e| anyhow!("invalid time '{}': {} (expected RFC3339 such as 2025-01-02T10:00:00Z)", s, e))?;
+        Ok(Self::from_datetime(t.with_timezone(&Utc)))
+    }
+
+    pub fn from_datetime(t: DateTime<Utc>) -> Self {
+        Self(t.timestamp_millis())
+    }
+
+    /// Milliseconds outside chrono's range clamp to its limits.
+    pub fn to_datetime(self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.0)
+            .unwrap_or(if self.0 < 0 { DateTime::<Utc>::MIN_UTC } else { DateTime::<Utc>::MAX_UTC })
+    }
+
+    pub fn millis(self) -> i64 {
+        self.0
+    }
+
+    /// Whole seconds since the epoch, rounded down.
+    pub fn secs(self) -> i64 {
+        self.0.div_euclid(1000)
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = if self.0.rem_euclid(1000) == 0 { SecondsFormat::Secs } else { SecondsFormat::Millis };
+        f.write_str(&self.to_datetime().to_rfc3339_opts(format, true))
+    }
+}
+
+impl std::str::FromStr for Timestamp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Millis(i64),
+            Text(String),
+        }
+        match Repr::deserialize(d)? {
+            Repr::Millis(ms) if DateTime::from_timestamp_millis(ms).is_some() => Ok(Self(ms)),
+            Repr::Millis(ms) => Err(serde::de::Error::custom(format!("timestamp {} ms is out of range", ms))),
+            Repr::Text(s) => Self::parse(&s).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// A field value. Numbers cover both integer and float readings; `Null`
+/// records that the sensor explicitly reported no data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Number(f64),
+    Bool(bool),
+    Text(String),
+    Null,
+}
+
+impl FieldValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Number(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Numbers and explicit nulls; booleans and text are skipped by numeric aggregation.
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, FieldValue::Number(_) | FieldValue::Null)
+    }
+}
+
+/// One reading of a station: free-form string tags and named field values.
+///
+/// Rows are stored as `{"station_id", "time", "tags", "fields"}`. Rows
+/// written before fields were generic kept the weather columns at the top
+/// level with a `nulls` bitmap; they are still read (see [`LegacyRow`]).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "LegacyRow")]
 pub struct Observation {
     pub station_id: String,
-    pub time: String,
-    pub temp: Option<f64>,
-    pub humidity: Option<f64>,
-    pub pressure: Option<f64>,
-    pub wind_speed: Option<f64>,
-    pub wind_dir: Option<u16>,
+    pub time: Timestamp,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldValue>,
+}
+
+/// Either row layout: `fields` for current rows, the top-level weather
+/// columns plus `nulls` for old ones. Bit `i` of `nulls` marks
+/// `LEGACY_FIELDS[i]` as an explicit null; a null with its bit clear was
+/// never sent.
+#[derive(Deserialize)]
+struct LegacyRow {
+    station_id: String,
+    time: Timestamp,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    fields: Option<BTreeMap<String, FieldValue>>,
+    temp: Option<f64>,
+    humidity: Option<f64>,
+    pressure: Option<f64>,
+    wind_speed: Option<f64>,
+    wind_dir: Option<f64>,
+    #[serde(default)]
+    nulls: u8,
+}
+
+impl From<LegacyRow> for Observation {
+    fn from(row: LegacyRow) -> Self {
+        let mut obs = Observation { station_id: row.station_id, time: row.time, tags: row.tags, fields: BTreeMap::new() };
+        if let Some(fields) = row.fields {
+            obs.fields = fields;
+            return obs;
+        }
+        let values = [row.temp, row.humidity, row.pressure, row.wind_speed, row.wind_dir];
+        for (i, (name, value)) in Observation::LEGACY_FIELDS.iter().zip(values).enumerate() {
+            match value {
+                Some(v) => obs.set_field(*name, v),
+                None if row.nulls & (1 << i) != 0 => obs.set_null(*name),
+                None => {}
+            }
+        }
+        obs
+    }
+}
+
+impl Observation {
+    /// The weather fields that had fixed columns before fields were generic;
+    /// write requests still accept them at the top level.
+    pub const LEGACY_FIELDS: [&'static str; 5] = ["temp", "humidity", "pressure", "wind_speed", "wind_dir"];
+
+    /// Set a field, replacing any earlier value. Wind directions are
+    /// normalised to whole degrees in `0..360`.
+    pub fn set(&mut self, name: impl Into<String>, value: FieldValue) {
+        let name = name.into();
+        let value = match value {
+            FieldValue::Number(v) if name == "wind_dir" => FieldValue::Number(v.rem_euclid(360.0).round() % 360.0),
+            v => v,
+        };
+        self.fields.insert(name, value);
+    }
+
+    /// Set a numeric field.
+    pub fn set_field(&mut self, name: impl Into<String>, value: f64) {
+        self.set(name, FieldValue::Number(value));
+    }
+
+    /// Mark a field as explicitly reported null, clearing any value.
+    pub fn set_null(&mut self, name: impl Into<String>) {
+        self.set(name, FieldValue::Null);
+    }
+
+    /// The numeric value of a field; `None` when it is missing, null or not a number.
+    pub fn number(&self, name: &str) -> Option<f64> {
+        self.fields.get(name).and_then(FieldValue::as_f64)
+    }
+
+    /// An observation with no tags or field values yet.
+    pub fn empty(station_id: impl Into<String>, time: Timestamp) -> Self {
+        Self { station_id: station_id.into(), time, tags: BTreeMap::new(), fields: BTreeMap::new() }
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.time.to_datetime()
+    }
+}
+
+/// Drop all but the last of the rows with the same station within a run of
+/// consecutive rows with the same time; the kept row takes the place of the
+/// first. With rows sorted by time and, within a time, in write order, this
+/// is last-write-wins even when stations interleave. Returns how many rows
+/// were dropped.
+pub fn dedup_last(rows: &mut Vec<Observation>) -> usize {
+    let before = rows.len();
+    let mut out: Vec<Observation> = Vec::with_capacity(before);
+    for o in rows.drain(..) {
+        match out.iter_mut().rev().take_while(|last| last.time == o.time).find(|last| last.station_id == o.station_id) {
+            Some(last) => *last = o,
+            None => out.push(o),
+        }
+    }
+    *rows = out;
+    before - rows.len()
 }
 
 #[derive(Debug, Default)]
 pub struct MemTable {
     // keyed by station_id -> vector of observations
     pub buffer: HashMap<String, Vec<Observation>>,
+    /// Keeps the WAL segments holding the buffered rows until they are flushed.
+    pub wal_pin: Option<SegmentPin>,
 }
 
 impl MemTable {
     pub fn new() -> Self {
-        Self { buffer: HashMap::new() }
+        Self { buffer: HashMap::new(), wal_pin: None }
+    }
+
+    /// Take the buffered rows and the pin on their WAL segments.
+    pub fn drain(&mut self) -> (HashMap<String, Vec<Observation>>, Option<SegmentPin>) {
+        (std::mem::take(&mut self.buffer), self.wal_pin.take())
+    }
+
+    /// Hold on to `pin` if it covers older segments than the current one.
+    pub fn retain_pin(&mut self, pin: SegmentPin) {
+        self.wal_pin = Some(SegmentPin::min(self.wal_pin.take(), pin));
     }
 
     pub fn insert(&mut self, obs: Observation) {
         self.buffer.entry(obs.station_id.clone()).or_default().push(obs);
     }
 
+    /// Drop a station's buffered rows at `times`; returns how many went.
+    pub fn remove_points(&mut self, station_id: &str, times: &BTreeSet<Timestamp>) -> usize {
+        let Some(rows) = self.buffer.get_mut(station_id) else { return 0 };
+        let before = rows.len();
+        rows.retain(|o| !times.contains(&o.time));
+        before - rows.len()
+    }
+
+    /// Drop the buffered rows a range delete covers; returns how many went.
+    pub fn remove_range(&mut self, tombstone: &Tombstone) -> usize {
+        let Some(rows) = self.buffer.get_mut(&tombstone.station_id) else { return 0 };
+        let before = rows.len();
+        rows.retain(|o| !tombstone.covers(o));
+        before - rows.len()
+    }
+
     // Placeholder for flush logic
     pub fn flush(&mut self) {
         self.buffer.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_markers_are_distinct_from_missing() {
+        let mut o = Observation::empty("A", "2025-01-01T00:00:00Z".parse().unwrap());
+        o.set_field("temp", 12.0);
+        o.set_null("humidity");
+        o.set("soil_moisture", FieldValue::Number(0.31));
+        o.tags.insert("sensor".into(), "probe-2".into());
+
+        let json = serde_json::to_string(&o).unwrap();
+        let back: Observation = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, o);
+        assert_eq!(back.fields.get("humidity"), Some(&FieldValue::Null));
+        assert_eq!(back.fields.get("pressure"), None);
+        assert_eq!(back.number("soil_moisture"), Some(0.31));
+    }
+
+    #[test]
+    fn reads_legacy_rows() {
+        let row = r#"{"station_id":"A","time":"2025-01-01T00:00:00Z","temp":12.0,"humidity":null,"pressure":null,"wind_speed":null,"wind_dir":365,"nulls":2}"#;
+        let o: Observation = serde_json::from_str(row).unwrap();
+        assert_eq!(o.number("temp"), Some(12.0));
+        assert_eq!(o.fields.get("humidity"), Some(&FieldValue::Null));
+        assert!(!o.fields.contains_key("pressure"));
+        assert_eq!(o.number("wind_dir"), Some(5.0));
+        assert!(serde_json::to_string(&o).unwrap().contains(r#""fields":{"humidity":null,"temp":12.0,"wind_dir":5.0}"#));
+    }
+
+    #[test]
+    fn dedup_keeps_the_last_write() {
+        let at = |station: &str, t: &str, temp: f64| {
+            let mut o = Observation::empty(station, t.parse().unwrap());
+            o.set_field("temp", temp);
+            o
+        };
+        let mut rows = vec![
+            at("A", "2025-01-01T00:00:00Z", 1.0),
+            at("A", "2025-01-01T00:00:00Z", 2.0),
+            at("B", "2025-01-01T00:00:00Z", 3.0),
+            // a later write of A after B's row at the same time
+            at("A", "2025-01-01T00:00:00Z", 5.0),
+            at("B", "2025-01-01T00:01:00Z", 4.0),
+        ];
+        assert_eq!(dedup_last(&mut rows), 2);
+        assert_eq!(rows.iter().map(|o| o.number("temp").unwrap()).collect::<Vec<_>>(), vec![5.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn timestamps_normalise_to_utc_millis() {
+        let t = Timestamp::parse("2025-01-01T08:00:00+08:00").unwrap();
+        assert_eq!(t.millis(), 1_735_689_600_000);
+        assert_eq!(t.to_string(), "2025-01-01T00:00:00Z");
+        assert_eq!(Timestamp(1_735_689_600_250).to_string(), "2025-01-01T00:00:00.250Z");
+        assert_eq!(serde_json::from_str::<Timestamp>("1735689600000").unwrap(), t);
+        assert_eq!(serde_json::to_string(&t).unwrap(), "\"2025-01-01T00:00:00Z\"");
+        let err = Timestamp::parse("yesterday").unwrap_err().to_string();
+        assert!(err.contains("invalid time 'yesterday'"), "{}", err);
+    }
+}
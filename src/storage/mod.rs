@@ -1,7 +1,25 @@
 pub mod memtable;
 pub mod wal;
 pub mod chunk_store;
+pub mod manifest;
+pub mod diff;
+pub mod retention;
+pub mod rollup;
+pub mod last_values;
+pub mod archive;
+pub mod integrity;
+pub mod checksum;
+pub mod columnar;
+pub mod fragmentation;
+pub mod tombstone;
+pub mod backend;
+pub mod tiering;
+pub mod dir_lock;
 
 pub use memtable::MemTable;
 pub use wal::WAL;
 pub use chunk_store::ChunkStore;
+pub use manifest::{ChunkMeta, Manifest};
+pub use rollup::RollupStore;
+pub use last_values::LastValues;
+pub use dir_lock::DirLock;
@@ -0,0 +1,246 @@
+use std::time::Duration;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::ingest::routing::TenantRouter;
+use crate::storage::{ChunkStore, Manifest};
+use crate::units;
+
+/// How long chunk data is kept before the retention worker deletes it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Maximum age of a chunk's newest observation; `None` keeps data forever.
+    #[serde(alias = "max_age", deserialize_with = "units::opt_secs")]
+    pub max_age_secs: Option<u64>,
+    /// How often the background worker checks for expired chunks.
+    #[serde(alias = "check_interval", deserialize_with = "units::secs")]
+    pub check_interval_secs: u64,
+    /// Overrides for stations matching a pattern; the first match wins over
+    /// both tenant retention and `max_age`.
+    pub stations: Vec<StationRetention>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self { max_age_secs: None, check_interval_secs: 3600, stations: Vec::new() }
+    }
+}
+
+/// Retention for the stations whose id matches `pattern`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StationRetention {
+    /// Station ids to match; `*` stands for any run of characters, e.g. `DEMO*`.
+    pub pattern: String,
+    /// Maximum age of these stations' data; `"forever"` keeps it.
+    #[serde(alias = "max_age", deserialize_with = "age_or_forever")]
+    pub max_age_secs: Option<u64>,
+}
+
+fn age_or_forever<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    let value = toml::Value::deserialize(d)?;
+    if value.as_str() == Some("forever") {
+        return Ok(None);
+    }
+    units::opt_secs(value).map_err(serde::de::Error::custom)
+}
+
+/// Whether `station_id` matches `pattern`, where `*` matches any run of characters.
+pub(crate) fn matches(pattern: &str, station_id: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = station_id.strip_prefix(first) else { return false };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else { return rest.is_empty() };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl RetentionPolicy {
+    /// Override with `SKYPULSE_RETENTION_DAYS` and `SKYPULSE_RETENTION_CHECK_SECS`;
+    /// both take a unit too, e.g. `12w` or `30m`.
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Some(age) = units::env_secs("SKYPULSE_RETENTION_DAYS", "d")? {
+            self.max_age_secs = Some(age);
+        }
+        if let Some(every) = units::env_secs("SKYPULSE_RETENTION_CHECK_SECS", "s")? {
+            self.check_interval_secs = every;
+        }
+        Ok(())
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs.max(1))
+    }
+
+    /// The override of the first pattern `station_id` matches, if any;
+    /// `Some(None)` keeps the station's data forever.
+    pub fn station_override(&self, station_id: &str) -> Option<Option<u64>> {
+        self.stations.iter().find(|s| matches(&s.pattern, station_id)).map(|s| s.max_age_secs)
+    }
+
+    /// Whether any data can expire under the policy itself, tenants aside.
+    pub fn expires_anything(&self) -> bool {
+        self.max_age_secs.is_some() || self.stations.iter().any(|s| s.max_age_secs.is_some())
+    }
+
+    /// Oldest `max_time` that is still retained at `now`.
+    pub fn cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        cutoff(now, self.max_age_secs?)
+    }
+}
+
+fn cutoff(now: DateTime<Utc>, max_age_secs: u64) -> Option<DateTime<Utc>> {
+    let secs = i64::try_from(max_age_secs).ok()?;
+    now.checked_sub_signed(chrono::Duration::seconds(secs))
+}
+
+/// Chunk files whose newest observation is older than the max age returned by
+/// `max_age_for(station_id)` (`None` keeps the station's data forever).
+/// Chunks without a parseable `max_time` are never expired, and a packed chunk
+/// only expires once every station in it has.
+pub fn expired_chunks(manifest: &Manifest, now: DateTime<Utc>, max_age_for: impl Fn(&str) -> Option<u64>) -> Vec<String> {
+    manifest
+        .chunks
+        .iter()
+        .filter(|(_, meta)| {
+            meta.slices().iter().all(|slice| {
+                let Some(cutoff) = max_age_for(&slice.station_id).and_then(|age| cutoff(now, age)) else { return false };
+                slice
+                    .max_time
+                    .as_deref()
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .is_some_and(|t| t.with_timezone(&Utc) < cutoff)
+            })
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Delete every chunk that has aged out under `policy`, with per-station
+/// overrides from its station patterns, then from tenant routing; returns
+/// the deleted file names.
+pub async fn enforce(
+    store: &ChunkStore,
+    policy: &RetentionPolicy,
+    router: Option<&TenantRouter>,
+    now: DateTime<Utc>,
+) -> Result<Vec<String>> {
+    let _maintenance = store.maintenance().await;
+    let overrides = match router {
+        Some(r) => r.retention_overrides().await,
+        None => Default::default(),
+    };
+    let expired = expired_chunks(&store.manifest().await, now, |station| {
+        policy.station_override(station).unwrap_or_else(|| overrides.get(station).copied().or(policy.max_age_secs))
+    });
+    if !expired.is_empty() {
+        store.delete_chunks(&expired).await?;
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ChunkMeta;
+    use crate::storage::memtable::Observation;
+
+    fn meta(max_time: Option<&str>) -> ChunkMeta {
+        station_meta("s", max_time)
+    }
+
+    fn station_meta(station_id: &str, max_time: Option<&str>) -> ChunkMeta {
+        ChunkMeta {
+            station_id: station_id.into(),
+            rows: 1,
+            bytes: 1,
+            crc32: 0,
+            min_time: None,
+            max_time: max_time.map(String::from),
+            blocks: Vec::new(),
+            stations: Vec::new(),
+            written: 0,
+        }
+    }
+
+    #[test]
+    fn expires_only_old_dated_chunks() {
+        let mut m = Manifest::default();
+        m.chunks.insert("old".into(), meta(Some("2024-01-01T00:00:00Z")));
+        m.chunks.insert("new".into(), meta(Some("2025-06-01T00:00:00+08:00")));
+        m.chunks.insert("undated".into(), meta(Some("yesterday")));
+        m.chunks.insert("empty".into(), meta(None));
+
+        let now = DateTime::parse_from_rfc3339("2025-07-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(expired_chunks(&m, now, |_| Some(90 * 86_400)), vec!["old".to_string()]);
+    }
+
+    #[test]
+    fn per_station_overrides() {
+        let mut m = Manifest::default();
+        m.chunks.insert("demo-1".into(), station_meta("demo", Some("2025-06-20T00:00:00Z")));
+        m.chunks.insert("research-1".into(), station_meta("research", Some("2020-01-01T00:00:00Z")));
+        let now = DateTime::parse_from_rfc3339("2025-07-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let expired = expired_chunks(&m, now, |s| if s == "demo" { Some(7 * 86_400) } else { None });
+        assert_eq!(expired, vec!["demo-1".to_string()]);
+    }
+
+    #[test]
+    fn packed_chunks_expire_with_their_last_station() {
+        let (_, mut packed) = crate::storage::manifest::encode_ndjson(
+            "",
+            &[
+                Observation::empty("demo", "2025-06-20T00:00:00Z".parse().unwrap()),
+                Observation::empty("research", "2020-01-01T00:00:00Z".parse().unwrap()),
+            ],
+        )
+        .unwrap();
+        let mut m = Manifest::default();
+        m.chunks.insert("_packed-1".into(), packed.clone());
+        let now = DateTime::parse_from_rfc3339("2025-07-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        assert!(expired_chunks(&m, now, |s| if s == "demo" { Some(7 * 86_400) } else { None }).is_empty());
+        packed.stations[1].max_time = Some("2025-06-01T00:00:00Z".into());
+        m.chunks.insert("_packed-1".into(), packed);
+        assert_eq!(expired_chunks(&m, now, |_| Some(7 * 86_400)), vec!["_packed-1".to_string()]);
+    }
+
+    #[test]
+    fn station_patterns_override_the_max_age() {
+        let policy: RetentionPolicy = toml::from_str(
+            r#"
+            max_age = "90d"
+            stations = [
+                { pattern = "RES-*", max_age = "forever" },
+                { pattern = "DEMO*", max_age = "7d" },
+                { pattern = "*-test-*-b", max_age = 60 },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policy.station_override("RES-01"), Some(None));
+        assert_eq!(policy.station_override("DEMO"), Some(Some(7 * 86_400)));
+        assert_eq!(policy.station_override("hk-test-1-b"), Some(Some(60)));
+        assert_eq!(policy.station_override("hk-test-1-bc"), None);
+        assert_eq!(policy.station_override("RES"), None);
+
+        let mut m = Manifest::default();
+        m.chunks.insert("res-1".into(), station_meta("RES-01", Some("2020-01-01T00:00:00Z")));
+        m.chunks.insert("demo-1".into(), station_meta("DEMO7", Some("2025-06-20T00:00:00Z")));
+        m.chunks.insert("hk-1".into(), station_meta("HK01", Some("2025-06-20T00:00:00Z")));
+        let now = DateTime::parse_from_rfc3339("2025-07-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let expired = expired_chunks(&m, now, |s| policy.station_override(s).unwrap_or(policy.max_age_secs));
+        assert_eq!(expired, vec!["demo-1".to_string()]);
+    }
+
+    #[test]
+    fn no_max_age_keeps_everything() {
+        assert!(RetentionPolicy::default().cutoff(Utc::now()).is_none());
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use crate::storage::manifest::PACKED_PREFIX;
+use crate::storage::memtable::Observation;
+
+/// Rollup bucket widths maintained by the flush path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Resolution {
+    #[serde(rename = "1m")]
+    Minute,
+    #[serde(rename = "1h")]
+    Hour,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 2] = [Resolution::Minute, Resolution::Hour];
+
+    pub fn secs(self) -> i64 {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 3600,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Minute => "1m",
+            Resolution::Hour => "1h",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.as_str() == s)
+    }
+}
+
+/// Aggregate of one field within one bucket. Keeps `sum`/`count` so partial
+/// aggregates from different flushes of the same bucket can be merged exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FieldAgg {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl FieldAgg {
+    pub fn new(v: f64) -> Self {
+        Self { min: v, max: v, avg: v, sum: v, count: 1 }
+    }
+
+    pub fn merge(&mut self, other: &FieldAgg) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+        self.avg = self.sum / self.count as f64;
+    }
+}
+
+/// Aggregates for one station and one bucket, `time` being the bucket start.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RollupRow {
+    pub station_id: String,
+    pub time: String,
+    pub fields: BTreeMap<String, FieldAgg>,
+}
+
+/// Start of the bucket containing `t`.
+pub fn bucket_start(t: DateTime<Utc>, res: Resolution) -> DateTime<Utc> {
+    let secs = t.timestamp().div_euclid(res.secs()) * res.secs();
+    DateTime::from_timestamp(secs, 0).unwrap_or(t)
+}
+
+/// Compute rollup rows for `obs`, in the canonical units whatever unit a
+/// row stores a field in.
+pub fn compute(obs: &[Observation], res: Resolution) -> Vec<RollupRow> {
+    let mut buckets: BTreeMap<(String, String), RollupRow> = BTreeMap::new();
+    for o in obs {
+        let time = bucket_start(o.timestamp(), res).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let row = buckets
+            .entry((o.station_id.clone(), time.clone()))
+            .or_insert_with(|| RollupRow { station_id: o.station_id.clone(), time, fields: BTreeMap::new() });
+        for (name, value) in &o.fields {
+            let Some(v) = value.as_f64() else { continue };
+            let v = crate::query::convert::canonical_value(o, name, v);
+            row.fields
+                .entry(name.clone())
+                .and_modify(|agg| agg.merge(&FieldAgg::new(v)))
+                .or_insert_with(|| FieldAgg::new(v));
+        }
+    }
+    buckets.into_values().collect()
+}
+
+/// Merge rows describing the same (station, bucket) and return them ordered by time.
+pub fn merge(rows: Vec<RollupRow>) -> Vec<RollupRow> {
+    let mut buckets: BTreeMap<(String, String), RollupRow> = BTreeMap::new();
+    for row in rows {
+        match buckets.get_mut(&(row.time.clone(), row.station_id.clone())) {
+            Some(existing) => {
+                for (name, agg) in row.fields {
+                    existing.fields.entry(name).and_modify(|a| a.merge(&agg)).or_insert(agg);
+                }
+            }
+            None => {
+                buckets.insert((row.time.clone(), row.station_id.clone()), row);
+            }
+        }
+    }
+    buckets.into_values().collect()
+}
+
+/// Rollup chunks live next to raw chunks under `data_dir/rollups/<resolution>/`.
+pub struct RollupStore {
+    dir: PathBuf,
+    read_only: bool,
+}
+
+impl RollupStore {
+    pub fn new(data_dir: PathBuf) -> Result<Self> {
+        let dir = data_dir.join("rollups");
+        for res in Resolution::ALL {
+            std::fs::create_dir_all(dir.join(res.as_str()))?;
+        }
+        Ok(Self { dir, read_only: false })
+    }
+
+    /// Open rollups of an existing data directory without creating anything.
+    /// Missing rollup directories simply read as empty.
+    pub fn open_read_only(data_dir: PathBuf) -> Self {
+        Self { dir: data_dir.join("rollups"), read_only: true }
+    }
+
+    /// Compute and write rollups at every resolution for a freshly flushed chunk.
+    pub async fn write_chunk(&self, station_id: &str, chunk_name: &str, obs: &[Observation]) -> Result<()> {
+        self.write_file(&format!("{}-{}.ndjson", station_id, chunk_name), obs).await
+    }
+
+    /// Rollups of a packed chunk go into one shared file per resolution too.
+    pub async fn write_packed(&self, chunk_name: &str, obs: &[Observation]) -> Result<()> {
+        self.write_file(&format!("{}{}.ndjson", PACKED_PREFIX, chunk_name), obs).await
+    }
+
+    async fn write_file(&self, fname: &str, obs: &[Observation]) -> Result<()> {
+        self.ensure_writable()?;
+        for res in Resolution::ALL {
+            let rows = compute(obs, res);
+            if rows.is_empty() {
+                continue;
+            }
+            tokio::fs::write(self.dir.join(res.as_str()).join(fname), encode(&rows)?).await?;
+        }
+        Ok(())
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!("rollup store at {} is read-only", self.dir.display());
+        }
+        Ok(())
+    }
+
+    /// Recompute the rollup file `fname` from its chunk's rows, replacing the
+    /// rows of `station_id` (every station when `None`) and keeping the rest.
+    pub async fn replace(&self, fname: &str, station_id: Option<&str>, obs: &[Observation]) -> Result<()> {
+        self.ensure_writable()?;
+        for res in Resolution::ALL {
+            let path = self.dir.join(res.as_str()).join(fname);
+            let mut rows = match tokio::fs::read(&path).await {
+                Ok(data) => parse_rows(&data),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+            rows.retain(|r| station_id.is_some_and(|s| r.station_id != s));
+            rows.extend(compute(obs, res));
+            if rows.is_empty() {
+                remove_if_exists(&path).await?;
+            } else {
+                tokio::fs::write(&path, encode(&rows)?).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop every stored rollup row of `station_id` (everything when `None`),
+    /// deleting files that end up empty. Returns the number of files touched.
+    pub async fn clear(&self, station_id: Option<&str>) -> Result<usize> {
+        self.ensure_writable()?;
+        let mut touched = 0;
+        for res in Resolution::ALL {
+            let mut rd = tokio::fs::read_dir(self.dir.join(res.as_str())).await?;
+            while let Some(entry) = rd.next_entry().await? {
+                let path = entry.path();
+                let Some(station_id) = station_id else {
+                    remove_if_exists(&path).await?;
+                    touched += 1;
+                    continue;
+                };
+                let mut rows = parse_rows(&tokio::fs::read(&path).await?);
+                let before = rows.len();
+                rows.retain(|r| r.station_id != station_id);
+                if rows.len() == before {
+                    continue;
+                }
+                touched += 1;
+                if rows.is_empty() {
+                    remove_if_exists(&path).await?;
+                } else {
+                    tokio::fs::write(&path, encode(&rows)?).await?;
+                }
+            }
+        }
+        Ok(touched)
+    }
+
+    /// Read all stored rollup rows for a station at `res` (not merged).
+    pub async fn read(&self, station_id: &str, res: Resolution) -> Result<Vec<RollupRow>> {
+        let mut out = Vec::new();
+        let mut rd = match tokio::fs::read_dir(self.dir.join(res.as_str())).await {
+            Ok(rd) => rd,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(out),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = rd.next_entry().await? {
+            let name = entry.file_name().into_string().unwrap_or_default();
+            if !name.starts_with(&format!("{}-", station_id)) && !name.starts_with(PACKED_PREFIX) {
+                continue;
+            }
+            let data = tokio::fs::read(entry.path()).await?;
+            out.extend(parse_rows(&data).into_iter().filter(|row| row.station_id == station_id));
+        }
+        Ok(out)
+    }
+}
+
+/// Name of the rollup file derived from the chunk file `chunk_name`.
+pub fn file_for_chunk(chunk_name: &str) -> String {
+    let stem = chunk_name
+        .strip_suffix(crate::storage::archive::ARCHIVE_SUFFIX)
+        .or_else(|| chunk_name.strip_suffix(".ndjson"))
+        .or_else(|| chunk_name.strip_suffix(crate::storage::columnar::BLOCK_SUFFIX))
+        .unwrap_or(chunk_name);
+    format!("{}.ndjson", stem)
+}
+
+fn encode(rows: &[RollupRow]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut data, row)?;
+        data.push(b'\n');
+    }
+    Ok(data)
+}
+
+fn parse_rows(data: &[u8]) -> Vec<RollupRow> {
+    data.split(|b| *b == b'\n').filter(|l| !l.is_empty()).filter_map(|l| serde_json::from_slice(l).ok()).collect()
+}
+
+async fn remove_if_exists(path: &std::path::Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obs(time: &str, temp: f64) -> Observation {
+        let mut o = Observation::empty("TPE001", time.parse().unwrap());
+        o.set_field("temp", temp);
+        o
+    }
+
+    #[test]
+    fn computes_minute_buckets() {
+        let rows = compute(
+            &[obs("2025-01-02T10:00:05Z", 10.0), obs("2025-01-02T10:00:55Z", 14.0), obs("2025-01-02T10:01:00Z", 9.0)],
+            Resolution::Minute,
+        );
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].time.to_string(), "2025-01-02T10:00:00Z");
+        let t = rows[0].fields["temp"];
+        assert_eq!((t.min, t.max, t.avg, t.count), (10.0, 14.0, 12.0, 2));
+        assert!(!rows[0].fields.contains_key("humidity"));
+    }
+
+    #[test]
+    fn merges_partial_buckets_from_separate_flushes() {
+        let a = compute(&[obs("2025-01-02T10:10:00Z", 10.0)], Resolution::Hour);
+        let b = compute(&[obs("2025-01-02T10:50:00Z", 20.0), obs("2025-01-02T10:55:00Z", 30.0)], Resolution::Hour);
+        let merged = merge(a.into_iter().chain(b).collect());
+        assert_eq!(merged.len(), 1);
+        let t = merged[0].fields["temp"];
+        assert_eq!((t.min, t.max, t.avg, t.count), (10.0, 30.0, 20.0, 3));
+    }
+
+    #[test]
+    fn names_rollup_files_after_chunks() {
+        assert_eq!(file_for_chunk("A-flush-1.ndjson"), "A-flush-1.ndjson");
+        assert_eq!(file_for_chunk("A-flush-1.block"), "A-flush-1.ndjson");
+        assert_eq!(file_for_chunk("A-archive-2024.ndjson.zst"), "A-archive-2024.ndjson");
+    }
+}
@@ -0,0 +1,289 @@
+// Tiered chunk storage: recent chunks on local disk (hot), older ones in
+// object storage (cold). Every chunk is written to the hot tier; a background
+// worker moves chunks whose newest row is older than `hot` to the cold tier.
+// Reads go to the hot tier first and fall through to the cold one, keeping
+// what they fetched in a bounded local cache so repeated queries over old
+// data do not download it again.
+//
+// A chunk rewritten after it went cold (by a purge, compaction or archive
+// run) is hot again; its stale cold copy is shadowed until the next move
+// replaces it.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::storage::backend::{ChunkBackend, LocalBackend};
+use crate::storage::memtable::Timestamp;
+use crate::storage::ChunkStore;
+use crate::units;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TieringPolicy {
+    /// Chunks whose newest row is younger than this stay on local disk.
+    #[serde(alias = "hot", deserialize_with = "units::secs")]
+    pub hot_secs: u64,
+    /// How often the worker looks for chunks to move. Defaults to hourly.
+    #[serde(alias = "check_interval", default = "default_check_interval_secs", deserialize_with = "units::secs")]
+    pub check_interval_secs: u64,
+    /// Local cache of cold chunks read by queries; 0 turns it off.
+    #[serde(alias = "cache", default = "default_cache_bytes", deserialize_with = "units::bytes")]
+    pub cache_bytes: u64,
+    /// Where cached cold chunks go; `data_dir/cold-cache` when unset.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+}
+
+fn default_check_interval_secs() -> u64 {
+    3600
+}
+
+fn default_cache_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+impl TieringPolicy {
+    pub fn check_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.check_interval_secs.max(1))
+    }
+}
+
+/// Cold chunks kept on local disk after a read, least recently used evicted first.
+struct ColdCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Serializes inserts and evictions.
+    lock: Mutex<()>,
+}
+
+impl ColdCache {
+    /// The cached copy of `name`, marked as just used.
+    async fn get(&self, name: &str) -> Option<PathBuf> {
+        let path = self.dir.join(name);
+        let file = std::fs::File::options().write(true).open(&path).ok()?;
+        let _ = file.set_modified(SystemTime::now());
+        Some(path)
+    }
+
+    async fn insert(&self, name: &str, data: &[u8]) -> Result<()> {
+        if data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        let _guard = self.lock.lock().await;
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, self.dir.join(name)).await?;
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let meta = entry.metadata().await?;
+            entries.push((meta.modified()?, meta.len(), entry.path()));
+        }
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            tokio::fs::remove_file(&path).await?;
+            total -= len;
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        LocalBackend::new(self.dir.clone()).delete(name).await
+    }
+}
+
+/// Hot chunks in the local chunk directory, cold ones in `cold`.
+pub struct TieredBackend {
+    dir: PathBuf,
+    hot: LocalBackend,
+    cold: Arc<dyn ChunkBackend>,
+    cache: Option<ColdCache>,
+}
+
+impl TieredBackend {
+    pub fn new(dir: PathBuf, cold: Arc<dyn ChunkBackend>, policy: &TieringPolicy, data_dir: &Path) -> Result<Self> {
+        let cache = if policy.cache_bytes > 0 {
+            let cache_dir = policy.cache_dir.clone().unwrap_or_else(|| data_dir.join("cold-cache"));
+            std::fs::create_dir_all(&cache_dir)?;
+            Some(ColdCache { dir: cache_dir, max_bytes: policy.cache_bytes, lock: Mutex::new(()) })
+        } else {
+            None
+        };
+        Ok(Self { hot: LocalBackend::new(dir.clone()), dir, cold, cache })
+    }
+
+    /// Whether chunk `name` is on local disk.
+    pub async fn is_hot(&self, name: &str) -> bool {
+        tokio::fs::try_exists(self.dir.join(name)).await.unwrap_or(false)
+    }
+
+    /// Read a cold chunk through the cache.
+    async fn read_cold(&self, name: &str) -> Result<Vec<u8>> {
+        if let Some(cache) = &self.cache {
+            if let Some(path) = cache.get(name).await {
+                return Ok(tokio::fs::read(path).await?);
+            }
+        }
+        let data = self.cold.read(name).await?;
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.insert(name, &data).await {
+                tracing::warn!("could not cache cold chunk {}: {}", name, e);
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl ChunkBackend for TieredBackend {
+    fn describe(&self) -> String {
+        format!("{} (hot) and {} (cold)", self.dir.display(), self.cold.describe())
+    }
+
+    async fn read(&self, name: &str) -> Result<Vec<u8>> {
+        if self.is_hot(name).await {
+            return self.hot.read(name).await;
+        }
+        self.read_cold(name).await
+    }
+
+    async fn read_range(&self, name: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if self.is_hot(name).await {
+            return self.hot.read_range(name, offset, len).await;
+        }
+        if self.cache.is_none() {
+            return self.cold.read_range(name, offset, len).await;
+        }
+        let data = self.read_cold(name).await?;
+        let range = (offset as usize).min(data.len())..((offset + len) as usize).min(data.len());
+        Ok(data[range].to_vec())
+    }
+
+    async fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        self.hot.write(name, data).await?;
+        if let Some(cache) = &self.cache {
+            cache.remove(name).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.hot.delete(name).await?;
+        self.cold.delete(name).await?;
+        if let Some(cache) = &self.cache {
+            cache.remove(name).await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut names = self.hot.list().await?;
+        names.extend(self.cold.list().await?);
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Chunks moved by one pass of [`migrate`].
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Migration {
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+/// Move every hot chunk whose newest row is older than `before` to the cold tier.
+pub async fn migrate(store: &ChunkStore, tiers: &TieredBackend, before: Timestamp) -> Result<Migration> {
+    let _maintenance = store.maintenance().await;
+    let mut moved = Migration::default();
+    for (name, meta) in store.manifest().await.chunks {
+        let newest = meta.max_time.as_deref().and_then(|t| Timestamp::parse(t).ok());
+        if newest.is_none_or(|t| t >= before) || !tiers.is_hot(&name).await {
+            continue;
+        }
+        let Some(data) = tiers.hot.read(&name).await.ok() else { continue };
+        if data.len() as u64 != meta.bytes || crc32fast::hash(&data) != meta.crc32 {
+            // being rewritten; the next pass picks it up
+            continue;
+        }
+        tiers.cold.write(&name, &data).await?;
+        if store.unchanged_while(&name, &meta, tiers.hot.delete(&name)).await? {
+            if let Some(cache) = &tiers.cache {
+                cache.remove(&name).await?;
+            }
+            moved.chunks += 1;
+            moved.bytes += meta.bytes;
+        }
+    }
+    Ok(moved)
+}
+
+/// Tiering worker: runs until `shutdown` fires.
+pub async fn run(
+    store: Arc<ChunkStore>,
+    tiers: Arc<TieredBackend>,
+    policy: TieringPolicy,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) {
+    let mut ticker = tokio::time::interval(policy.check_interval());
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {
+                let before = Timestamp(chrono::Utc::now().timestamp_millis() - policy.hot_secs as i64 * 1000);
+                match migrate(&store, &tiers, before).await {
+                    Ok(m) if m.chunks > 0 => tracing::info!("tiering: moved {} chunks ({} bytes) to {}", m.chunks, m.bytes, tiers.cold.describe()),
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("tiering error: {}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::columnar::ChunkFormat;
+    use crate::storage::integrity::IntegrityMode;
+    use crate::storage::memtable::Observation;
+
+    #[tokio::test]
+    async fn old_chunks_move_to_the_cold_tier() {
+        let dir = crate::test_util::TempDir::new("tiering");
+        let (hot, cold) = (dir.join("chunks"), dir.join("bucket"));
+        std::fs::create_dir_all(&cold).unwrap();
+        let policy = TieringPolicy { hot_secs: 86_400, check_interval_secs: 3600, cache_bytes: 1 << 20, cache_dir: None };
+        let tiers = Arc::new(TieredBackend::new(hot.clone(), Arc::new(LocalBackend::new(cold.clone())), &policy, &dir).unwrap());
+        let store = ChunkStore::open_backend(hot.clone(), tiers.clone(), IntegrityMode::Strict, false).await.unwrap().with_format(ChunkFormat::Block);
+        let at = |t: &str| Observation::empty("A", t.parse().unwrap());
+        store.write_chunk("A", "old", &[at("2025-01-01T00:00:00Z")]).await.unwrap();
+        store.write_chunk("A", "new", &[at("2025-03-01T00:00:00Z")]).await.unwrap();
+
+        let moved = migrate(&store, &tiers, "2025-02-01T00:00:00Z".parse().unwrap()).await.unwrap();
+        assert_eq!(moved.chunks, 1);
+        assert!(!hot.join("A-old.block").exists() && cold.join("A-old.block").exists());
+        // reads fall through to the cold tier and fill the cache
+        assert_eq!(store.read_chunks("A").await.unwrap().len(), 2);
+        assert!(dir.join("cold-cache").join("A-old.block").exists());
+
+        // rewriting a cold chunk makes it hot again, and a restart sees both tiers;
+        // tagged rows go to NDJSON, which replaces the block in both tiers
+        let mut tagged = at("2025-01-02T00:00:00Z");
+        tagged.tags.insert("qc".into(), "ok".into());
+        store.write_chunk("A", "old", &[at("2025-01-01T00:00:00Z"), tagged]).await.unwrap();
+        assert!(hot.join("A-old.ndjson").exists() && !cold.join("A-old.block").exists());
+        assert!(!store.manifest().await.chunks.contains_key("A-old.block"));
+        drop(store);
+        let store = ChunkStore::open_backend(hot.clone(), tiers.clone(), IntegrityMode::Strict, false).await.unwrap().with_format(ChunkFormat::Block);
+        assert_eq!(store.read_chunks("A").await.unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use crate::storage::memtable::{Observation, Timestamp};
+
+/// A range delete: a station's rows in `[start, end)` (either side open when
+/// unset) that were written before the delete was issued. Tombstones are
+/// logged to the WAL, kept in the manifest and mask rows on every read until
+/// the purge has removed the rows from the chunk files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub station_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<Timestamp>,
+    /// Epoch milliseconds when the delete was issued. Chunks written later
+    /// hold rows that arrived after it and are left alone.
+    pub issued: i64,
+}
+
+impl Tombstone {
+    /// Whether `o` lies in the deleted range, regardless of when it was written.
+    pub fn covers(&self, o: &Observation) -> bool {
+        o.station_id == self.station_id && self.start.is_none_or(|s| o.time >= s) && self.end.is_none_or(|e| o.time < e)
+    }
+
+    /// Whether the deleted range meets `[min, max]`; unknown bounds are
+    /// assumed to.
+    pub fn overlaps(&self, min: Option<Timestamp>, max: Option<Timestamp>) -> bool {
+        self.start.is_none_or(|s| max.is_none_or(|m| m >= s)) && self.end.is_none_or(|e| min.is_none_or(|m| m < e))
+    }
+
+    /// Whether the tombstone hides rows of a chunk written at `written`
+    /// (epoch milliseconds; 0 for chunks from before write times were kept).
+    pub fn applies_to(&self, written: i64) -> bool {
+        written <= self.issued
+    }
+}
+
+/// Drop the rows of a chunk written at `written` that `tombstones` delete;
+/// returns how many were dropped.
+pub fn mask(rows: &mut Vec<Observation>, written: i64, tombstones: &[Tombstone]) -> usize {
+    let live: Vec<&Tombstone> = tombstones.iter().filter(|t| t.applies_to(written)).collect();
+    if live.is_empty() {
+        return 0;
+    }
+    let before = rows.len();
+    rows.retain(|o| !live.iter().any(|t| t.covers(o)));
+    before - rows.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obs(station: &str, t: &str) -> Observation {
+        Observation::empty(station, t.parse().unwrap())
+    }
+
+    #[test]
+    fn masks_the_range_of_older_chunks_only() {
+        let t = Tombstone {
+            station_id: "A".into(),
+            start: Some("2025-01-01T01:00:00Z".parse().unwrap()),
+            end: Some("2025-01-01T02:00:00Z".parse().unwrap()),
+            issued: 1_000,
+        };
+        let rows = vec![
+            obs("A", "2025-01-01T00:59:00Z"),
+            obs("A", "2025-01-01T01:00:00Z"),
+            obs("B", "2025-01-01T01:30:00Z"),
+            obs("A", "2025-01-01T02:00:00Z"),
+        ];
+        let mut older = rows.clone();
+        assert_eq!(mask(&mut older, 1_000, std::slice::from_ref(&t)), 1);
+        assert_eq!(older.len(), 3);
+        let mut newer = rows;
+        assert_eq!(mask(&mut newer, 1_001, std::slice::from_ref(&t)), 0);
+
+        assert!(t.overlaps(None, Some("2025-01-01T01:00:00Z".parse().unwrap())));
+        assert!(!t.overlaps(Some("2025-01-01T02:00:00Z".parse().unwrap()), None));
+        let all = Tombstone { start: None, end: None, ..t };
+        assert!(all.covers(&obs("A", "1970-01-01T00:00:00Z")));
+    }
+}
@@ -1,48 +1,1001 @@
-use std::path::PathBuf;
+// Write-ahead log, kept as a directory of numbered segment files. After an
+// 8-byte magic header each segment is a sequence of records, each a
+// little-endian u32 payload length, a u32 CRC32 of the payload, and the
+// payload (one JSON observation, or a `{"tombstone": ...}` range delete).
+// Appends go to the newest segment, which is sealed once it grows past
+// `segment_bytes` or its rows are handed to a flush. A single writer task
+// owns the open segment: appends that arrive while it is busy are written
+// together (group commit). When each write is synced to disk is set by the
+// `Durability` policy. Sealed segments are deleted once nothing buffered
+// still needs them: whoever holds rows that are only durable in the WAL holds
+// a `SegmentPin` on the oldest segment they came from. Replay reads the
+// segments in order. A damaged record is skipped up to the next intact one,
+// found by its checksum, so one bad sector costs the records it held rather
+// than the rest of the segment; damage with nothing intact after it (a torn
+// last write) is cut off. A damaged segment is then rewritten with its intact
+// records only, and appends move on to a fresh segment. Followers reading
+// damage that appeared since skip it the same way. `recovery = "strict"`
+// refuses to start on any damage instead, leaving the segments untouched.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use crate::storage::memtable::Observation;
+use crate::storage::tombstone::Tombstone;
+use crate::units;
+
+pub const WAL_MAGIC: &[u8; 8] = b"SPWAL01\n";
+const RECORD_HEADER: usize = 8;
+const SEGMENT_EXT: &str = "wal";
+/// Appends waiting for the writer before callers are held up.
+const QUEUE: usize = 1024;
+/// Upper bound on the bytes written and synced as one group.
+const MAX_GROUP_BYTES: usize = 8 * 1024 * 1024;
+/// The single-file log used before segmentation.
+const LEGACY_FILE: &str = "wal.log";
+
+/// When appended records are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// `sync_data` before a write is acknowledged: nothing acknowledged is
+    /// lost on power failure, at the cost of a disk sync per group commit.
+    #[default]
+    Always,
+    /// Sync at most this often: a power failure loses up to one interval of
+    /// acknowledged writes.
+    Interval(Duration),
+    /// Leave syncing to the OS: a crash of the process loses nothing, a
+    /// power failure may lose whatever the OS had not written back.
+    Never,
+}
+
+impl Durability {
+    /// `always`, `never` or `interval(<ms>)`, e.g. `interval(100)`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s {
+            "always" => return Ok(Durability::Always),
+            "never" => return Ok(Durability::Never),
+            _ => {}
+        }
+        let Some(every) = s.strip_prefix("interval(").and_then(|r| r.strip_suffix(')')) else {
+            bail!("durability must be always, never or interval(<duration>), got '{}'", s);
+        };
+        match units::parse_duration_in(every, "ms").with_context(|| format!("durability '{}'", s))? {
+            d if d.is_zero() => bail!("durability interval must be positive, got '{}'", s),
+            d => Ok(Durability::Interval(d)),
+        }
+    }
+}
+
+impl std::fmt::Display for Durability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Durability::Always => f.write_str("always"),
+            Durability::Interval(d) => write!(f, "interval({}ms)", d.as_millis()),
+            Durability::Never => f.write_str("never"),
+        }
+    }
+}
+
+impl Serialize for Durability {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Durability {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        Durability::parse(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// What replay does about damaged records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Recovery {
+    /// Replay every intact record, skipping damaged ones and cutting off a
+    /// damaged tail.
+    #[default]
+    Partial,
+    /// Refuse to start, changing nothing, so the segments can be examined.
+    Strict,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalConfig {
+    /// Start a new segment once the current one reaches this size.
+    #[serde(alias = "segment_size", deserialize_with = "units::bytes")]
+    pub segment_bytes: u64,
+    pub durability: Durability,
+    pub recovery: Recovery,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self { segment_bytes: 64 * 1024 * 1024, durability: Durability::default(), recovery: Recovery::default() }
+    }
+}
+
+impl WalConfig {
+    /// `SKYPULSE_WAL_SEGMENT_BYTES`, `SKYPULSE_WAL_DURABILITY` and
+    /// `SKYPULSE_WAL_RECOVERY`.
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Some(bytes) = units::env_size("SKYPULSE_WAL_SEGMENT_BYTES")? {
+            self.segment_bytes = bytes;
+        }
+        if let Ok(v) = std::env::var("SKYPULSE_WAL_DURABILITY") {
+            self.durability = Durability::parse(&v).context("SKYPULSE_WAL_DURABILITY")?;
+        }
+        match std::env::var("SKYPULSE_WAL_RECOVERY").as_deref() {
+            Ok("partial") => self.recovery = Recovery::Partial,
+            Ok("strict") => self.recovery = Recovery::Strict,
+            Ok(other) => bail!("SKYPULSE_WAL_RECOVERY must be partial or strict, got '{}'", other),
+            Err(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Where and why replay found a damaged record.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Corruption {
+    pub segment: u64,
+    /// Byte offset of the damaged record within the segment.
+    pub offset: u64,
+    pub reason: String,
+}
+
+/// What replay read and what it had to leave out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RecoveryReport {
+    /// Segments read, oldest first.
+    pub segments: Vec<u64>,
+    /// Records replayed, observations and range deletes alike.
+    pub records: usize,
+    /// Bytes of intact records across all segments.
+    pub valid_bytes: u64,
+    /// Bytes of damaged records skipped between intact ones.
+    pub skipped_bytes: u64,
+    /// Bytes cut from the ends of segments: a torn last write, or damage
+    /// with nothing intact after it.
+    pub truncated_bytes: u64,
+    /// The first damaged record found.
+    pub corruption: Option<Corruption>,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        self.corruption.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// Rows not deleted by a later tombstone, in log order.
+    pub observations: Vec<Observation>,
+    pub tombstones: Vec<Tombstone>,
+    pub report: RecoveryReport,
+}
+
+/// Keeps WAL segments from `segment` onward on disk while held.
+#[derive(Debug)]
+pub struct SegmentPin {
+    pins: Arc<std::sync::Mutex<BTreeMap<u64, usize>>>,
+    segment: u64,
+}
+
+impl SegmentPin {
+    pub fn segment(&self) -> u64 {
+        self.segment
+    }
+
+    /// Keep whichever of two pins protects more, releasing the other.
+    pub fn min(a: Option<SegmentPin>, b: SegmentPin) -> SegmentPin {
+        match a {
+            Some(a) if a.segment <= b.segment => a,
+            _ => b,
+        }
+    }
+}
+
+impl Drop for SegmentPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(n) = pins.get_mut(&self.segment) {
+            *n -= 1;
+            if *n == 0 {
+                pins.remove(&self.segment);
+            }
+        }
+    }
+}
+
+type Pins = Arc<std::sync::Mutex<BTreeMap<u64, usize>>>;
+
+fn pin(pins: &Pins, segment: u64) -> SegmentPin {
+    *pins.lock().unwrap_or_else(|e| e.into_inner()).entry(segment).or_default() += 1;
+    SegmentPin { pins: pins.clone(), segment }
+}
+
+enum Command {
+    /// Encoded records to append; answered with a pin on their segment.
+    Append(Vec<u8>, oneshot::Sender<Result<SegmentPin, String>>),
+    /// Seal the current segment if it holds any records.
+    Rotate(oneshot::Sender<Result<(), String>>),
+    /// Sync what is written, whatever the durability policy.
+    Sync(oneshot::Sender<Result<(), String>>),
+}
+
+/// The writer task's open segment.
+struct Writer {
+    dir: PathBuf,
+    segment_bytes: u64,
+    durability: Durability,
+    file: tokio::fs::File,
+    seq: u64,
+    len: u64,
+    /// Written but not yet synced since this instant (`Interval` and `Never`).
+    unsynced: Option<tokio::time::Instant>,
+    /// Shared with `WAL::remove_flushed`, which must never delete this segment.
+    active: Arc<AtomicU64>,
+    pins: Pins,
+}
+
+impl Writer {
+    async fn sync(&mut self) -> Result<()> {
+        if self.unsynced.take().is_some() {
+            self.file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    async fn seal(&mut self) -> Result<()> {
+        // a sealed segment is complete on disk whatever the policy
+        self.sync().await?;
+        let next = self.seq + 1;
+        let path = self.dir.join(segment_name(next));
+        let mut file = tokio::fs::OpenOptions::new().create_new(true).append(true).open(&path).await?;
+        file.write_all(WAL_MAGIC).await?;
+        file.sync_data().await?;
+        self.file = file;
+        self.seq = next;
+        self.len = WAL_MAGIC.len() as u64;
+        self.active.store(next, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Write and sync one group of appends, cutting the segment back to its
+    /// previous length if that fails.
+    async fn commit(&mut self, data: &[u8]) -> Result<()> {
+        // replay may have cut a torn tail off the segment since it was opened
+        self.len = self.file.metadata().await?.len();
+        if self.len >= self.segment_bytes {
+            self.seal().await?;
+        }
+        let written = async {
+            self.file.write_all(data).await?;
+            self.file.flush().await?;
+            if self.durability == Durability::Always {
+                self.file.sync_data().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = self.file.set_len(self.len).await;
+            return Err(e.into());
+        }
+        self.len += data.len() as u64;
+        if self.durability != Durability::Always {
+            self.unsynced.get_or_insert_with(tokio::time::Instant::now);
+        }
+        if let (Durability::Interval(every), Some(since)) = (self.durability, self.unsynced) {
+            if since.elapsed() >= every {
+                self.sync().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// When the pending sync of the `Interval` policy is due, if one is pending.
+    fn sync_due(&self) -> Option<tokio::time::Instant> {
+        match (self.durability, self.unsynced) {
+            (Durability::Interval(every), Some(since)) => Some(since + every),
+            _ => None,
+        }
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<Command>) {
+        let mut next = None;
+        loop {
+            let cmd = match next.take() {
+                Some(cmd) => cmd,
+                None => {
+                    let due = self.sync_due();
+                    tokio::select! {
+                        cmd = rx.recv() => match cmd {
+                            Some(cmd) => cmd,
+                            None => break,
+                        },
+                        _ = tokio::time::sleep_until(due.unwrap_or_else(tokio::time::Instant::now)), if due.is_some() => {
+                            if let Err(e) = self.sync().await {
+                                tracing::error!("WAL sync failed: {:#}", e);
+                            }
+                            continue;
+                        }
+                    }
+                }
+            };
+            match cmd {
+                Command::Rotate(reply) => {
+                    let result = match self.file.metadata().await {
+                        Ok(m) if m.len() > WAL_MAGIC.len() as u64 => self.seal().await,
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(e.into()),
+                    };
+                    let _ = reply.send(result.map_err(|e| format!("{:#}", e)));
+                }
+                Command::Sync(reply) => {
+                    let _ = reply.send(self.sync().await.map_err(|e| format!("{:#}", e)));
+                }
+                Command::Append(data, reply) => {
+                    // gather whatever else is already waiting into the same write
+                    let mut group = data;
+                    let mut replies = vec![reply];
+                    while group.len() < MAX_GROUP_BYTES {
+                        match rx.try_recv() {
+                            Ok(Command::Append(data, reply)) => {
+                                group.extend_from_slice(&data);
+                                replies.push(reply);
+                            }
+                            Ok(other) => {
+                                next = Some(other);
+                                break;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    match self.commit(&group).await {
+                        Ok(()) => {
+                            for reply in replies {
+                                let _ = reply.send(Ok(pin(&self.pins, self.seq)));
+                            }
+                        }
+                        Err(e) => {
+                            let msg = format!("{:#}", e);
+                            for reply in replies {
+                                let _ = reply.send(Err(msg.clone()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Err(e) = self.sync().await {
+            tracing::error!("WAL sync on close failed: {:#}", e);
+        }
+    }
+}
 
 pub struct WAL {
-    path: PathBuf,
+    dir: PathBuf,
+    durability: Durability,
+    recovery: Recovery,
+    tx: mpsc::Sender<Command>,
+    active: Arc<AtomicU64>,
+    pins: Pins,
+}
+
+/// A record payload: a range delete, or (the common case) an observation.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Record {
+    Delete { tombstone: Tombstone },
+    Write(Observation),
+}
+
+fn encode_record(buf: &mut Vec<u8>, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+/// Good records of a segment body: observations a later tombstone of the
+/// same body deleted are already left out.
+#[derive(Default)]
+struct Decoded {
+    observations: Vec<Observation>,
+    tombstones: Vec<Tombstone>,
+    records: usize,
+    valid_bytes: u64,
+    /// Damaged records skipped: offset, length and why.
+    skipped: Vec<(u64, u64, String)>,
+    /// Offset just past the last good record.
+    end: u64,
+    /// Where the damaged tail starts and why, if the body has one.
+    tail: Option<(u64, String)>,
+}
+
+/// The intact record at `pos`, if there is one: its payload passes the
+/// checksum in its header.
+fn intact(data: &[u8], pos: usize) -> Option<&[u8]> {
+    let header = data.get(pos..pos + RECORD_HEADER)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let payload = data.get(pos + RECORD_HEADER..pos + RECORD_HEADER + len)?;
+    // every payload is a JSON object, which rules most offsets out cheaply
+    (payload.first() == Some(&b'{') && crc32fast::hash(payload) == crc).then_some(payload)
+}
+
+/// Decode the records of a segment body starting at `base` (the offset of
+/// `data[0]` in the file), skipping damaged records up to the next intact one.
+fn decode(data: &[u8], base: u64) -> Decoded {
+    let mut out = Decoded { end: base, ..Default::default() };
+    let mut pos = 0;
+    while pos < data.len() {
+        let offset = base + pos as u64;
+        let problem = match intact(data, pos) {
+            Some(payload) => match serde_json::from_slice::<Record>(payload) {
+                Ok(record) => {
+                    match record {
+                        Record::Write(obs) => out.observations.push(obs),
+                        Record::Delete { tombstone } => {
+                            out.observations.retain(|o| !tombstone.covers(o));
+                            out.tombstones.push(tombstone);
+                        }
+                    }
+                    pos += RECORD_HEADER + payload.len();
+                    out.records += 1;
+                    out.valid_bytes += (RECORD_HEADER + payload.len()) as u64;
+                    out.end = base + pos as u64;
+                    continue;
+                }
+                Err(e) => format!("undecodable record: {}", e),
+            },
+            None => match data.get(pos..pos + RECORD_HEADER) {
+                None => format!("torn record header ({} bytes)", data.len() - pos),
+                Some(header) => {
+                    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+                    match data.len() - pos - RECORD_HEADER < len {
+                        true => format!("record of {} bytes runs past the end of the log", len),
+                        false => "checksum mismatch".to_string(),
+                    }
+                }
+            },
+        };
+        match (pos + 1..data.len()).find(|&p| intact(data, p).is_some()) {
+            Some(next) => {
+                out.skipped.push((offset, (next - pos) as u64, problem));
+                pos = next;
+            }
+            None => {
+                out.tail = Some((offset, problem));
+                break;
+            }
+        }
+    }
+    out
+}
+
+/// Every record of `data`, complete records only, in log order. Unlike
+/// replay, a bad record is an error rather than the end of the log.
+pub(crate) fn decode_records(data: &[u8]) -> Result<Vec<Record>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data.get(pos..pos + RECORD_HEADER).with_context(|| format!("torn record header at {}", pos))?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let payload = data.get(pos + RECORD_HEADER..pos + RECORD_HEADER + len).with_context(|| format!("record at {} is cut short", pos))?;
+        if crc32fast::hash(payload) != crc {
+            bail!("checksum mismatch in record at {}", pos);
+        }
+        out.push(serde_json::from_slice(payload).with_context(|| format!("undecodable record at {}", pos))?);
+        pos += RECORD_HEADER + len;
+    }
+    Ok(out)
+}
+
+/// Replace the segment at `path` with its intact records only, so the damage
+/// replay skipped is not met again by the next replay or by a follower.
+async fn rewrite_intact(path: &Path, data: &[u8], skipped: &[(u64, u64, String)], end: u64) -> Result<()> {
+    let mut kept = WAL_MAGIC.to_vec();
+    let mut from = WAL_MAGIC.len();
+    for (offset, len, _) in skipped {
+        kept.extend_from_slice(&data[from..*offset as usize]);
+        from = (offset + len) as usize;
+    }
+    kept.extend_from_slice(&data[from..end as usize]);
+    let tmp = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(&kept).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await.with_context(|| format!("replacing {}", path.display()))?;
+    if let Some(dir) = path.parent() {
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Length of the complete, intact records at the start of `data`.
+fn complete_prefix(data: &[u8]) -> usize {
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..pos + RECORD_HEADER) {
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        match data.get(pos + RECORD_HEADER..pos + RECORD_HEADER + len) {
+            Some(payload) if crc32fast::hash(payload) == crc => pos += RECORD_HEADER + len,
+            _ => break,
+        }
+    }
+    pos
+}
+
+/// Records read from one segment by [`WAL::read_records`].
+#[derive(Debug)]
+pub struct Tail {
+    pub segment: u64,
+    /// Byte offset just past the records returned.
+    pub next_offset: u64,
+    /// Encoded records, exactly as in the segment.
+    pub records: Vec<u8>,
+    /// Set once `segment` is sealed and read to its end: the segment to
+    /// continue with, from its start.
+    pub next_segment: Option<u64>,
+    /// The segment appends go to now.
+    pub active: u64,
+}
+
+fn segment_name(seq: u64) -> String {
+    format!("{:020}.{}", seq, SEGMENT_EXT)
+}
+
+/// Sequence numbers of the segment files in `dir`, oldest first.
+async fn list_segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut out = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some((stem, ext)) = name.to_str().and_then(|n| n.rsplit_once('.')) else { continue };
+        if let (SEGMENT_EXT, Ok(seq)) = (ext, stem.parse::<u64>()) {
+            out.push(seq);
+        }
+    }
+    out.sort_unstable();
+    Ok(out)
+}
+
+/// Turn the unsegmented log at `legacy` into segment 1 of `dir`, then set it
+/// aside as `wal.log.old`. Its records are the segment format already, or
+/// JSON lines in a log older still. Segments existing already mean a start
+/// that stopped between the two steps adopted it, so it is only set aside.
+async fn adopt_legacy(dir: &Path, legacy: &Path) -> Result<()> {
+    if list_segments(dir).await?.is_empty() {
+        let data = tokio::fs::read(legacy).await.with_context(|| format!("reading {}", legacy.display()))?;
+        let segment = if data.starts_with(WAL_MAGIC) {
+            data
+        } else {
+            let mut segment = WAL_MAGIC.to_vec();
+            for line in data.split(|b| *b == b'\n').filter(|l| !l.trim_ascii().is_empty()) {
+                if serde_json::from_slice::<Observation>(line).is_ok() {
+                    encode_record(&mut segment, line);
+                }
+            }
+            segment
+        };
+        let path = dir.join(segment_name(1));
+        let tmp = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&segment).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        tracing::info!("replaying the unsegmented WAL {} as {}", legacy.display(), path.display());
+    }
+    let aside = legacy.with_extension("log.old");
+    tokio::fs::rename(legacy, &aside).await?;
+    tracing::info!("moved the unsegmented WAL {} to {}; delete it once no longer needed", legacy.display(), aside.display());
+    Ok(())
 }
 
 impl WAL {
-    pub async fn open(path: PathBuf) -> anyhow::Result<Self> {
-        // ensure parent exists
-        if let Some(parent) = path.parent() {
-            tokio::fs::create_dir_all(parent).await.ok();
-        }
-        // create file if missing
-        let _ = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&path)
-            .await?;
-        Ok(Self { path })
-    }
-
-    pub async fn append(&self, data: &[u8]) -> anyhow::Result<()> {
-        let mut file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)
-            .await?;
-        file.write_all(data).await?;
-        file.write_all(b"\n").await?;
-        file.flush().await?;
-        Ok(())
+    /// Open (creating if needed) the segment directory `dir`. A log from
+    /// before segmentation, next to `dir`, becomes the first segment, so
+    /// rows not flushed before the upgrade are replayed like any other.
+    pub async fn open(dir: PathBuf, cfg: &WalConfig) -> Result<Self> {
+        tokio::fs::create_dir_all(&dir).await.with_context(|| format!("creating {}", dir.display()))?;
+        if let Some(legacy) = dir.parent().map(|p| p.join(LEGACY_FILE)) {
+            if tokio::fs::try_exists(&legacy).await? {
+                adopt_legacy(&dir, &legacy).await?;
+            }
+        }
+        let (seq, len) = match list_segments(&dir).await?.last() {
+            Some(&seq) => (seq, tokio::fs::metadata(dir.join(segment_name(seq))).await?.len()),
+            None => {
+                tokio::fs::write(dir.join(segment_name(1)), WAL_MAGIC).await?;
+                (1, WAL_MAGIC.len() as u64)
+            }
+        };
+        let file = tokio::fs::OpenOptions::new().append(true).open(dir.join(segment_name(seq))).await?;
+        let active = Arc::new(AtomicU64::new(seq));
+        let pins = Pins::default();
+        let writer = Writer {
+            dir: dir.clone(),
+            segment_bytes: cfg.segment_bytes.max(WAL_MAGIC.len() as u64 + 1),
+            durability: cfg.durability,
+            file,
+            seq,
+            len,
+            unsynced: None,
+            active: active.clone(),
+            pins: pins.clone(),
+        };
+        let (tx, rx) = mpsc::channel(QUEUE);
+        tokio::spawn(writer.run(rx));
+        Ok(Self { dir, durability: cfg.durability, recovery: cfg.recovery, tx, active, pins })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// The segment appends go to.
+    pub fn active_segment(&self) -> u64 {
+        self.active.load(Ordering::SeqCst)
     }
 
-    pub async fn replay(&self) -> anyhow::Result<Vec<crate::storage::memtable::Observation>> {
-        let content = tokio::fs::read_to_string(&self.path).await.unwrap_or_default();
-        let mut out = Vec::new();
-        for line in content.lines() {
-            if line.trim().is_empty() {
-                continue;
+    /// Complete records of `segment` (the oldest on disk when 0) from byte
+    /// `offset`, about `max_bytes` of them but at least one if there is one.
+    /// Damaged bytes at `offset` are skipped up to the next intact record, as
+    /// replay does. `Ok(None)` when the segment has already been removed.
+    pub async fn read_records(&self, segment: u64, offset: u64, max_bytes: u64) -> Result<Option<Tail>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        // a segment below the active one read here is sealed and final
+        let active = self.active_segment();
+        let segments = list_segments(&self.dir).await?;
+        let segment = if segment == 0 { segments.first().copied().unwrap_or(active) } else { segment };
+        if segment > active {
+            bail!("segment {} is ahead of the log (active segment {})", segment, active);
+        }
+        let mut file = match tokio::fs::File::open(self.dir.join(segment_name(segment))).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata().await?.len();
+        let offset = offset.max(WAL_MAGIC.len() as u64).min(len);
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let mut records = Vec::new();
+        (&mut file).take(max_bytes.max(RECORD_HEADER as u64)).read_to_end(&mut records).await?;
+        let mut start = 0;
+        let mut end = complete_prefix(&records);
+        if end == 0 && offset < len {
+            // the first record alone is larger than max_bytes, or damaged:
+            // look at the rest of the segment for the next intact record
+            file.read_to_end(&mut records).await?;
+            match (0..records.len()).find(|&p| intact(&records, p).is_some()) {
+                Some(p) => {
+                    let first = RECORD_HEADER + u32::from_le_bytes(records[p..p + 4].try_into().unwrap()) as usize;
+                    let window = &records[p..records.len().min(p + first.max(max_bytes as usize))];
+                    (start, end) = (p, p + complete_prefix(window));
+                }
+                // nothing intact after it: in the active segment that may be a write still under way
+                None if segment == active => {}
+                None => (start, end) = (records.len(), records.len()),
             }
-            if let Ok(obs) = serde_json::from_str::<crate::storage::memtable::Observation>(line) {
-                out.push(obs);
+            if start > 0 {
+                tracing::warn!("WAL segment {} damaged at offset {}; followers skip {} bytes", segment, offset, start);
             }
         }
+        let records = records[start..end].to_vec();
+        let next_offset = offset + end as u64;
+        let next_segment = (segment < active && next_offset == len).then_some(segment + 1);
+        Ok(Some(Tail { segment, next_offset, records, next_segment, active }))
+    }
+
+    /// Keep segments from `segment` onward until the pin is dropped.
+    pub fn pin(&self, segment: u64) -> SegmentPin {
+        pin(&self.pins, segment)
+    }
+
+    pub async fn append(&self, data: &[u8]) -> Result<SegmentPin> {
+        self.append_batch(&[data.to_vec()]).await
+    }
+
+    /// Log a range delete. On replay it drops the rows logged before it.
+    pub async fn append_tombstone(&self, tombstone: &Tombstone) -> Result<SegmentPin> {
+        self.append(&serde_json::to_vec(&Record::Delete { tombstone: tombstone.clone() })?).await
+    }
+
+    /// Append several records and wait until they are written (and synced,
+    /// if the durability policy says so), possibly in one write with other
+    /// callers' records. The returned pin
+    /// keeps the records' segment until the caller has put the rows
+    /// somewhere that pins them (or persisted them).
+    pub async fn append_batch(&self, records: &[Vec<u8>]) -> Result<SegmentPin> {
+        let mut buf = Vec::with_capacity(records.iter().map(|r| r.len() + RECORD_HEADER).sum());
+        for r in records {
+            encode_record(&mut buf, r);
+        }
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Command::Append(buf, reply)).await.map_err(|_| anyhow!("WAL writer has stopped"))?;
+        rx.await.map_err(|_| anyhow!("WAL writer has stopped"))?.map_err(|e| anyhow!("WAL append failed: {}", e))
+    }
+
+    /// Seal the current segment if it holds any records, so it can be
+    /// deleted once the rows handed to a flush are persisted.
+    pub async fn rotate(&self) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Command::Rotate(reply)).await.map_err(|_| anyhow!("WAL writer has stopped"))?;
+        rx.await.map_err(|_| anyhow!("WAL writer has stopped"))?.map_err(|e| anyhow!("WAL rotation failed: {}", e))
+    }
+
+    /// Sync every record appended so far, whatever the durability policy,
+    /// for callers that must not acknowledge their source before that.
+    pub async fn sync(&self) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Command::Sync(reply)).await.map_err(|_| anyhow!("WAL writer has stopped"))?;
+        rx.await.map_err(|_| anyhow!("WAL writer has stopped"))?.map_err(|e| anyhow!("WAL sync failed: {}", e))
+    }
+
+    /// Delete sealed segments older than every pin. Returns how many went.
+    pub async fn remove_flushed(&self) -> Result<usize> {
+        // the writer pins a group's segment before it can move past it, so
+        // reading the active segment first never misses a pin
+        let active = self.active.load(Ordering::SeqCst);
+        let oldest_pin = self.pins.lock().unwrap_or_else(|e| e.into_inner()).keys().next().copied();
+        let keep_from = oldest_pin.map_or(active, |p| p.min(active));
+        let mut removed = 0;
+        for seq in list_segments(&self.dir).await?.into_iter().take_while(|s| *s < keep_from) {
+            tokio::fs::remove_file(self.dir.join(segment_name(seq))).await?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Read back every intact record, oldest segment first, skipping damaged
+    /// records and cutting a damaged tail off its segment; under
+    /// `Recovery::Strict`, fail on the first damage instead. Meant for
+    /// startup, before anything is appended.
+    pub async fn replay(&self) -> Result<Replay> {
+        let mut out = Replay::default();
+        let report = &mut out.report;
+        let mut rewritten_active = false;
+        for seq in list_segments(&self.dir).await? {
+            let path = self.dir.join(segment_name(seq));
+            let data = tokio::fs::read(&path).await?;
+            let Some(body) = data.strip_prefix(WAL_MAGIC.as_slice()) else {
+                anyhow::bail!("{} is not a WAL segment (bad magic)", path.display());
+            };
+            let decoded = decode(body, WAL_MAGIC.len() as u64);
+            let damage = decoded.skipped.first().map(|(offset, _, reason)| (*offset, reason.clone())).or(decoded.tail.clone());
+            if let (Recovery::Strict, Some((offset, reason))) = (self.recovery, damage) {
+                bail!(
+                    "WAL segment {} is damaged at offset {} ({}); nothing was changed. Start with wal.recovery = \"partial\" to replay around it",
+                    path.display(),
+                    offset,
+                    reason
+                );
+            }
+            report.segments.push(seq);
+            for t in &decoded.tombstones {
+                out.observations.retain(|o| !t.covers(o));
+            }
+            out.observations.extend(decoded.observations);
+            out.tombstones.extend(decoded.tombstones);
+            report.records += decoded.records;
+            report.valid_bytes += decoded.valid_bytes;
+            for (offset, len, reason) in &decoded.skipped {
+                tracing::warn!("WAL segment {} damaged at offset {} ({}); skipping {} bytes", path.display(), offset, reason, len);
+                report.skipped_bytes += len;
+                report.corruption.get_or_insert(Corruption { segment: seq, offset: *offset, reason: reason.clone() });
+            }
+            let damaged = !decoded.skipped.is_empty() || decoded.tail.is_some();
+            if let Some((offset, reason)) = decoded.tail {
+                let truncated = data.len() as u64 - decoded.end;
+                tracing::warn!("WAL segment {} damaged at offset {} ({}); truncating {} bytes", path.display(), offset, reason, truncated);
+                report.truncated_bytes += truncated;
+                report.corruption.get_or_insert(Corruption { segment: seq, offset, reason });
+            }
+            if damaged {
+                rewrite_intact(&path, &data, &decoded.skipped, decoded.end).await?;
+                rewritten_active |= seq == self.active_segment();
+            }
+        }
+        if rewritten_active {
+            // the writer still has the replaced file open
+            self.rotate().await?;
+        }
         Ok(out)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: &str) -> Vec<u8> {
+        serde_json::to_vec(&Observation::empty("A", time.parse().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn skips_damaged_records() {
+        let mut data = Vec::new();
+        encode_record(&mut data, &record("2025-01-01T00:00:00Z"));
+        let second = data.len();
+        encode_record(&mut data, &record("2025-01-01T01:00:00Z"));
+        let third = data.len();
+        encode_record(&mut data, &record("2025-01-01T02:00:00Z"));
+
+        let decoded = decode(&data, 0);
+        assert_eq!((decoded.records, decoded.end, decoded.valid_bytes), (3, data.len() as u64, data.len() as u64));
+        assert!(decoded.skipped.is_empty() && decoded.tail.is_none());
+
+        // flip a payload byte of the second record: the third is still replayed
+        let mut flipped = data.clone();
+        flipped[second + RECORD_HEADER + 3] ^= 0x20;
+        let decoded = decode(&flipped, 8);
+        let times: Vec<String> = decoded.observations.iter().map(|o| o.time.to_string()).collect();
+        assert_eq!(times, ["2025-01-01T00:00:00Z", "2025-01-01T02:00:00Z"]);
+        assert_eq!(decoded.skipped, vec![(8 + second as u64, (third - second) as u64, "checksum mismatch".to_string())]);
+        assert_eq!((decoded.end, decoded.tail), (8 + data.len() as u64, None));
+
+        // a damaged length sends the reader nowhere near the next record, which is found anyway
+        let mut garbled = data.clone();
+        garbled[second + 2] = 0x7f;
+        let decoded = decode(&garbled, 0);
+        assert_eq!((decoded.records, decoded.skipped[0].1), (2, (third - second) as u64));
+        assert!(decoded.skipped[0].2.contains("past the end"));
+
+        // a torn final write
+        let decoded = decode(&data[..data.len() - 5], 0);
+        assert_eq!((decoded.observations.len(), decoded.end), (2, third as u64));
+        assert!(decoded.tail.unwrap().1.contains("past the end"));
+    }
+
+    #[test]
+    fn tombstones_drop_earlier_rows_only() {
+        let tombstone = Tombstone { station_id: "A".into(), start: None, end: Some("2025-01-01T02:00:00Z".parse().unwrap()), issued: 1 };
+        let mut data = Vec::new();
+        encode_record(&mut data, &record("2025-01-01T00:00:00Z"));
+        encode_record(&mut data, &serde_json::to_vec(&Record::Delete { tombstone: tombstone.clone() }).unwrap());
+        // written again after the delete
+        encode_record(&mut data, &record("2025-01-01T01:00:00Z"));
+        let decoded = decode(&data, 0);
+        assert_eq!(decoded.tail, None);
+        assert_eq!(decoded.tombstones, vec![tombstone]);
+        let times: Vec<String> = decoded.observations.iter().map(|o| o.time.to_string()).collect();
+        assert_eq!(times, vec!["2025-01-01T01:00:00Z"]);
+    }
+
+    #[test]
+    fn parses_durability() {
+        assert_eq!(Durability::parse("always").unwrap(), Durability::Always);
+        assert_eq!(Durability::parse("interval(250ms)").unwrap(), Durability::Interval(Duration::from_millis(250)));
+        assert_eq!(Durability::parse("interval(100)").unwrap().to_string(), "interval(100ms)");
+        assert_eq!(Durability::parse("interval(1s)").unwrap(), Durability::Interval(Duration::from_secs(1)));
+        assert!(Durability::parse("interval(0)").is_err());
+        assert!(Durability::parse("sometimes").is_err());
+    }
+
+    #[tokio::test]
+    async fn rotates_and_keeps_pinned_segments() {
+        let dir = crate::test_util::TempDir::new("wal");
+        let wal = WAL::open(dir.to_path_buf(), &WalConfig { segment_bytes: 40, ..Default::default() }).await.unwrap();
+
+        // each record is over 40 bytes, so every append after the first starts a segment
+        let first = wal.append(&record("2025-01-01T00:00:00Z")).await.unwrap();
+        let second = wal.append(&record("2025-01-01T01:00:00Z")).await.unwrap();
+        drop(second);
+        wal.append(&record("2025-01-01T02:00:00Z")).await.unwrap();
+        assert_eq!(list_segments(&dir).await.unwrap(), vec![1, 2, 3]);
+
+        // segment 1 is pinned, so nothing can go yet
+        assert_eq!(wal.remove_flushed().await.unwrap(), 0);
+        let replay = wal.replay().await.unwrap();
+        assert_eq!((replay.report.segments, replay.observations.len()), (vec![1, 2, 3], 3));
+        assert_eq!(replay.observations[2].time.to_string(), "2025-01-01T02:00:00Z");
+
+        drop(first);
+        assert_eq!(wal.remove_flushed().await.unwrap(), 2);
+        wal.rotate().await.unwrap();
+        assert_eq!(wal.remove_flushed().await.unwrap(), 1);
+        assert_eq!(list_segments(&dir).await.unwrap(), vec![4]);
+        assert!(wal.replay().await.unwrap().observations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replays_an_unsegmented_log_once() {
+        let dir = crate::test_util::TempDir::new("wal-legacy");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut legacy = WAL_MAGIC.to_vec();
+        encode_record(&mut legacy, &record("2025-01-01T00:00:00Z"));
+        encode_record(&mut legacy, &record("2025-01-01T01:00:00Z"));
+        std::fs::write(dir.join(LEGACY_FILE), &legacy).unwrap();
+
+        let wal = WAL::open(dir.join("wal"), &WalConfig::default()).await.unwrap();
+        wal.append(&record("2025-01-01T02:00:00Z")).await.unwrap();
+        let replay = wal.replay().await.unwrap();
+        let times: Vec<String> = replay.observations.iter().map(|o| o.time.to_string()).collect();
+        assert_eq!(times, ["2025-01-01T00:00:00Z", "2025-01-01T01:00:00Z", "2025-01-01T02:00:00Z"]);
+        assert!(!dir.join(LEGACY_FILE).exists() && dir.join("wal.log.old").exists());
+        drop(wal);
+
+        // the old log stays retired: a restart replays each row once
+        let wal = WAL::open(dir.join("wal"), &WalConfig::default()).await.unwrap();
+        assert_eq!(wal.replay().await.unwrap().observations.len(), 3);
+
+        // a log from before the checksummed format holds JSON lines
+        let older = crate::test_util::TempDir::new("wal-legacy-lines");
+        std::fs::create_dir_all(&older).unwrap();
+        let mut lines = record("2025-01-01T00:00:00Z");
+        lines.extend_from_slice(b"\nnot json\n");
+        std::fs::write(older.join(LEGACY_FILE), &lines).unwrap();
+        let wal = WAL::open(older.join("wal"), &WalConfig::default()).await.unwrap();
+        assert_eq!(wal.replay().await.unwrap().observations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_appends_are_all_durable() {
+        let dir = crate::test_util::TempDir::new("wal-group");
+        let wal = Arc::new(WAL::open(dir.to_path_buf(), &WalConfig::default()).await.unwrap());
+        let tasks: Vec<_> = (0..50)
+            .map(|i| {
+                let wal = wal.clone();
+                tokio::spawn(async move { wal.append(&record(&format!("2025-01-01T00:{:02}:00Z", i))).await.map(|p| p.segment()) })
+            })
+            .collect();
+        for t in tasks {
+            assert_eq!(t.await.unwrap().unwrap(), 1);
+        }
+        let replay = wal.replay().await.unwrap();
+        assert_eq!((replay.observations.len(), replay.report.corruption), (50, None));
+    }
+
+    #[tokio::test]
+    async fn reports_damage_and_refuses_it_when_strict() {
+        let dir = crate::test_util::TempDir::new("wal-damage");
+        let wal = WAL::open(dir.to_path_buf(), &WalConfig::default()).await.unwrap();
+        for hour in 0..3 {
+            drop(wal.append(&record(&format!("2025-01-01T0{}:00:00Z", hour))).await.unwrap());
+        }
+        drop(wal);
+        let path = dir.join(segment_name(1));
+        let mut data = std::fs::read(&path).unwrap();
+        let record_len = (data.len() - WAL_MAGIC.len()) / 3;
+        let second = WAL_MAGIC.len() + record_len;
+        data[second + RECORD_HEADER + 3] ^= 0x20;
+        data.extend_from_slice(&[7, 0, 0]);
+        std::fs::write(&path, &data).unwrap();
+
+        let strict = WAL::open(dir.to_path_buf(), &WalConfig { recovery: Recovery::Strict, ..Default::default() }).await.unwrap();
+        let err = strict.replay().await.unwrap_err().to_string();
+        assert!(err.contains(&format!("offset {}", second)), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        drop(strict);
+
+        let replay = WAL::open(dir.to_path_buf(), &WalConfig::default()).await.unwrap().replay().await.unwrap();
+        let report = replay.report;
+        assert_eq!((replay.observations.len(), report.records), (2, 2));
+        assert_eq!((report.skipped_bytes, report.truncated_bytes), (record_len as u64, 3));
+        assert_eq!(report.corruption.unwrap(), Corruption { segment: 1, offset: second as u64, reason: "checksum mismatch".into() });
+        // the damage is gone from disk, and appends went on in a fresh segment
+        assert_eq!(std::fs::read(&path).unwrap().len(), data.len() - 3 - record_len);
+        assert_eq!(list_segments(&dir).await.unwrap(), vec![1, 2]);
+        let replay = WAL::open(dir.to_path_buf(), &WalConfig::default()).await.unwrap().replay().await.unwrap();
+        assert_eq!((replay.observations.len(), replay.report.corruption), (2, None));
+    }
+}
@@ -0,0 +1,237 @@
+// Human-friendly durations and sizes for config values, environment
+// variables and query parameters: `90s`, `15m`, `1h30m`, `90d`, `250ms`,
+// `64MiB`, `1.5GB`. Plain integers keep meaning what the setting always
+// meant (seconds for `*_secs`, bytes for `*_bytes`), so existing configs
+// still parse. Sizes follow SI for KB/MB/GB and binary for KiB/MiB/GiB.
+
+use std::time::Duration;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Deserializer};
+
+const DURATION_UNITS: [(&str, u64); 7] = [
+    ("ms", 1),
+    ("s", 1_000),
+    ("m", 60_000),
+    ("h", 3_600_000),
+    ("d", 86_400_000),
+    ("w", 604_800_000),
+    ("y", 31_536_000_000),
+];
+
+// no bare `k`: it would read as KiB here and as KB to anyone used to SI
+const SIZE_UNITS: [(&str, u64); 9] = [
+    ("b", 1),
+    ("kb", 1_000),
+    ("mb", 1_000_000),
+    ("gb", 1_000_000_000),
+    ("tb", 1_000_000_000_000),
+    ("kib", 1_024),
+    ("mib", 1_048_576),
+    ("gib", 1_073_741_824),
+    ("tib", 1_099_511_627_776),
+];
+
+/// Split `s` into `(number, unit)` pairs, e.g. `1h30m` -> `[(1, "h"), (30, "m")]`.
+fn terms(s: &str) -> Option<Vec<(f64, String)>> {
+    let mut out = Vec::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let num_end = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+        let unit_end = rest[num_end..].find(|c: char| c.is_ascii_digit()).map_or(rest.len(), |i| num_end + i);
+        let n: f64 = rest[..num_end].parse().ok()?;
+        out.push((n, rest[num_end..unit_end].trim().to_ascii_lowercase()));
+        rest = rest[unit_end..].trim_start();
+    }
+    (!out.is_empty()).then_some(out)
+}
+
+/// Parse a duration such as `250ms`, `15m`, `1h30m` or `90d`. A bare number
+/// is taken in `default_unit` (one of ms, s, m, h, d, w, y).
+pub fn parse_duration_in(s: &str, default_unit: &str) -> Result<Duration> {
+    let expected = "expected a number with a unit of ms, s, m, h, d, w or y (e.g. 15m, 1h30m, 90d)";
+    let Some(terms) = terms(s) else { bail!("invalid duration '{}': {}", s.trim(), expected) };
+    let mut ms = 0.0;
+    for (n, unit) in &terms {
+        let unit = if unit.is_empty() && terms.len() == 1 { default_unit } else { unit.as_str() };
+        let Some((_, scale)) = DURATION_UNITS.iter().find(|(u, _)| *u == unit) else {
+            bail!("invalid duration '{}': unknown unit '{}', {}", s.trim(), unit, expected);
+        };
+        ms += n * *scale as f64;
+    }
+    if !ms.is_finite() || ms > u64::MAX as f64 {
+        bail!("duration '{}' is out of range", s.trim());
+    }
+    Ok(Duration::from_millis(ms.round() as u64))
+}
+
+/// Parse a duration whose bare numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    parse_duration_in(s, "s")
+}
+
+/// Parse a size such as `512KiB`, `64MB` or `1.5GiB`; a bare number is bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let expected = "expected bytes or a number with B, KB, MB, GB, TB, KiB, MiB, GiB or TiB (e.g. 64MiB)";
+    let parsed = terms(s).filter(|t| t.len() == 1).map(|t| t[0].clone());
+    let Some((n, unit)) = parsed else { bail!("invalid size '{}': {}", s.trim(), expected) };
+    let scale = match unit.as_str() {
+        "" => 1,
+        u => match SIZE_UNITS.iter().find(|(name, _)| *name == u) {
+            Some((_, scale)) => *scale,
+            None => bail!("invalid size '{}': unknown unit '{}', {}", s.trim(), unit, expected),
+        },
+    };
+    let bytes = n * scale as f64;
+    if !bytes.is_finite() || bytes > u64::MAX as f64 {
+        bail!("size '{}' is out of range", s.trim());
+    }
+    Ok(bytes.round() as u64)
+}
+
+/// A config value given as a number in the setting's own unit or as a string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(u64),
+    String(String),
+}
+
+/// Deserialize a duration given as a number of `bare_unit` or as a string
+/// such as `"15m"`.
+pub fn duration<'de, D: Deserializer<'de>>(d: D, bare_unit: &str) -> Result<Duration, D::Error> {
+    let text = match NumberOrString::deserialize(d)? {
+        NumberOrString::Number(n) => n.to_string(),
+        NumberOrString::String(s) => s,
+    };
+    parse_duration_in(&text, bare_unit).map_err(serde::de::Error::custom)
+}
+
+/// Deserialize a duration as a whole number of `unit` (ms, s, m, h, d, w or
+/// y); bare numbers are already in that unit. A duration above zero but
+/// under one `unit` is refused rather than rounded down to zero.
+pub fn duration_in<'de, D: Deserializer<'de>>(d: D, unit: &str) -> Result<u64, D::Error> {
+    whole(duration(d, unit)?, unit).map_err(serde::de::Error::custom)
+}
+
+/// `d` as a whole number of `unit`, refusing one above zero but under one
+/// `unit` rather than rounding it down to zero.
+fn whole(d: Duration, unit: &str) -> Result<u64> {
+    let scale = DURATION_UNITS.iter().find(|(u, _)| *u == unit).map_or(1, |(_, s)| *s) as u128;
+    if d.as_millis() > 0 && d.as_millis() < scale {
+        bail!("duration {:?} is less than 1{}, the smallest this setting takes", d, unit);
+    }
+    Ok((d.as_millis() / scale) as u64)
+}
+
+/// `deserialize_with` for `*_secs` fields: `30`, `"30s"`, `"15m"` or `"90d"`.
+pub fn secs<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    duration_in(d, "s")
+}
+
+/// `deserialize_with` for optional `*_secs` fields.
+pub fn opt_secs<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    secs(d).map(Some)
+}
+
+/// `deserialize_with` for `*_ms` fields: `250`, `"250ms"` or `"2s"`.
+pub fn millis<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    duration_in(d, "ms")
+}
+
+/// `deserialize_with` for optional `*_ms` fields and query parameters.
+pub fn opt_millis<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    millis(d).map(Some)
+}
+
+/// `deserialize_with` for `*_bytes` fields: `1048576`, `"1MiB"` or `"64MB"`.
+pub fn bytes<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    match NumberOrString::deserialize(d)? {
+        NumberOrString::Number(n) => Ok(n),
+        NumberOrString::String(s) => parse_size(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+/// `deserialize_with` for optional `*_bytes` fields.
+pub fn opt_bytes<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    bytes(d).map(Some)
+}
+
+/// Read an environment variable holding a duration; bare numbers are in
+/// `default_unit`, so `SKYPULSE_RETENTION_DAYS=90` and `=12w` both work.
+pub fn env_duration(name: &str, default_unit: &str) -> Result<Option<Duration>> {
+    let Ok(v) = std::env::var(name) else { return Ok(None) };
+    match parse_duration_in(&v, default_unit) {
+        Ok(d) => Ok(Some(d)),
+        Err(e) => bail!("{}: {}", name, e),
+    }
+}
+
+/// Read an environment variable holding a duration as whole seconds; bare
+/// numbers are in `default_unit`.
+pub fn env_secs(name: &str, default_unit: &str) -> Result<Option<u64>> {
+    let Some(d) = env_duration(name, default_unit)? else { return Ok(None) };
+    match whole(d, "s") {
+        Ok(secs) => Ok(Some(secs)),
+        Err(e) => bail!("{}: {}", name, e),
+    }
+}
+
+/// Read an environment variable holding a size in bytes.
+pub fn env_size(name: &str) -> Result<Option<u64>> {
+    let Ok(v) = std::env::var(name) else { return Ok(None) };
+    match parse_size(&v) {
+        Ok(n) => Ok(Some(n)),
+        Err(e) => bail!("{}: {}", name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("90d").unwrap(), Duration::from_secs(90 * 86_400));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration(" 30 ").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration_in("100", "ms").unwrap(), Duration::from_millis(100));
+        let err = parse_duration("15x").unwrap_err().to_string();
+        assert!(err.contains("unknown unit 'x'"), "{}", err);
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("64MB").unwrap(), 64_000_000);
+        assert_eq!(parse_size("64MiB").unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse_size("1.5 GiB").unwrap(), 1_610_612_736);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("1h").is_err());
+        assert!(parse_size("1MB2KB").is_err());
+        assert!(parse_size("4k").unwrap_err().to_string().contains("unknown unit 'k'"));
+    }
+
+    #[test]
+    fn config_fields_take_numbers_or_strings() {
+        #[derive(Deserialize)]
+        struct Cfg {
+            #[serde(deserialize_with = "secs")]
+            a_secs: u64,
+            #[serde(deserialize_with = "secs")]
+            b_secs: u64,
+            #[serde(deserialize_with = "millis")]
+            c_ms: u64,
+            #[serde(deserialize_with = "bytes")]
+            d_bytes: u64,
+        }
+        let cfg: Cfg = toml::from_str("a_secs = 30\nb_secs = \"90d\"\nc_ms = \"2s\"\nd_bytes = \"64MiB\"").unwrap();
+        assert_eq!((cfg.a_secs, cfg.b_secs, cfg.c_ms, cfg.d_bytes), (30, 7_776_000, 2000, 67_108_864));
+        let err = toml::from_str::<Cfg>("a_secs = \"500ms\"\nb_secs = 0\nc_ms = 1\nd_bytes = 1").err().unwrap();
+        assert!(err.to_string().contains("less than 1s"), "{}", err);
+        assert!(toml::from_str::<Cfg>("a_secs = \"0s\"\nb_secs = 0\nc_ms = 1\nd_bytes = 1").is_ok());
+    }
+}